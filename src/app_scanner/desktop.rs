@@ -0,0 +1,145 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use crate::config::{DesktopApplicationScannerConfig, ApplicationConfig};
+
+/// Directories that are searched for icons, in order of preference.
+const ICON_DIRECTORIES: &[&str] = &[
+	"$HOME/.local/share/icons/hicolor/512x512/apps",
+	"$HOME/.local/share/icons/hicolor/256x256/apps",
+	"/usr/share/icons/hicolor/512x512/apps",
+	"/usr/share/icons/hicolor/256x256/apps",
+	"/var/lib/flatpak/exports/share/icons/hicolor/512x512/apps",
+	"/var/lib/flatpak/exports/share/icons/hicolor/256x256/apps",
+	"/usr/share/pixmaps",
+];
+
+pub fn scan_desktop_applications(config: &DesktopApplicationScannerConfig) -> Result<Vec<ApplicationConfig>, ()> {
+	let mut applications = Vec::new();
+
+	for directory in &config.directories {
+		let directory = directory.to_string_lossy().to_string();
+		let directory = match shellexpand::full(&directory) {
+			Ok(directory) => PathBuf::from(directory.as_ref()),
+			Err(e) => {
+				tracing::warn!("Failed to expand {directory:?}: {e}");
+				continue;
+			},
+		};
+
+		let entries = match std::fs::read_dir(&directory) {
+			Ok(entries) => entries,
+			Err(e) => {
+				tracing::debug!("Skipping application directory {directory:?}: {e}");
+				continue;
+			},
+		};
+
+		for entry in entries.flatten() {
+			let path = entry.path();
+			if path.extension().and_then(|extension| extension.to_str()) != Some("desktop") {
+				continue;
+			}
+
+			let Ok(application) = parse_desktop_entry(&path, config) else {
+				continue;
+			};
+
+			// The same application can be installed in multiple directories, the first directory takes precedence.
+			if !applications.iter().any(|a: &ApplicationConfig| a.title == application.title) {
+				applications.push(application);
+			}
+		}
+	}
+
+	applications.sort_by(|a, b| a.title.cmp(&b.title));
+
+	Ok(applications)
+}
+
+fn parse_desktop_entry(path: &Path, config: &DesktopApplicationScannerConfig) -> Result<ApplicationConfig, ()> {
+	let contents = std::fs::read_to_string(path)
+		.map_err(|e| tracing::warn!("Failed to read desktop entry {path:?}: {e}"))?;
+
+	// Only the main group describes the application, other groups describe additional actions.
+	let mut entry = HashMap::new();
+	let mut in_desktop_entry = false;
+	for line in contents.lines().map(str::trim) {
+		if line.starts_with('[') {
+			in_desktop_entry = line == "[Desktop Entry]";
+		} else if let (true, Some((key, value))) = (in_desktop_entry, line.split_once('=')) {
+			entry.insert(key.trim(), value.trim());
+		}
+	}
+
+	let is_true = |key: &str| entry.get(key) == Some(&"true");
+	if entry.get("Type") != Some(&"Application") || is_true("NoDisplay") || is_true("Hidden") || is_true("Terminal") {
+		return Err(());
+	}
+
+	let categories: Vec<&str> = entry.get("Categories")
+		.map(|categories| categories.split(';').filter(|c| !c.is_empty()).collect())
+		.unwrap_or_default();
+	if !config.categories.is_empty() && !config.categories.iter().any(|c| categories.contains(&c.as_str())) {
+		return Err(());
+	}
+
+	let title = entry.get("Name")
+		.ok_or_else(|| tracing::debug!("Desktop entry {path:?} has no name."))?
+		.to_string();
+	let command = entry.get("Exec")
+		.map(|exec| parse_exec(exec))
+		.filter(|command| !command.is_empty())
+		.ok_or_else(|| tracing::debug!("Desktop entry {path:?} has no command."))?;
+
+	Ok(ApplicationConfig {
+		title,
+		boxart: entry.get("Icon").and_then(|icon| find_icon(icon)),
+		run_before: config.run_before.clone(),
+		command: Some(command),
+		run_after: config.run_after.clone(),
+		..Default::default()
+	})
+}
+
+/// Split the `Exec` key of a desktop entry into arguments, without the field codes (`%f`, `%U`, ...).
+fn parse_exec(exec: &str) -> Vec<String> {
+	let mut arguments = Vec::new();
+	let mut argument = String::new();
+	let mut quoted = false;
+	let mut chars = exec.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'"' => quoted = !quoted,
+			'\\' if quoted => argument.extend(chars.next()),
+			' ' if !quoted => {
+				if !argument.is_empty() {
+					arguments.push(std::mem::take(&mut argument));
+				}
+			},
+			c => argument.push(c),
+		}
+	}
+	if !argument.is_empty() {
+		arguments.push(argument);
+	}
+
+	arguments
+		.into_iter()
+		.filter(|argument| !(argument.len() == 2 && argument.starts_with('%')))
+		.map(|argument| argument.replace("%%", "%"))
+		.collect()
+}
+
+/// Find the image of an icon, which is either a path or the name of an icon in the icon theme.
+fn find_icon(icon: &str) -> Option<PathBuf> {
+	let path = PathBuf::from(icon);
+	if path.is_absolute() {
+		return Some(path).filter(|path| path.exists());
+	}
+
+	ICON_DIRECTORIES
+		.iter()
+		.filter_map(|directory| shellexpand::full(directory).ok())
+		.map(|directory| PathBuf::from(directory.as_ref()).join(format!("{icon}.png")))
+		.find(|path| path.exists())
+}
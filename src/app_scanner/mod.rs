@@ -1,5 +1,8 @@
+use std::sync::{Arc, RwLock};
+
 use crate::config::{ApplicationScannerConfig, ApplicationConfig};
 
+mod desktop;
 mod steam;
 
 pub fn scan_applications(application_scanners: &Vec<ApplicationScannerConfig>) -> Vec<ApplicationConfig> {
@@ -13,8 +16,67 @@ pub fn scan_applications(application_scanners: &Vec<ApplicationScannerConfig>) -
 					Err(()) => continue,
 				}
 			},
+			ApplicationScannerConfig::Desktop(config) => {
+				match desktop::scan_desktop_applications(config) {
+					Ok(desktop_applications) => applications.extend(desktop_applications),
+					Err(()) => continue,
+				}
+			},
 		}
 	}
 
 	applications
 }
+
+/// Applications that are exposed to clients, both configured and scanned.
+#[derive(Clone)]
+pub struct ApplicationCatalog {
+	/// Applications defined in the config.
+	configured: Vec<ApplicationConfig>,
+
+	/// Scanners that add applications to the catalog.
+	scanners: Vec<ApplicationScannerConfig>,
+
+	/// Applications found by the scanners during the last scan.
+	scanned: Arc<RwLock<Vec<ApplicationConfig>>>,
+}
+
+impl ApplicationCatalog {
+	pub fn new(configured: Vec<ApplicationConfig>, scanners: Vec<ApplicationScannerConfig>) -> Self {
+		let catalog = Self { configured, scanners, scanned: Default::default() };
+		catalog.refresh();
+		catalog
+	}
+
+	/// Scan for applications again, returns the number of scanned applications.
+	pub fn refresh(&self) -> usize {
+		let scanned = scan_applications(&self.scanners);
+		tracing::debug!("Adding scanned applications:\n{:#?}", scanned);
+
+		let count = scanned.len();
+		match self.scanned.write() {
+			Ok(mut applications) => *applications = scanned,
+			Err(e) => tracing::error!("Failed to update scanned applications: {e}"),
+		}
+
+		count
+	}
+
+	/// All applications, configured applications come first.
+	pub fn list(&self) -> Vec<ApplicationConfig> {
+		let mut applications = self.configured.clone();
+		if let Ok(scanned) = self.scanned.read() {
+			// Configured applications take precedence over scanned applications with the same title.
+			applications.extend(scanned.iter()
+				.filter(|a| !self.configured.iter().any(|c| c.title == a.title))
+				.cloned()
+			);
+		}
+
+		applications
+	}
+
+	pub fn find(&self, id: i32) -> Option<ApplicationConfig> {
+		self.list().into_iter().find(|application| application.id() == id)
+	}
+}
@@ -1,133 +1,126 @@
-use std::{path::{Path, PathBuf}, str::FromStr};
+use std::path::{Path, PathBuf};
 
 use crate::config::{SteamApplicationScannerConfig, ApplicationConfig};
 
 pub fn scan_steam_applications(config: &SteamApplicationScannerConfig) -> Result<Vec<ApplicationConfig>, ()> {
-	let library_path = config.library.join("steamapps").join("libraryfolders.vdf").to_string_lossy().to_string();
-	let library_path = shellexpand::full(&library_path)
-		.map_err(|e| tracing::error!("Failed to expand {library_path:?}: {e}"))?;
-	let library = std::fs::read_to_string(library_path.as_ref())
+	let steam_path = config.library.to_string_lossy().to_string();
+	let steam_path = PathBuf::from(shellexpand::full(&steam_path)
+		.map_err(|e| tracing::error!("Failed to expand {steam_path:?}: {e}"))?
+		.as_ref());
+	let library_path = steam_path.join("steamapps").join("libraryfolders.vdf");
+	let library = std::fs::read_to_string(&library_path)
 		.map_err(|e| tracing::warn!("Failed to open library: {e}"))?;
 
-	// Poor man's library parsing.
-	let start_apps = library.find("apps")
-		.ok_or_else(|| tracing::warn!("Failed to find 'apps' key in {library_path:?}."))?;
-	let library = &library[start_apps..];
-	let stop_apps = library.find('}')
-		.ok_or_else(|| tracing::warn!("Failed to find end of 'apps' section."))?;
-	let library = &library[..stop_apps];
+	// Poor man's library parsing, games can be installed in any of the library folders.
+	let mut library_folders: Vec<PathBuf> = library.lines()
+		.filter(|line| line.contains("\"path\""))
+		.filter_map(|line| line.split('\"').nth(3))
+		.map(|path| PathBuf::from(path.replace("\\\\", "\\")))
+		.collect();
+	if library_folders.is_empty() {
+		library_folders.push(steam_path.clone());
+	}
 
 	let mut applications = Vec::new();
-	for line in library.lines().skip(2) {
-		let mut application = ApplicationConfig::default();
-
-		if line.trim().is_empty() {
-			continue;
-		}
-
-		let game_id = match line.split('\"').nth(1) {
-			Some(game_id) => game_id,
-			None => {
-				tracing::warn!("Failed to parse library entry: '{line}'");
+	for library_folder in library_folders {
+		let steamapps = library_folder.join("steamapps");
+		let entries = match std::fs::read_dir(&steamapps) {
+			Ok(entries) => entries,
+			Err(e) => {
+				tracing::warn!("Failed to read Steam library folder {steamapps:?}: {e}");
 				continue;
 			},
 		};
 
-		let game_id: u32 = match game_id.parse() {
-			Ok(game_id) => game_id,
-			Err(e) => {
-				tracing::warn!("Failed to parse game id: {e}");
+		for entry in entries.flatten() {
+			let manifest_path = entry.path();
+			let is_manifest = manifest_path.file_name()
+				.and_then(|name| name.to_str())
+				.map(|name| name.starts_with("appmanifest_") && name.ends_with(".acf"))
+				.unwrap_or(false);
+			if !is_manifest {
 				continue;
-			},
-		};
+			}
 
-		application.title = match get_game_name(game_id, library_path.as_ref()) {
-			Ok(title) => title,
-			Err(()) => continue,
-		};
+			let Ok((game_id, title)) = parse_manifest(&manifest_path) else {
+				continue;
+			};
 
-		// Skip things that aren't really games.
-		if application.title.starts_with("Proton")
-			|| application.title.starts_with("Steam Linux Runtime")
-			|| application.title.starts_with("Steamworks Common Redistributables") {
-			continue;
-		}
+			// Skip things that aren't really games.
+			if title.starts_with("Proton")
+				|| title.starts_with("Steam Linux Runtime")
+				|| title.starts_with("Steamworks Common Redistributables") {
+				continue;
+			}
 
-		if let Some(run_before) = &config.run_before {
-			application.run_before = Some(
-				run_before
-					.clone()
-					.iter_mut()
-					.map(|c| {
-						c
-							.iter_mut()
-							.map(|a| a.replace("{game_id}", &game_id.to_string()))
-							.collect()
-					})
-					.collect()
-			);
+			applications.push(ApplicationConfig {
+				title,
+				boxart: find_boxart(&steam_path, game_id),
+				run_before: config.run_before.clone()
+					.map(|commands| commands.into_iter().map(|command| replace_game_id(command, game_id)).collect()),
+				command: Some(replace_game_id(config.command.clone().unwrap_or_else(default_command), game_id)),
+				run_after: config.run_after.clone()
+					.map(|commands| commands.into_iter().map(|command| replace_game_id(command, game_id)).collect()),
+				..Default::default()
+			});
 		}
+	}
 
-		if let Some(run_after) = &config.run_after {
-			application.run_after = Some(
-				run_after
-					.clone()
-					.iter_mut()
-					.map(|c| {
-						c
-							.iter_mut()
-							.map(|a| a.replace("{game_id}", &game_id.to_string()))
-							.collect()
-					})
-					.collect()
-			);
-		}
+	// Directory order is arbitrary, keep the list stable between scans.
+	applications.sort_by(|a, b| a.title.cmp(&b.title));
 
-		let boxart = config.library.join(format!("appcache/librarycache/{game_id}_library_600x900.jpg"));
-		if let Ok(boxart) = shellexpand::full(&boxart.to_string_lossy()) {
-			match PathBuf::from_str(&boxart) {
-				Ok(path) => {
-					if path.exists() {
-						application.boxart = Some(path);
-					} else {
-						tracing::warn!("No boxart for game '{}' at '{boxart}", application.title);
-					}
-				},
-				Err(e) => {
-					tracing::warn!("Failed to parse boxart path: {e}");
-				}
-			}
-		}
+	Ok(applications)
+}
 
-		applications.push(application);
-	}
+/// Launch the game through Steam if no command is configured.
+fn default_command() -> Vec<String> {
+	vec!["steam".to_string(), "steam://run/{game_id}".to_string()]
+}
 
-	Ok(applications)
+fn replace_game_id(command: Vec<String>, game_id: u32) -> Vec<String> {
+	command
+		.into_iter()
+		.map(|argument| argument.replace("{game_id}", &game_id.to_string()))
+		.collect()
 }
 
-fn get_game_name<P: AsRef<Path>>(game_id: u32, library: P) -> Result<String, ()> {
-	let manifest_path = library
-		.as_ref()
-		.parent()
-		.ok_or_else(|| eprintln!("Expected '{:?}' to have a parent, but couldn't find one.", library.as_ref()))?
-		.join(format!("appmanifest_{}.acf", game_id));
-	let manifest = std::fs::read_to_string(&manifest_path)
-		.map_err(|e| eprintln!("Failed to open Steam game manifest ({manifest_path:?}): {e}"))?;
-	let name_line = manifest
-		.lines()
-		.find(|l| l.contains("\"name\""));
-
-	match name_line {
-		Some(line) => {
-			line
-				.split('\"')
-				.nth(3)
-				.ok_or_else(|| eprintln!("Line '{}' doesn't match expected format (expected: \"name\" \"<NAME>\").", line))
-				.map(|l| l.to_string())
-		},
-		None => {
-			eprintln!("Couldn't find name for game with ID '{game_id}'.");
-			Err(())
-		},
+/// Find the grid art that Steam downloaded for this game.
+fn find_boxart(steam_path: &Path, game_id: u32) -> Option<PathBuf> {
+	let library_cache = steam_path.join("appcache").join("librarycache");
+
+	// Newer Steam clients store the art of each game in its own directory.
+	let boxart = [
+		library_cache.join(format!("{game_id}_library_600x900.jpg")),
+		library_cache.join(game_id.to_string()).join("library_600x900.jpg"),
+	]
+		.into_iter()
+		.find(|path| path.exists());
+
+	if boxart.is_none() {
+		tracing::debug!("No boxart found for game with ID '{game_id}'.");
 	}
+
+	boxart
+}
+
+/// Read the game id and name from an `appmanifest_<game id>.acf` file.
+fn parse_manifest(manifest_path: &Path) -> Result<(u32, String), ()> {
+	let manifest = std::fs::read_to_string(manifest_path)
+		.map_err(|e| tracing::warn!("Failed to open Steam game manifest ({manifest_path:?}): {e}"))?;
+
+	let value = |key: &str| {
+		manifest
+			.lines()
+			.find(|line| line.split('\"').nth(1) == Some(key))
+			.and_then(|line| line.split('\"').nth(3))
+			.map(|value| value.to_string())
+			.ok_or_else(|| tracing::warn!("Couldn't find '{key}' in Steam game manifest ({manifest_path:?})."))
+	};
+
+	let game_id = value("appid")?
+		.parse()
+		.map_err(|e| tracing::warn!("Failed to parse game id in Steam game manifest ({manifest_path:?}): {e}"))?;
+	let name = value("name")?;
+
+	Ok((game_id, name))
 }
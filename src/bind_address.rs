@@ -0,0 +1,62 @@
+//! Resolution of the configured `address` to the IP address that all servers and streams bind to.
+//!
+//! The address can be a literal IP address (including the `0.0.0.0` and `::` wildcards), the name of a network
+//! interface (ie. `eth0`) or a hostname.
+
+use std::net::{IpAddr, ToSocketAddrs};
+
+use network_interface::NetworkInterfaceConfig;
+
+/// Resolve `address` once at startup, so that every bind uses the same IP address.
+pub fn resolve(address: &str) -> Result<IpAddr, ()> {
+	let address = address.trim();
+	if let Ok(ip) = address.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+		return Ok(ip);
+	}
+
+	if let Some(ip) = interface_address(address)? {
+		tracing::info!("Binding to address {ip} of interface '{address}'.");
+		return Ok(ip);
+	}
+
+	let addresses: Vec<IpAddr> = (address, 0).to_socket_addrs()
+		.map_err(|e| tracing::error!("Address '{address}' is not an IP address or network interface, and failed to resolve as a hostname: {e}"))?
+		.map(|address| address.ip())
+		.collect();
+	let ip = preferred(&addresses)
+		.ok_or_else(|| tracing::error!("Hostname '{address}' resolved to no addresses."))?;
+
+	tracing::info!("Binding to address {ip} of hostname '{address}'.");
+	Ok(ip)
+}
+
+/// Address of the network interface named `name`, or `None` if there is no such interface.
+fn interface_address(name: &str) -> Result<Option<IpAddr>, ()> {
+	let interfaces = network_interface::NetworkInterface::show()
+		.map_err(|e| tracing::error!("Failed to retrieve network interfaces: {e}"))?;
+
+	let addresses: Vec<IpAddr> = interfaces.into_iter()
+		.filter(|interface| interface.name == name)
+		.flat_map(|interface| interface.addr)
+		.map(|address| address.ip())
+		.collect();
+	if addresses.is_empty() {
+		return Ok(None);
+	}
+
+	preferred(&addresses)
+		.map(Some)
+		.ok_or_else(|| tracing::error!("Network interface '{name}' has no address that can be bound to."))
+}
+
+/// Pick an IPv4 address if there is one, because the ENet control stream only supports IPv4.
+///
+/// Link-local IPv6 addresses are skipped, they can't be bound to without a scope.
+fn preferred(addresses: &[IpAddr]) -> Option<IpAddr> {
+	addresses.iter().find(|address| address.is_ipv4())
+		.or_else(|| addresses.iter().find(|address| match address {
+			IpAddr::V6(address) => (address.segments()[0] & 0xffc0) != 0xfe80,
+			IpAddr::V4(_) => false,
+		}))
+		.copied()
+}
@@ -1,28 +1,62 @@
-use std::{sync::Arc, collections::BTreeMap};
+use std::{collections::HashMap, net::IpAddr, time::Duration};
 
 use async_shutdown::TriggerShutdownToken;
-use openssl::{hash::MessageDigest, pkey::{PKey, PKeyRef, Private}, md::Md, md_ctx::MdCtx, x509::X509, cipher::Cipher};
-use tokio::sync::{oneshot, mpsc, Notify};
+use notify_rust::Notification;
+use tokio::{sync::{oneshot, mpsc}, time::Instant};
 
-use crate::{crypto::{encrypt, decrypt}, state::State};
+use crate::{crypto::{self, Certificate, PrivateKey}, events::{EventBus, SessionEvent}, state::{ClientInfo, PairedClient, State}};
+
+/// Number of failed steps after which a pairing attempt is invalidated.
+const MAX_FAILED_ATTEMPTS: u32 = 3;
+
+/// Time an address is blocked from pairing after its first failure, doubled for every consecutive failure.
+const PAIRING_BACKOFF_BASE: Duration = Duration::from_secs(2);
+
+/// Maximum time an address is blocked from pairing.
+const PAIRING_BACKOFF_MAX: Duration = Duration::from_secs(15 * 60);
+
+/// Time after the backoff of an address expired after which its failures are forgotten.
+const PAIRING_FAILURE_RETENTION: Duration = Duration::from_secs(15 * 60);
+
+/// Time after which the link to enter the PIN of a pairing attempt stops working.
+const PIN_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
 
 /// A client that is not yet paired, but in the pairing process.
 pub struct PendingClient {
 	/// Unique id of the client.
 	pub id: String,
 
+	/// Address from which the client started pairing.
+	pub address: IpAddr,
+
+	/// Name of the device, as reported by the client.
+	pub device_name: Option<String>,
+
+	/// Kind of client, from the user agent of the pairing request.
+	pub client_type: Option<String>,
+
 	/// Client certificate used for secure communication.
-	pub pem: X509,
+	pub pem: Certificate,
 
 	/// Salt provided by the client to use for encryption.
 	pub salt: [u8; 16],
 
-	/// A channel that sends a notification when a PIN has been received for this client.
+	/// A channel that signals when a PIN has been received for this client.
 	///
 	/// The client shows a PIN code on the clients screen.
 	/// The user is expected to provide this PIN to the server.
-	/// This channel notifies listeners that the user has provided a PIN code.
-	pub pin_notify: Arc<Notify>,
+	/// The channel is closed without a signal if the attempt is replaced or invalidated before that.
+	pub pin_tx: Option<oneshot::Sender<()>>,
+
+	/// One-time token that allows entering the PIN for this client without knowing its id, until it expires.
+	///
+	/// If the server generated the PIN, the token allows showing that PIN instead.
+	pub pin_token: Option<(String, Instant)>,
+
+	/// PIN generated by the server for this attempt, which the user enters on the client.
+	///
+	/// The key is derived from it when pairing starts, so PINs entered on the host are rejected.
+	pub server_pin: Option<String>,
 
 	///
 	pub key: Option<[u8; 16]>,
@@ -35,18 +69,50 @@ pub struct PendingClient {
 
 	///
 	pub client_hash: Option<Vec<u8>>,
+
+	/// Number of pairing steps that failed for this client.
+	pub failed_attempts: u32,
+
+	/// Whether the client signed its pairing secret with the key of its certificate, only then it can be paired.
+	pub verified: bool,
 }
 
-pub enum ClientManagerCommand {
-	/// Check if a client is already paired.
-	IsPaired(IsPairedCommand),
+impl PendingClient {
+	/// Create a random one-time token for the link to enter the PIN of this client.
+	pub fn create_pin_token() -> Result<(String, Instant), ()> {
+		let token = crypto::random_bytes::<16>()
+			.map_err(|e| tracing::error!("Failed to create PIN token: {e}"))?;
+
+		Ok((hex::encode(token), Instant::now() + PIN_TOKEN_LIFETIME))
+	}
+
+	/// Create a random four digit PIN for the user to enter on the client.
+	pub fn create_server_pin() -> Result<String, ()> {
+		let random = crypto::random_bytes::<4>()
+			.map_err(|e| tracing::error!("Failed to create PIN: {e}"))?;
+
+		Ok(format!("{:04}", u32::from_le_bytes(random) % 10000))
+	}
+
+	/// Check whether the token is the unexpired PIN token of this client.
+	fn has_pin_token(&self, token: &str) -> bool {
+		match &self.pin_token {
+			Some((pin_token, expires)) => *expires > Instant::now() && crypto::secrets_equal(pin_token.as_bytes(), token.as_bytes()),
+			None => false,
+		}
+	}
+}
 
+pub enum ClientManagerCommand {
 	/// Initiate the pairing procedure.
 	StartPairing(StartPairingCommand),
 
 	/// Register a pin for a client.
 	RegisterPin(RegisterPinCommand),
 
+	/// Retrieve the PIN that the server generated for a client.
+	GetServerPin(GetServerPinCommand),
+
 	/// Run a challenge for the client.
 	ClientChallenge(ClientChallengeCommand),
 
@@ -59,29 +125,41 @@ pub enum ClientManagerCommand {
 	/// Add a client to the list of paired clients.
 	AddClient(AddClientCommand),
 
-	// /// Remove client from the list of paired clients.
-	// RemoveClient(RemoveClientCommand),
-}
+	/// Look up a paired client and record that it was seen.
+	ClientSeen(ClientSeenCommand),
 
-/// Query the manager to check if this unique id is paired or not.
-pub struct IsPairedCommand {
-	/// Unique id of the client.
-	pub id: String,
+	/// List the paired clients.
+	ListClients(ListClientsCommand),
 
-	/// Channel used to provide a response.
-	pub response: oneshot::Sender<Result<bool, String>>,
+	/// Give a paired client a name.
+	RenameClient(RenameClientCommand),
+
+	// /// Remove client from the list of paired clients.
+	// RemoveClient(RemoveClientCommand),
 }
 
 /// Initiate a pairing process for a client.
 pub struct StartPairingCommand {
 	/// Client to start the pairing process for.
 	pub pending_client: PendingClient,
+
+	/// Channel used to provide a response.
+	pub response: oneshot::Sender<Result<(), String>>,
+}
+
+/// Identifies the pending client a PIN is meant for.
+pub enum PinRecipient {
+	/// The unique id of the client.
+	Id(String),
+
+	/// The one-time token from the link that was shown when pairing started.
+	Token(String),
 }
 
 /// Register a pin for a client.
 pub struct RegisterPinCommand {
-	/// Id of the client.
-	pub id: String,
+	/// Client the pin is meant for.
+	pub recipient: PinRecipient,
 
 	/// The pin for the client.
 	pub pin: String,
@@ -90,11 +168,23 @@ pub struct RegisterPinCommand {
 	pub response: oneshot::Sender<Result<(), String>>,
 }
 
+/// Retrieve the PIN that the server generated for a client.
+pub struct GetServerPinCommand {
+	/// The one-time token from the link that was shown when pairing started.
+	pub token: String,
+
+	/// Channel used to provide a response, `None` if the token is unknown or the client generated the PIN.
+	pub response: oneshot::Sender<Option<String>>,
+}
+
 /// Run a challenge for the client.
 pub struct ClientChallengeCommand {
 	/// Id of the client.
 	pub id: String,
 
+	/// Address of the client, which tells attempts with the same id apart.
+	pub address: IpAddr,
+
 	/// Challenge from the client.
 	pub challenge: Vec<u8>,
 
@@ -107,6 +197,9 @@ pub struct ServerChallengeResponseCommand {
 	/// Id of the client.
 	pub id: String,
 
+	/// Address of the client, which tells attempts with the same id apart.
+	pub address: IpAddr,
+
 	/// Challenge response from the client.
 	pub challenge_response: Vec<u8>,
 
@@ -119,6 +212,9 @@ pub struct CheckClientPairingSecretCommand {
 	/// Id of the client.
 	pub id: String,
 
+	/// Address of the client, which tells attempts with the same id apart.
+	pub address: IpAddr,
+
 	/// Challenge response from the client.
 	pub client_secret: Vec<u8>,
 
@@ -131,10 +227,40 @@ pub struct AddClientCommand {
 	/// Id of the client.
 	pub id: String,
 
+	/// Address of the client, which tells attempts with the same id apart.
+	pub address: IpAddr,
+
 	/// Channel used to provide a response.
 	pub response: oneshot::Sender<Result<(), String>>,
 }
 
+/// Look up a paired client and record that it was seen.
+pub struct ClientSeenCommand {
+	/// Fingerprint of the certificate the client presented.
+	pub id: String,
+
+	/// Channel used to provide a response, `None` if the client isn't paired.
+	pub response: oneshot::Sender<Result<Option<ClientInfo>, String>>,
+}
+
+/// List the paired clients.
+pub struct ListClientsCommand {
+	/// Channel used to provide a response.
+	pub response: oneshot::Sender<Result<Vec<PairedClient>, String>>,
+}
+
+/// Give a paired client a name.
+pub struct RenameClientCommand {
+	/// Fingerprint of the certificate of the client.
+	pub id: String,
+
+	/// New name of the client, `None` removes its name.
+	pub name: Option<String>,
+
+	/// Channel used to provide a response, false if the client isn't paired.
+	pub response: oneshot::Sender<Result<bool, String>>,
+}
+
 // /// Remove client from the list of paired clients.
 // pub struct RemoveClientCommand {
 // 	/// Id of the client.
@@ -152,38 +278,34 @@ pub struct ClientManager {
 impl ClientManager {
 	pub fn new(
 		state: State,
-		server_certs: X509,
-		server_pkey: PKey<Private>,
+		server_certs: Certificate,
+		server_pkey: PrivateKey,
+		events: EventBus,
 		shutdown_token: TriggerShutdownToken<i32>,
 	) -> Self {
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = ClientManagerInner { server_certs, server_pkey };
+		let inner = ClientManagerInner { server_certs, server_pkey, events };
 		tokio::spawn(async move { inner.run(command_rx, state).await; drop(shutdown_token); });
 
 		Self { command_tx }
 	}
 
-	pub async fn is_paired(&self, id: String) -> Result<bool, ()> {
+	pub async fn start_pairing(&self, pending_client: PendingClient) -> Result<(), ()> {
 		let (response_tx, response_rx) = oneshot::channel();
-		self.command_tx.send(ClientManagerCommand::IsPaired(IsPairedCommand { id, response: response_tx }))
+		self.command_tx.send(ClientManagerCommand::StartPairing(StartPairingCommand { pending_client, response: response_tx }))
 			.await
-			.map_err(|e| tracing::error!("Failed to check paired status: {e}"))?;
-
-		response_rx.await
-			.map_err(|e| tracing::error!("Failed to receive IsPaired response: {e}"))?
-			.map_err(|e| tracing::error!("Failed to check paired status: {e}"))
-	}
+			.map_err(|e| tracing::error!("Failed to start pairing: {e}"))?;
 
-	pub async fn start_pairing(&self, pending_client: PendingClient) -> Result<(), ()> {
-		self.command_tx.send(ClientManagerCommand::StartPairing(StartPairingCommand { pending_client }))
+		response_rx
 			.await
-			.map_err(|e| tracing::error!("Failed to start pairing: {e}"))
+			.map_err(|e| tracing::error!("Failed to wait for response to StartPairing command from client manager: {e}"))?
+			.map_err(|e| tracing::warn!("{e}"))
 	}
 
-	pub async fn register_pin(&self, id: &str, pin: &str) -> Result<(), ()> {
+	pub async fn register_pin(&self, recipient: PinRecipient, pin: &str) -> Result<(), ()> {
 		let (response_tx, response_rx) = oneshot::channel();
 		self.command_tx.send(ClientManagerCommand::RegisterPin(RegisterPinCommand {
-			id: id.to_string(),
+			recipient,
 			pin: pin.to_string(),
 			response: response_tx,
 		}))
@@ -196,10 +318,26 @@ impl ClientManager {
 			.map_err(|e| tracing::warn!("{e}"))
 	}
 
-	pub async fn add_client(&self, id: &str) -> Result<(), ()> {
+	/// The PIN the server generated for the pairing attempt with this PIN token, if any.
+	pub async fn get_server_pin(&self, token: &str) -> Result<Option<String>, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::GetServerPin(GetServerPinCommand {
+			token: token.to_string(),
+			response: response_tx,
+		}))
+			.await
+			.map_err(|e| tracing::error!("Failed to send GetServerPin command to client manager: {e}"))?;
+
+		response_rx
+			.await
+			.map_err(|e| tracing::error!("Failed to wait for response to GetServerPin command from client manager: {e}"))
+	}
+
+	pub async fn add_client(&self, id: &str, address: IpAddr) -> Result<(), ()> {
 		let (response_tx, response_rx) = oneshot::channel();
 		self.command_tx.send(ClientManagerCommand::AddClient(AddClientCommand {
 			id: id.to_string(),
+			address,
 			response: response_tx,
 		}))
 			.await
@@ -211,10 +349,57 @@ impl ClientManager {
 			.map_err(|e| tracing::warn!("{e}"))
 	}
 
-	pub async fn client_challenge(&self, id: &str, challenge: Vec<u8>) -> Result<Vec<u8>, ()> {
+	/// Look up the paired client with this certificate fingerprint and record that it was seen now, returns `None` if no
+	/// client paired with this certificate.
+	pub async fn client_seen(&self, id: &str) -> Result<Option<ClientInfo>, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::ClientSeen(ClientSeenCommand {
+			id: id.to_string(),
+			response: response_tx,
+		}))
+			.await
+			.map_err(|e| tracing::error!("Failed to send ClientSeen command to client manager: {e}"))?;
+
+		response_rx
+			.await
+			.map_err(|e| tracing::error!("Failed to wait for response to ClientSeen command from client manager: {e}"))?
+			.map_err(|e| tracing::warn!("{e}"))
+	}
+
+	pub async fn list_clients(&self) -> Result<Vec<PairedClient>, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::ListClients(ListClientsCommand { response: response_tx }))
+			.await
+			.map_err(|e| tracing::error!("Failed to send ListClients command to client manager: {e}"))?;
+
+		response_rx
+			.await
+			.map_err(|e| tracing::error!("Failed to wait for response to ListClients command from client manager: {e}"))?
+			.map_err(|e| tracing::warn!("{e}"))
+	}
+
+	/// Give a paired client a name, or remove its name with `None`, returns false if the client isn't paired.
+	pub async fn rename_client(&self, id: &str, name: Option<String>) -> Result<bool, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::RenameClient(RenameClientCommand {
+			id: id.to_string(),
+			name,
+			response: response_tx,
+		}))
+			.await
+			.map_err(|e| tracing::error!("Failed to send RenameClient command to client manager: {e}"))?;
+
+		response_rx
+			.await
+			.map_err(|e| tracing::error!("Failed to wait for response to RenameClient command from client manager: {e}"))?
+			.map_err(|e| tracing::warn!("{e}"))
+	}
+
+	pub async fn client_challenge(&self, id: &str, address: IpAddr, challenge: Vec<u8>) -> Result<Vec<u8>, ()> {
 		let (response_tx, response_rx) = oneshot::channel();
 		self.command_tx.send(ClientManagerCommand::ClientChallenge(ClientChallengeCommand {
 			id: id.to_string(),
+			address,
 			challenge,
 			response: response_tx,
 		}))
@@ -227,10 +412,11 @@ impl ClientManager {
 			.map_err(|e| tracing::warn!("{e}"))
 	}
 
-	pub async fn server_challenge_response(&self, id: &str, challenge_response: Vec<u8>) -> Result<Vec<u8>, ()> {
+	pub async fn server_challenge_response(&self, id: &str, address: IpAddr, challenge_response: Vec<u8>) -> Result<Vec<u8>, ()> {
 		let (response_tx, response_rx) = oneshot::channel();
 		self.command_tx.send(ClientManagerCommand::ServerChallengeResponse(ServerChallengeResponseCommand {
 			id: id.to_string(),
+			address,
 			challenge_response,
 			response: response_tx,
 		}))
@@ -243,10 +429,11 @@ impl ClientManager {
 			.map_err(|e| tracing::warn!("{e}"))
 	}
 
-	pub async fn check_client_pairing_secret(&self, id: &str, client_secret: Vec<u8>) -> Result<(), ()> {
+	pub async fn check_client_pairing_secret(&self, id: &str, address: IpAddr, client_secret: Vec<u8>) -> Result<(), ()> {
 		let (response_tx, response_rx) = oneshot::channel();
 		self.command_tx.send(ClientManagerCommand::CheckClientPairingSecret(CheckClientPairingSecretCommand {
 			id: id.to_string(),
+			address,
 			client_secret,
 			response: response_tx,
 		}))
@@ -276,37 +463,53 @@ impl ClientManager {
 }
 
 struct ClientManagerInner {
-	server_certs: X509,
-	server_pkey: PKey<Private>,
+	server_certs: Certificate,
+	server_pkey: PrivateKey,
+
+	/// Receives an event for every client that finishes pairing.
+	events: EventBus,
 }
 
 impl ClientManagerInner {
 	async fn run(self, mut command_rx: mpsc::Receiver<ClientManagerCommand>, state: State) {
 		tracing::debug!("Waiting for commands.");
 
-		let mut pending_clients = BTreeMap::new();
+		let mut pending_clients = PendingPairings::default();
+		let mut pairing_limiter = PairingLimiter::default();
 		while let Some(command) = command_rx.recv().await {
 			match command {
-				ClientManagerCommand::IsPaired(command) => {
-					match state.has_client(command.id).await {
-						Ok(result) => {
-							command.response.send(Ok(result))
-								.map_err(|_| tracing::error!("Failed to send IsPaired response.")).ok();
-						},
-						Err(()) => {
-							command.response.send(Err("Failed to check client paired status.".to_string()))
-								.map_err(|_| tracing::error!("Failed to send IsPaired response.")).ok();
-						},
+				ClientManagerCommand::StartPairing(command) => {
+					if let Some(remaining) = pairing_limiter.blocked_for(command.pending_client.address) {
+						command.response.send(Err(format!(
+							"Too many failed pairing attempts from {}, try again in {} seconds.",
+							command.pending_client.address,
+							remaining.as_secs() + 1,
+						)))
+							.map_err(|_| tracing::error!("Failed to send StartPairing response.")).ok();
+						continue;
 					}
-				},
 
-				ClientManagerCommand::StartPairing(command) => {
-					pending_clients.insert(command.pending_client.id.clone(), command.pending_client);
+					pending_clients.insert(command.pending_client);
+					command.response.send(Ok(()))
+						.map_err(|_| tracing::error!("Failed to send StartPairing response.")).ok();
 				},
 
 				ClientManagerCommand::RegisterPin(command) => {
-					match pending_clients.get_mut(&command.id) {
-						Some(client) => {
+					let client = match &command.recipient {
+						PinRecipient::Id(id) => pending_clients.find_by_id(id),
+						PinRecipient::Token(token) => Ok(pending_clients.find_by_token(token)),
+					};
+
+					match client {
+						Err(()) => {
+							command.response.send(Err("Multiple clients with this id are pairing, use the link that was shown when pairing started.".to_string()))
+								.map_err(|_| tracing::error!("Failed to send RegisterPin response.")).ok();
+						},
+						Ok(Some(client)) if client.server_pin.is_some() => {
+							command.response.send(Err("The PIN of this pairing attempt was generated by the server, it has to be entered on the client.".to_string()))
+								.map_err(|_| tracing::error!("Failed to send RegisterPin response.")).ok();
+						},
+						Ok(Some(client)) => {
 							let key = match create_key(&client.salt, &command.pin) {
 								Ok(key) => key,
 								Err(e) => {
@@ -317,19 +520,35 @@ impl ClientManagerInner {
 								}
 							};
 							client.key = Some(key);
-							client.pin_notify.notify_waiters();
+							// The link can only be used once.
+							client.pin_token = None;
+							if let Some(pin_tx) = client.pin_tx.take() {
+								// Fails if the pairing request was cancelled by the client.
+								let _ = pin_tx.send(());
+							}
 							command.response.send(Ok(()))
 								.map_err(|_| tracing::error!("Failed to send RegisterPin error.")).ok();
 						},
-						None => {
-							command.response.send(Err(format!("No known client with id {}", command.id)))
+						Ok(None) => {
+							let message = match command.recipient {
+								PinRecipient::Id(id) => format!("No known client with id {id}"),
+								PinRecipient::Token(_) => "Unknown or expired PIN token".to_string(),
+							};
+							command.response.send(Err(message))
 								.map_err(|_| tracing::error!("Failed to send pin notify error.")).ok();
 						},
 					};
 				},
 
+				ClientManagerCommand::GetServerPin(command) => {
+					let pin = pending_clients.find_by_token(&command.token)
+						.and_then(|client| client.server_pin.clone());
+					command.response.send(pin)
+						.map_err(|_| tracing::error!("Failed to send GetServerPin response.")).ok();
+				},
+
 				ClientManagerCommand::ClientChallenge(command) => {
-					match pending_clients.get_mut(&command.id) {
+					match pending_clients.get_mut(&command.id, command.address) {
 						Some(client) => {
 							match self.client_challenge(client, command.challenge).await {
 								Ok(response) => {
@@ -340,6 +559,7 @@ impl ClientManagerInner {
 									tracing::error!("Failed to respond to client challenge: {e}");
 									command.response.send(Err(e))
 										.map_err(|_| tracing::error!("Failed to send ClientChallenge error.")).ok();
+									record_pairing_failure(&mut pending_clients, &mut pairing_limiter, &command.id, command.address);
 									continue;
 								},
 							};
@@ -352,7 +572,7 @@ impl ClientManagerInner {
 				},
 
 				ClientManagerCommand::ServerChallengeResponse(command) => {
-					match pending_clients.get_mut(&command.id) {
+					match pending_clients.get_mut(&command.id, command.address) {
 						Some(client) => {
 							match self.server_challenge_response(client, command.challenge_response).await {
 								Ok(response) => {
@@ -363,6 +583,7 @@ impl ClientManagerInner {
 									tracing::error!("Failed to respond to server challenge: {e}");
 									command.response.send(Err(e))
 										.map_err(|_| tracing::error!("Failed to send ServerChallengeResponse error.")).ok();
+									record_pairing_failure(&mut pending_clients, &mut pairing_limiter, &command.id, command.address);
 									continue;
 								},
 							};
@@ -375,10 +596,12 @@ impl ClientManagerInner {
 				},
 
 				ClientManagerCommand::CheckClientPairingSecret(command) => {
-					match pending_clients.get_mut(&command.id) {
+					match pending_clients.get_mut(&command.id, command.address) {
 						Some(client) => {
 							match check_client_pairing_secret(client, command.client_secret).await {
 								Ok(()) => {
+									pairing_limiter.reset(client.address);
+									client.verified = true;
 									command.response.send(Ok(()))
 										.map_err(|_| tracing::error!("Failed to send CheckClientPairingSecret response.")).ok();
 								},
@@ -386,6 +609,7 @@ impl ClientManagerInner {
 									tracing::error!("Failed to check client pairing secret: {e}");
 									command.response.send(Err(e))
 										.map_err(|_| tracing::error!("Failed to send CheckClientPairingSecret error.")).ok();
+									record_pairing_failure(&mut pending_clients, &mut pairing_limiter, &command.id, command.address);
 									continue;
 								},
 							};
@@ -398,29 +622,82 @@ impl ClientManagerInner {
 				},
 
 				ClientManagerCommand::AddClient(command) => {
-					let Ok(has_client) = state.has_client(command.id.clone()).await else {
+					// The attempt is complete, from here on the client is known through the state.
+					let Some(client) = pending_clients.remove(&command.id, command.address) else {
+						command.response.send(Err(format!("No known client with id {}", command.id)))
+							.map_err(|_| tracing::error!("Failed to send AddClient command response.")).ok();
+						continue;
+					};
+					if !client.verified {
+						command.response.send(Err("Client did not complete pairing, can't add it.".to_string()))
+							.map_err(|_| tracing::error!("Failed to send AddClient command response.")).ok();
+						continue;
+					}
+
+					// Every Moonlight client sends the same unique id, the certificate it paired with is what tells it apart.
+					let fingerprint = match crypto::certificate_fingerprint(&client.pem) {
+						Ok(fingerprint) => fingerprint,
+						Err(e) => {
+							command.response.send(Err(format!("Failed to get fingerprint of client certificate: {e}")))
+								.map_err(|_| tracing::error!("Failed to send AddClient command response.")).ok();
+							continue;
+						},
+					};
+
+					let Ok(has_client) = state.has_client(fingerprint.clone()).await else {
 						command.response.send(Err("Failed to check client paired status.".to_string()))
 							.map_err(|_| tracing::error!("Failed to send AddClient command response.")).ok();
 						continue;
 					};
 
+					// A client that pairs again with the same certificate keeps its name and history.
 					if has_client {
-						command.response.send(Err("Client is already paired, can't add it again.".to_string()))
+						tracing::info!("Client with certificate {fingerprint} paired again.");
+						command.response.send(Ok(()))
 							.map_err(|_| tracing::error!("Failed to send AddClient command response.")).ok();
 						continue;
 					}
 
-					if let Err(()) = state.add_client(command.id).await {
+					let info = ClientInfo {
+						device_name: client.device_name,
+						client_type: client.client_type,
+						..Default::default()
+					};
+					let name = info.display_name().unwrap_or(&fingerprint).to_string();
+					if let Err(()) = state.add_client(fingerprint.clone(), info).await {
 						command.response.send(Err("Failed to add client.".to_string()))
 							.map_err(|_| tracing::error!("Failed to send AddClient command response.")).ok();
 					} else {
+						tracing::info!("Paired client '{name}'.");
+						self.events.publish(SessionEvent::ClientPaired { client_id: fingerprint.into() });
 						command.response.send(Ok(()))
 							.map_err(|_| tracing::error!("Failed to send AddClient command response.")).ok();
 					}
 				},
 
+				ClientManagerCommand::ClientSeen(command) => {
+					let result = state.client_seen(command.id).await
+						.map_err(|()| "Failed to look up client.".to_string());
+					command.response.send(result)
+						.map_err(|_| tracing::error!("Failed to send ClientSeen command response.")).ok();
+				},
+
+				ClientManagerCommand::ListClients(command) => {
+					let result = state.get_clients().await
+						.map_err(|()| "Failed to list clients.".to_string());
+					command.response.send(result)
+						.map_err(|_| tracing::error!("Failed to send ListClients command response.")).ok();
+				},
+
+				ClientManagerCommand::RenameClient(command) => {
+					let result = state.rename_client(command.id, command.name).await
+						.map_err(|()| "Failed to rename client.".to_string());
+					command.response.send(result)
+						.map_err(|_| tracing::error!("Failed to send RenameClient command response.")).ok();
+				},
+
 				// ClientManagerCommand::RemoveClient(command) => {
-				// 	pending_clients.remove(&command.id);
+				// 	pending_clients.remove_all(&command.id);
 				// 	let Ok(result) = state.remove_client(command.id).await else {
 				// 		command.response.send(Err("Failed to remove client.".to_string()))
 				// 			.map_err(|_| tracing::error!("Failed to send RemoveClient command response.")).ok();
@@ -451,28 +728,25 @@ impl ClientManagerInner {
 		};
 
 		// Generate a random server secret.
-		let mut server_secret = [0u8; 16];
-		openssl::rand::rand_bytes(&mut server_secret)
+		let server_secret = crypto::random_bytes::<16>()
 			.map_err(|e| format!("Failed to create random server secret: {e}"))?;
 		client.server_secret = Some(server_secret);
 
-		let mut decrypted = decrypt(Cipher::aes_128_ecb(), &challenge, key)
+		let mut decrypted = crypto::decrypt_ecb(key, &challenge)
 			.map_err(|e| format!("Failed to decrypt client challenge: {e}"))?;
-		decrypted.extend_from_slice(self.server_certs.signature().as_slice());
+		decrypted.extend_from_slice(self.server_certs.signature());
 		decrypted.extend_from_slice(&server_secret);
 
-		let mut server_challenge = [0u8; 16];
-		openssl::rand::rand_bytes(&mut server_challenge)
+		let server_challenge = crypto::random_bytes::<16>()
 			.map_err(|e| format!("Failed to create random server challenge: {e}"))?;
 		client.server_challenge = Some(server_challenge);
 
-		let mut challenge_response = openssl::hash::hash(MessageDigest::sha256(), decrypted.as_slice())
+		let mut challenge_response = crypto::hash(&decrypted)
 			.map_err(|e| format!("Failed to hash client challenge response: {e}"))?
 			.to_vec();
 		challenge_response.extend(server_challenge);
 
-		let cipher = Cipher::aes_128_ecb();
-		let challenge_response = encrypt(cipher, &challenge_response, Some(key), None, false)
+		let challenge_response = crypto::encrypt_ecb(key, &challenge_response)
 			.map_err(|e| format!("Failed to encrypt client challenge response: {e}"))?;
 
 		Ok(challenge_response)
@@ -490,7 +764,7 @@ impl ClientManagerInner {
 			}
 		};
 
-		let decrypted = decrypt(Cipher::aes_128_ecb(), &challenge_response, key)
+		let decrypted = crypto::decrypt_ecb(key, &challenge_response)
 			.map_err(|e| format!("Failed to decrypt server challenge response: {e}"))?;
 		client.client_hash = Some(decrypted);
 
@@ -498,7 +772,7 @@ impl ClientManagerInner {
 			.ok_or("Client does not have a server secret.".to_string())?;
 
 		let mut pairing_secret = server_secret.to_vec();
-		let signed = sign(&server_secret, &self.server_pkey)
+		let signed = crypto::sign(&server_secret, &self.server_pkey)
 			.map_err(|e| format!("Failed to sign server secret: {e}"))?;
 		pairing_secret.extend(signed);
 
@@ -506,30 +780,156 @@ impl ClientManagerInner {
 	}
 }
 
-fn create_key(salt: &[u8; 16], pin: &str) -> Result<[u8; 16], String> {
-	let mut key = Vec::with_capacity(salt.len() + pin.len());
-	key.extend(salt);
-	key.extend(pin.as_bytes());
-	openssl::hash::hash(MessageDigest::sha256(), &key)
-		.map_err(|e| format!("Failed to hash key for client: {e}"))?
-		.to_vec()[..16]
-		.try_into()
-		.map_err(|e| format!("Received unexpected key result: {e}"))
+/// Pairing attempts that are in progress, kept apart from the paired clients in the state.
+///
+/// All Moonlight clients use the same unique id, so attempts are identified by the id together with the address they
+/// were started from. That way clients on different devices can pair at the same time.
+#[derive(Default)]
+struct PendingPairings {
+	attempts: HashMap<(String, IpAddr), PendingClient>,
 }
 
-fn sign<T>(data: &[u8], key: &PKeyRef<T>) -> Result<Vec<u8>, openssl::error::ErrorStack>
-	where T: openssl::pkey::HasPrivate
-{
-	// Create the signature.
-	let mut context = MdCtx::new()?;
-	context.digest_sign_init(Some(Md::sha256()), key)?;
-	context.digest_sign_update(data)?;
+impl PendingPairings {
+	/// Add an attempt, replacing an earlier attempt of the same client from the same address.
+	///
+	/// Dropping the replaced attempt closes its PIN channel, so a request still waiting for its PIN fails instead of hanging.
+	fn insert(&mut self, client: PendingClient) {
+		if let Some(previous) = self.attempts.insert((client.id.clone(), client.address), client) {
+			tracing::info!("Pairing attempt for client {} from {} was replaced by a new attempt.", previous.id, previous.address);
+		}
+	}
 
-	// let mut signature = [0u8; 256];
-	let mut signature = Vec::new();
-	context.digest_sign_final_to_vec(&mut signature)?;
+	fn get_mut(&mut self, id: &str, address: IpAddr) -> Option<&mut PendingClient> {
+		self.attempts.get_mut(&(id.to_string(), address))
+	}
 
-	Ok(signature)
+	fn remove(&mut self, id: &str, address: IpAddr) -> Option<PendingClient> {
+		self.attempts.remove(&(id.to_string(), address))
+	}
+
+	/// Find the attempt with this unexpired PIN token.
+	fn find_by_token(&mut self, token: &str) -> Option<&mut PendingClient> {
+		self.attempts.values_mut().find(|client| client.has_pin_token(token))
+	}
+
+	/// Find the attempt of the client with this id, fails if several attempts use the id.
+	fn find_by_id(&mut self, id: &str) -> Result<Option<&mut PendingClient>, ()> {
+		let mut clients = self.attempts.values_mut().filter(|client| client.id == id);
+		let client = clients.next();
+		if clients.next().is_some() {
+			return Err(());
+		}
+
+		Ok(client)
+	}
+}
+
+/// Failed pairing attempts from a single address.
+struct FailedPairingAttempts {
+	/// Number of consecutive failures.
+	count: u32,
+
+	/// Moment until which new pairing attempts are rejected.
+	blocked_until: Instant,
+}
+
+/// Limits pairing attempts from addresses that failed pairing before, to prevent brute-forcing the PIN.
+///
+/// Attempts are tracked by address, because all Moonlight clients use the same unique id.
+#[derive(Default)]
+struct PairingLimiter {
+	failures: HashMap<IpAddr, FailedPairingAttempts>,
+}
+
+impl PairingLimiter {
+	/// Returns how long the address is still blocked from pairing, if at all.
+	fn blocked_for(&mut self, address: IpAddr) -> Option<Duration> {
+		self.prune();
+
+		let failures = self.failures.get(&address)?;
+		let now = Instant::now();
+		if failures.blocked_until > now {
+			Some(failures.blocked_until - now)
+		} else {
+			None
+		}
+	}
+
+	/// Register a failure for an address, returns the number of consecutive failures.
+	fn record_failure(&mut self, address: IpAddr) -> u32 {
+		self.prune();
+
+		let failures = self.failures.entry(address).or_insert(FailedPairingAttempts { count: 0, blocked_until: Instant::now() });
+		failures.count += 1;
+
+		let backoff = PAIRING_BACKOFF_BASE
+			.saturating_mul(2u32.saturating_pow(failures.count - 1))
+			.min(PAIRING_BACKOFF_MAX);
+		failures.blocked_until = Instant::now() + backoff;
+		tracing::debug!("Blocking pairing attempts from {address} for {} seconds.", backoff.as_secs());
+
+		failures.count
+	}
+
+	/// Forget all failures for an address.
+	fn reset(&mut self, address: IpAddr) {
+		self.failures.remove(&address);
+	}
+
+	/// Forget the failures of addresses whose backoff expired a while ago, so failures from many addresses don't pile up.
+	///
+	/// Failures are kept for a while after the backoff expires, so consecutive failures keep increasing the backoff.
+	fn prune(&mut self) {
+		let now = Instant::now();
+		self.failures.retain(|_, failures| failures.blocked_until + PAIRING_FAILURE_RETENTION > now);
+	}
+}
+
+/// Register a failed pairing step for a client.
+///
+/// The pairing attempt is invalidated after too many failures, repeated failures from the same address are reported to the user.
+fn record_pairing_failure(
+	pending_clients: &mut PendingPairings,
+	pairing_limiter: &mut PairingLimiter,
+	id: &str,
+	address: IpAddr,
+) {
+	let Some(client) = pending_clients.get_mut(id, address) else {
+		return;
+	};
+
+	client.failed_attempts += 1;
+	if client.failed_attempts >= MAX_FAILED_ATTEMPTS {
+		tracing::warn!("Pairing attempt for client {id} from {address} failed {} times, invalidating it.", client.failed_attempts);
+		pending_clients.remove(id, address);
+	}
+
+	let address_failures = pairing_limiter.record_failure(address);
+	if address_failures >= MAX_FAILED_ATTEMPTS {
+		tracing::warn!("Pairing from {address} failed {address_failures} times in a row.");
+		let _ = std::thread::Builder::new().name("pairing-failure-notification".to_string()).spawn(move || {
+			Notification::new()
+				.appname("Moonshine")
+				.summary("Repeated failed pairing attempts.")
+				.body(&format!("Pairing from {address} failed {address_failures} times in a row."))
+				.show()
+				.map(|_| ())
+				.map_err(|e| tracing::warn!("Failed to show pairing failure notification: {e}"))
+		});
+	}
+}
+
+/// Derive the key that encrypts the pairing challenges from the salt of the client and the PIN.
+pub fn create_key(salt: &[u8; 16], pin: &str) -> Result<[u8; 16], String> {
+	let mut key = Vec::with_capacity(salt.len() + pin.len());
+	key.extend(salt);
+	key.extend(pin.as_bytes());
+	let hash = crypto::hash(&key)
+		.map_err(|e| format!("Failed to hash key for client: {e}"))?;
+
+	let mut key = [0u8; 16];
+	key.copy_from_slice(&hash[..16]);
+	Ok(key)
 }
 
 async fn check_client_pairing_secret(client: &mut PendingClient, client_secret: Vec<u8>) -> Result<(), String> {
@@ -548,23 +948,25 @@ async fn check_client_pairing_secret(client: &mut PendingClient, client_secret:
 		None => return Err("Client does not have a server challenge, possibly incorrect pairing procedure?".to_string()),
 	};
 
-	let client_secret = &client_secret[..16];
-	// let signed_client_secret = &client_pairing_secret[16..];
+	let (client_secret, client_signature) = client_secret.split_at(16);
 
 	let mut data = server_challenge.to_vec();
-	data.extend(client.pem.signature().as_slice());
+	data.extend(client.pem.signature());
 	data.extend(client_secret);
 
-	let data = match openssl::hash::hash(MessageDigest::sha256(), &data) {
-		Ok(data) => data,
-		Err(e) => {
-			return Err(format!("Failed to hash secret: {e}"));
-		}
-	};
+	let data = crypto::hash(&data)
+		.map_err(|e| format!("Failed to hash secret: {e}"))?;
 
-	if !data.to_vec().eq(client_hash) {
+	if !crypto::secrets_equal(&data, client_hash) {
 		return Err("Client hash is not as expected, MITM?".to_string());
 	}
 
+	// The client signs its secret with the key of the certificate it sent when pairing started.
+	let valid = crypto::verify(client_secret, client_signature, &client.pem)
+		.map_err(|e| format!("Failed to verify client pairing secret: {e}"))?;
+	if !valid {
+		return Err("Client pairing secret is not signed by the client certificate.".to_string());
+	}
+
 	Ok(())
 }
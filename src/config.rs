@@ -1,4 +1,4 @@
-use std::{path::{PathBuf, Path}, collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
+use std::{path::{PathBuf, Path}, collections::{hash_map::DefaultHasher, HashMap}, hash::{Hash, Hasher}, net::IpAddr, str::FromStr};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -6,7 +6,7 @@ pub struct Config {
 	/// Name of the Moonshine host.
 	pub name: String,
 
-	/// Address to bind to.
+	/// Address to bind to, either an IP address (`0.0.0.0` or `::` for all addresses), a network interface or a hostname.
 	pub address: String,
 
 	/// Configuration for the webserver.
@@ -25,7 +25,62 @@ pub struct Config {
 	pub application_scanners: Vec<ApplicationScannerConfig>,
 
 	/// Time in seconds since last ping after which the stream closes.
+	///
+	/// Can be overridden per application and per client, see [`Config::stream_timeout_for`].
 	pub stream_timeout: u64,
+
+	/// How the host warns about and tries to prevent stream timeouts.
+	#[serde(default)]
+	pub keepalive: KeepaliveConfig,
+
+	/// Time in seconds that an application is kept running after its stream stopped.
+	///
+	/// Launching the same application within this period reattaches to the running application.
+	/// A value of 0 stops the application as soon as the stream stops.
+	#[serde(default)]
+	pub session_grace_period: u64,
+
+	/// If provided, limit how much data is streamed to clients per month.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub bandwidth_cap: Option<BandwidthCapConfig>,
+
+	/// Configuration for clients that join a running session to play along.
+	#[serde(default)]
+	pub coop: CoopConfig,
+
+	/// Configuration for integrations with the host desktop.
+	#[serde(default)]
+	pub host: HostConfig,
+
+	/// Configuration for network access.
+	#[serde(default)]
+	pub network: NetworkConfig,
+
+	/// If set, a transcript of the messages exchanged with clients is written to this directory for every session.
+	///
+	/// Secrets are redacted from the transcript, so that it can be attached to bug reports.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub transcript_directory: Option<PathBuf>,
+
+	/// Configuration for log files and the logs kept in memory.
+	#[serde(default)]
+	pub log: LogConfig,
+
+	/// If set, session events are written as lines of JSON to every process that connects to this Unix socket.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub event_socket: Option<PathBuf>,
+
+	/// Configuration for the threads the host runs on.
+	#[serde(default)]
+	pub runtime: RuntimeConfig,
+
+	/// If provided, browsers can watch the stream over WebRTC, this requires the `webrtc` feature.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub webrtc: Option<WebRtcConfig>,
+
+	/// Profiles that translate input of clients, by name, applications use one through their `input_profile`.
+	#[serde(rename = "input_profile", default, skip_serializing_if = "HashMap::is_empty")]
+	pub input_profiles: HashMap<String, InputProfileConfig>,
 }
 
 impl Config {
@@ -38,6 +93,16 @@ impl Config {
 
 		Ok(config)
 	}
+
+	/// Stream timeout for a client streaming an application, a timeout for the client takes precedence over a timeout
+	/// for the application, which takes precedence over `stream_timeout`.
+	///
+	/// Clients are identified by their certificate fingerprint, every Moonlight client sends the same unique id.
+	pub fn stream_timeout_for(&self, client_id: &str, application: &ApplicationConfig) -> u64 {
+		self.keepalive.client_timeouts.get(client_id).copied()
+			.or(application.stream_timeout)
+			.unwrap_or(self.stream_timeout)
+	}
 }
 
 impl Default for Config {
@@ -57,10 +122,18 @@ impl Default for Config {
 							"{height}".to_string(),
 						],
 					]),
+					command: None,
 					run_after: Some(vec![
 						vec!["$HOME/.local/bin/resolution".to_string()],
 					]),
 					boxart: None,
+					privacy_mode: None,
+					monitor: None,
+					gamescope: false,
+					wrapper: None,
+					stream_timeout: None,
+					input_profile: None,
+					terminate_on_cancel: None,
 				},
 
 				ApplicationConfig {
@@ -71,15 +144,22 @@ impl Default for Config {
 							"{width}".to_string(),
 							"{height}".to_string(),
 						],
-						vec![
-							"/usr/bin/steam".to_string(),
-							"steam://open/bigpicture".to_string(),
-						],
+					]),
+					command: Some(vec![
+						"/usr/bin/steam".to_string(),
+						"steam://open/bigpicture".to_string(),
 					]),
 					run_after: Some(vec![
 						vec!["$HOME/.local/bin/resolution".to_string()],
 					]),
 					boxart: None,
+					privacy_mode: None,
+					monitor: None,
+					gamescope: false,
+					wrapper: None,
+					stream_timeout: None,
+					input_profile: None,
+					terminate_on_cancel: None,
 				},
 			],
 			application_scanners: vec![
@@ -95,10 +175,10 @@ impl Default for Config {
 							"/usr/bin/steam".to_string(),
 							"steam://open/bigpicture".to_string(),
 						],
-						vec![
-							"/usr/bin/steam".to_string(),
-							"steam://rungameid/{game_id}".to_string(),
-						],
+					]),
+					command: Some(vec![
+						"/usr/bin/steam".to_string(),
+						"steam://rungameid/{game_id}".to_string(),
 					]),
 					run_after: Some(vec![
 						vec!["$HOME/.local/bin/resolution".to_string()],
@@ -106,10 +186,87 @@ impl Default for Config {
 				}),
 			],
 			stream_timeout: 60,
+			keepalive: Default::default(),
+			session_grace_period: 0,
+			bandwidth_cap: None,
+			coop: Default::default(),
+			host: Default::default(),
+			network: Default::default(),
+			transcript_directory: None,
+			log: Default::default(),
+			event_socket: None,
+			runtime: Default::default(),
+			webrtc: None,
+			input_profiles: HashMap::new(),
 		}
 	}
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogConfig {
+	/// If provided, logs are also written to this file, rotated files get a numbered suffix (`moonshine.log.1`).
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub file: Option<PathBuf>,
+
+	/// Size in megabytes after which the log file is rotated.
+	#[serde(default = "default_log_max_size")]
+	pub max_size: u64,
+
+	/// If provided, the log file is also rotated every this many hours.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub rotation_interval: Option<u64>,
+
+	/// Number of rotated log files to keep, older files are deleted.
+	#[serde(default = "default_log_max_files")]
+	pub max_files: usize,
+
+	/// Number of recent log records that are kept in memory, so they can be retrieved through the API.
+	#[serde(default = "default_log_buffer_size")]
+	pub buffer_size: usize,
+}
+
+impl Default for LogConfig {
+	fn default() -> Self {
+		Self {
+			file: None,
+			max_size: default_log_max_size(),
+			rotation_interval: None,
+			max_files: default_log_max_files(),
+			buffer_size: default_log_buffer_size(),
+		}
+	}
+}
+
+/// Configuration of the async runtime, the defaults suit most hosts.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+	/// Number of worker threads of the runtime, by default one per CPU core.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub worker_threads: Option<usize>,
+
+	/// Maximum number of threads for blocking work, such as file access and probing encoders, by default 512.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_blocking_threads: Option<usize>,
+
+	/// If provided, the webserver runs on a runtime of its own with this many worker threads.
+	///
+	/// On hosts with few cores this keeps HTTP requests, such as clients polling the server info, from delaying the streams.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub web_worker_threads: Option<usize>,
+}
+
+fn default_log_max_size() -> u64 {
+	10
+}
+
+fn default_log_max_files() -> usize {
+	5
+}
+
+fn default_log_buffer_size() -> usize {
+	10000
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WebserverConfig {
 	/// Port of the webserver.
@@ -123,6 +280,14 @@ pub struct WebserverConfig {
 
 	/// Path to the private key for SSL encryption.
 	pub private_key: PathBuf,
+
+	/// Whether the live session dashboard can be opened from other devices, by default it is only available on the host itself.
+	#[serde(default)]
+	pub remote_dashboard: bool,
+
+	/// Configuration for pairing new clients.
+	#[serde(default)]
+	pub pairing: PairingConfig,
 }
 
 impl Default for WebserverConfig {
@@ -132,10 +297,400 @@ impl Default for WebserverConfig {
 			port_https: 47984,
 			certificate: "$HOME/.config/moonshine/cert.pem".into(),
 			private_key: "$HOME/.config/moonshine/key.pem".into(),
+			remote_dashboard: false,
+			pairing: Default::default(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PairingConfig {
+	/// Which side generates the PIN of a pairing attempt.
+	#[serde(default)]
+	pub pin: PinMode,
+
+	/// Where a PIN generated by the server is shown.
+	#[serde(default = "default_pin_display")]
+	pub pin_display: Vec<PinDisplay>,
+}
+
+impl Default for PairingConfig {
+	fn default() -> Self {
+		Self { pin: Default::default(), pin_display: default_pin_display() }
+	}
+}
+
+fn default_pin_display() -> Vec<PinDisplay> {
+	vec![PinDisplay::Log, PinDisplay::Notification, PinDisplay::Web]
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PinMode {
+	/// The client shows a PIN, which is entered on the host.
+	#[default]
+	Client,
+
+	/// The host generates a PIN for every pairing attempt and shows it, the PIN is entered on the client.
+	Server,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PinDisplay {
+	/// Write the PIN to the log.
+	Log,
+
+	/// Show the PIN in a desktop notification.
+	Notification,
+
+	/// Show the PIN on the page that the pairing link opens.
+	Web,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NetworkConfig {
+	/// If not empty, only clients with an address in one of these subnets (ie. `192.168.1.0/24`) can pair or stream.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub allowed_subnets: Vec<Subnet>,
+
+	/// DSCP values that stream packets are marked with, so routers can prioritize them.
+	#[serde(default)]
+	pub qos: QosConfig,
+
+	/// Address that clients outside the local network reach the host on, advertised to those clients instead of the local address.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub external_address: Option<IpAddr>,
+
+	/// If set and no external address is configured, the external address is detected through this STUN server.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub stun_server: Option<String>,
+}
+
+/// DSCP values (0-63) of outgoing stream packets, 0 leaves the packets of that socket unmarked.
+///
+/// Video and audio packets are only marked when the client asks for QoS.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QosConfig {
+	/// DSCP of video packets, AF41 by default.
+	#[serde(default = "default_qos_video")]
+	pub video: u8,
+
+	/// DSCP of audio packets, EF by default.
+	#[serde(default = "default_qos_audio")]
+	pub audio: u8,
+
+	/// DSCP of control packets, which carry input, EF by default.
+	#[serde(default = "default_qos_control")]
+	pub control: u8,
+}
+
+impl Default for QosConfig {
+	fn default() -> Self {
+		Self {
+			video: default_qos_video(),
+			audio: default_qos_audio(),
+			control: default_qos_control(),
+		}
+	}
+}
+
+impl QosConfig {
+	/// Convert a DSCP value to the value of the IP TOS field, which holds the DSCP in its upper six bits.
+	pub fn tos(dscp: u8) -> u32 {
+		(dscp as u32 & 0x3f) << 2
+	}
+}
+
+fn default_qos_video() -> u8 {
+	34
+}
+
+fn default_qos_audio() -> u8 {
+	46
+}
+
+fn default_qos_control() -> u8 {
+	46
+}
+
+impl NetworkConfig {
+	/// Check whether a client with this address is allowed to connect.
+	pub fn is_allowed(&self, address: IpAddr) -> bool {
+		self.allowed_subnets.is_empty()
+			|| self.allowed_subnets.iter().any(|subnet| subnet.contains(address))
+	}
+}
+
+/// A range of IP addresses in CIDR notation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Subnet {
+	/// Network address of the subnet.
+	pub address: IpAddr,
+
+	/// Number of leading bits of the address that identify the network.
+	pub prefix_length: u8,
+}
+
+impl Subnet {
+	pub fn contains(&self, address: IpAddr) -> bool {
+		// IPv4 clients connecting to an IPv6 socket show up as IPv4-mapped IPv6 addresses.
+		match (self.address, address.to_canonical()) {
+			(IpAddr::V4(network), IpAddr::V4(address)) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix_length as u32).unwrap_or(0);
+				u32::from(network) & mask == u32::from(address) & mask
+			},
+			(IpAddr::V6(network), IpAddr::V6(address)) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix_length as u32).unwrap_or(0);
+				u128::from(network) & mask == u128::from(address) & mask
+			},
+			_ => false,
+		}
+	}
+}
+
+impl FromStr for Subnet {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let (address, prefix_length) = match value.split_once('/') {
+			Some((address, prefix_length)) => (address, Some(prefix_length)),
+			None => (value, None),
+		};
+
+		let address: IpAddr = address.parse()
+			.map_err(|e| format!("Invalid address in subnet '{value}': {e}"))?;
+		let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+		let prefix_length = match prefix_length {
+			Some(prefix_length) => prefix_length.parse()
+				.map_err(|e| format!("Invalid prefix length in subnet '{value}': {e}"))?,
+			None => max_prefix_length,
+		};
+		if prefix_length > max_prefix_length {
+			return Err(format!("Prefix length in subnet '{value}' can be at most {max_prefix_length}."));
+		}
+
+		Ok(Self { address, prefix_length })
+	}
+}
+
+impl TryFrom<String> for Subnet {
+	type Error = String;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		value.parse()
+	}
+}
+
+impl From<Subnet> for String {
+	fn from(subnet: Subnet) -> Self {
+		format!("{}/{}", subnet.address, subnet.prefix_length)
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BandwidthCapConfig {
+	/// Number of gigabytes (10^9 bytes) that can be streamed per calendar month (UTC).
+	pub monthly_limit: u64,
+
+	/// What happens when a client launches a session after the limit is reached.
+	#[serde(default)]
+	pub action: BandwidthCapAction,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebRtcConfig {
+	/// Token that viewers send as bearer token, offers without it are rejected.
+	pub token: String,
+
+	/// STUN and TURN servers (ie. `stun:stun.l.google.com:19302`), for viewers outside the local network.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub ice_servers: Vec<String>,
+
+	/// Maximum number of viewers that watch at the same time.
+	#[serde(default = "default_webrtc_max_viewers")]
+	pub max_viewers: usize,
+}
+
+fn default_webrtc_max_viewers() -> usize {
+	4
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BandwidthCapAction {
+	/// Log a warning, but start the session anyway.
+	#[default]
+	Warn,
+
+	/// Refuse to start new sessions until the next month.
+	Refuse,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HostConfig {
+	/// Suppress host notifications while a session is running, they would otherwise show up in the stream.
+	#[serde(default)]
+	pub do_not_disturb: bool,
+
+	/// Disable the X11 compositor while a session is running, it adds a frame of latency to captured frames.
+	#[serde(default)]
+	pub optimize_compositor: bool,
+
+	/// Mute the speakers of the host while a session is running, the client still receives the audio.
+	///
+	/// Same as `audio_routing = "stream"`, which takes precedence if it is set to anything but `both`.
+	#[serde(default)]
+	pub mute_audio: bool,
+
+	/// Where audio plays while a session is running.
+	#[serde(default)]
+	pub audio_routing: AudioRouting,
+
+	/// Keep the host from suspending and the screen from locking while a session is running.
+	#[serde(default)]
+	pub inhibit_sleep: bool,
+
+	/// How keyboard and mouse input of clients is injected on the host.
+	#[serde(default)]
+	pub input_backend: InputBackendKind,
+
+	/// Emit the input events of packets that arrive together with a single `SYN_REPORT`, instead of one per packet.
+	///
+	/// This lowers the load on the compositor under heavy input, at the cost of some latency. Only applies to uinput.
+	#[serde(default)]
+	pub batch_input: bool,
+
+	/// Inject input on a dedicated thread instead of a task of the async runtime, so it never waits for other tasks.
+	#[serde(default)]
+	pub input_thread: bool,
+}
+
+/// Translates gamepad input to keyboard and mouse input and the other way around, for applications that only support one of them.
+///
+/// Controls that are translated no longer reach the device they came from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InputProfileConfig {
+	/// Gamepad controls that press a key or mouse button instead, for example `a = { key = "Space" }`.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub buttons: HashMap<GamepadControl, InputTarget>,
+
+	/// Keys that press a gamepad control instead, by their name (ie. `W`, `Space` or `LeftShift`).
+	///
+	/// Translated keys are pressed on a virtual gamepad of their own, which requires the `uinput` feature.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub keys: HashMap<String, GamepadControl>,
+
+	/// Fraction of its range a stick or trigger has to move before it counts as pressed.
+	#[serde(default = "default_axis_threshold")]
+	pub axis_threshold: f32,
+}
+
+fn default_axis_threshold() -> f32 {
+	0.5
+}
+
+/// A button of a gamepad, or the direction of a stick or a trigger that is treated like a button.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadControl {
+	A,
+	B,
+	X,
+	Y,
+	Up,
+	Down,
+	Left,
+	Right,
+	Start,
+	Select,
+	Home,
+	LeftShoulder,
+	RightShoulder,
+	LeftStickClick,
+	RightStickClick,
+	LeftTrigger,
+	RightTrigger,
+	LeftStickUp,
+	LeftStickDown,
+	LeftStickLeft,
+	LeftStickRight,
+	RightStickUp,
+	RightStickDown,
+	RightStickLeft,
+	RightStickRight,
+}
+
+/// What a translated gamepad control presses on the host.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputTarget {
+	/// A key, by its name (ie. `W`, `Space` or `LeftShift`).
+	Key(String),
+
+	/// A mouse button.
+	MouseButton(MappedMouseButton),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappedMouseButton {
+	Left,
+	Middle,
+	Right,
+	Side,
+	Extra,
+}
+
+impl HostConfig {
+	/// Where audio plays for a client, `local_audio` is whether the client asked to keep playing audio on the host.
+	pub fn audio_routing_for(&self, local_audio: bool) -> AudioRouting {
+		match self.audio_routing {
+			AudioRouting::Client if local_audio => AudioRouting::Both,
+			AudioRouting::Client => AudioRouting::Stream,
+			AudioRouting::Both if self.mute_audio => AudioRouting::Stream,
+			routing => routing,
 		}
 	}
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioRouting {
+	/// Play audio on the host and stream it to the client.
+	#[default]
+	Both,
+
+	/// Only stream audio to the client, the speakers of the host are muted.
+	Stream,
+
+	/// Only play audio on the host, no audio is streamed to the client.
+	Host,
+
+	/// Follow the "play audio on host" option of the client, which is `both` if it is enabled and `stream` if it is not.
+	Client,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputBackendKind {
+	/// Virtual devices through `/dev/uinput`, which works everywhere but needs write access to it.
+	#[default]
+	Uinput,
+
+	/// libei through the RemoteDesktop portal, for Wayland sessions without access to `/dev/uinput`.
+	///
+	/// Requires building with the `libei` feature.
+	Libei,
+
+	/// `SendInput`, on Windows hosts.
+	SendInput,
+
+	/// Ignore input from clients, for hosts without a supported way to inject input.
+	Disabled,
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ApplicationConfig {
 	/// Title of the application.
@@ -149,10 +704,65 @@ pub struct ApplicationConfig {
 	/// Note that multiple entries can be provided, in which case they will be executed in that same order.
 	pub run_before: Option<Vec<Vec<String>>>,
 
+	/// If provided, run this command as the application itself, after the `run_before` commands.
+	///
+	/// Unlike the `run_before` commands the application is tracked: it runs in its own process group that is terminated
+	/// when the session ends, and the session ends when it exits.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub command: Option<Vec<String>>,
+
 	/// If provided, run this command after stopping this application.
 	///
 	/// Note that multiple entries can be provided, in which case they will be executed in that same order.
 	pub run_after: Option<Vec<Vec<String>>>,
+
+	/// If provided, hide the physical host display while this application is running.
+	pub privacy_mode: Option<PrivacyMode>,
+
+	/// If provided, stream the monitor connected to this output (for example "DP-1") instead of `stream.video.capture`.
+	pub monitor: Option<String>,
+
+	/// Run the application (its `command`) in gamescope at the resolution and refresh rate of the client, and stream the
+	/// gamescope output instead of `stream.video.capture`.
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub gamescope: bool,
+
+	/// If provided, run the application (its `command`) through this wrapper.
+	pub wrapper: Option<LaunchWrapper>,
+
+	/// If provided, use this stream timeout instead of `stream_timeout` while streaming this application.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub stream_timeout: Option<u64>,
+
+	/// If provided, translate the input of clients with this profile from `input_profile` while streaming this application.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub input_profile: Option<String>,
+
+	/// Whether to terminate the application when a client quits it, the default. Otherwise quitting only ends the
+	/// session, and the application keeps running on the host.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub terminate_on_cancel: Option<bool>,
+}
+
+/// Wrapper to run an application with, for applications that are not installed on the host directly.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LaunchWrapper {
+	/// Run a Flatpak, the command starts with the application id (ie. `["com.valvesoftware.Steam"]`).
+	Flatpak,
+
+	/// Run a container with Podman, the command starts with the image to run.
+	Podman,
+
+	/// Prefix the command with these arguments.
+	Custom(Vec<String>),
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyMode {
+	/// Black out the physical monitors, what is captured is unaffected.
+	Blank,
 }
 
 impl ApplicationConfig {
@@ -161,14 +771,22 @@ impl ApplicationConfig {
 		self.title.hash(&mut hasher);
 		hasher.finish() as i32
 	}
+
+	/// Whether quitting the application from a client terminates it, see [`ApplicationConfig::terminate_on_cancel`].
+	pub fn terminates_on_cancel(&self) -> bool {
+		self.terminate_on_cancel.unwrap_or(true)
+	}
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum ApplicationScannerConfig {
-	/// Scans a 'libraryfolders.vdf' file from a Steam library directory.
+	/// Scans the game manifests of all library folders in a Steam installation.
 	Steam(SteamApplicationScannerConfig),
+
+	/// Scans directories with '.desktop' files of installed applications.
+	Desktop(DesktopApplicationScannerConfig),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -181,6 +799,10 @@ pub struct SteamApplicationScannerConfig {
 	/// Note that multiple entries can be provided, in which case they will be executed in that same order.
 	pub run_before: Option<Vec<Vec<String>>>,
 
+	/// If provided, start the game with this command, otherwise the game is started with `steam steam://run/{game_id}`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub command: Option<Vec<String>>,
+
 	/// If provided, run this command after stopping an application.
 	///
 	/// Note that multiple entries can be provided, in which case they will be executed in that same order.
@@ -188,6 +810,40 @@ pub struct SteamApplicationScannerConfig {
 
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DesktopApplicationScannerConfig {
+	/// Directories containing '.desktop' files, earlier directories take precedence.
+	#[serde(default = "default_desktop_directories")]
+	pub directories: Vec<PathBuf>,
+
+	/// Only add applications in at least one of these categories, or all applications if empty.
+	#[serde(default = "default_desktop_categories")]
+	pub categories: Vec<String>,
+
+	/// If provided, run this command before starting an application, the application itself is started after these commands.
+	///
+	/// Note that multiple entries can be provided, in which case they will be executed in that same order.
+	pub run_before: Option<Vec<Vec<String>>>,
+
+	/// If provided, run this command after stopping an application.
+	///
+	/// Note that multiple entries can be provided, in which case they will be executed in that same order.
+	pub run_after: Option<Vec<Vec<String>>>,
+}
+
+fn default_desktop_directories() -> Vec<PathBuf> {
+	vec![
+		"$HOME/.local/share/applications".into(),
+		"$HOME/.local/share/flatpak/exports/share/applications".into(),
+		"/var/lib/flatpak/exports/share/applications".into(),
+		"/usr/share/applications".into(),
+	]
+}
+
+fn default_desktop_categories() -> Vec<String> {
+	vec!["Game".to_string()]
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StreamConfig {
 	/// Port to bind the RTSP server to.
@@ -201,6 +857,11 @@ pub struct StreamConfig {
 
 	/// Configuration for the control stream.
 	pub control: ControlStreamConfig,
+
+	/// If provided, drop, reorder and delay video and audio packets to test how the stream recovers.
+	#[cfg(feature = "netsim")]
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub network_simulation: Option<NetworkSimulationConfig>,
 }
 
 impl Default for StreamConfig {
@@ -210,10 +871,37 @@ impl Default for StreamConfig {
 			video: Default::default(),
 			audio: Default::default(),
 			control: Default::default(),
+			#[cfg(feature = "netsim")]
+			network_simulation: None,
 		}
 	}
 }
 
+/// Impairments that are applied to outgoing video and audio packets, only available with the `netsim` feature.
+#[cfg(feature = "netsim")]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NetworkSimulationConfig {
+	/// Fraction of packets that are dropped (between 0.0 and 1.0).
+	#[serde(default)]
+	pub loss: f64,
+
+	/// Fraction of packets that are held back and sent after the next packet (between 0.0 and 1.0).
+	#[serde(default)]
+	pub reorder: f64,
+
+	/// Delay in milliseconds that is added to every packet.
+	#[serde(default)]
+	pub delay: u64,
+
+	/// Maximum random delay in milliseconds that is added on top of `delay`.
+	#[serde(default)]
+	pub jitter: u64,
+
+	/// Seed for the random decisions, the same seed drops, reorders and delays the same packets.
+	#[serde(default)]
+	pub seed: u64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VideoStreamConfig {
 	/// Port to use for streaming video data.
@@ -225,8 +913,77 @@ pub struct VideoStreamConfig {
 	/// Type of codec to use for h264.
 	pub codec_hevc: String,
 
+	/// Codecs to try, in order, if the h264 codec fails to open.
+	///
+	/// Codecs need to support either CUDA frames or the yuv420p or nv12 pixel formats.
+	#[serde(default = "default_fallback_codecs_h264")]
+	pub fallback_codecs_h264: Vec<String>,
+
+	/// Codecs to try, in order, if the hevc codec fails to open.
+	#[serde(default = "default_fallback_codecs_hevc")]
+	pub fallback_codecs_hevc: Vec<String>,
+
 	/// What percentage of data packets should be parity packets.
 	pub fec_percentage: u8,
+
+	/// If provided, lower the bitrate when the client reports losses in the first moments of the stream.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub bandwidth_probe: Option<BandwidthProbeConfig>,
+
+	/// Part of the desktop to stream, the entire desktop is streamed if not provided.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub capture: Option<CaptureArea>,
+
+	/// Number of slices to encode each frame in, overrides the number of slices the client asks for.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub slices_per_frame: Option<u32>,
+
+	/// If provided, lower the encode resolution while the bitrate is starved and raise it again when it recovers.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub dynamic_resolution: Option<DynamicResolutionConfig>,
+
+	/// If provided, encode at a low keepalive rate while the captured screen doesn't change.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub idle: Option<IdleConfig>,
+
+	/// How captured frames queue up for the encoder, and which are dropped when it falls behind.
+	#[serde(default)]
+	pub encode_queue: EncodeQueueConfig,
+
+	/// If provided, let the kernel schedule the transmission of video packets with `SO_TXTIME`.
+	///
+	/// This requires an ETF qdisc on the outgoing network interface, otherwise packets are dropped by the kernel.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub txtime: Option<TxTimeConfig>,
+
+	/// If provided, encode in this color space instead of the one the client asks for.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub color_space: Option<ColorSpace>,
+
+	/// If provided, encode with this color range instead of the one the client asks for.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub color_range: Option<ColorRange>,
+
+	/// Encrypt video packets for clients that support it, so that others on the network can't watch the stream.
+	///
+	/// Off by default, because encrypting every packet costs CPU time at high bitrates.
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub encrypt: bool,
+
+	/// Options passed to the encoder as they are (for example `rc = "cbr"` or `preset = "p1"` for NVENC).
+	///
+	/// They are applied after the options Moonshine sets itself, options the encoder doesn't know are skipped with a warning.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub encoder_options: HashMap<String, String>,
+
+	/// H.264 profile and level that the decoders of clients support.
+	#[serde(default)]
+	pub h264_decoder: H264DecoderConfig,
+
+	/// H.264 decoder limits of specific clients, by the fingerprint of the certificate they paired with, these take
+	/// precedence over `h264_decoder`.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub h264_client_decoders: HashMap<String, H264DecoderConfig>,
 }
 
 impl Default for VideoStreamConfig {
@@ -235,7 +992,373 @@ impl Default for VideoStreamConfig {
 			port: 47998,
 			codec_h264: "h264_nvenc".to_string(),
 			codec_hevc: "hevc_nvenc".to_string(),
+			fallback_codecs_h264: default_fallback_codecs_h264(),
+			fallback_codecs_hevc: default_fallback_codecs_hevc(),
 			fec_percentage: 20,
+			bandwidth_probe: None,
+			capture: None,
+			slices_per_frame: None,
+			dynamic_resolution: None,
+			idle: None,
+			encode_queue: Default::default(),
+			txtime: None,
+			color_space: None,
+			color_range: None,
+			encrypt: false,
+			encoder_options: HashMap::new(),
+			h264_decoder: Default::default(),
+			h264_client_decoders: HashMap::new(),
+		}
+	}
+}
+
+impl VideoStreamConfig {
+	/// All codecs to try for h264, in order of preference.
+	pub fn codecs_h264(&self) -> Vec<String> {
+		std::iter::once(self.codec_h264.clone()).chain(self.fallback_codecs_h264.iter().cloned()).collect()
+	}
+
+	/// All codecs to try for hevc, in order of preference.
+	pub fn codecs_hevc(&self) -> Vec<String> {
+		std::iter::once(self.codec_hevc.clone()).chain(self.fallback_codecs_hevc.iter().cloned()).collect()
+	}
+
+	/// H.264 decoder limits of a client, GameStream clients don't announce these so they come from the configuration.
+	///
+	/// Clients are identified by their certificate fingerprint, every Moonlight client sends the same unique id.
+	pub fn h264_decoder_for(&self, client_id: &str) -> &H264DecoderConfig {
+		self.h264_client_decoders.get(client_id).unwrap_or(&self.h264_decoder)
+	}
+}
+
+fn default_fallback_codecs_h264() -> Vec<String> {
+	vec!["libx264".to_string()]
+}
+
+fn default_fallback_codecs_hevc() -> Vec<String> {
+	vec!["libx265".to_string()]
+}
+
+/// Color space that frames are converted to before encoding, signaled to the decoder in the bitstream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSpace {
+	/// Rec. 601, which clients expect unless they ask otherwise.
+	#[default]
+	Bt601,
+
+	/// Rec. 709, the color space of HD content.
+	Bt709,
+
+	/// Rec. 2020, the color space of UHD content.
+	Bt2020,
+}
+
+/// Range of the encoded luma and chroma values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorRange {
+	/// Values from 16 to 235 (240 for chroma), like TV content.
+	#[default]
+	Limited,
+
+	/// Values from 0 to 255.
+	Full,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct H264DecoderConfig {
+	/// Profile to encode H.264 in, the encoder picks one if not provided.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub profile: Option<H264Profile>,
+
+	/// Highest level the decoder supports, launches at a resolution and frame rate that need a higher level are refused.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_level: Option<H264Level>,
+}
+
+/// H.264 profile, which limits the coding tools the encoder may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum H264Profile {
+	/// Constrained baseline, the profile that every decoder supports.
+	Baseline,
+
+	Main,
+
+	/// The profile encoders use by default, it compresses best.
+	High,
+}
+
+impl H264Profile {
+	/// Name of the profile in the options of ffmpeg encoders.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Baseline => "baseline",
+			Self::Main => "main",
+			Self::High => "high",
+		}
+	}
+}
+
+/// H.264 level, which limits the frame size and the number of macroblocks per second that a decoder has to handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum H264Level {
+	#[serde(rename = "1")] L1,
+	#[serde(rename = "1.1")] L1_1,
+	#[serde(rename = "1.2")] L1_2,
+	#[serde(rename = "1.3")] L1_3,
+	#[serde(rename = "2")] L2,
+	#[serde(rename = "2.1")] L2_1,
+	#[serde(rename = "2.2")] L2_2,
+	#[serde(rename = "3")] L3,
+	#[serde(rename = "3.1")] L3_1,
+	#[serde(rename = "3.2")] L3_2,
+	#[serde(rename = "4")] L4,
+	#[serde(rename = "4.1")] L4_1,
+	#[serde(rename = "4.2")] L4_2,
+	#[serde(rename = "5")] L5,
+	#[serde(rename = "5.1")] L5_1,
+	#[serde(rename = "5.2")] L5_2,
+	#[serde(rename = "6")] L6,
+	#[serde(rename = "6.1")] L6_1,
+	#[serde(rename = "6.2")] L6_2,
+}
+
+impl H264Level {
+	const ALL: [Self; 19] = [
+		Self::L1, Self::L1_1, Self::L1_2, Self::L1_3,
+		Self::L2, Self::L2_1, Self::L2_2,
+		Self::L3, Self::L3_1, Self::L3_2,
+		Self::L4, Self::L4_1, Self::L4_2,
+		Self::L5, Self::L5_1, Self::L5_2,
+		Self::L6, Self::L6_1, Self::L6_2,
+	];
+
+	/// Lowest level that allows frames of `width` by `height` at `fps` frames per second, none if no level does.
+	///
+	/// Levels also limit the bitrate, which isn't taken into account, decoders of game streaming clients rarely enforce it.
+	pub fn required(width: u32, height: u32, fps: u32) -> Option<Self> {
+		let width_in_macroblocks = width.div_ceil(16) as u64;
+		let height_in_macroblocks = height.div_ceil(16) as u64;
+		let frame_size = width_in_macroblocks * height_in_macroblocks;
+
+		Self::ALL.into_iter().find(|level| {
+			let (max_macroblocks_per_second, max_frame_size) = level.limits();
+			// Neither side of the frame may exceed the square root of eight times the maximum frame size.
+			let max_side = ((8 * max_frame_size) as f64).sqrt() as u64;
+			frame_size <= max_frame_size
+				&& width_in_macroblocks <= max_side
+				&& height_in_macroblocks <= max_side
+				&& frame_size * fps as u64 <= max_macroblocks_per_second
+		})
+	}
+
+	/// Maximum macroblocks per second and maximum frame size in macroblocks, from table A-1 of the H.264 specification.
+	fn limits(self) -> (u64, u64) {
+		match self {
+			Self::L1 => (1_485, 99),
+			Self::L1_1 => (3_000, 396),
+			Self::L1_2 => (6_000, 396),
+			Self::L1_3 | Self::L2 => (11_880, 396),
+			Self::L2_1 => (19_800, 792),
+			Self::L2_2 => (20_250, 1_620),
+			Self::L3 => (40_500, 1_620),
+			Self::L3_1 => (108_000, 3_600),
+			Self::L3_2 => (216_000, 5_120),
+			Self::L4 | Self::L4_1 => (245_760, 8_192),
+			Self::L4_2 => (522_240, 8_704),
+			Self::L5 => (589_824, 22_080),
+			Self::L5_1 => (983_040, 36_864),
+			Self::L5_2 => (2_073_600, 36_864),
+			Self::L6 => (4_177_920, 139_264),
+			Self::L6_1 => (8_355_840, 139_264),
+			Self::L6_2 => (16_711_680, 139_264),
+		}
+	}
+
+	/// Name of the level, like "4.1", which is also how ffmpeg encoders name it.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::L1 => "1",
+			Self::L1_1 => "1.1",
+			Self::L1_2 => "1.2",
+			Self::L1_3 => "1.3",
+			Self::L2 => "2",
+			Self::L2_1 => "2.1",
+			Self::L2_2 => "2.2",
+			Self::L3 => "3",
+			Self::L3_1 => "3.1",
+			Self::L3_2 => "3.2",
+			Self::L4 => "4",
+			Self::L4_1 => "4.1",
+			Self::L4_2 => "4.2",
+			Self::L5 => "5",
+			Self::L5_1 => "5.1",
+			Self::L5_2 => "5.2",
+			Self::L6 => "6",
+			Self::L6_1 => "6.1",
+			Self::L6_2 => "6.2",
+		}
+	}
+
+	/// The level as `level_idc` in the bitstream, ten times the level number.
+	pub fn idc(self) -> i32 {
+		match self {
+			Self::L1 => 10,
+			Self::L1_1 => 11,
+			Self::L1_2 => 12,
+			Self::L1_3 => 13,
+			Self::L2 => 20,
+			Self::L2_1 => 21,
+			Self::L2_2 => 22,
+			Self::L3 => 30,
+			Self::L3_1 => 31,
+			Self::L3_2 => 32,
+			Self::L4 => 40,
+			Self::L4_1 => 41,
+			Self::L4_2 => 42,
+			Self::L5 => 50,
+			Self::L5_1 => 51,
+			Self::L5_2 => 52,
+			Self::L6 => 60,
+			Self::L6_1 => 61,
+			Self::L6_2 => 62,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureArea {
+	/// Stream a single monitor, identified by its output name (for example "DP-1").
+	Output(String),
+
+	/// Stream a rectangle of the desktop.
+	Region(CaptureRegion),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaptureRegion {
+	/// Horizontal offset in pixels from the left of the desktop.
+	pub x: u32,
+
+	/// Vertical offset in pixels from the top of the desktop.
+	pub y: u32,
+
+	/// Width in pixels.
+	pub width: u32,
+
+	/// Height in pixels.
+	pub height: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BandwidthProbeConfig {
+	/// Duration of the probe in milliseconds, counted from the start of the stream.
+	pub duration: u64,
+
+	/// Percentage of the requested bitrate to continue with when the client reported losses during the probe.
+	pub usable_percentage: u8,
+}
+
+impl Default for BandwidthProbeConfig {
+	fn default() -> Self {
+		Self {
+			duration: 2000,
+			usable_percentage: 80,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DynamicResolutionConfig {
+	/// Lowest encode resolution, as a percentage of the stream resolution.
+	pub minimum_scale: u32,
+}
+
+impl Default for DynamicResolutionConfig {
+	fn default() -> Self {
+		Self {
+			minimum_scale: 50,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IdleConfig {
+	/// Time in milliseconds without screen changes or input before the stream is considered idle.
+	pub timeout: u64,
+
+	/// Frames per second that are still encoded while idle, so the client keeps receiving video.
+	pub keepalive_fps: u32,
+}
+
+impl Default for IdleConfig {
+	fn default() -> Self {
+		Self {
+			timeout: 1000,
+			keepalive_fps: 1,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncodeQueueConfig {
+	/// Number of captured frames that can wait for the encoder.
+	///
+	/// Every queued frame is a full frame in GPU memory, and adds a frame of latency while the encoder is behind.
+	#[serde(default = "default_encode_queue_depth")]
+	pub depth: usize,
+
+	/// Which frame is dropped when a frame is captured while the queue is full.
+	#[serde(default)]
+	pub drop_policy: FrameDropPolicy,
+}
+
+impl Default for EncodeQueueConfig {
+	fn default() -> Self {
+		Self {
+			depth: default_encode_queue_depth(),
+			drop_policy: Default::default(),
+		}
+	}
+}
+
+fn default_encode_queue_depth() -> usize {
+	1
+}
+
+/// Frame that is dropped when the encode queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameDropPolicy {
+	/// Drop the oldest queued frame, so the encoder always gets the most recent frames.
+	#[default]
+	DropOldest,
+
+	/// Drop the frame that was just captured, so every queued frame is encoded.
+	DropNewest,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TxTimeConfig {
+	/// Rate at which packets are transmitted, as a multiple of the video bitrate.
+	///
+	/// Higher values send frames in shorter bursts, lower values spread them out more evenly.
+	pub pacing_factor: f64,
+
+	/// Time in microseconds between handing a packet to the kernel and its earliest transmission time.
+	///
+	/// This should be larger than the `delta` of the ETF qdisc.
+	pub lead_time: u64,
+}
+
+impl Default for TxTimeConfig {
+	fn default() -> Self {
+		Self {
+			pacing_factor: 2.0,
+			lead_time: 500,
 		}
 	}
 }
@@ -244,22 +1367,156 @@ impl Default for VideoStreamConfig {
 pub struct AudioStreamConfig {
 	/// Port to use for streaming audio data.
 	pub port: u16,
+
+	/// Bitrate of the encoded audio in bits per second.
+	#[serde(default = "default_audio_bitrate")]
+	pub bitrate: u32,
+
+	/// Bitrate of the encoded audio in bits per second, when the client asks for high quality audio.
+	#[serde(default = "default_audio_high_quality_bitrate")]
+	pub high_quality_bitrate: u32,
+
+	/// What the audio encoder is tuned for.
+	#[serde(default)]
+	pub application: AudioApplication,
+
+	/// Duration of audio packets in milliseconds, used when the client doesn't ask for a specific duration.
+	///
+	/// Supported durations are 5, 10, 20, 40 and 60 milliseconds.
+	#[serde(default = "default_audio_packet_duration")]
+	pub packet_duration: u32,
 }
 
 impl Default for AudioStreamConfig {
 	fn default() -> Self {
-		Self { port: 48000 }
+		Self {
+			port: 48000,
+			bitrate: default_audio_bitrate(),
+			high_quality_bitrate: default_audio_high_quality_bitrate(),
+			application: Default::default(),
+			packet_duration: default_audio_packet_duration(),
+		}
+	}
+}
+
+fn default_audio_bitrate() -> u32 {
+	64_000
+}
+
+fn default_audio_high_quality_bitrate() -> u32 {
+	192_000
+}
+
+fn default_audio_packet_duration() -> u32 {
+	5
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioApplication {
+	/// Favor speech intelligibility.
+	Voip,
+
+	/// Favor faithful reproduction of music and other non-speech audio.
+	Audio,
+
+	/// Lowest possible latency, at the cost of quality.
+	#[default]
+	LowDelay,
+}
+
+impl From<AudioApplication> for opus::Application {
+	fn from(application: AudioApplication) -> Self {
+		match application {
+			AudioApplication::Voip => opus::Application::Voip,
+			AudioApplication::Audio => opus::Application::Audio,
+			AudioApplication::LowDelay => opus::Application::LowDelay,
+		}
+	}
+}
+
+/// Clients that join a running session as guests, for local multiplayer over the network.
+///
+/// A paired client that resumes a session it didn't launch joins it as a guest. Guests receive no audio and only the key
+/// frames of the video, which keeps Moonlight connected, their gamepads are added to the session as the next free players.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CoopConfig {
+	/// Maximum number of guests per session, 0 to not allow guests.
+	#[serde(default)]
+	pub max_guests: usize,
+
+	/// Time in milliseconds after which a key frame is requested for the guests, 0 to only send one when a guest joins.
+	///
+	/// Every key frame is also sent to the client that owns the session, so a shorter interval costs it bitrate.
+	#[serde(default = "default_guest_key_frame_interval")]
+	pub guest_key_frame_interval: u64,
+}
+
+impl Default for CoopConfig {
+	fn default() -> Self {
+		Self {
+			max_guests: 0,
+			guest_key_frame_interval: default_guest_key_frame_interval(),
+		}
+	}
+}
+
+fn default_guest_key_frame_interval() -> u64 {
+	5000
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeepaliveConfig {
+	/// Time in seconds before a stream times out at which a `timeout_warning` event is published, 0 disables the warning.
+	#[serde(default = "default_keepalive_warning")]
+	pub warning: u64,
+
+	/// If provided, send the client a ping every this many seconds while it is connected.
+	///
+	/// Clients don't answer these pings, but the control stream acknowledges them, so a link that is gone is detected as
+	/// a disconnect (which the reconnect window covers). While host pings are enabled, every message from the client
+	/// counts as a sign of life, not only its pings, so a client that is late with a ping isn't stopped while it is
+	/// still sending input or statistics.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub host_ping_interval: Option<u64>,
+
+	/// Stream timeouts in seconds for specific clients, by the fingerprint of the certificate they paired with.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub client_timeouts: HashMap<String, u64>,
+}
+
+impl Default for KeepaliveConfig {
+	fn default() -> Self {
+		Self {
+			warning: default_keepalive_warning(),
+			host_ping_interval: None,
+			client_timeouts: HashMap::new(),
+		}
 	}
 }
 
+fn default_keepalive_warning() -> u64 {
+	10
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ControlStreamConfig {
 	/// Port to use for streaming control data.
 	pub port: u16,
+
+	/// Time in seconds to wait for a client to reconnect after it disconnected, before the stream stops.
+	///
+	/// Video and audio are paused while the client is disconnected.
+	#[serde(default = "default_control_reconnect_window")]
+	pub reconnect_window: u64,
 }
 
 impl Default for ControlStreamConfig {
 	fn default() -> Self {
-		Self { port: 47999 }
+		Self { port: 47999, reconnect_window: default_control_reconnect_window() }
 	}
 }
+
+fn default_control_reconnect_window() -> u64 {
+	10
+}
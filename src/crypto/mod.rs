@@ -0,0 +1,255 @@
+//! Cryptographic primitives used for pairing and streaming.
+//!
+//! All functions are fallible, so that malformed input from a client results in an error instead of a panic.
+//! Everything, including certificates, signatures and TLS, is implemented by OpenSSL, or by pure Rust crates with the
+//! `rust-crypto` feature so that moonshine can be built without OpenSSL.
+
+#[cfg(not(any(feature = "openssl", feature = "rust-crypto")))]
+compile_error!("Enable either the `openssl` or the `rust-crypto` feature.");
+
+#[cfg(not(feature = "rust-crypto"))]
+mod openssl_backend;
+#[cfg(not(feature = "rust-crypto"))]
+pub use openssl_backend::*;
+
+#[cfg(feature = "rust-crypto")]
+mod rust_backend;
+#[cfg(feature = "rust-crypto")]
+pub use rust_backend::*;
+
+/// Length of the authentication tag of AES-GCM encrypted data.
+pub const GCM_TAG_LENGTH: usize = 16;
+
+/// Reasons why a cryptographic operation failed.
+#[derive(Debug)]
+pub enum CryptoError {
+	/// OpenSSL reported an error, for example because a key has the wrong size.
+	#[cfg(not(feature = "rust-crypto"))]
+	OpenSsl(openssl::error::ErrorStack),
+
+	/// The output of an operation does not have the expected length.
+	UnexpectedLength {
+		expected: usize,
+		actual: usize,
+	},
+
+	/// An operation failed without further details, for example because an authentication tag does not match.
+	Failed(&'static str),
+}
+
+impl std::fmt::Display for CryptoError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			#[cfg(not(feature = "rust-crypto"))]
+			Self::OpenSsl(e) => write!(f, "{e}"),
+			Self::UnexpectedLength { expected, actual } => write!(f, "expected {expected} bytes, got {actual} bytes"),
+			Self::Failed(operation) => write!(f, "{operation} failed"),
+		}
+	}
+}
+
+#[cfg(not(feature = "rust-crypto"))]
+impl From<openssl::error::ErrorStack> for CryptoError {
+	fn from(e: openssl::error::ErrorStack) -> Self {
+		Self::OpenSsl(e)
+	}
+}
+
+/// SHA-256 fingerprint of a certificate, as colon separated uppercase hex like `AB:CD:...`.
+///
+/// This is the format browsers and `openssl x509 -fingerprint -sha256` show, so users can compare it on either end.
+pub fn certificate_fingerprint(certificate: &Certificate) -> Result<String, CryptoError> {
+	der_fingerprint(&certificate.to_der()?)
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate, in the same format as [`certificate_fingerprint`].
+pub fn der_fingerprint(der: &[u8]) -> Result<String, CryptoError> {
+	let digest = hash(der)?;
+	let fingerprint = digest.iter()
+		.map(|byte| format!("{byte:02X}"))
+		.collect::<Vec<_>>()
+		.join(":");
+
+	Ok(fingerprint)
+}
+
+/// Known-answer tests, which run against whichever backend is built, so CI runs them with and without `rust-crypto`.
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn bytes(hex: &str) -> Vec<u8> {
+		hex::decode(hex).unwrap()
+	}
+
+	#[test]
+	fn ecb() {
+		// FIPS-197, appendix C.1.
+		let key = bytes("000102030405060708090a0b0c0d0e0f");
+		let plaintext = bytes("00112233445566778899aabbccddeeff");
+		let ciphertext = bytes("69c4e0d86a7b0430d8cdb78070b4c55a");
+
+		assert_eq!(encrypt_ecb(&key, &plaintext).unwrap(), ciphertext);
+		assert_eq!(decrypt_ecb(&key, &ciphertext).unwrap(), plaintext);
+
+		let plaintext = [plaintext.clone(), plaintext].concat();
+		assert_eq!(encrypt_ecb(&key, &plaintext).unwrap(), [ciphertext.clone(), ciphertext].concat());
+	}
+
+	#[test]
+	fn ecb_rejects_bad_input() {
+		let key = [0u8; 16];
+		assert!(encrypt_ecb(&key, &[0u8; 15]).is_err());
+		assert!(decrypt_ecb(&key, &[0u8; 17]).is_err());
+		assert!(encrypt_ecb(&key[..15], &[0u8; 16]).is_err());
+		assert!(decrypt_ecb(&[0u8; 17], &[0u8; 16]).is_err());
+	}
+
+	#[test]
+	fn cbc() {
+		// NIST SP 800-38A, F.2.1, followed by a block of PKCS#7 padding.
+		let key = bytes("2b7e151628aed2a6abf7158809cf4f3c");
+		let iv = bytes("000102030405060708090a0b0c0d0e0f");
+		let plaintext = bytes("6bc1bee22e409f96e93d7e117393172a");
+		assert_eq!(
+			encrypt_cbc(&key, &iv, &plaintext).unwrap(),
+			bytes("7649abac8119b246cee98e9b12e9197d8964e0b149c10b7b682e6e39aaeb731c"),
+		);
+
+		// Audio packets are rarely block aligned.
+		let plaintext = bytes("6bc1bee22e409f96e93d7e117393172aae2d8a57");
+		assert_eq!(
+			encrypt_cbc(&key, &iv, &plaintext).unwrap(),
+			bytes("7649abac8119b246cee98e9b12e9197d2e013f890472d82217b17f45f6e7f539"),
+		);
+	}
+
+	#[test]
+	fn cbc_rejects_bad_input() {
+		assert!(encrypt_cbc(&[0u8; 15], &[0u8; 16], b"data").is_err());
+		assert!(encrypt_cbc(&[0u8; 16], &[0u8; 8], b"data").is_err());
+	}
+
+	#[test]
+	fn gcm() {
+		// Test cases 2 and 3 of "The Galois/Counter Mode of Operation (GCM)".
+		let key = [0u8; 16];
+		let iv = [0u8; 12];
+		let (ciphertext, tag) = encrypt_gcm(&key, &iv, &[0u8; 16]).unwrap();
+		assert_eq!(ciphertext, bytes("0388dace60b6a392f328c2b971b2fe78"));
+		assert_eq!(tag.as_slice(), bytes("ab6e47d42cec13bdf53a67b21257bddf"));
+
+		let key = bytes("feffe9928665731c6d6a8f9467308308");
+		let iv = bytes("cafebabefacedbaddecaf888");
+		let plaintext = bytes(concat!(
+			"d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72",
+			"1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255",
+		));
+		let expected_ciphertext = bytes(concat!(
+			"42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e",
+			"21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091473f5985",
+		));
+		let expected_tag = bytes("4d5c2af327cd64a62cf35abd2ba6fab4");
+
+		let (ciphertext, tag) = encrypt_gcm(&key, &iv, &plaintext).unwrap();
+		assert_eq!(ciphertext, expected_ciphertext);
+		assert_eq!(tag.as_slice(), expected_tag);
+		assert_eq!(decrypt_gcm(&key, &iv, &ciphertext, &tag).unwrap(), plaintext);
+	}
+
+	#[test]
+	fn gcm_long_iv() {
+		// Control messages use 16 byte initialization vectors, which GCM hashes instead of using them directly.
+		let key = bytes("feffe9928665731c6d6a8f9467308308");
+		let iv = bytes("000102030405060708090a0b0c0d0e0f");
+		let plaintext = bytes(concat!(
+			"d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72",
+			"1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+		));
+		let expected_ciphertext = bytes(concat!(
+			"9333269f7190747c0c40f4e68b264bccb425eb7f01668da81e32f7c519b37524",
+			"9297ea2dc3f88c3a2b1c70373831df37959db053642fb009a4710555",
+		));
+		let expected_tag = bytes("7544c8fbb3cd11612988b735cf312149");
+
+		let (ciphertext, tag) = encrypt_gcm(&key, &iv, &plaintext).unwrap();
+		assert_eq!(ciphertext, expected_ciphertext);
+		assert_eq!(tag.as_slice(), expected_tag);
+		assert_eq!(decrypt_gcm(&key, &iv, &ciphertext, &tag).unwrap(), plaintext);
+	}
+
+	#[test]
+	fn gcm_rejects_tampering() {
+		let key = [1u8; 16];
+		let iv = [2u8; 16];
+		let (ciphertext, tag) = encrypt_gcm(&key, &iv, b"RequestIdrFrame").unwrap();
+
+		let mut tampered = ciphertext.clone();
+		tampered[0] ^= 1;
+		assert!(decrypt_gcm(&key, &iv, &tampered, &tag).is_err());
+
+		let mut tampered_tag = tag;
+		tampered_tag[15] ^= 1;
+		assert!(decrypt_gcm(&key, &iv, &ciphertext, &tampered_tag).is_err());
+
+		assert!(decrypt_gcm(&key, &iv, &ciphertext, &tag[..12]).is_err());
+		assert!(decrypt_gcm(&[3u8; 16], &iv, &ciphertext, &tag).is_err());
+		assert!(decrypt_gcm(&key, &[4u8; 16], &ciphertext, &tag).is_err());
+		assert!(encrypt_gcm(&key[..8], &iv, b"data").is_err());
+	}
+
+	#[test]
+	fn sha256() {
+		assert_eq!(hash(b"").unwrap().as_slice(), bytes("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+		assert_eq!(hash(b"abc").unwrap().as_slice(), bytes("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+		assert_eq!(
+			hash(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").unwrap().as_slice(),
+			bytes("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+		);
+	}
+
+	#[test]
+	fn secrets() {
+		assert!(secrets_equal(b"", b""));
+		assert!(secrets_equal(b"secret", b"secret"));
+		assert!(!secrets_equal(b"secret", b"secreT"));
+		assert!(!secrets_equal(b"secret", b"secret!"));
+		assert!(!secrets_equal(b"secret", b""));
+	}
+
+	#[test]
+	fn random() {
+		let a = random_bytes::<16>().unwrap();
+		let b = random_bytes::<16>().unwrap();
+		assert_ne!(a, b);
+	}
+
+	#[test]
+	fn certificate() {
+		let (certificate, private_key) = create_certificate().unwrap();
+		assert!(certificate.matches(&private_key).unwrap());
+
+		let certificate = Certificate::from_pem(&certificate.to_pem().unwrap()).unwrap();
+		let private_key = PrivateKey::from_pem(&private_key.to_pem().unwrap()).unwrap();
+		assert!(certificate.matches(&private_key).unwrap());
+		assert!(!certificate.signature().is_empty());
+
+		let (other_certificate, other_private_key) = create_certificate().unwrap();
+		assert!(!certificate.matches(&other_private_key).unwrap());
+
+		let signature = sign(b"server secret", &private_key).unwrap();
+		assert!(verify(b"server secret", &signature, &certificate).unwrap());
+		assert!(!verify(b"server secreT", &signature, &certificate).unwrap());
+		assert!(!verify(b"server secret", &signature, &other_certificate).unwrap());
+
+		// Certificates are valid for ten years.
+		let lifetime = certificate.not_after().unwrap().duration_since(std::time::SystemTime::now()).unwrap();
+		assert!(lifetime > std::time::Duration::from_secs(3649 * 24 * 60 * 60));
+
+		let fingerprint = certificate_fingerprint(&certificate).unwrap();
+		assert_eq!(fingerprint.len(), 32 * 3 - 1);
+		assert!(fingerprint.split(':').all(|byte| byte.len() == 2 && byte.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_lowercase())));
+		assert_ne!(fingerprint, certificate_fingerprint(&other_certificate).unwrap());
+		assert_eq!(fingerprint, der_fingerprint(&certificate.to_der().unwrap()).unwrap());
+	}
+}
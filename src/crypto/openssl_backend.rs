@@ -0,0 +1,247 @@
+//! Ciphers, hashing, randomness, certificates and signatures implemented by OpenSSL.
+
+use std::time::{Duration, SystemTime};
+
+use openssl::{
+	asn1::Asn1Time,
+	bn::{BigNum, MsbOption},
+	cipher::{Cipher, CipherRef},
+	cipher_ctx::CipherCtx,
+	hash::MessageDigest,
+	md::Md,
+	md_ctx::MdCtx,
+	pkey::{PKey, Private},
+	rsa::Rsa,
+	x509::{
+		extension::{
+			BasicConstraints, KeyUsage, SubjectKeyIdentifier
+		},
+		X509
+	}
+};
+
+use super::{CryptoError, GCM_TAG_LENGTH};
+
+/// OpenSSL panics on keys and initialization vectors that are too short, so their lengths are checked first.
+fn check_length(data: &[u8], expected: usize) -> Result<(), CryptoError> {
+	if data.len() != expected {
+		return Err(CryptoError::UnexpectedLength { expected, actual: data.len() });
+	}
+
+	Ok(())
+}
+
+fn encrypt(cipher: &CipherRef, plaintext: &[u8], key: &[u8], iv: Option<&[u8]>, padding: bool) -> Result<Vec<u8>, CryptoError> {
+	check_length(key, cipher.key_length())?;
+	if let Some(iv) = iv {
+		check_length(iv, cipher.iv_length())?;
+	}
+
+	let mut context = CipherCtx::new()?;
+	context.encrypt_init(Some(cipher), Some(key), iv)?;
+	context.set_padding(padding);
+
+	let mut ciphertext = Vec::with_capacity(plaintext.len());
+	context.cipher_update_vec(plaintext, &mut ciphertext)?;
+	context.cipher_final_vec(&mut ciphertext)?;
+
+	Ok(ciphertext)
+}
+
+/// Decrypt data without padding, so the plaintext has the same length as the ciphertext.
+fn decrypt(cipher: &CipherRef, ciphertext: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	check_length(key, cipher.key_length())?;
+
+	let mut context = CipherCtx::new()?;
+	context.decrypt_init(Some(cipher), Some(key), None)?;
+	context.set_padding(false);
+
+	let mut plaintext = Vec::with_capacity(ciphertext.len());
+	context.cipher_update_vec(ciphertext, &mut plaintext)?;
+	context.cipher_final_vec(&mut plaintext)?;
+
+	if plaintext.len() != ciphertext.len() {
+		return Err(CryptoError::UnexpectedLength { expected: ciphertext.len(), actual: plaintext.len() });
+	}
+
+	Ok(plaintext)
+}
+
+/// Encrypt a block aligned message with AES-128-ECB, as used during pairing.
+pub fn encrypt_ecb(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	encrypt(Cipher::aes_128_ecb(), plaintext, key, None, false)
+}
+
+/// Decrypt a block aligned message with AES-128-ECB, as used during pairing.
+pub fn decrypt_ecb(key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	decrypt(Cipher::aes_128_ecb(), ciphertext, key)
+}
+
+/// Encrypt data with AES-128-CBC and PKCS#7 padding, as used for audio packets.
+pub fn encrypt_cbc(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	encrypt(Cipher::aes_128_cbc(), plaintext, key, Some(iv), true)
+}
+
+/// Encrypt data with AES-128-GCM, returns the ciphertext and the authentication tag.
+pub fn encrypt_gcm(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, [u8; GCM_TAG_LENGTH]), CryptoError> {
+	let mut tag = [0u8; GCM_TAG_LENGTH];
+	let ciphertext = openssl::symm::encrypt_aead(
+		openssl::symm::Cipher::aes_128_gcm(),
+		key,
+		Some(iv),
+		&[],
+		plaintext,
+		&mut tag,
+	)?;
+
+	Ok((ciphertext, tag))
+}
+
+/// Decrypt data with AES-128-GCM, fails if the data does not match the authentication tag.
+pub fn decrypt_gcm(key: &[u8], iv: &[u8], ciphertext: &[u8], tag: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	if tag.len() != GCM_TAG_LENGTH {
+		return Err(CryptoError::UnexpectedLength { expected: GCM_TAG_LENGTH, actual: tag.len() });
+	}
+
+	Ok(openssl::symm::decrypt_aead(
+		openssl::symm::Cipher::aes_128_gcm(),
+		key,
+		Some(iv),
+		&[],
+		ciphertext,
+		tag,
+	)?)
+}
+
+/// Compute the SHA-256 hash of data.
+pub fn hash(data: &[u8]) -> Result<[u8; 32], CryptoError> {
+	let digest = openssl::hash::hash(MessageDigest::sha256(), data)?;
+	digest.as_ref()
+		.try_into()
+		.map_err(|_| CryptoError::UnexpectedLength { expected: 32, actual: digest.len() })
+}
+
+/// Generate cryptographically secure random bytes.
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], CryptoError> {
+	let mut bytes = [0u8; N];
+	openssl::rand::rand_bytes(&mut bytes)?;
+	Ok(bytes)
+}
+
+/// Compare two secrets in constant time.
+pub fn secrets_equal(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
+
+/// An X.509 certificate, of the host or of a paired client.
+#[derive(Clone)]
+pub struct Certificate(X509);
+
+impl Certificate {
+	pub fn from_pem(pem: &[u8]) -> Result<Self, CryptoError> {
+		Ok(Self(X509::from_pem(pem)?))
+	}
+
+	pub fn to_pem(&self) -> Result<Vec<u8>, CryptoError> {
+		Ok(self.0.to_pem()?)
+	}
+
+	pub fn to_der(&self) -> Result<Vec<u8>, CryptoError> {
+		Ok(self.0.to_der()?)
+	}
+
+	/// The signature of the certificate, which pairing mixes into the challenge hashes.
+	pub fn signature(&self) -> &[u8] {
+		self.0.signature().as_slice()
+	}
+
+	/// Whether the public key of this certificate belongs to `private_key`.
+	pub fn matches(&self, private_key: &PrivateKey) -> Result<bool, CryptoError> {
+		Ok(self.0.public_key()?.public_eq(&private_key.0))
+	}
+
+	/// The moment the certificate stops being valid.
+	pub fn not_after(&self) -> Result<SystemTime, CryptoError> {
+		let since_epoch = Asn1Time::from_unix(0)?.diff(self.0.not_after())?;
+		let seconds = i64::from(since_epoch.days) * 24 * 60 * 60 + i64::from(since_epoch.secs);
+		let seconds = u64::try_from(seconds)
+			.map_err(|_| CryptoError::Failed("Reading the expiry of the certificate"))?;
+
+		Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+	}
+}
+
+/// An RSA private key.
+pub struct PrivateKey(PKey<Private>);
+
+impl PrivateKey {
+	pub fn from_pem(pem: &[u8]) -> Result<Self, CryptoError> {
+		Ok(Self(PKey::private_key_from_pem(pem)?))
+	}
+
+	/// Serialize the key as PKCS#8.
+	pub fn to_pem(&self) -> Result<Vec<u8>, CryptoError> {
+		Ok(self.0.private_key_to_pem_pkcs8()?)
+	}
+}
+
+/// Create a self signed certificate for the host, with a new 2048 bit RSA key that is valid for ten years.
+pub fn create_certificate() -> Result<(Certificate, PrivateKey), CryptoError> {
+	let rsa = Rsa::generate(2048)?;
+	let key_pair = PKey::from_rsa(rsa)?;
+
+	let mut cert_builder = X509::builder()?;
+	cert_builder.set_version(2)?;
+	let serial_number = {
+		let mut serial = BigNum::new()?;
+		serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+		serial.to_asn1_integer()?
+	};
+	cert_builder.set_serial_number(&serial_number)?;
+	cert_builder.set_pubkey(&key_pair)?;
+	let not_before = Asn1Time::days_from_now(0)?;
+	cert_builder.set_not_before(&not_before)?;
+	let not_after = Asn1Time::days_from_now(3650)?;
+	cert_builder.set_not_after(&not_after)?;
+
+	cert_builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+	cert_builder.append_extension(
+		KeyUsage::new()
+			.digital_signature()
+			.key_encipherment()
+			.key_agreement()
+			.build()?,
+	)?;
+
+	let subject_key_identifier =
+		SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(None, None))?;
+	cert_builder.append_extension(subject_key_identifier)?;
+
+	cert_builder.sign(&key_pair, MessageDigest::sha256())?;
+	let cert = cert_builder.build();
+
+	Ok((Certificate(cert), PrivateKey(key_pair)))
+}
+
+/// Sign data with a private key, using SHA-256.
+pub fn sign(data: &[u8], key: &PrivateKey) -> Result<Vec<u8>, CryptoError> {
+	let mut context = MdCtx::new()?;
+	context.digest_sign_init(Some(Md::sha256()), &key.0)?;
+	context.digest_sign_update(data)?;
+
+	let mut signature = Vec::new();
+	context.digest_sign_final_to_vec(&mut signature)?;
+
+	Ok(signature)
+}
+
+/// Verify that data was signed by the private key that belongs to this certificate, using SHA-256.
+pub fn verify(data: &[u8], signature: &[u8], certificate: &Certificate) -> Result<bool, CryptoError> {
+	let key = certificate.0.public_key()?;
+	let mut context = MdCtx::new()?;
+	context.digest_verify_init(Some(Md::sha256()), &key)?;
+	context.digest_verify_update(data)?;
+
+	// OpenSSL reports a signature that doesn't match as an error, the Rust backend as `false`.
+	Ok(context.digest_verify_final(signature).unwrap_or(false))
+}
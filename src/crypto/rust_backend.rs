@@ -0,0 +1,269 @@
+//! Ciphers, hashing, randomness, certificates and signatures implemented by pure Rust crates, enabled with the
+//! `rust-crypto` feature.
+
+use std::time::SystemTime;
+
+use aes::{cipher::{block_padding::Pkcs7, generic_array::GenericArray, BlockDecrypt, BlockEncrypt, BlockEncryptMut, KeyInit, KeyIvInit}, Aes128};
+use aes_gcm::{aead::{consts::{U12, U16}, AeadInPlace}, AesGcm};
+use rsa::{
+	pkcs1::DecodeRsaPrivateKey,
+	pkcs1v15::{Signature, SigningKey, VerifyingKey},
+	pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, LineEnding},
+	rand_core::OsRng,
+	signature::{SignatureEncoding, Signer, Verifier},
+	RsaPrivateKey, RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use x509_cert::der::{Decode, DecodePem, Encode, EncodePem};
+
+use super::{CryptoError, GCM_TAG_LENGTH};
+
+/// AES-128-GCM with the 16 byte initialization vectors that Moonlight uses.
+type Aes128Gcm = AesGcm<Aes128, U16>;
+
+/// AES-128-GCM with the 12 byte initialization vectors of encrypted video packets and RTSP messages.
+type Aes128GcmShortIv = AesGcm<Aes128, U12>;
+
+const KEY_LENGTH: usize = 16;
+const BLOCK_LENGTH: usize = 16;
+const IV_LENGTH: usize = 16;
+const SHORT_IV_LENGTH: usize = 12;
+
+fn check_length(data: &[u8], expected: usize) -> Result<(), CryptoError> {
+	if data.len() != expected {
+		return Err(CryptoError::UnexpectedLength { expected, actual: data.len() });
+	}
+
+	Ok(())
+}
+
+/// ECB works on whole blocks, messages are not padded.
+fn check_block_aligned(data: &[u8]) -> Result<(), CryptoError> {
+	if data.len() % BLOCK_LENGTH != 0 {
+		return Err(CryptoError::UnexpectedLength { expected: data.len().next_multiple_of(BLOCK_LENGTH), actual: data.len() });
+	}
+
+	Ok(())
+}
+
+/// Encrypt a block aligned message with AES-128-ECB, as used during pairing.
+pub fn encrypt_ecb(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	check_length(key, KEY_LENGTH)?;
+	check_block_aligned(plaintext)?;
+
+	let cipher = Aes128::new(GenericArray::from_slice(key));
+	let mut ciphertext = plaintext.to_vec();
+	for block in ciphertext.chunks_exact_mut(BLOCK_LENGTH) {
+		cipher.encrypt_block(GenericArray::from_mut_slice(block));
+	}
+
+	Ok(ciphertext)
+}
+
+/// Decrypt a block aligned message with AES-128-ECB, as used during pairing.
+pub fn decrypt_ecb(key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	check_length(key, KEY_LENGTH)?;
+	check_block_aligned(ciphertext)?;
+
+	let cipher = Aes128::new(GenericArray::from_slice(key));
+	let mut plaintext = ciphertext.to_vec();
+	for block in plaintext.chunks_exact_mut(BLOCK_LENGTH) {
+		cipher.decrypt_block(GenericArray::from_mut_slice(block));
+	}
+
+	Ok(plaintext)
+}
+
+/// Encrypt data with AES-128-CBC and PKCS#7 padding, as used for audio packets.
+pub fn encrypt_cbc(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	check_length(key, KEY_LENGTH)?;
+	check_length(iv, IV_LENGTH)?;
+
+	let cipher = cbc::Encryptor::<Aes128>::new(GenericArray::from_slice(key), GenericArray::from_slice(iv));
+	Ok(cipher.encrypt_padded_vec_mut::<Pkcs7>(plaintext))
+}
+
+/// Encrypt data with AES-128-GCM, returns the ciphertext and the authentication tag.
+///
+/// The initialization vector is either 16 bytes, or 12 bytes for video packets and RTSP messages.
+pub fn encrypt_gcm(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, [u8; GCM_TAG_LENGTH]), CryptoError> {
+	check_length(key, KEY_LENGTH)?;
+
+	let mut ciphertext = plaintext.to_vec();
+	let result = if iv.len() == SHORT_IV_LENGTH {
+		Aes128GcmShortIv::new(GenericArray::from_slice(key))
+			.encrypt_in_place_detached(GenericArray::from_slice(iv), &[], &mut ciphertext)
+	} else {
+		check_length(iv, IV_LENGTH)?;
+		Aes128Gcm::new(GenericArray::from_slice(key))
+			.encrypt_in_place_detached(GenericArray::from_slice(iv), &[], &mut ciphertext)
+	};
+	let tag = result.map_err(|_| CryptoError::Failed("AES-GCM encryption"))?;
+
+	let mut tag_bytes = [0u8; GCM_TAG_LENGTH];
+	tag_bytes.copy_from_slice(&tag);
+	Ok((ciphertext, tag_bytes))
+}
+
+/// Decrypt data with AES-128-GCM, fails if the data does not match the authentication tag.
+///
+/// The initialization vector is either 16 bytes, or 12 bytes for RTSP messages.
+pub fn decrypt_gcm(key: &[u8], iv: &[u8], ciphertext: &[u8], tag: &[u8]) -> Result<Vec<u8>, CryptoError> {
+	check_length(key, KEY_LENGTH)?;
+	check_length(tag, GCM_TAG_LENGTH)?;
+
+	let mut plaintext = ciphertext.to_vec();
+	let result = if iv.len() == SHORT_IV_LENGTH {
+		Aes128GcmShortIv::new(GenericArray::from_slice(key))
+			.decrypt_in_place_detached(GenericArray::from_slice(iv), &[], &mut plaintext, GenericArray::from_slice(tag))
+	} else {
+		check_length(iv, IV_LENGTH)?;
+		Aes128Gcm::new(GenericArray::from_slice(key))
+			.decrypt_in_place_detached(GenericArray::from_slice(iv), &[], &mut plaintext, GenericArray::from_slice(tag))
+	};
+	result.map_err(|_| CryptoError::Failed("AES-GCM decryption"))?;
+
+	Ok(plaintext)
+}
+
+/// Compute the SHA-256 hash of data.
+pub fn hash(data: &[u8]) -> Result<[u8; 32], CryptoError> {
+	let mut hash = [0u8; 32];
+	hash.copy_from_slice(&Sha256::digest(data));
+	Ok(hash)
+}
+
+/// Generate cryptographically secure random bytes.
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], CryptoError> {
+	let mut bytes = [0u8; N];
+	getrandom::getrandom(&mut bytes)
+		.map_err(|_| CryptoError::Failed("Generating random bytes"))?;
+	Ok(bytes)
+}
+
+/// Compare two secrets in constant time.
+pub fn secrets_equal(a: &[u8], b: &[u8]) -> bool {
+	a.ct_eq(b).into()
+}
+
+/// An X.509 certificate, of the host or of a paired client.
+#[derive(Clone)]
+pub struct Certificate(x509_cert::Certificate);
+
+impl Certificate {
+	pub fn from_pem(pem: &[u8]) -> Result<Self, CryptoError> {
+		x509_cert::Certificate::from_pem(pem)
+			.map(Self)
+			.map_err(|_| CryptoError::Failed("Parsing the certificate"))
+	}
+
+	pub fn to_pem(&self) -> Result<Vec<u8>, CryptoError> {
+		self.0.to_pem(LineEnding::LF)
+			.map(String::into_bytes)
+			.map_err(|_| CryptoError::Failed("Serializing the certificate"))
+	}
+
+	pub fn to_der(&self) -> Result<Vec<u8>, CryptoError> {
+		self.0.to_der()
+			.map_err(|_| CryptoError::Failed("Serializing the certificate"))
+	}
+
+	/// The signature of the certificate, which pairing mixes into the challenge hashes.
+	pub fn signature(&self) -> &[u8] {
+		self.0.signature.raw_bytes()
+	}
+
+	/// Whether the public key of this certificate belongs to `private_key`.
+	pub fn matches(&self, private_key: &PrivateKey) -> Result<bool, CryptoError> {
+		Ok(self.public_key()? == private_key.0.to_public_key())
+	}
+
+	/// The moment the certificate stops being valid.
+	pub fn not_after(&self) -> Result<SystemTime, CryptoError> {
+		Ok(self.0.tbs_certificate.validity.not_after.to_system_time())
+	}
+
+	fn public_key(&self) -> Result<RsaPublicKey, CryptoError> {
+		let public_key = self.0.tbs_certificate.subject_public_key_info.to_der()
+			.map_err(|_| CryptoError::Failed("Reading the public key of the certificate"))?;
+		RsaPublicKey::from_public_key_der(&public_key)
+			.map_err(|_| CryptoError::Failed("Reading the public key of the certificate"))
+	}
+}
+
+/// An RSA private key.
+pub struct PrivateKey(RsaPrivateKey);
+
+impl PrivateKey {
+	/// Parse a PKCS#8 key, or a PKCS#1 key like older versions of OpenSSL wrote.
+	pub fn from_pem(pem: &[u8]) -> Result<Self, CryptoError> {
+		let pem = std::str::from_utf8(pem)
+			.map_err(|_| CryptoError::Failed("Parsing the private key"))?;
+		RsaPrivateKey::from_pkcs8_pem(pem)
+			.or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+			.map(Self)
+			.map_err(|_| CryptoError::Failed("Parsing the private key"))
+	}
+
+	/// Serialize the key as PKCS#8.
+	pub fn to_pem(&self) -> Result<Vec<u8>, CryptoError> {
+		self.0.to_pkcs8_pem(LineEnding::LF)
+			.map(|pem| pem.as_bytes().to_vec())
+			.map_err(|_| CryptoError::Failed("Serializing the private key"))
+	}
+}
+
+/// Create a self signed certificate for the host, with a new 2048 bit RSA key that is valid for ten years.
+pub fn create_certificate() -> Result<(Certificate, PrivateKey), CryptoError> {
+	let private_key = RsaPrivateKey::new(&mut OsRng, 2048)
+		.map_err(|_| CryptoError::Failed("Generating the private key"))?;
+	let private_key = PrivateKey(private_key);
+
+	// rcgen can't generate RSA keys, but it can sign with one.
+	let pem = private_key.to_pem()?;
+	let pem = std::str::from_utf8(&pem)
+		.map_err(|_| CryptoError::Failed("Serializing the private key"))?;
+	let key_pair = rcgen::KeyPair::from_pkcs8_pem_and_sign_algo(pem, &rcgen::PKCS_RSA_SHA256)
+		.map_err(|_| CryptoError::Failed("Loading the private key"))?;
+
+	let mut serial_number = random_bytes::<20>()?;
+	serial_number[0] &= 0x7f;
+
+	let mut params = rcgen::CertificateParams::default();
+	params.distinguished_name = rcgen::DistinguishedName::new();
+	params.serial_number = Some(rcgen::SerialNumber::from_slice(&serial_number));
+	params.not_before = time::OffsetDateTime::now_utc();
+	params.not_after = params.not_before + time::Duration::days(3650);
+	params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+	params.key_usages = vec![
+		rcgen::KeyUsagePurpose::DigitalSignature,
+		rcgen::KeyUsagePurpose::KeyEncipherment,
+		rcgen::KeyUsagePurpose::KeyAgreement,
+	];
+	let certificate = params.self_signed(&key_pair)
+		.map_err(|_| CryptoError::Failed("Signing the certificate"))?;
+
+	let certificate = x509_cert::Certificate::from_der(certificate.der())
+		.map_err(|_| CryptoError::Failed("Parsing the certificate"))?;
+	Ok((Certificate(certificate), private_key))
+}
+
+/// Sign data with a private key, using SHA-256.
+pub fn sign(data: &[u8], key: &PrivateKey) -> Result<Vec<u8>, CryptoError> {
+	let signing_key = SigningKey::<Sha256>::new(key.0.clone());
+	let signature = signing_key.try_sign(data)
+		.map_err(|_| CryptoError::Failed("Signing"))?;
+
+	Ok(signature.to_vec())
+}
+
+/// Verify that data was signed by the private key that belongs to this certificate, using SHA-256.
+pub fn verify(data: &[u8], signature: &[u8], certificate: &Certificate) -> Result<bool, CryptoError> {
+	let verifying_key = VerifyingKey::<Sha256>::new(certificate.public_key()?);
+	let Ok(signature) = Signature::try_from(signature) else {
+		return Ok(false);
+	};
+
+	Ok(verifying_key.verify(data, &signature).is_ok())
+}
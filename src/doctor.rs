@@ -0,0 +1,351 @@
+//! Checks of the environment the host runs in, for the `doctor` command.
+//!
+//! Most problems users run into are not bugs but a missing permission, driver or daemon, so every check that fails
+//! explains how to fix it.
+
+use std::{
+	io::ErrorKind,
+	net::{SocketAddr, TcpListener, UdpSocket},
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+
+use crate::{bind_address, crypto::{Certificate, PrivateKey}, config::{Config, InputBackendKind}, session::stream::{benchmark_capture, EncoderCapabilities}};
+
+/// Certificates that expire within this many days are reported, clients have to pair again once it expired.
+const CERTIFICATE_EXPIRY_WARNING_DAYS: u32 = 30;
+
+/// Number of frames captured to measure frame capture.
+const CAPTURE_BENCHMARK_FRAMES: u32 = 120;
+
+/// Framerate that frame capture is measured at, the highest framerate most clients ask for.
+const CAPTURE_BENCHMARK_FRAMERATE: u32 = 60;
+
+/// Socket that the Avahi daemon listens on, zeroconf publishes the host through it.
+const AVAHI_SOCKET: &str = "/run/avahi-daemon/socket";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+	Ok,
+	Info,
+	Warning,
+	Error,
+}
+
+/// Outcome of a single check.
+struct Check {
+	name: &'static str,
+	status: Status,
+	message: String,
+
+	/// What the user can do about a problem.
+	fix: Option<String>,
+}
+
+impl Check {
+	fn new(name: &'static str, status: Status, message: impl Into<String>) -> Self {
+		Self { name, status, message: message.into(), fix: None }
+	}
+
+	fn fix(mut self, fix: impl Into<String>) -> Self {
+		self.fix = Some(fix.into());
+		self
+	}
+}
+
+/// Run all checks against `config` and print a report, fails if any check found an error.
+pub async fn run(config: &Config) -> Result<(), ()> {
+	let mut checks = Vec::new();
+	checks.push(check_input(config.host.input_backend));
+	checks.extend(check_render_nodes());
+	checks.push(check_encoders(config).await);
+	checks.push(check_capture().await);
+	checks.push(check_avahi());
+	checks.extend(check_ports(config));
+	checks.extend(check_certificate(config));
+	checks.extend(check_firewall(config));
+
+	for check in &checks {
+		let status = match check.status {
+			Status::Ok => "ok",
+			Status::Info => "info",
+			Status::Warning => "warn",
+			Status::Error => "FAIL",
+		};
+		println!("[{status:>4}] {}: {}", check.name, check.message);
+		if let Some(fix) = &check.fix {
+			for line in fix.lines() {
+				println!("       {line}");
+			}
+		}
+	}
+
+	let errors = checks.iter().filter(|check| check.status == Status::Error).count();
+	let warnings = checks.iter().filter(|check| check.status == Status::Warning).count();
+	println!();
+	println!("{errors} error(s), {warnings} warning(s).");
+
+	if errors > 0 {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+fn check_input(backend: InputBackendKind) -> Check {
+	const NAME: &str = "input";
+	match backend {
+		InputBackendKind::Uinput => {},
+		InputBackendKind::Disabled => return Check::new(NAME, Status::Info, "Input of clients is ignored, as configured."),
+		backend => return Check::new(NAME, Status::Info, format!("Input is injected through {backend:?}, /dev/uinput is not needed.")),
+	}
+
+	match std::fs::OpenOptions::new().write(true).open("/dev/uinput") {
+		Ok(_) => Check::new(NAME, Status::Ok, "/dev/uinput is writable."),
+		Err(e) if e.kind() == ErrorKind::NotFound => Check::new(NAME, Status::Error, "/dev/uinput does not exist, clients can't control the host.")
+			.fix("Load the uinput kernel module with `sudo modprobe uinput`, and add `uinput` to /etc/modules-load.d/uinput.conf to load it on boot."),
+		Err(e) if e.kind() == ErrorKind::PermissionDenied => Check::new(NAME, Status::Error, "No write access to /dev/uinput, clients can't control the host.")
+			.fix("Give the logged in user access with a udev rule in /etc/udev/rules.d/60-moonshine.rules:\n  KERNEL==\"uinput\", SUBSYSTEM==\"misc\", TAG+=\"uaccess\", OPTIONS+=\"static_node=uinput\"\nThen reload the rules with `sudo udevadm control --reload && sudo udevadm trigger`, or set `host.input_backend = \"libei\"`."),
+		Err(e) => Check::new(NAME, Status::Error, format!("Failed to open /dev/uinput: {e}")),
+	}
+}
+
+fn check_render_nodes() -> Vec<Check> {
+	const NAME: &str = "render nodes";
+	let nodes = match std::fs::read_dir("/dev/dri") {
+		Ok(entries) => {
+			let mut nodes: Vec<PathBuf> = entries
+				.filter_map(|entry| entry.ok())
+				.map(|entry| entry.path())
+				.filter(|path| path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("renderD")))
+				.collect();
+			nodes.sort();
+			nodes
+		},
+		Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+		Err(e) => return vec![Check::new(NAME, Status::Warning, format!("Failed to list /dev/dri: {e}"))],
+	};
+
+	if nodes.is_empty() {
+		return vec![Check::new(NAME, Status::Warning, "No render nodes found in /dev/dri.")
+			.fix("Install the drivers of the GPU, and pass /dev/dri to the container when running Moonshine in one.")];
+	}
+
+	nodes.into_iter()
+		.map(|node| match std::fs::OpenOptions::new().read(true).write(true).open(&node) {
+			Ok(_) => Check::new(NAME, Status::Ok, format!("{} is accessible.", node.display())),
+			Err(e) if e.kind() == ErrorKind::PermissionDenied => Check::new(NAME, Status::Warning, format!("No access to {}.", node.display()))
+				.fix("Add the user to the render group with `sudo usermod -aG render $USER` and log in again."),
+			Err(e) => Check::new(NAME, Status::Warning, format!("Failed to open {}: {e}", node.display())),
+		})
+		.collect()
+}
+
+async fn check_encoders(config: &Config) -> Check {
+	const NAME: &str = "encoders";
+	if !cfg!(feature = "nvidia") {
+		return Check::new(NAME, Status::Error, "Built without the 'nvidia' feature, sessions can't stream video.")
+			.fix("Build with the default features on a host with an NVIDIA GPU.");
+	}
+
+	let capabilities = EncoderCapabilities::probe(config.stream.video.clone()).await;
+	let available = [("H.264", capabilities.h264), ("HEVC", capabilities.hevc)]
+		.into_iter()
+		.filter_map(|(codec, available)| available.then_some(codec))
+		.collect::<Vec<_>>();
+
+	if available.is_empty() {
+		Check::new(NAME, Status::Error, "None of the configured video encoders could be opened.")
+			.fix("Check that the NVIDIA driver is loaded (`nvidia-smi`) and that FFmpeg was built with NVENC, the log above shows why each encoder failed.")
+	} else if !capabilities.hevc {
+		Check::new(NAME, Status::Warning, format!("Only {} is available.", available.join(", ")))
+			.fix("Clients that prefer HEVC fall back to H.264, check `stream.video.codec_hevc` if the GPU supports HEVC.")
+	} else {
+		Check::new(NAME, Status::Ok, format!("{} available.", available.join(" and ")))
+	}
+}
+
+async fn check_capture() -> Check {
+	const NAME: &str = "capture";
+	if !cfg!(feature = "nvidia") {
+		return Check::new(NAME, Status::Error, "Built without the 'nvidia' feature, there is no capture backend.")
+			.fix("Build with the default features on a host with an NVIDIA GPU.");
+	}
+
+	let framerate = CAPTURE_BENCHMARK_FRAMERATE;
+	let result = tokio::task::spawn_blocking(move || benchmark_capture(framerate, CAPTURE_BENCHMARK_FRAMES)).await;
+	match result {
+		Ok(Ok(result)) => {
+			let message = format!(
+				"{} captures {:.1} fps at {framerate} fps, acquiring a frame takes {:.2?} (p50) to {:.2?} (p95).",
+				result.backend, result.fps, result.latency_p50, result.latency_p95,
+			);
+			if result.fps < framerate as f64 * 0.9 {
+				Check::new(NAME, Status::Warning, message)
+					.fix("Capture can't keep up with the framerate, check that nothing else is capturing the screen and that the GPU isn't fully loaded.")
+			} else {
+				Check::new(NAME, Status::Ok, message)
+			}
+		},
+		Ok(Err(())) => Check::new(NAME, Status::Error, "Failed to capture frames with NvFBC.")
+			.fix("NvFBC requires an NVIDIA GPU and a running X11 session, consumer GPUs need a driver with NvFBC enabled (for example through nvidia-patch)."),
+		Err(e) => Check::new(NAME, Status::Error, format!("Failed to run the capture benchmark: {e}")),
+	}
+}
+
+fn check_avahi() -> Check {
+	const NAME: &str = "avahi";
+	if !cfg!(feature = "mdns") {
+		return Check::new(NAME, Status::Info, "Built without the 'mdns' feature, clients have to add the host by its address.");
+	}
+
+	if Path::new(AVAHI_SOCKET).exists() {
+		Check::new(NAME, Status::Ok, "The Avahi daemon is running, clients find the host automatically.")
+	} else {
+		Check::new(NAME, Status::Warning, "The Avahi daemon is not running, clients have to add the host by its address.")
+			.fix("Install Avahi and start it with `sudo systemctl enable --now avahi-daemon`.")
+	}
+}
+
+/// Ports the host listens on, as (protocol, port, purpose).
+fn ports(config: &Config) -> Vec<(&'static str, u16, &'static str)> {
+	vec![
+		("TCP", config.webserver.port, "HTTP"),
+		("TCP", config.webserver.port_https, "HTTPS"),
+		("TCP", config.stream.port, "RTSP"),
+		("UDP", config.stream.video.port, "video"),
+		("UDP", config.stream.control.port, "control"),
+		("UDP", config.stream.audio.port, "audio"),
+	]
+}
+
+fn check_ports(config: &Config) -> Vec<Check> {
+	const NAME: &str = "ports";
+	let address = match bind_address::resolve(&config.address) {
+		Ok(address) => address,
+		Err(()) => return vec![Check::new(NAME, Status::Error, format!("Failed to resolve the configured address '{}'.", config.address))
+			.fix("Set `address` to an IP address, hostname or network interface of this host.")],
+	};
+
+	ports(config).into_iter()
+		.map(|(protocol, port, purpose)| {
+			let result = match protocol {
+				"TCP" => TcpListener::bind(SocketAddr::new(address, port)).map(|_| ()),
+				_ => UdpSocket::bind(SocketAddr::new(address, port)).map(|_| ()),
+			};
+			let port_name = format!("{protocol} port {port} ({purpose})");
+			match result {
+				Ok(()) => Check::new(NAME, Status::Ok, format!("{port_name} is available.")),
+				Err(e) if e.kind() == ErrorKind::AddrInUse => Check::new(NAME, Status::Error, format!("{port_name} is in use."))
+					.fix(format!("Stop the other process, often Sunshine or a running Moonshine, or change the port in the configuration. `ss -lnp | grep {port}` shows which process it is.")),
+				Err(e) => Check::new(NAME, Status::Error, format!("Failed to bind {port_name} on {address}: {e}")),
+			}
+		})
+		.collect()
+}
+
+fn check_certificate(config: &Config) -> Vec<Check> {
+	const NAME: &str = "certificate";
+	let (Ok(certificate_path), Ok(private_key_path)) = (expand(&config.webserver.certificate), expand(&config.webserver.private_key)) else {
+		return vec![Check::new(NAME, Status::Error, "Failed to expand the certificate paths.")];
+	};
+	if !certificate_path.exists() && !private_key_path.exists() {
+		return vec![Check::new(NAME, Status::Info, format!("No certificate at {}, one is created when the host starts.", certificate_path.display()))];
+	}
+
+	let regenerate = format!(
+		"Remove {} and {} to create a new certificate when the host starts, clients have to pair again afterwards.",
+		certificate_path.display(),
+		private_key_path.display(),
+	);
+	let certificate = match std::fs::read(&certificate_path).map_err(|e| e.to_string())
+		.and_then(|pem| Certificate::from_pem(&pem).map_err(|e| e.to_string()))
+	{
+		Ok(certificate) => certificate,
+		Err(e) => return vec![Check::new(NAME, Status::Error, format!("Failed to read the certificate at {}: {e}", certificate_path.display())).fix(regenerate)],
+	};
+	let private_key = match std::fs::read(&private_key_path).map_err(|e| e.to_string())
+		.and_then(|pem| PrivateKey::from_pem(&pem).map_err(|e| e.to_string()))
+	{
+		Ok(private_key) => private_key,
+		Err(e) => return vec![Check::new(NAME, Status::Error, format!("Failed to read the private key at {}: {e}", private_key_path.display())).fix(regenerate)],
+	};
+
+	let mut checks = Vec::new();
+	match certificate.matches(&private_key) {
+		Ok(true) => {},
+		Ok(false) => checks.push(Check::new(NAME, Status::Error, "The private key does not belong to the certificate.").fix(regenerate.clone())),
+		Err(e) => checks.push(Check::new(NAME, Status::Error, format!("Failed to read the public key of the certificate: {e}")).fix(regenerate.clone())),
+	}
+
+	const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+	let expiry = certificate.not_after()
+		.map(|not_after| not_after.duration_since(SystemTime::now()));
+	match expiry {
+		Ok(Err(expired)) => {
+			let days = expired.duration().as_secs() / SECONDS_PER_DAY;
+			checks.push(Check::new(NAME, Status::Error, format!("The certificate expired {days} days ago.")).fix(regenerate));
+		},
+		Ok(Ok(remaining)) if remaining.as_secs() / SECONDS_PER_DAY < u64::from(CERTIFICATE_EXPIRY_WARNING_DAYS) => {
+			let days = remaining.as_secs() / SECONDS_PER_DAY;
+			checks.push(Check::new(NAME, Status::Warning, format!("The certificate expires in {days} days.")).fix(regenerate));
+		},
+		Ok(Ok(remaining)) => {
+			if checks.is_empty() {
+				let days = remaining.as_secs() / SECONDS_PER_DAY;
+				checks.push(Check::new(NAME, Status::Ok, format!("Valid for another {days} days.")));
+			}
+		},
+		Err(e) => checks.push(Check::new(NAME, Status::Warning, format!("Failed to check the expiry of the certificate: {e}"))),
+	}
+
+	checks
+}
+
+/// Firewalls can't be inspected without root, so this only explains which ports to open in the ones that are installed.
+fn check_firewall(config: &Config) -> Vec<Check> {
+	const NAME: &str = "firewall";
+	let ports = ports(config);
+	let port_list = |protocol: &str| ports.iter()
+		.filter(|(port_protocol, _, _)| *port_protocol == protocol)
+		.map(|(_, port, _)| port.to_string())
+		.collect::<Vec<_>>();
+	let (tcp, udp) = (port_list("TCP"), port_list("UDP"));
+
+	let mut checks = Vec::new();
+	if find_executable("ufw") {
+		let mut commands = tcp.iter().map(|port| format!("sudo ufw allow {port}/tcp")).collect::<Vec<_>>();
+		commands.extend(udp.iter().map(|port| format!("sudo ufw allow {port}/udp")));
+		checks.push(Check::new(NAME, Status::Info, "ufw is installed, if it is enabled it has to allow the ports of the host.").fix(commands.join("\n")));
+	}
+	if find_executable("firewall-cmd") {
+		let mut commands = tcp.iter().map(|port| format!("sudo firewall-cmd --permanent --add-port={port}/tcp")).collect::<Vec<_>>();
+		commands.extend(udp.iter().map(|port| format!("sudo firewall-cmd --permanent --add-port={port}/udp")));
+		commands.push("sudo firewall-cmd --reload".to_string());
+		checks.push(Check::new(NAME, Status::Info, "firewalld is installed, if it is running it has to allow the ports of the host.").fix(commands.join("\n")));
+	}
+	if checks.is_empty() {
+		checks.push(Check::new(NAME, Status::Info, format!(
+			"If a firewall is active, allow TCP ports {} and UDP ports {}.",
+			tcp.join(", "),
+			udp.join(", "),
+		)));
+	}
+
+	checks
+}
+
+/// Whether an executable is in `PATH`, or in the sbin directories that aren't in the `PATH` of regular users.
+fn find_executable(name: &str) -> bool {
+	let path = std::env::var_os("PATH").unwrap_or_default();
+	std::env::split_paths(&path)
+		.chain(["/usr/sbin", "/sbin", "/usr/local/sbin"].into_iter().map(PathBuf::from))
+		.any(|directory| directory.join(name).is_file())
+}
+
+fn expand(path: &Path) -> Result<PathBuf, ()> {
+	let path = path.to_string_lossy().to_string();
+	shellexpand::full(&path)
+		.map(|path| PathBuf::from(path.to_string()))
+		.map_err(|e| tracing::error!("Failed to expand path '{path}': {e}"))
+}
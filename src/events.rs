@@ -0,0 +1,167 @@
+//! Events about sessions and clients, for integrations that react to them (ie. switching OBS scenes when a stream starts).
+//!
+//! Library users subscribe through [`EventBus::subscribe`], external processes can connect to the event socket,
+//! which writes every event as a line of JSON.
+
+use std::path::PathBuf;
+
+use async_shutdown::ShutdownManager;
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, net::{UnixListener, UnixStream}, sync::broadcast};
+
+use crate::session::{history::DisconnectReason, stats::SessionStatsSnapshot, ClientId, SessionId};
+
+/// Number of events kept for subscribers that fall behind, older events are dropped for them.
+const EVENT_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+	/// A client started streaming an application.
+	Started {
+		session_id: SessionId,
+		client_id: ClientId,
+		application: String,
+	},
+
+	/// A session ended and its application was stopped.
+	Stopped {
+		session_id: SessionId,
+		client_id: ClientId,
+		application: String,
+		reason: DisconnectReason,
+	},
+
+	/// A session hasn't received a ping for a while and will time out unless the client responds.
+	TimeoutWarning {
+		session_id: SessionId,
+		client_id: ClientId,
+		/// Seconds until the session times out.
+		remaining: u64,
+	},
+
+	/// A client finished pairing with the host.
+	ClientPaired {
+		client_id: ClientId,
+	},
+
+	/// Periodic statistics of the running session.
+	StatsUpdated {
+		client_id: ClientId,
+		stats: SessionStatsSnapshot,
+	},
+}
+
+/// Broadcasts events to all subscribers, publishing never blocks on slow subscribers.
+#[derive(Clone)]
+pub struct EventBus {
+	sender: broadcast::Sender<SessionEvent>,
+}
+
+impl Default for EventBus {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl EventBus {
+	pub fn new() -> Self {
+		let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+		Self { sender }
+	}
+
+	pub fn publish(&self, event: SessionEvent) {
+		tracing::trace!("Publishing event: {event:?}");
+
+		// Fails if there are no subscribers, which is fine.
+		let _ = self.sender.send(event);
+	}
+
+	pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+		self.sender.subscribe()
+	}
+
+	/// Whether anyone is listening, so that expensive events can be skipped otherwise.
+	pub fn has_subscribers(&self) -> bool {
+		self.sender.receiver_count() > 0
+	}
+}
+
+/// Serves events on a Unix socket, every connection receives all events that are published after it connected.
+pub struct EventSocket {
+	path: PathBuf,
+}
+
+impl EventSocket {
+	pub fn new(path: PathBuf, events: EventBus, shutdown: ShutdownManager<i32>) -> Result<Self, ()> {
+		// A socket left behind by a previous run can't be bound to again.
+		if path.exists() {
+			std::fs::remove_file(&path)
+				.map_err(|e| tracing::error!("Failed to remove stale event socket {}: {e}", path.display()))?;
+		}
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)
+				.map_err(|e| tracing::error!("Failed to create directory for event socket: {e}"))?;
+		}
+
+		let listener = UnixListener::bind(&path)
+			.map_err(|e| tracing::error!("Failed to bind event socket {}: {e}", path.display()))?;
+		tracing::info!("Serving events on {}.", path.display());
+
+		tokio::spawn(async move {
+			let _ = shutdown.wrap_cancel(shutdown.wrap_trigger_shutdown(6, accept(listener, events, shutdown.clone()))).await;
+		});
+
+		Ok(Self { path })
+	}
+}
+
+impl Drop for EventSocket {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
+async fn accept(listener: UnixListener, events: EventBus, shutdown: ShutdownManager<i32>) {
+	loop {
+		let stream = match listener.accept().await {
+			Ok((stream, _)) => stream,
+			Err(e) => {
+				tracing::error!("Failed to accept event socket connection: {e}");
+				continue;
+			},
+		};
+
+		tracing::debug!("Event subscriber connected.");
+		let subscription = events.subscribe();
+		tokio::spawn(shutdown.wrap_cancel(forward(stream, subscription)));
+	}
+}
+
+/// Write events to a subscriber as lines of JSON, until it disconnects.
+async fn forward(mut stream: UnixStream, mut subscription: broadcast::Receiver<SessionEvent>) {
+	loop {
+		let event = match subscription.recv().await {
+			Ok(event) => event,
+			Err(broadcast::error::RecvError::Lagged(skipped)) => {
+				tracing::warn!("Event subscriber fell behind, skipped {skipped} events.");
+				continue;
+			},
+			Err(broadcast::error::RecvError::Closed) => break,
+		};
+
+		let mut line = match serde_json::to_vec(&event) {
+			Ok(line) => line,
+			Err(e) => {
+				tracing::error!("Failed to serialize event: {e}");
+				continue;
+			},
+		};
+		line.push(b'\n');
+
+		if let Err(e) = stream.write_all(&line).await {
+			tracing::debug!("Event subscriber disconnected: {e}");
+			break;
+		}
+	}
+}
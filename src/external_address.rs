@@ -0,0 +1,337 @@
+//! The address that clients outside the local network reach the host on.
+//!
+//! It is either configured, or detected by asking a STUN server which address our packets come from.
+
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
+
+use async_shutdown::ShutdownManager;
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::{config::NetworkConfig, crypto};
+
+/// Interval at which the public address is detected again, it can change when the ISP hands out a new one.
+const DETECT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Time to wait for a response of the STUN server, per attempt.
+const STUN_TIMEOUT: Duration = Duration::from_secs(3);
+const STUN_ATTEMPTS: usize = 3;
+
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_MAGIC_COOKIE: u32 = 0x2112A442;
+const STUN_ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Public address of the host, shared between the services that advertise it.
+#[derive(Clone, Default)]
+pub struct ExternalAddress {
+	address: Arc<Mutex<Option<IpAddr>>>,
+}
+
+impl ExternalAddress {
+	pub fn new(config: NetworkConfig, shutdown: ShutdownManager<i32>) -> Self {
+		let external_address = Self { address: Arc::new(Mutex::new(config.external_address)) };
+
+		// A configured address always takes precedence.
+		if config.external_address.is_some() {
+			return external_address;
+		}
+
+		if let Some(stun_server) = config.stun_server {
+			tokio::spawn(shutdown.wrap_cancel({
+				let external_address = external_address.clone();
+				async move {
+					let mut interval = tokio::time::interval(DETECT_INTERVAL);
+					loop {
+						interval.tick().await;
+						if let Ok(address) = detect(&stun_server).await {
+							external_address.set(address);
+						}
+					}
+				}
+			}));
+		}
+
+		external_address
+	}
+
+	pub fn get(&self) -> Option<IpAddr> {
+		*self.address.lock().ok()?
+	}
+
+	fn set(&self, address: IpAddr) {
+		if let Ok(mut current) = self.address.lock() {
+			if *current != Some(address) {
+				tracing::info!("Detected external address {address}.");
+			}
+			*current = Some(address);
+		}
+	}
+
+	/// Address to advertise to a client that connected to the host on `local_address`, see [`is_external`].
+	///
+	/// Clients that don't need the external address, and all clients if the external address is unknown, get
+	/// `local_address`.
+	pub fn for_client(&self, client_address: IpAddr, local_address: IpAddr) -> IpAddr {
+		if !is_external(client_address, local_address) {
+			return local_address;
+		}
+
+		self.get().unwrap_or(local_address)
+	}
+}
+
+/// Whether a client needs the external address to reach the host.
+///
+/// That is the case for a client outside the local network that connected to a local address of the host, through a
+/// forwarded port. A client that reached the host on a public address can keep using that address.
+pub fn is_external(client_address: IpAddr, local_address: IpAddr) -> bool {
+	!is_local(client_address) && is_local(local_address)
+}
+
+/// Whether an address belongs to a private, shared (carrier-grade NAT), link-local or loopback network.
+pub fn is_local(address: IpAddr) -> bool {
+	match address {
+		IpAddr::V4(address) => {
+			// Shared address space (100.64.0.0/10), used by carrier-grade NAT and VPNs like Tailscale.
+			let shared = address.octets()[0] == 100 && (address.octets()[1] & 0xc0) == 64;
+			address.is_private() || address.is_loopback() || address.is_link_local() || shared
+		},
+		IpAddr::V6(address) => match address.to_ipv4_mapped() {
+			Some(address) => is_local(IpAddr::V4(address)),
+			None => {
+				// Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+				let first = address.segments()[0];
+				address.is_loopback() || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80
+			},
+		},
+	}
+}
+
+/// Ask a STUN server which address our packets come from.
+async fn detect(stun_server: &str) -> Result<IpAddr, ()> {
+	let server = lookup_host(stun_server)
+		.await
+		.map_err(|e| tracing::warn!("Failed to resolve STUN server '{stun_server}': {e}"))?
+		.next()
+		.ok_or_else(|| tracing::warn!("Failed to resolve STUN server '{stun_server}'."))?;
+
+	let bind_address: SocketAddr = match server {
+		SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+		SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+	};
+	let socket = UdpSocket::bind(bind_address)
+		.await
+		.map_err(|e| tracing::warn!("Failed to bind socket for STUN request: {e}"))?;
+
+	let transaction_id = crypto::random_bytes::<12>()
+		.map_err(|e| tracing::warn!("Failed to create STUN transaction ID: {e}"))?;
+	let mut request = Vec::with_capacity(20);
+	request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+	request.extend_from_slice(&0u16.to_be_bytes());
+	request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+	request.extend_from_slice(&transaction_id);
+
+	let mut buffer = [0u8; 512];
+	for _ in 0..STUN_ATTEMPTS {
+		socket.send_to(&request, server)
+			.await
+			.map_err(|e| tracing::warn!("Failed to send STUN request to {server}: {e}"))?;
+
+		let length = match tokio::time::timeout(STUN_TIMEOUT, socket.recv_from(&mut buffer)).await {
+			Ok(Ok((length, address))) if address == server => length,
+			Ok(Ok(_)) => continue,
+			Ok(Err(e)) => {
+				tracing::warn!("Failed to receive STUN response: {e}");
+				return Err(());
+			},
+			Err(_) => continue,
+		};
+
+		if let Some(address) = parse_binding_response(&buffer[..length], &transaction_id) {
+			return Ok(address);
+		}
+	}
+
+	tracing::warn!("No valid response from STUN server {server}, the external address is unknown.");
+	Err(())
+}
+
+/// Read the mapped address from a STUN binding response, see RFC 5389.
+fn parse_binding_response(response: &[u8], transaction_id: &[u8; 12]) -> Option<IpAddr> {
+	if response.len() < 20
+		|| u16::from_be_bytes([response[0], response[1]]) != STUN_BINDING_RESPONSE
+		|| u32::from_be_bytes([response[4], response[5], response[6], response[7]]) != STUN_MAGIC_COOKIE
+		|| &response[8..20] != transaction_id
+	{
+		return None;
+	}
+
+	let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+	let mut attributes = response.get(20..20 + length)?;
+	let mut mapped_address = None;
+	while attributes.len() >= 4 {
+		let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+		let length = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+		let value = attributes.get(4..4 + length)?;
+
+		match kind {
+			// XOR-MAPPED-ADDRESS is preferred, some NATs rewrite addresses in the plain MAPPED-ADDRESS.
+			STUN_ATTRIBUTE_XOR_MAPPED_ADDRESS => return parse_address(value, Some(&response[4..20])),
+			STUN_ATTRIBUTE_MAPPED_ADDRESS => mapped_address = parse_address(value, None),
+			_ => {},
+		}
+
+		// Attributes are padded to a multiple of 4 bytes.
+		let padded = (4 + length + 3) & !3;
+		attributes = attributes.get(padded..).unwrap_or_default();
+	}
+
+	mapped_address
+}
+
+/// Parse the value of an address attribute, `xor` holds the magic cookie and transaction ID for XOR-MAPPED-ADDRESS.
+fn parse_address(value: &[u8], xor: Option<&[u8]>) -> Option<IpAddr> {
+	let family = *value.get(1)?;
+	let length = match family {
+		0x01 => 4,
+		0x02 => 16,
+		_ => return None,
+	};
+
+	let mut octets = value.get(4..4 + length)?.to_vec();
+	if let Some(xor) = xor {
+		for (octet, key) in octets.iter_mut().zip(xor) {
+			*octet ^= key;
+		}
+	}
+
+	match family {
+		0x01 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(octets).ok()?))),
+		_ => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TRANSACTION_ID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+	fn address(address: &str) -> IpAddr {
+		address.parse().unwrap()
+	}
+
+	/// A binding response with these attributes, as (type, value).
+	fn binding_response(attributes: &[(u16, Vec<u8>)]) -> Vec<u8> {
+		let mut body = Vec::new();
+		for (kind, value) in attributes {
+			body.extend_from_slice(&kind.to_be_bytes());
+			body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+			body.extend_from_slice(value);
+			body.resize((body.len() + 3) & !3, 0);
+		}
+
+		let mut response = Vec::new();
+		response.extend_from_slice(&STUN_BINDING_RESPONSE.to_be_bytes());
+		response.extend_from_slice(&(body.len() as u16).to_be_bytes());
+		response.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+		response.extend_from_slice(&TRANSACTION_ID);
+		response.extend_from_slice(&body);
+		response
+	}
+
+	/// Value of an address attribute, XORed with the magic cookie and transaction ID like XOR-MAPPED-ADDRESS if `xor`.
+	fn address_value(address: IpAddr, port: u16, xor: bool) -> Vec<u8> {
+		let (family, mut octets) = match address {
+			IpAddr::V4(address) => (0x01, address.octets().to_vec()),
+			IpAddr::V6(address) => (0x02, address.octets().to_vec()),
+		};
+		if xor {
+			let key: Vec<u8> = STUN_MAGIC_COOKIE.to_be_bytes().into_iter().chain(TRANSACTION_ID).collect();
+			for (octet, key) in octets.iter_mut().zip(key) {
+				*octet ^= key;
+			}
+		}
+
+		[vec![0, family], port.to_be_bytes().to_vec(), octets].concat()
+	}
+
+	#[test]
+	fn local_addresses() {
+		for local in ["10.0.0.1", "172.16.5.4", "192.168.1.10", "127.0.0.1", "169.254.1.1", "100.64.0.1", "100.127.255.254", "::1", "fd12::1", "fe80::1", "::ffff:192.168.1.10"] {
+			assert!(is_local(address(local)), "{local} is local");
+		}
+		for public in ["203.0.113.7", "8.8.8.8", "100.63.255.255", "100.128.0.1", "2001:db8::1", "::ffff:203.0.113.7"] {
+			assert!(!is_local(address(public)), "{public} is public");
+		}
+	}
+
+	#[test]
+	fn external_address_only_behind_nat() {
+		let external_address = ExternalAddress::default();
+		external_address.set(address("203.0.113.7"));
+
+		// A remote client that reached a private address of the host came through a forwarded port.
+		assert_eq!(external_address.for_client(address("198.51.100.20"), address("192.168.1.10")), address("203.0.113.7"));
+
+		// Clients on the local network, and clients that reached the host on a public address, use the address they connected to.
+		assert_eq!(external_address.for_client(address("192.168.1.20"), address("192.168.1.10")), address("192.168.1.10"));
+		assert_eq!(external_address.for_client(address("100.101.102.103"), address("100.64.0.1")), address("100.64.0.1"));
+		assert_eq!(external_address.for_client(address("198.51.100.20"), address("203.0.113.8")), address("203.0.113.8"));
+
+		// Without an external address, every client gets the address it connected to.
+		assert_eq!(ExternalAddress::default().for_client(address("198.51.100.20"), address("192.168.1.10")), address("192.168.1.10"));
+	}
+
+	#[test]
+	fn xor_mapped_address() {
+		for mapped in [address("203.0.113.7"), address("2001:db8::7")] {
+			let response = binding_response(&[(STUN_ATTRIBUTE_XOR_MAPPED_ADDRESS, address_value(mapped, 47989, true))]);
+			assert_eq!(parse_binding_response(&response, &TRANSACTION_ID), Some(mapped));
+		}
+	}
+
+	#[test]
+	fn xor_mapped_address_is_preferred() {
+		let response = binding_response(&[
+			(STUN_ATTRIBUTE_MAPPED_ADDRESS, address_value(address("192.168.1.1"), 47989, false)),
+			(0x8022, b"software".to_vec()),
+			(STUN_ATTRIBUTE_XOR_MAPPED_ADDRESS, address_value(address("203.0.113.7"), 47989, true)),
+		]);
+		assert_eq!(parse_binding_response(&response, &TRANSACTION_ID), Some(address("203.0.113.7")));
+
+		let response = binding_response(&[(STUN_ATTRIBUTE_MAPPED_ADDRESS, address_value(address("203.0.113.7"), 47989, false))]);
+		assert_eq!(parse_binding_response(&response, &TRANSACTION_ID), Some(address("203.0.113.7")));
+	}
+
+	#[test]
+	fn invalid_binding_responses() {
+		let response = binding_response(&[(STUN_ATTRIBUTE_XOR_MAPPED_ADDRESS, address_value(address("203.0.113.7"), 47989, true))]);
+
+		// Another transaction, another message type or a missing magic cookie.
+		assert_eq!(parse_binding_response(&response, &[0; 12]), None);
+		let mut request = response.clone();
+		request[..2].copy_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+		assert_eq!(parse_binding_response(&request, &TRANSACTION_ID), None);
+		let mut classic = response.clone();
+		classic[4..8].fill(0);
+		assert_eq!(parse_binding_response(&classic, &TRANSACTION_ID), None);
+
+		// Truncated headers, attributes and addresses.
+		for length in [0, 19, 20, 23, response.len() - 1] {
+			assert_eq!(parse_binding_response(&response[..length], &TRANSACTION_ID), None, "truncated to {length} bytes");
+		}
+		let mut short = binding_response(&[(STUN_ATTRIBUTE_XOR_MAPPED_ADDRESS, address_value(address("203.0.113.7"), 47989, true)[..6].to_vec())]);
+		assert_eq!(parse_binding_response(&short, &TRANSACTION_ID), None);
+
+		// An attribute that claims to be longer than the message.
+		short[22..24].copy_from_slice(&64u16.to_be_bytes());
+		assert_eq!(parse_binding_response(&short, &TRANSACTION_ID), None);
+
+		// An unknown address family.
+		let mut value = address_value(address("203.0.113.7"), 47989, true);
+		value[1] = 0x03;
+		assert_eq!(parse_binding_response(&binding_response(&[(STUN_ATTRIBUTE_XOR_MAPPED_ADDRESS, value)]), &TRANSACTION_ID), None);
+	}
+}
@@ -0,0 +1,65 @@
+use super::{host_command_output, run_host_command};
+
+/// Name of the virtual sink that applications play to while the host is muted.
+const STREAM_SINK_NAME: &str = "moonshine";
+
+/// Mutes the physical audio output of the host while it exists, the previous output is restored when dropped.
+///
+/// Applications are moved to a virtual sink that isn't connected to any speakers.
+/// The audio stream captures the monitor of the default sink, so the client keeps hearing everything the host plays.
+/// Works with PulseAudio and with PipeWire through `pipewire-pulse`.
+pub struct HostAudioMuteGuard {
+	/// Default sink before the host was muted.
+	previous_sink: String,
+
+	/// Index of the module that created the virtual sink.
+	module: String,
+}
+
+impl HostAudioMuteGuard {
+	pub fn enable() -> Result<Self, ()> {
+		let previous_sink = host_command_output(&["pactl", "get-default-sink"])
+			.map_err(|()| tracing::warn!("Failed to get the default audio sink, is PulseAudio or pipewire-pulse running?"))?
+			.trim()
+			.to_string();
+
+		let module = host_command_output(&[
+			"pactl",
+			"load-module",
+			"module-null-sink",
+			&format!("sink_name={STREAM_SINK_NAME}"),
+			"sink_properties=device.description=Moonshine",
+		])?
+			.trim()
+			.to_string();
+
+		tracing::info!("Muting host audio, moving playback from '{previous_sink}' to '{STREAM_SINK_NAME}'.");
+		let guard = Self { previous_sink, module };
+		run_host_command(&["pactl", "set-default-sink", STREAM_SINK_NAME])?;
+		move_sink_inputs(STREAM_SINK_NAME);
+
+		Ok(guard)
+	}
+}
+
+impl Drop for HostAudioMuteGuard {
+	fn drop(&mut self) {
+		tracing::info!("Restoring host audio output '{}'.", self.previous_sink);
+		let _ = run_host_command(&["pactl", "set-default-sink", &self.previous_sink]);
+		move_sink_inputs(&self.previous_sink);
+
+		// Unloading the module removes the virtual sink, anything still playing to it moves to the default sink.
+		let _ = run_host_command(&["pactl", "unload-module", &self.module]);
+	}
+}
+
+/// Move everything that is currently playing to `sink`.
+fn move_sink_inputs(sink: &str) {
+	let Ok(sink_inputs) = host_command_output(&["pactl", "list", "short", "sink-inputs"]) else {
+		return;
+	};
+
+	for sink_input in sink_inputs.lines().filter_map(|line| line.split_whitespace().next()) {
+		let _ = run_host_command(&["pactl", "move-sink-input", sink_input, sink]);
+	}
+}
@@ -0,0 +1,91 @@
+use super::{host_command_output, run_host_command};
+
+/// Compositors that can be disabled while streaming.
+enum Compositor {
+	/// KWin, compositing is suspended over D-Bus.
+	KWin,
+
+	/// Xfwm4, compositing is disabled through xfconf.
+	Xfwm,
+
+	/// A standalone compositor process, such as picom, which is stopped and started again with the same arguments.
+	Standalone { command: Vec<String> },
+}
+
+/// Standalone X11 compositors that are stopped while streaming.
+const STANDALONE_COMPOSITORS: &[&str] = &["picom", "compton"];
+
+/// Disables the X11 compositor while it exists, compositing is restored when dropped.
+///
+/// Compositors add a frame of latency between an application drawing a frame and that frame being captured.
+pub struct CompositorGuard {
+	compositor: Compositor,
+}
+
+impl CompositorGuard {
+	pub fn enable() -> Result<Self, ()> {
+		if std::env::var_os("DISPLAY").is_none() {
+			tracing::warn!("Disabling the compositor is only supported on X11.");
+			return Err(());
+		}
+
+		if host_command_output(&["qdbus", "org.kde.KWin", "/Compositor", "org.kde.kwin.Compositing.active"]).is_ok_and(|active| active.trim() == "true") {
+			tracing::info!("Suspending KWin compositing.");
+			run_host_command(&["qdbus", "org.kde.KWin", "/Compositor", "suspend"])?;
+			return Ok(Self { compositor: Compositor::KWin });
+		}
+
+		if host_command_output(&["xfconf-query", "-c", "xfwm4", "-p", "/general/use_compositing"]).is_ok_and(|enabled| enabled.trim() == "true") {
+			tracing::info!("Disabling xfwm4 compositing.");
+			run_host_command(&["xfconf-query", "-c", "xfwm4", "-p", "/general/use_compositing", "-s", "false"])?;
+			return Ok(Self { compositor: Compositor::Xfwm });
+		}
+
+		for name in STANDALONE_COMPOSITORS {
+			let Ok(pids) = host_command_output(&["pgrep", "-x", name]) else {
+				continue;
+			};
+			let Some(pid) = pids.lines().next() else {
+				continue;
+			};
+
+			// Remember how the compositor was started, so it can be started the same way afterwards.
+			let command: Vec<String> = std::fs::read(format!("/proc/{}/cmdline", pid.trim()))
+				.map_err(|e| tracing::warn!("Failed to read command line of {name}: {e}"))?
+				.split(|&byte| byte == 0)
+				.filter(|argument| !argument.is_empty())
+				.map(|argument| String::from_utf8_lossy(argument).into_owned())
+				.collect();
+
+			tracing::info!("Stopping {name}.");
+			run_host_command(&["pkill", "-x", name])?;
+			return Ok(Self { compositor: Compositor::Standalone { command } });
+		}
+
+		tracing::warn!("No supported compositor is running, compositing will not be disabled.");
+		Err(())
+	}
+}
+
+impl Drop for CompositorGuard {
+	fn drop(&mut self) {
+		match &self.compositor {
+			Compositor::KWin => {
+				tracing::info!("Resuming KWin compositing.");
+				let _ = run_host_command(&["qdbus", "org.kde.KWin", "/Compositor", "resume"]);
+			},
+			Compositor::Xfwm => {
+				tracing::info!("Restoring xfwm4 compositing.");
+				let _ = run_host_command(&["xfconf-query", "-c", "xfwm4", "-p", "/general/use_compositing", "-s", "true"]);
+			},
+			Compositor::Standalone { command } => {
+				tracing::info!("Restarting compositor: {command:?}");
+
+				// Detach the compositor from Moonshine, so it keeps running when Moonshine exits.
+				let mut detached = vec!["setsid", "-f"];
+				detached.extend(command.iter().map(String::as_str));
+				let _ = run_host_command(&detached);
+			},
+		}
+	}
+}
@@ -0,0 +1,148 @@
+//! Hiding the physical host display while a session is running, without changing what is captured.
+//!
+//! Turning the display off through the session doesn't work: DPMS is undone by the first input event of the client, and
+//! a locked session is what the client would then be streaming. Instead only the picture that reaches the monitors is
+//! blanked. On X11 the gamma of every output is set to black, which the captured framebuffer doesn't pass through. On
+//! Wayland the monitors are powered off over DDC/CI with `ddcutil`, while the compositor keeps its outputs.
+
+use crate::config::PrivacyMode;
+
+use super::{host_command_output, run_host_command};
+
+/// VCP code of the power mode of a monitor, with the values to turn it on and off.
+const DDC_POWER_MODE: &str = "D6";
+const DDC_POWER_ON: &str = "01";
+const DDC_POWER_OFF: &str = "04";
+
+/// How the physical displays were hidden.
+enum Blanking {
+	/// Outputs of the X server that have a black gamma, with the brightness they had before.
+	Gamma(Vec<(String, String)>),
+
+	/// Monitors that were powered off over DDC/CI, by their `ddcutil` display number.
+	Ddc(Vec<String>),
+}
+
+/// Hides the physical host display while it exists, the display is restored in the background when dropped.
+pub struct PrivacyGuard {
+	blanking: Option<Blanking>,
+}
+
+impl PrivacyGuard {
+	pub fn enable(mode: PrivacyMode) -> Result<Self, ()> {
+		let blanking = match mode {
+			PrivacyMode::Blank => blank()?,
+		};
+
+		Ok(Self { blanking: Some(blanking) })
+	}
+}
+
+impl Drop for PrivacyGuard {
+	fn drop(&mut self) {
+		let Some(blanking) = self.blanking.take() else {
+			return;
+		};
+
+		// The commands can take a while, so they don't run on the task that ends the session.
+		match tokio::runtime::Handle::try_current() {
+			Ok(runtime) => {
+				runtime.spawn_blocking(move || blanking.restore());
+			},
+			Err(_) => blanking.restore(),
+		}
+	}
+}
+
+impl Blanking {
+	fn restore(self) {
+		tracing::info!("Restoring host display.");
+		match self {
+			Self::Gamma(outputs) => {
+				for (output, brightness) in outputs {
+					let _ = run_host_command(&["xrandr", "--output", &output, "--brightness", &brightness]);
+				}
+			},
+			Self::Ddc(displays) => {
+				for display in displays {
+					let _ = run_host_command(&["ddcutil", "--display", &display, "setvcp", DDC_POWER_MODE, DDC_POWER_ON]);
+				}
+			},
+		}
+	}
+}
+
+/// Blank the physical displays in the way the running session allows.
+fn blank() -> Result<Blanking, ()> {
+	if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+		blank_ddc()
+	} else if std::env::var_os("DISPLAY").is_some() {
+		blank_gamma()
+	} else {
+		tracing::warn!("Blanking the host display requires an X11 or Wayland session.");
+		Err(())
+	}
+}
+
+/// Set the gamma of every active X11 output to black.
+fn blank_gamma() -> Result<Blanking, ()> {
+	let outputs = host_command_output(&["xrandr", "--verbose"])
+		.map_err(|()| tracing::warn!("Failed to list outputs, is xrandr installed?"))?;
+
+	tracing::info!("Blanking host display.");
+	let outputs: Vec<(String, String)> = active_outputs(&outputs)
+		.into_iter()
+		.filter(|(output, _)| run_host_command(&["xrandr", "--output", output, "--brightness", "0"]).is_ok())
+		.collect();
+	if outputs.is_empty() {
+		tracing::warn!("No output could be blanked.");
+		return Err(());
+	}
+
+	Ok(Blanking::Gamma(outputs))
+}
+
+/// Outputs that show part of the desktop, with their brightness, from the output of `xrandr --verbose`.
+fn active_outputs(xrandr: &str) -> Vec<(String, String)> {
+	let mut outputs = Vec::new();
+	let mut output = None;
+	for line in xrandr.lines() {
+		// Outputs are listed as "DP-1 connected primary 2560x1440+0+0 ...", followed by indented properties.
+		if !line.starts_with(char::is_whitespace) {
+			let parts: Vec<&str> = line.split_whitespace().collect();
+			let active = parts.get(1) == Some(&"connected") && parts.iter().skip(2).take(2).any(|part| part.contains('+'));
+			output = active.then(|| parts[0].to_string());
+			continue;
+		}
+
+		let Some(brightness) = line.trim().strip_prefix("Brightness:").map(str::trim) else {
+			continue;
+		};
+		if let Some(output) = output.take() {
+			// An output that is still being restored after the previous session is given its full brightness back.
+			let brightness = if brightness.parse::<f32>().is_ok_and(|brightness| brightness > 0.0) { brightness } else { "1.0" };
+			outputs.push((output, brightness.to_string()));
+		}
+	}
+
+	outputs
+}
+
+/// Power off every monitor that supports DDC/CI.
+fn blank_ddc() -> Result<Blanking, ()> {
+	let displays = host_command_output(&["ddcutil", "detect", "--terse"])
+		.map_err(|()| tracing::warn!("Failed to list monitors, is ddcutil installed and allowed to access /dev/i2c-*?"))?;
+
+	tracing::info!("Blanking host display.");
+	let displays: Vec<String> = displays.lines()
+		.filter_map(|line| line.strip_prefix("Display "))
+		.map(|display| display.trim().to_string())
+		.filter(|display| run_host_command(&["ddcutil", "--display", display, "setvcp", DDC_POWER_MODE, DDC_POWER_OFF]).is_ok())
+		.collect();
+	if displays.is_empty() {
+		tracing::warn!("No monitor could be powered off over DDC/CI.");
+		return Err(());
+	}
+
+	Ok(Blanking::Ddc(displays))
+}
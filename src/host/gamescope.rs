@@ -0,0 +1,66 @@
+//! Running applications inside a gamescope session, at the resolution and refresh rate of the client.
+
+use std::time::Duration;
+
+use crate::config::CaptureRegion;
+
+use super::host_command_output;
+
+/// Title of the window that gamescope creates when it runs nested in an X11 session.
+const GAMESCOPE_WINDOW_NAME: &str = "gamescope";
+
+/// Time to wait for the gamescope window to appear.
+const WINDOW_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wrap a command so that it runs in a fullscreen gamescope session.
+pub fn wrap_command(command: &[String], resolution: (u32, u32), refresh_rate: u32) -> Vec<String> {
+	let (width, height) = (resolution.0.to_string(), resolution.1.to_string());
+	let mut wrapped: Vec<String> = [
+		"gamescope",
+		"-w", &width, "-h", &height,
+		"-W", &width, "-H", &height,
+		"-r", &refresh_rate.to_string(),
+		"-f",
+		"--",
+	]
+		.iter()
+		.map(|argument| argument.to_string())
+		.collect();
+	wrapped.extend_from_slice(command);
+
+	wrapped
+}
+
+/// Find the part of the desktop that shows the gamescope output, waiting for gamescope to start if necessary.
+pub async fn window_region() -> Result<CaptureRegion, ()> {
+	let deadline = tokio::time::Instant::now() + WINDOW_TIMEOUT;
+	loop {
+		if let Some(region) = find_window() {
+			return Ok(region);
+		}
+
+		if tokio::time::Instant::now() > deadline {
+			tracing::warn!("No gamescope window appeared within {} seconds.", WINDOW_TIMEOUT.as_secs());
+			return Err(());
+		}
+
+		tokio::time::sleep(Duration::from_millis(250)).await;
+	}
+}
+
+fn find_window() -> Option<CaptureRegion> {
+	let info = host_command_output(&["xwininfo", "-name", GAMESCOPE_WINDOW_NAME]).ok()?;
+
+	let value = |key: &str| -> Option<u32> {
+		info.lines()
+			.find_map(|line| line.trim().strip_prefix(key))
+			.and_then(|value| value.trim().parse().ok())
+	};
+
+	Some(CaptureRegion {
+		x: value("Absolute upper-left X:")?,
+		y: value("Absolute upper-left Y:")?,
+		width: value("Width:")?,
+		height: value("Height:")?,
+	})
+}
@@ -0,0 +1,41 @@
+use std::process::{Child, Stdio};
+
+/// Keeps the host from suspending, and the screen locker from kicking in, while it exists.
+///
+/// The locks are taken through `systemd-inhibit`, which holds logind inhibitor locks for as long as the command it runs.
+/// Dropping the guard stops that command, which releases the locks.
+pub struct SleepInhibitGuard {
+	process: Child,
+}
+
+impl SleepInhibitGuard {
+	pub fn enable() -> Result<Self, ()> {
+		let process = std::process::Command::new("systemd-inhibit")
+			.args([
+				"--what=sleep:idle:handle-lid-switch",
+				"--who=Moonshine",
+				"--why=A client is streaming from this host",
+				"--mode=block",
+				"sleep",
+				"infinity",
+			])
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.stdin(Stdio::null())
+			.spawn()
+			.map_err(|e| tracing::warn!("Failed to run systemd-inhibit, the host may suspend while streaming: {e}"))?;
+
+		tracing::info!("Inhibiting sleep and idle while the session is running.");
+		Ok(Self { process })
+	}
+}
+
+impl Drop for SleepInhibitGuard {
+	fn drop(&mut self) {
+		tracing::info!("Releasing sleep and idle inhibitor.");
+		if let Err(e) = self.process.kill() {
+			tracing::warn!("Failed to stop systemd-inhibit: {e}");
+		}
+		let _ = self.process.wait();
+	}
+}
@@ -0,0 +1,47 @@
+//! Integrations with the host desktop that are active while a session is running.
+
+use std::process::Stdio;
+
+pub mod audio;
+pub mod compositor;
+pub mod display;
+pub mod gamescope;
+pub mod inhibit;
+pub mod monitors;
+pub mod notifications;
+
+/// Run a command on the host and wait for it to finish.
+fn run_host_command(command: &[&str]) -> Result<(), ()> {
+	let status = std::process::Command::new(command[0])
+		.args(&command[1..])
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.stdin(Stdio::null())
+		.status()
+		.map_err(|e| tracing::warn!("Failed to run {command:?}: {e}"))?;
+
+	if !status.success() {
+		tracing::warn!("Command {command:?} failed with {status}.");
+		return Err(());
+	}
+
+	Ok(())
+}
+
+/// Run a command on the host and return what it printed to stdout.
+fn host_command_output(command: &[&str]) -> Result<String, ()> {
+	let output = std::process::Command::new(command[0])
+		.args(&command[1..])
+		.stderr(Stdio::null())
+		.stdin(Stdio::null())
+		.output()
+		.map_err(|e| tracing::debug!("Failed to run {command:?}: {e}"))?;
+
+	if !output.status.success() {
+		tracing::debug!("Command {command:?} failed with {}.", output.status);
+		return Err(());
+	}
+
+	String::from_utf8(output.stdout)
+		.map_err(|e| tracing::warn!("Command {command:?} produced invalid output: {e}"))
+}
@@ -0,0 +1,72 @@
+//! Lookup of the monitors that make up the desktop.
+
+use serde::Serialize;
+
+use crate::config::CaptureRegion;
+
+use super::host_command_output;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Monitor {
+	/// Name of the output the monitor is connected to (for example "DP-1").
+	pub output: String,
+
+	/// Part of the desktop that is shown on this monitor.
+	pub region: CaptureRegion,
+
+	/// Whether this is the primary monitor.
+	pub primary: bool,
+}
+
+/// List the monitors that make up the desktop.
+pub fn list_monitors() -> Result<Vec<Monitor>, ()> {
+	let monitors = host_command_output(&["xrandr", "--listmonitors"])
+		.map_err(|()| tracing::warn!("Failed to list monitors, is xrandr installed?"))?;
+
+	// Each monitor is listed as " 0: +*DP-1 2560/597x1440/336+0+0  DP-1".
+	let monitors = monitors.lines()
+		.skip(1)
+		.filter_map(|line| {
+			let parts: Vec<&str> = line.split_whitespace().collect();
+			if parts.len() < 4 {
+				return None;
+			}
+
+			let region = parse_geometry(parts[2])
+				.or_else(|| { tracing::warn!("Failed to parse monitor geometry '{}'.", parts[2]); None })?;
+
+			Some(Monitor {
+				output: parts[parts.len() - 1].to_string(),
+				region,
+				primary: parts[1].contains('*'),
+			})
+		})
+		.collect();
+
+	Ok(monitors)
+}
+
+/// Find the part of the desktop that is shown on the monitor connected to `output`.
+pub fn output_region(output: &str) -> Result<CaptureRegion, ()> {
+	list_monitors()?
+		.into_iter()
+		.find(|monitor| monitor.output == output)
+		.map(|monitor| monitor.region)
+		.ok_or_else(|| tracing::warn!("No monitor found that is connected to output '{output}'."))
+}
+
+/// Parse a geometry in the form "<width>/<mm>x<height>/<mm>+<x>+<y>".
+fn parse_geometry(geometry: &str) -> Option<CaptureRegion> {
+	let (size, offset) = geometry.split_once('+')?;
+	let (x, y) = offset.split_once('+')?;
+	let (width, height) = size.split_once('x')?;
+	let width = width.split('/').next()?;
+	let height = height.split('/').next()?;
+
+	Some(CaptureRegion {
+		x: x.parse().ok()?,
+		y: y.parse().ok()?,
+		width: width.parse().ok()?,
+		height: height.parse().ok()?,
+	})
+}
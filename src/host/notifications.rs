@@ -0,0 +1,51 @@
+use super::{host_command_output, run_host_command};
+
+/// Notification daemons for which notifications can be suppressed.
+enum NotificationDaemon {
+	/// GNOME Shell, notifications are suppressed by disabling banners.
+	Gnome { show_banners: bool },
+
+	/// Dunst, notifications are suppressed by pausing the daemon.
+	Dunst { paused: bool },
+}
+
+/// Suppresses host notifications while it exists, the previous state is restored when dropped.
+///
+/// Notifications would otherwise be visible in the captured video.
+pub struct DoNotDisturbGuard {
+	daemon: NotificationDaemon,
+}
+
+impl DoNotDisturbGuard {
+	pub fn enable() -> Result<Self, ()> {
+		if let Ok(show_banners) = host_command_output(&["gsettings", "get", "org.gnome.desktop.notifications", "show-banners"]) {
+			tracing::info!("Disabling GNOME notification banners.");
+			run_host_command(&["gsettings", "set", "org.gnome.desktop.notifications", "show-banners", "false"])?;
+			return Ok(Self { daemon: NotificationDaemon::Gnome { show_banners: show_banners.trim() == "true" } });
+		}
+
+		if let Ok(paused) = host_command_output(&["dunstctl", "is-paused"]) {
+			tracing::info!("Pausing dunst notifications.");
+			run_host_command(&["dunstctl", "set-paused", "true"])?;
+			return Ok(Self { daemon: NotificationDaemon::Dunst { paused: paused.trim() == "true" } });
+		}
+
+		tracing::warn!("No supported notification daemon found, host notifications will not be suppressed.");
+		Err(())
+	}
+}
+
+impl Drop for DoNotDisturbGuard {
+	fn drop(&mut self) {
+		match self.daemon {
+			NotificationDaemon::Gnome { show_banners } => {
+				tracing::info!("Restoring GNOME notification banners.");
+				let _ = run_host_command(&["gsettings", "set", "org.gnome.desktop.notifications", "show-banners", &show_banners.to_string()]);
+			},
+			NotificationDaemon::Dunst { paused } => {
+				tracing::info!("Restoring dunst notifications.");
+				let _ = run_host_command(&["dunstctl", "set-paused", &paused.to_string()]);
+			},
+		}
+	}
+}
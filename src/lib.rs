@@ -0,0 +1,295 @@
+//! A GameStream host for Moonlight clients.
+//!
+//! The binary is a thin wrapper around this library, other projects can embed a host with [`Moonshine::builder`]:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), ()> {
+//! let config = moonshine_core::config::Config::read_from_file("config.toml")?;
+//! let exit_code = moonshine_core::Moonshine::builder().config(config).run().await?;
+//! println!("Moonshine stopped with exit code {exit_code}.");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{io::Write, path::Path};
+
+use async_shutdown::ShutdownManager;
+
+use crate::app_scanner::ApplicationCatalog;
+use crate::clients::ClientManager;
+use crate::config::Config;
+use crate::crypto::{certificate_fingerprint, create_certificate, Certificate, PrivateKey};
+use crate::events::EventSocket;
+use crate::external_address::ExternalAddress;
+use crate::session::stream::EncoderCapabilities;
+use crate::state::State;
+use crate::supervisor::Supervisor;
+use crate::transcript::Transcript;
+
+pub use crate::events::{EventBus, SessionEvent};
+pub use crate::logging::Logs;
+pub use crate::publisher::ServicePublisher;
+pub use crate::rtsp::RtspServer;
+pub use crate::session::SessionManager;
+pub use crate::session::stream::{benchmark_conversion, benchmark_encoders, BenchmarkOptions, BenchmarkResult};
+pub use crate::session::{history::DisconnectReason, stats::SessionStatsSnapshot};
+pub use crate::webserver::Webserver;
+
+mod app_scanner;
+mod bind_address;
+mod clients;
+pub mod config;
+mod crypto;
+pub mod doctor;
+mod events;
+mod external_address;
+#[cfg(feature = "nvidia")]
+mod ffmpeg;
+mod host;
+pub mod logging;
+mod rtsp;
+mod session;
+mod state;
+mod storage;
+mod publisher;
+mod supervisor;
+pub mod sunshine;
+mod transcript;
+mod webserver;
+#[cfg(feature = "webrtc")]
+mod whep;
+
+/// A running GameStream host, dropping it stops all of its services.
+pub struct Moonshine {
+	shutdown: ShutdownManager<i32>,
+	events: EventBus,
+	_rtsp_server: RtspServer,
+	_session_manager: SessionManager,
+	_client_manager: ClientManager,
+	_webserver: Webserver,
+	_publisher: ServicePublisher,
+	_event_socket: Option<EventSocket>,
+}
+
+impl Moonshine {
+	pub fn builder() -> MoonshineBuilder {
+		MoonshineBuilder::default()
+	}
+
+	/// Handle to stop the host, triggering a shutdown with an exit code stops all services.
+	pub fn shutdown_handle(&self) -> ShutdownManager<i32> {
+		self.shutdown.clone()
+	}
+
+	/// Bus that session lifecycle events are published on, see [`SessionEvent`].
+	pub fn events(&self) -> EventBus {
+		self.events.clone()
+	}
+
+	/// Run until a shutdown is triggered and all services stopped, returns the exit code of the shutdown.
+	pub async fn run(self) -> i32 {
+		let shutdown = self.shutdown.clone();
+
+		// Wait until something causes a shutdown trigger.
+		shutdown.wait_shutdown_triggered().await;
+
+		// Drop the main moonshine object, triggering other systems to shutdown too.
+		drop(self);
+
+		// Wait until everything was shutdown.
+		let exit_code = shutdown.wait_shutdown_complete().await;
+		tracing::trace!("Successfully waited for shutdown to complete.");
+		exit_code
+	}
+
+	async fn new(
+		mut config: Config,
+		logs: Logs,
+		events: EventBus,
+		shutdown: ShutdownManager<i32>,
+		web_runtime: tokio::runtime::Handle,
+	) -> Result<Self, ()> {
+		// Hostnames and interface names are resolved once, so every server and stream binds to the same address.
+		config.address = bind_address::resolve(&config.address)?.to_string();
+
+		let state = State::new().await?;
+
+		let (cert, pkey) = if !config.webserver.certificate.exists() && !config.webserver.private_key.exists() {
+			tracing::info!("No certificate found, creating a new one.");
+
+			let (cert, pkey) = create_certificate()
+				.map_err(|e| tracing::error!("Failed to create certificate: {e}"))?;
+
+			// Write certificate to file
+			let cert_dir = config.webserver.certificate.parent()
+				.ok_or_else(|| tracing::error!("Failed to find parent directory for certificate file."))?;
+			std::fs::create_dir_all(cert_dir)
+				.map_err(|e| tracing::error!("Failed to create certificate directory: {e}"))?;
+			let mut certfile = std::fs::File::create(&config.webserver.certificate).unwrap();
+			certfile.write(&cert.to_pem().map_err(|e| tracing::error!("Failed to serialize PEM: {e}"))?)
+				.map_err(|e| tracing::error!("Failed to write PEM to file: {e}"))?;
+
+			// Write private key to file
+			let private_key_dir = config.webserver.private_key.parent()
+				.ok_or_else(|| tracing::error!("Failed to find parent directory for private key file."))?;
+			std::fs::create_dir_all(private_key_dir)
+				.map_err(|e| tracing::error!("Failed to create private key directory: {e}"))?;
+			let mut keyfile = std::fs::File::create(&config.webserver.private_key).unwrap();
+			keyfile.write(&pkey.to_pem().map_err(|e| tracing::error!("Failed to serialize private key: {e}"))?)
+				.map_err(|e| tracing::error!("Failed to write private key to file: {e}"))?;
+
+			tracing::debug!("Saved private key to {}", config.webserver.certificate.display());
+			tracing::debug!("Saved certificate to {}", config.webserver.private_key.display());
+
+			(cert, pkey)
+		} else {
+			let cert = std::fs::read(&config.webserver.certificate)
+				.map_err(|e| tracing::error!("Failed to read server certificate: {e}"))?;
+			let cert = Certificate::from_pem(&cert)
+				.map_err(|e| tracing::error!("Failed to parse server certificate: {e}"))?;
+
+			let pkey = PrivateKey::from_pem(&std::fs::read(&config.webserver.private_key)
+				.map_err(|e| tracing::error!("Failed to read private key: {e}"))?)
+				.map_err(|e| tracing::error!("Failed to parse private key: {e}"))?;
+
+			(cert, pkey)
+		};
+
+		// Users compare this with the fingerprint their client shows, to make sure they pair with this host.
+		match certificate_fingerprint(&cert) {
+			Ok(fingerprint) => tracing::info!("Server certificate fingerprint (SHA-256): {fingerprint}"),
+			Err(e) => tracing::warn!("Failed to compute the server certificate fingerprint: {e}"),
+		}
+
+		// Check which video encoders work on this host, so we don't advertise codecs we can't deliver.
+		let encoder_capabilities = EncoderCapabilities::probe(config.stream.video.clone()).await;
+		if !encoder_capabilities.any() {
+			tracing::warn!("No working video encoder found, clients will not be able to stream.");
+		}
+
+		// Record the messages exchanged with clients, if enabled.
+		let transcript = Transcript::new(config.transcript_directory.clone());
+
+		// Serve session events to external processes, if enabled.
+		let event_socket = match &config.event_socket {
+			Some(path) => Some(EventSocket::new(path.clone(), events.clone(), shutdown.clone())?),
+			None => None,
+		};
+
+		// Create a manager for interacting with sessions.
+		let session_manager = SessionManager::new(config.clone(), transcript.clone(), events.clone(), shutdown.trigger_shutdown_token(2))?;
+
+		// Create a manager for saving and loading client state.
+		let client_manager = ClientManager::new(state.clone(), cert.clone(), pkey, events.clone(), shutdown.trigger_shutdown_token(3));
+
+		// Run the RTSP server.
+		let rtsp_server = RtspServer::new(config.clone(), encoder_capabilities, session_manager.clone(), transcript.clone(), shutdown.clone());
+
+		// Publish the Moonshine service using zeroconf.
+		let supervisor = Supervisor::new(shutdown.clone());
+		let publisher = ServicePublisher::spawn(config.webserver.port, config.name.clone(), &supervisor);
+
+		// Scan for applications, they can be scanned again through the API.
+		let applications = ApplicationCatalog::new(config.applications.clone(), config.application_scanners.clone());
+
+		// Find the address that clients outside the local network reach the host on.
+		let external_address = ExternalAddress::new(config.network.clone(), shutdown.clone());
+
+		// Create a handler for the webserver.
+		let webserver = Webserver::new(
+			config,
+			applications,
+			state.get_uuid().await?,
+			cert,
+			encoder_capabilities,
+			external_address,
+			client_manager.clone(),
+			session_manager.clone(),
+			transcript,
+			logs,
+			web_runtime,
+			shutdown.clone(),
+		)?;
+
+		Ok(Self {
+			shutdown,
+			events,
+			_rtsp_server: rtsp_server,
+			_session_manager: session_manager,
+			_client_manager: client_manager,
+			_webserver: webserver,
+			_publisher: publisher,
+			_event_socket: event_socket,
+		})
+	}
+}
+
+/// SHA-256 fingerprint of the certificate at `path`, as colon separated uppercase hex.
+#[allow(clippy::result_unit_err)]
+pub fn server_fingerprint(path: &Path) -> Result<String, ()> {
+	let certificate = std::fs::read(path)
+		.map_err(|e| tracing::error!("Failed to read server certificate at {}: {e}", path.display()))?;
+	let certificate = Certificate::from_pem(&certificate)
+		.map_err(|e| tracing::error!("Failed to parse server certificate: {e}"))?;
+
+	certificate_fingerprint(&certificate)
+		.map_err(|e| tracing::error!("Failed to compute server certificate fingerprint: {e}"))
+}
+
+/// Configures a [`Moonshine`] host before starting it.
+#[derive(Default)]
+pub struct MoonshineBuilder {
+	config: Option<Config>,
+	logs: Option<Logs>,
+	events: Option<EventBus>,
+	shutdown: Option<ShutdownManager<i32>>,
+	web_runtime: Option<tokio::runtime::Handle>,
+}
+
+impl MoonshineBuilder {
+	/// Configuration of the host, the default configuration is used if not provided.
+	pub fn config(mut self, config: Config) -> Self {
+		self.config = Some(config);
+		self
+	}
+
+	/// Logs that the API exposes, nothing is exposed if not provided.
+	pub fn logs(mut self, logs: Logs) -> Self {
+		self.logs = Some(logs);
+		self
+	}
+
+	/// Bus to publish session events on, so subscriptions can be made before the host starts.
+	pub fn events(mut self, events: EventBus) -> Self {
+		self.events = Some(events);
+		self
+	}
+
+	/// Shutdown manager that stops the host when triggered, a new one is created if not provided.
+	pub fn shutdown(mut self, shutdown: ShutdownManager<i32>) -> Self {
+		self.shutdown = Some(shutdown);
+		self
+	}
+
+	/// Runtime to run the webserver on, so HTTP requests don't compete with the streams, the current runtime is used if not provided.
+	pub fn web_runtime(mut self, web_runtime: tokio::runtime::Handle) -> Self {
+		self.web_runtime = Some(web_runtime);
+		self
+	}
+
+	/// Start all services of the host.
+	pub async fn build(self) -> Result<Moonshine, ()> {
+		Moonshine::new(
+			self.config.unwrap_or_default(),
+			self.logs.unwrap_or_default(),
+			self.events.unwrap_or_default(),
+			self.shutdown.unwrap_or_else(ShutdownManager::new),
+			self.web_runtime.unwrap_or_else(tokio::runtime::Handle::current),
+		).await
+	}
+
+	/// Start all services of the host and run until it shuts down, returns the exit code of the shutdown.
+	pub async fn run(self) -> Result<i32, ()> {
+		Ok(self.build().await?.run().await)
+	}
+}
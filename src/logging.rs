@@ -0,0 +1,266 @@
+//! Log output to the terminal, to rotated log files and to a buffer of recent records that the API exposes.
+
+use std::{collections::VecDeque, fmt::Debug, fs::File, io::Write, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use serde::Serialize;
+use tracing::{field::{Field, Visit}, level_filters::LevelFilter, Event, Subscriber};
+use tracing_subscriber::{fmt::MakeWriter, layer::{Context, SubscriberExt}, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::config::LogConfig;
+
+/// Number of records kept in memory until the configuration is loaded.
+const DEFAULT_BUFFER_SIZE: usize = 10000;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LogRecord {
+	/// Time the record was logged, in milliseconds since the UNIX epoch.
+	pub timestamp: u64,
+
+	/// Level of the record, for example `INFO`.
+	pub level: String,
+
+	/// Module that logged the record.
+	pub target: String,
+
+	/// Message of the record, followed by its other fields.
+	pub message: String,
+}
+
+/// Handle to the log outputs, to configure them once the configuration is loaded and to retrieve recent records.
+#[derive(Clone)]
+pub struct Logs {
+	buffer: Arc<Mutex<LogBuffer>>,
+	file: Arc<Mutex<Option<RotatingFile>>>,
+}
+
+impl Default for Logs {
+	/// Outputs that aren't connected to a subscriber, so they stay empty.
+	fn default() -> Self {
+		Self {
+			buffer: Arc::new(Mutex::new(LogBuffer { records: VecDeque::new(), capacity: DEFAULT_BUFFER_SIZE })),
+			file: Arc::new(Mutex::new(None)),
+		}
+	}
+}
+
+impl Logs {
+	/// Install the global subscriber, records of `log_level` and above are logged.
+	pub fn init(log_level: LevelFilter) -> Self {
+		let logs = Self::default();
+
+		tracing_subscriber::registry()
+			.with(tracing_subscriber::fmt::layer()
+				.with_filter(log_level)
+			)
+			.with(tracing_subscriber::fmt::layer()
+				.with_ansi(false)
+				.with_writer(FileWriter(logs.file.clone()))
+				.with_filter(log_level)
+			)
+			.with(BufferLayer(logs.buffer.clone())
+				.with_filter(log_level)
+			)
+			.with(EnvFilter::builder()
+				.with_default_directive(LevelFilter::INFO.into())
+				.from_env_lossy(),
+			)
+			.init();
+
+		logs
+	}
+
+	/// Start writing to the configured log file and resize the buffer of recent records.
+	#[allow(clippy::result_unit_err)]
+	pub fn configure(&self, config: &LogConfig) -> Result<(), ()> {
+		if let Ok(mut buffer) = self.buffer.lock() {
+			buffer.capacity = config.buffer_size;
+			buffer.truncate();
+		}
+
+		let Some(path) = &config.file else {
+			return Ok(());
+		};
+
+		let file = RotatingFile::open(
+			path.clone(),
+			config.max_size * 1024 * 1024,
+			config.rotation_interval.map(|hours| Duration::from_secs(hours * 3600)),
+			config.max_files,
+		)?;
+		*self.file.lock().map_err(|e| tracing::error!("Failed to lock log file: {e}"))? = Some(file);
+
+		tracing::info!("Writing logs to {path:?}.");
+		Ok(())
+	}
+
+	/// Records that are kept in memory, optionally only those logged within `period`, the oldest record first.
+	#[allow(clippy::result_unit_err)]
+	pub fn recent(&self, period: Option<Duration>) -> Result<Vec<LogRecord>, ()> {
+		let since = period.map(|period| unix_millis(SystemTime::now()).saturating_sub(period.as_millis() as u64));
+		let buffer = self.buffer.lock().map_err(|e| tracing::error!("Failed to lock log buffer: {e}"))?;
+		Ok(buffer.records.iter()
+			.filter(|record| !since.is_some_and(|since| record.timestamp < since))
+			.cloned()
+			.collect())
+	}
+}
+
+/// Bounded buffer of the most recent records.
+struct LogBuffer {
+	records: VecDeque<LogRecord>,
+	capacity: usize,
+}
+
+impl LogBuffer {
+	fn truncate(&mut self) {
+		while self.records.len() > self.capacity {
+			self.records.pop_front();
+		}
+	}
+}
+
+/// Layer that stores every record in the buffer.
+struct BufferLayer(Arc<Mutex<LogBuffer>>);
+
+impl<S: Subscriber> Layer<S> for BufferLayer {
+	fn on_event(&self, event: &Event<'_>, _context: Context<'_, S>) {
+		let mut visitor = MessageVisitor::default();
+		event.record(&mut visitor);
+
+		let record = LogRecord {
+			timestamp: unix_millis(SystemTime::now()),
+			level: event.metadata().level().to_string(),
+			target: event.metadata().target().to_string(),
+			message: visitor.message,
+		};
+
+		// Logging from here would deadlock, so a poisoned buffer silently drops records.
+		if let Ok(mut buffer) = self.0.lock() {
+			buffer.records.push_back(record);
+			buffer.truncate();
+		}
+	}
+}
+
+/// Formats the fields of an event like the terminal output does.
+#[derive(Default)]
+struct MessageVisitor {
+	message: String,
+}
+
+impl Visit for MessageVisitor {
+	fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+		if !self.message.is_empty() {
+			self.message.push(' ');
+		}
+
+		if field.name() == "message" {
+			self.message.push_str(&format!("{value:?}"));
+		} else {
+			self.message.push_str(&format!("{}={value:?}", field.name()));
+		}
+	}
+}
+
+/// Writer for the file layer, which discards output while no log file is configured.
+#[derive(Clone)]
+struct FileWriter(Arc<Mutex<Option<RotatingFile>>>);
+
+impl<'a> MakeWriter<'a> for FileWriter {
+	type Writer = Self;
+
+	fn make_writer(&'a self) -> Self::Writer {
+		self.clone()
+	}
+}
+
+impl Write for FileWriter {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let mut file = self.0.lock().map_err(|e| std::io::Error::other(e.to_string()))?;
+		match file.as_mut() {
+			Some(file) => file.write(buf),
+			None => Ok(buf.len()),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		let mut file = self.0.lock().map_err(|e| std::io::Error::other(e.to_string()))?;
+		match file.as_mut() {
+			Some(file) => file.file.flush(),
+			None => Ok(()),
+		}
+	}
+}
+
+/// Log file that is moved to `<path>.1` when it grows too large or too old, older files move up one number.
+struct RotatingFile {
+	path: PathBuf,
+	file: File,
+
+	/// Size of the current file in bytes.
+	size: u64,
+
+	/// When the current file was started.
+	opened: SystemTime,
+
+	max_size: u64,
+	max_age: Option<Duration>,
+	max_files: usize,
+}
+
+impl RotatingFile {
+	fn open(path: PathBuf, max_size: u64, max_age: Option<Duration>, max_files: usize) -> Result<Self, ()> {
+		if let Some(parent_dir) = path.parent() {
+			std::fs::create_dir_all(parent_dir)
+				.map_err(|e| tracing::error!("Failed to create log dir {parent_dir:?}: {e}"))?;
+		}
+
+		let file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)
+			.map_err(|e| tracing::error!("Failed to open log file {path:?}: {e}"))?;
+		let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+
+		Ok(Self { path, file, size, opened: SystemTime::now(), max_size, max_age, max_files })
+	}
+
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let too_large = self.size > 0 && self.size + buf.len() as u64 > self.max_size;
+		let too_old = self.max_age.is_some_and(|max_age| self.opened.elapsed().unwrap_or_default() >= max_age);
+		if too_large || too_old {
+			self.rotate()?;
+		}
+
+		self.file.write_all(buf)?;
+		self.size += buf.len() as u64;
+		Ok(buf.len())
+	}
+
+	fn rotate(&mut self) -> std::io::Result<()> {
+		let numbered = |number: usize| {
+			let mut path = self.path.clone().into_os_string();
+			path.push(format!(".{number}"));
+			PathBuf::from(path)
+		};
+
+		if self.max_files == 0 {
+			std::fs::remove_file(&self.path)?;
+		} else {
+			// Renaming over the oldest file deletes it.
+			for number in (1..self.max_files).rev() {
+				let _ = std::fs::rename(numbered(number), numbered(number + 1));
+			}
+			std::fs::rename(&self.path, numbered(1))?;
+		}
+
+		self.file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+		self.size = 0;
+		self.opened = SystemTime::now();
+		Ok(())
+	}
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
@@ -1,37 +1,16 @@
-use std::io::Write;
 use std::path::PathBuf;
 
 use async_shutdown::ShutdownManager;
 use clap::Parser;
+use moonshine_core::{benchmark_conversion, benchmark_encoders, config::{Config, RuntimeConfig}, doctor, server_fingerprint, sunshine, BenchmarkOptions, Logs, Moonshine};
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer};
-use crate::clients::ClientManager;
-use crate::config::Config;
-use crate::crypto::create_certificate;
-use crate::rtsp::RtspServer;
-use crate::session::SessionManager;
-use crate::state::State;
-use crate::webserver::Webserver;
-use openssl::pkey::PKey;
-
-mod app_scanner;
-mod clients;
-mod config;
-mod crypto;
-mod ffmpeg;
-mod rtsp;
-mod session;
-mod state;
-mod publisher;
-mod webserver;
 
 #[derive(Parser, Debug)]
-#[clap(version)]
+#[clap(version, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
 	/// Path to configuration file.
-	config: PathBuf,
+	#[clap(required = true)]
+	config: Option<PathBuf>,
 
 	/// Show more log messages.
 	#[clap(long, short)]
@@ -42,10 +21,71 @@ struct Args {
 	#[clap(long, short)]
 	#[clap(action = clap::ArgAction::Count)]
 	quiet: u8,
+
+	#[clap(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+	/// Convert a Sunshine configuration (sunshine.conf and apps.json) to a Moonshine configuration.
+	ImportSunshine {
+		/// Path to the Sunshine config directory or sunshine.conf file.
+		path: PathBuf,
+
+		/// Path to write the Moonshine configuration to, it is printed if not provided.
+		#[clap(long, short)]
+		output: Option<PathBuf>,
+	},
+
+	/// Encode synthetic frames with the configured encoders and report their framerate, latency and bitrate accuracy,
+	/// followed by the speed of converting frames for encoders that can't read them from the GPU.
+	BenchEncoder {
+		/// Path to the configuration file with the encoders to benchmark, the default encoders are used if not provided.
+		#[clap(long, short)]
+		config: Option<PathBuf>,
+
+		/// Resolution to benchmark, as WIDTHxHEIGHT (can be repeated).
+		#[clap(long = "resolution", short, value_parser = parse_resolution)]
+		resolutions: Vec<(u32, u32)>,
+
+		/// Number of frames to encode per encoder and resolution.
+		#[clap(long, default_value_t = 600)]
+		frames: u32,
+
+		/// Framerate to configure the encoders with.
+		#[clap(long, default_value_t = 60)]
+		framerate: u32,
+
+		/// Target bitrate in kbps.
+		#[clap(long, default_value_t = 20_000)]
+		bitrate: usize,
+	},
+
+	/// Check the environment for common problems, like missing permissions, drivers or ports in use, and explain how to fix them.
+	Doctor {
+		/// Path to the configuration file to check against, the default configuration is used if not provided.
+		#[clap(long, short)]
+		config: Option<PathBuf>,
+	},
+
+	/// Print the SHA-256 fingerprint of the server certificate, to compare with the one shown while pairing.
+	Fingerprint {
+		/// Path to the configuration file with the certificate path, the default configuration is used if not provided.
+		#[clap(long, short)]
+		config: Option<PathBuf>,
+	},
+}
+
+fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+	let (width, height) = value.split_once('x')
+		.ok_or_else(|| format!("expected WIDTHxHEIGHT, got '{value}'"))?;
+	let width = width.parse().map_err(|e| format!("invalid width '{width}': {e}"))?;
+	let height = height.parse().map_err(|e| format!("invalid height '{height}': {e}"))?;
+	Ok((width, height))
 }
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<(), ()> {
+fn main() -> Result<(), ()> {
 	let args = Args::parse();
 
 	let log_level = match i16::from(args.verbose) - i16::from(args.quiet) {
@@ -56,31 +96,60 @@ async fn main() -> Result<(), ()> {
 		2.. => LevelFilter::TRACE,
 	};
 
-	tracing_subscriber::registry()
-		.with(tracing_subscriber::fmt::layer()
-			.with_filter(log_level)
-		)
-		.with(EnvFilter::builder()
-			.with_default_directive(LevelFilter::INFO.into())
-			.from_env_lossy(),
-		)
-		.init();
+	let logs = Logs::init(log_level);
+
+	match args.command {
+		Some(Command::ImportSunshine { path, output }) => return sunshine::import(&path, output.as_deref()),
+		Some(Command::BenchEncoder { config, resolutions, frames, framerate, bitrate }) => {
+			let runtime = build_runtime(&RuntimeConfig::default(), "moonshine-worker")?;
+			return runtime.block_on(bench_encoder(config, resolutions, frames, framerate, bitrate));
+		},
+		Some(Command::Doctor { config }) => {
+			let config = match config {
+				Some(config_path) => Config::read_from_file(config_path)?,
+				None => Config::default(),
+			};
+			let runtime = build_runtime(&config.runtime, "moonshine-worker")?;
+			return runtime.block_on(doctor::run(&config));
+		},
+		Some(Command::Fingerprint { config }) => {
+			let config = match config {
+				Some(config_path) => Config::read_from_file(config_path)?,
+				None => Config::default(),
+			};
+			let cert_path = config.webserver.certificate.to_string_lossy().to_string();
+			let cert_path = shellexpand::full(&cert_path)
+				.map_err(|e| tracing::error!("Failed to expand certificate path: {e}"))?;
+			let cert_path = PathBuf::from(cert_path.to_string());
+			if !cert_path.exists() {
+				tracing::error!("No certificate at {}, one is created when the host starts.", cert_path.display());
+				return Err(());
+			}
+
+			println!("{}", server_fingerprint(&cert_path)?);
+			return Ok(());
+		},
+		None => {},
+	}
+
+	let config_path = args.config
+		.ok_or_else(|| tracing::error!("No configuration file provided."))?;
 
 	let mut config;
-	if args.config.exists() {
-		config = Config::read_from_file(args.config).map_err(|_| std::process::exit(1))?;
+	if config_path.exists() {
+		config = Config::read_from_file(&config_path).map_err(|_| std::process::exit(1))?;
 	} else {
-		tracing::info!("No config file found at {}, creating a default config file.", args.config.display());
+		tracing::info!("No config file found at {}, creating a default config file.", config_path.display());
 		config = Config::default();
 
 		let serialized_config = toml::to_string_pretty(&config)
 			.map_err(|e| tracing::error!("Failed to serialize config: {e}"))?;
 
-		let config_dir = args.config.parent()
+		let config_dir = config_path.parent()
 			.ok_or_else(|| tracing::error!("Failed to get parent directory of config file."))?;
 		std::fs::create_dir_all(config_dir)
 			.map_err(|e| tracing::error!("Failed to create config directory: {e}"))?;
-		std::fs::write(args.config, serialized_config)
+		std::fs::write(&config_path, serialized_config)
 			.map_err(|e| tracing::error!("Failed to save config file: {e}"))?;
 	}
 
@@ -95,124 +164,144 @@ async fn main() -> Result<(), ()> {
 		.map_err(|e| tracing::error!("Failed to expand private key path: {e}"))?;
 	config.webserver.private_key = private_key_path.to_string().into();
 
+	if let Some(transcript_directory) = &config.transcript_directory {
+		let transcript_directory = transcript_directory.to_string_lossy().to_string();
+		let transcript_directory = shellexpand::full(&transcript_directory)
+			.map_err(|e| tracing::error!("Failed to expand transcript directory: {e}"))?;
+		config.transcript_directory = Some(transcript_directory.to_string().into());
+	}
+
+	if let Some(event_socket) = &config.event_socket {
+		let event_socket = event_socket.to_string_lossy().to_string();
+		let event_socket = shellexpand::full(&event_socket)
+			.map_err(|e| tracing::error!("Failed to expand event socket path: {e}"))?;
+		config.event_socket = Some(event_socket.to_string().into());
+	}
+
+	if let Some(log_file) = &config.log.file {
+		let log_file = log_file.to_string_lossy().to_string();
+		let log_file = shellexpand::full(&log_file)
+			.map_err(|e| tracing::error!("Failed to expand log file path: {e}"))?;
+		config.log.file = Some(log_file.to_string().into());
+	}
+
+	// Logs keep going to the terminal if the log file can't be opened.
+	let _ = logs.configure(&config.log);
+
 	tracing::debug!("Using configuration:\n{:#?}", config);
 
-	let scanned_applications = app_scanner::scan_applications(&config.application_scanners);
-	tracing::debug!("Adding scanned applications:\n{:#?}", scanned_applications);
-	config.applications.extend(scanned_applications);
-
-	// Spawn a task to wait for CTRL+C and trigger a shutdown.
-	let shutdown = ShutdownManager::new();
-	tokio::spawn({
-		let shutdown = shutdown.clone();
-		async move {
-			if let Err(e) = tokio::signal::ctrl_c().await {
-				tracing::error!("Failed to wait for CTRL+C: {e}");
-				std::process::exit(1);
+	let runtime = build_runtime(&config.runtime, "moonshine-worker")?;
+	let web_runtime = match config.runtime.web_worker_threads {
+		Some(worker_threads) => {
+			let web_config = RuntimeConfig { worker_threads: Some(worker_threads), ..config.runtime.clone() };
+			Some(build_runtime(&web_config, "moonshine-web")?)
+		},
+		None => None,
+	};
+	let web_runtime_handle = web_runtime.as_ref().map(|web_runtime| web_runtime.handle().clone());
+
+	let exit_code = runtime.block_on(async move {
+		// Spawn a task to wait for CTRL+C and trigger a shutdown.
+		let shutdown = ShutdownManager::new();
+		tokio::spawn({
+			let shutdown = shutdown.clone();
+			async move {
+				if let Err(e) = tokio::signal::ctrl_c().await {
+					tracing::error!("Failed to wait for CTRL+C: {e}");
+					std::process::exit(1);
+				}
+
+				tracing::info!("Received interrupt signal. Shutting down server...");
+				shutdown.trigger_shutdown(1).ok();
 			}
+		});
+
+		// Run the host until something causes a shutdown trigger.
+		let mut builder = Moonshine::builder()
+			.config(config)
+			.logs(logs)
+			.shutdown(shutdown);
+		if let Some(web_runtime_handle) = web_runtime_handle {
+			builder = builder.web_runtime(web_runtime_handle);
+		}
+		builder.run().await
+	})?;
+	std::process::exit(exit_code);
+}
 
-			tracing::info!("Received interrupt signal. Shutting down server...");
-			shutdown.trigger_shutdown(1).ok();
+/// Build a multi-threaded runtime as configured, threads are named after `name` so they are recognizable in profilers.
+fn build_runtime(config: &RuntimeConfig, name: &str) -> Result<tokio::runtime::Runtime, ()> {
+	let mut builder = tokio::runtime::Builder::new_multi_thread();
+	builder.enable_all().thread_name(name);
+	if let Some(worker_threads) = config.worker_threads {
+		if worker_threads == 0 {
+			tracing::error!("The runtime needs at least one worker thread.");
+			return Err(());
 		}
-	});
+		builder.worker_threads(worker_threads);
+	}
+	if let Some(max_blocking_threads) = config.max_blocking_threads {
+		if max_blocking_threads == 0 {
+			tracing::error!("The runtime needs at least one blocking thread.");
+			return Err(());
+		}
+		builder.max_blocking_threads(max_blocking_threads);
+	}
 
-	// Create the main application.
-	let moonshine = Moonshine::new(config, shutdown.clone()).await?;
+	builder.build()
+		.map_err(|e| tracing::error!("Failed to create runtime: {e}"))
+}
 
-	// Wait until something causes a shutdown trigger.
-	shutdown.wait_shutdown_triggered().await;
+async fn bench_encoder(config_path: Option<PathBuf>, resolutions: Vec<(u32, u32)>, frames: u32, framerate: u32, bitrate: usize) -> Result<(), ()> {
+	let config = match config_path {
+		Some(config_path) => Config::read_from_file(config_path)?,
+		None => Config::default(),
+	};
 
-	// Drop the main moonshine object, triggering other systems to shutdown too.
-	drop(moonshine);
+	let mut options = BenchmarkOptions { frames, framerate, bitrate: bitrate * 1000, ..Default::default() };
+	if !resolutions.is_empty() {
+		options.resolutions = resolutions;
+	}
 
-	// Wait until everything was shutdown.
-	let exit_code = shutdown.wait_shutdown_complete().await;
-	tracing::trace!("Successfully waited for shutdown to complete.");
-	std::process::exit(exit_code);
-}
+	let (results, conversions) = tokio::task::spawn_blocking(move || {
+		let results = benchmark_encoders(&config.stream.video, &options)?;
+		if results.is_empty() {
+			tracing::error!("None of the configured encoders could be benchmarked.");
+			return Err(());
+		}
 
-pub struct Moonshine {
-	_rtsp_server: RtspServer,
-	_session_manager: SessionManager,
-	_client_manager: ClientManager,
-	_webserver: Webserver,
-}
+		Ok((results, benchmark_conversion(&options)?))
+	})
+		.await
+		.map_err(|e| tracing::error!("Failed to run encoder benchmark: {e}"))??;
+
+	println!("{:<16} {:>10} {:>9} {:>9} {:>9} {:>9} {:>12} {:>9}", "encoder", "resolution", "fps", "p50", "p95", "p99", "kbps", "accuracy");
+	for result in results {
+		println!(
+			"{:<16} {:>10} {:>9.1} {:>7.2}ms {:>7.2}ms {:>7.2}ms {:>12} {:>8.0}%",
+			result.codec,
+			format!("{}x{}", result.width, result.height),
+			result.fps,
+			result.latency_p50.as_secs_f64() * 1000.0,
+			result.latency_p95.as_secs_f64() * 1000.0,
+			result.latency_p99.as_secs_f64() * 1000.0,
+			result.bitrate / 1000,
+			result.bitrate_accuracy() * 100.0,
+		);
+	}
 
-impl Moonshine {
-	pub async fn new(
-		config: Config,
-		shutdown: ShutdownManager<i32>,
-	) -> Result<Self, ()> {
-		let state = State::new().await?;
-
-		let (cert, pkey) = if !config.webserver.certificate.exists() && !config.webserver.private_key.exists() {
-			tracing::info!("No certificate found, creating a new one.");
-
-			let (cert, pkey) = create_certificate()
-				.map_err(|e| tracing::error!("Failed to create certificate: {e}"))?;
-
-			// Write certificate to file
-			let cert_dir = config.webserver.certificate.parent()
-				.ok_or_else(|| tracing::error!("Failed to find parent directory for certificate file."))?;
-			std::fs::create_dir_all(cert_dir)
-				.map_err(|e| tracing::error!("Failed to create certificate directory: {e}"))?;
-			let mut certfile = std::fs::File::create(&config.webserver.certificate).unwrap();
-			certfile.write(&cert.to_pem().map_err(|e| tracing::error!("Failed to serialize PEM: {e}"))?)
-				.map_err(|e| tracing::error!("Failed to write PEM to file: {e}"))?;
-
-			// Write private key to file
-			let private_key_dir = config.webserver.private_key.parent()
-				.ok_or_else(|| tracing::error!("Failed to find parent directory for private key file."))?;
-			std::fs::create_dir_all(private_key_dir)
-				.map_err(|e| tracing::error!("Failed to create private key directory: {e}"))?;
-			let mut keyfile = std::fs::File::create(&config.webserver.private_key).unwrap();
-			keyfile.write(&pkey.private_key_to_pem_pkcs8().map_err(|e| tracing::error!("Failed to serialize private key: {e}"))?)
-				.map_err(|e| tracing::error!("Failed to write private key to file: {e}"))?;
-
-			tracing::debug!("Saved private key to {}", config.webserver.certificate.display());
-			tracing::debug!("Saved certificate to {}", config.webserver.private_key.display());
-
-			(cert, pkey)
-		} else {
-			let cert = std::fs::read(&config.webserver.certificate)
-				.map_err(|e| tracing::error!("Failed to read server certificate: {e}"))?;
-			let cert = openssl::x509::X509::from_pem(&cert)
-				.map_err(|e| tracing::error!("Failed to parse server certificate: {e}"))?;
-
-			let pkey = PKey::private_key_from_pem(&std::fs::read(&config.webserver.private_key)
-				.map_err(|e| tracing::error!("Failed to read private key: {e}"))?)
-				.map_err(|e| tracing::error!("Failed to parse private key: {e}"))?;
-
-			(cert, pkey)
-		};
-
-		// Create a manager for interacting with sessions.
-		let session_manager = SessionManager::new(config.clone(), shutdown.trigger_shutdown_token(2))?;
-
-		// Create a manager for saving and loading client state.
-		let client_manager = ClientManager::new(state.clone(), cert.clone(), pkey, shutdown.trigger_shutdown_token(3));
-
-		// Run the RTSP server.
-		let rtsp_server = RtspServer::new(config.clone(), session_manager.clone(), shutdown.clone());
-
-		// Publish the Moonshine service using zeroconf.
-		publisher::spawn(config.webserver.port, config.name.clone());
-
-		// Create a handler for the webserver.
-		let webserver = Webserver::new(
-			config,
-			state.get_uuid().await?,
-			cert,
-			client_manager.clone(),
-			session_manager.clone(),
-			shutdown,
-		)?;
-
-		Ok(Self {
-			_rtsp_server: rtsp_server,
-			_session_manager: session_manager,
-			_client_manager: client_manager,
-			_webserver: webserver,
-		})
+	println!();
+	println!("{:<16} {:>10} {:>9} {:>9} {:>9}", "conversion", "resolution", "fps", "p50", "p95");
+	for result in conversions {
+		println!(
+			"{:<16} {:>10} {:>9.1} {:>7.2}ms {:>7.2}ms",
+			format!("{} {}", result.format, result.implementation),
+			format!("{}x{}", result.width, result.height),
+			result.fps,
+			result.latency_p50.as_secs_f64() * 1000.0,
+			result.latency_p95.as_secs_f64() * 1000.0,
+		);
 	}
+
+	Ok(())
 }
@@ -1,9 +1,35 @@
+#[cfg(feature = "mdns")]
 use zeroconf::prelude::*;
 
-pub fn spawn(port: u16, name: String) {
-	tokio::task::spawn_blocking(move || { run(port, name) });
+use crate::supervisor::Supervisor;
+
+/// Publishes the host on the local network with mDNS, so clients find it without entering its address.
+pub struct ServicePublisher;
+
+impl ServicePublisher {
+	/// Publishing is restarted when it fails, for example when the mDNS daemon wasn't running yet.
+	#[cfg(feature = "mdns")]
+	pub fn spawn(port: u16, name: String, supervisor: &Supervisor<i32>) -> Self {
+		supervisor.spawn_restartable("service publisher", move || {
+			let name = name.clone();
+			async move {
+				tokio::task::spawn_blocking(move || run(port, name))
+					.await
+					.map_err(|e| tracing::error!("Service publisher stopped unexpectedly: {e}"))?
+			}
+		});
+		Self
+	}
+
+	/// Without mDNS there is nothing to publish the host with.
+	#[cfg(not(feature = "mdns"))]
+	pub fn spawn(_port: u16, _name: String, _supervisor: &Supervisor<i32>) -> Self {
+		tracing::warn!("Publishing the host requires building with the 'mdns' feature, clients have to add it by its address.");
+		Self
+	}
 }
 
+#[cfg(feature = "mdns")]
 fn run(port: u16, name: String) -> Result<(), ()> {
 	let mut service = zeroconf::MdnsService::new(
 		zeroconf::ServiceType::new("nvstream", "tcp")
@@ -27,6 +53,7 @@ fn run(port: u16, name: String) -> Result<(), ()> {
 	}
 }
 
+#[cfg(feature = "mdns")]
 fn on_service_registered(
 	result: zeroconf::Result<zeroconf::ServiceRegistration>,
 	_context: Option<std::sync::Arc<dyn std::any::Any>>,
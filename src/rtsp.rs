@@ -1,23 +1,48 @@
-use std::{net::{ToSocketAddrs, SocketAddr}, str::FromStr};
+use std::{net::{ToSocketAddrs, SocketAddr}, sync::{atomic::{AtomicU32, Ordering}, Arc}};
 use async_shutdown::ShutdownManager;
 use rtsp_types::{headers::{self, Transport}, Method};
 use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}};
 
-use crate::{config::Config, session::{stream::{AudioStreamContext, VideoStreamContext}, manager::SessionManager}};
+use crate::{config::Config, transcript::{self, Transcript}, session::{stream::{AudioStreamContext, EncoderCapabilities, VideoStreamContext, VideoStreamSettings}, manager::SessionManager, ClientCapabilities}};
+
+mod encryption;
+mod sdp;
+
+/// Durations of audio packets in milliseconds that the audio encoder supports.
+const SUPPORTED_AUDIO_PACKET_DURATIONS: [u32; 5] = [5, 10, 20, 40, 60];
+
+/// Header of the SETUP response with the payload that clients send in their audio and video PINGs.
+const PING_PAYLOAD_HEADER: &str = "X-SS-Ping-Payload";
+
+/// Maximum number of slices per frame, more slices than this only add overhead.
+const MAX_SLICES_PER_FRAME: u32 = 16;
 
 #[derive(Clone)]
 pub struct RtspServer {
 	config: Config,
+	encoder_capabilities: EncoderCapabilities,
 	session_manager: SessionManager,
+	transcript: Transcript,
+
+	/// Sequence number of the next encrypted response, it never repeats so initialization vectors are never reused.
+	sequence_number: Arc<AtomicU32>,
 }
 
 impl RtspServer {
 	pub fn new(
 		config: Config,
+		encoder_capabilities: EncoderCapabilities,
 		session_manager: SessionManager,
+		transcript: Transcript,
 		shutdown: ShutdownManager<i32>,
 	) -> Self {
-		let server = Self { config: config.clone(), session_manager };
+		let server = Self {
+			config: config.clone(),
+			encoder_capabilities,
+			session_manager,
+			transcript,
+			sequence_number: Arc::new(AtomicU32::new(0)),
+		};
 
 		tokio::spawn({
 			let server = server.clone();
@@ -41,6 +66,11 @@ impl RtspServer {
 								.map_err(|e| tracing::error!("Failed to accept connection: {}", e))?;
 							tracing::trace!("Accepted connection from {}", address);
 
+							if !server.config.network.is_allowed(address.ip()) {
+								tracing::warn!("Rejecting connection from {address}, address is not in an allowed subnet.");
+								continue;
+							}
+
 							tokio::spawn({
 								let server = server.clone();
 								async move {
@@ -63,29 +93,25 @@ impl RtspServer {
 	}
 
 	#[allow(clippy::result_unit_err)]
-	pub fn description(&self) -> String {
-		// This is a very simple SDP description, the minimal that Moonlight requires.
-		// TODO: Fill this based on server settings.
-		// TODO: Use:
-		//       "a=x-ss-general.featureFlags: <FEATURE FLAGS>"
-		//       "x-nv-video[0].refPicInvalidation=1"
-		//       "a=rtpmap:98 AV1/90000" (For AV1 support)
-		//       "a=fmtp:97 surround-params=<SURROUND PARAMS>"
-		//       "<AUDIO STREAM MAPPING>"
-		"sprop-parameter-sets=AAAAAU\na=fmtp:96 packetization-mode=1".to_string()
+	/// Describe the stream, including the parameter sets of the video stream if the encoder already produced them.
+	pub fn description(&self, parameter_sets: Option<&str>) -> String {
+		// Clients that support encrypted video enable it when the host asks for it.
+		sdp::description(parameter_sets, self.config.stream.video.encrypt)
 	}
 
 	fn handle_options_request(&self, request: &rtsp_types::Request<Vec<u8>>, cseq: i32) -> rtsp_types::Response<Vec<u8>> {
 		rtsp_types::Response::builder(request.version(), rtsp_types::StatusCode::Ok)
 			.header(headers::CSEQ, cseq.to_string())
-			.header(headers::PUBLIC, "OPTIONS DESCRIBE SETUP PLAY")
+			.header(headers::PUBLIC, "OPTIONS DESCRIBE SETUP PLAY SET_PARAMETER")
 			.build(Vec::new())
 	}
 
-	fn handle_setup_request(
+	/// Guests don't receive the ping payload, their PINGs shouldn't claim the audio and video streams.
+	async fn handle_setup_request(
 		&self,
 		request: &rtsp_types::Request<Vec<u8>>,
 		cseq: i32,
+		is_guest: bool,
 	) -> rtsp_types::Response<Vec<u8>> {
 		let transports = match request.typed_header::<rtsp_types::headers::Transports>() {
 			Ok(transports) => transports,
@@ -124,29 +150,29 @@ impl RtspServer {
 						return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 					}
 
-					// Example query: streamid=control/13/0
-					let (stream_id, port) = match query.1.split('/').next() {
-						Some("video") => ("video", self.config.stream.video.port),
-						Some("audio") => ("audio", self.config.stream.audio.port),
-						Some("control") => ("control", self.config.stream.control.port),
-						Some(stream) => {
-							tracing::warn!("Unknown stream '{stream}'");
-							return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
-						}
-						None => {
-							tracing::warn!("Unexpected query format for query '{}'", query.1);
-							return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
-						},
+					let Some(stream) = sdp::Stream::from_stream_id(&query.1) else {
+						tracing::warn!("Unknown stream in query '{}'", query.1);
+						return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 					};
+					let port = stream.port(&self.config.stream);
 
-					tracing::info!("Responding with server_port={port} for stream '{stream_id}'.");
+					tracing::info!("Responding with server_port={port} for stream '{}'.", stream.name());
 
-					return rtsp_types::Response::builder(request.version(), rtsp_types::StatusCode::Ok)
+					let mut response = rtsp_types::Response::builder(request.version(), rtsp_types::StatusCode::Ok)
 						.header(headers::CSEQ, cseq.to_string())
-						.header(headers::SESSION, "MoonshineSession;timeout = 90".to_string())
-						.header(headers::TRANSPORT, format!("server_port={port}"))
-						.build(Vec::new())
-					;
+						.header(headers::SESSION, sdp::SESSION.to_string())
+						.header(headers::TRANSPORT, sdp::transport(port));
+
+					// Clients send this payload in their PINGs, which lets the audio and video streams ignore PINGs from other sources.
+					if stream != sdp::Stream::Control && !is_guest {
+						let context = self.session_manager.get_session_context().await.ok().flatten();
+						let header = rtsp_types::HeaderName::from_static_str(PING_PAYLOAD_HEADER);
+						if let (Some(context), Ok(header)) = (context, header) {
+							response = response.header(header, context.ping_payload);
+						}
+					}
+
+					return response.build(Vec::new());
 				}
 				t => {
 					tracing::warn!("Received request for unsupported transport: {:?}", t);
@@ -164,7 +190,11 @@ impl RtspServer {
 		request: &rtsp_types::Request<Vec<u8>>,
 		cseq: i32,
 	) -> rtsp_types::Response<Vec<u8>> {
-		let description = self.description();
+		let parameter_sets = self.session_manager.get_session_stats().await
+			.ok()
+			.flatten()
+			.and_then(|stats| stats.video_parameter_sets());
+		let description = self.description(parameter_sets.as_deref());
 		tracing::debug!("SDP session data: \n{}", description.trim());
 		rtsp_types::Response::builder(request.version(), rtsp_types::StatusCode::Ok)
 			.header(headers::CSEQ, cseq.to_string())
@@ -186,63 +216,85 @@ impl RtspServer {
 
 		tracing::trace!("Received SDP session from ANNOUNCE request: {sdp_session:#?}");
 
-		let width = match get_sdp_attribute(&sdp_session, "x-nv-video[0].clientViewportWd") {
+		let width = match sdp::attribute(&sdp_session, sdp::CLIENT_VIEWPORT_WIDTH) {
 			Ok(width) => width,
 			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-video[0].clientViewportWd in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::CLIENT_VIEWPORT_WIDTH);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		let height = match get_sdp_attribute(&sdp_session, "x-nv-video[0].clientViewportHt") {
+		let height = match sdp::attribute(&sdp_session, sdp::CLIENT_VIEWPORT_HEIGHT) {
 			Ok(height) => height,
 			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-video[0].clientViewportHt in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::CLIENT_VIEWPORT_HEIGHT);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		let fps = match get_sdp_attribute(&sdp_session, "x-nv-video[0].maxFPS") {
+		let fps = match sdp::attribute(&sdp_session, sdp::MAX_FPS) {
 			Ok(fps) => fps,
 			Err(()) => {
-				tracing::warn!("Failed to parse xx-nv-video[0].maxFPS in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::MAX_FPS);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		let packet_size = match get_sdp_attribute(&sdp_session, "x-nv-video[0].packetSize") {
+		let packet_size = match sdp::attribute(&sdp_session, sdp::PACKET_SIZE) {
 			Ok(packet_size) => packet_size,
 			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-video[0].packetSize in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::PACKET_SIZE);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		let mut bitrate = match get_sdp_attribute(&sdp_session, "x-nv-vqos[0].bw.maximumBitrateKbps") {
+		let mut bitrate = match sdp::attribute(&sdp_session, sdp::MAXIMUM_BITRATE_KBPS) {
 			Ok(bitrate) => bitrate,
 			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-vqos[0].bw.maximumBitrateKbps in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::MAXIMUM_BITRATE_KBPS);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
 		bitrate *= 1024; // Convert from kbps to bps.
-		let minimum_fec_packets = match get_sdp_attribute(&sdp_session, "x-nv-vqos[0].fec.minRequiredFecPackets") {
+		let minimum_fec_packets = match sdp::attribute(&sdp_session, sdp::MINIMUM_FEC_PACKETS) {
 			Ok(minimum_fec_packets) => minimum_fec_packets,
 			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-vqos[0].fec.minRequiredFecPackets in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::MINIMUM_FEC_PACKETS);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		let video_qos_type: String = match get_sdp_attribute(&sdp_session, "x-nv-vqos[0].qosTrafficType") {
+		let video_qos_type: String = match sdp::attribute(&sdp_session, sdp::VIDEO_QOS_TRAFFIC_TYPE) {
 			Ok(video_qos_type) => video_qos_type,
 			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-vqos[0].qosTrafficType in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::VIDEO_QOS_TRAFFIC_TYPE);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		let video_format: u32 = match get_sdp_attribute(&sdp_session, "x-nv-vqos[0].bitStreamFormat") {
+		let video_format: u32 = match sdp::attribute(&sdp_session, sdp::BIT_STREAM_FORMAT) {
 			Ok(video_format) => video_format,
 			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-vqos[0].bitStreamFormat in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::BIT_STREAM_FORMAT);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
+		if !self.encoder_capabilities.supports(video_format) {
+			tracing::warn!("Client requested video format {video_format}, but no working encoder is available for it.");
+			return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+		}
+
+		// Clients that don't ask for a number of slices expect a single slice per frame.
+		let slices_per_frame = self.config.stream.video.slices_per_frame
+			.or_else(|| sdp::attribute(&sdp_session, sdp::SLICES_PER_FRAME).ok())
+			.unwrap_or(1)
+			.clamp(1, MAX_SLICES_PER_FRAME);
+
+		// The configured color space and range take precedence, the decoder reads them from the bitstream.
+		let (requested_color_space, requested_color_range) = sdp::color_from_csc_mode(
+			sdp::attribute(&sdp_session, sdp::ENCODER_CSC_MODE).unwrap_or(0)
+		);
+		let color_space = self.config.stream.video.color_space.unwrap_or(requested_color_space);
+		let color_range = self.config.stream.video.color_range.unwrap_or(requested_color_range);
+		if (color_space, color_range) != (requested_color_space, requested_color_range) {
+			tracing::info!(
+				"Client asked for {requested_color_space:?} with {requested_color_range:?} range, encoding {color_space:?} with {color_range:?} range instead.",
+			);
+		}
 
 		let video_stream_context = VideoStreamContext {
 			width,
@@ -253,29 +305,57 @@ impl RtspServer {
 			minimum_fec_packets,
 			qos: video_qos_type != "0",
 			video_format,
+			slices_per_frame,
+			color_space,
+			color_range,
+			// Filled in from the session when the stream starts.
+			ping_payload: String::new(),
+			encryption_keys: None,
+			h264_decoder: Default::default(),
 		};
 
-		let packet_duration = match get_sdp_attribute(&sdp_session, "x-nv-aqos.packetDuration") {
-			Ok(packet_duration) => packet_duration,
-			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-video[0].clientViewportHt in SDP session.");
-				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
-			},
+		let packet_duration = sdp::attribute(&sdp_session, sdp::AUDIO_PACKET_DURATION)
+			.unwrap_or(self.config.stream.audio.packet_duration);
+		if !SUPPORTED_AUDIO_PACKET_DURATIONS.contains(&packet_duration) {
+			tracing::warn!("Unsupported audio packet duration of {packet_duration}ms, expected one of {SUPPORTED_AUDIO_PACKET_DURATIONS:?}.");
+			return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+		}
+		let high_quality_audio = matches!(
+			sdp_session.get_first_attribute_value(sdp::AUDIO_QUALITY),
+			Ok(Some(quality)) if quality.trim() == "1"
+		);
+		let audio_bitrate = if high_quality_audio {
+			self.config.stream.audio.high_quality_bitrate
+		} else {
+			self.config.stream.audio.bitrate
 		};
-		let audio_qos_type: String = match get_sdp_attribute(&sdp_session, "x-nv-aqos.qosTrafficType") {
+		let audio_qos_type: String = match sdp::attribute(&sdp_session, sdp::AUDIO_QOS_TRAFFIC_TYPE) {
 			Ok(audio_qos_type) => audio_qos_type,
 			Err(()) => {
-				tracing::warn!("Failed to parse x-nv-aqos.qosTrafficType in SDP session.");
+				tracing::warn!("Failed to parse {} in SDP session.", sdp::AUDIO_QOS_TRAFFIC_TYPE);
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
 
 		let audio_stream_context = AudioStreamContext {
 			packet_duration,
+			bitrate: audio_bitrate,
 			qos: audio_qos_type != "0",
+			// Filled in from the session when the stream starts.
+			ping_payload: String::new(),
+			host_only: false,
 		};
 
-		if self.session_manager.set_stream_context(video_stream_context, audio_stream_context).await.is_err() {
+		let capabilities = ClientCapabilities {
+			client_version: request.headers()
+				.find(|(name, _)| name.as_str().eq_ignore_ascii_case("X-GS-ClientVersion"))
+				.and_then(|(_, value)| value.as_str().trim().parse().ok()),
+			feature_flags: sdp::attribute(&sdp_session, sdp::FEATURE_FLAGS).ok(),
+			encryption_flags: sdp::attribute(&sdp_session, sdp::ENCRYPTION_ENABLED).ok(),
+			..Default::default()
+		};
+
+		if self.session_manager.set_stream_context(video_stream_context, audio_stream_context, capabilities).await.is_err() {
 			return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::InternalServerError)
 		}
 
@@ -298,12 +378,81 @@ impl RtspServer {
 			.build(Vec::new())
 	}
 
+	/// Change the bitrate or framerate of the running stream.
+	///
+	/// The body contains one `<parameter>: <value>` pair per line, using the same names as the SDP attributes in ANNOUNCE.
+	async fn handle_set_parameter_request(
+		&self,
+		request: &rtsp_types::Request<Vec<u8>>,
+		cseq: i32,
+	) -> rtsp_types::Response<Vec<u8>> {
+		let body = match std::str::from_utf8(request.body()) {
+			Ok(body) => body,
+			Err(e) => {
+				tracing::warn!("Failed to parse SET_PARAMETER request body: {e}");
+				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+			},
+		};
+
+		let parameters = match sdp::parameters(body) {
+			Ok(parameters) => parameters,
+			Err(line) => {
+				tracing::warn!("Invalid parameter in SET_PARAMETER request: '{line}'");
+				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+			},
+		};
+
+		let mut settings = VideoStreamSettings::default();
+		for (name, value) in parameters {
+			match name {
+				sdp::MAXIMUM_BITRATE_KBPS => match value.parse::<usize>() {
+					Ok(bitrate) => settings.bitrate = Some(bitrate * 1024), // Convert from kbps to bps.
+					Err(e) => {
+						tracing::warn!("Failed to parse bitrate '{value}': {e}");
+						return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+					},
+				},
+				sdp::MAX_FPS => match value.parse::<u32>() {
+					Ok(fps) if fps > 0 => settings.fps = Some(fps),
+					_ => {
+						tracing::warn!("Failed to parse framerate '{value}'.");
+						return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+					},
+				},
+				name => {
+					tracing::warn!("Unsupported parameter in SET_PARAMETER request: '{name}'");
+					return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+				},
+			}
+		}
+
+		if self.session_manager.update_video_settings(settings).await.is_err() {
+			return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::InternalServerError)
+		}
+
+		rtsp_response(cseq, request.version(), rtsp_types::StatusCode::Ok)
+	}
+
 	async fn handle_connection(
 		&self,
 		mut connection: TcpStream,
 		address: SocketAddr,
 	) -> Result<(), ()> {
-		let mut message_buffer = String::new();
+		let context = self.session_manager.get_session_context().await?;
+
+		// Guests that joined the session are recognized by their address, they don't get to change the stream.
+		let guest = match &context {
+			Some(_) if self.config.coop.max_guests > 0 => self.session_manager.get_guests().await?
+				.and_then(|guests| guests.find(address.ip())),
+			_ => None,
+		};
+
+		// Clients that launched with encrypted RTSP have to encrypt every message, with the key of the session.
+		// Guests use the key they resumed the session with.
+		let encryption_key = context
+			.filter(|context| context.capabilities.encrypted_rtsp)
+			.map(|context| guest.as_ref().map_or(&context.keys, |guest| &guest.keys).key().to_vec());
+		let mut received = Vec::new();
 
 		let message = loop {
 			let mut buffer = [0u8; 2048];
@@ -313,8 +462,28 @@ impl RtspServer {
 				tracing::warn!("Received empty RTSP request.");
 				return Ok(());
 			}
-			message_buffer.push_str(std::str::from_utf8(&buffer[..bytes_read])
-				.map_err(|e| tracing::error!("Failed to convert message to string: {e}"))?);
+			received.extend_from_slice(&buffer[..bytes_read]);
+			if received.len() > encryption::MAX_MESSAGE_LENGTH {
+				tracing::warn!("Closing connection from {address}, its request exceeds {} bytes.", encryption::MAX_MESSAGE_LENGTH);
+				return Err(());
+			}
+
+			let plaintext = match &encryption_key {
+				Some(key) => {
+					if !encryption::is_encrypted(&received) {
+						tracing::warn!("Rejecting unencrypted RTSP request from {address}, the session uses encrypted RTSP.");
+						return Err(());
+					}
+					let Some(length) = encryption::message_length(&received)?.filter(|length| received.len() >= *length) else {
+						tracing::debug!("Incomplete encrypted RTSP message received, waiting for more data.");
+						continue;
+					};
+					encryption::decrypt(key, &received[..length])?
+				},
+				None => received.clone(),
+			};
+			let message_buffer = std::str::from_utf8(&plaintext)
+				.map_err(|e| tracing::error!("Failed to convert message to string: {e}"))?;
 
 			// Hacky workaround to fix rtsp_types parsing SETUP/PLAY requests from Moonlight.
 			let message_buffer = message_buffer.replace("streamid", "rtsp://localhost?streamid");
@@ -348,12 +517,23 @@ impl RtspServer {
 					.parse()
 					.map_err(|e| tracing::error!("Failed to parse CSeq header: {}", e))?;
 
+				self.transcript.record("rtsp", format_args!(
+					"{address} > {:?} CSeq={cseq}\n{}",
+					request.method(),
+					transcript::truncate_payload(request.body()),
+				));
+
 				match request.method() {
+					Method::Announce | Method::Play | Method::SetParameter if guest.is_some() => {
+						tracing::debug!("Ignoring RTSP {:?} request from guest {address}.", request.method());
+						rtsp_response(cseq, request.version(), rtsp_types::StatusCode::Ok)
+					},
 					Method::Announce => self.handle_announce_request(request, cseq).await,
 					Method::Describe => self.handle_describe_request(request, cseq).await,
 					Method::Options => self.handle_options_request(request, cseq),
-					Method::Setup => self.handle_setup_request(request, cseq),
+					Method::Setup => self.handle_setup_request(request, cseq, guest.is_some()).await,
 					Method::Play => self.handle_play_request(request, cseq).await,
+					Method::SetParameter => self.handle_set_parameter_request(request, cseq).await,
 					method => {
 						tracing::warn!("Received request with unsupported method {:?}", method);
 						rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest)
@@ -368,10 +548,18 @@ impl RtspServer {
 
 		tracing::debug!("Sending RTSP response");
 		tracing::trace!("{:#?}", response);
+		self.transcript.record("rtsp", format_args!(
+			"{address} < {:?}\n{}",
+			response.status(),
+			transcript::truncate_payload(response.body()),
+		));
 
 		let mut buffer = Vec::new();
 		response.write(&mut buffer)
 			.map_err(|e| tracing::error!("Failed to serialize RTSP response: {}", e))?;
+		if let Some(key) = &encryption_key {
+			buffer = encryption::encrypt(key, self.sequence_number.fetch_add(1, Ordering::Relaxed), &buffer)?;
+		}
 
 		connection.write_all(&buffer).await
 			.map_err(|e| tracing::error!("Failed to send RTSP response: {}", e))?;
@@ -390,12 +578,3 @@ fn rtsp_response(cseq: i32, version: rtsp_types::Version, status: rtsp_types::St
 		.header(headers::CSEQ, cseq.to_string())
 		.build(Vec::new())
 }
-
-fn get_sdp_attribute<F: FromStr>(sdp_session: &sdp_types::Session, attribute: &str) -> Result<F, ()> {
-	sdp_session.get_first_attribute_value(attribute)
-		.map_err(|e| tracing::warn!("Failed to attribute {attribute} from request: {e}"))?
-		.ok_or_else(|| tracing::warn!("No {attribute} attribute in request"))?
-		.trim()
-		.parse()
-		.map_err(|_| tracing::warn!("Attribute {attribute} can't be parsed."))
-}
@@ -0,0 +1,136 @@
+//! Encrypted RTSP messages, which clients that launch with `corever=1` send instead of plain text.
+//!
+//! Every message starts with a header of its length with the high bit set, a sequence number and the AES-GCM tag,
+//! followed by the ciphertext. The initialization vector is the sequence number followed by the side that sent the
+//! message, so the client and the host never use the same vector.
+
+use crate::crypto;
+
+/// Length of the header: the type and length, the sequence number and the tag.
+pub const HEADER_LENGTH: usize = 24;
+
+/// Bit in the first word of the header that marks the message as encrypted, the other bits are the ciphertext length.
+const ENCRYPTED_BIT: u32 = 0x8000_0000;
+
+const INITIALIZATION_VECTOR_LENGTH: usize = 12;
+
+/// Largest message a client may send, including its header. RTSP requests of Moonlight are a few kilobytes at most.
+pub const MAX_MESSAGE_LENGTH: usize = 64 * 1024;
+
+/// Whether the data starts with the header of an encrypted message, plain text RTSP never has the high bit set.
+pub fn is_encrypted(data: &[u8]) -> bool {
+	data.first().is_some_and(|byte| byte & 0x80 != 0)
+}
+
+/// Length of the encrypted message the data starts with, including its header, or `None` if the header is incomplete.
+///
+/// Fails if the message is longer than [`MAX_MESSAGE_LENGTH`].
+pub fn message_length(data: &[u8]) -> Result<Option<usize>, ()> {
+	let Some(type_and_length) = data.get(..4) else {
+		return Ok(None);
+	};
+	let type_and_length = u32::from_be_bytes([type_and_length[0], type_and_length[1], type_and_length[2], type_and_length[3]]);
+	let length = HEADER_LENGTH + (type_and_length & !ENCRYPTED_BIT) as usize;
+	if length > MAX_MESSAGE_LENGTH {
+		tracing::warn!("Encrypted RTSP message of {length} bytes exceeds the maximum of {MAX_MESSAGE_LENGTH} bytes.");
+		return Err(());
+	}
+
+	Ok(Some(length))
+}
+
+/// Decrypt a complete message from the client.
+pub fn decrypt(key: &[u8], message: &[u8]) -> Result<Vec<u8>, ()> {
+	if message.len() < HEADER_LENGTH {
+		tracing::warn!("Encrypted RTSP message of {} bytes is shorter than its header.", message.len());
+		return Err(());
+	}
+
+	let sequence_number = u32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+	let tag = &message[8..HEADER_LENGTH];
+	crypto::decrypt_gcm(key, &initialization_vector(sequence_number, b'C'), &message[HEADER_LENGTH..], tag)
+		.map_err(|e| tracing::warn!("Failed to decrypt RTSP message: {e}"))
+}
+
+/// Encrypt a message to the client, `sequence_number` may never be used twice with the same key.
+pub fn encrypt(key: &[u8], sequence_number: u32, plaintext: &[u8]) -> Result<Vec<u8>, ()> {
+	let (ciphertext, tag) = crypto::encrypt_gcm(key, &initialization_vector(sequence_number, b'H'), plaintext)
+		.map_err(|e| tracing::error!("Failed to encrypt RTSP message: {e}"))?;
+
+	let mut message = Vec::with_capacity(HEADER_LENGTH + ciphertext.len());
+	message.extend((ciphertext.len() as u32 | ENCRYPTED_BIT).to_be_bytes());
+	message.extend(sequence_number.to_be_bytes());
+	message.extend(tag);
+	message.extend(ciphertext);
+	Ok(message)
+}
+
+/// The sequence number, followed by who sent the message ('C' for client or 'H' for host) and 'R' for RTSP.
+fn initialization_vector(sequence_number: u32, origin: u8) -> [u8; INITIALIZATION_VECTOR_LENGTH] {
+	let mut initialization_vector = [0u8; INITIALIZATION_VECTOR_LENGTH];
+	initialization_vector[..4].copy_from_slice(&sequence_number.to_le_bytes());
+	initialization_vector[10] = origin;
+	initialization_vector[11] = b'R';
+	initialization_vector
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const KEY: [u8; 16] = *b"0123456789abcdef";
+
+	const REQUEST: &[u8] = b"OPTIONS rtsp://10.0.0.1:48010 RTSP/1.0\r\nCSeq: 1\r\n\r\n";
+
+	/// Encrypt a message like a client does.
+	fn client_message(sequence_number: u32, plaintext: &[u8]) -> Vec<u8> {
+		let (ciphertext, tag) = crypto::encrypt_gcm(&KEY, &initialization_vector(sequence_number, b'C'), plaintext).unwrap();
+		[&(ciphertext.len() as u32 | ENCRYPTED_BIT).to_be_bytes()[..], &sequence_number.to_be_bytes()[..], &tag[..], &ciphertext[..]].concat()
+	}
+
+	#[test]
+	fn client_messages() {
+		let message = client_message(7, REQUEST);
+		assert!(is_encrypted(&message));
+		assert!(!is_encrypted(REQUEST));
+		assert_eq!(message_length(&message[..3]), Ok(None));
+		assert_eq!(message_length(&message), Ok(Some(message.len())));
+		assert_eq!(decrypt(&KEY, &message).unwrap(), REQUEST);
+	}
+
+	#[test]
+	fn host_messages() {
+		let message = encrypt(&KEY, 3, REQUEST).unwrap();
+		assert!(is_encrypted(&message));
+		assert_eq!(message_length(&message), Ok(Some(message.len())));
+		assert_eq!(&message[4..8], &3u32.to_be_bytes());
+
+		let plaintext = crypto::decrypt_gcm(&KEY, &initialization_vector(3, b'H'), &message[HEADER_LENGTH..], &message[8..HEADER_LENGTH]).unwrap();
+		assert_eq!(plaintext, REQUEST);
+
+		// The host never uses the initialization vector of the client, so its messages don't decrypt as client messages.
+		assert!(decrypt(&KEY, &message).is_err());
+	}
+
+	#[test]
+	fn rejects_invalid_messages() {
+		let message = client_message(1, REQUEST);
+
+		assert!(decrypt(&KEY, &message[..HEADER_LENGTH - 1]).is_err());
+		assert!(decrypt(&KEY, &message[..message.len() - 1]).is_err());
+		assert!(decrypt(b"fedcba9876543210", &message).is_err());
+
+		let mut wrong_tag = message.clone();
+		wrong_tag[8] ^= 1;
+		assert!(decrypt(&KEY, &wrong_tag).is_err());
+
+		let mut wrong_sequence_number = message.clone();
+		wrong_sequence_number[7] ^= 1;
+		assert!(decrypt(&KEY, &wrong_sequence_number).is_err());
+
+		let oversized = ((MAX_MESSAGE_LENGTH - HEADER_LENGTH + 1) as u32 | ENCRYPTED_BIT).to_be_bytes();
+		assert_eq!(message_length(&oversized), Err(()));
+		let largest = ((MAX_MESSAGE_LENGTH - HEADER_LENGTH) as u32 | ENCRYPTED_BIT).to_be_bytes();
+		assert_eq!(message_length(&largest), Ok(Some(MAX_MESSAGE_LENGTH)));
+	}
+}
@@ -0,0 +1,161 @@
+//! The SDP that Moonlight and the host exchange over RTSP, which relies on attributes of NVIDIA's GameStream protocol.
+//!
+//! The host describes the stream in the DESCRIBE response, the client announces how it wants to receive it in the
+//! ANNOUNCE request and changes some of that with SET_PARAMETER. The names of all attributes involved are kept here,
+//! so the handlers don't need to know how they are spelled.
+
+use std::str::FromStr;
+
+use crate::{config::{ColorRange, ColorSpace, StreamConfig}, session::{ENCRYPTION_CONTROL, ENCRYPTION_VIDEO}};
+
+/// Width of the viewport of the client, in pixels.
+pub const CLIENT_VIEWPORT_WIDTH: &str = "x-nv-video[0].clientViewportWd";
+
+/// Height of the viewport of the client, in pixels.
+pub const CLIENT_VIEWPORT_HEIGHT: &str = "x-nv-video[0].clientViewportHt";
+
+/// Framerate of the stream, also used in SET_PARAMETER.
+pub const MAX_FPS: &str = "x-nv-video[0].maxFPS";
+
+/// Maximum size of video packets, including their headers.
+pub const PACKET_SIZE: &str = "x-nv-video[0].packetSize";
+
+/// Bitrate of the video stream in kbps, also used in SET_PARAMETER.
+pub const MAXIMUM_BITRATE_KBPS: &str = "x-nv-vqos[0].bw.maximumBitrateKbps";
+
+/// Minimum number of FEC packets per block of video packets.
+pub const MINIMUM_FEC_PACKETS: &str = "x-nv-vqos[0].fec.minRequiredFecPackets";
+
+/// Whether video packets are marked for QoS, `0` if not.
+pub const VIDEO_QOS_TRAFFIC_TYPE: &str = "x-nv-vqos[0].qosTrafficType";
+
+/// Codec of the video stream, see `VIDEO_FORMAT_H264` and `VIDEO_FORMAT_HEVC`.
+pub const BIT_STREAM_FORMAT: &str = "x-nv-vqos[0].bitStreamFormat";
+
+/// Number of slices the client wants per frame.
+pub const SLICES_PER_FRAME: &str = "x-nv-video[0].videoEncoderSlicesPerFrame";
+
+/// Color space and range, see [`color_from_csc_mode`].
+pub const ENCODER_CSC_MODE: &str = "x-nv-video[0].encoderCscMode";
+
+/// Duration of audio packets in milliseconds.
+pub const AUDIO_PACKET_DURATION: &str = "x-nv-aqos.packetDuration";
+
+/// Whether the client wants high quality audio, `1` if so.
+pub const AUDIO_QUALITY: &str = "x-nv-audio.surround.AudioQuality";
+
+/// Whether audio packets are marked for QoS, `0` if not.
+pub const AUDIO_QOS_TRAFFIC_TYPE: &str = "x-nv-aqos.qosTrafficType";
+
+/// Features the client supports.
+pub const FEATURE_FLAGS: &str = "x-nv-general.featureFlags";
+
+/// Streams the client wants encrypted, as `ENCRYPTION_*` bits.
+pub const ENCRYPTION_ENABLED: &str = "x-ss-general.encryptionEnabled";
+
+/// Moonlight looks for this exact string to decide whether the server supports HEVC.
+const HEVC_SUPPORTED: &str = "sprop-parameter-sets=AAAAAU";
+
+/// Value of the `Session` header of SETUP responses.
+pub const SESSION: &str = "MoonshineSession;timeout = 90";
+
+/// Streams that clients set up, from the `streamid` in the SETUP request, for example `streamid=control/13/0`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+	Video,
+	Audio,
+	Control,
+}
+
+impl Stream {
+	/// Parse the stream from the value of the `streamid` query parameter, the parts after the first `/` are ignored.
+	pub fn from_stream_id(stream_id: &str) -> Option<Self> {
+		match stream_id.split('/').next()? {
+			"video" => Some(Self::Video),
+			"audio" => Some(Self::Audio),
+			"control" => Some(Self::Control),
+			_ => None,
+		}
+	}
+
+	/// Port the stream is served on, which the client learns from the `Transport` header of the SETUP response.
+	pub fn port(&self, config: &StreamConfig) -> u16 {
+		match self {
+			Self::Video => config.video.port,
+			Self::Audio => config.audio.port,
+			Self::Control => config.control.port,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Video => "video",
+			Self::Audio => "audio",
+			Self::Control => "control",
+		}
+	}
+}
+
+/// Value of the `Transport` header of the SETUP response, Moonlight only reads the port from it.
+pub fn transport(port: u16) -> String {
+	format!("server_port={port}")
+}
+
+/// Describe the stream, including the parameter sets of the video stream if the encoder already produced them.
+///
+/// With `encrypt_video`, clients that support encrypted video are asked to enable it.
+pub fn description(parameter_sets: Option<&str>, encrypt_video: bool) -> String {
+	// This is a very simple SDP description, the minimal that Moonlight requires.
+	// TODO: Use:
+	//       "a=x-ss-general.featureFlags: <FEATURE FLAGS>"
+	//       "x-nv-video[0].refPicInvalidation=1"
+	//       "a=rtpmap:98 AV1/90000" (For AV1 support)
+	//       "a=fmtp:97 surround-params=<SURROUND PARAMS>"
+	//       "<AUDIO STREAM MAPPING>"
+	let mut lines = vec![HEVC_SUPPORTED.to_string()];
+
+	// Clients that set up their decoder before the first IDR frame arrives can use these parameter sets.
+	match parameter_sets {
+		Some(parameter_sets) => lines.push(format!("a=fmtp:96 packetization-mode=1;{parameter_sets}")),
+		None => lines.push("a=fmtp:96 packetization-mode=1".to_string()),
+	}
+
+	if encrypt_video {
+		lines.push(format!("a=x-ss-general.encryptionSupported:{}", ENCRYPTION_CONTROL | ENCRYPTION_VIDEO));
+		lines.push(format!("a=x-ss-general.encryptionRequested:{ENCRYPTION_VIDEO}"));
+	}
+
+	lines.join("\n")
+}
+
+/// Parse an attribute from the SDP of an ANNOUNCE request.
+pub fn attribute<F: FromStr>(sdp_session: &sdp_types::Session, attribute: &str) -> Result<F, ()> {
+	sdp_session.get_first_attribute_value(attribute)
+		.map_err(|e| tracing::warn!("Failed to attribute {attribute} from request: {e}"))?
+		.ok_or_else(|| tracing::warn!("No {attribute} attribute in request"))?
+		.trim()
+		.parse()
+		.map_err(|_| tracing::warn!("Attribute {attribute} can't be parsed."))
+}
+
+/// Parse the body of a SET_PARAMETER request, which has one `<attribute>: <value>` pair per line.
+///
+/// Returns the first line that isn't a pair if the body is invalid.
+pub fn parameters(body: &str) -> Result<Vec<(&str, &str)>, &str> {
+	body.lines()
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| line.split_once(':').map(|(name, value)| (name.trim(), value.trim())).ok_or(line))
+		.collect()
+}
+
+/// Color space and range from `encoderCscMode`, which holds the color space in bits 1-2 and whether to use full range in bit 0.
+pub fn color_from_csc_mode(csc_mode: u32) -> (ColorSpace, ColorRange) {
+	let color_space = match csc_mode >> 1 {
+		1 => ColorSpace::Bt709,
+		2 => ColorSpace::Bt2020,
+		_ => ColorSpace::Bt601,
+	};
+	let color_range = if csc_mode & 1 == 1 { ColorRange::Full } else { ColorRange::Limited };
+
+	(color_space, color_range)
+}
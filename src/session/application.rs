@@ -0,0 +1,116 @@
+//! The process of the application that is launched for a session.
+
+use std::{os::unix::process::CommandExt, process::{ExitStatus, Stdio}};
+
+use tokio::sync::mpsc;
+
+use crate::config::LaunchWrapper;
+
+/// Sent when the application of a session exits.
+#[derive(Debug)]
+pub struct ApplicationExit {
+	/// Process id of the application that exited.
+	pub pid: u32,
+
+	/// Exit status of the application.
+	pub status: ExitStatus,
+}
+
+/// An application that runs in its own process group, so that it can be terminated with all its child processes.
+pub struct ApplicationProcess {
+	pid: u32,
+}
+
+impl ApplicationProcess {
+	/// Start the application with `environment` added to the environment of the host, its exit status is sent to
+	/// `exit_tx` when it exits.
+	pub fn spawn(command: Vec<String>, environment: Vec<(String, String)>, exit_tx: mpsc::Sender<ApplicationExit>) -> Result<Self, ()> {
+		if command.is_empty() {
+			tracing::warn!("Can't run an empty command.");
+			return Err(());
+		}
+
+		tracing::info!("Running application: {command:?}");
+
+		let mut process = std::process::Command::new(&command[0]);
+		process
+			.args(&command[1..])
+			.envs(environment)
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.stdin(Stdio::null())
+			.process_group(0);
+
+		// Applications in containers can only reach the audio server if they know where its socket is.
+		if std::env::var_os("PULSE_SERVER").is_none() {
+			if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+				process.env("PULSE_SERVER", format!("unix:{}/pulse/native", runtime_dir.to_string_lossy()));
+			}
+		}
+
+		let mut child = process.spawn()
+			.map_err(|e| tracing::error!("Failed to run application: {e}"))?;
+		let pid = child.id();
+
+		std::thread::spawn(move || {
+			match child.wait() {
+				Ok(status) => {
+					let _ = exit_tx.blocking_send(ApplicationExit { pid, status });
+				},
+				Err(e) => tracing::error!("Failed to wait for application to exit: {e}"),
+			}
+		});
+
+		Ok(Self { pid })
+	}
+
+	pub fn pid(&self) -> u32 {
+		self.pid
+	}
+
+	/// Terminate the application and all processes it started.
+	pub fn terminate(&self) {
+		tracing::debug!("Terminating process group {}.", self.pid);
+
+		// The process group id is the pid of the application, since it leads its own group.
+		let result = std::process::Command::new("kill")
+			.args(["-TERM", "--", &format!("-{}", self.pid)])
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.status();
+		match result {
+			// The group no longer exists if all processes already exited.
+			Ok(_) => {},
+			Err(e) => tracing::warn!("Failed to terminate application: {e}"),
+		}
+	}
+}
+
+/// Prefix the command of an application so that it runs through a wrapper.
+pub fn wrap_command(wrapper: &LaunchWrapper, command: &[String]) -> Vec<String> {
+	let mut wrapped: Vec<String> = match wrapper {
+		// The sockets are usually granted by the Flatpak itself, but not every Flatpak asks for them.
+		LaunchWrapper::Flatpak => [
+			"flatpak", "run",
+			"--socket=x11",
+			"--socket=pulseaudio",
+			"--device=dri",
+		].iter().map(|argument| argument.to_string()).collect(),
+
+		// Share the display, audio server and GPU with the container.
+		LaunchWrapper::Podman => [
+			"podman", "run", "--rm",
+			"-e", "DISPLAY",
+			"-e", "XDG_RUNTIME_DIR",
+			"-e", "PULSE_SERVER",
+			"-v", "/tmp/.X11-unix:/tmp/.X11-unix",
+			"-v", "$XDG_RUNTIME_DIR/pulse:$XDG_RUNTIME_DIR/pulse",
+			"--device", "/dev/dri",
+		].iter().map(|argument| argument.to_string()).collect(),
+
+		LaunchWrapper::Custom(wrapper) => wrapper.clone(),
+	};
+	wrapped.extend_from_slice(command);
+
+	wrapped
+}
@@ -0,0 +1,85 @@
+//! What a client supports, as it tells the host when launching a session and negotiating the stream.
+
+/// Bit in `x-ss-general.encryptionEnabled` for encrypted control messages.
+pub const ENCRYPTION_CONTROL: u32 = 0x01;
+
+/// Bit in `x-ss-general.encryptionEnabled` for encrypted video packets.
+pub const ENCRYPTION_VIDEO: u32 = 0x02;
+
+#[derive(Clone, Debug)]
+pub struct ClientCapabilities {
+	/// Number of audio channels the client can play, from `surroundAudioInfo` in the launch request.
+	pub audio_channels: u16,
+
+	/// Whether the client asked for an HDR stream, from `hdrMode` in the launch request.
+	pub hdr: bool,
+
+	/// Whether the client asked to keep playing audio on the host, from `localAudioPlayMode` in the launch request.
+	pub local_audio: bool,
+
+	/// Whether the client encrypts its RTSP messages, from `corever` in the launch request.
+	pub encrypted_rtsp: bool,
+
+	/// Version of the RTSP protocol the client speaks, from the `X-GS-ClientVersion` header.
+	pub client_version: Option<u32>,
+
+	/// Features the client supports, from `x-nv-general.featureFlags` in the ANNOUNCE request.
+	pub feature_flags: Option<u32>,
+
+	/// Streams the client wants encrypted, from `x-ss-general.encryptionEnabled` in the ANNOUNCE request.
+	pub encryption_flags: Option<u32>,
+}
+
+impl Default for ClientCapabilities {
+	fn default() -> Self {
+		Self {
+			audio_channels: 2,
+			hdr: false,
+			local_audio: false,
+			encrypted_rtsp: false,
+			client_version: None,
+			feature_flags: None,
+			encryption_flags: None,
+		}
+	}
+}
+
+impl ClientCapabilities {
+	/// Capabilities from the parameters of a launch request, parameters that are not provided keep their default.
+	pub fn from_launch_parameters(
+		surround_audio_info: Option<u32>,
+		hdr_mode: Option<u32>,
+		local_audio_play_mode: Option<u32>,
+		core_version: Option<u32>,
+	) -> Self {
+		Self {
+			// The lower 16 bits are the number of channels, the upper 16 bits the channel mask.
+			audio_channels: surround_audio_info.map(|info| (info & 0xFFFF) as u16).filter(|channels| *channels > 0).unwrap_or(2),
+			hdr: hdr_mode.is_some_and(|mode| mode != 0),
+			local_audio: local_audio_play_mode.is_some_and(|mode| mode != 0),
+			// Version 1 of the core protocol added encrypted RTSP.
+			encrypted_rtsp: core_version.is_some_and(|version| version >= 1),
+			..Default::default()
+		}
+	}
+
+	/// Take over the capabilities that the client announced while negotiating the stream.
+	pub fn update_from_announce(&mut self, announced: &ClientCapabilities) {
+		self.client_version = announced.client_version;
+		self.feature_flags = announced.feature_flags;
+		self.encryption_flags = announced.encryption_flags;
+	}
+
+	/// Whether control messages to the client are encrypted.
+	///
+	/// Clients that don't announce which streams they encrypt are current Moonlight versions, which always encrypt control messages.
+	pub fn encrypts_control(&self) -> bool {
+		self.encryption_flags.map_or(true, |flags| flags & ENCRYPTION_CONTROL != 0)
+	}
+
+	/// Whether video packets to the client are encrypted, which clients only ask for when the host offers it.
+	pub fn encrypts_video(&self) -> bool {
+		self.encryption_flags.is_some_and(|flags| flags & ENCRYPTION_VIDEO != 0)
+	}
+
+}
@@ -0,0 +1,49 @@
+use std::time::{Duration, Instant};
+
+/// Rate of the RTP timestamps of the audio and video streams.
+const RTP_CLOCK_RATE: u64 = 90_000;
+
+/// Source of the current time, so that deadlines can be driven by something other than the wall clock.
+pub trait Clock: Clone + Send + Sync + 'static {
+	fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+}
+
+/// Clock that is shared by all streams of a session, so that their timestamps share the same origin.
+///
+/// Streams can be restarted during a session (for example when the video pipeline restarts), their timestamps continue where they left off.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionClock {
+	start: Instant,
+}
+
+impl SessionClock {
+	pub fn new() -> Self {
+		Self { start: Instant::now() }
+	}
+
+	/// Time since the clock started.
+	pub fn elapsed(&self) -> Duration {
+		self.start.elapsed()
+	}
+
+	/// RTP timestamp of the current moment.
+	pub fn rtp_timestamp(&self) -> u32 {
+		(self.elapsed().as_micros() as u64 * RTP_CLOCK_RATE / 1_000_000) as u32
+	}
+}
+
+impl Default for SessionClock {
+	fn default() -> Self {
+		Self::new()
+	}
+}
@@ -0,0 +1,79 @@
+//! Player numbers of the gamepads of clients, which stay the same for as long as the session runs.
+//!
+//! Clients number their gamepads from 0, so without slots two clients would both have a gamepad 0. A gamepad that
+//! disconnects keeps its slot, when it comes back it is the same player again.
+
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+/// Number of gamepads a session supports, Moonlight supports up to 16 gamepads per client.
+const MAX_SLOTS: usize = 16;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GamepadSlot {
+	/// Player number, starting at 1.
+	pub player: u8,
+
+	/// Fingerprint of the certificate of the client that the gamepad belongs to.
+	///
+	/// All Moonlight clients send the same unique id, so that can't tell the gamepads of two clients apart.
+	pub client_certificate: String,
+
+	/// Index of the gamepad on the client.
+	pub index: u8,
+
+	/// Whether the gamepad is currently connected, disconnected gamepads keep their slot.
+	pub connected: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct GamepadSlots {
+	slots: Arc<Mutex<Vec<GamepadSlot>>>,
+}
+
+impl GamepadSlots {
+	/// Assign a player number to gamepad `index` of a client, a gamepad that was seen before gets its previous number back.
+	pub fn connect(&self, client_certificate: &str, index: u8) -> Result<u8, ()> {
+		let mut slots = self.slots.lock()
+			.map_err(|e| tracing::error!("Failed to lock gamepad slots: {e}"))?;
+
+		if let Some(slot) = slots.iter_mut().find(|slot| slot.client_certificate == client_certificate && slot.index == index) {
+			slot.connected = true;
+			return Ok(slot.player);
+		}
+
+		if slots.len() >= MAX_SLOTS {
+			tracing::warn!("Can't assign a player to gamepad {index} of client {client_certificate}, all {MAX_SLOTS} slots are taken.");
+			return Err(());
+		}
+
+		// Slots are never removed, so the next player number is the number of slots plus one.
+		let player = slots.len() as u8 + 1;
+		tracing::info!("Gamepad {index} of client {client_certificate} is player {player}.");
+		slots.push(GamepadSlot { player, client_certificate: client_certificate.to_string(), index, connected: true });
+		Ok(player)
+	}
+
+	/// Mark all gamepads of a client as disconnected, they keep their slots for when the client comes back.
+	pub fn disconnect_client(&self, client_certificate: &str) {
+		let Ok(mut slots) = self.slots.lock() else {
+			tracing::error!("Failed to lock gamepad slots.");
+			return;
+		};
+
+		for slot in slots.iter_mut().filter(|slot| slot.client_certificate == client_certificate) {
+			slot.connected = false;
+		}
+	}
+
+	pub fn list(&self) -> Vec<GamepadSlot> {
+		match self.slots.lock() {
+			Ok(slots) => slots.clone(),
+			Err(e) => {
+				tracing::error!("Failed to lock gamepad slots: {e}");
+				Vec::new()
+			},
+		}
+	}
+}
@@ -0,0 +1,74 @@
+//! Clients that join the session of another client to play along with their gamepads.
+//!
+//! Guests only receive the key frames of the video stream, their gamepads are added to the session as extra players. A
+//! guest is recognized by the IP address it resumed the session from, since its RTSP and control connections carry no
+//! client id.
+
+use std::{net::IpAddr, sync::{Arc, Mutex}};
+
+use super::SessionKeys;
+
+#[derive(Clone, Debug)]
+pub struct Guest {
+	/// Fingerprint of the certificate of the client.
+	pub client_id: String,
+
+	/// Name of the client, if it has one.
+	pub client_name: Option<String>,
+
+	/// Address the client joined from.
+	pub address: IpAddr,
+
+	/// Keys the client encrypts its RTSP and control messages with.
+	pub keys: SessionKeys,
+}
+
+/// Reasons a client could not join as a guest.
+#[derive(Debug)]
+pub enum JoinError {
+	/// The session already has the maximum number of guests.
+	Full(usize),
+
+	/// The guests could not be accessed, the reason is logged.
+	Failed,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Guests {
+	guests: Arc<Mutex<Vec<Guest>>>,
+}
+
+impl Guests {
+	/// Add a guest to the session, a client that joined before is updated with its new address and keys.
+	pub fn join(&self, guest: Guest, max_guests: usize) -> Result<(), JoinError> {
+		let mut guests = self.guests.lock()
+			.map_err(|e| tracing::error!("Failed to lock guests: {e}"))
+			.map_err(|()| JoinError::Failed)?;
+
+		if let Some(existing) = guests.iter_mut().find(|existing| existing.client_id == guest.client_id) {
+			*existing = guest;
+			return Ok(());
+		}
+
+		// Two guests can't share an address, the most recent one takes it over.
+		guests.retain(|existing| existing.address != guest.address);
+		if guests.len() >= max_guests {
+			return Err(JoinError::Full(max_guests));
+		}
+
+		tracing::info!("Client {} joined the session as a guest from {}.", guest.client_id, guest.address);
+		guests.push(guest);
+		Ok(())
+	}
+
+	/// The guest that joined from `address`, if any.
+	pub fn find(&self, address: IpAddr) -> Option<Guest> {
+		match self.guests.lock() {
+			Ok(guests) => guests.iter().find(|guest| guest.address == address).cloned(),
+			Err(e) => {
+				tracing::error!("Failed to lock guests: {e}");
+				None
+			},
+		}
+	}
+}
@@ -0,0 +1,381 @@
+//! Persistent history of the sessions that ran on this host.
+
+use std::{collections::BTreeMap, io::Write, os::unix::process::ExitStatusExt, path::{Path, PathBuf}, process::ExitStatus, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage;
+
+use super::{ClientId, SessionId};
+
+/// Maximum number of sessions that are kept in memory, older sessions are only in the history file.
+const MAX_HISTORY_LENGTH: usize = 100;
+
+/// Reason a session ended.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+	/// The client quit the application.
+	Cancelled,
+
+	/// The stream stopped, because the client disconnected or stopped sending pings.
+	StreamStopped,
+
+	/// The stream stopped and no client reconnected within the grace period.
+	GracePeriodExpired,
+
+	/// The streams or the encoder failed to start, so the session was rolled back.
+	StartFailed,
+
+	/// A client launched a different application while the session was disconnected.
+	Replaced,
+
+	/// The application quit normally.
+	ApplicationExited,
+
+	/// The application quit with an error or crashed.
+	ApplicationCrashed,
+
+	/// The server shut down.
+	Shutdown,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionRecord {
+	/// Identifies the session, missing for sessions that were recorded before sessions had identifiers.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub session_id: Option<SessionId>,
+
+	/// Unique id of the client that launched the session.
+	pub client_id: ClientId,
+
+	/// Title of the application that was launched.
+	pub application: String,
+
+	/// Time the session started, in seconds since the UNIX epoch.
+	pub start: u64,
+
+	/// Time the session ended, in seconds since the UNIX epoch.
+	pub end: u64,
+
+	/// Number of bytes sent to the client in audio and video packets.
+	pub bytes_sent: u64,
+
+	/// Average bitrate of the audio and video packets over the session, in bits per second.
+	pub average_bitrate: u64,
+
+	/// Reason the session ended.
+	pub disconnect_reason: DisconnectReason,
+
+	/// Exit code of the application, if it exited by itself.
+	pub exit_code: Option<i32>,
+
+	/// Signal that killed the application, if it crashed or was killed.
+	pub exit_signal: Option<i32>,
+}
+
+impl SessionRecord {
+	pub fn new(
+		session_id: SessionId,
+		client_id: ClientId,
+		application: String,
+		start: SystemTime,
+		bytes_sent: u64,
+		disconnect_reason: DisconnectReason,
+		exit_status: Option<ExitStatus>,
+	) -> Self {
+		let duration = start.elapsed().unwrap_or_default().as_secs_f64();
+		let average_bitrate = if duration > 0.0 { (bytes_sent as f64 * 8.0 / duration) as u64 } else { 0 };
+
+		Self {
+			session_id: Some(session_id),
+			client_id,
+			application,
+			start: unix_seconds(start),
+			end: unix_seconds(SystemTime::now()),
+			bytes_sent,
+			average_bitrate,
+			disconnect_reason,
+			exit_code: exit_status.and_then(|status| status.code()),
+			exit_signal: exit_status.and_then(|status| status.signal()),
+		}
+	}
+}
+
+/// Bytes sent to clients during a calendar month (UTC).
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BandwidthUsage {
+	/// Month the usage applies to, formatted as `YYYY-MM`.
+	pub month: String,
+
+	/// Total number of bytes sent to all clients.
+	pub bytes_sent: u64,
+
+	/// Number of bytes sent per client, by the fingerprint of the certificate the client paired with.
+	///
+	/// Sessions recorded before clients were told apart by their certificate count under the unique id they sent.
+	pub clients: BTreeMap<String, u64>,
+}
+
+impl BandwidthUsage {
+	fn new(month: String) -> Self {
+		Self { month, ..Default::default() }
+	}
+
+	pub fn add(&mut self, client_id: &str, bytes_sent: u64) {
+		self.bytes_sent += bytes_sent;
+		*self.clients.entry(client_id.to_string()).or_default() += bytes_sent;
+	}
+}
+
+/// History of sessions, every session is appended to a file with one JSON record per line.
+#[derive(Default)]
+pub struct SessionHistory {
+	/// File the history is stored in, or None if the history is only kept in memory.
+	path: Option<PathBuf>,
+
+	/// Most recent sessions, the most recent session last.
+	records: Vec<SessionRecord>,
+
+	/// Bandwidth used by the sessions that ended this month, including sessions that are no longer in `records`.
+	usage: BandwidthUsage,
+}
+
+impl SessionHistory {
+	/// Load the history from the data directory.
+	pub fn load() -> Self {
+		let Some(path) = dirs::data_dir().map(|dir| dir.join("moonshine").join("history.jsonl")) else {
+			tracing::warn!("Failed to get data directory, session history is not saved.");
+			return Self::default();
+		};
+
+		let records = match std::fs::read(&path) {
+			Ok(contents) => parse_records(&path, &contents),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+			Err(e) => {
+				tracing::warn!("Failed to read session history from {path:?}: {e}");
+				Vec::new()
+			},
+		};
+
+		let current_month = month(unix_seconds(SystemTime::now()));
+		let mut usage = BandwidthUsage::new(current_month.clone());
+		for record in records.iter().filter(|record| month(record.end) == current_month) {
+			usage.add(record.client_id.as_str(), record.bytes_sent);
+		}
+
+		let skip = records.len().saturating_sub(MAX_HISTORY_LENGTH);
+		Self { path: Some(path), records: records.into_iter().skip(skip).collect(), usage }
+	}
+
+	pub fn push(&mut self, record: SessionRecord) {
+		if let Some(path) = &self.path {
+			let _ = append_record(path, &record);
+		}
+
+		let record_month = month(record.end);
+		if record_month != self.usage.month {
+			self.usage = BandwidthUsage::new(record_month);
+		}
+		self.usage.add(record.client_id.as_str(), record.bytes_sent);
+
+		if self.records.len() >= MAX_HISTORY_LENGTH {
+			self.records.remove(0);
+		}
+		self.records.push(record);
+	}
+
+	/// Sessions in the history, the most recent session last.
+	pub fn records(&self) -> &[SessionRecord] {
+		&self.records
+	}
+
+	/// Bandwidth used by the sessions that ended in the current month.
+	pub fn usage(&self) -> BandwidthUsage {
+		let current_month = month(unix_seconds(SystemTime::now()));
+		if self.usage.month == current_month {
+			self.usage.clone()
+		} else {
+			BandwidthUsage::new(current_month)
+		}
+	}
+}
+
+/// Parse the records of a history file.
+///
+/// If some records are invalid, for example because the host crashed while a record was appended, the file is backed up
+/// and rewritten with only the valid records. Otherwise the next record would be appended to a truncated line.
+fn parse_records(path: &Path, contents: &[u8]) -> Vec<SessionRecord> {
+	let mut corrupt = false;
+	let records: Vec<SessionRecord> = contents.split(|byte| *byte == b'\n')
+		.filter(|line| !line.is_empty())
+		.filter_map(|line| serde_json::from_slice(line)
+			.map_err(|e| {
+				tracing::warn!("Skipping invalid session history record: {e}");
+				corrupt = true;
+			})
+			.ok()
+		)
+		.collect();
+
+	// A crash while appending can also leave a valid record without its newline.
+	corrupt |= !contents.is_empty() && !contents.ends_with(b"\n");
+	if corrupt && storage::back_up_corrupt(path).is_ok() {
+		let mut contents = Vec::new();
+		for record in &records {
+			if let Ok(line) = serde_json::to_vec(record) {
+				contents.extend_from_slice(&line);
+				contents.push(b'\n');
+			}
+		}
+
+		if storage::write_atomic(path, &contents).is_ok() {
+			tracing::info!("Rewrote session history with {} valid records.", records.len());
+		}
+	}
+
+	records
+}
+
+fn append_record(path: &PathBuf, record: &SessionRecord) -> Result<(), ()> {
+	let parent_dir = path.parent().ok_or_else(|| tracing::error!("Failed to get history dir for file {path:?}"))?;
+	std::fs::create_dir_all(parent_dir)
+		.map_err(|e| tracing::error!("Failed to create history dir: {e}"))?;
+
+	let mut line = serde_json::to_string(record)
+		.map_err(|e| tracing::error!("Failed to serialize session record: {e}"))?;
+	line.push('\n');
+
+	std::fs::OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)
+		.and_then(|mut file| {
+			file.write_all(line.as_bytes())?;
+			file.sync_data()
+		})
+		.map_err(|e| tracing::error!("Failed to save session record: {e}"))
+}
+
+/// Format the month (UTC) of a time in seconds since the UNIX epoch as `YYYY-MM`.
+fn month(unix_seconds: u64) -> String {
+	// Converts days to a civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+	let days = (unix_seconds / 86400) as i64 + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days.rem_euclid(146097);
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+	let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+	format!("{year:04}-{month:02}")
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).map(|t| t.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn record(application: &str) -> SessionRecord {
+		SessionRecord::new(
+			SessionId::new(),
+			ClientId::from("0123456789ABCDEF".to_string()),
+			application.to_string(),
+			SystemTime::now(),
+			1000,
+			DisconnectReason::Cancelled,
+			None,
+		)
+	}
+
+	fn line(record: &SessionRecord) -> Vec<u8> {
+		let mut line = serde_json::to_vec(record).unwrap();
+		line.push(b'\n');
+		line
+	}
+
+	/// Contents of the backups of a corrupt history file next to `path`.
+	fn backups(path: &Path) -> Vec<Vec<u8>> {
+		std::fs::read_dir(path.parent().unwrap()).unwrap()
+			.map(|entry| entry.unwrap().path())
+			.filter(|backup| backup.file_name().unwrap().to_string_lossy().starts_with("history.jsonl.corrupt-"))
+			.map(|backup| std::fs::read(backup).unwrap())
+			.collect()
+	}
+
+	fn applications(records: &[SessionRecord]) -> Vec<&str> {
+		records.iter().map(|record| record.application.as_str()).collect()
+	}
+
+	#[test]
+	fn valid_history() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("history.jsonl");
+		let contents = [line(&record("Desktop")), line(&record("Steam"))].concat();
+		std::fs::write(&path, &contents).unwrap();
+
+		let records = parse_records(&path, &contents);
+		assert_eq!(applications(&records), ["Desktop", "Steam"]);
+		assert_eq!(std::fs::read(&path).unwrap(), contents);
+		assert!(backups(&path).is_empty());
+	}
+
+	#[test]
+	fn truncated_line_is_rewritten() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("history.jsonl");
+		let valid = [line(&record("Desktop")), line(&record("Steam"))].concat();
+		let truncated = line(&record("Crashed"));
+		let contents = [&valid[..], &truncated[..truncated.len() / 2]].concat();
+		std::fs::write(&path, &contents).unwrap();
+
+		let records = parse_records(&path, &contents);
+		assert_eq!(applications(&records), ["Desktop", "Steam"]);
+		assert_eq!(std::fs::read(&path).unwrap(), valid);
+		assert_eq!(backups(&path), [contents]);
+
+		// The next record starts on a line of its own.
+		append_record(&path, &record("Next")).unwrap();
+		let contents = std::fs::read(&path).unwrap();
+		assert_eq!(applications(&parse_records(&path, &contents)), ["Desktop", "Steam", "Next"]);
+		assert_eq!(backups(&path).len(), 1);
+	}
+
+	#[test]
+	fn missing_newline_is_rewritten() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("history.jsonl");
+		let valid = [line(&record("Desktop")), line(&record("Steam"))].concat();
+		let contents = &valid[..valid.len() - 1];
+		std::fs::write(&path, contents).unwrap();
+
+		let records = parse_records(&path, contents);
+		assert_eq!(applications(&records), ["Desktop", "Steam"]);
+		assert_eq!(std::fs::read(&path).unwrap(), valid);
+		assert_eq!(backups(&path), [contents]);
+	}
+
+	#[test]
+	fn invalid_line_in_the_middle_is_dropped() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("history.jsonl");
+		let contents = [line(&record("Desktop")), b"{\"client_id\":\n".to_vec(), line(&record("Steam"))].concat();
+		std::fs::write(&path, &contents).unwrap();
+
+		let records = parse_records(&path, &contents);
+		assert_eq!(applications(&records), ["Desktop", "Steam"]);
+		assert_eq!(std::fs::read(&path).unwrap(), [line(&records[0]), line(&records[1])].concat());
+	}
+
+	#[test]
+	fn months() {
+		assert_eq!(month(0), "1970-01");
+		assert_eq!(month(951_782_400), "2000-02");
+		assert_eq!(month(1_709_251_199), "2024-02");
+		assert_eq!(month(1_709_251_200), "2024-03");
+	}
+}
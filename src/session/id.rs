@@ -0,0 +1,55 @@
+//! Identifiers that tie logs, stats, events and the session history to a session and the client that launched it.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Identifies a session, generated when a client launches an application.
+///
+/// A client that reattaches to a running application keeps the identifier of the session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SessionId(uuid::Uuid);
+
+impl SessionId {
+	pub fn new() -> Self {
+		Self(uuid::Uuid::new_v4())
+	}
+}
+
+impl Default for SessionId {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl fmt::Display for SessionId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.0.fmt(f)
+	}
+}
+
+/// Fingerprint of the certificate a client paired with, the same for every session of that client.
+///
+/// All Moonlight clients send the same unique id with their requests, the certificate is what tells them apart.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ClientId(String);
+
+impl ClientId {
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl From<String> for ClientId {
+	fn from(id: String) -> Self {
+		Self(id)
+	}
+}
+
+impl fmt::Display for ClientId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
@@ -0,0 +1,55 @@
+//! Keys that a client provides when launching or resuming a session.
+
+/// Length of the AES key in the `rikey` parameter.
+const KEY_LENGTH: usize = 16;
+
+/// Length of the initialization vectors for audio and control encryption.
+const INITIALIZATION_VECTOR_LENGTH: usize = 16;
+
+/// Keys used to encrypt the audio, control and video streams of a session.
+#[derive(Clone, Debug)]
+pub struct SessionKeys {
+	/// AES key shared with the client.
+	key: [u8; KEY_LENGTH],
+
+	/// Id of the key, which the audio initialization vectors are derived from.
+	key_id: u32,
+}
+
+impl SessionKeys {
+	/// Create keys from the `rikey` and `rikeyid` parameters of a launch or resume request.
+	///
+	/// Clients send the key id as a signed 32 bit integer, it is interpreted as the unsigned integer with the same bits.
+	pub fn from_launch_parameters(key: &[u8], key_id: i64) -> Result<Self, String> {
+		let key = key.try_into()
+			.map_err(|_| format!("Expected 'rikey' to be {KEY_LENGTH} bytes, got {} bytes.", key.len()))?;
+		let key_id = i32::try_from(key_id)
+			.map(|key_id| key_id as u32)
+			.or_else(|_| u32::try_from(key_id))
+			.map_err(|_| format!("Expected 'rikeyid' to be a 32 bit integer, got {key_id}."))?;
+
+		Ok(Self { key, key_id })
+	}
+
+	pub fn key(&self) -> &[u8] {
+		&self.key
+	}
+
+	/// Initialization vector for the audio packet with the given sequence number.
+	///
+	/// The vector starts with the sum of the key id and the sequence number in big endian, followed by zeroes.
+	pub fn audio_initialization_vector(&self, sequence_number: u16) -> [u8; INITIALIZATION_VECTOR_LENGTH] {
+		let mut initialization_vector = [0u8; INITIALIZATION_VECTOR_LENGTH];
+		initialization_vector[..4].copy_from_slice(&self.key_id.wrapping_add(sequence_number as u32).to_be_bytes());
+		initialization_vector
+	}
+
+	/// Initialization vector for the encrypted control message with the given sequence number.
+	///
+	/// Only the lowest byte of the sequence number is used, the rest of the vector is zeroes.
+	pub fn control_initialization_vector(&self, sequence_number: u32) -> [u8; INITIALIZATION_VECTOR_LENGTH] {
+		let mut initialization_vector = [0u8; INITIALIZATION_VECTOR_LENGTH];
+		initialization_vector[0] = sequence_number as u8;
+		initialization_vector
+	}
+}
@@ -1,24 +1,54 @@
 use async_shutdown::{TriggerShutdownToken, ShutdownManager};
-use enet::Enet;
 use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
 
-use crate::config::Config;
+use crate::{config::{CaptureArea, Config}, events::{EventBus, SessionEvent}, transcript::Transcript};
 
-use super::{Session, stream::{AudioStreamContext, VideoStreamContext}, SessionContext, SessionKeys};
+use super::{ApplicationExit, Session, gamepads::GamepadSlot, guests::Guests, history::{BandwidthUsage, DisconnectReason, SessionHistory, SessionRecord}, stats::SessionStats, stream::{TerminationReason, TransportContext, AudioStreamContext, VideoStreamContext, VideoStreamSettings, VideoTap}, ClientCapabilities, SessionContext, SessionId, SessionKeys};
+
+/// Applications that exit successfully within this duration are assumed to have started a detached process.
+const DETACHED_LAUNCHER_DURATION: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Interval at which statistics of the running session are published to event subscribers.
+const STATS_EVENT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
 
 pub enum SessionManagerCommand {
-	SetStreamContext(VideoStreamContext, AudioStreamContext),
+	SetStreamContext(VideoStreamContext, AudioStreamContext, ClientCapabilities),
 	GetSessionContext(oneshot::Sender<Option<SessionContext>>),
-	InitializeSession(SessionContext),
+	GetSessionStats(oneshot::Sender<Option<SessionStats>>),
+	GetGamepads(oneshot::Sender<Vec<GamepadSlot>>),
+	GetGuests(oneshot::Sender<Option<Guests>>),
+	GetHistory(oneshot::Sender<Vec<SessionRecord>>),
+	GetBandwidthUsage(oneshot::Sender<BandwidthUsage>),
+	InitializeSession(SessionContext, oneshot::Sender<Result<(), InitializeSessionError>>),
 	// GetCurrentSession(oneshot::Sender<Option<Session>>),
-	StartSession,
-	StopSession,
+	StartSession(oneshot::Sender<Result<(), ()>>),
+	/// The streams of a session that is starting listen for the client, or failed to.
+	StreamListening(SessionId, Result<(), ()>, oneshot::Sender<Result<(), ()>>),
+	/// The encoder of a session that the client started failed to start.
+	StreamStartFailed(SessionId),
+	StopSession(oneshot::Sender<()>),
 	UpdateKeys(SessionKeys),
+	UpdateVideoSettings(VideoStreamSettings),
+	UpdateCaptureArea(Option<CaptureArea>, oneshot::Sender<Result<(), ()>>),
+	UpdateAudioBitrate(u32, oneshot::Sender<Result<(), ()>>),
+	RequestIdrFrame,
+}
+
+/// Reasons a session could not be initialized.
+#[derive(Debug)]
+pub enum InitializeSessionError {
+	/// Another session is active, with the title of its application.
+	SessionActive(String, SessionId),
+
+	/// The session could not be created, the reason is logged.
+	Failed,
 }
 
 #[derive(Clone)]
 pub struct SessionManager {
 	command_tx: mpsc::Sender<SessionManagerCommand>,
+	video_tap: VideoTap,
 }
 
 #[derive(Default)]
@@ -31,29 +61,59 @@ struct SessionManagerInner {
 
 	/// The context within which the next audio stream will be created.
 	audio_stream_context: Option<AudioStreamContext>,
+
+	/// If set, the stream of the active session disconnected and the application is kept alive until this deadline.
+	grace_deadline: Option<tokio::time::Instant>,
+
+	/// Whether the stream of the active session is waiting for its streams to listen.
+	stream_starting: bool,
+
+	/// Sends commands to the manager itself, without keeping it alive.
+	command_tx: Option<mpsc::WeakSender<SessionManagerCommand>>,
+
+	/// Sessions that have ended.
+	history: SessionHistory,
+
+	/// Receives session lifecycle events.
+	events: EventBus,
+
+	/// Receives the encoded frames of every session.
+	video_tap: VideoTap,
 }
 
 impl SessionManager {
 	#[allow(clippy::result_unit_err)]
-	pub fn new(config: Config, shutdown_token: TriggerShutdownToken<i32>) -> Result<Self, ()> {
+	pub fn new(
+		config: Config,
+		transcript: Transcript,
+		events: EventBus,
+		shutdown_token: TriggerShutdownToken<i32>,
+	) -> Result<Self, ()> {
 		// Preferably this gets constructed in control.rs, however it needs to stay
 		// alive throughout the entire application runtime.
 		// Once dropped, it cannot be initialized again.
-		let enet = Enet::new()
-			.map_err(|e| tracing::error!("Failed to initialize Enet session: {e}"))?;
+		let transport = TransportContext::new()?;
 
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner: SessionManagerInner = Default::default();
-		tokio::spawn(async move { inner.run(config, command_rx, enet).await; drop(shutdown_token); });
-		Ok(Self { command_tx })
+		let video_tap = VideoTap::new();
+		let inner = SessionManagerInner {
+			history: SessionHistory::load(),
+			events,
+			video_tap: video_tap.clone(),
+			command_tx: Some(command_tx.downgrade()),
+			..Default::default()
+		};
+		tokio::spawn(async move { inner.run(config, transcript, command_rx, transport).await; drop(shutdown_token); });
+		Ok(Self { command_tx, video_tap })
 	}
 
 	pub async fn set_stream_context(
 		&self,
 		video_stream_context: VideoStreamContext,
-		audio_stream_context: AudioStreamContext
+		audio_stream_context: AudioStreamContext,
+		capabilities: ClientCapabilities,
 	) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context)).await
+		self.command_tx.send(SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context, capabilities)).await
 			.map_err(|e| tracing::error!("Failed to send SetStreamContext command: {e}"))
 	}
 
@@ -66,11 +126,63 @@ impl SessionManager {
 			.map_err(|e| tracing::error!("Failed to wait for GetCurrentSession response: {e}"))
 	}
 
-	pub async fn initialize_session(&self, context: SessionContext) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::InitializeSession(context))
+	pub async fn get_session_stats(&self) -> Result<Option<SessionStats>, ()> {
+		let (session_stats_tx, session_stats_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::GetSessionStats(session_stats_tx))
+			.await
+			.map_err(|e| tracing::error!("Failed to get session stats: {e}"))?;
+		session_stats_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for GetSessionStats response: {e}"))
+	}
+
+	/// Clients that joined the active session as guests, or none if there is no active session.
+	pub async fn get_guests(&self) -> Result<Option<Guests>, ()> {
+		let (guests_tx, guests_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::GetGuests(guests_tx))
 			.await
-			.map_err(|e| tracing::error!("Failed to initialize session: {e}"))?;
-		Ok(())
+			.map_err(|e| tracing::error!("Failed to get guests: {e}"))?;
+		guests_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for GetGuests response: {e}"))
+	}
+
+	/// Player numbers of the gamepads in the active session, empty if there is no active session.
+	pub async fn get_gamepads(&self) -> Result<Vec<GamepadSlot>, ()> {
+		let (gamepads_tx, gamepads_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::GetGamepads(gamepads_tx))
+			.await
+			.map_err(|e| tracing::error!("Failed to get gamepads: {e}"))?;
+		gamepads_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for GetGamepads response: {e}"))
+	}
+
+	/// Bytes sent to clients this month, including the active session.
+	pub async fn get_bandwidth_usage(&self) -> Result<BandwidthUsage, ()> {
+		let (usage_tx, usage_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::GetBandwidthUsage(usage_tx))
+			.await
+			.map_err(|e| tracing::error!("Failed to get bandwidth usage: {e}"))?;
+		usage_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for GetBandwidthUsage response: {e}"))
+	}
+
+	pub async fn get_history(&self) -> Result<Vec<SessionRecord>, ()> {
+		let (history_tx, history_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::GetHistory(history_tx))
+			.await
+			.map_err(|e| tracing::error!("Failed to get session history: {e}"))?;
+		history_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for GetHistory response: {e}"))
+	}
+
+	pub async fn initialize_session(&self, context: SessionContext) -> Result<(), InitializeSessionError> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::InitializeSession(context, response_tx))
+			.await
+			.map_err(|e| tracing::error!("Failed to initialize session: {e}"))
+			.map_err(|()| InitializeSessionError::Failed)?;
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for InitializeSession response: {e}"))
+			.map_err(|()| InitializeSessionError::Failed)?
 	}
 
 	// pub async fn current_session(&self) -> Result<Option<Session>, ()> {
@@ -82,16 +194,24 @@ impl SessionManager {
 	// 		.map_err(|e| tracing::error!("Failed to wait for GetCurrentSession response: {e}"))
 	// }
 
+	/// Start the stream of the session, a session whose stream fails to start is closed.
 	pub async fn start_session(&self) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::StartSession)
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::StartSession(response_tx))
 			.await
-			.map_err(|e| tracing::error!("Failed to start session: {e}"))
+			.map_err(|e| tracing::error!("Failed to start session: {e}"))?;
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for StartSession response: {e}"))?
 	}
 
+	/// Stop the stream and close the session, returns once the session is closed.
 	pub async fn stop_session(&self) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::StopSession)
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::StopSession(response_tx))
 			.await
-			.map_err(|e| tracing::error!("Failed to stop session: {e}"))
+			.map_err(|e| tracing::error!("Failed to stop session: {e}"))?;
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for StopSession response: {e}"))
 	}
 
 	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
@@ -99,27 +219,165 @@ impl SessionManager {
 			.await
 			.map_err(|e| tracing::error!("Failed to stop session: {e}"))
 	}
+
+	pub async fn update_video_settings(&self, settings: VideoStreamSettings) -> Result<(), ()> {
+		self.command_tx.send(SessionManagerCommand::UpdateVideoSettings(settings))
+			.await
+			.map_err(|e| tracing::error!("Failed to update video settings: {e}"))
+	}
+
+	/// Encoded frames of the video stream of whichever session is running.
+	pub fn video_tap(&self) -> VideoTap {
+		self.video_tap.clone()
+	}
+
+	/// Ask the encoder of the running session for an IDR frame, does nothing if there is no running session.
+	pub async fn request_idr_frame(&self) -> Result<(), ()> {
+		self.command_tx.send(SessionManagerCommand::RequestIdrFrame)
+			.await
+			.map_err(|e| tracing::error!("Failed to request IDR frame: {e}"))
+	}
+
+	/// Change the part of the desktop that is streamed, fails if there is no running stream.
+	pub async fn update_capture_area(&self, capture_area: Option<CaptureArea>) -> Result<(), ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::UpdateCaptureArea(capture_area, response_tx))
+			.await
+			.map_err(|e| tracing::error!("Failed to update capture area: {e}"))?;
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for UpdateCaptureArea response: {e}"))?
+	}
+
+	/// Change the bitrate of the audio stream of the running session, in bits per second.
+	pub async fn update_audio_bitrate(&self, bitrate: u32) -> Result<(), ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(SessionManagerCommand::UpdateAudioBitrate(bitrate, response_tx))
+			.await
+			.map_err(|e| tracing::error!("Failed to update audio bitrate: {e}"))?;
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for UpdateAudioBitrate response: {e}"))?
+	}
 }
 
 impl SessionManagerInner {
+	/// Close the active session, if any, and add it to the history.
+	fn close_session(&mut self, reason: DisconnectReason) {
+		if let Some(session) = self.session.take() {
+			self.events.publish(SessionEvent::Stopped {
+				session_id: session.get_context().session_id,
+				client_id: session.get_context().client_id.clone(),
+				application: session.get_context().application.title.clone(),
+				reason,
+			});
+			self.history.push(SessionRecord::new(
+				session.get_context().session_id,
+				session.get_context().client_id.clone(),
+				session.get_context().application.title.clone(),
+				session.started(),
+				session.stats().bytes_sent(),
+				reason,
+				session.application_exit(),
+			));
+		}
+	}
+
 	async fn run(
 		mut self,
 		config: Config,
+		transcript: Transcript,
 		mut command_rx: mpsc::Receiver<SessionManagerCommand>,
-		enet: Enet,
+		transport: TransportContext,
 	) {
 		tracing::debug!("Waiting for commands.");
 
 		let mut stop_signal = ShutdownManager::new();
+		let (application_exit_tx, mut application_exit_rx) = mpsc::channel::<ApplicationExit>(10);
+		let mut stats_interval = tokio::time::interval(STATS_EVENT_INTERVAL);
+		stats_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
 		loop {
 			tokio::select! {
-				_ = stop_signal.wait_shutdown_triggered() => {
-					tracing::debug!("Closing session.");
-					self.session = None;
+				// While the stream starts, failures are handled when the result of starting it comes in.
+				_ = stop_signal.wait_shutdown_triggered(), if !self.stream_starting => {
+					match &mut self.session {
+						Some(session) if config.session_grace_period > 0 => {
+							tracing::info!(
+								"Stream stopped, keeping application '{}' alive for {} seconds.",
+								session.get_context().application.title,
+								config.session_grace_period,
+							);
+							session.mark_disconnected();
+							self.grace_deadline = Some(tokio::time::Instant::now() + std::time::Duration::from_secs(config.session_grace_period));
+						},
+						_ => {
+							tracing::debug!("Closing session.");
+							self.close_session(DisconnectReason::StreamStopped);
+						},
+					}
 					stop_signal = ShutdownManager::new();
 				},
 
+				_ = tokio::time::sleep_until(self.grace_deadline.unwrap_or_else(tokio::time::Instant::now)), if self.grace_deadline.is_some() => {
+					tracing::info!("No client reconnected within the grace period, closing session.");
+					self.grace_deadline = None;
+					self.close_session(DisconnectReason::GracePeriodExpired);
+				},
+
+				_ = stats_interval.tick() => {
+					let Some(session) = self.session.as_ref().filter(|s| s.is_running()) else {
+						continue;
+					};
+					if !self.events.has_subscribers() {
+						continue;
+					}
+
+					if let Ok(stats) = session.stats().snapshot() {
+						self.events.publish(SessionEvent::StatsUpdated {
+							client_id: session.get_context().client_id.clone(),
+							stats,
+						});
+					}
+				},
+
+				Some(exit) = application_exit_rx.recv() => {
+					// Applications of previous sessions can exit as well, those can be ignored.
+					let Some(session) = self.session.as_mut().filter(|s| s.application_pid() == Some(exit.pid)) else {
+						tracing::debug!("Process {} exited with {}.", exit.pid, exit.status);
+						continue;
+					};
+
+					// Launchers (ie. `steam steam://run/<id>`) hand the application over to another process and exit right away.
+					let launch_duration = session.started().elapsed().unwrap_or_default();
+					if exit.status.success() && launch_duration < DETACHED_LAUNCHER_DURATION {
+						tracing::info!("Application '{}' exited right after launching, assuming it started a detached process.", session.get_context().application.title);
+						continue;
+					}
+
+					tracing::info!("Application '{}' exited with {}.", session.get_context().application.title, exit.status);
+					session.set_application_exit(exit.status);
+					let disconnect_reason = if exit.status.success() {
+						DisconnectReason::ApplicationExited
+					} else {
+						DisconnectReason::ApplicationCrashed
+					};
+
+					if session.is_running() {
+						// Let the client know the stream ended because of the application, not because of a network issue.
+						let reason = if exit.status.success() {
+							TerminationReason::ApplicationExited
+						} else {
+							TerminationReason::ApplicationCrashed
+						};
+						let _ = session.terminate(reason).await;
+						self.close_session(disconnect_reason);
+					} else if self.grace_deadline.is_some() {
+						// There is nothing left to reconnect to.
+						tracing::info!("Closing disconnected session, because its application exited.");
+						self.grace_deadline = None;
+						self.close_session(disconnect_reason);
+					}
+				},
+
 				command = command_rx.recv() => {
 					let command = match command {
 						Some(command) => command,
@@ -130,13 +388,26 @@ impl SessionManagerInner {
 					};
 
 					match command {
-						SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context) =>  {
-							if self.session.is_none() {
+						SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context, capabilities) =>  {
+							let Some(session) = &mut self.session else {
 								// Well we can, but it is not expected.
 								tracing::warn!("Can't set stream context without an active session.");
 								continue;
+							};
+
+							// A client that re-negotiates while streaming changes the settings of the running stream.
+							if session.is_running() {
+								let settings = VideoStreamSettings {
+									bitrate: Some(video_stream_context.bitrate),
+									fps: Some(video_stream_context.fps),
+								};
+								let _ = session.update_video_settings(settings).await;
 							}
 
+							let mut session_context = session.get_context().clone();
+							session_context.capabilities.update_from_announce(&capabilities);
+							let _ = session.update_context(session_context).await;
+
 							self.video_stream_context = Some(video_stream_context);
 							self.audio_stream_context = Some(audio_stream_context);
 						},
@@ -148,16 +419,88 @@ impl SessionManagerInner {
 							}
 						},
 
-						SessionManagerCommand::InitializeSession(session_context) => {
-							if self.session.is_some() {
-								tracing::warn!("Can't initialize a session, there is already an active session.");
-								continue;
+						SessionManagerCommand::GetHistory(history_tx) => {
+							if history_tx.send(self.history.records().to_vec()).is_err() {
+								tracing::error!("Failed to send session history.");
+							}
+						},
+
+						SessionManagerCommand::GetBandwidthUsage(usage_tx) => {
+							let mut usage = self.history.usage();
+							if let Some(session) = &self.session {
+								usage.add(session.get_context().client_id.as_str(), session.stats().bytes_sent());
+							}
+							if usage_tx.send(usage).is_err() {
+								tracing::error!("Failed to send bandwidth usage.");
+							}
+						},
+
+						SessionManagerCommand::GetSessionStats(session_stats_tx) => {
+							let stats = self.session.as_ref().map(|s| s.stats().clone());
+							if session_stats_tx.send(stats).is_err() {
+								tracing::error!("Failed to send current session stats.");
+							}
+						},
+
+						SessionManagerCommand::GetGuests(guests_tx) => {
+							let guests = self.session.as_ref().map(|s| s.guests().clone());
+							if guests_tx.send(guests).is_err() {
+								tracing::error!("Failed to send guests.");
 							}
+						},
+
+						SessionManagerCommand::GetGamepads(gamepads_tx) => {
+							let gamepads = self.session.as_ref().map(|s| s.gamepads().list()).unwrap_or_default();
+							if gamepads_tx.send(gamepads).is_err() {
+								tracing::error!("Failed to send gamepads.");
+							}
+						},
 
-							self.session = match Session::new(config.clone(), session_context, enet.clone(), stop_signal.clone()) {
-								Ok(session) => Some(session),
-								Err(()) => continue,
+						SessionManagerCommand::InitializeSession(session_context, response_tx) => {
+							if let Some(session) = &mut self.session {
+								if self.grace_deadline.is_none() {
+									let context = session.get_context();
+									tracing::warn!("Can't initialize a session, session {} is already active.", context.session_id);
+									let error = InitializeSessionError::SessionActive(context.application.title.clone(), context.session_id);
+									if response_tx.send(Err(error)).is_err() {
+										tracing::error!("Failed to send InitializeSession response.");
+									}
+									continue;
+								}
+
+								if session.get_context().application_id == session_context.application_id {
+									tracing::info!("Reattaching to running application '{}'.", session_context.application.title);
+									self.grace_deadline = None;
+
+									// The reattached client continues the existing session.
+									let mut session_context = session_context;
+									session_context.session_id = session.get_context().session_id;
+									let result = session.update_context(session_context).await
+										.map_err(|()| InitializeSessionError::Failed);
+									if response_tx.send(result).is_err() {
+										tracing::error!("Failed to send InitializeSession response.");
+									}
+									continue;
+								}
+
+								tracing::info!(
+									"Closing disconnected session for '{}', because a different application was requested.",
+									session.get_context().application.title,
+								);
+								self.grace_deadline = None;
+								self.close_session(DisconnectReason::Replaced);
+							}
+
+							let result = match Session::new(config.clone(), session_context, transport.clone(), transcript.clone(), self.events.clone(), self.video_tap.clone(), application_exit_tx.clone()) {
+								Ok(session) => {
+									self.session = Some(session);
+									Ok(())
+								},
+								Err(()) => Err(InitializeSessionError::Failed),
 							};
+							if response_tx.send(result).is_err() {
+								tracing::error!("Failed to send InitializeSession response.");
+							}
 						},
 
 						// SessionManagerCommand::GetCurrentSession(session_tx) => {
@@ -166,36 +509,176 @@ impl SessionManagerInner {
 						// 	}
 						// }
 
-						SessionManagerCommand::StartSession => {
+						SessionManagerCommand::StartSession(response_tx) => {
 							let Some(session) = &mut self.session else {
 								tracing::warn!("Can't launch a session, there is no session created yet.");
+								let _ = response_tx.send(Err(()));
 								continue;
 							};
 
 							if session.is_running() {
 								tracing::info!("Can't start session, it is already running.");
+								let _ = response_tx.send(Ok(()));
+								continue;
+							}
+							if self.stream_starting {
+								tracing::info!("Can't start session, its stream is already starting.");
+								let _ = response_tx.send(Err(()));
 								continue;
 							}
 
 							let Some(video_stream_context) = self.video_stream_context.clone() else {
 								tracing::warn!("Can't start a stream without a video stream context.");
+								let _ = response_tx.send(Err(()));
 								continue;
 							};
 							let Some(audio_stream_context) = self.audio_stream_context.clone() else {
 								tracing::warn!("Can't start a stream without a audio stream context.");
+								let _ = response_tx.send(Err(()));
 								continue;
 							};
 
-							let _ = session.start_stream(video_stream_context, audio_stream_context).await;
+							let session_id = session.get_context().session_id;
+							let stream_start = match session.start_stream(video_stream_context, audio_stream_context, stop_signal.clone()).await {
+								Ok(stream_start) => stream_start,
+								Err(()) => {
+									tracing::warn!("Failed to start the stream, closing session.");
+									let _ = stop_signal.trigger_shutdown(());
+									stop_signal = ShutdownManager::new();
+									self.close_session(DisconnectReason::StartFailed);
+									self.grace_deadline = None;
+									let _ = response_tx.send(Err(()));
+									continue;
+								},
+							};
+
+							// The streams can take a while to listen, meanwhile other commands are handled.
+							self.stream_starting = true;
+							let command_tx = self.command_tx.clone();
+							tokio::spawn(async move {
+								let listening = stream_start.listening.await
+									.unwrap_or_else(|e| {
+										tracing::error!("Failed to wait for StartStream response: {e}");
+										Err(())
+									});
+								let listened = listening.is_ok();
+								let Some(manager_tx) = command_tx.as_ref().and_then(mpsc::WeakSender::upgrade) else {
+									return;
+								};
+								if manager_tx.send(SessionManagerCommand::StreamListening(session_id, listening, response_tx)).await.is_err() || !listened {
+									return;
+								}
+								drop(manager_tx);
+
+								// The client starts the encoder later on, a stream that stops before that drops the sender.
+								if let Ok(Err(())) = stream_start.started.await {
+									if let Some(manager_tx) = command_tx.as_ref().and_then(mpsc::WeakSender::upgrade) {
+										let _ = manager_tx.send(SessionManagerCommand::StreamStartFailed(session_id)).await;
+									}
+								}
+							}.in_current_span());
 						},
 
-						SessionManagerCommand::StopSession => {
+						SessionManagerCommand::StreamListening(session_id, result, response_tx) => {
+							self.stream_starting = false;
+							let Some(session) = self.session.as_mut().filter(|session| session.get_context().session_id == session_id) else {
+								// The session was stopped meanwhile, which stopped its streams as well.
+								tracing::debug!("Session {session_id:?} closed while its stream was starting.");
+								stop_signal = ShutdownManager::new();
+								let _ = response_tx.send(Err(()));
+								continue;
+							};
+
+							if result.is_ok() {
+								session.mark_running();
+								self.events.publish(SessionEvent::Started {
+									session_id,
+									client_id: session.get_context().client_id.clone(),
+									application: session.get_context().application.title.clone(),
+								});
+							} else {
+								// Nothing of the stream is left running, so the client can launch again without a restart of the host.
+								tracing::warn!("Failed to start the stream, closing session.");
+								let _ = stop_signal.trigger_shutdown(());
+								stop_signal = ShutdownManager::new();
+								self.close_session(DisconnectReason::StartFailed);
+								self.grace_deadline = None;
+							}
+
+							if response_tx.send(result).is_err() {
+								tracing::error!("Failed to send StartSession response.");
+							}
+						},
+
+						SessionManagerCommand::StreamStartFailed(session_id) => {
+							let Some(session) = self.session.as_mut().filter(|session| session.is_running() && session.get_context().session_id == session_id) else {
+								continue;
+							};
+
+							// Tell the client why the stream ends, otherwise it waits for video until it times out.
+							tracing::warn!("Failed to start the encoder, closing session.");
+							let _ = session.terminate(TerminationReason::StartFailed).await;
+							let _ = stop_signal.trigger_shutdown(());
+							stop_signal = ShutdownManager::new();
+							self.close_session(DisconnectReason::StartFailed);
+							self.grace_deadline = None;
+						},
+
+						SessionManagerCommand::StopSession(response_tx) => {
 							if let Some(session) = &mut self.session {
 								let _ = session.stop_stream().await;
-								self.session = None;
+								if !session.get_context().application.terminates_on_cancel() {
+									session.detach_application();
+								}
+								self.close_session(DisconnectReason::Cancelled);
+								self.grace_deadline = None;
 							} else {
 								tracing::debug!("Trying to stop session, but no session is currently active.");
 							}
+
+							// The session is gone by now, so the client sees the host as free when it asks for the server info.
+							let _ = response_tx.send(());
+						},
+
+						SessionManagerCommand::RequestIdrFrame => {
+							if let Some(session) = &self.session {
+								let _ = session.request_idr_frame().await;
+							}
+						},
+
+						SessionManagerCommand::UpdateVideoSettings(settings) => {
+							let Some(session) = &self.session else {
+								tracing::warn!("Can't update video settings, there is no session created yet.");
+								continue;
+							};
+
+							let _ = session.update_video_settings(settings).await;
+						},
+
+						SessionManagerCommand::UpdateCaptureArea(capture_area, response_tx) => {
+							let result = match &self.session {
+								Some(session) if session.is_running() => session.update_capture_area(capture_area).await,
+								_ => {
+									tracing::warn!("Can't update the capture area, there is no running stream.");
+									Err(())
+								},
+							};
+							if response_tx.send(result).is_err() {
+								tracing::error!("Failed to send UpdateCaptureArea response.");
+							}
+						},
+
+						SessionManagerCommand::UpdateAudioBitrate(bitrate, response_tx) => {
+							let result = match &self.session {
+								Some(session) if session.is_running() => session.update_audio_bitrate(bitrate).await,
+								_ => {
+									tracing::warn!("Can't update the audio bitrate, there is no running stream.");
+									Err(())
+								},
+							};
+							if response_tx.send(result).is_err() {
+								tracing::error!("Failed to send UpdateAudioBitrate response.");
+							}
 						},
 
 						SessionManagerCommand::UpdateKeys(keys) => {
@@ -210,5 +693,7 @@ impl SessionManagerInner {
 				}
 			}
 		}
+
+		self.close_session(DisconnectReason::Shutdown);
 	}
 }
@@ -1,25 +1,34 @@
-use std::process::Stdio;
+use std::{process::{ExitStatus, Stdio}, time::SystemTime};
 
 use async_shutdown::ShutdownManager;
-use enet::Enet;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
 
-use crate::{config::{Config, ApplicationConfig}, session::stream::{VideoStream, AudioStream, ControlStream}};
+use crate::{config::{AudioRouting, Config, ApplicationConfig, CaptureArea}, events::EventBus, host::{audio::HostAudioMuteGuard, compositor::CompositorGuard, display::PrivacyGuard, gamescope, inhibit::SleepInhibitGuard, notifications::DoNotDisturbGuard}, session::stream::{VideoStream, VideoStreamSettings, VideoTap, AudioStream, ControlStream, TerminationReason, TransportContext}, transcript::Transcript};
 
-use self::stream::{VideoStreamContext, AudioStreamContext};
+use self::{application::ApplicationProcess, gamepads::GamepadSlots, guests::Guests, stats::SessionStats, stream::{VideoStreamContext, AudioStreamContext}};
+pub use application::ApplicationExit;
+pub use capabilities::{ClientCapabilities, ENCRYPTION_CONTROL, ENCRYPTION_VIDEO};
+pub use clock::{Clock, SessionClock, SystemClock};
+pub use id::{ClientId, SessionId};
+pub use keys::SessionKeys;
 pub use manager::SessionManager;
 
+mod application;
+mod capabilities;
+mod clock;
+mod id;
+mod keys;
+
+pub mod gamepads;
+pub mod guests;
+pub mod history;
 pub mod manager;
+pub mod stats;
 pub mod stream;
 
-#[derive(Clone, Debug)]
-pub struct SessionKeys {
-	/// AES GCM key used for encoding control messages.
-	pub remote_input_key: Vec<u8>,
-
-	/// AES GCM initialization vector for control messages.
-	pub remote_input_key_id: i64,
-}
+/// Time the video and audio streams have to start listening for the client before the session is rolled back.
+const STREAM_START_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
 /// Launch a session for a client.
 #[derive(Clone, Debug)]
@@ -38,19 +47,73 @@ pub struct SessionContext {
 
 	/// Encryption keys for encoding traffic.
 	pub keys: SessionKeys,
+
+	/// Identifies the session in logs, stats, events and the session history.
+	pub session_id: SessionId,
+
+	/// Fingerprint of the certificate of the client that launched the session.
+	pub client_id: ClientId,
+
+	/// Name of the client that launched the session, if it has one.
+	pub client_name: Option<String>,
+
+	/// What the client supports, completed when the client negotiates the stream.
+	pub capabilities: ClientCapabilities,
+
+	/// Random payload the client sends in audio and video PINGs, announced in the RTSP SETUP response.
+	pub ping_payload: String,
+}
+
+impl SessionContext {
+	/// Whether the session was launched by the client with this certificate, only that client may resume or cancel it.
+	pub fn is_owned_by(&self, client_certificate: &str) -> bool {
+		self.client_id.as_str() == client_certificate
+	}
+
+	/// Name of the client that launched the session, or its id if it has no name.
+	pub fn owner_name(&self) -> &str {
+		self.client_name.as_deref().unwrap_or(self.client_id.as_str())
+	}
+}
+
+/// Progress of a stream that is starting.
+pub struct StreamStart {
+	/// Resolves once the streams listen for the client, or failed to.
+	pub listening: oneshot::Receiver<Result<(), ()>>,
+
+	/// Resolves once the client started the stream and the encoder started, or failed to.
+	///
+	/// The sender is dropped if the stream stops before the client starts it.
+	pub started: oneshot::Receiver<Result<(), ()>>,
 }
 
 enum SessionCommand {
-	StartStream(VideoStreamContext, AudioStreamContext),
+	StartStream(VideoStreamContext, AudioStreamContext, ShutdownManager<()>, oneshot::Sender<Result<(), ()>>, oneshot::Sender<Result<(), ()>>),
 	StopStream,
 	UpdateKeys(SessionKeys),
+	UpdateContext(SessionContext),
+	UpdateVideoSettings(VideoStreamSettings),
+	UpdateCaptureArea(Option<CaptureArea>),
+	UpdateAudioBitrate(u32),
+	RequestIdrFrame,
+	Terminate(TerminationReason, oneshot::Sender<Result<(), ()>>),
 }
 
-#[derive(Clone)]
 pub struct Session {
 	command_tx: mpsc::Sender<SessionCommand>,
 	context: SessionContext,
 	running: bool,
+	stats: SessionStats,
+	gamepads: GamepadSlots,
+	guests: Guests,
+	started: SystemTime,
+	application: Option<ApplicationProcess>,
+	application_exit: Option<ExitStatus>,
+	_privacy_guard: Option<PrivacyGuard>,
+	_do_not_disturb_guard: Option<DoNotDisturbGuard>,
+	_compositor_guard: Option<CompositorGuard>,
+	_audio_mute_guard: Option<HostAudioMuteGuard>,
+	_sleep_inhibit_guard: Option<SleepInhibitGuard>,
 }
 
 #[allow(clippy::result_unit_err)]
@@ -58,30 +121,101 @@ impl Session {
 	pub fn new(
 		config: Config,
 		context: SessionContext,
-		enet: Enet,
-		stop_signal: ShutdownManager<()>,
+		transport: TransportContext,
+		transcript: Transcript,
+		events: EventBus,
+		video_tap: VideoTap,
+		application_exit_tx: mpsc::Sender<ApplicationExit>,
 	) -> Result<Self, ()> {
-		if let Some(run_before) = &context.application.run_before {
-			for command in run_before {
-				run_command(command, &context);
+		for command in context.application.run_before.iter().flatten() {
+			run_command(command, &context);
+		}
+
+		// The application itself is tracked, so that it can be terminated with the session.
+		let mut application = None;
+		if let Some(application_command) = &context.application.command {
+			let mut application_command = application_command.clone();
+			if let Some(wrapper) = &context.application.wrapper {
+				application_command = application::wrap_command(wrapper, &application_command);
+			}
+			if context.application.gamescope {
+				application_command = gamescope::wrap_command(&application_command, context.resolution, context.refresh_rate);
 			}
+			application = ApplicationProcess::spawn(prepare_command(&application_command, &context), stream_environment(&context), application_exit_tx).ok();
+		} else if context.application.gamescope || context.application.wrapper.is_some() {
+			tracing::warn!("Application '{}' has no command to run.", context.application.title);
 		}
 
+		let privacy_guard = context.application.privacy_mode
+			.and_then(|mode| PrivacyGuard::enable(mode).ok());
+		let do_not_disturb_guard = if config.host.do_not_disturb {
+			DoNotDisturbGuard::enable().ok()
+		} else {
+			None
+		};
+		let compositor_guard = if config.host.optimize_compositor {
+			CompositorGuard::enable().ok()
+		} else {
+			None
+		};
+		let audio_routing = config.host.audio_routing_for(context.capabilities.local_audio);
+		tracing::debug!("Routing audio to {audio_routing:?}.");
+		let audio_mute_guard = if audio_routing == AudioRouting::Stream {
+			HostAudioMuteGuard::enable().ok()
+		} else {
+			None
+		};
+		let sleep_inhibit_guard = if config.host.inhibit_sleep {
+			SleepInhibitGuard::enable().ok()
+		} else {
+			None
+		};
+
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = SessionInner { config, video_stream: None, audio_stream: None, control_stream: None };
-		tokio::spawn(inner.run(command_rx, context.clone(), enet, stop_signal));
-		Ok(Self { command_tx, context, running: false })
+		let stats = SessionStats::new(context.session_id, context.client_id.clone());
+		let gamepads = GamepadSlots::default();
+		let guests = Guests::default();
+		let inner = SessionInner {
+			config,
+			stats: stats.clone(),
+			gamepads: gamepads.clone(),
+			guests: guests.clone(),
+			transcript,
+			events,
+			video_tap,
+			video_stream: None,
+			audio_stream: None,
+			control_stream: None,
+			stop_signal: None,
+		};
+		// Everything the session spawns runs in this span, so logs of concurrent sessions can be told apart.
+		let span = tracing::info_span!("session", id = %context.session_id, client = %context.client_id, name = tracing::field::Empty);
+		if let Some(name) = &context.client_name {
+			span.record("name", name.as_str());
+		}
+		tokio::spawn(inner.run(command_rx, context.clone(), transport).instrument(span));
+		Ok(Self { command_tx, context, running: false, stats, gamepads, guests, started: SystemTime::now(), application, application_exit: None, _privacy_guard: privacy_guard, _do_not_disturb_guard: do_not_disturb_guard, _compositor_guard: compositor_guard, _audio_mute_guard: audio_mute_guard, _sleep_inhibit_guard: sleep_inhibit_guard })
 	}
 
+	/// Start the streams, the session is only running once [`Session::mark_running`] is called.
 	pub async fn start_stream(
-		&mut self,
+		&self,
 		video_stream_context: VideoStreamContext,
 		audio_stream_context: AudioStreamContext,
-	) -> Result <(), ()> {
-		self.running = true;
-		self.command_tx.send(SessionCommand::StartStream(video_stream_context, audio_stream_context))
+		stop_signal: ShutdownManager<()>,
+	) -> Result <StreamStart, ()> {
+		let (listening_tx, listening) = oneshot::channel();
+		let (started_tx, started) = oneshot::channel();
+		self.command_tx.send(SessionCommand::StartStream(video_stream_context, audio_stream_context, stop_signal, listening_tx, started_tx))
 			.await
-			.map_err(|e| tracing::error!("Failed to send StartStream command: {e}"))
+			.map_err(|e| tracing::error!("Failed to send StartStream command: {e}"))?;
+
+		Ok(StreamStart { listening, started })
+	}
+
+	/// Mark the stream as running, once its streams listen for the client.
+	pub fn mark_running(&mut self) {
+		self.running = true;
 	}
 
 	pub async fn stop_stream(&mut self) -> Result<(), ()> {
@@ -99,14 +233,104 @@ impl Session {
 		self.running
 	}
 
-	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
+	pub fn stats(&self) -> &SessionStats {
+		&self.stats
+	}
+
+	/// Player numbers of the gamepads of clients in this session.
+	pub fn gamepads(&self) -> &GamepadSlots {
+		&self.gamepads
+	}
+
+	/// Clients that joined this session to play along.
+	pub fn guests(&self) -> &Guests {
+		&self.guests
+	}
+
+	/// Process id of the application that was launched for this session, if it was launched.
+	pub fn application_pid(&self) -> Option<u32> {
+		self.application.as_ref().map(ApplicationProcess::pid)
+	}
+
+	/// Time at which the session was created.
+	pub fn started(&self) -> SystemTime {
+		self.started
+	}
+
+	/// Remember how the application exited, so it can be reported.
+	pub fn set_application_exit(&mut self, status: ExitStatus) {
+		self.application_exit = Some(status);
+	}
+
+	pub fn application_exit(&self) -> Option<ExitStatus> {
+		self.application_exit
+	}
+
+	/// Tell the client why the stream ends and stop the stream.
+	pub async fn terminate(&mut self, reason: TerminationReason) -> Result<(), ()> {
+		self.running = false;
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(SessionCommand::Terminate(reason, response_tx))
+			.await
+			.map_err(|e| tracing::error!("Failed to send Terminate command: {e}"))?;
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for Terminate response: {e}"))?
+	}
+
+	/// Leave the application running when the session ends, `run_after` commands still run.
+	pub fn detach_application(&mut self) {
+		if let Some(application) = self.application.take() {
+			tracing::info!("Leaving application '{}' (process {}) running.", self.context.application.title, application.pid());
+		}
+	}
+
+	/// Mark the stream as stopped, without stopping the application.
+	///
+	/// This allows a client to reconnect to the application that is still running.
+	pub fn mark_disconnected(&mut self) {
+		self.running = false;
+	}
+
+	pub async fn update_context(&mut self, context: SessionContext) -> Result<(), ()> {
+		self.context = context.clone();
+		self.command_tx.send(SessionCommand::UpdateContext(context)).await
+			.map_err(|e| tracing::error!("Failed to send UpdateContext command: {e}"))
+	}
+
+	pub async fn update_keys(&mut self, keys: SessionKeys) -> Result<(), ()> {
+		// The RTSP server encrypts with the keys of the context, so they have to be current when a client resumes.
+		self.context.keys = keys.clone();
 		self.command_tx.send(SessionCommand::UpdateKeys(keys)).await
 			.map_err(|e| tracing::error!("Failed to send UpdateKeys command: {e}"))
 	}
+
+	pub async fn update_video_settings(&self, settings: VideoStreamSettings) -> Result<(), ()> {
+		self.command_tx.send(SessionCommand::UpdateVideoSettings(settings)).await
+			.map_err(|e| tracing::error!("Failed to send UpdateVideoSettings command: {e}"))
+	}
+
+	pub async fn update_capture_area(&self, capture_area: Option<CaptureArea>) -> Result<(), ()> {
+		self.command_tx.send(SessionCommand::UpdateCaptureArea(capture_area)).await
+			.map_err(|e| tracing::error!("Failed to send UpdateCaptureArea command: {e}"))
+	}
+
+	pub async fn update_audio_bitrate(&self, bitrate: u32) -> Result<(), ()> {
+		self.command_tx.send(SessionCommand::UpdateAudioBitrate(bitrate)).await
+			.map_err(|e| tracing::error!("Failed to send UpdateAudioBitrate command: {e}"))
+	}
+
+	pub async fn request_idr_frame(&self) -> Result<(), ()> {
+		self.command_tx.send(SessionCommand::RequestIdrFrame).await
+			.map_err(|e| tracing::error!("Failed to send RequestIdrFrame command: {e}"))
+	}
 }
 
 impl Drop for Session {
 	fn drop(&mut self) {
+		if let Some(application) = &self.application {
+			application.terminate();
+		}
+
 		if let Some(run_after) = &self.context.application.run_after {
 			for command in run_after {
 				run_command(command, &self.context);
@@ -117,9 +341,16 @@ impl Drop for Session {
 
 struct SessionInner {
 	config: Config,
+	stats: SessionStats,
+	gamepads: GamepadSlots,
+	guests: Guests,
+	transcript: Transcript,
+	events: EventBus,
+	video_tap: VideoTap,
 	video_stream: Option<VideoStream>,
 	audio_stream: Option<AudioStream>,
 	control_stream: Option<ControlStream>,
+	stop_signal: Option<ShutdownManager<()>>,
 }
 
 impl SessionInner {
@@ -127,36 +358,165 @@ impl SessionInner {
 		mut self,
 		mut command_rx: mpsc::Receiver<SessionCommand>,
 		mut session_context: SessionContext,
-		enet: Enet,
-		stop_signal: ShutdownManager<()>,
+		transport: TransportContext,
 	) {
 		while let Some(command) = command_rx.recv().await {
 			match command {
-				SessionCommand::StartStream(video_stream_context, audio_stream_context) => {
-					let video_stream = VideoStream::new(self.config.clone(), video_stream_context, stop_signal.clone());
-					let audio_stream = AudioStream::new(self.config.clone(), audio_stream_context, stop_signal.clone());
+				SessionCommand::StartStream(mut video_stream_context, mut audio_stream_context, stop_signal, listening_tx, started_tx) => {
+					let capabilities = &session_context.capabilities;
+					tracing::debug!("Starting stream for client with {capabilities:?}.");
+					if capabilities.hdr {
+						tracing::warn!("Client asked for an HDR stream, but only SDR is supported.");
+					}
+					if capabilities.audio_channels > 2 {
+						tracing::info!("Client supports {} audio channels, but audio is streamed in stereo.", capabilities.audio_channels);
+					}
+
+					// Applications can run on a specific monitor, in which case only that monitor is streamed.
+					let mut video_config = self.config.clone();
+					if let Some(monitor) = &session_context.application.monitor {
+						video_config.stream.video.capture = Some(CaptureArea::Output(monitor.clone()));
+					}
+
+					// Capture only the gamescope output, which is rendered at the resolution of the client.
+					if session_context.application.gamescope {
+						match gamescope::window_region().await {
+							Ok(region) => video_config.stream.video.capture = Some(CaptureArea::Region(region)),
+							Err(()) => tracing::warn!("Failed to find the gamescope output, streaming the configured capture area instead."),
+						}
+					}
+
+					video_stream_context.ping_payload = session_context.ping_payload.clone();
+					video_stream_context.encryption_keys = capabilities.encrypts_video().then(|| session_context.keys.clone());
+					video_stream_context.h264_decoder = self.config.stream.video.h264_decoder_for(&session_context.client_id).clone();
+					video_stream_context.guests = self.guests.clone();
+					audio_stream_context.ping_payload = session_context.ping_payload.clone();
+					audio_stream_context.host_only = self.config.host.audio_routing_for(capabilities.local_audio) == AudioRouting::Host;
+
+					// Audio and video timestamps are taken from the same clock, so they stay in sync.
+					let clock = SessionClock::new();
+					let video_stream = VideoStream::new(video_config, video_stream_context, self.stats.clone(), clock, self.video_tap.clone(), stop_signal.clone());
+					let audio_stream = AudioStream::new(self.config.clone(), audio_stream_context, self.stats.clone(), clock, stop_signal.clone());
 					let control_stream = match ControlStream::new(
 						self.config.clone(),
 						video_stream.clone(),
 						audio_stream.clone(),
 						session_context.clone(),
-						enet.clone(),
+						self.stats.clone(),
+						self.gamepads.clone(),
+						self.guests.clone(),
+						self.transcript.clone(),
+						self.events.clone(),
+						transport.clone(),
 						stop_signal.clone()
 					) {
 						Ok(control_stream) => control_stream,
 						Err(()) => {
-							tracing::error!("Failed to create control stream, killing session.");
+							// The session manager stops the streams that did start.
+							tracing::error!("Failed to create control stream.");
+							let _ = listening_tx.send(Err(()));
 							continue;
 						},
 					};
 
+					// Waiting for the streams shouldn't hold up other commands, such as stopping the stream.
+					tokio::spawn({
+						let video_stream = video_stream.clone();
+						let audio_stream = audio_stream.clone();
+						async move {
+							// The client starts sending PINGs right after PLAY, so the streams need to listen before it gets a response.
+							let listening = tokio::time::timeout(STREAM_START_TIMEOUT, async {
+								video_stream.wait_listening().await?;
+								audio_stream.wait_listening().await
+							}).await
+								.unwrap_or_else(|_| {
+									tracing::error!("Streams didn't start within {} seconds.", STREAM_START_TIMEOUT.as_secs());
+									Err(())
+								});
+
+							let listened = listening.is_ok();
+							if listening_tx.send(listening).is_err() {
+								tracing::error!("Failed to send StartStream response.");
+							}
+							if !listened {
+								return;
+							}
+
+							// The encoder only starts once the client starts the stream, which can still fail.
+							if let Some(started) = video_stream.wait_started().await {
+								let _ = started_tx.send(started);
+							}
+						}.in_current_span()
+					});
+
 					self.video_stream = Some(video_stream);
 					self.audio_stream = Some(audio_stream);
 					self.control_stream = Some(control_stream);
+					self.stop_signal = Some(stop_signal);
 				},
 
 				SessionCommand::StopStream => {
-					let _ = stop_signal.trigger_shutdown(());
+					if let Some(stop_signal) = &self.stop_signal {
+						let _ = stop_signal.trigger_shutdown(());
+					}
+				},
+
+				SessionCommand::UpdateContext(context) => {
+					tracing::debug!("Updating session context.");
+					session_context = context;
+				},
+
+				SessionCommand::UpdateVideoSettings(settings) => {
+					let Some(video_stream) = &self.video_stream else {
+						tracing::warn!("Can't update video settings without a video stream.");
+						continue;
+					};
+
+					let _ = video_stream.update_settings(settings).await;
+				},
+
+				SessionCommand::UpdateCaptureArea(capture_area) => {
+					let Some(video_stream) = &self.video_stream else {
+						tracing::warn!("Can't update the capture area without a video stream.");
+						continue;
+					};
+
+					let _ = video_stream.update_capture_area(capture_area).await;
+				},
+
+				SessionCommand::UpdateAudioBitrate(bitrate) => {
+					let Some(audio_stream) = &self.audio_stream else {
+						tracing::warn!("Can't update the audio bitrate without an audio stream.");
+						continue;
+					};
+
+					let _ = audio_stream.update_bitrate(bitrate).await;
+				},
+
+				SessionCommand::RequestIdrFrame => {
+					let Some(video_stream) = &self.video_stream else {
+						tracing::debug!("Can't request an IDR frame without a video stream.");
+						continue;
+					};
+
+					let _ = video_stream.request_idr_frame().await;
+				},
+
+				SessionCommand::Terminate(reason, response_tx) => {
+					let result = match &self.control_stream {
+						Some(control_stream) => control_stream.terminate(reason).await,
+						None => {
+							tracing::warn!("Can't terminate the stream without a control stream.");
+							Err(())
+						},
+					};
+					if let Some(stop_signal) = &self.stop_signal {
+						let _ = stop_signal.trigger_shutdown(());
+					}
+
+					if response_tx.send(result).is_err() {
+						tracing::error!("Failed to send Terminate response.");
+					}
 				},
 
 				SessionCommand::UpdateKeys(keys) => {
@@ -171,12 +531,17 @@ impl SessionInner {
 
 					session_context.keys = keys.clone();
 					let _ = audio_stream.update_keys(keys.clone()).await;
+					if let Some(video_stream) = &self.video_stream {
+						let _ = video_stream.update_keys(keys.clone()).await;
+					}
 					let _ = control_stream.update_keys(keys).await;
 				},
 			}
 		}
 
-		let _ = stop_signal.trigger_shutdown(());
+		if let Some(stop_signal) = &self.stop_signal {
+			let _ = stop_signal.trigger_shutdown(());
+		}
 		tracing::debug!("Command channel closed.");
 	}
 }
@@ -187,24 +552,50 @@ fn run_command(command: &[String], context: &SessionContext) {
 		return;
 	}
 
-	let command: Vec<String> = command.to_vec()
-		.iter_mut()
-		.map(|c| {
-			let c = c
-				.replace("{width}", &context.resolution.0.to_string())
-				.replace("{height}", &context.resolution.1.to_string());
-			shellexpand::full(&c).map(|c| c.into()).unwrap_or(c)
-		})
-		.collect();
-
+	let command = prepare_command(command, context);
 	tracing::info!("Running command: {command:?}");
 
 	// Now run the command.
 	let _ = std::process::Command::new(&command[0])
 		.args(&command[1..])
+		.envs(stream_environment(context))
 		.stdout(Stdio::null())
 		.stderr(Stdio::null())
 		.stdin(Stdio::null())
 		.spawn()
 		.map_err(|e| tracing::error!("Failed to run command: {e}"));
 }
+
+/// Replace the environment variables and template values in a command.
+///
+/// Environment variables are expanded first, so that a client name that looks like a variable is left alone.
+fn prepare_command(command: &[String], context: &SessionContext) -> Vec<String> {
+	let parameters = stream_parameters(context);
+	command.iter()
+		.map(|c| {
+			let mut c = shellexpand::full(c).map(|c| c.into_owned()).unwrap_or_else(|_| c.clone());
+			for (name, value) in &parameters {
+				c = c.replace(&format!("{{{name}}}"), value);
+			}
+			c
+		})
+		.collect()
+}
+
+/// Parameters of the stream that commands can use, as `{name}` templates and `MOONSHINE_<NAME>` environment variables.
+fn stream_parameters(context: &SessionContext) -> [(&'static str, String); 5] {
+	[
+		("width", context.resolution.0.to_string()),
+		("height", context.resolution.1.to_string()),
+		("fps", context.refresh_rate.to_string()),
+		("client_name", context.client_name.clone().unwrap_or_else(|| context.client_id.to_string())),
+		("hdr", context.capabilities.hdr.to_string()),
+	]
+}
+
+/// Environment variables with the parameters of the stream, for the commands of an application.
+fn stream_environment(context: &SessionContext) -> Vec<(String, String)> {
+	stream_parameters(context).into_iter()
+		.map(|(name, value)| (format!("MOONSHINE_{}", name.to_uppercase()), value))
+		.collect()
+}
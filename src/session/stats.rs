@@ -0,0 +1,236 @@
+use std::{collections::VecDeque, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+
+use serde::Serialize;
+
+use super::{ClientId, SessionId};
+
+/// Number of samples that are kept in a latency histogram.
+const LATENCY_WINDOW: usize = 1000;
+
+/// Upper bounds of the buckets of a latency histogram, in microseconds.
+const LATENCY_BUCKETS: [u64; 8] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000];
+
+/// Keeps track of the most recent latency measurements.
+#[derive(Default)]
+pub struct LatencyHistogram {
+	samples: VecDeque<Duration>,
+}
+
+impl LatencyHistogram {
+	pub fn record(&mut self, latency: Duration) {
+		if self.samples.len() == LATENCY_WINDOW {
+			self.samples.pop_front();
+		}
+		self.samples.push_back(latency);
+	}
+
+	pub fn summary(&self) -> LatencySummary {
+		let mut samples: Vec<u64> = self.samples.iter().map(|s| s.as_micros() as u64).collect();
+		samples.sort_unstable();
+
+		let percentile = |p: usize| {
+			if samples.is_empty() {
+				0
+			} else {
+				samples[(samples.len() - 1) * p / 100]
+			}
+		};
+
+		let mut buckets: Vec<LatencyBucket> = LATENCY_BUCKETS.iter()
+			.map(|&upper_bound| LatencyBucket { upper_bound: Some(upper_bound), count: 0 })
+			.collect();
+		buckets.push(LatencyBucket { upper_bound: None, count: 0 });
+		for &sample in &samples {
+			let index = LATENCY_BUCKETS.iter().position(|&upper_bound| sample <= upper_bound).unwrap_or(LATENCY_BUCKETS.len());
+			buckets[index].count += 1;
+		}
+
+		LatencySummary {
+			samples: samples.len(),
+			min: samples.first().copied().unwrap_or(0),
+			mean: if samples.is_empty() { 0 } else { samples.iter().sum::<u64>() / samples.len() as u64 },
+			p50: percentile(50),
+			p95: percentile(95),
+			p99: percentile(99),
+			max: samples.last().copied().unwrap_or(0),
+			buckets,
+		}
+	}
+}
+
+/// Summary of a latency histogram, all values are in microseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct LatencySummary {
+	pub samples: usize,
+	pub min: u64,
+	pub mean: u64,
+	pub p50: u64,
+	pub p95: u64,
+	pub p99: u64,
+	pub max: u64,
+	pub buckets: Vec<LatencyBucket>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LatencyBucket {
+	/// Inclusive upper bound of this bucket in microseconds, or None for the last bucket.
+	pub upper_bound: Option<u64>,
+
+	/// Number of samples in this bucket.
+	pub count: usize,
+}
+
+/// Statistics of a session, shared between the streams that produce them and the webserver that reports them.
+#[derive(Clone)]
+pub struct SessionStats {
+	session_id: SessionId,
+	client_id: ClientId,
+
+	inner: Arc<Mutex<SessionStatsInner>>,
+
+	/// Number of bytes sent to the client in audio and video packets, counted separately since it is updated for every packet.
+	bytes_sent: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct SessionStatsInner {
+	/// Time between receiving an input event and writing it to the virtual input device.
+	input_latency: LatencyHistogram,
+
+	/// Time the encoder waited for the GPU to finish writing a captured frame.
+	fence_wait: LatencyHistogram,
+
+	/// Time between sending a frame to the encoder and receiving the encoded frame.
+	encode_latency: LatencyHistogram,
+
+	/// Number of video frames that were encoded.
+	frames_encoded: u64,
+
+	/// Number of captured video frames that were dropped, because the encoder fell behind.
+	frames_dropped: u64,
+
+	/// Number of packets the client reported as lost.
+	packets_lost: u64,
+
+	/// Number of audio packets that were sent as silence, because the capture didn't deliver audio in time.
+	audio_underruns: u64,
+
+	/// Number of captured audio fragments that were dropped, because the encoder fell behind.
+	audio_overruns: u64,
+
+	/// Name of the codec that is used to encode video.
+	video_encoder: Option<String>,
+
+	/// Parameter sets of the video stream as SDP format parameters, once the encoder produced them.
+	video_parameter_sets: Option<String>,
+}
+
+impl SessionStats {
+	pub fn new(session_id: SessionId, client_id: ClientId) -> Self {
+		Self { session_id, client_id, inner: Default::default(), bytes_sent: Default::default() }
+	}
+
+	pub fn record_input_latency(&self, latency: Duration) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.input_latency.record(latency);
+		}
+	}
+
+	pub fn record_fence_wait(&self, duration: Duration) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.fence_wait.record(duration);
+		}
+	}
+
+	pub fn record_encoded_frame(&self, latency: Duration) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.frames_encoded += 1;
+			inner.encode_latency.record(latency);
+		}
+	}
+
+	pub fn record_dropped_frame(&self) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.frames_dropped += 1;
+		}
+	}
+
+	pub fn record_packets_lost(&self, packets: u32) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.packets_lost += packets as u64;
+		}
+	}
+
+	pub fn record_audio_underrun(&self) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.audio_underruns += 1;
+		}
+	}
+
+	pub fn record_audio_overruns(&self, fragments: u64) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.audio_overruns += fragments;
+		}
+	}
+
+	pub fn record_bytes_sent(&self, bytes: usize) {
+		self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+	}
+
+	pub fn bytes_sent(&self) -> u64 {
+		self.bytes_sent.load(Ordering::Relaxed)
+	}
+
+	pub fn set_video_encoder(&self, codec_name: String) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.video_encoder = Some(codec_name);
+		}
+	}
+
+	pub fn set_video_parameter_sets(&self, parameter_sets: String) {
+		if let Ok(mut inner) = self.inner.lock() {
+			inner.video_parameter_sets = Some(parameter_sets);
+		}
+	}
+
+	pub fn video_parameter_sets(&self) -> Option<String> {
+		self.inner.lock().ok()?.video_parameter_sets.clone()
+	}
+
+	pub fn snapshot(&self) -> Result<SessionStatsSnapshot, ()> {
+		let inner = self.inner.lock()
+			.map_err(|e| tracing::error!("Failed to lock session stats: {e}"))?;
+
+		Ok(SessionStatsSnapshot {
+			session_id: self.session_id,
+			client_id: self.client_id.clone(),
+			input_latency: inner.input_latency.summary(),
+			fence_wait: inner.fence_wait.summary(),
+			encode_latency: inner.encode_latency.summary(),
+			frames_encoded: inner.frames_encoded,
+			frames_dropped: inner.frames_dropped,
+			packets_lost: inner.packets_lost,
+			audio_underruns: inner.audio_underruns,
+			audio_overruns: inner.audio_overruns,
+			video_encoder: inner.video_encoder.clone(),
+			bytes_sent: self.bytes_sent(),
+		})
+	}
+}
+
+/// Statistics of a session at a moment in time.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionStatsSnapshot {
+	pub session_id: SessionId,
+	pub client_id: ClientId,
+	pub input_latency: LatencySummary,
+	pub fence_wait: LatencySummary,
+	pub encode_latency: LatencySummary,
+	pub frames_encoded: u64,
+	pub frames_dropped: u64,
+	pub packets_lost: u64,
+	pub audio_underruns: u64,
+	pub audio_overruns: u64,
+	pub video_encoder: Option<String>,
+	pub bytes_sent: u64,
+}
@@ -0,0 +1,118 @@
+use std::{collections::VecDeque, time::Duration};
+
+use crate::session::SessionClock;
+
+/// Time after the first fragment before the offset between the audio device and the session clock is measured.
+///
+/// The first fragments often arrive in a burst, which would skew the measurement.
+const WARMUP: Duration = Duration::from_secs(2);
+
+/// Drift (in milliseconds) after which the audio is resampled to catch up with the session clock.
+const TOLERANCE_MS: u64 = 5;
+
+/// Compensates for an audio device whose clock runs slightly faster or slower than the session clock.
+///
+/// Over long sessions, even a small difference in clock rate makes the audio drift away from the video.
+/// When the drift exceeds a tolerance, every fragment is resampled by one frame until the drift is gone.
+pub struct DriftCorrector {
+	clock: SessionClock,
+	sample_rate: u32,
+	channels: usize,
+
+	/// Number of frames in every fragment that is returned.
+	fragment_frames: Option<usize>,
+
+	/// Session time when the first fragment was received.
+	start: Option<Duration>,
+
+	/// Offset in frames between the audio device and the session clock after the warmup.
+	baseline: Option<i64>,
+
+	/// Number of frames received from the audio device.
+	received_frames: u64,
+
+	/// Number of frames that were added (positive) or removed (negative) by resampling.
+	corrected_frames: i64,
+
+	/// Interleaved samples that are waiting to be returned as a fragment.
+	buffer: VecDeque<i16>,
+}
+
+impl DriftCorrector {
+	pub fn new(clock: SessionClock, sample_rate: u32, channels: u8) -> Self {
+		Self {
+			clock,
+			sample_rate,
+			channels: channels.max(1) as usize,
+			fragment_frames: None,
+			start: None,
+			baseline: None,
+			received_frames: 0,
+			corrected_frames: 0,
+			buffer: VecDeque::new(),
+		}
+	}
+
+	/// Add a fragment of interleaved samples from the audio device.
+	pub fn push(&mut self, fragment: Vec<i16>) {
+		let frames = fragment.len() / self.channels;
+		self.fragment_frames.get_or_insert(frames);
+		let now = self.clock.elapsed();
+		let start = *self.start.get_or_insert(now);
+		self.received_frames += frames as u64;
+
+		// Frames that the audio device delivered more (positive) or less (negative) than the session clock expects.
+		let expected_frames = (now - start).as_micros() as u64 * self.sample_rate as u64 / 1_000_000;
+		let offset = self.received_frames as i64 - expected_frames as i64;
+		if self.baseline.is_none() && now - start >= WARMUP {
+			self.baseline = Some(offset);
+		}
+
+		let drift = match self.baseline {
+			Some(baseline) => offset - baseline + self.corrected_frames,
+			None => 0,
+		};
+		let tolerance = (self.sample_rate as u64 * TOLERANCE_MS / 1000) as i64;
+		if drift.abs() <= tolerance || frames < 2 {
+			self.buffer.extend(fragment);
+			return;
+		}
+
+		// The audio device is ahead of the session clock, remove a frame. Otherwise add one.
+		let output_frames = if drift > 0 { frames - 1 } else { frames + 1 };
+		tracing::trace!("Audio drifted {drift} frames from the session clock, resampling {frames} frames to {output_frames} frames.");
+		self.corrected_frames += output_frames as i64 - frames as i64;
+		self.buffer.extend(resample(&fragment, self.channels, output_frames));
+	}
+
+	/// Take the next fragment, with the same number of frames as the fragments from the audio device.
+	pub fn pop(&mut self) -> Option<Vec<i16>> {
+		let fragment_samples = self.fragment_frames? * self.channels;
+		if self.buffer.len() < fragment_samples {
+			return None;
+		}
+
+		Some(self.buffer.drain(..fragment_samples).collect())
+	}
+}
+
+/// Linearly resample interleaved samples to the given number of frames.
+fn resample(samples: &[i16], channels: usize, output_frames: usize) -> Vec<i16> {
+	let input_frames = samples.len() / channels;
+	let step = (input_frames - 1) as f64 / (output_frames - 1).max(1) as f64;
+
+	let mut output = Vec::with_capacity(output_frames * channels);
+	for frame in 0..output_frames {
+		let position = frame as f64 * step;
+		let index = (position as usize).min(input_frames - 1);
+		let next = (index + 1).min(input_frames - 1);
+		let fraction = position - index as f64;
+		for channel in 0..channels {
+			let current = samples[index * channels + channel] as f64;
+			let next = samples[next * channels + channel] as f64;
+			output.push((current + (next - current) * fraction).round() as i16);
+		}
+	}
+
+	output
+}
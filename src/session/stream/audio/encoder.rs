@@ -1,8 +1,17 @@
-use openssl::cipher::Cipher;
+use std::time::{Duration, Instant};
+
 use reed_solomon_erasure::{galois_8, ReedSolomon};
 use tokio::sync::mpsc;
 
-use crate::{crypto::encrypt, session::{stream::RtpHeader, SessionKeys}};
+use crate::{config::AudioApplication, crypto, session::{stream::RtpHeader, SessionClock, SessionKeys}};
+
+use super::{drift::DriftCorrector, ring::AudioConsumer};
+
+/// Interval at which the encoder checks for the first captured fragment.
+const FIRST_FRAGMENT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Number of packets the encoder may fall behind before it stops catching up and restarts its schedule.
+const MAX_LATE_PACKETS: u32 = 4;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -16,6 +25,7 @@ struct AudioFecHeader {
 
 enum AudioEncoderCommand {
 	UpdateKeys(SessionKeys),
+	UpdateBitrate(u32),
 }
 
 pub struct AudioEncoder {
@@ -23,20 +33,26 @@ pub struct AudioEncoder {
 }
 
 impl AudioEncoder {
+	#[allow(clippy::too_many_arguments)]
 	pub fn new(
 		sample_rate: u32,
 		channels: u8,
-		audio_rx: mpsc::Receiver<Vec<i16>>,
+		bitrate: u32,
+		application: AudioApplication,
+		audio: AudioConsumer,
 		keys: SessionKeys,
-		packet_tx: mpsc::Sender<Vec<u8>>
+		packet_tx: mpsc::Sender<Vec<u8>>,
+		clock: SessionClock,
 	) -> Result<Self, ()> {
-		tracing::debug!("Creating audio encoder with sample rate {} and {} channels.", sample_rate, channels);
+		tracing::debug!("Creating audio encoder with sample rate {} and {} channels at {} kbps.", sample_rate, channels, bitrate / 1000);
 		let mut encoder = opus::Encoder::new(
 			sample_rate,
 			if channels > 1 { opus::Channels::Stereo } else { opus::Channels::Mono },
-			opus::Application::LowDelay,
+			application.into(),
 		)
 			.map_err(|e| tracing::error!("Failed to create audio encoder: {e}"))?;
+		encoder.set_bitrate(opus::Bitrate::Bits(bitrate as i32))
+			.map_err(|e| tracing::error!("Failed to set audio bitrate: {e}"))?;
 
 		// Moonlight expects a constant bitrate.
 		encoder.set_vbr(false)
@@ -44,8 +60,9 @@ impl AudioEncoder {
 
 		let (command_tx, command_rx) = mpsc::channel(10);
 		let inner = AudioEncoderInner { };
+		let span = tracing::Span::current();
 		std::thread::Builder::new().name("audio-encode".to_string()).spawn(move || {
-			inner.run(command_rx, audio_rx, encoder, keys, packet_tx)
+			span.in_scope(|| inner.run(command_rx, audio, encoder, keys, packet_tx, clock, sample_rate, channels))
 		})
 			.map_err(|e| tracing::error!("Failed to start audio encode thread: {e}"))?;
 
@@ -56,22 +73,31 @@ impl AudioEncoder {
 		self.command_tx.send(AudioEncoderCommand::UpdateKeys(keys)).await
 			.map_err(|e| tracing::error!("Failed to send UpdateKeys command: {e}"))
 	}
+
+	pub async fn update_bitrate(&self, bitrate: u32) -> Result<(), ()> {
+		self.command_tx.send(AudioEncoderCommand::UpdateBitrate(bitrate)).await
+			.map_err(|e| tracing::error!("Failed to send UpdateBitrate command: {e}"))
+	}
 }
 
 struct AudioEncoderInner {
 }
 
 impl AudioEncoderInner {
+	#[allow(clippy::too_many_arguments)]
 	fn run(
 		self,
 		mut command_rx: mpsc::Receiver<AudioEncoderCommand>,
-		mut audio_rx: mpsc::Receiver<Vec<i16>>,
+		mut audio: AudioConsumer,
 		mut encoder: opus::Encoder,
 		mut keys: SessionKeys,
 		packet_tx: mpsc::Sender<Vec<u8>>,
+		clock: SessionClock,
+		sample_rate: u32,
+		channels: u8,
 	) -> Result<(), ()> {
 		let mut sequence_number = 0u16;
-		let stream_start_time = std::time::Instant::now();
+		let mut drift_corrector = DriftCorrector::new(clock, sample_rate, channels);
 
 		const NR_DATA_SHARDS: usize = 4;
 		const NR_PARITY_SHARDS: usize = 2;
@@ -100,6 +126,11 @@ impl AudioEncoderInner {
 		// TODO: Decide the correct size for this buffer.
 		let mut encoded_audio = vec![0u8; 1024];
 
+		// Once the first fragment arrived, a packet is encoded every packet duration, with silence if no audio is captured.
+		let mut next_packet: Option<Instant> = None;
+		let mut packet_duration = Duration::ZERO;
+		let mut silence = Vec::new();
+
 		loop {
 			// Check if there's a command.
 			match command_rx.try_recv() {
@@ -108,7 +139,13 @@ impl AudioEncoderInner {
 						AudioEncoderCommand::UpdateKeys(new_keys) => {
 							tracing::debug!("Updating session keys.");
 							keys = new_keys;
-						}
+						},
+						AudioEncoderCommand::UpdateBitrate(bitrate) => {
+							tracing::info!("Changing audio bitrate to {} kbps.", bitrate / 1000);
+							if let Err(e) = encoder.set_bitrate(opus::Bitrate::Bits(bitrate as i32)) {
+								tracing::warn!("Failed to change audio bitrate: {e}");
+							}
+						},
 					}
 				},
 				Err(mpsc::error::TryRecvError::Disconnected) => {
@@ -118,13 +155,62 @@ impl AudioEncoderInner {
 				Err(mpsc::error::TryRecvError::Empty) => { },
 			};
 
-			let audio_fragment = audio_rx.blocking_recv();
-			let Some(audio_fragment) = audio_fragment else {
-				tracing::debug!("Audio fragment channel closed.");
-				break;
+			// The first fragment tells the packet duration, nothing is sent before it arrives.
+			let Some(due) = next_packet else {
+				match audio.pop() {
+					Ok(Some(fragment)) => {
+						let frames = fragment.len() / channels.max(1) as usize;
+						packet_duration = Duration::from_micros(frames as u64 * 1_000_000 / sample_rate as u64);
+						silence = vec![0i16; fragment.len()];
+
+						// Halfway between two fragments, so fragments that arrive a little early or late are still in time.
+						next_packet = Some(Instant::now() + packet_duration / 2);
+						drift_corrector.push(fragment);
+					},
+					Ok(None) => std::thread::sleep(FIRST_FRAGMENT_POLL_INTERVAL),
+					Err(()) => {
+						tracing::debug!("Audio capture stopped before it delivered audio.");
+						break;
+					},
+				}
+				continue;
+			};
+
+			let now = Instant::now();
+			if now < due {
+				std::thread::sleep(due - now);
+			}
+
+			// After a stall the schedule restarts, instead of sending a burst of late packets.
+			let now = Instant::now();
+			next_packet = Some(if now > due + packet_duration * MAX_LATE_PACKETS { now + packet_duration } else { due + packet_duration });
+
+			// Fragments pass through the drift corrector, which keeps the audio in sync with the session clock.
+			let mut capture_stopped = false;
+			loop {
+				match audio.pop() {
+					Ok(Some(fragment)) => drift_corrector.push(fragment),
+					Ok(None) => break,
+					Err(()) => {
+						capture_stopped = true;
+						break;
+					},
+				}
+			}
+			let audio_fragment = match drift_corrector.pop() {
+				Some(audio_fragment) => audio_fragment,
+				None if capture_stopped => {
+					tracing::debug!("Audio capture stopped.");
+					break;
+				},
+				None => {
+					tracing::trace!("No audio captured in time, sending silence.");
+					audio.record_underrun();
+					silence.clone()
+				},
 			};
 
-			let timestamp = ((std::time::Instant::now() - stream_start_time).as_micros() / (1000 / 90)) as u32;
+			let timestamp = clock.rtp_timestamp();
 			let encoded_size = match encoder.encode(&audio_fragment, &mut encoded_audio) {
 				Ok(encoded_size) => encoded_size,
 				Err(e) => {
@@ -136,10 +222,8 @@ impl AudioEncoderInner {
 
 			// Encrypt the audio data.
 			// TODO: Check if we should, some clients (ie. Steam Link) don't support this.
-			let iv = keys.remote_input_key_id as u32 + sequence_number as u32;
-			let mut iv = iv.to_be_bytes().to_vec();
-			iv.extend([0u8; 12]);
-			let payload = match encrypt(Cipher::aes_128_cbc(), &encoded_audio[..encoded_size], Some(&keys.remote_input_key), Some(&iv), true) {
+			let iv = keys.audio_initialization_vector(sequence_number);
+			let payload = match crypto::encrypt_cbc(keys.key(), &iv, &encoded_audio[..encoded_size]) {
 				Ok(payload) => payload,
 				Err(e) => {
 					tracing::error!("Failed to encrypt audio: {e}");
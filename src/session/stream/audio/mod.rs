@@ -1,27 +1,45 @@
 use async_shutdown::ShutdownManager;
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::{net::UdpSocket, sync::{mpsc, watch}};
+use tracing::Instrument;
 
-use crate::{config::Config, session::SessionKeys};
+use crate::{config::{Config, QosConfig}, session::{stats::SessionStats, SessionClock, SessionKeys}, supervisor::Supervisor};
 
-use self::{capture::AudioCapture, encoder::AudioEncoder};
+use self::{capture::AudioCapture, encoder::AudioEncoder, ring::audio_ring};
+use super::{ping::PingTracker, socket::{SocketEvent, StreamSocket}};
 
 mod capture;
+mod drift;
 mod encoder;
+mod ring;
 
 #[derive(Clone, Default)]
 pub struct AudioStreamContext {
+	/// Duration of a single audio packet in milliseconds.
 	pub packet_duration: u32,
+
+	/// Bitrate of the encoded audio in bits per second.
+	pub bitrate: u32,
+
 	pub qos: bool,
+
+	/// Payload the client sends in its PING messages, to authenticate them.
+	pub ping_payload: String,
+
+	/// Audio only plays on the host, so nothing is captured or streamed.
+	pub host_only: bool,
 }
 
 enum AudioStreamCommand {
 	Start(SessionKeys),
 	UpdateKeys(SessionKeys),
+	UpdateBitrate(u32),
+	Pause,
 }
 
 #[derive(Clone)]
 pub struct AudioStream {
 	command_tx: mpsc::Sender<AudioStreamCommand>,
+	listening_rx: watch::Receiver<bool>,
 }
 
 struct AudioStreamInner {
@@ -35,18 +53,33 @@ impl AudioStream {
 	pub fn new(
 		config: Config,
 		context: AudioStreamContext,
+		stats: SessionStats,
+		clock: SessionClock,
 		stop_signal: ShutdownManager<()>,
 	) -> Self {
 		let (command_tx, command_rx) = mpsc::channel(10);
+		let (listening_tx, listening_rx) = watch::channel(false);
 		let inner = AudioStreamInner { capture: None, encoder: None };
-		tokio::spawn(stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
+
+		// Not cancelled on shutdown, the stream stops by itself so it can tear down the encoder and socket.
+		Supervisor::new(stop_signal.clone()).spawn_critical("audio stream", (), inner.run(
 			config,
 			context,
+			stats,
+			clock,
 			command_rx,
+			listening_tx,
 			stop_signal.clone(),
-		))));
+		));
+
+		AudioStream { command_tx, listening_rx }
+	}
 
-		AudioStream { command_tx }
+	/// Wait until the stream listens for the client, fails if the stream stopped before that.
+	pub async fn wait_listening(&self) -> Result<(), ()> {
+		self.listening_rx.clone().wait_for(|listening| *listening).await
+			.map(|_| ())
+			.map_err(|_| tracing::error!("Audio stream stopped before it was listening."))
 	}
 
 	pub async fn start(&self, keys: SessionKeys) -> Result<(), ()> {
@@ -58,23 +91,37 @@ impl AudioStream {
 		self.command_tx.send(AudioStreamCommand::UpdateKeys(keys)).await
 			.map_err(|e| tracing::error!("Failed to send UpdateKeys command: {e}"))
 	}
+
+	/// Change the bitrate of the encoded audio, in bits per second.
+	pub async fn update_bitrate(&self, bitrate: u32) -> Result<(), ()> {
+		self.command_tx.send(AudioStreamCommand::UpdateBitrate(bitrate)).await
+			.map_err(|e| tracing::error!("Failed to send UpdateBitrate command: {e}"))
+	}
+
+	/// Stop capturing and encoding audio, `start` continues the stream.
+	pub async fn pause(&self) -> Result<(), ()> {
+		self.command_tx.send(AudioStreamCommand::Pause).await
+			.map_err(|e| tracing::error!("Failed to send Pause command: {e}"))
+	}
 }
 
 impl AudioStreamInner {
 	async fn run(
 		mut self,
 		config: Config,
-		audio_stream_context: AudioStreamContext,
+		mut audio_stream_context: AudioStreamContext,
+		stats: SessionStats,
+		clock: SessionClock,
 		mut command_rx: mpsc::Receiver<AudioStreamCommand>,
-		_stop_signal: ShutdownManager<()>,
+		listening_tx: watch::Sender<bool>,
+		stop_signal: ShutdownManager<()>,
 	) -> Result<(), ()> {
 		let socket = UdpSocket::bind((config.address, config.stream.audio.port)).await
 			.map_err(|e| tracing::error!("Failed to bind to UDP socket: {e}"))?;
 
-		if audio_stream_context.qos {
-			// TODO: Check this value 224, what does it mean exactly?
-			tracing::debug!("Enabling QoS on audio socket.");
-			socket.set_tos(224)
+		if audio_stream_context.qos && config.network.qos.audio != 0 {
+			tracing::debug!("Marking audio packets with DSCP {}.", config.network.qos.audio);
+			socket.set_tos(QosConfig::tos(config.network.qos.audio))
 				.map_err(|e| tracing::error!("Failed to set QoS on the audio socket: {e}"))?;
 		}
 
@@ -83,57 +130,69 @@ impl AudioStreamInner {
 			socket.local_addr()
 			.map_err(|e| tracing::error!("Failed to get local address associated with control socket: {e}"))?
 		);
+		listening_tx.send_replace(true);
 
-		let (packet_tx, mut packet_rx) = mpsc::channel::<Vec<u8>>(10);
+		// The socket is owned by this task, which ends when the session stops so the port is released straight away.
+		let socket = std::sync::Arc::new(socket);
+		#[cfg(feature = "netsim")]
+		let mut network_simulator = config.stream.network_simulation.clone()
+			.map(|simulation| super::netsim::NetworkSimulator::new("audio", simulation, socket.clone()));
+		let (packet_tx, packet_rx) = mpsc::channel::<Vec<u8>>(10);
+		let ping_tracker = PingTracker::new("audio", &audio_stream_context.ping_payload);
+		let mut stream_socket = StreamSocket::new(socket.clone(), packet_rx, ping_tracker, stop_signal.clone());
+		let socket_stats = stats.clone();
 		tokio::spawn(async move {
-			let mut buf = [0; 1024];
-			let mut client_address = None;
-
-			loop {
-				tokio::select! {
-					packet = packet_rx.recv() => {
-						match packet {
-							Some(packet) => {
-								if let Some(client_address) = client_address {
-									if let Err(e) = socket.send_to(packet.as_slice(), client_address).await {
-										tracing::warn!("Failed to send packet to client: {e}");
-									}
-								}
-							},
-							None => {
-								tracing::debug!("Packet channel closed.");
-								break;
-							},
-						}
-					},
+			while let Some(event) = stream_socket.next().await {
+				let SocketEvent::Send(packet, client_address) = event else {
+					continue;
+				};
 
-					message = socket.recv_from(&mut buf) => {
-						let (len, address) = match message {
-							Ok((len, address)) => (len, address),
-							Err(e) => {
-								tracing::warn!("Failed to receive message: {e}");
-								break;
-							},
-						};
-
-						if &buf[..len] == b"PING" {
-							tracing::trace!("Received video stream PING message from {address}.");
-							client_address = Some(address);
-						} else {
-							tracing::warn!("Received unknown message on video stream of length {len}.");
-						}
-					},
+				#[cfg(feature = "netsim")]
+				if let Some(network_simulator) = network_simulator.as_mut() {
+					socket_stats.record_bytes_sent(packet.len());
+					network_simulator.send_to(packet, client_address);
+					continue;
+				}
+
+				match socket.send_to(packet.as_slice(), client_address).await {
+					Ok(bytes) => socket_stats.record_bytes_sent(bytes),
+					Err(e) => tracing::warn!("Failed to send packet to client: {e}"),
 				}
 			}
-		});
+		}.in_current_span());
+
+		// Nothing is captured or encoded until the client sends StartB, which makes the control stream send Start.
+		loop {
+			let command = tokio::select! {
+				_ = stop_signal.wait_shutdown_triggered() => {
+					tracing::debug!("Session stopped.");
+					break;
+				},
+				command = command_rx.recv() => match command {
+					Some(command) => command,
+					None => {
+						tracing::debug!("Command channel closed.");
+						break;
+					},
+				},
+			};
 
-		while let Some(command) = command_rx.recv().await {
 			match command {
 				AudioStreamCommand::Start(keys) => {
+					if self.encoder.is_some() {
+						tracing::warn!("Can't start streaming twice.");
+						continue;
+					}
+
+					if audio_stream_context.host_only {
+						tracing::info!("Audio is routed to the host only, not streaming audio.");
+						continue;
+					}
+
 					tracing::info!("Starting audio stream.");
 
-					let (audio_tx, audio_rx) = mpsc::channel(10);
-					let capture = match AudioCapture::new(audio_tx).await {
+					let (producer, consumer) = audio_ring(audio_stream_context.packet_duration, stats.clone());
+					let capture = match AudioCapture::new(producer, audio_stream_context.packet_duration).await {
 						Ok(capture) => capture,
 						Err(()) => continue,
 					};
@@ -141,9 +200,12 @@ impl AudioStreamInner {
 					let encoder = match AudioEncoder::new(
 						capture.sample_rate(),
 						capture.channels(),
-						audio_rx,
+						audio_stream_context.bitrate,
+						config.stream.audio.application,
+						consumer,
 						keys.clone(),
-						packet_tx.clone()
+						packet_tx.clone(),
+						clock,
 					) {
 						Ok(encoder) => encoder,
 						Err(()) => continue,
@@ -161,10 +223,34 @@ impl AudioStreamInner {
 
 					let _ = encoder.update_keys(keys).await;
 				},
+
+				AudioStreamCommand::UpdateBitrate(bitrate) => {
+					// Also use the new bitrate if the encoder is created later.
+					audio_stream_context.bitrate = bitrate;
+
+					if let Some(encoder) = &self.encoder {
+						let _ = encoder.update_bitrate(bitrate).await;
+					}
+				},
+
+				AudioStreamCommand::Pause => {
+					if self.encoder.is_some() {
+						tracing::info!("Pausing audio stream.");
+					}
+
+					// Dropping the encoder stops its thread, which in turn stops the capture thread.
+					self.encoder = None;
+					self.capture = None;
+				},
 			}
 		}
 
-		tracing::debug!("Command channel closed.");
+		// Dropping the encoder stops its thread, which in turn stops the capture thread.
+		if self.encoder.take().is_some() {
+			tracing::info!("Stopping audio stream.");
+		}
+		self.capture = None;
+
 		Ok(())
 	}
 
@@ -0,0 +1,85 @@
+//! Hands captured audio fragments to the encoder through a lock-free ring buffer.
+//!
+//! The encoder takes a fragment every packet duration. If the capture didn't deliver one in time (an underrun), the
+//! encoder sends silence instead, so the client keeps receiving packets at a steady rate. If fragments pile up because
+//! the encoder fell behind (an overrun), the oldest are dropped, so the audio doesn't lag behind the video.
+
+use crate::session::stats::SessionStats;
+
+/// Audio that may be queued before the oldest fragments are dropped, in milliseconds.
+const MAX_QUEUED_AUDIO_MS: u32 = 60;
+
+/// Create a ring buffer for fragments of `packet_duration` milliseconds.
+pub fn audio_ring(packet_duration: u32, stats: SessionStats) -> (AudioProducer, AudioConsumer) {
+	let max_queued = (MAX_QUEUED_AUDIO_MS / packet_duration.max(1)).max(2) as usize;
+
+	// The consumer drops fragments beyond `max_queued`, the extra room is for fragments that arrive while it is busy.
+	let (producer, consumer) = rtrb::RingBuffer::new(max_queued * 2);
+
+	(
+		AudioProducer { producer, stats: stats.clone() },
+		AudioConsumer { consumer, max_queued, stats },
+	)
+}
+
+/// The end of the ring buffer that the capture thread pushes fragments into.
+pub struct AudioProducer {
+	producer: rtrb::Producer<Vec<i16>>,
+	stats: SessionStats,
+}
+
+impl AudioProducer {
+	/// Queue a captured fragment, returns an error once the encoder is gone.
+	pub fn push(&mut self, fragment: Vec<i16>) -> Result<(), ()> {
+		if self.producer.is_abandoned() {
+			return Err(());
+		}
+
+		// Normally the encoder drops the oldest fragments long before the ring is full, if it's full anyway the encoder
+		// is stuck and the newest fragment is dropped instead.
+		if self.producer.push(fragment).is_err() {
+			tracing::debug!("Audio ring buffer is full, dropping captured fragment.");
+			self.stats.record_audio_overruns(1);
+		}
+
+		Ok(())
+	}
+}
+
+/// The end of the ring buffer that the encoder takes fragments from.
+pub struct AudioConsumer {
+	consumer: rtrb::Consumer<Vec<i16>>,
+
+	/// Number of fragments that may be queued before the oldest are dropped.
+	max_queued: usize,
+
+	stats: SessionStats,
+}
+
+impl AudioConsumer {
+	/// Take the oldest queued fragment, after dropping the fragments that exceed the maximum queue length.
+	///
+	/// Returns none if no fragment is queued, or an error once the capture stopped and all fragments are taken.
+	pub fn pop(&mut self) -> Result<Option<Vec<i16>>, ()> {
+		let queued = self.consumer.slots();
+		if queued > self.max_queued {
+			let excess = queued - self.max_queued;
+			tracing::debug!("Audio encoder fell behind, dropping {excess} captured fragments.");
+			for _ in 0..excess {
+				let _ = self.consumer.pop();
+			}
+			self.stats.record_audio_overruns(excess as u64);
+		}
+
+		match self.consumer.pop() {
+			Ok(fragment) => Ok(Some(fragment)),
+			Err(_) if self.consumer.is_abandoned() => Err(()),
+			Err(_) => Ok(None),
+		}
+	}
+
+	/// The capture didn't deliver a fragment in time, so silence is encoded instead.
+	pub fn record_underrun(&self) {
+		self.stats.record_audio_underrun();
+	}
+}
@@ -0,0 +1,51 @@
+//! ENet channels used by the control stream.
+//!
+//! Moonlight sends messages for different purposes on different channels.
+//! ENet only sequences packets within a channel, so a lost packet on one channel
+//! does not delay packets on the other channels (ie. mouse movement is not held back by a lost key press).
+
+/// Messages that don't have a dedicated channel.
+pub const GENERIC: u8 = 0x00;
+
+/// IDR frame requests and reference frame invalidation.
+pub const URGENT: u8 = 0x01;
+
+/// Keyboard input.
+pub const KEYBOARD: u8 = 0x02;
+
+/// Mouse input.
+pub const MOUSE: u8 = 0x03;
+
+/// Pen input.
+pub const PEN: u8 = 0x04;
+
+/// Touch input.
+pub const TOUCH: u8 = 0x05;
+
+/// UTF-8 text input.
+pub const UTF8: u8 = 0x06;
+
+/// Gamepad input and rumble, there is one channel per gamepad.
+pub const GAMEPAD_BASE: u8 = 0x10;
+
+/// Gamepad motion sensors, there is one channel per gamepad.
+pub const SENSOR_BASE: u8 = 0x20;
+
+/// Total number of channels.
+pub const COUNT: usize = 0x30;
+
+/// Returns a readable name for a channel, used for logging.
+pub fn name(channel_id: u8) -> &'static str {
+	match channel_id {
+		GENERIC => "generic",
+		URGENT => "urgent",
+		KEYBOARD => "keyboard",
+		MOUSE => "mouse",
+		PEN => "pen",
+		TOUCH => "touch",
+		UTF8 => "utf8",
+		x if (GAMEPAD_BASE..SENSOR_BASE).contains(&x) => "gamepad",
+		x if (SENSOR_BASE as usize..COUNT).contains(&(x as usize)) => "sensor",
+		_ => "unknown",
+	}
+}
@@ -0,0 +1,167 @@
+#[cfg(feature = "uinput")]
+use evdev::{uinput::VirtualDevice, EventType, InputEvent};
+
+use super::{keyboard::Key, mouse::MouseButton};
+#[cfg(feature = "uinput")]
+use super::{keyboard::Keyboard, mouse::Mouse};
+
+/// Injects keyboard and mouse input on the host.
+///
+/// Gamepads are always created through uinput, since the alternatives don't support them.
+pub trait InputBackend: Send {
+	fn key_down(&mut self, key: Key) -> Result<(), ()>;
+	fn key_up(&mut self, key: Key) -> Result<(), ()>;
+
+	fn move_relative(&mut self, x: i32, y: i32) -> Result<(), ()>;
+
+	/// Move the mouse to (`x`, `y`) within a client screen of `width` by `height`.
+	fn move_absolute(&mut self, x: i32, y: i32, width: i32, height: i32) -> Result<(), ()>;
+
+	fn button_down(&mut self, button: MouseButton) -> Result<(), ()>;
+	fn button_up(&mut self, button: MouseButton) -> Result<(), ()>;
+
+	/// Scroll by `amount`, where 120 is one notch of a scroll wheel.
+	fn scroll_vertical(&mut self, amount: i16) -> Result<(), ()>;
+	fn scroll_horizontal(&mut self, amount: i16) -> Result<(), ()>;
+
+	/// Deliver events that were held back, for backends that batch events.
+	fn flush(&mut self) -> Result<(), ()> {
+		Ok(())
+	}
+}
+
+/// Drops all input, for hosts that only stream.
+pub struct DisabledBackend;
+
+impl InputBackend for DisabledBackend {
+	fn key_down(&mut self, _key: Key) -> Result<(), ()> {
+		Ok(())
+	}
+
+	fn key_up(&mut self, _key: Key) -> Result<(), ()> {
+		Ok(())
+	}
+
+	fn move_relative(&mut self, _x: i32, _y: i32) -> Result<(), ()> {
+		Ok(())
+	}
+
+	fn move_absolute(&mut self, _x: i32, _y: i32, _width: i32, _height: i32) -> Result<(), ()> {
+		Ok(())
+	}
+
+	fn button_down(&mut self, _button: MouseButton) -> Result<(), ()> {
+		Ok(())
+	}
+
+	fn button_up(&mut self, _button: MouseButton) -> Result<(), ()> {
+		Ok(())
+	}
+
+	fn scroll_vertical(&mut self, _amount: i16) -> Result<(), ()> {
+		Ok(())
+	}
+
+	fn scroll_horizontal(&mut self, _amount: i16) -> Result<(), ()> {
+		Ok(())
+	}
+}
+
+/// Injects input through virtual uinput devices, which requires write access to `/dev/uinput`.
+#[cfg(feature = "uinput")]
+pub struct UinputBackend {
+	mouse: Mouse,
+	keyboard: Keyboard,
+}
+
+#[cfg(feature = "uinput")]
+impl UinputBackend {
+	/// If `batch` is set, events are held back until `flush` is called.
+	pub fn new(batch: bool) -> Result<Self, ()> {
+		Ok(Self { mouse: Mouse::new(batch)?, keyboard: Keyboard::new(batch)? })
+	}
+}
+
+#[cfg(feature = "uinput")]
+impl InputBackend for UinputBackend {
+	fn key_down(&mut self, key: Key) -> Result<(), ()> {
+		self.keyboard.key_down(key)
+	}
+
+	fn key_up(&mut self, key: Key) -> Result<(), ()> {
+		self.keyboard.key_up(key)
+	}
+
+	fn move_relative(&mut self, x: i32, y: i32) -> Result<(), ()> {
+		self.mouse.move_relative(x, y)
+	}
+
+	fn move_absolute(&mut self, x: i32, y: i32, _width: i32, _height: i32) -> Result<(), ()> {
+		self.mouse.move_absolute(x, y)
+	}
+
+	fn button_down(&mut self, button: MouseButton) -> Result<(), ()> {
+		self.mouse.button_down(button)
+	}
+
+	fn button_up(&mut self, button: MouseButton) -> Result<(), ()> {
+		self.mouse.button_up(button)
+	}
+
+	fn scroll_vertical(&mut self, amount: i16) -> Result<(), ()> {
+		self.mouse.scroll_vertical(amount)
+	}
+
+	fn scroll_horizontal(&mut self, amount: i16) -> Result<(), ()> {
+		self.mouse.scroll_horizontal(amount)
+	}
+
+	fn flush(&mut self) -> Result<(), ()> {
+		let mouse = self.mouse.flush();
+		let keyboard = self.keyboard.flush();
+		mouse.and(keyboard)
+	}
+}
+
+/// Virtual device that either emits events right away, or collects them so they are emitted with a single `SYN_REPORT`.
+#[cfg(feature = "uinput")]
+pub struct BatchedDevice {
+	device: VirtualDevice,
+	batch: bool,
+	pending: Vec<InputEvent>,
+}
+
+#[cfg(feature = "uinput")]
+impl BatchedDevice {
+	pub fn new(device: VirtualDevice, batch: bool) -> Self {
+		Self { device, batch, pending: Vec::new() }
+	}
+
+	pub fn emit(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+		if !self.batch {
+			return self.device.emit(events);
+		}
+
+		// A press and release of the same key in one report can be merged by the compositor, so those are split up.
+		let repeats_key = events.iter().any(|event| {
+			event.event_type() == EventType::KEY
+				&& self.pending.iter().any(|pending| pending.event_type() == EventType::KEY && pending.code() == event.code())
+		});
+		if repeats_key {
+			self.flush()?;
+		}
+
+		self.pending.extend_from_slice(events);
+		Ok(())
+	}
+
+	pub fn flush(&mut self) -> std::io::Result<()> {
+		if self.pending.is_empty() {
+			return Ok(());
+		}
+
+		let result = self.device.emit(&self.pending);
+		self.pending.clear();
+		result
+	}
+}
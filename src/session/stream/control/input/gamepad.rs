@@ -11,7 +11,9 @@ use evdev::{
 	InputId,
 };
 use strum::IntoEnumIterator;
-use strum_macros::{FromRepr, EnumIter};
+use strum_macros::FromRepr;
+
+use super::protocol::{ControllerArrivalPacket, ControllerUpdatePacket, GamepadButton};
 
 #[derive(Debug, FromRepr)]
 #[repr(u8)]
@@ -50,35 +52,6 @@ enum GamepadCapability {
 	_RgbLed = 0x80,
 }
 
-#[derive(Copy, Clone, Debug, EnumIter, PartialEq)]
-#[repr(u32)]
-enum GamepadButton {
-	// Button flags.
-	Up              = 0x00000001,
-	Down            = 0x00000002,
-	Left            = 0x00000004,
-	Right           = 0x00000008,
-	Start           = 0x00000010,
-	Select          = 0x00000020,
-	LeftStickClick  = 0x00000040,
-	RightStickClick = 0x00000080,
-	LB              = 0x00000100,
-	RB              = 0x00000200,
-	Home            = 0x00000400,
-	A               = 0x00001000,
-	B               = 0x00002000,
-	X               = 0x00004000,
-	Y               = 0x00008000,
-
-	// Extended buttons (Sunshine / Moonshine only)
-	Paddle1  = 0x00010000,
-	Paddle2  = 0x00020000,
-	Paddle3  = 0x00040000,
-	Paddle4  = 0x00080000,
-	Touchpad = 0x00100000, // Touchpad buttons on Sony controllers.
-	Misc     = 0x00200000, // Share/Mic/Capture/Mute buttons on various controllers.
-}
-
 impl From<GamepadButton> for Key {
 	fn from(val: GamepadButton) -> Self {
 		match val {
@@ -107,107 +80,28 @@ impl From<GamepadButton> for Key {
 	}
 }
 
-#[derive(Debug)]
-pub struct GamepadInfo {
-	index: u8,
-	// kind: GamepadKind,
-	// capabilities: u16,
-	// supported_buttons: u32,
-}
-
-impl GamepadInfo {
-	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		const EXPECTED_SIZE: usize =
-			std::mem::size_of::<u8>()    // index
-			+ std::mem::size_of::<u8>()  // kind
-			+ std::mem::size_of::<u16>() // capabilities
-			+ std::mem::size_of::<u32>() // supported_buttons
-		;
-
-		if buffer.len() < EXPECTED_SIZE {
-			tracing::warn!("Expected at least {EXPECTED_SIZE} bytes for GamepadInfo, got {} bytes.", buffer.len());
-			return Err(());
-		}
-
-		Ok(Self {
-			index: buffer[0],
-			// kind: GamepadKind::from_repr(buffer[1]).ok_or_else(|| tracing::warn!("Unknown gamepad kind: {}", buffer[1]))?,
-			// capabilities: u16::from_le_bytes(buffer[2..4].try_into().unwrap()),
-			// supported_buttons: u32::from_le_bytes(buffer[4..8].try_into().unwrap()),
-		})
-	}
-
-	// fn has_capability(&self, capability: &GamepadCapability) -> bool {
-	// 	(self.capabilities & *capability as u16) != 0
-	// }
-
-	// fn has_button(&self, button: &GamepadButton) -> bool {
-	// 	(self.supported_buttons & *button as u32) != 0
-	// }
-}
-
-#[derive(Debug)]
-pub struct GamepadUpdate {
-	pub index: u16,
-	_active_gamepad_mask: u16,
-	button_flags: u32,
-	left_trigger: u8,
-	right_trigger: u8,
-	left_stick: (i16, i16),
-	right_stick: (i16, i16),
-}
-
-impl GamepadUpdate {
-	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		const EXPECTED_SIZE: usize =
-			std::mem::size_of::<u16>()   // header
-			+ std::mem::size_of::<u16>() // index
-			+ std::mem::size_of::<u16>() // active gamepad mask
-			+ std::mem::size_of::<u16>() // mid B
-			+ std::mem::size_of::<u16>() // button flags
-			+ std::mem::size_of::<u8>()  // left trigger
-			+ std::mem::size_of::<u8>()  // right trigger
-			+ std::mem::size_of::<i16>() // left stick x
-			+ std::mem::size_of::<i16>() // left stick y
-			+ std::mem::size_of::<i16>() // right stick x
-			+ std::mem::size_of::<i16>() // right stick y
-			+ std::mem::size_of::<i16>() // tail a
-			+ std::mem::size_of::<i16>() // button flags 2
-			+ std::mem::size_of::<i16>() // tail b
-		;
-
-		if buffer.len() < EXPECTED_SIZE {
-			tracing::warn!("Expected at least {EXPECTED_SIZE} bytes for GamepadUpdate, got {} bytes.", buffer.len());
-			return Err(());
-		}
-
-		Ok(Self {
-			index: u16::from_le_bytes(buffer[2..4].try_into().unwrap()),
-			_active_gamepad_mask: u16::from_le_bytes(buffer[4..6].try_into().unwrap()),
-			button_flags: u16::from_le_bytes(buffer[8..10].try_into().unwrap()) as u32 | (u16::from_le_bytes(buffer[22..24].try_into().unwrap()) as u32) << 16,
-			left_trigger: buffer[10],
-			right_trigger: buffer[11],
-			left_stick: (
-				i16::from_le_bytes(buffer[12..14].try_into().unwrap()),
-				i16::from_le_bytes(buffer[14..16].try_into().unwrap()),
-			),
-			right_stick: (
-				i16::from_le_bytes(buffer[16..18].try_into().unwrap()),
-				i16::from_le_bytes(buffer[18..20].try_into().unwrap())
-			),
-		})
-	}
-}
-
 pub struct Gamepad {
-	_info: GamepadInfo,
+	/// Announcement of the gamepad of the client, none for the gamepad that keys are translated to.
+	_arrival: Option<ControllerArrivalPacket>,
 	device: VirtualDevice,
 	button_state: u32,
 }
 
 impl Gamepad {
-	pub fn new(info: GamepadInfo) -> Result<Self, ()> {
-		// Ideally we use info.supported_buttons, but this gives unexpected results.
+	/// Create the gamepad of a client, named after its player number so games and the host show the same player.
+	pub fn new(arrival: ControllerArrivalPacket, player: u8) -> Result<Self, ()> {
+		let device = Self::create_device(&format!("Moonshine Gamepad {player}"))?;
+		Ok(Self { _arrival: Some(arrival), device, button_state: 0 })
+	}
+
+	/// Create the gamepad that keys of the client are translated to by an input profile.
+	pub fn for_keyboard() -> Result<Self, ()> {
+		let device = Self::create_device("Moonshine Keyboard Gamepad")?;
+		Ok(Self { _arrival: None, device, button_state: 0 })
+	}
+
+	fn create_device(name: &str) -> Result<VirtualDevice, ()> {
+		// Ideally we use arrival.supported_buttons, but this gives unexpected results.
 		// For example, the left and right joystick buttons would be mapped to SELECT / START for some reason..
 		let buttons = AttributeSet::from_iter([
 			evdev::Key::BTN_WEST,
@@ -228,7 +122,7 @@ impl Gamepad {
 		let device = VirtualDeviceBuilder::new()
 			.map_err(|e| tracing::error!("Failed to initiate virtual gamepad: {e}"))?
 			.input_id(InputId::new(evdev::BusType::BUS_BLUETOOTH, 0x54C, 0x5C4, 0x8100))
-			.name(format!("Moonshine Gamepad {}", info.index).as_str())
+			.name(name)
 			.with_keys(&buttons)
 			.map_err(|e| tracing::error!("Failed to add keys to virtual gamepad: {e}"))?
 			// Dpad.
@@ -289,14 +183,14 @@ impl Gamepad {
 			.build()
 			.map_err(|e| tracing::error!("Failed to create virtual gamepad: {e}"))?;
 
-		Ok(Self { _info: info, device, button_state: 0 })
+		Ok(device)
 	}
 
 	fn button_changed(&self, button: &GamepadButton, new_state: u32) -> bool {
 		(self.button_state & *button as u32) != (new_state & *button as u32)
 	}
 
-	pub fn update(&mut self, update: GamepadUpdate) -> Result<(), ()> {
+	pub fn update(&mut self, update: ControllerUpdatePacket) -> Result<(), ()> {
 		let mut events = Vec::new();
 
 		// Check all buttons that have changed and emit their update.
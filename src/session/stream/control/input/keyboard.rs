@@ -1,8 +1,13 @@
-use evdev::{uinput::{VirtualDevice, VirtualDeviceBuilder}, AttributeSet};
+#[cfg(feature = "uinput")]
+use evdev::{uinput::VirtualDeviceBuilder, AttributeSet};
+#[cfg(feature = "uinput")]
 use strum::IntoEnumIterator;
+
+#[cfg(feature = "uinput")]
+use super::backend::BatchedDevice;
 use strum_macros::{FromRepr, EnumIter};
 
-#[derive(Debug, Eq, PartialEq, FromRepr, EnumIter)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, FromRepr, EnumIter)]
 #[repr(u8)]
 pub enum Key {
 	Backspace = 0x08,
@@ -135,25 +140,7 @@ pub enum Key {
 	NonUsBackslash = 0xE2,
 }
 
-impl Key {
-	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		const EXPECTED_SIZE: usize =
-			std::mem::size_of::<u8>()    // flags
-			+ std::mem::size_of::<u16>() // key
-			+ std::mem::size_of::<u8>()  // modifiers
-			+ std::mem::size_of::<u16>() // padding
-		;
-
-		if buffer.len() < EXPECTED_SIZE {
-			tracing::warn!("Expected at least {EXPECTED_SIZE} bytes for Key, got {} bytes.", buffer.len());
-			return Err(());
-		}
-
-
-		Key::from_repr(buffer[1]).ok_or_else(|| tracing::warn!("Unknown keycode: {}", buffer[5]))
-	}
-}
-
+#[cfg(any(feature = "uinput", feature = "libei"))]
 impl From<Key> for evdev::Key {
 	fn from(val: Key) -> Self {
 		match val {
@@ -289,12 +276,14 @@ impl From<Key> for evdev::Key {
 	}
 }
 
+#[cfg(feature = "uinput")]
 pub struct Keyboard {
-	device: VirtualDevice,
+	device: BatchedDevice,
 }
 
+#[cfg(feature = "uinput")]
 impl Keyboard {
-	pub fn new() -> Result<Self, ()> {
+	pub fn new(batch: bool) -> Result<Self, ()> {
 		let mut attributes = AttributeSet::new();
 		for key in Key::iter() {
 			attributes.insert(key.into());
@@ -308,7 +297,12 @@ impl Keyboard {
 			.build()
 			.map_err(|e| tracing::error!("Failed to create virtual keyboard: {e}"))?;
 
-		Ok(Self { device })
+		Ok(Self { device: BatchedDevice::new(device, batch) })
+	}
+
+	pub fn flush(&mut self) -> Result<(), ()> {
+		self.device.flush()
+			.map_err(|e| tracing::error!("Failed to emit keyboard events: {e}"))
 	}
 
 	pub fn key_down(&mut self, key: Key) -> Result<(), ()> {
@@ -0,0 +1,259 @@
+//! Input injection through libei, using a connection that the RemoteDesktop portal hands out.
+//!
+//! This works in Wayland sessions without access to `/dev/uinput`, but the compositor asks the user for permission.
+
+use std::{io, os::unix::net::UnixStream, time::Duration};
+
+use ashpd::desktop::{remote_desktop::{DeviceType, RemoteDesktop}, PersistMode, Session};
+use reis::{ei, event::{DeviceCapability, EiEvent, EiEventConverter}, PendingRequestResult};
+
+use super::{backend::InputBackend, keyboard::Key, mouse::MouseButton};
+
+/// Time to wait for the compositor to offer a keyboard and a pointer.
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct LibeiBackend {
+	/// Portal session, the EIS connection is closed when it is dropped.
+	_session: Session<'static, RemoteDesktop<'static>>,
+	_portal: RemoteDesktop<'static>,
+
+	context: ei::Context,
+	devices: Devices,
+}
+
+impl LibeiBackend {
+	pub async fn connect() -> Result<Self, ()> {
+		let portal = RemoteDesktop::new().await
+			.map_err(|e| tracing::error!("Failed to connect to the RemoteDesktop portal: {e}"))?;
+		let session = portal.create_session().await
+			.map_err(|e| tracing::error!("Failed to create RemoteDesktop session: {e}"))?;
+		portal.select_devices(&session, DeviceType::Keyboard | DeviceType::Pointer, None, PersistMode::DoNot).await
+			.map_err(|e| tracing::error!("Failed to select RemoteDesktop devices: {e}"))?;
+		portal.start(&session, None).await
+			.and_then(|request| request.response())
+			.map_err(|e| tracing::error!("Failed to start RemoteDesktop session, was the request denied? {e}"))?;
+		let fd = portal.connect_to_eis(&session).await
+			.map_err(|e| tracing::error!("Failed to connect to EIS: {e}"))?;
+
+		// The handshake and device negotiation block, the socket is made non-blocking afterwards.
+		let (context, devices) = tokio::task::spawn_blocking(move || {
+			let stream = UnixStream::from(fd);
+			stream.set_read_timeout(Some(DEVICE_TIMEOUT))
+				.map_err(|e| tracing::error!("Failed to set EIS socket timeout: {e}"))?;
+			let context = ei::Context::new(stream.try_clone().map_err(|e| tracing::error!("Failed to clone EIS socket: {e}"))?)
+				.map_err(|e| tracing::error!("Failed to create libei context: {e}"))?;
+			let handshake = reis::handshake::ei_handshake_blocking(&context, "moonshine", ei::handshake::ContextType::Sender)
+				.map_err(|e| tracing::error!("Failed libei handshake: {e}"))?;
+
+			let mut devices = Devices {
+				converter: EiEventConverter::new(&context, handshake),
+				resumed: Vec::new(),
+				serial: 0,
+				sequence: 0,
+			};
+			while !devices.has(DeviceCapability::Keyboard) || !devices.has(DeviceCapability::Pointer) {
+				context.read().map_err(|e| tracing::error!("Failed to receive libei devices: {e}"))?;
+				devices.dispatch(&context)?;
+			}
+
+			stream.set_nonblocking(true)
+				.map_err(|e| tracing::error!("Failed to make EIS socket non-blocking: {e}"))?;
+			Ok::<_, ()>((context, devices))
+		}).await.map_err(|e| tracing::error!("Failed to join libei setup: {e}"))??;
+
+		tracing::info!("Injecting input through libei.");
+		Ok(Self { _session: session, _portal: portal, context, devices })
+	}
+
+	/// Handle what the compositor sent since the last event, devices can be paused, resumed or removed at any time.
+	fn dispatch(&mut self) -> Result<(), ()> {
+		match self.context.read() {
+			Ok(_) => {},
+			Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+			Err(e) => {
+				tracing::error!("Failed to read from EIS socket: {e}");
+				return Err(());
+			},
+		}
+
+		self.devices.dispatch(&self.context)
+	}
+
+	/// Send events to the first device with `capability`, followed by a frame event that makes the compositor process them.
+	fn emit(&mut self, capability: DeviceCapability, emit: impl FnOnce(&reis::event::Device)) -> Result<(), ()> {
+		self.dispatch()?;
+
+		let Some(device) = self.devices.resumed.iter().find(|device| device.has_capability(capability)) else {
+			tracing::warn!("No libei device with capability {capability:?}, dropping input event.");
+			return Ok(());
+		};
+
+		emit(device);
+		device.device().frame(self.devices.serial, monotonic_micros());
+		self.context.flush()
+			.map_err(|e| tracing::error!("Failed to send libei events: {e}"))
+	}
+}
+
+/// Devices that the compositor offers, these can be paused, resumed or removed at any time.
+struct Devices {
+	converter: EiEventConverter,
+
+	/// Devices that the compositor currently accepts events for.
+	resumed: Vec<reis::event::Device>,
+
+	/// Serial of the last event received from the compositor, which is sent along with requests that need it.
+	serial: u32,
+
+	/// Sequence number for `start_emulating`.
+	sequence: u32,
+}
+
+impl Devices {
+	fn has(&self, capability: DeviceCapability) -> bool {
+		self.resumed.iter().any(|device| device.has_capability(capability))
+	}
+
+	fn dispatch(&mut self, context: &ei::Context) -> Result<(), ()> {
+		while let Some(result) = context.pending_event() {
+			let request = match result {
+				PendingRequestResult::Request(request) => request,
+				PendingRequestResult::ParseError(e) => {
+					tracing::error!("Failed to parse libei event: {e}");
+					return Err(());
+				},
+				PendingRequestResult::InvalidObject(_) => continue,
+			};
+
+			self.converter.handle_event(request)
+				.map_err(|e| tracing::error!("Failed to handle libei event: {e}"))?;
+		}
+
+		while let Some(event) = self.converter.next_event() {
+			match event {
+				EiEvent::SeatAdded(event) => {
+					event.seat.bind_capabilities(&[
+						DeviceCapability::Keyboard,
+						DeviceCapability::Pointer,
+						DeviceCapability::PointerAbsolute,
+						DeviceCapability::Button,
+						DeviceCapability::Scroll,
+					]);
+				},
+				EiEvent::DeviceResumed(event) => {
+					tracing::debug!("libei device resumed: {:?}", event.device.name());
+					self.serial = event.serial;
+					self.sequence = self.sequence.wrapping_add(1);
+					event.device.device().start_emulating(self.serial, self.sequence);
+					self.resumed.push(event.device);
+				},
+				EiEvent::DevicePaused(event) => {
+					tracing::debug!("libei device paused: {:?}", event.device.name());
+					self.serial = event.serial;
+					self.resumed.retain(|device| device != &event.device);
+				},
+				EiEvent::DeviceRemoved(event) => {
+					self.resumed.retain(|device| device != &event.device);
+				},
+				EiEvent::Disconnected(event) => {
+					tracing::error!("Compositor closed the libei connection: {:?}", event.reason);
+					return Err(());
+				},
+				_ => {},
+			}
+		}
+
+		context.flush()
+			.map_err(|e| tracing::error!("Failed to send libei requests: {e}"))
+	}
+}
+
+impl InputBackend for LibeiBackend {
+	fn key_down(&mut self, key: Key) -> Result<(), ()> {
+		let code = evdev::Key::from(key).code() as u32;
+		self.emit(DeviceCapability::Keyboard, |device| {
+			if let Some(keyboard) = device.interface::<ei::Keyboard>() {
+				keyboard.key(code, ei::keyboard::KeyState::Press);
+			}
+		})
+	}
+
+	fn key_up(&mut self, key: Key) -> Result<(), ()> {
+		let code = evdev::Key::from(key).code() as u32;
+		self.emit(DeviceCapability::Keyboard, |device| {
+			if let Some(keyboard) = device.interface::<ei::Keyboard>() {
+				keyboard.key(code, ei::keyboard::KeyState::Released);
+			}
+		})
+	}
+
+	fn move_relative(&mut self, x: i32, y: i32) -> Result<(), ()> {
+		self.emit(DeviceCapability::Pointer, |device| {
+			if let Some(pointer) = device.interface::<ei::Pointer>() {
+				pointer.motion_relative(x as f32, y as f32);
+			}
+		})
+	}
+
+	fn move_absolute(&mut self, x: i32, y: i32, width: i32, height: i32) -> Result<(), ()> {
+		if width <= 0 || height <= 0 {
+			return Ok(());
+		}
+
+		self.emit(DeviceCapability::PointerAbsolute, |device| {
+			// Absolute positions are in the logical coordinates of the regions of the device.
+			let Some(region) = device.regions().first() else {
+				return;
+			};
+
+			if let Some(pointer) = device.interface::<ei::PointerAbsolute>() {
+				pointer.motion_absolute(
+					region.x as f32 + x as f32 / width as f32 * region.width as f32,
+					region.y as f32 + y as f32 / height as f32 * region.height as f32,
+				);
+			}
+		})
+	}
+
+	fn button_down(&mut self, button: MouseButton) -> Result<(), ()> {
+		let code = evdev::Key::from(button).code() as u32;
+		self.emit(DeviceCapability::Button, |device| {
+			if let Some(buttons) = device.interface::<ei::Button>() {
+				buttons.button(code, ei::button::ButtonState::Press);
+			}
+		})
+	}
+
+	fn button_up(&mut self, button: MouseButton) -> Result<(), ()> {
+		let code = evdev::Key::from(button).code() as u32;
+		self.emit(DeviceCapability::Button, |device| {
+			if let Some(buttons) = device.interface::<ei::Button>() {
+				buttons.button(code, ei::button::ButtonState::Released);
+			}
+		})
+	}
+
+	fn scroll_vertical(&mut self, amount: i16) -> Result<(), ()> {
+		// Clients scroll up with positive amounts, libei scrolls down with positive amounts.
+		self.emit(DeviceCapability::Scroll, |device| {
+			if let Some(scroll) = device.interface::<ei::Scroll>() {
+				scroll.scroll_discrete(0, -(amount as i32));
+			}
+		})
+	}
+
+	fn scroll_horizontal(&mut self, amount: i16) -> Result<(), ()> {
+		self.emit(DeviceCapability::Scroll, |device| {
+			if let Some(scroll) = device.interface::<ei::Scroll>() {
+				scroll.scroll_discrete(amount as i32, 0);
+			}
+		})
+	}
+}
+
+/// Current time of `CLOCK_MONOTONIC` in microseconds, which libei uses for frame timestamps.
+fn monotonic_micros() -> u64 {
+	let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+	time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1000
+}
@@ -0,0 +1,226 @@
+//! Translation of gamepad input to keyboard and mouse input and the other way around, as configured by input profiles.
+
+use std::collections::HashMap;
+
+use strum::IntoEnumIterator;
+
+use crate::config::{GamepadControl, InputProfileConfig, InputTarget, MappedMouseButton};
+
+use super::{keyboard::Key, mouse::MouseButton, protocol::{ControllerUpdatePacket, GamepadButton}};
+
+/// What a gamepad control is translated to.
+#[derive(Clone, Copy, Debug)]
+pub enum Translated {
+	Key(Key),
+	MouseButton(MouseButton),
+}
+
+/// Keys and mouse buttons to press or release on the host.
+#[derive(Debug)]
+pub enum Action {
+	Press(Translated),
+	Release(Translated),
+}
+
+pub struct InputMapping {
+	/// Gamepad controls that are translated, with what they press instead.
+	buttons: Vec<(GamepadControl, Translated)>,
+
+	/// Keys that are translated, with the gamepad control they press instead.
+	keys: HashMap<Key, GamepadControl>,
+
+	/// Fraction of its range a stick or trigger has to move before it counts as pressed.
+	axis_threshold: f32,
+
+	/// Translated gamepad controls that are pressed, per gamepad.
+	pressed: HashMap<u16, Vec<GamepadControl>>,
+
+	/// Translated keys that are held down.
+	held_keys: Vec<Key>,
+}
+
+impl InputMapping {
+	pub fn new(profile: &InputProfileConfig) -> Result<Self, ()> {
+		if !(profile.axis_threshold > 0.0 && profile.axis_threshold <= 1.0) {
+			tracing::error!("The axis threshold of an input profile should be above 0 and at most 1, but it is {}.", profile.axis_threshold);
+			return Err(());
+		}
+
+		let buttons = profile.buttons.iter()
+			.map(|(control, target)| {
+				let translated = match target {
+					InputTarget::Key(name) => Translated::Key(key_from_name(name)?),
+					InputTarget::MouseButton(button) => Translated::MouseButton(mouse_button(*button)),
+				};
+				Ok((*control, translated))
+			})
+			.collect::<Result<Vec<_>, ()>>()?;
+		let keys = profile.keys.iter()
+			.map(|(name, control)| Ok((key_from_name(name)?, *control)))
+			.collect::<Result<HashMap<_, _>, ()>>()?;
+
+		if !keys.is_empty() && !cfg!(feature = "uinput") {
+			tracing::warn!("Translating keys to gamepad input requires building with the 'uinput' feature, these keys are ignored.");
+		}
+
+		Ok(Self {
+			buttons,
+			keys,
+			axis_threshold: profile.axis_threshold,
+			pressed: HashMap::new(),
+			held_keys: Vec::new(),
+		})
+	}
+
+	/// Translate the controls of a gamepad update, which are removed from the update so they don't reach the gamepad.
+	///
+	/// Returns the keys and mouse buttons to press or release for controls that changed since the previous update.
+	pub fn translate_gamepad(&mut self, update: &mut ControllerUpdatePacket) -> Vec<Action> {
+		if self.buttons.is_empty() {
+			return Vec::new();
+		}
+
+		let pressed = self.pressed.entry(update.index).or_default();
+		let mut actions = Vec::new();
+		for (control, translated) in &self.buttons {
+			let is_pressed = amount(*control, update) >= self.axis_threshold;
+			let was_pressed = pressed.contains(control);
+			if is_pressed && !was_pressed {
+				pressed.push(*control);
+				actions.push(Action::Press(*translated));
+			} else if !is_pressed && was_pressed {
+				pressed.retain(|pressed| pressed != control);
+				actions.push(Action::Release(*translated));
+			}
+		}
+
+		// Only after all controls are read, releasing one direction of a stick releases the other as well.
+		for (control, _) in &self.buttons {
+			set_control(*control, update, false);
+		}
+
+		actions
+	}
+
+	/// Translate a key that is pressed or released.
+	///
+	/// Returns the new state of the gamepad that keys are translated to, or none if this key is not translated.
+	pub fn translate_key(&mut self, key: Key, down: bool) -> Option<ControllerUpdatePacket> {
+		if !self.keys.contains_key(&key) {
+			return None;
+		}
+
+		if !down {
+			self.held_keys.retain(|held| *held != key);
+		} else if !self.held_keys.contains(&key) {
+			self.held_keys.push(key);
+		}
+
+		let mut update = ControllerUpdatePacket {
+			index: 0,
+			active_gamepad_mask: 1,
+			button_flags: 0,
+			left_trigger: 0,
+			right_trigger: 0,
+			left_stick: (0, 0),
+			right_stick: (0, 0),
+		};
+		for held in &self.held_keys {
+			if let Some(control) = self.keys.get(held) {
+				set_control(*control, &mut update, true);
+			}
+		}
+
+		Some(update)
+	}
+}
+
+/// Find a key by its name, ignoring case.
+fn key_from_name(name: &str) -> Result<Key, ()> {
+	Key::iter()
+		.find(|key| format!("{key:?}").eq_ignore_ascii_case(name))
+		.ok_or_else(|| tracing::error!("Unknown key '{name}' in input profile."))
+}
+
+fn mouse_button(button: MappedMouseButton) -> MouseButton {
+	match button {
+		MappedMouseButton::Left => MouseButton::Left,
+		MappedMouseButton::Middle => MouseButton::Middle,
+		MappedMouseButton::Right => MouseButton::Right,
+		MappedMouseButton::Side => MouseButton::Side,
+		MappedMouseButton::Extra => MouseButton::Extra,
+	}
+}
+
+/// The button flag of a control, none for controls that are an axis.
+fn button(control: GamepadControl) -> Option<GamepadButton> {
+	match control {
+		GamepadControl::A => Some(GamepadButton::A),
+		GamepadControl::B => Some(GamepadButton::B),
+		GamepadControl::X => Some(GamepadButton::X),
+		GamepadControl::Y => Some(GamepadButton::Y),
+		GamepadControl::Up => Some(GamepadButton::Up),
+		GamepadControl::Down => Some(GamepadButton::Down),
+		GamepadControl::Left => Some(GamepadButton::Left),
+		GamepadControl::Right => Some(GamepadButton::Right),
+		GamepadControl::Start => Some(GamepadButton::Start),
+		GamepadControl::Select => Some(GamepadButton::Select),
+		GamepadControl::Home => Some(GamepadButton::Home),
+		GamepadControl::LeftShoulder => Some(GamepadButton::LB),
+		GamepadControl::RightShoulder => Some(GamepadButton::RB),
+		GamepadControl::LeftStickClick => Some(GamepadButton::LeftStickClick),
+		GamepadControl::RightStickClick => Some(GamepadButton::RightStickClick),
+		_ => None,
+	}
+}
+
+/// How far a control is pressed in an update, from 0 to 1.
+fn amount(control: GamepadControl, update: &ControllerUpdatePacket) -> f32 {
+	if let Some(button) = button(control) {
+		return if update.button_flags & button as u32 != 0 { 1.0 } else { 0.0 };
+	}
+
+	// Sticks point up for positive values.
+	let stick = |value: i16| value as f32 / i16::MAX as f32;
+	match control {
+		GamepadControl::LeftTrigger => update.left_trigger as f32 / u8::MAX as f32,
+		GamepadControl::RightTrigger => update.right_trigger as f32 / u8::MAX as f32,
+		GamepadControl::LeftStickUp => stick(update.left_stick.1),
+		GamepadControl::LeftStickDown => -stick(update.left_stick.1),
+		GamepadControl::LeftStickLeft => -stick(update.left_stick.0),
+		GamepadControl::LeftStickRight => stick(update.left_stick.0),
+		GamepadControl::RightStickUp => stick(update.right_stick.1),
+		GamepadControl::RightStickDown => -stick(update.right_stick.1),
+		GamepadControl::RightStickLeft => -stick(update.right_stick.0),
+		GamepadControl::RightStickRight => stick(update.right_stick.0),
+		_ => 0.0,
+	}
+}
+
+/// Fully press or release a control in an update, releasing a direction of a stick centers it on that axis.
+fn set_control(control: GamepadControl, update: &mut ControllerUpdatePacket, pressed: bool) {
+	if let Some(button) = button(control) {
+		if pressed {
+			update.button_flags |= button as u32;
+		} else {
+			update.button_flags &= !(button as u32);
+		}
+		return;
+	}
+
+	let trigger = if pressed { u8::MAX } else { 0 };
+	let stick = |direction: i16| if pressed { direction * i16::MAX } else { 0 };
+	match control {
+		GamepadControl::LeftTrigger => update.left_trigger = trigger,
+		GamepadControl::RightTrigger => update.right_trigger = trigger,
+		GamepadControl::LeftStickUp => update.left_stick.1 = stick(1),
+		GamepadControl::LeftStickDown => update.left_stick.1 = stick(-1),
+		GamepadControl::LeftStickLeft => update.left_stick.0 = stick(-1),
+		GamepadControl::LeftStickRight => update.left_stick.0 = stick(1),
+		GamepadControl::RightStickUp => update.right_stick.1 = stick(1),
+		GamepadControl::RightStickDown => update.right_stick.1 = stick(-1),
+		GamepadControl::RightStickLeft => update.right_stick.0 = stick(-1),
+		GamepadControl::RightStickRight => update.right_stick.0 = stick(1),
+		_ => {},
+	}
+}
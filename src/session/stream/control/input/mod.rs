@@ -1,170 +1,440 @@
-use strum_macros::FromRepr;
+#[cfg(feature = "uinput")]
+use std::collections::HashMap;
+use std::{sync::Mutex, thread::Thread, time::Instant};
+
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
-use crate::session::stream::control::input::gamepad::Gamepad;
+use crate::{config::{HostConfig, InputBackendKind, InputProfileConfig}, session::{gamepads::GamepadSlots, stats::SessionStats}};
 
+#[cfg(feature = "uinput")]
+use self::{backend::UinputBackend, gamepad::Gamepad};
 use self::{
-	mouse::{
-		Mouse,
-		MouseButton,
-		MouseMoveAbsolute,
-		MouseMoveRelative,
-		MouseScrollVertical,
-		MouseScrollHorizontal,
-	},
-	keyboard::{Keyboard, Key},
-	gamepad::{GamepadInfo, GamepadUpdate}
+	backend::{DisabledBackend, InputBackend},
+	mapping::{Action, InputMapping, Translated},
+	mouse::MouseButton,
+	keyboard::Key,
+	protocol::{ControllerUpdatePacket, InputPacket, ProtocolVersion},
 };
 
+mod backend;
 mod keyboard;
+#[cfg(feature = "libei")]
+mod libei;
+mod mapping;
 mod mouse;
+#[cfg(feature = "uinput")]
 mod gamepad;
+mod protocol;
+#[cfg(windows)]
+mod sendinput;
+
+/// Number of input packets that can be queued for the input thread.
+const INPUT_QUEUE_SIZE: usize = 256;
 
-#[derive(FromRepr)]
-#[repr(u32)]
-enum InputEventType {
-	KeyDown = 0x00000003,
-	KeyUp = 0x00000004,
-	MouseMoveAbsolute = 0x00000005,
-	MouseMoveRelative = 0x00000007,
-	MouseButtonDown = 0x00000008,
-	MouseButtonUp = 0x00000009,
-	MouseScrollVertical = 0x0000000A,
-	MouseScrollHorizontal = 0x55000001,
-	GamepadInfo = 0x55000004, // Called ControllerArrival in Moonlight.
-	GamepadUpdate = 0x0000000C,
+pub struct InputHandler {
+	sender: InputSender,
+
+	/// Version of the input protocol that clients use.
+	version: ProtocolVersion,
 }
 
-#[derive(Debug)]
-#[repr(u32)]
-enum InputEvent {
-	KeyDown(Key),
-	KeyUp(Key),
-	MouseMoveAbsolute(MouseMoveAbsolute),
-	MouseMoveRelative(MouseMoveRelative),
-	MouseButtonDown(MouseButton),
-	MouseButtonUp(MouseButton),
-	MouseScrollVertical(MouseScrollVertical),
-	MouseScrollHorizontal(MouseScrollHorizontal),
-	GamepadInfo(GamepadInfo),
-	GamepadUpdate(GamepadUpdate),
+/// Where input packets are handed over to be injected.
+enum InputSender {
+	Task(mpsc::Sender<(InputPacket, Instant)>),
+	Thread(InputThread),
 }
 
-impl InputEvent {
-	fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		if buffer.len() < 4 {
-			tracing::warn!("Expected control message to have at least 4 bytes, got {}", buffer.len());
-			return Err(());
-		}
+/// Hands input packets to a dedicated thread through a lock-free ring buffer, waking it up for every packet.
+struct InputThread {
+	/// Only the control stream pushes packets, the mutex is never contended.
+	producer: Option<Mutex<rtrb::Producer<(InputPacket, Instant)>>>,
+	thread: Thread,
+}
+
+impl Drop for InputThread {
+	fn drop(&mut self) {
+		// Drop the producer before waking the thread, so it sees that it should stop.
+		self.producer = None;
+		self.thread.unpark();
+	}
+}
+
+/// A backend that is created right away, or one that has to connect first.
+enum PendingBackend {
+	Ready(Box<dyn InputBackend>),
 
-		let event_type = u32::from_le_bytes(buffer[..4].try_into().unwrap());
-		match InputEventType::from_repr(event_type) {
-			Some(InputEventType::KeyDown) => Ok(InputEvent::KeyDown(Key::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::KeyUp) => Ok(InputEvent::KeyUp(Key::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::MouseMoveAbsolute) => Ok(InputEvent::MouseMoveAbsolute(MouseMoveAbsolute::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::MouseMoveRelative) => Ok(InputEvent::MouseMoveRelative(MouseMoveRelative::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::MouseButtonDown) => Ok(InputEvent::MouseButtonDown(MouseButton::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::MouseButtonUp) => Ok(InputEvent::MouseButtonUp(MouseButton::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::MouseScrollVertical) => Ok(InputEvent::MouseScrollVertical(MouseScrollVertical::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::MouseScrollHorizontal) => Ok(InputEvent::MouseScrollHorizontal(MouseScrollHorizontal::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::GamepadInfo) => Ok(InputEvent::GamepadInfo(GamepadInfo::from_bytes(&buffer[4..])?)),
-			Some(InputEventType::GamepadUpdate) => Ok(InputEvent::GamepadUpdate(GamepadUpdate::from_bytes(&buffer[4..])?)),
-			None => {
-				tracing::warn!("Received unknown event type: {event_type}");
+	/// Connecting through the portal waits for the user to allow it, so it happens on the input task or thread.
+	#[cfg(feature = "libei")]
+	Libei,
+}
+
+impl PendingBackend {
+	fn new(config: &HostConfig) -> Result<Self, ()> {
+		match config.input_backend {
+			#[cfg(feature = "uinput")]
+			InputBackendKind::Uinput => Ok(Self::Ready(Box::new(UinputBackend::new(config.batch_input)?))),
+
+			#[cfg(not(feature = "uinput"))]
+			InputBackendKind::Uinput => {
+				tracing::error!("The uinput input backend requires building with the 'uinput' feature.");
 				Err(())
-			}
+			},
+
+			#[cfg(feature = "libei")]
+			InputBackendKind::Libei => Ok(Self::Libei),
+
+			#[cfg(not(feature = "libei"))]
+			InputBackendKind::Libei => {
+				tracing::error!("The libei input backend requires building with the 'libei' feature.");
+				Err(())
+			},
+
+			#[cfg(windows)]
+			InputBackendKind::SendInput => Ok(Self::Ready(Box::new(sendinput::SendInputBackend))),
+
+			#[cfg(not(windows))]
+			InputBackendKind::SendInput => {
+				tracing::error!("The SendInput input backend is only available on Windows.");
+				Err(())
+			},
+
+			InputBackendKind::Disabled => {
+				tracing::info!("Input is disabled, input from the client is ignored.");
+				Ok(Self::Ready(Box::new(DisabledBackend)))
+			},
 		}
 	}
-}
 
-pub struct InputHandler {
-	command_tx: mpsc::Sender<InputEvent>,
+	async fn connect(self) -> Result<Box<dyn InputBackend>, ()> {
+		match self {
+			Self::Ready(backend) => Ok(backend),
+
+			#[cfg(feature = "libei")]
+			Self::Libei => {
+				let backend = libei::LibeiBackend::connect().await
+					.map_err(|()| tracing::error!("Failed to set up libei, input from the client is ignored."))?;
+				Ok(Box::new(backend))
+			},
+		}
+	}
 }
 
 impl InputHandler {
-	pub fn new() -> Result<Self, ()> {
-		let mouse = Mouse::new()?;
-		let keyboard = Keyboard::new()?;
+	/// If `profile` is provided, input is translated with it before it is injected.
+	///
+	/// Gamepads of the client with the certificate fingerprint `client_certificate` get their player number from `gamepads`.
+	pub fn new(
+		config: &HostConfig,
+		profile: Option<&InputProfileConfig>,
+		gamepads: GamepadSlots,
+		client_certificate: String,
+		stats: SessionStats,
+	) -> Result<Self, ()> {
+		let backend = PendingBackend::new(config)?;
+		let batch = config.batch_input;
+		let mapping = profile.map(InputMapping::new).transpose()?;
+
+		let sender = if config.input_thread {
+			let (producer, consumer) = rtrb::RingBuffer::new(INPUT_QUEUE_SIZE);
+			let runtime = tokio::runtime::Handle::current();
+			let span = tracing::Span::current();
+			let thread = std::thread::Builder::new()
+				.name("input".to_string())
+				.spawn(move || span.in_scope(|| {
+					let Ok(backend) = runtime.block_on(backend.connect()) else {
+						return;
+					};
+
+					InputHandlerInner::new(backend, stats, batch, mapping, gamepads, client_certificate).run_thread(consumer);
+				}))
+				.map_err(|e| tracing::error!("Failed to spawn input thread: {e}"))?;
+
+			InputSender::Thread(InputThread { producer: Some(Mutex::new(producer)), thread: thread.thread().clone() })
+		} else {
+			let (command_tx, command_rx) = mpsc::channel(10);
+			tokio::spawn(async move {
+				let Ok(backend) = backend.connect().await else {
+					return;
+				};
+
+				InputHandlerInner::new(backend, stats, batch, mapping, gamepads, client_certificate).run(command_rx).await;
+			}.in_current_span());
 
-		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = InputHandlerInner { mouse, keyboard };
-		tokio::spawn(inner.run(command_rx));
+			InputSender::Task(command_tx)
+		};
 
-		Ok(Self { command_tx })
+		// We report a Sunshine compatible `appversion`, so clients send the extended packets.
+		Ok(Self { sender, version: ProtocolVersion::Gen7Extended })
 	}
 
-	async fn handle_input(&self, event: InputEvent) -> Result<(), ()> {
-		self.command_tx.send(event).await
-			.map_err(|e| tracing::error!("Failed to send input event: {e}"))
+	async fn handle_input(&self, packet: InputPacket, received: Instant) -> Result<(), ()> {
+		match &self.sender {
+			InputSender::Task(command_tx) => command_tx.send((packet, received)).await
+				.map_err(|e| tracing::error!("Failed to send input event: {e}")),
+
+			InputSender::Thread(input_thread) => {
+				let Some(producer) = &input_thread.producer else {
+					return Err(());
+				};
+				let mut producer = producer.lock()
+					.map_err(|e| tracing::error!("Failed to lock input queue: {e}"))?;
+				if producer.is_abandoned() {
+					tracing::error!("Failed to send input event, the input thread stopped.");
+					return Err(());
+				}
+				producer.push((packet, received))
+					.map_err(|_| tracing::warn!("Input queue is full, dropping input event."))?;
+				input_thread.thread.unpark();
+				Ok(())
+			},
+		}
 	}
 
-	pub async fn handle_raw_input<'a>(&self, event: &'a [u8]) -> Result<(), ()> {
-		let event = InputEvent::from_bytes(event)?;
-		self.handle_input(event).await
+	/// Handle an input event, `received` is the moment the event was received from the client.
+	pub async fn handle_raw_input<'a>(&self, event: &'a [u8], received: Instant) -> Result<(), ()> {
+		let packet = InputPacket::from_bytes(event, self.version)
+			.map_err(|e| tracing::warn!("Failed to parse input event: {e}"))?;
+		self.handle_input(packet, received).await
+	}
+
+	/// Handle an input event of a guest, which only gets to use gamepads.
+	pub async fn handle_raw_gamepad_input<'a>(&self, event: &'a [u8], received: Instant) -> Result<(), ()> {
+		let packet = InputPacket::from_bytes(event, self.version)
+			.map_err(|e| tracing::warn!("Failed to parse input event: {e}"))?;
+		if !packet.is_gamepad() {
+			tracing::trace!("Ignoring input event of guest that isn't about a gamepad.");
+			return Ok(());
+		}
+		self.handle_input(packet, received).await
 	}
 }
 
 struct InputHandlerInner {
-	mouse: Mouse,
-	keyboard: Keyboard,
+	backend: Box<dyn InputBackend>,
+	stats: SessionStats,
+
+	/// Whether the backend holds events back until it is flushed.
+	batch: bool,
+
+	/// Translates input with the input profile of the application.
+	mapping: Option<InputMapping>,
+
+	/// Player numbers of gamepads, shared by all clients of the session.
+	slots: GamepadSlots,
+
+	/// Fingerprint of the certificate of the client that this input comes from.
+	client_certificate: String,
+
+	/// Gamepads of the client, by their index on the client.
+	#[cfg(feature = "uinput")]
+	gamepads: HashMap<u8, Gamepad>,
+
+	/// Gamepad that keys are translated to, created when the first translated key is pressed.
+	#[cfg(feature = "uinput")]
+	keyboard_gamepad: Option<Gamepad>,
 }
 
 impl InputHandlerInner {
-	pub async fn run(mut self, mut command_rx: mpsc::Receiver<InputEvent>) {
-		let mut gamepads = Vec::new();
+	fn new(
+		backend: Box<dyn InputBackend>,
+		stats: SessionStats,
+		batch: bool,
+		mapping: Option<InputMapping>,
+		slots: GamepadSlots,
+		client_certificate: String,
+	) -> Self {
+		Self {
+			backend,
+			stats,
+			batch,
+			mapping,
+			slots,
+			client_certificate,
+			#[cfg(feature = "uinput")]
+			gamepads: HashMap::new(),
+			#[cfg(feature = "uinput")]
+			keyboard_gamepad: None,
+		}
+	}
+
+	async fn run(mut self, mut command_rx: mpsc::Receiver<(InputPacket, Instant)>) {
+		while let Some((packet, received)) = command_rx.recv().await {
+			self.handle(packet, received);
+
+			// Packets that arrived together are emitted together.
+			if self.batch {
+				while let Ok((packet, received)) = command_rx.try_recv() {
+					self.handle(packet, received);
+				}
+				let _ = self.backend.flush();
+			}
+		}
+
+		tracing::debug!("Input handler closing.");
+	}
+
+	fn run_thread(mut self, mut consumer: rtrb::Consumer<(InputPacket, Instant)>) {
+		loop {
+			while let Ok((packet, received)) = consumer.pop() {
+				self.handle(packet, received);
+				if !self.batch {
+					continue;
+				}
+
+				// Packets that arrived together are emitted together.
+				if consumer.is_empty() {
+					let _ = self.backend.flush();
+				}
+			}
+
+			if consumer.is_abandoned() {
+				break;
+			}
+
+			// Woken up by the control stream for every packet it pushes.
+			std::thread::park();
+		}
+
+		tracing::debug!("Input thread closing.");
+	}
 
-		while let Some(command) = command_rx.recv().await {
-			match command {
-				InputEvent::KeyDown(key) => {
+	fn handle(&mut self, command: InputPacket, received: Instant) {
+		match command {
+			InputPacket::KeyDown(packet) => {
+				let Some(key) = key_from_packet(&packet) else { return };
+				if let Some(update) = self.mapping.as_mut().and_then(|mapping| mapping.translate_key(key, true)) {
+					tracing::trace!("Pressing key {key:?} on gamepad: {update:?}");
+					self.update_keyboard_gamepad(update);
+				} else {
 					tracing::trace!("Pressing key: {key:?}");
-					let _ = self.keyboard.key_down(key);
-				},
-				InputEvent::KeyUp(key) => {
+					let _ = self.backend.key_down(key);
+				}
+			},
+			InputPacket::KeyUp(packet) => {
+				let Some(key) = key_from_packet(&packet) else { return };
+				if let Some(update) = self.mapping.as_mut().and_then(|mapping| mapping.translate_key(key, false)) {
+					tracing::trace!("Releasing key {key:?} on gamepad: {update:?}");
+					self.update_keyboard_gamepad(update);
+				} else {
 					tracing::trace!("Releasing key: {key:?}");
-					let _ = self.keyboard.key_up(key);
-				},
-				InputEvent::MouseMoveAbsolute(event) => {
-					tracing::trace!("Absolute mouse movement: {event:?}");
-					let _ = self.mouse.move_absolute(event.x as i32, event.y as i32);
-				},
-				InputEvent::MouseMoveRelative(event) => {
-					tracing::trace!("Moving mouse relative: {event:?}");
-					let _ = self.mouse.move_relative(event.x as i32, event.y as i32);
-				},
-				InputEvent::MouseButtonDown(button) => {
-					tracing::trace!("Pressing mouse button: {button:?}");
-					let _ = self.mouse.button_down(button);
-				},
-				InputEvent::MouseButtonUp(button) => {
-					tracing::trace!("Releasing mouse button: {button:?}");
-					let _ = self.mouse.button_up(button);
-				},
-				InputEvent::MouseScrollVertical(event) => {
-					tracing::trace!("Scrolling vertically: {event:?}");
-					let _ = self.mouse.scroll_vertical(event.amount);
-				},
-				InputEvent::MouseScrollHorizontal(event) => {
-					tracing::trace!("Scrolling horizontally: {event:?}");
-					let _ = self.mouse.scroll_horizontal(event.amount);
-				},
-				InputEvent::GamepadInfo(gamepad) => {
-					tracing::debug!("Gamepad info: {gamepad:?}");
-					if let Ok(gamepad) = Gamepad::new(gamepad) {
-						gamepads.push(gamepad);
-					}
-				},
-				InputEvent::GamepadUpdate(gamepad_update) => {
-					tracing::trace!("Gamepad update: {gamepad_update:?}");
-					if gamepad_update.index as usize >= gamepads.len() {
-						tracing::warn!("Received update for gamepad {}, but we only have {} gamepads.", gamepad_update.index, gamepads.len());
-						continue;
+					let _ = self.backend.key_up(key);
+				}
+			},
+			InputPacket::MouseMoveAbsolute(packet) => {
+				tracing::trace!("Absolute mouse movement: {packet:?}");
+				let _ = self.backend.move_absolute(packet.x as i32, packet.y as i32, packet.width as i32, packet.height as i32);
+			},
+			InputPacket::MouseMoveRelative(packet) => {
+				tracing::trace!("Moving mouse relative: {packet:?}");
+				let _ = self.backend.move_relative(packet.x as i32, packet.y as i32);
+			},
+			InputPacket::MouseButtonDown(packet) => {
+				let Some(button) = MouseButton::from_repr(packet.button) else {
+					tracing::warn!("Unknown mouse button: {}", packet.button);
+					return;
+				};
+				tracing::trace!("Pressing mouse button: {button:?}");
+				let _ = self.backend.button_down(button);
+			},
+			InputPacket::MouseButtonUp(packet) => {
+				let Some(button) = MouseButton::from_repr(packet.button) else {
+					tracing::warn!("Unknown mouse button: {}", packet.button);
+					return;
+				};
+				tracing::trace!("Releasing mouse button: {button:?}");
+				let _ = self.backend.button_up(button);
+			},
+			InputPacket::ScrollVertical(packet) => {
+				tracing::trace!("Scrolling vertically: {packet:?}");
+				let _ = self.backend.scroll_vertical(packet.amount);
+			},
+			InputPacket::ScrollHorizontal(packet) => {
+				tracing::trace!("Scrolling horizontally: {packet:?}");
+				let _ = self.backend.scroll_horizontal(packet.amount);
+			},
+			#[cfg(feature = "uinput")]
+			InputPacket::ControllerArrival(packet) => {
+				tracing::debug!("Gamepad arrived: {packet:?}");
+				let Ok(player) = self.slots.connect(&self.client_certificate, packet.index) else { return };
+				let index = packet.index;
+				if let Ok(gamepad) = Gamepad::new(packet, player) {
+					self.gamepads.insert(index, gamepad);
+				}
+			},
+			InputPacket::ControllerUpdate(mut packet) => {
+				tracing::trace!("Gamepad update: {packet:?}");
+				if let Some(mapping) = &mut self.mapping {
+					for action in mapping.translate_gamepad(&mut packet) {
+						self.apply(action);
 					}
+				}
 
-					let _ = gamepads[gamepad_update.index as usize].update(gamepad_update);
-				},
-			}
+				#[cfg(feature = "uinput")]
+				{
+					let Some(gamepad) = u8::try_from(packet.index).ok().and_then(|index| self.gamepads.get_mut(&index)) else {
+						tracing::warn!("Received update for gamepad {}, but it never arrived.", packet.index);
+						return;
+					};
+
+					let _ = gamepad.update(packet);
+				}
+			},
+			#[cfg(not(feature = "uinput"))]
+			InputPacket::ControllerArrival(_) => {
+				tracing::warn!("Gamepads require building with the 'uinput' feature, ignoring gamepad.");
+				return;
+			},
+			packet => {
+				tracing::debug!("Ignoring unsupported input packet: {packet:?}");
+				return;
+			},
 		}
 
-		tracing::debug!("Input handler closing.");
+		self.stats.record_input_latency(received.elapsed());
+	}
+
+	/// Press or release a key or mouse button that a gamepad control was translated to.
+	fn apply(&mut self, action: Action) {
+		tracing::trace!("Translated gamepad input: {action:?}");
+		let _ = match action {
+			Action::Press(Translated::Key(key)) => self.backend.key_down(key),
+			Action::Release(Translated::Key(key)) => self.backend.key_up(key),
+			Action::Press(Translated::MouseButton(button)) => self.backend.button_down(button),
+			Action::Release(Translated::MouseButton(button)) => self.backend.button_up(button),
+		};
 	}
+
+	#[cfg(feature = "uinput")]
+	fn update_keyboard_gamepad(&mut self, update: ControllerUpdatePacket) {
+		if self.keyboard_gamepad.is_none() {
+			self.keyboard_gamepad = Gamepad::for_keyboard().ok();
+		}
+
+		if let Some(gamepad) = &mut self.keyboard_gamepad {
+			let _ = gamepad.update(update);
+		}
+	}
+
+	/// Without uinput there is no gamepad to translate keys to, which is logged when the input profile is loaded.
+	#[cfg(not(feature = "uinput"))]
+	fn update_keyboard_gamepad(&mut self, _update: ControllerUpdatePacket) {}
+}
+
+impl Drop for InputHandlerInner {
+	fn drop(&mut self) {
+		// The virtual gamepads are removed with the handler, their players are kept for when the client comes back.
+		self.slots.disconnect_client(&self.client_certificate);
+	}
+}
+
+/// Find the key for a keyboard packet, the key code is sent as a Windows virtual key code in the lower byte.
+fn key_from_packet(packet: &protocol::KeyboardPacket) -> Option<Key> {
+	let key_code = (packet.key_code & 0xFF) as u8;
+	let key = Key::from_repr(key_code);
+	if key.is_none() {
+		tracing::warn!("Unknown keycode: {key_code}");
+	}
+
+	key
 }
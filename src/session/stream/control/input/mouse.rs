@@ -1,59 +1,13 @@
 use strum_macros::FromRepr;
-use evdev::{uinput::{VirtualDeviceBuilder, VirtualDevice}, AttributeSet, RelativeAxisType, Key, AbsoluteAxisType, UinputAbsSetup, AbsInfo};
+#[cfg(any(feature = "uinput", feature = "libei"))]
+use evdev::Key;
+#[cfg(feature = "uinput")]
+use evdev::{uinput::VirtualDeviceBuilder, AttributeSet, RelativeAxisType, AbsoluteAxisType, UinputAbsSetup, AbsInfo};
 
-#[derive(Debug)]
-pub struct MouseMoveAbsolute {
-	pub x: i16,
-	pub y: i16,
-	// width: i16,
-	// height: i16,
-}
-
-impl MouseMoveAbsolute {
-	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		const EXPECTED_SIZE: usize =
-			std::mem::size_of::<i16>()   // x
-			+ std::mem::size_of::<i16>() // y
-			+ std::mem::size_of::<i16>() // padding
-			+ std::mem::size_of::<i16>() // width
-			+ std::mem::size_of::<i16>() // height
-		;
-
-		if buffer.len() < EXPECTED_SIZE {
-			tracing::warn!("Expected at least {EXPECTED_SIZE} bytes for MouseMoveAbsolute, got {} bytes.", buffer.len());
-			return Err(());
-		}
-
-		Ok(Self {
-			x: i16::from_be_bytes(buffer[0..2].try_into().unwrap()),
-			y: i16::from_be_bytes(buffer[2..4].try_into().unwrap()),
-			// width: i16::from_be_bytes(buffer[6..8].try_into().unwrap()),
-			// height: i16::from_be_bytes(buffer[8..10].try_into().unwrap()),
-		})
-	}
-}
-
-#[derive(Debug)]
-pub struct MouseMoveRelative {
-	pub x: i16,
-	pub y: i16,
-}
+#[cfg(feature = "uinput")]
+use super::backend::BatchedDevice;
 
-impl MouseMoveRelative {
-	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		if buffer.len() < std::mem::size_of::<Self>() {
-			tracing::warn!("Expected at least {} bytes for MouseMoveRelative, got {} bytes.", std::mem::size_of::<Self>(), buffer.len());
-			return Err(());
-		}
-
-		Ok(Self {
-			x: i16::from_be_bytes(buffer[0..2].try_into().unwrap()),
-			y: i16::from_be_bytes(buffer[2..4].try_into().unwrap()),
-		})
-	}
-}
-
-#[derive(Debug, Eq, PartialEq, FromRepr)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromRepr)]
 #[repr(u8)]
 pub enum MouseButton {
 	Left = 0x01,
@@ -63,19 +17,7 @@ pub enum MouseButton {
 	Extra = 0x05,
 }
 
-impl MouseButton {
-	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		const EXPECTED_SIZE: usize = std::mem::size_of::<u8>(); // button
-
-		if buffer.len() < EXPECTED_SIZE {
-			tracing::warn!("Expected at least {EXPECTED_SIZE} bytes for MouseButton, got {} bytes.", buffer.len());
-			return Err(());
-		}
-
-		MouseButton::from_repr(buffer[0]).ok_or_else(|| tracing::warn!("Unknown mouse button: {}", buffer[0]))
-	}
-}
-
+#[cfg(any(feature = "uinput", feature = "libei"))]
 impl From<MouseButton> for Key {
 	fn from(val: MouseButton) -> Self {
 		match val {
@@ -88,48 +30,14 @@ impl From<MouseButton> for Key {
 	}
 }
 
-#[derive(Debug)]
-pub struct MouseScrollVertical {
-	pub amount: i16,
-}
-
-impl MouseScrollVertical {
-	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		if buffer.len() < std::mem::size_of::<Self>() {
-			tracing::warn!("Expected at least {} bytes for MouseScrollVertical, got {} bytes.", std::mem::size_of::<Self>(), buffer.len());
-			return Err(());
-		}
-
-		Ok(Self {
-			amount: i16::from_be_bytes(buffer[0..2].try_into().unwrap()),
-		})
-	}
-}
-
-#[derive(Debug)]
-pub struct MouseScrollHorizontal {
-	pub amount: i16,
-}
-
-impl MouseScrollHorizontal {
-	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
-		if buffer.len() < std::mem::size_of::<Self>() {
-			tracing::warn!("Expected at least {} bytes for MouseScrollHorizontal, got {} bytes.", std::mem::size_of::<Self>(), buffer.len());
-			return Err(());
-		}
-
-		Ok(Self {
-			amount: i16::from_be_bytes(buffer[0..2].try_into().unwrap()),
-		})
-	}
-}
-
+#[cfg(feature = "uinput")]
 pub struct Mouse {
-	device: VirtualDevice,
+	device: BatchedDevice,
 }
 
+#[cfg(feature = "uinput")]
 impl Mouse {
-	pub fn new() -> Result<Self, ()> {
+	pub fn new(batch: bool) -> Result<Self, ()> {
 		let device = VirtualDeviceBuilder::new()
 			.map_err(|e| tracing::error!("Failed to initiate virtual mouse: {e}"))?
 			.name("Moonshine Mouse")
@@ -159,7 +67,12 @@ impl Mouse {
 			.build()
 			.map_err(|e| tracing::error!("Failed to create virtual mouse: {e}"))?;
 
-		Ok(Self { device })
+		Ok(Self { device: BatchedDevice::new(device, batch) })
+	}
+
+	pub fn flush(&mut self) -> Result<(), ()> {
+		self.device.flush()
+			.map_err(|e| tracing::error!("Failed to emit mouse events: {e}"))
 	}
 
 	pub fn move_relative(&mut self, x: i32, y: i32) -> Result<(), ()> {
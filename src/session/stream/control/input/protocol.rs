@@ -0,0 +1,705 @@
+//! Typed representation of the input packets that Moonlight clients send.
+//!
+//! Every packet starts with a little endian `u32` describing its type, followed by the payload of that type.
+//! Note that some payloads are big endian and others little endian, this is how the clients send them.
+
+use strum_macros::{EnumIter, FromRepr};
+
+use super::super::reader::{ByteReader, ParseError};
+
+/// Version of the input protocol spoken by the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolVersion {
+	/// The protocol of GameStream generation 7, as used by GeForce Experience.
+	// Clients without the extensions aren't detected yet, only the tests parse their packets.
+	#[allow(dead_code)]
+	Gen7,
+
+	/// Generation 7 with the extensions introduced by Sunshine.
+	///
+	/// Clients use these extensions when the host reports a negative last component in its `appversion`.
+	Gen7Extended,
+}
+
+#[derive(Clone, Copy, Debug, FromRepr)]
+#[repr(u32)]
+enum PacketType {
+	KeyDown = 0x00000003,
+	KeyUp = 0x00000004,
+	MouseMoveAbsolute = 0x00000005,
+	MouseMoveRelative = 0x00000007,
+	MouseButtonDown = 0x00000008,
+	MouseButtonUp = 0x00000009,
+	ScrollVertical = 0x0000000A,
+	MultiController = 0x0000000C,
+	EnableHaptics = 0x0000000D,
+	Utf8Text = 0x00000017,
+
+	// Extended packets (Sunshine / Moonshine only).
+	ScrollHorizontal = 0x55000001,
+	Touch = 0x55000002,
+	Pen = 0x55000003,
+	ControllerArrival = 0x55000004,
+	ControllerTouch = 0x55000005,
+	ControllerMotion = 0x55000006,
+	ControllerBattery = 0x55000007,
+}
+
+impl PacketType {
+	fn is_extension(&self) -> bool {
+		(*self as u32) & 0xFF000000 == 0x55000000
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub struct KeyboardPacket {
+	#[allow(dead_code)]
+	pub flags: u8,
+	pub key_code: u16,
+	#[allow(dead_code)]
+	pub modifiers: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MouseMoveAbsolutePacket {
+	pub x: i16,
+	pub y: i16,
+	pub width: i16,
+	pub height: i16,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MouseMoveRelativePacket {
+	pub x: i16,
+	pub y: i16,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MouseButtonPacket {
+	pub button: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScrollPacket {
+	pub amount: i16,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct Utf8TextPacket {
+	pub text: String,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct TouchPacket {
+	pub event_type: u8,
+	pub rotation: u16,
+	pub pointer_id: u32,
+	pub x: f32,
+	pub y: f32,
+	pub pressure_or_distance: f32,
+	pub contact_area_major: f32,
+	pub contact_area_minor: f32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct PenPacket {
+	pub event_type: u8,
+	pub tool_type: u8,
+	pub pen_buttons: u8,
+	pub x: f32,
+	pub y: f32,
+	pub pressure_or_distance: f32,
+	pub rotation: u16,
+	pub tilt: u8,
+	pub contact_area_major: f32,
+	pub contact_area_minor: f32,
+}
+
+/// Announces a gamepad that was connected on the client.
+#[derive(Debug, PartialEq)]
+pub struct ControllerArrivalPacket {
+	#[cfg_attr(not(feature = "uinput"), allow(dead_code))]
+	pub index: u8,
+	#[allow(dead_code)]
+	pub kind: u8,
+	#[allow(dead_code)]
+	pub capabilities: u16,
+	#[allow(dead_code)]
+	pub supported_buttons: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct ControllerTouchPacket {
+	pub index: u8,
+	pub event_type: u8,
+	pub pointer_id: u32,
+	pub x: f32,
+	pub y: f32,
+	pub pressure: f32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct ControllerMotionPacket {
+	pub index: u8,
+	pub motion_type: u8,
+	pub x: f32,
+	pub y: f32,
+	pub z: f32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct ControllerBatteryPacket {
+	pub index: u8,
+	pub state: u8,
+	pub percentage: u8,
+}
+
+/// Flags of the buttons in `ControllerUpdatePacket::button_flags`.
+#[derive(Copy, Clone, Debug, EnumIter, PartialEq)]
+#[repr(u32)]
+pub enum GamepadButton {
+	// Button flags.
+	Up              = 0x00000001,
+	Down            = 0x00000002,
+	Left            = 0x00000004,
+	Right           = 0x00000008,
+	Start           = 0x00000010,
+	Select          = 0x00000020,
+	LeftStickClick  = 0x00000040,
+	RightStickClick = 0x00000080,
+	LB              = 0x00000100,
+	RB              = 0x00000200,
+	Home            = 0x00000400,
+	A               = 0x00001000,
+	B               = 0x00002000,
+	X               = 0x00004000,
+	Y               = 0x00008000,
+
+	// Extended buttons (Sunshine / Moonshine only)
+	Paddle1  = 0x00010000,
+	Paddle2  = 0x00020000,
+	Paddle3  = 0x00040000,
+	Paddle4  = 0x00080000,
+	Touchpad = 0x00100000, // Touchpad buttons on Sony controllers.
+	Misc     = 0x00200000, // Share/Mic/Capture/Mute buttons on various controllers.
+}
+
+/// State of all buttons and axes of a gamepad.
+#[derive(Debug, PartialEq)]
+pub struct ControllerUpdatePacket {
+	pub index: u16,
+	#[allow(dead_code)]
+	pub active_gamepad_mask: u16,
+	pub button_flags: u32,
+	pub left_trigger: u8,
+	pub right_trigger: u8,
+	pub left_stick: (i16, i16),
+	pub right_stick: (i16, i16),
+}
+
+/// Packets that the input handler doesn't handle yet are still parsed completely, their fields allow dead code.
+#[derive(Debug, PartialEq)]
+pub enum InputPacket {
+	KeyDown(KeyboardPacket),
+	KeyUp(KeyboardPacket),
+	MouseMoveAbsolute(MouseMoveAbsolutePacket),
+	MouseMoveRelative(MouseMoveRelativePacket),
+	MouseButtonDown(MouseButtonPacket),
+	MouseButtonUp(MouseButtonPacket),
+	ScrollVertical(ScrollPacket),
+	ScrollHorizontal(ScrollPacket),
+	Utf8Text(Utf8TextPacket),
+	Touch(TouchPacket),
+	Pen(PenPacket),
+	ControllerArrival(ControllerArrivalPacket),
+	ControllerTouch(ControllerTouchPacket),
+	ControllerMotion(ControllerMotionPacket),
+	ControllerBattery(ControllerBatteryPacket),
+	ControllerUpdate(ControllerUpdatePacket),
+	EnableHaptics,
+}
+
+impl InputPacket {
+	/// Parse an input packet as it is sent by a client speaking the given protocol version.
+	pub fn from_bytes(buffer: &[u8], version: ProtocolVersion) -> Result<Self, ParseError> {
+		let mut reader = ByteReader::new(buffer);
+		let packet_type = reader.read_u32_le("packet type")?;
+		let packet_type = PacketType::from_repr(packet_type)
+			.filter(|packet_type| version == ProtocolVersion::Gen7Extended || !packet_type.is_extension())
+			.ok_or(ParseError::UnknownType(packet_type))?;
+
+		let packet = match packet_type {
+			PacketType::KeyDown => Self::KeyDown(read_keyboard(&mut reader)?),
+			PacketType::KeyUp => Self::KeyUp(read_keyboard(&mut reader)?),
+			PacketType::MouseMoveAbsolute => Self::MouseMoveAbsolute(MouseMoveAbsolutePacket {
+				x: reader.read_i16_be("x")?,
+				y: reader.read_i16_be("y")?,
+				width: { reader.skip("padding", 2)?; reader.read_i16_be("width")? },
+				height: reader.read_i16_be("height")?,
+			}),
+			PacketType::MouseMoveRelative => Self::MouseMoveRelative(MouseMoveRelativePacket {
+				x: reader.read_i16_be("x")?,
+				y: reader.read_i16_be("y")?,
+			}),
+			PacketType::MouseButtonDown => Self::MouseButtonDown(MouseButtonPacket { button: reader.read_u8("button")? }),
+			PacketType::MouseButtonUp => Self::MouseButtonUp(MouseButtonPacket { button: reader.read_u8("button")? }),
+			PacketType::ScrollVertical => Self::ScrollVertical(ScrollPacket { amount: reader.read_i16_be("scroll amount")? }),
+			PacketType::ScrollHorizontal => Self::ScrollHorizontal(ScrollPacket { amount: reader.read_i16_be("scroll amount")? }),
+			PacketType::Utf8Text => Self::Utf8Text(Utf8TextPacket {
+				text: String::from_utf8(reader.read_rest().to_vec())
+					.map_err(|_| ParseError::InvalidValue("text"))?,
+			}),
+			PacketType::Touch => Self::Touch(TouchPacket {
+				event_type: reader.read_u8("event type")?,
+				rotation: { reader.skip("padding", 1)?; reader.read_u16_le("rotation")? },
+				pointer_id: reader.read_u32_le("pointer id")?,
+				x: reader.read_f32_le("x")?,
+				y: reader.read_f32_le("y")?,
+				pressure_or_distance: reader.read_f32_le("pressure or distance")?,
+				contact_area_major: reader.read_f32_le("contact area major")?,
+				contact_area_minor: reader.read_f32_le("contact area minor")?,
+			}),
+			PacketType::Pen => Self::Pen(PenPacket {
+				event_type: reader.read_u8("event type")?,
+				tool_type: reader.read_u8("tool type")?,
+				pen_buttons: reader.read_u8("pen buttons")?,
+				x: { reader.skip("padding", 1)?; reader.read_f32_le("x")? },
+				y: reader.read_f32_le("y")?,
+				pressure_or_distance: reader.read_f32_le("pressure or distance")?,
+				rotation: reader.read_u16_le("rotation")?,
+				tilt: reader.read_u8("tilt")?,
+				contact_area_major: { reader.skip("padding", 1)?; reader.read_f32_le("contact area major")? },
+				contact_area_minor: reader.read_f32_le("contact area minor")?,
+			}),
+			PacketType::ControllerArrival => Self::ControllerArrival(ControllerArrivalPacket {
+				index: reader.read_u8("controller number")?,
+				kind: reader.read_u8("controller type")?,
+				capabilities: reader.read_u16_le("capabilities")?,
+				supported_buttons: reader.read_u32_le("supported buttons")?,
+			}),
+			PacketType::ControllerTouch => Self::ControllerTouch(ControllerTouchPacket {
+				index: reader.read_u8("controller number")?,
+				event_type: reader.read_u8("event type")?,
+				pointer_id: { reader.skip("padding", 2)?; reader.read_u32_le("pointer id")? },
+				x: reader.read_f32_le("x")?,
+				y: reader.read_f32_le("y")?,
+				pressure: reader.read_f32_le("pressure")?,
+			}),
+			PacketType::ControllerMotion => Self::ControllerMotion(ControllerMotionPacket {
+				index: reader.read_u8("controller number")?,
+				motion_type: reader.read_u8("motion type")?,
+				x: { reader.skip("padding", 2)?; reader.read_f32_le("x")? },
+				y: reader.read_f32_le("y")?,
+				z: reader.read_f32_le("z")?,
+			}),
+			PacketType::ControllerBattery => Self::ControllerBattery(ControllerBatteryPacket {
+				index: reader.read_u8("controller number")?,
+				state: reader.read_u8("battery state")?,
+				percentage: reader.read_u8("battery percentage")?,
+			}),
+			PacketType::MultiController => Self::ControllerUpdate(read_controller_update(&mut reader, version)?),
+			PacketType::EnableHaptics => Self::EnableHaptics,
+		};
+
+		Ok(packet)
+	}
+
+	/// Whether the packet is about a gamepad, as opposed to the keyboard, mouse, touch screen or pen.
+	pub fn is_gamepad(&self) -> bool {
+		matches!(
+			self,
+			Self::ControllerArrival(_)
+				| Self::ControllerTouch(_)
+				| Self::ControllerMotion(_)
+				| Self::ControllerBattery(_)
+				| Self::ControllerUpdate(_)
+		)
+	}
+}
+
+fn read_keyboard(reader: &mut ByteReader) -> Result<KeyboardPacket, ParseError> {
+	Ok(KeyboardPacket {
+		flags: reader.read_u8("flags")?,
+		key_code: reader.read_u16_le("key code")?,
+		modifiers: reader.read_u8("modifiers")?,
+	})
+}
+
+fn read_controller_update(reader: &mut ByteReader, version: ProtocolVersion) -> Result<ControllerUpdatePacket, ParseError> {
+	reader.skip("header", 2)?;
+	let index = reader.read_u16_le("controller number")?;
+	let active_gamepad_mask = reader.read_u16_le("active gamepad mask")?;
+	reader.skip("mid b", 2)?;
+	let button_flags = reader.read_u16_le("button flags")?;
+	let left_trigger = reader.read_u8("left trigger")?;
+	let right_trigger = reader.read_u8("right trigger")?;
+	let left_stick = (reader.read_i16_le("left stick x")?, reader.read_i16_le("left stick y")?);
+	let right_stick = (reader.read_i16_le("right stick x")?, reader.read_i16_le("right stick y")?);
+	reader.skip("tail a", 2)?;
+	let extended_button_flags = reader.read_u16_le("extended button flags")?;
+	reader.skip("tail b", 2)?;
+
+	// Only the extended protocol uses the second set of button flags, for paddles and other extra buttons.
+	let button_flags = match version {
+		ProtocolVersion::Gen7 => button_flags as u32,
+		ProtocolVersion::Gen7Extended => button_flags as u32 | (extended_button_flags as u32) << 16,
+	};
+
+	Ok(ControllerUpdatePacket {
+		index,
+		active_gamepad_mask,
+		button_flags,
+		left_trigger,
+		right_trigger,
+		left_stick,
+		right_stick,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use proptest::prelude::*;
+
+	use super::*;
+
+	/// Encode a packet the way Moonlight clients do, without the length that precedes it in the control message.
+	fn to_bytes(packet: &InputPacket) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		let mut put_type = |packet_type: PacketType| bytes.extend_from_slice(&(packet_type as u32).to_le_bytes());
+		match packet {
+			InputPacket::KeyDown(_) => put_type(PacketType::KeyDown),
+			InputPacket::KeyUp(_) => put_type(PacketType::KeyUp),
+			InputPacket::MouseMoveAbsolute(_) => put_type(PacketType::MouseMoveAbsolute),
+			InputPacket::MouseMoveRelative(_) => put_type(PacketType::MouseMoveRelative),
+			InputPacket::MouseButtonDown(_) => put_type(PacketType::MouseButtonDown),
+			InputPacket::MouseButtonUp(_) => put_type(PacketType::MouseButtonUp),
+			InputPacket::ScrollVertical(_) => put_type(PacketType::ScrollVertical),
+			InputPacket::ScrollHorizontal(_) => put_type(PacketType::ScrollHorizontal),
+			InputPacket::Utf8Text(_) => put_type(PacketType::Utf8Text),
+			InputPacket::Touch(_) => put_type(PacketType::Touch),
+			InputPacket::Pen(_) => put_type(PacketType::Pen),
+			InputPacket::ControllerArrival(_) => put_type(PacketType::ControllerArrival),
+			InputPacket::ControllerTouch(_) => put_type(PacketType::ControllerTouch),
+			InputPacket::ControllerMotion(_) => put_type(PacketType::ControllerMotion),
+			InputPacket::ControllerBattery(_) => put_type(PacketType::ControllerBattery),
+			InputPacket::ControllerUpdate(_) => put_type(PacketType::MultiController),
+			InputPacket::EnableHaptics => put_type(PacketType::EnableHaptics),
+		}
+
+		match packet {
+			InputPacket::KeyDown(key) | InputPacket::KeyUp(key) => {
+				bytes.push(key.flags);
+				bytes.extend_from_slice(&key.key_code.to_le_bytes());
+				bytes.push(key.modifiers);
+				bytes.extend_from_slice(&[0; 2]);
+			},
+			InputPacket::MouseMoveAbsolute(mouse) => {
+				bytes.extend_from_slice(&mouse.x.to_be_bytes());
+				bytes.extend_from_slice(&mouse.y.to_be_bytes());
+				bytes.extend_from_slice(&[0; 2]);
+				bytes.extend_from_slice(&mouse.width.to_be_bytes());
+				bytes.extend_from_slice(&mouse.height.to_be_bytes());
+			},
+			InputPacket::MouseMoveRelative(mouse) => {
+				bytes.extend_from_slice(&mouse.x.to_be_bytes());
+				bytes.extend_from_slice(&mouse.y.to_be_bytes());
+			},
+			InputPacket::MouseButtonDown(mouse) | InputPacket::MouseButtonUp(mouse) => bytes.push(mouse.button),
+			InputPacket::ScrollVertical(scroll) | InputPacket::ScrollHorizontal(scroll) => {
+				bytes.extend_from_slice(&scroll.amount.to_be_bytes());
+				bytes.extend_from_slice(&scroll.amount.to_be_bytes());
+				bytes.extend_from_slice(&[0; 2]);
+			},
+			InputPacket::Utf8Text(text) => bytes.extend_from_slice(text.text.as_bytes()),
+			InputPacket::Touch(touch) => {
+				bytes.push(touch.event_type);
+				bytes.push(0);
+				bytes.extend_from_slice(&touch.rotation.to_le_bytes());
+				bytes.extend_from_slice(&touch.pointer_id.to_le_bytes());
+				for value in [touch.x, touch.y, touch.pressure_or_distance, touch.contact_area_major, touch.contact_area_minor] {
+					bytes.extend_from_slice(&value.to_le_bytes());
+				}
+			},
+			InputPacket::Pen(pen) => {
+				bytes.extend_from_slice(&[pen.event_type, pen.tool_type, pen.pen_buttons, 0]);
+				for value in [pen.x, pen.y, pen.pressure_or_distance] {
+					bytes.extend_from_slice(&value.to_le_bytes());
+				}
+				bytes.extend_from_slice(&pen.rotation.to_le_bytes());
+				bytes.extend_from_slice(&[pen.tilt, 0]);
+				bytes.extend_from_slice(&pen.contact_area_major.to_le_bytes());
+				bytes.extend_from_slice(&pen.contact_area_minor.to_le_bytes());
+			},
+			InputPacket::ControllerArrival(arrival) => {
+				bytes.extend_from_slice(&[arrival.index, arrival.kind]);
+				bytes.extend_from_slice(&arrival.capabilities.to_le_bytes());
+				bytes.extend_from_slice(&arrival.supported_buttons.to_le_bytes());
+			},
+			InputPacket::ControllerTouch(touch) => {
+				bytes.extend_from_slice(&[touch.index, touch.event_type, 0, 0]);
+				bytes.extend_from_slice(&touch.pointer_id.to_le_bytes());
+				for value in [touch.x, touch.y, touch.pressure] {
+					bytes.extend_from_slice(&value.to_le_bytes());
+				}
+			},
+			InputPacket::ControllerMotion(motion) => {
+				bytes.extend_from_slice(&[motion.index, motion.motion_type, 0, 0]);
+				for value in [motion.x, motion.y, motion.z] {
+					bytes.extend_from_slice(&value.to_le_bytes());
+				}
+			},
+			InputPacket::ControllerBattery(battery) => {
+				bytes.extend_from_slice(&[battery.index, battery.state, battery.percentage]);
+			},
+			InputPacket::ControllerUpdate(update) => {
+				bytes.extend_from_slice(&0x001Au16.to_le_bytes());
+				bytes.extend_from_slice(&update.index.to_le_bytes());
+				bytes.extend_from_slice(&update.active_gamepad_mask.to_le_bytes());
+				bytes.extend_from_slice(&0x0014u16.to_le_bytes());
+				bytes.extend_from_slice(&(update.button_flags as u16).to_le_bytes());
+				bytes.extend_from_slice(&[update.left_trigger, update.right_trigger]);
+				for value in [update.left_stick.0, update.left_stick.1, update.right_stick.0, update.right_stick.1] {
+					bytes.extend_from_slice(&value.to_le_bytes());
+				}
+				bytes.extend_from_slice(&0x009Cu16.to_le_bytes());
+				bytes.extend_from_slice(&((update.button_flags >> 16) as u16).to_le_bytes());
+				bytes.extend_from_slice(&0x0055u16.to_le_bytes());
+			},
+			InputPacket::EnableHaptics => {},
+		}
+
+		bytes
+	}
+
+	#[test]
+	fn key_down() {
+		let bytes = [0x03, 0x00, 0x00, 0x00, 0x00, 0x41, 0x80, 0x01, 0x00, 0x00];
+		assert_eq!(
+			InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7),
+			Ok(InputPacket::KeyDown(KeyboardPacket { flags: 0, key_code: 0x8041, modifiers: 0x01 })),
+		);
+	}
+
+	#[test]
+	fn mouse_move() {
+		let bytes = [0x07, 0x00, 0x00, 0x00, 0xFF, 0xF6, 0x00, 0x05];
+		assert_eq!(
+			InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7),
+			Ok(InputPacket::MouseMoveRelative(MouseMoveRelativePacket { x: -10, y: 5 })),
+		);
+
+		let bytes = [0x05, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x80, 0x00, 0x00, 0x07, 0x80, 0x04, 0x38];
+		assert_eq!(
+			InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7),
+			Ok(InputPacket::MouseMoveAbsolute(MouseMoveAbsolutePacket { x: 256, y: 128, width: 1920, height: 1080 })),
+		);
+	}
+
+	#[test]
+	fn scroll() {
+		let bytes = [0x0A, 0x00, 0x00, 0x00, 0x00, 0x78, 0x00, 0x78, 0x00, 0x00];
+		assert_eq!(
+			InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7),
+			Ok(InputPacket::ScrollVertical(ScrollPacket { amount: 120 })),
+		);
+	}
+
+	#[test]
+	fn utf8_text() {
+		let bytes = [0x17, 0x00, 0x00, 0x00, 0xC3, 0xA9];
+		assert_eq!(
+			InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7),
+			Ok(InputPacket::Utf8Text(Utf8TextPacket { text: "é".to_string() })),
+		);
+
+		let bytes = [0x17, 0x00, 0x00, 0x00, 0xC3];
+		assert_eq!(InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7), Err(ParseError::InvalidValue("text")));
+	}
+
+	#[test]
+	fn controller_update() {
+		let bytes = [
+			0x0C, 0x00, 0x00, 0x00,
+			0x1A, 0x00,
+			0x01, 0x00,
+			0x03, 0x00,
+			0x14, 0x00,
+			0x00, 0x10,
+			0xFF, 0x00,
+			0x00, 0x80, 0xFF, 0x7F,
+			0x00, 0x00, 0x00, 0x00,
+			0x9C, 0x00,
+			0x01, 0x00,
+			0x55, 0x00,
+		];
+		let packet = |button_flags| InputPacket::ControllerUpdate(ControllerUpdatePacket {
+			index: 1,
+			active_gamepad_mask: 0b11,
+			button_flags,
+			left_trigger: 0xFF,
+			right_trigger: 0,
+			left_stick: (i16::MIN, i16::MAX),
+			right_stick: (0, 0),
+		});
+
+		// Only the extended protocol has paddles, older clients send garbage in the extended button flags.
+		assert_eq!(InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7), Ok(packet(GamepadButton::A as u32)));
+		assert_eq!(
+			InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7Extended),
+			Ok(packet(GamepadButton::A as u32 | GamepadButton::Paddle1 as u32)),
+		);
+	}
+
+	#[test]
+	fn extensions_require_extended_protocol() {
+		let packet = InputPacket::ControllerBattery(ControllerBatteryPacket { index: 0, state: 1, percentage: 50 });
+		let bytes = to_bytes(&packet);
+		assert_eq!(InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7), Err(ParseError::UnknownType(0x55000007)));
+		assert_eq!(InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7Extended), Ok(packet));
+	}
+
+	#[test]
+	fn unknown_type() {
+		let bytes = [0x42, 0x00, 0x00, 0x00];
+		assert_eq!(InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7Extended), Err(ParseError::UnknownType(0x42)));
+	}
+
+	#[test]
+	fn truncated() {
+		let packet = InputPacket::Touch(TouchPacket {
+			event_type: 1,
+			rotation: 90,
+			pointer_id: 2,
+			x: 0.5,
+			y: 0.25,
+			pressure_or_distance: 1.0,
+			contact_area_major: 0.1,
+			contact_area_minor: 0.1,
+		});
+		let bytes = to_bytes(&packet);
+		for length in 0..bytes.len() {
+			assert!(
+				matches!(InputPacket::from_bytes(&bytes[..length], ProtocolVersion::Gen7Extended), Err(ParseError::UnexpectedEnd { .. })),
+				"packet truncated to {length} bytes was parsed",
+			);
+		}
+		assert_eq!(InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7Extended), Ok(packet));
+	}
+
+	fn float() -> impl Strategy<Value = f32> {
+		-1.0e6f32..1.0e6f32
+	}
+
+	fn keyboard() -> impl Strategy<Value = KeyboardPacket> {
+		(any::<u8>(), any::<u16>(), any::<u8>()).prop_map(|(flags, key_code, modifiers)| KeyboardPacket { flags, key_code, modifiers })
+	}
+
+	/// Packets of the original protocol, which every client can send.
+	fn gen7_packet() -> impl Strategy<Value = InputPacket> {
+		prop_oneof![
+			keyboard().prop_map(InputPacket::KeyDown),
+			keyboard().prop_map(InputPacket::KeyUp),
+			any::<(i16, i16, i16, i16)>().prop_map(|(x, y, width, height)| {
+				InputPacket::MouseMoveAbsolute(MouseMoveAbsolutePacket { x, y, width, height })
+			}),
+			any::<(i16, i16)>().prop_map(|(x, y)| InputPacket::MouseMoveRelative(MouseMoveRelativePacket { x, y })),
+			any::<u8>().prop_map(|button| InputPacket::MouseButtonDown(MouseButtonPacket { button })),
+			any::<u8>().prop_map(|button| InputPacket::MouseButtonUp(MouseButtonPacket { button })),
+			any::<i16>().prop_map(|amount| InputPacket::ScrollVertical(ScrollPacket { amount })),
+			".*".prop_map(|text| InputPacket::Utf8Text(Utf8TextPacket { text })),
+			(any::<u16>(), any::<u16>(), any::<u16>(), any::<u8>(), any::<u8>(), any::<(i16, i16, i16, i16)>()).prop_map(
+				|(index, active_gamepad_mask, button_flags, left_trigger, right_trigger, sticks)| {
+					InputPacket::ControllerUpdate(ControllerUpdatePacket {
+						index,
+						active_gamepad_mask,
+						button_flags: button_flags as u32,
+						left_trigger,
+						right_trigger,
+						left_stick: (sticks.0, sticks.1),
+						right_stick: (sticks.2, sticks.3),
+					})
+				},
+			),
+			Just(()).prop_map(|()| InputPacket::EnableHaptics),
+		]
+	}
+
+	/// Packets that only clients speaking the extended protocol send.
+	fn extended_packet() -> impl Strategy<Value = InputPacket> {
+		prop_oneof![
+			any::<i16>().prop_map(|amount| InputPacket::ScrollHorizontal(ScrollPacket { amount })),
+			(any::<(u8, u16, u32)>(), float(), float(), float(), float(), float()).prop_map(
+				|((event_type, rotation, pointer_id), x, y, pressure_or_distance, contact_area_major, contact_area_minor)| {
+					InputPacket::Touch(TouchPacket { event_type, rotation, pointer_id, x, y, pressure_or_distance, contact_area_major, contact_area_minor })
+				},
+			),
+			(any::<(u8, u8, u8, u16, u8)>(), float(), float(), float(), float(), float()).prop_map(
+				|((event_type, tool_type, pen_buttons, rotation, tilt), x, y, pressure_or_distance, contact_area_major, contact_area_minor)| {
+					InputPacket::Pen(PenPacket {
+						event_type,
+						tool_type,
+						pen_buttons,
+						x,
+						y,
+						pressure_or_distance,
+						rotation,
+						tilt,
+						contact_area_major,
+						contact_area_minor,
+					})
+				},
+			),
+			any::<(u8, u8, u16, u32)>().prop_map(|(index, kind, capabilities, supported_buttons)| {
+				InputPacket::ControllerArrival(ControllerArrivalPacket { index, kind, capabilities, supported_buttons })
+			}),
+			(any::<(u8, u8, u32)>(), float(), float(), float()).prop_map(|((index, event_type, pointer_id), x, y, pressure)| {
+				InputPacket::ControllerTouch(ControllerTouchPacket { index, event_type, pointer_id, x, y, pressure })
+			}),
+			(any::<(u8, u8)>(), float(), float(), float()).prop_map(|((index, motion_type), x, y, z)| {
+				InputPacket::ControllerMotion(ControllerMotionPacket { index, motion_type, x, y, z })
+			}),
+			any::<(u8, u8, u8)>().prop_map(|(index, state, percentage)| {
+				InputPacket::ControllerBattery(ControllerBatteryPacket { index, state, percentage })
+			}),
+			(any::<u16>(), any::<u16>(), any::<u32>(), any::<u8>(), any::<u8>(), any::<(i16, i16, i16, i16)>()).prop_map(
+				|(index, active_gamepad_mask, button_flags, left_trigger, right_trigger, sticks)| {
+					InputPacket::ControllerUpdate(ControllerUpdatePacket {
+						index,
+						active_gamepad_mask,
+						button_flags,
+						left_trigger,
+						right_trigger,
+						left_stick: (sticks.0, sticks.1),
+						right_stick: (sticks.2, sticks.3),
+					})
+				},
+			),
+		]
+	}
+
+	proptest! {
+		#[test]
+		fn gen7_round_trip(packet in gen7_packet()) {
+			let bytes = to_bytes(&packet);
+			prop_assert_eq!(InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7), Ok(packet));
+		}
+
+		#[test]
+		fn extended_round_trip(packet in prop_oneof![gen7_packet(), extended_packet()]) {
+			let bytes = to_bytes(&packet);
+			prop_assert_eq!(InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7Extended), Ok(packet));
+		}
+
+		#[test]
+		fn arbitrary_input_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+			let _ = InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7);
+			let _ = InputPacket::from_bytes(&bytes, ProtocolVersion::Gen7Extended);
+		}
+	}
+}
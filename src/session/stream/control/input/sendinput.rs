@@ -0,0 +1,121 @@
+//! Input injection on Windows through `SendInput`.
+
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+	SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, INPUT_MOUSE, KEYBDINPUT, KEYBD_EVENT_FLAGS, KEYEVENTF_EXTENDEDKEY,
+	KEYEVENTF_KEYUP, MOUSEEVENTF_ABSOLUTE, MOUSEEVENTF_HWHEEL, MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP,
+	MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP, MOUSEEVENTF_MOVE, MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP,
+	MOUSEEVENTF_VIRTUALDESK, MOUSEEVENTF_WHEEL, MOUSEEVENTF_XDOWN, MOUSEEVENTF_XUP, MOUSEINPUT, MOUSE_EVENT_FLAGS,
+	VIRTUAL_KEY,
+};
+
+use super::{backend::InputBackend, keyboard::Key, mouse::MouseButton};
+
+/// Range of the normalized coordinates of absolute mouse movements.
+const ABSOLUTE_RANGE: i32 = 65535;
+
+/// Injects input as if it came from the physical keyboard and mouse.
+pub struct SendInputBackend;
+
+impl SendInputBackend {
+	fn key(&self, key: Key, up: bool) -> Result<(), ()> {
+		// Clients send Windows virtual key codes, so they can be injected as they are.
+		let code = key as u8;
+
+		let mut flags = if up { KEYEVENTF_KEYUP } else { KEYBD_EVENT_FLAGS(0) };
+		if is_extended_key(code) {
+			flags |= KEYEVENTF_EXTENDEDKEY;
+		}
+
+		send(INPUT {
+			r#type: INPUT_KEYBOARD,
+			Anonymous: INPUT_0 {
+				ki: KEYBDINPUT { wVk: VIRTUAL_KEY(code as u16), wScan: 0, dwFlags: flags, time: 0, dwExtraInfo: 0 },
+			},
+		})
+	}
+
+	fn mouse(&self, dx: i32, dy: i32, data: i32, flags: MOUSE_EVENT_FLAGS) -> Result<(), ()> {
+		send(INPUT {
+			r#type: INPUT_MOUSE,
+			Anonymous: INPUT_0 {
+				mi: MOUSEINPUT { dx, dy, mouseData: data as u32, dwFlags: flags, time: 0, dwExtraInfo: 0 },
+			},
+		})
+	}
+
+	fn button(&self, button: MouseButton, up: bool) -> Result<(), ()> {
+		let (flags, data) = match (button, up) {
+			(MouseButton::Left, false) => (MOUSEEVENTF_LEFTDOWN, 0),
+			(MouseButton::Left, true) => (MOUSEEVENTF_LEFTUP, 0),
+			(MouseButton::Middle, false) => (MOUSEEVENTF_MIDDLEDOWN, 0),
+			(MouseButton::Middle, true) => (MOUSEEVENTF_MIDDLEUP, 0),
+			(MouseButton::Right, false) => (MOUSEEVENTF_RIGHTDOWN, 0),
+			(MouseButton::Right, true) => (MOUSEEVENTF_RIGHTUP, 0),
+			(MouseButton::Side, false) => (MOUSEEVENTF_XDOWN, 1),
+			(MouseButton::Side, true) => (MOUSEEVENTF_XUP, 1),
+			(MouseButton::Extra, false) => (MOUSEEVENTF_XDOWN, 2),
+			(MouseButton::Extra, true) => (MOUSEEVENTF_XUP, 2),
+		};
+
+		self.mouse(0, 0, data, flags)
+	}
+}
+
+impl InputBackend for SendInputBackend {
+	fn key_down(&mut self, key: Key) -> Result<(), ()> {
+		self.key(key, false)
+	}
+
+	fn key_up(&mut self, key: Key) -> Result<(), ()> {
+		self.key(key, true)
+	}
+
+	fn move_relative(&mut self, x: i32, y: i32) -> Result<(), ()> {
+		self.mouse(x, y, 0, MOUSEEVENTF_MOVE)
+	}
+
+	fn move_absolute(&mut self, x: i32, y: i32, width: i32, height: i32) -> Result<(), ()> {
+		if width <= 0 || height <= 0 {
+			return Ok(());
+		}
+
+		self.mouse(
+			x * ABSOLUTE_RANGE / width,
+			y * ABSOLUTE_RANGE / height,
+			0,
+			MOUSEEVENTF_MOVE | MOUSEEVENTF_ABSOLUTE | MOUSEEVENTF_VIRTUALDESK,
+		)
+	}
+
+	fn button_down(&mut self, button: MouseButton) -> Result<(), ()> {
+		self.button(button, false)
+	}
+
+	fn button_up(&mut self, button: MouseButton) -> Result<(), ()> {
+		self.button(button, true)
+	}
+
+	fn scroll_vertical(&mut self, amount: i16) -> Result<(), ()> {
+		self.mouse(0, 0, amount as i32, MOUSEEVENTF_WHEEL)
+	}
+
+	fn scroll_horizontal(&mut self, amount: i16) -> Result<(), ()> {
+		self.mouse(0, 0, amount as i32, MOUSEEVENTF_HWHEEL)
+	}
+}
+
+fn send(input: INPUT) -> Result<(), ()> {
+	let sent = unsafe { SendInput(&[input], std::mem::size_of::<INPUT>() as i32) };
+	if sent != 1 {
+		tracing::error!("Failed to send input: {}", std::io::Error::last_os_error());
+		return Err(());
+	}
+
+	Ok(())
+}
+
+/// Whether a virtual key is on the extended part of the keyboard, these are injected with a different scan code.
+fn is_extended_key(code: u8) -> bool {
+	// Page up/down, end, home, arrows, insert, delete and the meta keys.
+	matches!(code, 0x21..=0x28 | 0x2D | 0x2E | 0x5B | 0x5C)
+}
@@ -0,0 +1,269 @@
+//! Deadlines of the control stream, which stop the stream when the client goes quiet or doesn't reconnect.
+
+use std::time::{Duration, Instant};
+
+use crate::session::Clock;
+
+/// Outcome of checking the deadlines.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeepaliveStatus {
+	Alive,
+
+	/// The stream times out in this much time, reported once until the deadline is extended.
+	Warning(Duration),
+
+	/// No ping was received within the stream timeout.
+	TimedOut,
+
+	/// The client didn't reconnect within the reconnect window.
+	ReconnectExpired,
+}
+
+pub struct Keepalive<C: Clock> {
+	clock: C,
+
+	/// Time without pings after which the stream stops.
+	stream_timeout: Duration,
+
+	/// Time before the stream times out at which the timeout is announced, zero to not announce it.
+	timeout_warning: Duration,
+
+	/// If set, the client is pinged at this interval while it is connected.
+	host_ping_interval: Option<Duration>,
+
+	/// Time a disconnected client has to reconnect.
+	reconnect_window: Duration,
+
+	stop_deadline: Instant,
+
+	/// Whether the upcoming timeout was already announced, reset whenever the deadline is extended.
+	timeout_warned: bool,
+
+	/// Set while the client is disconnected, the stream stops if it doesn't reconnect before this deadline.
+	reconnect_deadline: Option<Instant>,
+
+	next_host_ping: Option<Instant>,
+}
+
+impl<C: Clock> Keepalive<C> {
+	pub fn new(
+		clock: C,
+		stream_timeout: Duration,
+		timeout_warning: Duration,
+		host_ping_interval: Option<Duration>,
+		reconnect_window: Duration,
+	) -> Self {
+		let stop_deadline = clock.now() + stream_timeout;
+		Self {
+			clock,
+			stream_timeout,
+			timeout_warning,
+			host_ping_interval,
+			reconnect_window,
+			stop_deadline,
+			timeout_warned: false,
+			reconnect_deadline: None,
+			next_host_ping: None,
+		}
+	}
+
+	pub fn now(&self) -> Instant {
+		self.clock.now()
+	}
+
+	/// Check the deadlines, pings aren't expected while the client is disconnected.
+	pub fn check(&mut self) -> KeepaliveStatus {
+		let now = self.clock.now();
+		if let Some(deadline) = self.reconnect_deadline {
+			return if now > deadline { KeepaliveStatus::ReconnectExpired } else { KeepaliveStatus::Alive };
+		}
+
+		if now > self.stop_deadline {
+			return KeepaliveStatus::TimedOut;
+		}
+
+		let remaining = self.stop_deadline.saturating_duration_since(now);
+		if !self.timeout_warned && !self.timeout_warning.is_zero() && remaining <= self.timeout_warning {
+			self.timeout_warned = true;
+			return KeepaliveStatus::Warning(remaining);
+		}
+
+		KeepaliveStatus::Alive
+	}
+
+	/// A message was received at `received`.
+	///
+	/// Pings keep the stream alive, and while the host pings the client, so does any other message.
+	pub fn message_received(&mut self, received: Instant, is_ping: bool) {
+		if self.host_ping_interval.is_some() || is_ping {
+			self.extend(received);
+		}
+	}
+
+	/// The client disconnected, it has until the end of the reconnect window to come back.
+	pub fn disconnected(&mut self) {
+		self.reconnect_deadline = Some(self.clock.now() + self.reconnect_window);
+	}
+
+	/// The client connected, returns whether it reconnected after a disconnect.
+	pub fn connected(&mut self) -> bool {
+		if self.reconnect_deadline.take().is_none() {
+			return false;
+		}
+
+		self.extend(self.clock.now());
+		true
+	}
+
+	/// Whether the host should ping the client now, in which case the next ping is scheduled.
+	pub fn host_ping_due(&mut self) -> bool {
+		let Some(interval) = self.host_ping_interval else {
+			return false;
+		};
+
+		let now = self.clock.now();
+		if self.next_host_ping.is_some_and(|next| now < next) {
+			return false;
+		}
+
+		self.next_host_ping = Some(now + interval);
+		true
+	}
+
+	fn extend(&mut self, from: Instant) {
+		self.stop_deadline = from + self.stream_timeout;
+		self.timeout_warned = false;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+
+	const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+	const TIMEOUT_WARNING: Duration = Duration::from_secs(10);
+	const RECONNECT_WINDOW: Duration = Duration::from_secs(5);
+	const HOST_PING_INTERVAL: Duration = Duration::from_secs(2);
+
+	/// Clock that only moves when the test advances it.
+	#[derive(Clone)]
+	struct ManualClock(Arc<Mutex<Instant>>);
+
+	impl ManualClock {
+		fn new() -> Self {
+			Self(Arc::new(Mutex::new(Instant::now())))
+		}
+
+		fn advance(&self, duration: Duration) {
+			*self.0.lock().unwrap() += duration;
+		}
+	}
+
+	impl Clock for ManualClock {
+		fn now(&self) -> Instant {
+			*self.0.lock().unwrap()
+		}
+	}
+
+	fn keepalive(host_ping_interval: Option<Duration>) -> (Keepalive<ManualClock>, ManualClock) {
+		let clock = ManualClock::new();
+		(Keepalive::new(clock.clone(), STREAM_TIMEOUT, TIMEOUT_WARNING, host_ping_interval, RECONNECT_WINDOW), clock)
+	}
+
+	#[test]
+	fn warns_once_then_times_out() {
+		let (mut keepalive, clock) = keepalive(None);
+		assert_eq!(keepalive.check(), KeepaliveStatus::Alive);
+
+		clock.advance(STREAM_TIMEOUT - TIMEOUT_WARNING - Duration::from_secs(1));
+		assert_eq!(keepalive.check(), KeepaliveStatus::Alive);
+
+		clock.advance(Duration::from_secs(2));
+		assert_eq!(keepalive.check(), KeepaliveStatus::Warning(TIMEOUT_WARNING - Duration::from_secs(1)));
+		assert_eq!(keepalive.check(), KeepaliveStatus::Alive);
+
+		clock.advance(TIMEOUT_WARNING);
+		assert_eq!(keepalive.check(), KeepaliveStatus::TimedOut);
+	}
+
+	#[test]
+	fn pings_extend_the_deadline() {
+		let (mut keepalive, clock) = keepalive(None);
+		clock.advance(STREAM_TIMEOUT - Duration::from_secs(1));
+		assert!(matches!(keepalive.check(), KeepaliveStatus::Warning(_)));
+
+		// Other messages don't count while the host doesn't ping, a ping extends the deadline and warns again later.
+		keepalive.message_received(keepalive.now(), false);
+		clock.advance(Duration::from_secs(2));
+		assert_eq!(keepalive.check(), KeepaliveStatus::TimedOut);
+
+		keepalive.message_received(keepalive.now(), true);
+		assert_eq!(keepalive.check(), KeepaliveStatus::Alive);
+		clock.advance(STREAM_TIMEOUT - TIMEOUT_WARNING);
+		assert_eq!(keepalive.check(), KeepaliveStatus::Warning(TIMEOUT_WARNING));
+	}
+
+	#[test]
+	fn any_message_counts_while_host_pings() {
+		let (mut keepalive, clock) = keepalive(Some(HOST_PING_INTERVAL));
+		clock.advance(STREAM_TIMEOUT);
+		keepalive.message_received(keepalive.now(), false);
+		clock.advance(STREAM_TIMEOUT - TIMEOUT_WARNING - Duration::from_secs(1));
+		assert_eq!(keepalive.check(), KeepaliveStatus::Alive);
+	}
+
+	#[test]
+	fn reconnect_window_expires() {
+		let (mut keepalive, clock) = keepalive(None);
+		keepalive.disconnected();
+
+		// No pings are expected while disconnected, only the reconnect window counts.
+		clock.advance(RECONNECT_WINDOW);
+		assert_eq!(keepalive.check(), KeepaliveStatus::Alive);
+		clock.advance(Duration::from_millis(1));
+		assert_eq!(keepalive.check(), KeepaliveStatus::ReconnectExpired);
+	}
+
+	#[test]
+	fn reconnect_restarts_the_stream_timeout() {
+		let (mut keepalive, clock) = keepalive(None);
+		assert!(!keepalive.connected());
+
+		clock.advance(STREAM_TIMEOUT - Duration::from_secs(1));
+		keepalive.disconnected();
+		clock.advance(RECONNECT_WINDOW - Duration::from_secs(1));
+		assert!(keepalive.connected());
+		assert!(!keepalive.connected());
+
+		// The client has a full stream timeout from the reconnect, although the old deadline passed while it was away.
+		assert_eq!(keepalive.check(), KeepaliveStatus::Alive);
+		clock.advance(STREAM_TIMEOUT - TIMEOUT_WARNING);
+		assert_eq!(keepalive.check(), KeepaliveStatus::Warning(TIMEOUT_WARNING));
+		clock.advance(TIMEOUT_WARNING + Duration::from_millis(1));
+		assert_eq!(keepalive.check(), KeepaliveStatus::TimedOut);
+	}
+
+	#[test]
+	fn host_pings_are_scheduled() {
+		let (mut without_host_pings, _) = keepalive(None);
+		assert!(!without_host_pings.host_ping_due());
+
+		let (mut keepalive, clock) = keepalive(Some(HOST_PING_INTERVAL));
+		assert!(keepalive.host_ping_due());
+		assert!(!keepalive.host_ping_due());
+
+		clock.advance(HOST_PING_INTERVAL - Duration::from_millis(1));
+		assert!(!keepalive.host_ping_due());
+		clock.advance(Duration::from_millis(1));
+		assert!(keepalive.host_ping_due());
+
+		// A late check schedules the next ping from now, instead of catching up on missed pings.
+		clock.advance(HOST_PING_INTERVAL * 3);
+		assert!(keepalive.host_ping_due());
+		assert!(!keepalive.host_ping_due());
+		clock.advance(HOST_PING_INTERVAL);
+		assert!(keepalive.host_ping_due());
+	}
+}
@@ -1,24 +1,27 @@
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
 use async_shutdown::ShutdownManager;
-use enet::{
-	Address,
-	BandwidthLimit,
-	ChannelLimit,
-	Enet,
-	Event,
-};
-use openssl::symm::Cipher;
-use tokio::sync::mpsc::{self, error::TryRecvError};
-
-use crate::{session::{SessionContext, SessionKeys}, config::Config};
-use self::input::InputHandler;
-use super::{VideoStream, AudioStream};
+use tokio::sync::{mpsc::{self, error::TryRecvError}, oneshot};
+
+use crate::{session::{gamepads::GamepadSlots, guests::Guests, stats::SessionStats, Clock, SessionContext, SessionKeys, SystemClock}, config::{Config, QosConfig}, crypto, events::{EventBus, SessionEvent}, supervisor::Supervisor, transcript::Transcript};
+use self::{input::InputHandler, keepalive::{Keepalive, KeepaliveStatus}, reader::{ByteReader, ParseError}, transport::{ControlEvent, ControlHost}};
+use super::{VideoStream, AudioStream, video::FrameStats};
 
+mod channel;
 mod input;
+mod keepalive;
+mod reader;
+mod transport;
 
-const ENCRYPTION_TAG_LENGTH: usize = 16;
+pub use self::transport::TransportContext;
+
+const ENCRYPTION_TAG_LENGTH: usize = crypto::GCM_TAG_LENGTH;
 // Sequence number + tag + control message id
 const MINIMUM_ENCRYPTED_LENGTH: usize = 4 + ENCRYPTION_TAG_LENGTH + 4;
 
+/// Minimum time between two input notifications to the video stream, which only needs to know if input is still arriving.
+const INPUT_NOTIFICATION_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
 #[repr(u16)]
 enum ControlMessageType {
 	Encrypted = 0x0001,
@@ -32,6 +35,32 @@ enum ControlMessageType {
 	RequestIdrFrame = 0x0302,
 	StartA = 0x0305,
 	StartB = 0x0307,
+	/// Sent by the server to end the stream, the payload is the reason as an error code (u32 BE).
+	StreamTermination = 0x0109,
+}
+
+/// Reason for the server to end a stream, which the client shows to the user.
+#[derive(Clone, Copy, Debug)]
+pub enum TerminationReason {
+	/// The application quit normally.
+	ApplicationExited,
+
+	/// The application quit with an error or crashed.
+	ApplicationCrashed,
+
+	/// The encoder couldn't be started.
+	StartFailed,
+}
+
+impl TerminationReason {
+	fn error_code(&self) -> u32 {
+		match self {
+			// Clients treat this code as a graceful termination.
+			Self::ApplicationExited => 0x80030023,
+			// Clients report this code as an unexpected termination.
+			Self::ApplicationCrashed | Self::StartFailed => 0x800e9302,
+		}
+	}
 }
 
 impl TryFrom<u16> for ControlMessageType {
@@ -61,69 +90,91 @@ enum ControlMessage<'a> {
 	Ping,
 	Termination,
 	RumbleData,
-	LossStats,
-	FrameStats,
+	/// Number of packets the client lost since its previous report.
+	LossStats(u32),
+	/// How timely the client receives frames.
+	FrameStats(FrameStats),
 	InputData(&'a [u8]),
-	InvalidateReferenceFrames,
+	/// First and last frame that the client couldn't decode.
+	InvalidateReferenceFrames { first_frame: u64, last_frame: u64 },
 	RequestIdrFrame,
 	StartA,
 	StartB,
 }
 
 impl<'a> ControlMessage<'a> {
-	fn from_bytes(buffer: &'a [u8]) -> Result<Self, ()> {
-		if buffer.len() < 4 {
-			tracing::warn!("Expected control message to have at least 4 bytes, got {}", buffer.len());
-			return Err(());
+	fn from_bytes(buffer: &'a [u8]) -> Result<Self, ParseError> {
+		let mut reader = ByteReader::new(buffer);
+		let message_type = reader.read_u16_le("message type")?;
+		let length = reader.read_u16_le("message length")? as usize;
+		if length != reader.remaining() {
+			return Err(ParseError::LengthMismatch { field: "message length", expected: length, actual: reader.remaining() });
 		}
 
-		let length = u16::from_le_bytes(buffer[2..4].try_into().unwrap());
-		if length as usize != buffer.len() - 4 {
-			tracing::info!("Received incorrect packet length: expecting {length} bytes, but buffer says it should be {} bytes.", buffer.len() - 4);
-			return Err(());
-		}
-
-		match u16::from_le_bytes(buffer[..2].try_into().unwrap()).try_into()? {
+		let message_type: ControlMessageType = message_type.try_into()
+			.map_err(|()| ParseError::UnknownType(message_type as u32))?;
+		match message_type {
 			ControlMessageType::Encrypted => {
-				if buffer.len() < MINIMUM_ENCRYPTED_LENGTH {
-					tracing::info!("Expected encrypted control message of at least {MINIMUM_ENCRYPTED_LENGTH} bytes, got buffer of {} bytes.", buffer.len());
-					return Err(());
-				}
-
-				let length = u16::from_le_bytes(buffer[2..4].try_into().unwrap());
-				if (length as usize) < MINIMUM_ENCRYPTED_LENGTH {
-					tracing::info!("Expected encrypted control message of at least {MINIMUM_ENCRYPTED_LENGTH} bytes, got reported length of {length} bytes.");
-					return Err(());
+				if length < MINIMUM_ENCRYPTED_LENGTH {
+					return Err(ParseError::UnexpectedEnd {
+						field: "encrypted message",
+						needed: MINIMUM_ENCRYPTED_LENGTH,
+						remaining: length,
+					});
 				}
 
-				let sequence_number = u32::from_le_bytes(buffer[4..8].try_into().unwrap());
 				Ok(Self::Encrypted(EncryptedControlMessage {
-					_length: length,
-					sequence_number,
-					tag: buffer[8..8 + ENCRYPTION_TAG_LENGTH].try_into()
-						.map_err(|e| tracing::warn!("Failed to get tag from encrypted control message: {e}"))?,
-					payload: buffer[8 + ENCRYPTION_TAG_LENGTH..].to_vec(),
+					_length: length as u16,
+					sequence_number: reader.read_u32_le("sequence number")?,
+					tag: reader.read_array("tag")?,
+					payload: reader.read_rest().to_vec(),
 				}))
 			},
 			ControlMessageType::Ping => Ok(Self::Ping),
 			ControlMessageType::Termination => Ok(Self::Termination),
 			ControlMessageType::RumbleData => Ok(Self::RumbleData),
-			ControlMessageType::LossStats => Ok(Self::LossStats),
-			ControlMessageType::FrameStats => Ok(Self::FrameStats),
+			ControlMessageType::LossStats => Ok(Self::LossStats(reader.read_u32_le("lost packets")?)),
+			ControlMessageType::FrameStats => Ok(Self::FrameStats(FrameStats {
+				jitter: reader.read_u32_le("jitter")?,
+				queue_delay: reader.read_u32_le("queue delay")?,
+			})),
 			ControlMessageType::InputData => {
 				// Length of the input event, excluding the length itself.
-				let length = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
-				if length as usize != buffer.len() - 8 {
-					tracing::info!("Failed to interpret input event message: expected {length} bytes, but buffer has {} bytes left.", buffer.len() - 8);
-					return Err(());
+				let length = reader.read_u32_be("input event length")? as usize;
+				if length != reader.remaining() {
+					return Err(ParseError::LengthMismatch { field: "input event length", expected: length, actual: reader.remaining() });
 				}
 
-				Ok(Self::InputData(&buffer[8..]))
+				Ok(Self::InputData(reader.read_rest()))
+			},
+			ControlMessageType::InvalidateReferenceFrames => {
+				// Without a frame range, the client lost a single frame.
+				let first_frame = reader.read_u64_le("first frame").unwrap_or(0);
+				let last_frame = reader.read_u64_le("last frame").unwrap_or(first_frame);
+				Ok(Self::InvalidateReferenceFrames { first_frame, last_frame })
 			},
-			ControlMessageType::InvalidateReferenceFrames => Ok(Self::InvalidateReferenceFrames),
 			ControlMessageType::RequestIdrFrame => Ok(Self::RequestIdrFrame),
 			ControlMessageType::StartA => Ok(Self::StartA),
 			ControlMessageType::StartB => Ok(Self::StartB),
+			// Only sent by the server.
+			ControlMessageType::StreamTermination => Err(ParseError::UnknownType(ControlMessageType::StreamTermination as u32)),
+		}
+	}
+
+	/// Name of the message type, without its payload.
+	fn name(&self) -> &'static str {
+		match self {
+			Self::Encrypted(_) => "Encrypted",
+			Self::Ping => "Ping",
+			Self::Termination => "Termination",
+			Self::RumbleData => "RumbleData",
+			Self::LossStats(_) => "LossStats",
+			Self::FrameStats(_) => "FrameStats",
+			Self::InputData(_) => "InputData",
+			Self::InvalidateReferenceFrames { .. } => "InvalidateReferenceFrames",
+			Self::RequestIdrFrame => "RequestIdrFrame",
+			Self::StartA => "StartA",
+			Self::StartB => "StartB",
 		}
 	}
 }
@@ -138,6 +189,7 @@ struct EncryptedControlMessage {
 
 enum ControlStreamCommand {
 	UpdateKeys(SessionKeys),
+	Terminate(TerminationReason, oneshot::Sender<Result<(), ()>>),
 }
 
 pub struct ControlStream {
@@ -151,42 +203,94 @@ impl ControlStream {
 		video_stream: VideoStream,
 		audio_stream: AudioStream,
 		context: SessionContext,
-		enet: Enet,
+		stats: SessionStats,
+		gamepads: GamepadSlots,
+		guests: Guests,
+		transcript: Transcript,
+		events: EventBus,
+		transport: TransportContext,
 		stop_signal: ShutdownManager<()>,
 	) -> Result<Self, ()> {
-		let input_handler = InputHandler::new()?;
+		let input_profile = match &context.application.input_profile {
+			Some(name) => Some(config.input_profiles.get(name)
+				.ok_or_else(|| tracing::error!("Application '{}' uses input profile '{name}', which is not configured.", context.application.title))?),
+			None => None,
+		};
+		let input_handler = InputHandler::new(&config.host, input_profile, gamepads.clone(), context.client_id.to_string(), stats.clone())?;
 
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = ControlStreamInner { };
-		tokio::task::spawn_blocking({
-			move || {
-				tokio::runtime::Handle::current().block_on(
-					stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
-						config,
-						command_rx,
-						video_stream,
-						audio_stream,
-						context,
-						enet,
-						input_handler,
-					)))
-				)
-			}
+		let inner = ControlStreamInner { stats, gamepads, guests, transcript, events, clock: SystemClock };
+		let supervisor = Supervisor::new(stop_signal.clone());
+		// The ENet C library blocks while waiting for events, so it gets a thread of its own.
+		#[cfg(not(feature = "native-enet"))]
+		supervisor.spawn_critical_blocking("control stream", (), move || {
+			tokio::runtime::Handle::current().block_on(
+				stop_signal.wrap_cancel(inner.run(
+					config,
+					command_rx,
+					video_stream,
+					audio_stream,
+					context,
+					transport,
+					input_handler,
+				))
+			).unwrap_or(Ok(()))
+		});
+		#[cfg(feature = "native-enet")]
+		supervisor.spawn_critical("control stream", (), async move {
+			stop_signal.wrap_cancel(inner.run(
+				config,
+				command_rx,
+				video_stream,
+				audio_stream,
+				context,
+				transport,
+				input_handler,
+			)).await.unwrap_or(Ok(()))
 		});
 
 		Ok(Self { command_tx })
 	}
 
+	/// Switch to the keys that a client sent when resuming the session.
+	///
+	/// Keys only change when a client resumes, Moonlight has no message to hand it new keys in the middle of a stream.
 	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
 		self.command_tx.send(ControlStreamCommand::UpdateKeys(keys)).await
 			.map_err(|e| tracing::error!("Failed to send UpdateKeys command: {e}"))
 	}
+
+	/// Tell the client why the stream ends and stop the control stream.
+	pub async fn terminate(&self, reason: TerminationReason) -> Result<(), ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ControlStreamCommand::Terminate(reason, response_tx)).await
+			.map_err(|e| tracing::error!("Failed to send Terminate command: {e}"))?;
+
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for Terminate response: {e}"))?
+	}
+}
+
+/// A guest that is connected to the control stream, only its gamepad input is used.
+struct GuestConnection {
+	/// Keys the guest encrypts its control messages with.
+	keys: SessionKeys,
+
+	input_handler: InputHandler,
 }
 
-struct ControlStreamInner {
+struct ControlStreamInner<C: Clock> {
+	stats: SessionStats,
+	gamepads: GamepadSlots,
+	guests: Guests,
+	transcript: Transcript,
+	events: EventBus,
+
+	/// Clock that the deadlines of the stream are measured against.
+	clock: C,
 }
 
-impl ControlStreamInner {
+impl<C: Clock> ControlStreamInner<C> {
 	#[allow(clippy::too_many_arguments)] // TODO: Problem for later..
 	pub async fn run(
 		&self,
@@ -195,27 +299,42 @@ impl ControlStreamInner {
 		video_stream: VideoStream,
 		audio_stream: AudioStream,
 		mut context: SessionContext,
-		enet: Enet,
+		transport: TransportContext,
 		input_handler: InputHandler,
 	) -> Result<(), ()> {
-		let local_addr = Address::new(
-			config.address.parse()
-				.map_err(|e| tracing::error!("Failed to parse address: {e}"))?,
-			config.stream.control.port,
+		let mut host = ControlHost::bind(&transport, &config.address, config.stream.control.port, 10, channel::COUNT).await?;
+
+		tracing::debug!("Listening for control messages on {:?}", host.local_address());
+
+		if config.network.qos.control != 0 {
+			tracing::debug!("Marking control packets with DSCP {}.", config.network.qos.control);
+			host.set_tos(QosConfig::tos(config.network.qos.control))?;
+		}
+
+		let stream_timeout = std::time::Duration::from_secs(config.stream_timeout_for(context.client_id.as_str(), &context.application));
+		let mut keepalive = Keepalive::new(
+			self.clock.clone(),
+			stream_timeout,
+			std::time::Duration::from_secs(config.keepalive.warning),
+			config.keepalive.host_ping_interval.filter(|interval| *interval > 0).map(std::time::Duration::from_secs),
+			std::time::Duration::from_secs(config.stream.control.reconnect_window),
 		);
-		let mut host = enet
-			.create_host::<()>(
-				Some(&local_addr),
-				10,
-				ChannelLimit::Maximum,
-				BandwidthLimit::Unlimited,
-				BandwidthLimit::Unlimited,
-			)
-			.map_err(|e| tracing::error!("Failed to create Enet host: {e}"))?;
 
-		tracing::debug!("Listening for control messages on {:?}", host.address());
+		// Client that owns this session, other clients are rejected unless they joined as guests.
+		let mut owner: Option<SocketAddr> = None;
+		let mut owner_connected = false;
+
+		// Guests that are connected, by their address.
+		let mut guest_connections: HashMap<SocketAddr, GuestConnection> = HashMap::new();
+
+		// Whether the client asked to start the audio and video streams.
+		let mut streaming = false;
+
+		// When the video stream was last told about input, which is limited to avoid a command for every input event.
+		let mut last_input_notification: Option<std::time::Instant> = None;
 
-		let mut stop_deadline = std::time::Instant::now() + std::time::Duration::from_secs(config.stream_timeout);
+		// Sequence number for encrypted messages sent to the client.
+		let mut sequence_number = 0u32;
 
 		loop {
 			// Check if we received a command.
@@ -227,6 +346,23 @@ impl ControlStreamInner {
 							tracing::debug!("Updating session keys.");
 							context.keys = keys;
 						},
+						ControlStreamCommand::Terminate(reason, response) => {
+							tracing::info!("Terminating stream: {reason:?}.");
+							let payload = reason.error_code().to_be_bytes();
+							let message = if context.capabilities.encrypts_control() {
+								encrypt_control_message(ControlMessageType::StreamTermination, &payload, &context.keys, sequence_number)
+							} else {
+								Ok(control_message(ControlMessageType::StreamTermination, &payload))
+							};
+							let result = message.and_then(|message| send_to_owner(&mut host, owner, &message, channel::GENERIC));
+							host.flush();
+							self.transcript.record("control", format_args!("< StreamTermination ({reason:?}) on {} channel", channel::name(channel::GENERIC)));
+
+							response.send(result)
+								.map_err(|_| tracing::error!("Failed to send Terminate response."))
+								.ok();
+							break;
+						},
 					}
 				},
 				Err(TryRecvError::Disconnected) => {
@@ -237,32 +373,134 @@ impl ControlStreamInner {
 			}
 
 			// Check if the timeout has passed.
-			if std::time::Instant::now() > stop_deadline {
-				tracing::info!("Stopping because we haven't received a ping for {} seconds.", config.stream_timeout);
-				break;
+			match keepalive.check() {
+				KeepaliveStatus::Alive => {},
+				KeepaliveStatus::Warning(remaining) => {
+					tracing::warn!("Haven't received a ping for a while, stopping in {} seconds unless the client responds.", remaining.as_secs());
+					self.events.publish(SessionEvent::TimeoutWarning {
+						session_id: context.session_id,
+						client_id: context.client_id.clone(),
+						remaining: remaining.as_secs(),
+					});
+				},
+				KeepaliveStatus::TimedOut => {
+					tracing::info!("Stopping because we haven't received a ping for {} seconds.", stream_timeout.as_secs());
+					break;
+				},
+				KeepaliveStatus::ReconnectExpired => {
+					tracing::info!("Stopping because the client didn't reconnect within {} seconds.", config.stream.control.reconnect_window);
+					break;
+				},
+			}
+
+			if owner_connected && keepalive.host_ping_due() {
+				let message = if context.capabilities.encrypts_control() {
+					let message = encrypt_control_message(ControlMessageType::Ping, &[], &context.keys, sequence_number);
+					sequence_number = sequence_number.wrapping_add(1);
+					message
+				} else {
+					Ok(control_message(ControlMessageType::Ping, &[]))
+				};
+				if message.and_then(|message| send_to_owner(&mut host, owner, &message, channel::GENERIC)).is_ok() {
+					self.transcript.record("control", format_args!("< Ping on {} channel", channel::name(channel::GENERIC)));
+				}
 			}
 
-			match host.service(1000).map_err(|e| tracing::error!("Failure in enet host: {e}"))? {
-				Some(Event::Connect(_)) => {},
-				Some(Event::Disconnect(..)) => {},
-				Some(Event::Receive {
-					ref packet,
-					..
-				}) => {
-					let mut control_message = ControlMessage::from_bytes(packet.data())?;
-					tracing::trace!("Received control message: {control_message:?}");
+			match host.service(std::time::Duration::from_secs(1)).await? {
+				Some(ControlEvent::Connect(address)) => {
+					if !config.network.is_allowed(address.ip()) {
+						tracing::warn!("Rejecting control stream connection from {}, address is not in an allowed subnet.", address.ip());
+						host.disconnect(address);
+						continue;
+					}
+
+					// The client reconnects from a new port, so only the IP address identifies the owner.
+					let from_owner = owner.is_some_and(|owner| owner.ip() == address.ip());
+					if !from_owner {
+						if let Some(guest) = self.guests.find(address.ip()) {
+							match InputHandler::new(&config.host, None, self.gamepads.clone(), guest.client_id.clone(), self.stats.clone()) {
+								Ok(input_handler) => {
+									tracing::info!("Guest {} connected from {}.", guest.client_id, address.ip());
+									self.transcript.record("control", format_args!("guest {} connected", address.ip()));
+									guest_connections.insert(address, GuestConnection { keys: guest.keys, input_handler });
+								},
+								Err(()) => host.disconnect(address),
+							}
+							continue;
+						}
+					}
+
+					if owner_connected || owner.is_some_and(|owner| owner.ip() != address.ip()) {
+						tracing::warn!("Rejecting control stream connection from {}, the session belongs to another connection.", address.ip());
+						host.disconnect(address);
+						continue;
+					}
+
+					self.transcript.record("control", format_args!("{} connected", address.ip()));
+					owner = Some(address);
+					owner_connected = true;
+
+					if keepalive.connected() {
+						tracing::info!("Client {} reconnected, resuming stream.", address.ip());
+						if streaming {
+							audio_stream.start(context.keys.clone()).await?;
+							video_stream.resume().await?;
+						}
+					}
+				},
+				Some(ControlEvent::Disconnect(address)) => {
+					// Dropping the input handler of a guest releases its gamepads, the guest keeps its players for when it comes back.
+					if guest_connections.remove(&address).is_some() {
+						tracing::info!("Guest {} disconnected.", address.ip());
+						self.transcript.record("control", format_args!("guest {} disconnected", address.ip()));
+						continue;
+					}
+
+					if !owner_connected || owner != Some(address) {
+						continue;
+					}
+
+					self.transcript.record("control", format_args!("{} disconnected", address.ip()));
+					owner_connected = false;
+
+					if config.stream.control.reconnect_window == 0 {
+						tracing::info!("Stopping because the client disconnected.");
+						break;
+					}
+
+					tracing::info!(
+						"Client {} disconnected, pausing stream for up to {} seconds until it reconnects.",
+						address.ip(),
+						config.stream.control.reconnect_window,
+					);
+					keepalive.disconnected();
+					if streaming {
+						video_stream.pause().await?;
+						audio_stream.pause().await?;
+					}
+				},
+				Some(ControlEvent::Receive { address, channel_id, data }) => {
+					let received = keepalive.now();
+					if let Some(guest) = guest_connections.get(&address) {
+						self.handle_guest_message(guest, channel_id, &data, received).await;
+						continue;
+					}
+
+					let mut control_message = match ControlMessage::from_bytes(&data) {
+						Ok(control_message) => control_message,
+						Err(e) => {
+							tracing::warn!("Failed to parse control message on {} channel: {e}", channel::name(channel_id));
+							continue;
+						},
+					};
+					tracing::trace!("Received control message on {} channel: {control_message:?}", channel::name(channel_id));
 
 					// First check for encrypted control messages and decrypt them.
 					let decrypted;
 					if let ControlMessage::Encrypted(message) = control_message {
-						let mut initialization_vector = [0u8; 16];
-						initialization_vector[0] = message.sequence_number as u8;
-
-						let decrypted_result = openssl::symm::decrypt_aead(
-							Cipher::aes_128_gcm(),
-							&context.keys.remote_input_key,
-							Some(&initialization_vector),
-							&[],
+						let decrypted_result = crypto::decrypt_gcm(
+							context.keys.key(),
+							&context.keys.control_initialization_vector(message.sequence_number),
 							&message.payload,
 							&message.tag,
 						);
@@ -270,33 +508,66 @@ impl ControlStreamInner {
 						decrypted = match decrypted_result {
 							Ok(decrypted) => decrypted,
 							Err(e) => {
-								tracing::error!("Failed to decrypt control message: {:?}", e.errors());
+								tracing::error!("Failed to decrypt control message: {e}");
 								continue;
 							}
 						};
 
 						control_message = match ControlMessage::from_bytes(&decrypted) {
 							Ok(decrypted_message) => decrypted_message,
-							Err(()) => continue,
+							Err(e) => {
+								tracing::warn!("Failed to parse decrypted control message: {e}");
+								continue;
+							},
 						};
 
 						tracing::trace!("Decrypted control message: {control_message:?}");
 					}
 
+					// Payloads contain input events, so only the type and size are recorded.
+					self.transcript.record("control", format_args!(
+						"> {} on {} channel ({} bytes)",
+						control_message.name(),
+						channel::name(channel_id),
+						data.len(),
+					));
+
+					keepalive.message_received(received, matches!(control_message, ControlMessage::Ping));
+
 					match control_message {
 						ControlMessage::Encrypted(_) => unreachable!("Encrypted control messages should be decrypted already."),
-						ControlMessage::RequestIdrFrame | ControlMessage::InvalidateReferenceFrames => {
+						ControlMessage::RequestIdrFrame => {
+							video_stream.request_idr_frame().await?;
+						},
+						ControlMessage::InvalidateReferenceFrames { first_frame, last_frame } => {
+							let lost_frames = last_frame.saturating_sub(first_frame).saturating_add(1);
+							video_stream.report_losses(lost_frames.try_into().unwrap_or(u32::MAX)).await?;
 							video_stream.request_idr_frame().await?;
 						},
 						ControlMessage::StartB => {
+							streaming = true;
 							audio_stream.start(context.keys.clone()).await?;
 							video_stream.start().await?;
 						},
-						ControlMessage::Ping => {
-							stop_deadline = std::time::Instant::now() + std::time::Duration::from_secs(config.stream_timeout);
+						ControlMessage::Ping => (),
+						ControlMessage::LossStats(lost_packets) => {
+							self.stats.record_packets_lost(lost_packets);
+							if lost_packets > 0 {
+								video_stream.report_losses(lost_packets).await?;
+							}
+						},
+						ControlMessage::FrameStats(stats) => {
+							video_stream.update_frame_stats(stats).await?;
 						},
 						ControlMessage::InputData(event) => {
-							let _ = input_handler.handle_raw_input(event).await;
+							let _ = input_handler.handle_raw_input(event, received).await;
+
+							if config.stream.video.idle.is_some()
+								&& !last_input_notification.is_some_and(|last| received.duration_since(last) < INPUT_NOTIFICATION_INTERVAL)
+							{
+								last_input_notification = Some(received);
+								video_stream.notify_input().await?;
+							}
 						},
 						skipped_message => {
 							tracing::trace!("Skipped control message: {skipped_message:?}");
@@ -310,4 +581,226 @@ impl ControlStreamInner {
 		tracing::debug!("Control stream closing.");
 		Ok(())
 	}
+
+	/// Handle a control message of a guest, which only affects its gamepads.
+	async fn handle_guest_message(&self, guest: &GuestConnection, channel_id: u8, data: &[u8], received: Instant) {
+		let control_message = match ControlMessage::from_bytes(data) {
+			Ok(control_message) => control_message,
+			Err(e) => {
+				tracing::warn!("Failed to parse control message of guest on {} channel: {e}", channel::name(channel_id));
+				return;
+			},
+		};
+
+		let decrypted;
+		let control_message = match control_message {
+			ControlMessage::Encrypted(message) => {
+				decrypted = match crypto::decrypt_gcm(
+					guest.keys.key(),
+					&guest.keys.control_initialization_vector(message.sequence_number),
+					&message.payload,
+					&message.tag,
+				) {
+					Ok(decrypted) => decrypted,
+					Err(e) => {
+						tracing::error!("Failed to decrypt control message of guest: {e}");
+						return;
+					},
+				};
+
+				match ControlMessage::from_bytes(&decrypted) {
+					Ok(decrypted_message) => decrypted_message,
+					Err(e) => {
+						tracing::warn!("Failed to parse decrypted control message of guest: {e}");
+						return;
+					},
+				}
+			},
+			control_message => control_message,
+		};
+
+		self.transcript.record("control", format_args!(
+			"> {} from guest on {} channel ({} bytes)",
+			control_message.name(),
+			channel::name(channel_id),
+			data.len(),
+		));
+
+		match control_message {
+			ControlMessage::InputData(event) => {
+				let _ = guest.input_handler.handle_raw_gamepad_input(event, received).await;
+			},
+			skipped_message => {
+				tracing::trace!("Skipped control message of guest: {skipped_message:?}");
+			},
+		}
+	}
+}
+
+/// Send a message to the client that owns the session.
+fn send_to_owner(host: &mut ControlHost, owner: Option<SocketAddr>, message: &[u8], channel_id: u8) -> Result<(), ()> {
+	let owner = owner.ok_or_else(|| tracing::warn!("Can't send control message, the client never connected."))?;
+	host.send(owner, message, channel_id)
+}
+
+/// Serialize a control message that is sent unencrypted.
+fn control_message(message_type: ControlMessageType, payload: &[u8]) -> Vec<u8> {
+	let mut message = Vec::with_capacity(4 + payload.len());
+	message.extend((message_type as u16).to_le_bytes());
+	message.extend((payload.len() as u16).to_le_bytes());
+	message.extend(payload);
+	message
+}
+
+/// Wrap a control message in an encrypted control message.
+fn encrypt_control_message(
+	message_type: ControlMessageType,
+	payload: &[u8],
+	keys: &SessionKeys,
+	sequence_number: u32,
+) -> Result<Vec<u8>, ()> {
+	let message = control_message(message_type, payload);
+	let (encrypted, tag) = crypto::encrypt_gcm(keys.key(), &keys.control_initialization_vector(sequence_number), &message)
+		.map_err(|e| tracing::error!("Failed to encrypt control message: {e}"))?;
+
+	let mut buffer = Vec::with_capacity(MINIMUM_ENCRYPTED_LENGTH + encrypted.len());
+	buffer.extend((ControlMessageType::Encrypted as u16).to_le_bytes());
+	buffer.extend(((4 + ENCRYPTION_TAG_LENGTH + encrypted.len()) as u16).to_le_bytes());
+	buffer.extend(sequence_number.to_le_bytes());
+	buffer.extend(tag);
+	buffer.extend(encrypted);
+
+	Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Key of the session that the messages in `tests/fixtures/protocol/control` are encrypted with.
+	const KEY: &str = "7d3c5a0e91b2f46c28e5d7a1034b9f66";
+
+	fn keys() -> SessionKeys {
+		SessionKeys::from_launch_parameters(&hex::decode(KEY).unwrap(), 0).unwrap()
+	}
+
+	fn fixture(hex: &str) -> Vec<u8> {
+		hex::decode(hex.trim()).unwrap()
+	}
+
+	/// Decrypt an encrypted control message of the client, returns its sequence number and the decrypted message.
+	fn decrypt(message: &[u8]) -> (u32, Vec<u8>) {
+		let ControlMessage::Encrypted(message) = ControlMessage::from_bytes(message).unwrap() else {
+			panic!("expected an encrypted control message");
+		};
+		let keys = keys();
+		let decrypted = crypto::decrypt_gcm(
+			keys.key(),
+			&keys.control_initialization_vector(message.sequence_number),
+			&message.payload,
+			&message.tag,
+		).unwrap();
+
+		(message.sequence_number, decrypted)
+	}
+
+	#[test]
+	fn start() {
+		let (sequence_number, message) = decrypt(&fixture(include_str!("../../../../tests/fixtures/protocol/control/start_a.hex")));
+		assert_eq!(sequence_number, 0);
+		assert!(matches!(ControlMessage::from_bytes(&message), Ok(ControlMessage::StartA)));
+
+		let (sequence_number, message) = decrypt(&fixture(include_str!("../../../../tests/fixtures/protocol/control/start_b.hex")));
+		assert_eq!(sequence_number, 1);
+		assert!(matches!(ControlMessage::from_bytes(&message), Ok(ControlMessage::StartB)));
+	}
+
+	#[test]
+	fn ping() {
+		let (_, message) = decrypt(&fixture(include_str!("../../../../tests/fixtures/protocol/control/ping.hex")));
+		assert!(matches!(ControlMessage::from_bytes(&message), Ok(ControlMessage::Ping)));
+	}
+
+	#[test]
+	fn loss_stats() {
+		let (_, message) = decrypt(&fixture(include_str!("../../../../tests/fixtures/protocol/control/loss_stats.hex")));
+		assert!(matches!(ControlMessage::from_bytes(&message), Ok(ControlMessage::LossStats(3))));
+	}
+
+	#[test]
+	fn invalidate_reference_frames() {
+		let (_, message) = decrypt(&fixture(include_str!("../../../../tests/fixtures/protocol/control/invalidate_reference_frames.hex")));
+		assert!(matches!(
+			ControlMessage::from_bytes(&message),
+			Ok(ControlMessage::InvalidateReferenceFrames { first_frame: 100, last_frame: 120 }),
+		));
+	}
+
+	#[test]
+	fn frame_stats() {
+		let mut message = (ControlMessageType::FrameStats as u16).to_le_bytes().to_vec();
+		message.extend(8u16.to_le_bytes());
+		message.extend(1_500u32.to_le_bytes());
+		message.extend(20_000u32.to_le_bytes());
+		assert!(matches!(
+			ControlMessage::from_bytes(&message),
+			Ok(ControlMessage::FrameStats(FrameStats { jitter: 1_500, queue_delay: 20_000 })),
+		));
+
+		// Both fields are needed to tell whether frames arrive late.
+		let mut message = (ControlMessageType::FrameStats as u16).to_le_bytes().to_vec();
+		message.extend(4u16.to_le_bytes());
+		message.extend(1_500u32.to_le_bytes());
+		assert!(ControlMessage::from_bytes(&message).is_err());
+	}
+
+	#[test]
+	fn input() {
+		let (_, message) = decrypt(&fixture(include_str!("../../../../tests/fixtures/protocol/control/input_key_down.hex")));
+		let Ok(ControlMessage::InputData(event)) = ControlMessage::from_bytes(&message) else {
+			panic!("expected input data");
+		};
+		assert_eq!(event, [0x03, 0x00, 0x00, 0x00, 0x00, 0x41, 0x80, 0x01, 0x00, 0x00]);
+	}
+
+	#[test]
+	fn tampered_message() {
+		let mut message = fixture(include_str!("../../../../tests/fixtures/protocol/control/ping.hex"));
+		*message.last_mut().unwrap() ^= 1;
+		let ControlMessage::Encrypted(message) = ControlMessage::from_bytes(&message).unwrap() else {
+			panic!("expected an encrypted control message");
+		};
+		let keys = keys();
+		assert!(crypto::decrypt_gcm(
+			keys.key(),
+			&keys.control_initialization_vector(message.sequence_number),
+			&message.payload,
+			&message.tag,
+		).is_err());
+	}
+
+	#[test]
+	fn truncated_message() {
+		let message = fixture(include_str!("../../../../tests/fixtures/protocol/control/start_a.hex"));
+		for length in 0..message.len() {
+			assert!(ControlMessage::from_bytes(&message[..length]).is_err(), "parsed {length} bytes of a message");
+		}
+	}
+
+	#[test]
+	fn stream_termination() {
+		let message = encrypt_control_message(
+			ControlMessageType::StreamTermination,
+			&TerminationReason::ApplicationExited.error_code().to_be_bytes(),
+			&keys(),
+			0,
+		).unwrap();
+		assert_eq!(message, fixture(include_str!("../../../../tests/fixtures/protocol/control/stream_termination.hex")));
+	}
+
+	#[test]
+	fn host_ping() {
+		let message = encrypt_control_message(ControlMessageType::Ping, &[], &keys(), 1).unwrap();
+		assert_eq!(message, fixture(include_str!("../../../../tests/fixtures/protocol/control/host_ping.hex")));
+	}
 }
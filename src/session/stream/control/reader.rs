@@ -0,0 +1,288 @@
+//! Length-checked reading of binary messages received from clients.
+
+/// Reasons why a message received from a client could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+	/// The message ended before a field could be read.
+	UnexpectedEnd {
+		field: &'static str,
+		needed: usize,
+		remaining: usize,
+	},
+
+	/// A length field does not match the number of bytes in the message.
+	LengthMismatch {
+		field: &'static str,
+		expected: usize,
+		actual: usize,
+	},
+
+	/// The type of the message is not known.
+	UnknownType(u32),
+
+	/// A field contains a value that is not valid.
+	InvalidValue(&'static str),
+}
+
+impl std::fmt::Display for ParseError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::UnexpectedEnd { field, needed, remaining } =>
+				write!(f, "expected {needed} bytes for '{field}', but only {remaining} bytes are left"),
+			Self::LengthMismatch { field, expected, actual } =>
+				write!(f, "'{field}' says the message has {expected} bytes, but it has {actual} bytes"),
+			Self::UnknownType(message_type) => write!(f, "unknown message type {message_type:#x}"),
+			Self::InvalidValue(field) => write!(f, "invalid value for '{field}'"),
+		}
+	}
+}
+
+/// Reads fields from the start of a buffer, without ever reading past its end.
+pub struct ByteReader<'a> {
+	buffer: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+	pub fn new(buffer: &'a [u8]) -> Self {
+		Self { buffer }
+	}
+
+	/// Number of bytes that have not been read yet.
+	pub fn remaining(&self) -> usize {
+		self.buffer.len()
+	}
+
+	/// Read `length` bytes.
+	pub fn read_bytes(&mut self, field: &'static str, length: usize) -> Result<&'a [u8], ParseError> {
+		if length > self.buffer.len() {
+			return Err(ParseError::UnexpectedEnd { field, needed: length, remaining: self.buffer.len() });
+		}
+
+		let (bytes, rest) = self.buffer.split_at(length);
+		self.buffer = rest;
+		Ok(bytes)
+	}
+
+	/// Read exactly `N` bytes.
+	pub fn read_array<const N: usize>(&mut self, field: &'static str) -> Result<[u8; N], ParseError> {
+		let mut array = [0u8; N];
+		array.copy_from_slice(self.read_bytes(field, N)?);
+		Ok(array)
+	}
+
+	/// Skip `length` bytes, such as padding.
+	pub fn skip(&mut self, field: &'static str, length: usize) -> Result<(), ParseError> {
+		self.read_bytes(field, length).map(|_| ())
+	}
+
+	/// Read all bytes that have not been read yet.
+	pub fn read_rest(&mut self) -> &'a [u8] {
+		std::mem::take(&mut self.buffer)
+	}
+
+	pub fn read_u8(&mut self, field: &'static str) -> Result<u8, ParseError> {
+		Ok(self.read_array::<1>(field)?[0])
+	}
+
+	pub fn read_i16_le(&mut self, field: &'static str) -> Result<i16, ParseError> {
+		Ok(i16::from_le_bytes(self.read_array(field)?))
+	}
+
+	pub fn read_i16_be(&mut self, field: &'static str) -> Result<i16, ParseError> {
+		Ok(i16::from_be_bytes(self.read_array(field)?))
+	}
+
+	pub fn read_u16_le(&mut self, field: &'static str) -> Result<u16, ParseError> {
+		Ok(u16::from_le_bytes(self.read_array(field)?))
+	}
+
+	pub fn read_u16_be(&mut self, field: &'static str) -> Result<u16, ParseError> {
+		Ok(u16::from_be_bytes(self.read_array(field)?))
+	}
+
+	pub fn read_u32_le(&mut self, field: &'static str) -> Result<u32, ParseError> {
+		Ok(u32::from_le_bytes(self.read_array(field)?))
+	}
+
+	pub fn read_u32_be(&mut self, field: &'static str) -> Result<u32, ParseError> {
+		Ok(u32::from_be_bytes(self.read_array(field)?))
+	}
+
+	pub fn read_u64_le(&mut self, field: &'static str) -> Result<u64, ParseError> {
+		Ok(u64::from_le_bytes(self.read_array(field)?))
+	}
+
+	pub fn read_f32_le(&mut self, field: &'static str) -> Result<f32, ParseError> {
+		Ok(f32::from_le_bytes(self.read_array(field)?))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use proptest::prelude::*;
+
+	use super::*;
+
+	#[test]
+	fn read_fields() {
+		let buffer = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B];
+		let mut reader = ByteReader::new(&buffer);
+
+		assert_eq!(reader.read_u8("u8"), Ok(0x01));
+		assert_eq!(reader.read_u16_le("u16"), Ok(0x0302));
+		assert_eq!(reader.read_u16_be("u16"), Ok(0x0405));
+		assert_eq!(reader.read_u32_le("u32"), Ok(0x09080706));
+		assert_eq!(reader.remaining(), 2);
+		assert_eq!(reader.read_rest(), &[0x0A, 0x0B]);
+		assert_eq!(reader.remaining(), 0);
+		assert_eq!(reader.read_rest(), &[] as &[u8]);
+	}
+
+	#[test]
+	fn read_signed_and_float() {
+		let mut buffer = Vec::new();
+		buffer.extend_from_slice(&(-2i16).to_le_bytes());
+		buffer.extend_from_slice(&(-3i16).to_be_bytes());
+		buffer.extend_from_slice(&0x0102030405060708u64.to_le_bytes());
+		buffer.extend_from_slice(&1.5f32.to_le_bytes());
+		buffer.extend_from_slice(&0xDEADBEEFu32.to_be_bytes());
+		let mut reader = ByteReader::new(&buffer);
+
+		assert_eq!(reader.read_i16_le("i16"), Ok(-2));
+		assert_eq!(reader.read_i16_be("i16"), Ok(-3));
+		assert_eq!(reader.read_u64_le("u64"), Ok(0x0102030405060708));
+		assert_eq!(reader.read_f32_le("f32"), Ok(1.5));
+		assert_eq!(reader.read_u32_be("u32"), Ok(0xDEADBEEF));
+		assert_eq!(reader.remaining(), 0);
+	}
+
+	#[test]
+	fn short_read() {
+		let buffer = [0x01, 0x02, 0x03];
+		let mut reader = ByteReader::new(&buffer);
+
+		assert_eq!(
+			reader.read_u32_le("button flags"),
+			Err(ParseError::UnexpectedEnd { field: "button flags", needed: 4, remaining: 3 }),
+		);
+
+		// A failed read consumes nothing, so smaller fields can still be read.
+		assert_eq!(reader.remaining(), 3);
+		assert_eq!(reader.read_u16_be("u16"), Ok(0x0102));
+		assert_eq!(
+			reader.read_u16_be("u16"),
+			Err(ParseError::UnexpectedEnd { field: "u16", needed: 2, remaining: 1 }),
+		);
+		assert_eq!(reader.read_bytes("rest", 1), Ok(&[0x03][..]));
+		assert_eq!(
+			reader.read_u8("u8"),
+			Err(ParseError::UnexpectedEnd { field: "u8", needed: 1, remaining: 0 }),
+		);
+	}
+
+	#[test]
+	fn skip() {
+		let buffer = [0x01, 0x02, 0x03, 0x04];
+		let mut reader = ByteReader::new(&buffer);
+
+		assert_eq!(reader.skip("padding", 0), Ok(()));
+		assert_eq!(reader.skip("padding", 3), Ok(()));
+		assert_eq!(reader.read_u8("u8"), Ok(0x04));
+		assert_eq!(reader.skip("padding", 0), Ok(()));
+	}
+
+	#[test]
+	fn skip_past_end() {
+		let buffer = [0x01, 0x02, 0x03, 0x04];
+		let mut reader = ByteReader::new(&buffer);
+
+		assert_eq!(
+			reader.skip("padding", 5),
+			Err(ParseError::UnexpectedEnd { field: "padding", needed: 5, remaining: 4 }),
+		);
+		assert_eq!(reader.remaining(), 4);
+
+		assert_eq!(
+			reader.skip("padding", usize::MAX),
+			Err(ParseError::UnexpectedEnd { field: "padding", needed: usize::MAX, remaining: 4 }),
+		);
+		assert_eq!(reader.read_u32_be("u32"), Ok(0x01020304));
+	}
+
+	#[test]
+	fn display() {
+		let error = ParseError::UnexpectedEnd { field: "x", needed: 4, remaining: 1 };
+		assert_eq!(error.to_string(), "expected 4 bytes for 'x', but only 1 bytes are left");
+
+		let error = ParseError::LengthMismatch { field: "length", expected: 10, actual: 8 };
+		assert_eq!(error.to_string(), "'length' says the message has 10 bytes, but it has 8 bytes");
+
+		assert_eq!(ParseError::UnknownType(0x0206).to_string(), "unknown message type 0x206");
+		assert_eq!(ParseError::InvalidValue("button").to_string(), "invalid value for 'button'");
+	}
+
+	/// Operations that parsers perform on a reader.
+	#[derive(Clone, Debug)]
+	enum Operation {
+		U8,
+		U16Le,
+		U32Be,
+		U64Le,
+		F32Le,
+		Bytes(usize),
+		Skip(usize),
+		Rest,
+	}
+
+	fn operation() -> impl Strategy<Value = Operation> {
+		prop_oneof![
+			Just(Operation::U8),
+			Just(Operation::U16Le),
+			Just(Operation::U32Be),
+			Just(Operation::U64Le),
+			Just(Operation::F32Le),
+			(0..64usize).prop_map(Operation::Bytes),
+			prop_oneof![0..64usize, Just(usize::MAX)].prop_map(Operation::Skip),
+			Just(Operation::Rest),
+		]
+	}
+
+	proptest! {
+		#[test]
+		fn arbitrary_input_never_panics(
+			buffer in proptest::collection::vec(any::<u8>(), 0..128),
+			operations in proptest::collection::vec(operation(), 0..32),
+		) {
+			let mut reader = ByteReader::new(&buffer);
+			let mut consumed = 0;
+			for operation in operations {
+				let before = reader.remaining();
+				let result = match operation {
+					Operation::U8 => reader.read_u8("u8").map(|_| 1),
+					Operation::U16Le => reader.read_u16_le("u16").map(|_| 2),
+					Operation::U32Be => reader.read_u32_be("u32").map(|_| 4),
+					Operation::U64Le => reader.read_u64_le("u64").map(|_| 8),
+					Operation::F32Le => reader.read_f32_le("f32").map(|_| 4),
+					Operation::Bytes(length) => reader.read_bytes("bytes", length).map(|bytes| bytes.len()),
+					Operation::Skip(length) => reader.skip("skip", length).map(|()| length),
+					Operation::Rest => Ok(reader.read_rest().len()),
+				};
+
+				match result {
+					Ok(length) => {
+						prop_assert_eq!(reader.remaining(), before - length);
+						consumed += length;
+					},
+					Err(ParseError::UnexpectedEnd { remaining, needed, .. }) => {
+						prop_assert_eq!(remaining, before);
+						prop_assert!(needed > remaining);
+						prop_assert_eq!(reader.remaining(), before);
+					},
+					Err(e) => prop_assert!(false, "unexpected error {e}"),
+				}
+			}
+
+			prop_assert_eq!(consumed + reader.remaining(), buffer.len());
+		}
+	}
+}
@@ -0,0 +1,133 @@
+//! Control transport implemented by the ENet C library.
+
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, time::Duration};
+
+use enet::{
+	Address,
+	BandwidthLimit,
+	ChannelLimit,
+	Enet,
+	Event,
+	Host,
+	Packet,
+	PacketMode,
+	PeerState,
+};
+
+use super::{super::channel, ControlEvent};
+
+/// ENet can only be initialized once, this context is shared by all control streams.
+#[derive(Clone)]
+pub struct TransportContext {
+	enet: Enet,
+}
+
+impl TransportContext {
+	#[allow(clippy::result_unit_err)]
+	pub fn new() -> Result<Self, ()> {
+		let enet = Enet::new()
+			.map_err(|e| tracing::error!("Failed to initialize Enet session: {e}"))?;
+
+		Ok(Self { enet })
+	}
+}
+
+pub struct ControlHost {
+	host: Host<()>,
+}
+
+impl ControlHost {
+	pub async fn bind(
+		context: &TransportContext,
+		address: &str,
+		port: u16,
+		peer_limit: usize,
+		channel_count: usize,
+	) -> Result<Self, ()> {
+		// ENet only supports IPv4, the IPv6 wildcard is the closest to what was asked for.
+		let address = match address.parse() {
+			Ok(IpAddr::V4(address)) => address,
+			Ok(IpAddr::V6(address)) if address.is_unspecified() => Ipv4Addr::UNSPECIFIED,
+			Ok(IpAddr::V6(address)) => {
+				tracing::error!("The ENet control stream can't bind to IPv6 address {address}, build with the `native-enet` feature or use an IPv4 address.");
+				return Err(());
+			},
+			Err(e) => {
+				tracing::error!("Failed to parse address '{address}': {e}");
+				return Err(());
+			},
+		};
+		let local_addr = Address::new(address, port);
+		let host = context.enet
+			.create_host::<()>(
+				Some(&local_addr),
+				peer_limit,
+				ChannelLimit::Limited(channel_count),
+				BandwidthLimit::Unlimited,
+				BandwidthLimit::Unlimited,
+			)
+			.map_err(|e| tracing::error!("Failed to create Enet host: {e}"))?;
+
+		Ok(Self { host })
+	}
+
+	pub fn local_address(&self) -> Option<SocketAddr> {
+		Some(to_socket_addr(&self.host.address()))
+	}
+
+	/// Set the IP TOS field of outgoing datagrams.
+	///
+	/// The ENet C library doesn't expose its socket, so control packets stay unmarked.
+	pub fn set_tos(&self, _tos: u32) -> Result<(), ()> {
+		tracing::debug!("Marking control packets requires the native-enet feature.");
+		Ok(())
+	}
+
+	/// Wait for the next event, for at most `timeout`.
+	///
+	/// The ENet C library blocks the thread while waiting.
+	pub async fn service(&mut self, timeout: Duration) -> Result<Option<ControlEvent>, ()> {
+		let event = self.host.service(timeout.as_millis() as u32)
+			.map_err(|e| tracing::error!("Failure in enet host: {e}"))?;
+
+		Ok(match event {
+			Some(Event::Connect(peer)) => Some(ControlEvent::Connect(to_socket_addr(&peer.address()))),
+			Some(Event::Disconnect(peer, _)) => Some(ControlEvent::Disconnect(to_socket_addr(&peer.address()))),
+			Some(Event::Receive { ref sender, channel_id, ref packet }) => Some(ControlEvent::Receive {
+				address: to_socket_addr(&sender.address()),
+				channel_id,
+				data: packet.data().to_vec(),
+			}),
+			None => None,
+		})
+	}
+
+	/// Reliably send a message to the client at `address`.
+	pub fn send(&mut self, address: SocketAddr, message: &[u8], channel_id: u8) -> Result<(), ()> {
+		let Some(mut peer) = self.host.peers().find(|peer| peer.state() == PeerState::Connected && to_socket_addr(&peer.address()) == address) else {
+			tracing::warn!("Can't send control message, no client is connected from {address}.");
+			return Err(());
+		};
+
+		let packet = Packet::new(message, PacketMode::ReliableSequenced)
+			.map_err(|e| tracing::error!("Failed to create control packet: {e}"))?;
+		peer.send_packet(packet, channel_id)
+			.map_err(|e| tracing::error!("Failed to send control packet on {} channel: {e}", channel::name(channel_id)))
+	}
+
+	/// Disconnect the client at `address` without waiting for it to acknowledge.
+	pub fn disconnect(&mut self, address: SocketAddr) {
+		if let Some(mut peer) = self.host.peers().find(|peer| to_socket_addr(&peer.address()) == address) {
+			peer.disconnect_now(0);
+		}
+	}
+
+	/// Send all queued packets.
+	pub fn flush(&mut self) {
+		self.host.flush();
+	}
+}
+
+fn to_socket_addr(address: &Address) -> SocketAddr {
+	SocketAddr::new(IpAddr::V4(*address.ip()), address.port())
+}
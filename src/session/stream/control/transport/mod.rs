@@ -0,0 +1,36 @@
+//! Reliable UDP transport for the control stream.
+//!
+//! Moonlight speaks ENet on the control stream. By default the ENet C library is used, with the
+//! `native-enet` feature a pure Rust implementation of the protocol is used instead.
+
+use std::net::SocketAddr;
+
+#[cfg(not(feature = "native-enet"))]
+pub use self::enet::{ControlHost, TransportContext};
+#[cfg(feature = "native-enet")]
+pub use self::native::{ControlHost, TransportContext};
+
+#[cfg(not(feature = "native-enet"))]
+mod enet;
+#[cfg(feature = "native-enet")]
+mod native;
+
+#[cfg(not(any(feature = "enet", feature = "native-enet")))]
+compile_error!("Either the `enet` or the `native-enet` feature is required for the control stream.");
+
+/// Event that happened on the control host.
+#[derive(Debug)]
+pub enum ControlEvent {
+	/// A client connected from this address.
+	Connect(SocketAddr),
+
+	/// The client at this address disconnected.
+	Disconnect(SocketAddr),
+
+	/// The client at this address sent a packet on a channel.
+	Receive {
+		address: SocketAddr,
+		channel_id: u8,
+		data: Vec<u8>,
+	},
+}
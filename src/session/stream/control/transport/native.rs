@@ -0,0 +1,1120 @@
+//! Control transport implemented in Rust, enabled with the `native-enet` feature.
+//!
+//! This implements the server side of the ENet protocol, as far as Moonlight uses it: clients connect to the
+//! host, send packets reliably, unreliably or unsequenced (reliable packets may be fragmented), and the host
+//! sends packets reliably to the client. Bandwidth limits, throttling and compression are not supported.
+
+use std::{collections::{HashMap, VecDeque}, net::SocketAddr, time::{Duration, Instant}};
+
+use tokio::net::UdpSocket;
+
+use super::{super::{channel, reader::{ByteReader, ParseError}}, ControlEvent};
+
+/// Peer id used in the header by clients that are not connected yet.
+const MAXIMUM_PEER_ID: u16 = 0x0FFF;
+
+const HEADER_FLAG_SENT_TIME: u16 = 1 << 15;
+const HEADER_FLAG_COMPRESSED: u16 = 1 << 14;
+const HEADER_SESSION_MASK: u16 = 3 << 12;
+const HEADER_SESSION_SHIFT: u16 = 12;
+
+/// Size of the header of a datagram, including the sent time.
+const HEADER_SIZE: usize = 4;
+
+const COMMAND_FLAG_ACKNOWLEDGE: u8 = 1 << 7;
+const COMMAND_FLAG_UNSEQUENCED: u8 = 1 << 6;
+const COMMAND_MASK: u8 = 0x0F;
+
+const COMMAND_ACKNOWLEDGE: u8 = 1;
+const COMMAND_CONNECT: u8 = 2;
+const COMMAND_VERIFY_CONNECT: u8 = 3;
+const COMMAND_DISCONNECT: u8 = 4;
+const COMMAND_PING: u8 = 5;
+const COMMAND_SEND_RELIABLE: u8 = 6;
+const COMMAND_SEND_UNRELIABLE: u8 = 7;
+const COMMAND_SEND_FRAGMENT: u8 = 8;
+const COMMAND_SEND_UNSEQUENCED: u8 = 9;
+const COMMAND_BANDWIDTH_LIMIT: u8 = 10;
+const COMMAND_THROTTLE_CONFIGURE: u8 = 11;
+const COMMAND_SEND_UNRELIABLE_FRAGMENT: u8 = 12;
+
+/// Size of a send reliable command without its data.
+const SEND_RELIABLE_SIZE: usize = 6;
+
+/// Channel id of commands that don't belong to a channel, such as connecting and disconnecting.
+const PEER_CHANNEL: u8 = 0xFF;
+
+const MINIMUM_MTU: u32 = 576;
+const MAXIMUM_MTU: u32 = 4096;
+const DEFAULT_MTU: u32 = 1392;
+const MINIMUM_WINDOW_SIZE: u32 = 4096;
+const MAXIMUM_WINDOW_SIZE: u32 = 65536;
+
+/// Largest packet that is reassembled from fragments.
+const MAXIMUM_PACKET_SIZE: u32 = 1024 * 1024;
+
+/// Number of reliable commands that may arrive ahead of the next expected command on a channel.
+const MAXIMUM_PENDING_COMMANDS: u16 = 1024;
+
+/// Time after which an unacknowledged reliable command is sent again.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Time after which a client that doesn't acknowledge reliable commands, or sends nothing at all, is disconnected.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time without datagrams from a client after which it is pinged, like the ENet C library does.
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The native transport needs no global state, the context only exists to match the ENet C library.
+#[derive(Clone)]
+pub struct TransportContext;
+
+impl TransportContext {
+	#[allow(clippy::result_unit_err)]
+	pub fn new() -> Result<Self, ()> {
+		Ok(Self)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PeerState {
+	/// The connect command is verified, waiting for the client to acknowledge it.
+	AcknowledgingConnect,
+	Connected,
+}
+
+struct Peer {
+	address: SocketAddr,
+	state: PeerState,
+
+	/// Peer id that the client assigned to this host, sent in the header of every datagram.
+	outgoing_peer_id: u16,
+	incoming_session_id: u8,
+	outgoing_session_id: u8,
+	connect_id: u32,
+	mtu: u32,
+
+	/// Reliable sequence number of the commands sent on the peer channel.
+	outgoing_reliable_sequence_number: u16,
+	channels: Vec<Channel>,
+
+	/// Reliable commands that were sent, but are not acknowledged yet.
+	unacknowledged: Vec<OutgoingCommand>,
+
+	/// When the last datagram from the client arrived.
+	last_received: Instant,
+
+	/// When the client was last pinged.
+	last_ping: Instant,
+}
+
+#[derive(Default)]
+struct Channel {
+	outgoing_reliable_sequence_number: u16,
+	incoming_reliable_sequence_number: u16,
+
+	/// Reliable commands that arrived before the commands preceding them, by sequence number.
+	pending: HashMap<u16, IncomingCommand>,
+
+	/// Packet that is being reassembled from fragments.
+	fragments: Option<FragmentBuffer>,
+}
+
+struct OutgoingCommand {
+	channel_id: u8,
+	reliable_sequence_number: u16,
+
+	/// The command including its header, ready to be put in a datagram.
+	bytes: Vec<u8>,
+	first_sent: Instant,
+	last_sent: Instant,
+}
+
+enum IncomingCommand {
+	Packet(Vec<u8>),
+	Fragment(Fragment),
+}
+
+struct Fragment {
+	start_sequence_number: u16,
+	fragment_count: u32,
+	fragment_number: u32,
+	total_length: u32,
+	fragment_offset: u32,
+	data: Vec<u8>,
+}
+
+struct FragmentBuffer {
+	start_sequence_number: u16,
+	fragment_count: u32,
+	received: u32,
+	data: Vec<u8>,
+}
+
+/// Connect command sent by a client, without the fields that are ignored.
+struct Connect {
+	outgoing_peer_id: u16,
+	incoming_session_id: u8,
+	outgoing_session_id: u8,
+	mtu: u32,
+	window_size: u32,
+	channel_count: u32,
+	packet_throttle_interval: u32,
+	packet_throttle_acceleration: u32,
+	packet_throttle_deceleration: u32,
+
+	/// Echoed back to the client as is, so it is kept in network byte order.
+	connect_id: [u8; 4],
+}
+
+impl Channel {
+	/// Accept a reliable command, returns the packets that can be delivered in order.
+	fn receive_reliable(&mut self, sequence_number: u16, command: IncomingCommand) -> Vec<Vec<u8>> {
+		// Commands that were delivered already are acknowledged again, but not delivered twice.
+		let distance = sequence_number.wrapping_sub(self.incoming_reliable_sequence_number);
+		if distance == 0 || distance > MAXIMUM_PENDING_COMMANDS {
+			return Vec::new();
+		}
+
+		self.pending.insert(sequence_number, command);
+
+		let mut packets = Vec::new();
+		while let Some(command) = self.pending.remove(&self.incoming_reliable_sequence_number.wrapping_add(1)) {
+			self.incoming_reliable_sequence_number = self.incoming_reliable_sequence_number.wrapping_add(1);
+			if let Some(packet) = self.reassemble(command) {
+				packets.push(packet);
+			}
+		}
+
+		packets
+	}
+
+	/// Fragments are delivered in order, so a packet is complete when its last fragment arrives.
+	fn reassemble(&mut self, command: IncomingCommand) -> Option<Vec<u8>> {
+		let fragment = match command {
+			IncomingCommand::Packet(packet) => return Some(packet),
+			IncomingCommand::Fragment(fragment) => fragment,
+		};
+
+		if fragment.fragment_number == 0 {
+			self.fragments = Some(FragmentBuffer {
+				start_sequence_number: fragment.start_sequence_number,
+				fragment_count: fragment.fragment_count,
+				received: 0,
+				data: vec![0; fragment.total_length as usize],
+			});
+		}
+
+		let buffer = self.fragments.as_mut()?;
+		let start = fragment.fragment_offset as usize;
+		let end = start + fragment.data.len();
+		if buffer.start_sequence_number != fragment.start_sequence_number
+			|| buffer.fragment_count != fragment.fragment_count
+			|| end > buffer.data.len()
+		{
+			tracing::warn!("Dropping control packet with inconsistent fragments.");
+			self.fragments = None;
+			return None;
+		}
+
+		buffer.data[start..end].copy_from_slice(&fragment.data);
+		buffer.received += 1;
+		if buffer.received < buffer.fragment_count {
+			return None;
+		}
+
+		self.fragments.take().map(|buffer| buffer.data)
+	}
+}
+
+pub struct ControlHost {
+	socket: UdpSocket,
+	peers: Vec<Option<Peer>>,
+	channel_count: usize,
+	events: VecDeque<ControlEvent>,
+
+	/// Reference for the sent time in datagram headers.
+	start: Instant,
+}
+
+impl ControlHost {
+	pub async fn bind(
+		_context: &TransportContext,
+		address: &str,
+		port: u16,
+		peer_limit: usize,
+		channel_count: usize,
+	) -> Result<Self, ()> {
+		let socket = UdpSocket::bind((address, port)).await
+			.map_err(|e| tracing::error!("Failed to bind control socket: {e}"))?;
+
+		Ok(Self {
+			socket,
+			peers: (0..peer_limit.min(MAXIMUM_PEER_ID as usize)).map(|_| None).collect(),
+			channel_count,
+			events: VecDeque::new(),
+			start: Instant::now(),
+		})
+	}
+
+	pub fn local_address(&self) -> Option<SocketAddr> {
+		self.socket.local_addr().ok()
+	}
+
+	/// Set the IP TOS field of outgoing datagrams.
+	pub fn set_tos(&self, tos: u32) -> Result<(), ()> {
+		self.socket.set_tos(tos)
+			.map_err(|e| tracing::error!("Failed to set QoS on the control socket: {e}"))
+	}
+
+	/// Wait for the next event, for at most `timeout`.
+	///
+	/// Reliable commands that weren't acknowledged in time are sent again while waiting.
+	pub async fn service(&mut self, timeout: Duration) -> Result<Option<ControlEvent>, ()> {
+		let deadline = Instant::now() + timeout;
+		let mut buffer = vec![0u8; MAXIMUM_MTU as usize];
+
+		loop {
+			self.retransmit();
+			if let Some(event) = self.events.pop_front() {
+				return Ok(Some(event));
+			}
+
+			let now = Instant::now();
+			if now >= deadline {
+				return Ok(None);
+			}
+
+			let wait = (deadline - now).min(RETRANSMIT_TIMEOUT);
+			match tokio::time::timeout(wait, self.socket.recv_from(&mut buffer)).await {
+				Ok(Ok((length, address))) => {
+					if let Err(e) = self.handle_datagram(&buffer[..length], address) {
+						tracing::debug!("Ignoring invalid control datagram from {address}: {e}");
+					}
+				},
+				// Errors are caused by earlier datagrams (ie. ICMP port unreachable), they don't affect other clients.
+				Ok(Err(e)) => tracing::debug!("Failed to receive control datagram: {e}"),
+				Err(_) => { },
+			}
+		}
+	}
+
+	/// Reliably send a message to the client at `address`.
+	pub fn send(&mut self, address: SocketAddr, message: &[u8], channel_id: u8) -> Result<(), ()> {
+		let Some(index) = self.peers.iter().position(|peer| matches!(peer, Some(peer) if peer.state == PeerState::Connected && peer.address == address)) else {
+			tracing::warn!("Can't send control message, no client is connected from {address}.");
+			return Err(());
+		};
+
+		let Some(peer) = self.peers[index].as_ref() else {
+			return Err(());
+		};
+		if channel_id as usize >= peer.channels.len() {
+			tracing::error!("Failed to send control packet on {} channel: the client did not open this channel.", channel::name(channel_id));
+			return Err(());
+		}
+		if HEADER_SIZE + SEND_RELIABLE_SIZE + message.len() > peer.mtu as usize {
+			tracing::error!("Failed to send control packet of {} bytes, larger than the MTU of {}.", message.len(), peer.mtu);
+			return Err(());
+		}
+
+		let mut body = Vec::with_capacity(2 + message.len());
+		body.extend((message.len() as u16).to_be_bytes());
+		body.extend(message);
+		self.send_reliable_command(index, channel_id, COMMAND_SEND_RELIABLE, &body);
+
+		Ok(())
+	}
+
+	/// Disconnect the client at `address` without waiting for it to acknowledge.
+	pub fn disconnect(&mut self, address: SocketAddr) {
+		let Some(index) = self.peers.iter().position(|peer| matches!(peer, Some(peer) if peer.address == address)) else {
+			return;
+		};
+		let Some(peer) = self.peers[index].as_mut() else {
+			return;
+		};
+
+		// The command takes the next sequence number, the client drops it as a duplicate if it reuses an acknowledged one.
+		peer.outgoing_reliable_sequence_number = peer.outgoing_reliable_sequence_number.wrapping_add(1);
+		let mut command = vec![COMMAND_DISCONNECT | COMMAND_FLAG_UNSEQUENCED, PEER_CHANNEL];
+		command.extend(peer.outgoing_reliable_sequence_number.to_be_bytes());
+		command.extend(0u32.to_be_bytes());
+		self.send_commands(index, &[command]);
+
+		self.peers[index] = None;
+	}
+
+	/// Commands are sent immediately, so there is nothing to flush.
+	pub fn flush(&mut self) { }
+
+	fn handle_datagram(&mut self, datagram: &[u8], address: SocketAddr) -> Result<(), ParseError> {
+		let mut reader = ByteReader::new(datagram);
+		let header = reader.read_u16_be("peer id")?;
+		if header & HEADER_FLAG_COMPRESSED != 0 {
+			return Err(ParseError::InvalidValue("compressed datagram"));
+		}
+
+		let session_id = ((header & HEADER_SESSION_MASK) >> HEADER_SESSION_SHIFT) as u8;
+		let peer_id = header & MAXIMUM_PEER_ID;
+		let sent_time = if header & HEADER_FLAG_SENT_TIME != 0 {
+			Some(reader.read_u16_be("sent time")?)
+		} else {
+			None
+		};
+
+		let mut peer_index = None;
+		if peer_id != MAXIMUM_PEER_ID {
+			match self.peers.get(peer_id as usize) {
+				Some(Some(peer)) if peer.address == address && peer.incoming_session_id == session_id => peer_index = Some(peer_id as usize),
+				_ => return Err(ParseError::InvalidValue("peer id")),
+			}
+		}
+
+		if let Some(peer) = peer_index.and_then(|index| self.peers[index].as_mut()) {
+			peer.last_received = Instant::now();
+		}
+
+		let mut acknowledgements = Vec::new();
+		let mut disconnected = false;
+		while reader.remaining() > 0 && !disconnected {
+			let command_header = reader.read_u8("command")?;
+			let channel_id = reader.read_u8("channel id")?;
+			let reliable_sequence_number = reader.read_u16_be("reliable sequence number")?;
+			let command = command_header & COMMAND_MASK;
+
+			// Datagrams from clients that are not connected may only contain a connect command.
+			let index = match peer_index {
+				Some(index) => index,
+				None if command == COMMAND_CONNECT => {
+					let connect = read_connect(&mut reader)?;
+					match self.handle_connect(address, connect) {
+						Some(index) => {
+							peer_index = Some(index);
+							if command_header & COMMAND_FLAG_ACKNOWLEDGE != 0 {
+								acknowledgements.push((channel_id, reliable_sequence_number));
+							}
+						},
+						None => break,
+					}
+					continue;
+				},
+				None => return Err(ParseError::InvalidValue("command from unconnected client")),
+			};
+
+			match command {
+				COMMAND_ACKNOWLEDGE => {
+					let received_reliable_sequence_number = reader.read_u16_be("received reliable sequence number")?;
+					reader.skip("received sent time", 2)?;
+					self.handle_acknowledge(index, channel_id, received_reliable_sequence_number);
+				},
+				COMMAND_CONNECT => {
+					// The client sent its connect command again, it is verified already.
+					read_connect(&mut reader)?;
+				},
+				COMMAND_VERIFY_CONNECT => reader.skip("verify connect", 40)?,
+				COMMAND_DISCONNECT => {
+					reader.skip("disconnect data", 4)?;
+					disconnected = true;
+				},
+				COMMAND_PING => { },
+				COMMAND_SEND_RELIABLE => {
+					let length = reader.read_u16_be("data length")?;
+					let data = reader.read_bytes("data", length as usize)?.to_vec();
+					let channel = self.receiving_channel(index, channel_id)?;
+					for packet in channel.receive_reliable(reliable_sequence_number, IncomingCommand::Packet(data)) {
+						self.events.push_back(ControlEvent::Receive { address, channel_id, data: packet });
+					}
+				},
+				COMMAND_SEND_UNRELIABLE | COMMAND_SEND_UNSEQUENCED => {
+					reader.skip("sequence number", 2)?;
+					let length = reader.read_u16_be("data length")?;
+					let data = reader.read_bytes("data", length as usize)?.to_vec();
+					self.receiving_channel(index, channel_id)?;
+					self.events.push_back(ControlEvent::Receive { address, channel_id, data });
+				},
+				COMMAND_SEND_FRAGMENT => {
+					let start_sequence_number = reader.read_u16_be("start sequence number")?;
+					let length = reader.read_u16_be("data length")?;
+					let fragment_count = reader.read_u32_be("fragment count")?;
+					let fragment_number = reader.read_u32_be("fragment number")?;
+					let total_length = reader.read_u32_be("total length")?;
+					let fragment_offset = reader.read_u32_be("fragment offset")?;
+					let data = reader.read_bytes("data", length as usize)?.to_vec();
+					if total_length > MAXIMUM_PACKET_SIZE || fragment_number >= fragment_count {
+						return Err(ParseError::InvalidValue("fragment"));
+					}
+
+					let fragment = Fragment { start_sequence_number, fragment_count, fragment_number, total_length, fragment_offset, data };
+					let channel = self.receiving_channel(index, channel_id)?;
+					for packet in channel.receive_reliable(reliable_sequence_number, IncomingCommand::Fragment(fragment)) {
+						self.events.push_back(ControlEvent::Receive { address, channel_id, data: packet });
+					}
+				},
+				COMMAND_SEND_UNRELIABLE_FRAGMENT => {
+					// Moonlight doesn't send unreliable packets that need fragmenting.
+					reader.skip("fragment header", 4)?;
+					let length = reader.read_u16_be("data length")?;
+					reader.skip("fragment", 16)?;
+					reader.skip("data", length as usize)?;
+					tracing::debug!("Ignoring unreliable fragment on {} channel.", channel::name(channel_id));
+				},
+				COMMAND_BANDWIDTH_LIMIT => reader.skip("bandwidth limit", 8)?,
+				COMMAND_THROTTLE_CONFIGURE => reader.skip("throttle configure", 12)?,
+				_ => return Err(ParseError::UnknownType(command as u32)),
+			}
+
+			if command_header & COMMAND_FLAG_ACKNOWLEDGE != 0 {
+				acknowledgements.push((channel_id, reliable_sequence_number));
+			}
+		}
+
+		if let (Some(index), Some(sent_time)) = (peer_index, sent_time) {
+			self.send_acknowledgements(index, &acknowledgements, sent_time);
+		}
+
+		if let (true, Some(index)) = (disconnected, peer_index) {
+			if let Some(peer) = self.peers[index].take() {
+				if peer.state == PeerState::Connected {
+					self.events.push_back(ControlEvent::Disconnect(peer.address));
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Create a peer for a new client and verify its connect command, returns the index of the peer.
+	fn handle_connect(&mut self, address: SocketAddr, connect: Connect) -> Option<usize> {
+		let connect_id = u32::from_be_bytes(connect.connect_id);
+
+		// Clients send their connect command again until it is verified, the verify command is retransmitted instead.
+		if self.peers.iter().flatten().any(|peer| peer.address == address && peer.connect_id == connect_id) {
+			return None;
+		}
+
+		if connect.channel_count == 0 || connect.channel_count > u8::MAX as u32 {
+			tracing::warn!("Rejecting control stream connection from {address}, it requested {} channels.", connect.channel_count);
+			return None;
+		}
+
+		let Some(index) = self.peers.iter().position(Option::is_none) else {
+			tracing::warn!("Rejecting control stream connection from {address}, there are too many clients.");
+			return None;
+		};
+
+		// Session ids distinguish datagrams of this connection from datagrams of earlier connections.
+		let outgoing_session_id = connect.incoming_session_id.wrapping_add(1) & 3;
+		let incoming_session_id = connect.outgoing_session_id.wrapping_add(1) & 3;
+
+		let channel_count = (connect.channel_count as usize).min(self.channel_count);
+		let mtu = connect.mtu.clamp(MINIMUM_MTU, MAXIMUM_MTU).min(DEFAULT_MTU);
+		let window_size = connect.window_size.min(MAXIMUM_WINDOW_SIZE).clamp(MINIMUM_WINDOW_SIZE, MAXIMUM_WINDOW_SIZE);
+
+		let now = Instant::now();
+		self.peers[index] = Some(Peer {
+			address,
+			state: PeerState::AcknowledgingConnect,
+			outgoing_peer_id: connect.outgoing_peer_id,
+			incoming_session_id,
+			outgoing_session_id,
+			connect_id,
+			mtu,
+			outgoing_reliable_sequence_number: 0,
+			channels: (0..channel_count).map(|_| Channel::default()).collect(),
+			unacknowledged: Vec::new(),
+			last_received: now,
+			last_ping: now,
+		});
+
+		let mut body = Vec::with_capacity(40);
+		body.extend((index as u16).to_be_bytes());
+		body.push(outgoing_session_id);
+		body.push(incoming_session_id);
+		body.extend(mtu.to_be_bytes());
+		body.extend(window_size.to_be_bytes());
+		body.extend((channel_count as u32).to_be_bytes());
+		body.extend(0u32.to_be_bytes()); // Incoming bandwidth, unlimited.
+		body.extend(0u32.to_be_bytes()); // Outgoing bandwidth, unlimited.
+		body.extend(connect.packet_throttle_interval.to_be_bytes());
+		body.extend(connect.packet_throttle_acceleration.to_be_bytes());
+		body.extend(connect.packet_throttle_deceleration.to_be_bytes());
+		body.extend(connect.connect_id);
+		self.send_reliable_command(index, PEER_CHANNEL, COMMAND_VERIFY_CONNECT, &body);
+
+		Some(index)
+	}
+
+	fn handle_acknowledge(&mut self, index: usize, channel_id: u8, sequence_number: u16) {
+		let Some(peer) = self.peers[index].as_mut() else {
+			return;
+		};
+
+		let Some(position) = peer.unacknowledged.iter()
+			.position(|command| command.channel_id == channel_id && command.reliable_sequence_number == sequence_number)
+		else {
+			return;
+		};
+		peer.unacknowledged.remove(position);
+
+		// The client acknowledged the verify connect command, so the connection is established.
+		if channel_id == PEER_CHANNEL && peer.state == PeerState::AcknowledgingConnect {
+			peer.state = PeerState::Connected;
+			self.events.push_back(ControlEvent::Connect(peer.address));
+		}
+	}
+
+	/// Get a channel to receive packets on, which is only allowed once the client is connected.
+	fn receiving_channel(&mut self, index: usize, channel_id: u8) -> Result<&mut Channel, ParseError> {
+		let peer = self.peers[index].as_mut()
+			.filter(|peer| peer.state == PeerState::Connected)
+			.ok_or(ParseError::InvalidValue("packet before connecting"))?;
+
+		peer.channels.get_mut(channel_id as usize)
+			.ok_or(ParseError::InvalidValue("channel id"))
+	}
+
+	fn send_acknowledgements(&mut self, index: usize, acknowledgements: &[(u8, u16)], sent_time: u16) {
+		let commands: Vec<Vec<u8>> = acknowledgements.iter()
+			.map(|(channel_id, sequence_number)| {
+				let mut command = vec![COMMAND_ACKNOWLEDGE, *channel_id];
+				command.extend(sequence_number.to_be_bytes());
+				command.extend(sequence_number.to_be_bytes());
+				command.extend(sent_time.to_be_bytes());
+				command
+			})
+			.collect();
+
+		self.send_commands(index, &commands);
+	}
+
+	/// Send a command that the client acknowledges, it is retransmitted until it does.
+	fn send_reliable_command(&mut self, index: usize, channel_id: u8, command: u8, body: &[u8]) {
+		let Some(peer) = self.peers[index].as_mut() else {
+			return;
+		};
+
+		let sequence_number = if channel_id == PEER_CHANNEL {
+			peer.outgoing_reliable_sequence_number = peer.outgoing_reliable_sequence_number.wrapping_add(1);
+			peer.outgoing_reliable_sequence_number
+		} else {
+			let channel = &mut peer.channels[channel_id as usize];
+			channel.outgoing_reliable_sequence_number = channel.outgoing_reliable_sequence_number.wrapping_add(1);
+			channel.outgoing_reliable_sequence_number
+		};
+
+		let mut bytes = Vec::with_capacity(4 + body.len());
+		bytes.push(command | COMMAND_FLAG_ACKNOWLEDGE);
+		bytes.push(channel_id);
+		bytes.extend(sequence_number.to_be_bytes());
+		bytes.extend(body);
+
+		let now = Instant::now();
+		peer.unacknowledged.push(OutgoingCommand {
+			channel_id,
+			reliable_sequence_number: sequence_number,
+			bytes: bytes.clone(),
+			first_sent: now,
+			last_sent: now,
+		});
+
+		self.send_commands(index, &[bytes]);
+	}
+
+	/// Send reliable commands again that weren't acknowledged in time, ping clients that went quiet and drop clients
+	/// that stopped responding.
+	///
+	/// Pings are reliable commands, so a client that is gone stops acknowledging them even if the host has nothing
+	/// else to send.
+	fn retransmit(&mut self) {
+		let now = Instant::now();
+		for index in 0..self.peers.len() {
+			let Some(peer) = self.peers[index].as_mut() else {
+				continue;
+			};
+
+			let unacknowledged_timeout = peer.unacknowledged.iter().any(|command| now.duration_since(command.first_sent) > PEER_TIMEOUT);
+			if unacknowledged_timeout || now.duration_since(peer.last_received) > PEER_TIMEOUT {
+				tracing::info!("Control stream client {} stopped responding, disconnecting.", peer.address);
+				if peer.state == PeerState::Connected {
+					self.events.push_back(ControlEvent::Disconnect(peer.address));
+				}
+				self.peers[index] = None;
+				continue;
+			}
+
+			let commands: Vec<Vec<u8>> = peer.unacknowledged.iter_mut()
+				.filter(|command| now.duration_since(command.last_sent) >= RETRANSMIT_TIMEOUT)
+				.map(|command| {
+					command.last_sent = now;
+					command.bytes.clone()
+				})
+				.collect();
+
+			if !commands.is_empty() {
+				self.send_commands(index, &commands);
+			}
+
+			let Some(peer) = self.peers[index].as_mut() else {
+				continue;
+			};
+			if peer.state == PeerState::Connected
+				&& now.duration_since(peer.last_received) >= PING_INTERVAL
+				&& now.duration_since(peer.last_ping) >= PING_INTERVAL
+			{
+				peer.last_ping = now;
+				self.send_reliable_command(index, PEER_CHANNEL, COMMAND_PING, &[]);
+			}
+		}
+	}
+
+	/// Send commands to a client, combining as many commands in a datagram as the MTU allows.
+	fn send_commands(&self, index: usize, commands: &[Vec<u8>]) {
+		let Some(peer) = self.peers[index].as_ref() else {
+			return;
+		};
+
+		let header = peer.outgoing_peer_id
+			| ((peer.outgoing_session_id as u16) << HEADER_SESSION_SHIFT)
+			| HEADER_FLAG_SENT_TIME;
+		let sent_time = (self.start.elapsed().as_millis() & 0xFFFF) as u16;
+
+		let mut datagram = Vec::with_capacity(peer.mtu as usize);
+		for command in commands {
+			if !datagram.is_empty() && datagram.len() + command.len() > peer.mtu as usize {
+				self.send_datagram(&datagram, peer.address);
+				datagram.clear();
+			}
+
+			if datagram.is_empty() {
+				datagram.extend(header.to_be_bytes());
+				datagram.extend(sent_time.to_be_bytes());
+			}
+			datagram.extend(command);
+		}
+
+		if !datagram.is_empty() {
+			self.send_datagram(&datagram, peer.address);
+		}
+	}
+
+	/// Datagrams that can't be sent right away are recovered by retransmitting reliable commands.
+	fn send_datagram(&self, datagram: &[u8], address: SocketAddr) {
+		if let Err(e) = self.socket.try_send_to(datagram, address) {
+			tracing::debug!("Failed to send control datagram to {address}: {e}");
+		}
+	}
+}
+
+fn read_connect(reader: &mut ByteReader) -> Result<Connect, ParseError> {
+	let outgoing_peer_id = reader.read_u16_be("outgoing peer id")?;
+	let incoming_session_id = reader.read_u8("incoming session id")?;
+	let outgoing_session_id = reader.read_u8("outgoing session id")?;
+	let mtu = reader.read_u32_be("mtu")?;
+	let window_size = reader.read_u32_be("window size")?;
+	let channel_count = reader.read_u32_be("channel count")?;
+	reader.skip("bandwidth", 8)?;
+	let packet_throttle_interval = reader.read_u32_be("packet throttle interval")?;
+	let packet_throttle_acceleration = reader.read_u32_be("packet throttle acceleration")?;
+	let packet_throttle_deceleration = reader.read_u32_be("packet throttle deceleration")?;
+	let connect_id = reader.read_array("connect id")?;
+	reader.skip("connect data", 4)?;
+
+	if outgoing_peer_id > MAXIMUM_PEER_ID {
+		return Err(ParseError::InvalidValue("outgoing peer id"));
+	}
+
+	Ok(Connect {
+		outgoing_peer_id,
+		incoming_session_id,
+		outgoing_session_id,
+		mtu,
+		window_size,
+		channel_count,
+		packet_throttle_interval,
+		packet_throttle_acceleration,
+		packet_throttle_deceleration,
+		connect_id,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CHANNEL_COUNT: usize = 2;
+
+	/// Peer id the test client assigns to the host.
+	const CLIENT_PEER_ID: u16 = 7;
+
+	struct TestClient {
+		socket: UdpSocket,
+
+		/// Peer id the host assigned to the client.
+		peer_id: u16,
+		session_id: u8,
+	}
+
+	impl TestClient {
+		/// Send commands in a datagram, as a connected client.
+		fn send(&self, commands: &[Vec<u8>], host: &mut ControlHost) -> Result<(), ParseError> {
+			let header = self.peer_id | ((self.session_id as u16) << HEADER_SESSION_SHIFT) | HEADER_FLAG_SENT_TIME;
+			let mut datagram = header.to_be_bytes().to_vec();
+			datagram.extend(0x1234u16.to_be_bytes());
+			for command in commands {
+				datagram.extend(command);
+			}
+
+			let address = self.socket.local_addr().unwrap();
+			host.handle_datagram(&datagram, address)
+		}
+
+		/// Receive a datagram from the host, returns its commands.
+		async fn receive(&self) -> Vec<Vec<u8>> {
+			let mut buffer = vec![0u8; MAXIMUM_MTU as usize];
+			let length = tokio::time::timeout(Duration::from_secs(1), self.socket.recv(&mut buffer)).await
+				.expect("host did not send a datagram")
+				.unwrap();
+			split_commands(&buffer[HEADER_SIZE..length])
+		}
+	}
+
+	async fn bind() -> (ControlHost, UdpSocket) {
+		let host = ControlHost::bind(&TransportContext, "127.0.0.1", 0, 4, CHANNEL_COUNT).await.unwrap();
+		// Datagrams are sent without waiting, so wait until the runtime knows the socket is writable.
+		host.socket.writable().await.unwrap();
+		let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		(host, client)
+	}
+
+	fn connect_command(connect_id: u32) -> Vec<u8> {
+		let mut command = vec![COMMAND_CONNECT | COMMAND_FLAG_ACKNOWLEDGE, PEER_CHANNEL];
+		command.extend(1u16.to_be_bytes());
+		command.extend(CLIENT_PEER_ID.to_be_bytes());
+		command.push(0); // Incoming session id.
+		command.push(0); // Outgoing session id.
+		command.extend(DEFAULT_MTU.to_be_bytes());
+		command.extend(32768u32.to_be_bytes());
+		command.extend((CHANNEL_COUNT as u32).to_be_bytes());
+		command.extend([0; 8]); // Bandwidth.
+		command.extend(5000u32.to_be_bytes());
+		command.extend(2u32.to_be_bytes());
+		command.extend(2u32.to_be_bytes());
+		command.extend(connect_id.to_be_bytes());
+		command.extend([0; 4]); // Connect data.
+		command
+	}
+
+	fn acknowledge_command(channel_id: u8, sequence_number: u16) -> Vec<u8> {
+		let mut command = vec![COMMAND_ACKNOWLEDGE, channel_id];
+		command.extend(0u16.to_be_bytes());
+		command.extend(sequence_number.to_be_bytes());
+		command.extend(0u16.to_be_bytes());
+		command
+	}
+
+	fn reliable_command(channel_id: u8, sequence_number: u16, data: &[u8]) -> Vec<u8> {
+		let mut command = vec![COMMAND_SEND_RELIABLE | COMMAND_FLAG_ACKNOWLEDGE, channel_id];
+		command.extend(sequence_number.to_be_bytes());
+		command.extend((data.len() as u16).to_be_bytes());
+		command.extend(data);
+		command
+	}
+
+	fn fragment_command(channel_id: u8, sequence_number: u16, fragment: Fragment) -> Vec<u8> {
+		let mut command = vec![COMMAND_SEND_FRAGMENT | COMMAND_FLAG_ACKNOWLEDGE, channel_id];
+		command.extend(sequence_number.to_be_bytes());
+		command.extend(fragment.start_sequence_number.to_be_bytes());
+		command.extend((fragment.data.len() as u16).to_be_bytes());
+		command.extend(fragment.fragment_count.to_be_bytes());
+		command.extend(fragment.fragment_number.to_be_bytes());
+		command.extend(fragment.total_length.to_be_bytes());
+		command.extend(fragment.fragment_offset.to_be_bytes());
+		command.extend(fragment.data);
+		command
+	}
+
+	fn fragment(fragment_number: u32, total_length: u32, fragment_offset: u32, data: &[u8]) -> Fragment {
+		Fragment { start_sequence_number: 1, fragment_count: 2, fragment_number, total_length, fragment_offset, data: data.to_vec() }
+	}
+
+	/// Split the commands that the host sends, which are acknowledgements, verify connect, pings, disconnects and
+	/// reliable packets.
+	fn split_commands(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+		let mut commands = Vec::new();
+		while !bytes.is_empty() {
+			let length = match bytes[0] & COMMAND_MASK {
+				COMMAND_ACKNOWLEDGE => 8,
+				COMMAND_VERIFY_CONNECT => 44,
+				COMMAND_DISCONNECT => 8,
+				COMMAND_PING => 4,
+				COMMAND_SEND_RELIABLE => SEND_RELIABLE_SIZE + u16::from_be_bytes([bytes[4], bytes[5]]) as usize,
+				command => panic!("unexpected command {command}"),
+			};
+			commands.push(bytes[..length].to_vec());
+			bytes = &bytes[length..];
+		}
+
+		commands
+	}
+
+	fn command_type(command: &[u8]) -> u8 {
+		command[0] & COMMAND_MASK
+	}
+
+	fn sequence_number(command: &[u8]) -> u16 {
+		u16::from_be_bytes([command[2], command[3]])
+	}
+
+	/// Perform the connect handshake, returns the connected client.
+	async fn connect(host: &mut ControlHost, socket: UdpSocket) -> TestClient {
+		let address = socket.local_addr().unwrap();
+		let mut datagram = (MAXIMUM_PEER_ID | HEADER_FLAG_SENT_TIME).to_be_bytes().to_vec();
+		datagram.extend(0u16.to_be_bytes());
+		datagram.extend(connect_command(0xDEADBEEF));
+		host.handle_datagram(&datagram, address).unwrap();
+
+		let client = TestClient { socket, peer_id: 0, session_id: 0 };
+		let verify = client.receive().await;
+		assert_eq!(verify.len(), 1);
+		assert_eq!(command_type(&verify[0]), COMMAND_VERIFY_CONNECT);
+		let acknowledgement = client.receive().await;
+		assert_eq!(command_type(&acknowledgement[0]), COMMAND_ACKNOWLEDGE);
+
+		let verify = &verify[0];
+		let peer_id = u16::from_be_bytes([verify[4], verify[5]]);
+		let session_id = verify[7];
+		assert_eq!(&verify[40..44], &0xDEADBEEFu32.to_be_bytes());
+		let client = TestClient { peer_id, session_id, ..client };
+
+		assert!(host.events.is_empty(), "connected before acknowledging the verify connect command");
+		client.send(&[acknowledge_command(PEER_CHANNEL, sequence_number(verify))], host).unwrap();
+		assert!(matches!(host.events.pop_front(), Some(ControlEvent::Connect(connected)) if connected == address));
+
+		client
+	}
+
+	fn received(host: &mut ControlHost) -> Vec<Vec<u8>> {
+		host.events.drain(..)
+			.map(|event| match event {
+				ControlEvent::Receive { data, .. } => data,
+				event => panic!("unexpected event {event:?}"),
+			})
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn connect_and_verify() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+
+		let peer = host.peers[client.peer_id as usize].as_ref().unwrap();
+		assert_eq!(peer.state, PeerState::Connected);
+		assert_eq!(peer.outgoing_peer_id, CLIENT_PEER_ID);
+		assert_eq!(peer.channels.len(), CHANNEL_COUNT);
+		assert!(peer.unacknowledged.is_empty());
+	}
+
+	#[tokio::test]
+	async fn repeated_connect_is_ignored() {
+		let (mut host, socket) = bind().await;
+		let address = socket.local_addr().unwrap();
+		let mut datagram = (MAXIMUM_PEER_ID | HEADER_FLAG_SENT_TIME).to_be_bytes().to_vec();
+		datagram.extend(0u16.to_be_bytes());
+		datagram.extend(connect_command(1));
+
+		host.handle_datagram(&datagram, address).unwrap();
+		host.handle_datagram(&datagram, address).unwrap();
+		assert_eq!(host.peers.iter().flatten().count(), 1);
+	}
+
+	#[tokio::test]
+	async fn commands_before_connecting_are_rejected() {
+		let (mut host, socket) = bind().await;
+		let address = socket.local_addr().unwrap();
+		let mut datagram = 0u16.to_be_bytes().to_vec();
+		datagram.extend(reliable_command(0, 1, b"data"));
+
+		assert!(host.handle_datagram(&datagram, address).is_err());
+		assert!(host.events.is_empty());
+	}
+
+	#[tokio::test]
+	async fn acknowledge() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+		let address = client.socket.local_addr().unwrap();
+
+		// Reliable packets from the client are acknowledged.
+		client.send(&[reliable_command(0, 1, b"hello")], &mut host).unwrap();
+		assert_eq!(received(&mut host), vec![b"hello".to_vec()]);
+		let acknowledgements = client.receive().await;
+		assert_eq!(acknowledgements.len(), 1);
+		assert_eq!(command_type(&acknowledgements[0]), COMMAND_ACKNOWLEDGE);
+		assert_eq!(acknowledgements[0][1], 0);
+		assert_eq!(u16::from_be_bytes([acknowledgements[0][4], acknowledgements[0][5]]), 1);
+		assert_eq!(u16::from_be_bytes([acknowledgements[0][6], acknowledgements[0][7]]), 0x1234);
+
+		// Reliable packets from the host are retransmitted until the client acknowledges them.
+		host.send(address, b"world", 1).unwrap();
+		let sent = client.receive().await;
+		assert_eq!(command_type(&sent[0]), COMMAND_SEND_RELIABLE);
+		assert_eq!(&sent[0][SEND_RELIABLE_SIZE..], b"world");
+		assert_eq!(host.peers[client.peer_id as usize].as_ref().unwrap().unacknowledged.len(), 1);
+
+		client.send(&[acknowledge_command(1, sequence_number(&sent[0]))], &mut host).unwrap();
+		assert!(host.peers[client.peer_id as usize].as_ref().unwrap().unacknowledged.is_empty());
+	}
+
+	#[tokio::test]
+	async fn duplicate_packets_are_delivered_once() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+
+		client.send(&[reliable_command(0, 1, b"once")], &mut host).unwrap();
+		client.send(&[reliable_command(0, 1, b"once")], &mut host).unwrap();
+		assert_eq!(received(&mut host), vec![b"once".to_vec()]);
+	}
+
+	#[tokio::test]
+	async fn out_of_order_packets_are_delivered_in_order() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+
+		client.send(&[reliable_command(0, 2, b"second")], &mut host).unwrap();
+		assert!(host.events.is_empty());
+		client.send(&[reliable_command(0, 1, b"first")], &mut host).unwrap();
+		assert_eq!(received(&mut host), vec![b"first".to_vec(), b"second".to_vec()]);
+	}
+
+	#[tokio::test]
+	async fn fragment_reassembly() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+
+		let packet: Vec<u8> = (0..=255).collect();
+		let (first, second) = packet.split_at(200);
+		let total_length = packet.len() as u32;
+
+		// The second fragment arrives first, the packet is delivered once both arrived.
+		client.send(&[fragment_command(0, 2, fragment(1, total_length, 200, second))], &mut host).unwrap();
+		assert!(host.events.is_empty());
+		client.send(&[fragment_command(0, 1, fragment(0, total_length, 0, first))], &mut host).unwrap();
+		assert_eq!(received(&mut host), vec![packet]);
+	}
+
+	#[tokio::test]
+	async fn inconsistent_fragments_are_dropped() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+
+		// The offset of the second fragment points past the end of the packet.
+		client.send(&[fragment_command(0, 1, fragment(0, 8, 0, &[1; 4]))], &mut host).unwrap();
+		client.send(&[fragment_command(0, 2, fragment(1, 8, 6, &[2; 4]))], &mut host).unwrap();
+		assert!(host.events.is_empty());
+
+		// Later packets are still delivered.
+		client.send(&[reliable_command(0, 3, b"next")], &mut host).unwrap();
+		assert_eq!(received(&mut host), vec![b"next".to_vec()]);
+	}
+
+	#[tokio::test]
+	async fn sequence_number_wrap() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+
+		let peer = host.peers[client.peer_id as usize].as_mut().unwrap();
+		peer.channels[0].incoming_reliable_sequence_number = 0xFFFD;
+
+		client.send(&[reliable_command(0, 0xFFFE, b"a")], &mut host).unwrap();
+		// The packet after the wrap arrives before the one right before it.
+		client.send(&[reliable_command(0, 0, b"c")], &mut host).unwrap();
+		client.send(&[reliable_command(0, 0xFFFF, b"b")], &mut host).unwrap();
+		client.send(&[reliable_command(0, 1, b"d")], &mut host).unwrap();
+		assert_eq!(received(&mut host), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+
+		// Packets from before the wrap are old, not far ahead.
+		client.send(&[reliable_command(0, 0xFFFF, b"b")], &mut host).unwrap();
+		assert!(host.events.is_empty());
+	}
+
+	#[tokio::test]
+	async fn outgoing_sequence_number_wrap() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+		let address = client.socket.local_addr().unwrap();
+
+		host.peers[client.peer_id as usize].as_mut().unwrap().channels[0].outgoing_reliable_sequence_number = u16::MAX;
+		host.send(address, b"wrapped", 0).unwrap();
+		let sent = client.receive().await;
+		assert_eq!(sequence_number(&sent[0]), 0);
+	}
+
+	#[tokio::test]
+	async fn ping_quiet_client() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+
+		let Some(quiet_since) = Instant::now().checked_sub(PING_INTERVAL) else {
+			return;
+		};
+		let peer = host.peers[client.peer_id as usize].as_mut().unwrap();
+		peer.last_received = quiet_since;
+		peer.last_ping = quiet_since;
+
+		host.retransmit();
+		let ping = client.receive().await;
+		assert_eq!(command_type(&ping[0]), COMMAND_PING);
+		assert_ne!(ping[0][0] & COMMAND_FLAG_ACKNOWLEDGE, 0);
+
+		// Acknowledging the ping counts as a sign of life.
+		client.send(&[acknowledge_command(PEER_CHANNEL, sequence_number(&ping[0]))], &mut host).unwrap();
+		let peer = host.peers[client.peer_id as usize].as_ref().unwrap();
+		assert!(peer.unacknowledged.is_empty());
+		assert!(peer.last_received > quiet_since);
+	}
+
+	#[tokio::test]
+	async fn disconnect_silent_client() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+		let address = client.socket.local_addr().unwrap();
+
+		let Some(silent_since) = Instant::now().checked_sub(PEER_TIMEOUT + Duration::from_secs(1)) else {
+			return;
+		};
+		host.peers[client.peer_id as usize].as_mut().unwrap().last_received = silent_since;
+
+		host.retransmit();
+		assert!(matches!(host.events.pop_front(), Some(ControlEvent::Disconnect(disconnected)) if disconnected == address));
+		assert!(host.peers.iter().all(Option::is_none));
+	}
+
+	#[tokio::test]
+	async fn disconnect_takes_next_sequence_number() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+		let address = client.socket.local_addr().unwrap();
+
+		let acknowledged = host.peers[client.peer_id as usize].as_ref().unwrap().outgoing_reliable_sequence_number;
+		host.disconnect(address);
+		let disconnect = client.receive().await;
+		assert_eq!(command_type(&disconnect[0]), COMMAND_DISCONNECT);
+		assert_eq!(sequence_number(&disconnect[0]), acknowledged.wrapping_add(1));
+		assert!(host.peers.iter().all(Option::is_none));
+	}
+
+	#[tokio::test]
+	async fn disconnect_command() {
+		let (mut host, socket) = bind().await;
+		let client = connect(&mut host, socket).await;
+		let address = client.socket.local_addr().unwrap();
+
+		let mut disconnect = vec![COMMAND_DISCONNECT, PEER_CHANNEL];
+		disconnect.extend(0u16.to_be_bytes());
+		disconnect.extend(0u32.to_be_bytes());
+		client.send(&[disconnect], &mut host).unwrap();
+		assert!(matches!(host.events.pop_front(), Some(ControlEvent::Disconnect(disconnected)) if disconnected == address));
+		assert!(host.peers.iter().all(Option::is_none));
+	}
+}
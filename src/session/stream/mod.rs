@@ -1,13 +1,20 @@
 pub use self::{
 	audio::{AudioStreamContext, AudioStream},
-	video::{VideoStreamContext, VideoStream},
-	control::ControlStream,
+	video::{benchmark_capture, benchmark_conversion, benchmark_encoders, BenchmarkOptions, BenchmarkResult, EncoderCapabilities, VideoStreamContext, VideoStreamSettings, VideoStream, VideoTap},
+	control::{ControlStream, TerminationReason, TransportContext},
 };
 
 mod audio;
 mod control;
+#[cfg(feature = "netsim")]
+mod netsim;
+mod ping;
+mod socket;
 mod video;
 
+#[cfg(feature = "webrtc")]
+pub use self::video::Codec;
+
 #[derive(Debug)]
 #[repr(C)]
 struct RtpHeader {
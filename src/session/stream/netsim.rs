@@ -0,0 +1,93 @@
+//! Simulated network impairments for outgoing stream packets, to test FEC, pacing and recovery without external tools.
+//!
+//! Decisions come from a seeded generator, so the same configuration drops and reorders the same packets every run.
+//! Jitter delays packets but never reorders them, only `reorder` does.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{net::UdpSocket, sync::mpsc, time::Instant};
+
+use crate::config::NetworkSimulationConfig;
+
+/// Sends packets through a simulated network, in place of sending them on the socket directly.
+pub struct NetworkSimulator {
+	config: NetworkSimulationConfig,
+
+	/// State of the xorshift generator, never zero.
+	state: u64,
+
+	/// Packet that was held back, it is sent after the next packet.
+	held: Option<(Vec<u8>, SocketAddr)>,
+
+	queue: mpsc::UnboundedSender<(Instant, Vec<u8>, SocketAddr)>,
+}
+
+impl NetworkSimulator {
+	/// Create a simulator that sends on `socket`, the sending task stops when the simulator is dropped.
+	pub fn new(name: &str, config: NetworkSimulationConfig, socket: Arc<UdpSocket>) -> Self {
+		tracing::warn!(
+			"Simulating network impairments on the {name} stream: {}% loss, {}% reordering, {}ms delay, {}ms jitter.",
+			config.loss * 100.0,
+			config.reorder * 100.0,
+			config.delay,
+			config.jitter,
+		);
+
+		let (queue, mut queue_rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>, SocketAddr)>();
+		tokio::spawn(async move {
+			while let Some((deadline, packet, address)) = queue_rx.recv().await {
+				tokio::time::sleep_until(deadline).await;
+				if let Err(e) = socket.send_to(&packet, address).await {
+					tracing::warn!("Failed to send simulated packet to client: {e}");
+				}
+			}
+		});
+
+		Self {
+			state: config.seed.max(1),
+			config,
+			held: None,
+			queue,
+		}
+	}
+
+	/// Drop, hold back or delay a packet according to the configuration.
+	pub fn send_to(&mut self, packet: Vec<u8>, address: SocketAddr) {
+		if self.chance(self.config.loss) {
+			tracing::trace!("Simulating loss of a packet of {} bytes.", packet.len());
+			return;
+		}
+
+		if self.held.is_none() && self.chance(self.config.reorder) {
+			tracing::trace!("Simulating reordering of a packet of {} bytes.", packet.len());
+			self.held = Some((packet, address));
+			return;
+		}
+
+		self.enqueue(packet, address);
+		if let Some((held, address)) = self.held.take() {
+			self.enqueue(held, address);
+		}
+	}
+
+	fn enqueue(&mut self, packet: Vec<u8>, address: SocketAddr) {
+		let jitter = if self.config.jitter > 0 { self.next() % (self.config.jitter + 1) } else { 0 };
+		let deadline = Instant::now() + Duration::from_millis(self.config.delay + jitter);
+
+		// Fails only if the sending task stopped, in which case the stream is shutting down.
+		let _ = self.queue.send((deadline, packet, address));
+	}
+
+	/// Returns true with a probability of `probability`.
+	fn chance(&mut self, probability: f64) -> bool {
+		probability > 0.0 && (self.next() as f64 / u64::MAX as f64) < probability
+	}
+
+	/// Next value of a xorshift64 generator.
+	fn next(&mut self) -> u64 {
+		self.state ^= self.state << 13;
+		self.state ^= self.state >> 7;
+		self.state ^= self.state << 17;
+		self.state
+	}
+}
@@ -0,0 +1,73 @@
+//! Decides where the audio and video streams are sent, based on the PING messages clients send to their sockets.
+
+use std::net::SocketAddr;
+
+/// Message that clients which don't know the ping payload send.
+const LEGACY_PING: &[u8] = b"PING";
+
+/// Length of the sequence number that follows the payload in an authenticated PING.
+const SEQUENCE_NUMBER_LENGTH: usize = 4;
+
+/// Tracks the client address of a stream.
+///
+/// Clients that received the ping payload in the RTSP SETUP response send it with every PING, the address of the first
+/// such PING is pinned and PINGs from other addresses are ignored, so a third party can't redirect the stream.
+/// Legacy clients send a plain "PING", which pins the address until an authenticated PING arrives.
+pub struct PingTracker {
+	/// Name of the stream, for logging.
+	stream: &'static str,
+
+	/// Payload the client received in the RTSP SETUP response.
+	payload: Vec<u8>,
+
+	/// Address the stream is sent to.
+	address: Option<SocketAddr>,
+
+	/// Whether the address was pinned by an authenticated PING.
+	authenticated: bool,
+}
+
+impl PingTracker {
+	pub fn new(stream: &'static str, payload: &str) -> Self {
+		Self { stream, payload: payload.as_bytes().to_vec(), address: None, authenticated: false }
+	}
+
+	/// The address the stream should be sent to, if a PING was accepted.
+	pub fn address(&self) -> Option<SocketAddr> {
+		self.address
+	}
+
+	/// Handle a message received on the stream socket, returns whether the client address changed.
+	pub fn receive(&mut self, message: &[u8], source: SocketAddr) -> bool {
+		let authenticated = if message == LEGACY_PING {
+			false
+		} else if !self.payload.is_empty()
+			&& message.len() == self.payload.len() + SEQUENCE_NUMBER_LENGTH
+			&& message.starts_with(&self.payload)
+		{
+			true
+		} else {
+			tracing::warn!("Received unknown message on {} stream of length {}.", self.stream, message.len());
+			return false;
+		};
+
+		tracing::trace!("Received {} stream PING message from {source}.", self.stream);
+		if self.address == Some(source) {
+			self.authenticated |= authenticated;
+			return false;
+		}
+
+		// An authenticated PING can replace an address pinned by a legacy PING, but nothing replaces an authenticated address.
+		if let Some(address) = self.address {
+			if self.authenticated || !authenticated {
+				tracing::warn!("Ignoring {} stream PING from {source}, the stream is pinned to {address}.", self.stream);
+				return false;
+			}
+		}
+
+		tracing::debug!("Sending {} stream to {source}.", self.stream);
+		self.address = Some(source);
+		self.authenticated = authenticated;
+		true
+	}
+}
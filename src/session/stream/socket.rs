@@ -0,0 +1,94 @@
+//! Event loop shared by the UDP sockets of the audio and video streams.
+//!
+//! Packets to send and PINGs from the client are awaited together with the session shutdown, so the socket is idle
+//! while there is nothing to do and released as soon as the session stops.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use async_shutdown::ShutdownManager;
+use tokio::{net::UdpSocket, sync::mpsc};
+
+use crate::session::guests::{Guest, Guests};
+
+use super::ping::PingTracker;
+
+/// Something the stream needs to act on.
+pub enum SocketEvent {
+	/// A packet that should be sent to the client at this address.
+	Send(Vec<u8>, SocketAddr),
+
+	/// A PING moved the stream to a new client address.
+	ClientAddressChanged(SocketAddr),
+
+	/// A guest of the session sent a PING from this address.
+	GuestPing(SocketAddr, Guest),
+}
+
+pub struct StreamSocket {
+	socket: Arc<UdpSocket>,
+	packet_rx: mpsc::Receiver<Vec<u8>>,
+	ping_tracker: PingTracker,
+	stop_signal: ShutdownManager<()>,
+	buffer: [u8; 1024],
+
+	/// Guests of the session, whose PINGs are reported instead of moving the stream.
+	guests: Option<Guests>,
+}
+
+impl StreamSocket {
+	pub fn new(socket: Arc<UdpSocket>, packet_rx: mpsc::Receiver<Vec<u8>>, ping_tracker: PingTracker, stop_signal: ShutdownManager<()>) -> Self {
+		Self { socket, packet_rx, ping_tracker, stop_signal, buffer: [0; 1024], guests: None }
+	}
+
+	/// Report PINGs from guests of the session, instead of ignoring them.
+	pub fn accept_guests(&mut self, guests: Guests) {
+		self.guests = Some(guests);
+	}
+
+	/// Wait for the next event, returns `None` once the session stops, the packet channel closes or the socket fails.
+	///
+	/// Packets are dropped until the client sent a PING, since there is nowhere to send them before that.
+	pub async fn next(&mut self) -> Option<SocketEvent> {
+		loop {
+			tokio::select! {
+				_ = self.stop_signal.wait_shutdown_triggered() => {
+					tracing::debug!("Session stopped, closing stream socket.");
+					return None;
+				},
+
+				packet = self.packet_rx.recv() => {
+					let Some(packet) = packet else {
+						tracing::debug!("Packet channel closed.");
+						return None;
+					};
+
+					if let Some(client_address) = self.ping_tracker.address() {
+						return Some(SocketEvent::Send(packet, client_address));
+					}
+				},
+
+				message = self.socket.recv_from(&mut self.buffer) => {
+					let (length, address) = match message {
+						Ok(message) => message,
+						Err(e) => {
+							tracing::warn!("Failed to receive message: {e}");
+							return None;
+						},
+					};
+
+					// Guests never share an address with the client that owns the session.
+					if let Some(guest) = self.guests.as_ref().and_then(|guests| guests.find(address.ip())) {
+						tracing::trace!("Received PING from guest {} at {address}.", guest.client_id);
+						return Some(SocketEvent::GuestPing(address, guest));
+					}
+
+					if self.ping_tracker.receive(&self.buffer[..length], address) {
+						if let Some(client_address) = self.ping_tracker.address() {
+							return Some(SocketEvent::ClientAddressChanged(client_address));
+						}
+					}
+				},
+			}
+		}
+	}
+}
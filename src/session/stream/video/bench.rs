@@ -0,0 +1,330 @@
+//! Benchmark of the configured encoders on synthetic frames, to help choosing encoders and resolutions for a host,
+//! of frame capture on the desktop of the host and of the conversion of captured frames for encoders that can't read them
+//! from the GPU.
+
+use std::time::Duration;
+#[cfg(feature = "nvidia")]
+use std::time::Instant;
+
+#[cfg(feature = "nvidia")]
+use ffmpeg::format::Pixel;
+
+use crate::config::VideoStreamConfig;
+#[cfg(feature = "nvidia")]
+use crate::{config::{ColorRange, ColorSpace, H264DecoderConfig}, ffmpeg::check_ret};
+
+#[cfg(feature = "nvidia")]
+use super::{capture::FrameCapturer, convert::{Converter, OutputFormat}, encoder::Encoder, pipeline::create_frame};
+
+/// Number of frames that are encoded before measuring, so that encoder initialization doesn't skew the results.
+#[cfg(feature = "nvidia")]
+const WARMUP_FRAMES: u32 = 10;
+
+pub struct BenchmarkOptions {
+	/// Resolutions to encode at, as width and height.
+	pub resolutions: Vec<(u32, u32)>,
+
+	/// Number of frames to encode per encoder and resolution.
+	pub frames: u32,
+
+	/// Framerate the encoders are configured for.
+	pub framerate: u32,
+
+	/// Target bitrate in bits per second.
+	pub bitrate: usize,
+}
+
+impl Default for BenchmarkOptions {
+	fn default() -> Self {
+		Self {
+			resolutions: vec![(1280, 720), (1920, 1080), (2560, 1440), (3840, 2160)],
+			frames: 600,
+			framerate: 60,
+			bitrate: 20_000_000,
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct BenchmarkResult {
+	pub codec: String,
+	pub width: u32,
+	pub height: u32,
+
+	/// Frames per second the encoder achieved, if frames were encoded back to back.
+	pub fps: f64,
+
+	/// Time from sending a frame to the encoder until its packet is received.
+	pub latency_p50: Duration,
+	pub latency_p95: Duration,
+	pub latency_p99: Duration,
+
+	/// Bitrate of the encoded stream in bits per second, at the configured framerate.
+	pub bitrate: usize,
+
+	/// Bitrate that the encoder was configured with.
+	pub target_bitrate: usize,
+}
+
+impl BenchmarkResult {
+	/// Achieved bitrate relative to the target bitrate, where 1.0 is a perfect match.
+	pub fn bitrate_accuracy(&self) -> f64 {
+		self.bitrate as f64 / self.target_bitrate.max(1) as f64
+	}
+}
+
+#[derive(Debug)]
+pub struct CaptureBenchmarkResult {
+	/// Name of the capture backend.
+	pub backend: &'static str,
+
+	/// Frames per second that were captured, if frames were acquired back to back.
+	pub fps: f64,
+
+	/// Time it took to acquire a frame, including the wait for a new frame.
+	pub latency_p50: Duration,
+	pub latency_p95: Duration,
+}
+
+#[derive(Debug)]
+pub struct ConversionBenchmarkResult {
+	/// Format that frames were converted to.
+	pub format: &'static str,
+
+	/// Implementation that converted the rows, the scalar implementation is the reference for the others.
+	pub implementation: &'static str,
+
+	pub width: u32,
+	pub height: u32,
+
+	/// Frames per second that were converted, if frames were converted back to back.
+	pub fps: f64,
+
+	/// Time it took to convert a frame.
+	pub latency_p50: Duration,
+	pub latency_p95: Duration,
+}
+
+/// Encode synthetic frames with every configured encoder at every resolution.
+///
+/// Encoders that fail to open are skipped, this blocks until all benchmarks are done.
+#[cfg(feature = "nvidia")]
+#[allow(clippy::result_unit_err)]
+pub fn benchmark_encoders(config: &VideoStreamConfig, options: &BenchmarkOptions) -> Result<Vec<BenchmarkResult>, ()> {
+	let cuda_device = cudarc::driver::CudaDevice::new(0)
+		.map_err(|e| tracing::error!("Failed to initialize CUDA: {e}"))?;
+
+	let mut codec_names: Vec<String> = Vec::new();
+	for codec_name in config.codecs_h264().into_iter().chain(config.codecs_hevc()) {
+		if !codec_names.contains(&codec_name) {
+			codec_names.push(codec_name);
+		}
+	}
+
+	let mut results = Vec::new();
+	for codec_name in &codec_names {
+		for &(width, height) in &options.resolutions {
+			let Ok(mut encoder) = Encoder::new(
+				&cuda_device,
+				codec_name,
+				width, height,
+				None,
+				options.framerate,
+				options.bitrate,
+				1,
+				ColorSpace::default(),
+				ColorRange::default(),
+				&H264DecoderConfig::default(),
+				&config.encoder_options,
+			) else {
+				tracing::warn!("Skipping encoder '{codec_name}' at {width}x{height}, it failed to open.");
+				continue;
+			};
+
+			tracing::info!("Benchmarking encoder '{codec_name}' at {width}x{height}.");
+			match benchmark(&mut encoder, codec_name, width, height, options) {
+				Ok(result) => results.push(result),
+				Err(()) => tracing::warn!("Benchmark of encoder '{codec_name}' at {width}x{height} failed."),
+			}
+		}
+	}
+
+	Ok(results)
+}
+
+#[cfg(feature = "nvidia")]
+fn benchmark(encoder: &mut Encoder, codec_name: &str, width: u32, height: u32, options: &BenchmarkOptions) -> Result<BenchmarkResult, ()> {
+	let mut source = ffmpeg::frame::Video::new(Pixel::ZRGB32, width, height);
+	let mut frame = create_frame(width, height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
+
+	let mut latencies = Vec::with_capacity(options.frames as usize);
+	let mut encoded_bytes = 0;
+	for index in 0..WARMUP_FRAMES + options.frames {
+		// Uploading the frame isn't part of the measurement, captured frames are on the GPU already.
+		draw_frame(&mut source, index);
+		unsafe {
+			check_ret(ffmpeg::sys::av_hwframe_transfer_data(frame.as_mut_ptr(), source.as_ptr(), 0))
+				.map_err(|e| tracing::error!("Failed to upload frame to GPU: {e}"))?;
+		}
+		frame.set_pts(Some(index as i64));
+
+		let start = Instant::now();
+		let bytes = encoder.encode_frame(&frame)?;
+		if index >= WARMUP_FRAMES {
+			latencies.push(start.elapsed());
+			encoded_bytes += bytes;
+		}
+	}
+
+	let total: Duration = latencies.iter().sum();
+	latencies.sort();
+
+	Ok(BenchmarkResult {
+		codec: codec_name.to_string(),
+		width,
+		height,
+		fps: latencies.len() as f64 / total.as_secs_f64().max(f64::EPSILON),
+		latency_p50: percentile(&latencies, 50),
+		latency_p95: percentile(&latencies, 95),
+		latency_p99: percentile(&latencies, 99),
+		bitrate: (encoded_bytes as u64 * 8 * options.framerate as u64 / options.frames.max(1) as u64) as usize,
+		target_bitrate: options.bitrate,
+	})
+}
+
+/// Capture frames of the desktop at `framerate`, blocking until `frames` frames are captured.
+///
+/// NvFBC is the only capture backend, so there is no other backend to compare it with.
+#[cfg(feature = "nvidia")]
+pub fn benchmark_capture(framerate: u32, frames: u32) -> Result<CaptureBenchmarkResult, ()> {
+	// The CUDA context has to exist before the capturer binds to it.
+	let _cuda_device = cudarc::driver::CudaDevice::new(0)
+		.map_err(|e| tracing::error!("Failed to initialize CUDA: {e}"))?;
+
+	let mut latencies = FrameCapturer::new()?.measure(framerate, WARMUP_FRAMES + frames)?;
+	latencies.drain(..WARMUP_FRAMES as usize);
+	let total: Duration = latencies.iter().sum();
+	latencies.sort();
+
+	Ok(CaptureBenchmarkResult {
+		backend: "NvFBC",
+		fps: latencies.len() as f64 / total.as_secs_f64().max(f64::EPSILON),
+		latency_p50: percentile(&latencies, 50),
+		latency_p95: percentile(&latencies, 95),
+	})
+}
+
+/// Convert synthetic frames to NV12 and P010 at every resolution, with the scalar implementation and the one the CPU
+/// supports.
+///
+/// This blocks until all benchmarks are done.
+#[cfg(feature = "nvidia")]
+#[allow(clippy::result_unit_err)]
+pub fn benchmark_conversion(options: &BenchmarkOptions) -> Result<Vec<ConversionBenchmarkResult>, ()> {
+	let mut results = Vec::new();
+	for &(width, height) in &options.resolutions {
+		let mut source = ffmpeg::frame::Video::new(Pixel::ZRGB32, width, height);
+		draw_frame(&mut source, 0);
+
+		for (format, name) in [(OutputFormat::Nv12, "NV12"), (OutputFormat::P010, "P010")] {
+			let detected = Converter::new(format, ColorSpace::default(), ColorRange::default());
+			let scalar = Converter::scalar(format, ColorSpace::default(), ColorRange::default());
+			let converters = if detected.implementation() == scalar.implementation() { vec![scalar] } else { vec![scalar, detected] };
+
+			for converter in converters {
+				tracing::info!("Benchmarking {name} conversion at {width}x{height} with the {} implementation.", converter.implementation());
+				results.push(benchmark_converter(&converter, name, &source, options)?);
+			}
+		}
+	}
+
+	Ok(results)
+}
+
+#[cfg(feature = "nvidia")]
+fn benchmark_converter(
+	converter: &Converter,
+	format: &'static str,
+	source: &ffmpeg::frame::Video,
+	options: &BenchmarkOptions,
+) -> Result<ConversionBenchmarkResult, ()> {
+	let width = source.width() as usize;
+	let height = source.height() as usize;
+	let sample_size = converter.format().bytes_per_sample();
+	let y_stride = width * sample_size;
+	let uv_stride = width.div_ceil(2) * 2 * sample_size;
+	let mut y = vec![0; y_stride * height];
+	let mut uv = vec![0; uv_stride * height.div_ceil(2)];
+
+	let mut latencies = Vec::with_capacity(options.frames as usize);
+	for index in 0..WARMUP_FRAMES + options.frames {
+		let start = Instant::now();
+		converter.convert(source.data(0), source.stride(0), width, height, &mut y, y_stride, &mut uv, uv_stride)?;
+		if index >= WARMUP_FRAMES {
+			latencies.push(start.elapsed());
+		}
+	}
+
+	let total: Duration = latencies.iter().sum();
+	latencies.sort();
+
+	Ok(ConversionBenchmarkResult {
+		format,
+		implementation: converter.implementation(),
+		width: width as u32,
+		height: height as u32,
+		fps: latencies.len() as f64 / total.as_secs_f64().max(f64::EPSILON),
+		latency_p50: percentile(&latencies, 50),
+		latency_p95: percentile(&latencies, 95),
+	})
+}
+
+/// Value at `percentile` of sorted durations.
+#[cfg(feature = "nvidia")]
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+	let index = (sorted.len() * percentile / 100).min(sorted.len().saturating_sub(1));
+	sorted.get(index).copied().unwrap_or_default()
+}
+
+/// Draw a moving gradient with noise, so that every frame has detail and motion for the encoder to work on.
+#[cfg(feature = "nvidia")]
+fn draw_frame(frame: &mut ffmpeg::frame::Video, index: u32) {
+	let width = frame.width() as usize;
+	let height = frame.height() as usize;
+	let stride = frame.stride(0);
+	let data = frame.data_mut(0);
+
+	for y in 0..height {
+		let row = &mut data[y * stride..][..width * 4];
+		for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+			let noise = (x as u32).wrapping_mul(0x9E37_79B9) ^ (y as u32).wrapping_mul(0x85EB_CA6B) ^ index.wrapping_mul(0xC2B2_AE35);
+			pixel[0] = (x as u32).wrapping_add(index.wrapping_mul(4)) as u8;
+			pixel[1] = (y as u32).wrapping_add(index.wrapping_mul(2)) as u8;
+			pixel[2] = ((x + y) as u32 / 4) as u8 ^ (noise >> 28) as u8;
+			pixel[3] = 0xFF;
+		}
+	}
+}
+
+/// Without NVENC there are no encoders to benchmark.
+#[cfg(not(feature = "nvidia"))]
+#[allow(clippy::result_unit_err)]
+pub fn benchmark_encoders(_config: &VideoStreamConfig, _options: &BenchmarkOptions) -> Result<Vec<BenchmarkResult>, ()> {
+	tracing::error!("Benchmarking encoders requires building with the 'nvidia' feature.");
+	Err(())
+}
+
+/// Without NvFBC there is no capture backend to benchmark.
+#[cfg(not(feature = "nvidia"))]
+pub fn benchmark_capture(_framerate: u32, _frames: u32) -> Result<CaptureBenchmarkResult, ()> {
+	tracing::error!("Benchmarking capture requires building with the 'nvidia' feature.");
+	Err(())
+}
+
+/// Frames are only converted for NVENC, so the conversion isn't built without it.
+#[cfg(not(feature = "nvidia"))]
+#[allow(clippy::result_unit_err)]
+pub fn benchmark_conversion(_options: &BenchmarkOptions) -> Result<Vec<ConversionBenchmarkResult>, ()> {
+	tracing::error!("Benchmarking frame conversion requires building with the 'nvidia' feature.");
+	Err(())
+}
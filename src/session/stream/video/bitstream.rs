@@ -0,0 +1,386 @@
+//! Parsing of H.264 and HEVC bitstreams, to make sure parameter sets reach the client.
+//!
+//! Encoders write Annex-B bitstreams, where NAL units are separated by start codes.
+//! Extradata of some encoders is an AVCC configuration record instead, where NAL units are prefixed by their length.
+
+use base64::Engine;
+
+/// Start code that is written before every NAL unit in Annex-B bitstreams.
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Codec of a bitstream, which decides how NAL unit types are encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+	H264,
+	Hevc,
+}
+
+/// Type of a NAL unit, only the types that matter for parameter sets are distinguished.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NalUnitType {
+	Vps,
+	Sps,
+	Pps,
+	Other,
+}
+
+impl Codec {
+	pub fn nal_unit_type(&self, nal_unit: &[u8]) -> NalUnitType {
+		let Some(header) = nal_unit.first() else {
+			return NalUnitType::Other;
+		};
+
+		match self {
+			Self::H264 => match header & 0x1f {
+				7 => NalUnitType::Sps,
+				8 => NalUnitType::Pps,
+				_ => NalUnitType::Other,
+			},
+			Self::Hevc => match (header >> 1) & 0x3f {
+				32 => NalUnitType::Vps,
+				33 => NalUnitType::Sps,
+				34 => NalUnitType::Pps,
+				_ => NalUnitType::Other,
+			},
+		}
+	}
+}
+
+/// Iterate over the NAL units of an Annex-B bitstream, without their start codes.
+pub fn annexb_nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+	let mut remaining = match find_start_code(data) {
+		Some((_, end)) => &data[end..],
+		None => &[][..],
+	};
+
+	std::iter::from_fn(move || {
+		if remaining.is_empty() {
+			return None;
+		}
+
+		match find_start_code(remaining) {
+			Some((start, end)) => {
+				let nal_unit = &remaining[..start];
+				remaining = &remaining[end..];
+				Some(nal_unit)
+			},
+			None => Some(std::mem::take(&mut remaining)),
+		}
+	})
+}
+
+/// Find the next three or four byte start code, returns where it starts and where the NAL unit after it starts.
+fn find_start_code(data: &[u8]) -> Option<(usize, usize)> {
+	let position = data.windows(3).position(|window| window == [0, 0, 1])?;
+	let start = if position > 0 && data[position - 1] == 0 { position - 1 } else { position };
+	Some((start, position + 3))
+}
+
+/// Convert length prefixed NAL units to an Annex-B bitstream.
+pub fn avcc_to_annexb(data: &[u8], length_size: usize) -> Result<Vec<u8>, ()> {
+	if !(1..=4).contains(&length_size) {
+		tracing::warn!("Invalid NAL unit length size {length_size}.");
+		return Err(());
+	}
+
+	let mut output = Vec::with_capacity(data.len());
+	let mut remaining = data;
+	while !remaining.is_empty() {
+		if remaining.len() < length_size {
+			tracing::warn!("Truncated NAL unit length in AVCC bitstream.");
+			return Err(());
+		}
+
+		let length = remaining[..length_size].iter().fold(0usize, |length, byte| (length << 8) | *byte as usize);
+		remaining = &remaining[length_size..];
+		if remaining.len() < length {
+			tracing::warn!("NAL unit of {length} bytes exceeds the remaining {} bytes of the AVCC bitstream.", remaining.len());
+			return Err(());
+		}
+
+		output.extend_from_slice(&START_CODE);
+		output.extend_from_slice(&remaining[..length]);
+		remaining = &remaining[length..];
+	}
+
+	Ok(output)
+}
+
+/// Size of the NAL unit length prefix of packets from an encoder, if its extradata is an H.264 configuration record.
+///
+/// Returns `None` for encoders that write Annex-B bitstreams.
+pub fn avcc_length_size(codec: Codec, extradata: &[u8]) -> Option<usize> {
+	if codec != Codec::H264 || extradata.first() != Some(&1) {
+		return None;
+	}
+
+	extradata.get(4).map(|byte| (byte & 0x03) as usize + 1)
+}
+
+/// Parameter sets a decoder needs before it can decode an IDR frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParameterSets {
+	codec: Codec,
+
+	/// Parameter set NAL units without start codes, in the order they appeared in the bitstream.
+	nal_units: Vec<Vec<u8>>,
+}
+
+impl ParameterSets {
+	/// Collect the parameter sets of an Annex-B bitstream, returns `None` if it has no SPS and PPS.
+	pub fn from_annexb(codec: Codec, data: &[u8]) -> Option<Self> {
+		let nal_units: Vec<Vec<u8>> = annexb_nal_units(data)
+			.filter(|nal_unit| codec.nal_unit_type(nal_unit) != NalUnitType::Other)
+			.map(|nal_unit| nal_unit.to_vec())
+			.collect();
+
+		let parameter_sets = Self { codec, nal_units };
+		parameter_sets.is_complete().then_some(parameter_sets)
+	}
+
+	/// Collect the parameter sets from the extradata of an encoder, which is either Annex-B or an AVCC configuration record.
+	pub fn from_extradata(codec: Codec, extradata: &[u8]) -> Option<Self> {
+		// Annex-B extradata starts with a start code, a configuration record starts with its version which is always 1.
+		if extradata.first() != Some(&1) {
+			return Self::from_annexb(codec, extradata);
+		}
+
+		if codec != Codec::H264 {
+			tracing::debug!("Parsing HEVC configuration records is not supported, waiting for parameter sets in the bitstream.");
+			return None;
+		}
+
+		let annexb = avcc_configuration_to_annexb(extradata)
+			.map_err(|()| tracing::warn!("Failed to parse AVCC configuration record of the encoder."))
+			.ok()?;
+		Self::from_annexb(codec, &annexb)
+	}
+
+	/// Whether all parameter sets the codec needs are present.
+	fn is_complete(&self) -> bool {
+		let has = |nal_unit_type| !self.of_type(nal_unit_type).is_empty();
+		has(NalUnitType::Sps) && has(NalUnitType::Pps) && (self.codec == Codec::H264 || has(NalUnitType::Vps))
+	}
+
+	/// The parameter sets of one type, encoded as base64 and separated by commas.
+	fn of_type(&self, nal_unit_type: NalUnitType) -> String {
+		self.nal_units.iter()
+			.filter(|nal_unit| self.codec.nal_unit_type(nal_unit) == nal_unit_type)
+			.map(|nal_unit| base64::engine::general_purpose::STANDARD.encode(nal_unit))
+			.collect::<Vec<_>>()
+			.join(",")
+	}
+
+	/// The parameter sets as an Annex-B bitstream, to prepend to an IDR frame.
+	pub fn to_annexb(&self) -> Vec<u8> {
+		let mut output = Vec::new();
+		for nal_unit in &self.nal_units {
+			output.extend_from_slice(&START_CODE);
+			output.extend_from_slice(nal_unit);
+		}
+
+		output
+	}
+
+	/// The parameter sets as SDP format parameters, following RFC 6184 for H.264 and RFC 7798 for HEVC.
+	pub fn fmtp_parameters(&self) -> String {
+		match self.codec {
+			Codec::H264 => format!(
+				"sprop-parameter-sets={},{}",
+				self.of_type(NalUnitType::Sps),
+				self.of_type(NalUnitType::Pps),
+			),
+			Codec::Hevc => format!(
+				"sprop-vps={};sprop-sps={};sprop-pps={}",
+				self.of_type(NalUnitType::Vps),
+				self.of_type(NalUnitType::Sps),
+				self.of_type(NalUnitType::Pps),
+			),
+		}
+	}
+}
+
+/// Whether an Annex-B bitstream contains an SPS, in which case it starts with its own parameter sets.
+pub fn contains_parameter_sets(codec: Codec, data: &[u8]) -> bool {
+	annexb_nal_units(data).any(|nal_unit| codec.nal_unit_type(nal_unit) == NalUnitType::Sps)
+}
+
+/// Convert an H.264 decoder configuration record (ISO/IEC 14496-15) to an Annex-B bitstream of its parameter sets.
+fn avcc_configuration_to_annexb(record: &[u8]) -> Result<Vec<u8>, ()> {
+	let mut output = Vec::new();
+	let mut offset = 5;
+
+	// The SPS and PPS lists each start with their count, SPS count is stored in the lower 5 bits.
+	for count_mask in [0x1f, 0xff] {
+		let count = *record.get(offset).ok_or(())? & count_mask;
+		offset += 1;
+
+		for _ in 0..count {
+			let length = u16::from_be_bytes([*record.get(offset).ok_or(())?, *record.get(offset + 1).ok_or(())?]) as usize;
+			offset += 2;
+			let nal_unit = record.get(offset..offset + length).ok_or(())?;
+			offset += length;
+
+			output.extend_from_slice(&START_CODE);
+			output.extend_from_slice(nal_unit);
+		}
+	}
+
+	Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const H264_IDR: &str = include_str!("../../../../tests/fixtures/bitstream/h264-idr.hex");
+	const H264_P: &str = include_str!("../../../../tests/fixtures/bitstream/h264-p.hex");
+	const H264_EXTRADATA: &str = include_str!("../../../../tests/fixtures/bitstream/h264-extradata.hex");
+	const HEVC_IDR: &str = include_str!("../../../../tests/fixtures/bitstream/hevc-idr.hex");
+	const HEVC_P: &str = include_str!("../../../../tests/fixtures/bitstream/hevc-p.hex");
+
+	/// The bitstream of a fixture, which has one NAL unit per line.
+	fn fixture(hex: &str) -> Vec<u8> {
+		hex::decode(hex.split_whitespace().collect::<String>()).unwrap()
+	}
+
+	/// The NAL units of a fixture without their start codes.
+	fn fixture_nal_units(hex: &str) -> Vec<Vec<u8>> {
+		hex.lines()
+			.map(|line| {
+				let nal_unit = hex::decode(line.trim()).unwrap();
+				let start_code_length = if nal_unit.starts_with(&START_CODE) { 4 } else { 3 };
+				nal_unit[start_code_length..].to_vec()
+			})
+			.collect()
+	}
+
+	/// Length prefix NAL units, the way encoders with AVCC output write them.
+	fn avcc(nal_units: &[Vec<u8>], length_size: usize) -> Vec<u8> {
+		nal_units.iter()
+			.flat_map(|nal_unit| nal_unit.len().to_be_bytes()[8 - length_size..].iter().chain(nal_unit).copied().collect::<Vec<_>>())
+			.collect()
+	}
+
+	#[test]
+	fn h264_nal_units() {
+		let bitstream = fixture(H264_IDR);
+		let nal_units: Vec<&[u8]> = annexb_nal_units(&bitstream).collect();
+		assert_eq!(nal_units, fixture_nal_units(H264_IDR));
+
+		let types: Vec<NalUnitType> = nal_units.iter().map(|nal_unit| Codec::H264.nal_unit_type(nal_unit)).collect();
+		assert_eq!(types, [NalUnitType::Other, NalUnitType::Sps, NalUnitType::Pps, NalUnitType::Other, NalUnitType::Other]);
+	}
+
+	#[test]
+	fn hevc_nal_units() {
+		let bitstream = fixture(HEVC_IDR);
+		let nal_units: Vec<&[u8]> = annexb_nal_units(&bitstream).collect();
+		assert_eq!(nal_units, fixture_nal_units(HEVC_IDR));
+
+		let types: Vec<NalUnitType> = nal_units.iter().map(|nal_unit| Codec::Hevc.nal_unit_type(nal_unit)).collect();
+		assert_eq!(types, [NalUnitType::Other, NalUnitType::Vps, NalUnitType::Sps, NalUnitType::Pps, NalUnitType::Other]);
+	}
+
+	#[test]
+	fn nal_units_without_start_code() {
+		assert_eq!(annexb_nal_units(&[]).count(), 0);
+		assert_eq!(annexb_nal_units(&[0x65, 0x88, 0x84]).count(), 0);
+		assert_eq!(Codec::H264.nal_unit_type(&[]), NalUnitType::Other);
+	}
+
+	#[test]
+	fn h264_parameter_sets() {
+		let parameter_sets = ParameterSets::from_annexb(Codec::H264, &fixture(H264_IDR)).unwrap();
+		assert_eq!(parameter_sets.fmtp_parameters(), "sprop-parameter-sets=Z2QAKKzZQHgCJ+XARAAAAwAEAAADAPA8YMZY,aOvjyyLA");
+
+		let nal_units = fixture_nal_units(H264_IDR);
+		let mut expected = Vec::new();
+		for nal_unit in &nal_units[1..3] {
+			expected.extend_from_slice(&START_CODE);
+			expected.extend_from_slice(nal_unit);
+		}
+		assert_eq!(parameter_sets.to_annexb(), expected);
+	}
+
+	#[test]
+	fn hevc_parameter_sets() {
+		let parameter_sets = ParameterSets::from_annexb(Codec::Hevc, &fixture(HEVC_IDR)).unwrap();
+		assert_eq!(
+			parameter_sets.fmtp_parameters(),
+			"sprop-vps=QAEMAf//AWAAAAMAkAAAAwAAAwBdlZgJ;sprop-sps=QgEBAWAAAAMAkAAAAwAAAwBdoAPAgBDlllZpJMrgEAAAAwAQAAADAeCA;sprop-pps=RAHBcrRiQA==",
+		);
+	}
+
+	#[test]
+	fn incomplete_parameter_sets() {
+		assert_eq!(ParameterSets::from_annexb(Codec::H264, &fixture(H264_P)), None);
+		assert_eq!(ParameterSets::from_annexb(Codec::Hevc, &fixture(HEVC_P)), None);
+
+		// HEVC decoders need the VPS as well.
+		let without_vps: Vec<u8> = fixture_nal_units(HEVC_IDR).iter()
+			.filter(|nal_unit| Codec::Hevc.nal_unit_type(nal_unit) != NalUnitType::Vps)
+			.flat_map(|nal_unit| START_CODE.iter().chain(nal_unit).copied().collect::<Vec<_>>())
+			.collect();
+		assert_eq!(ParameterSets::from_annexb(Codec::Hevc, &without_vps), None);
+
+		// The NAL unit types of one codec mean something else in the other.
+		assert_eq!(ParameterSets::from_annexb(Codec::Hevc, &fixture(H264_IDR)), None);
+	}
+
+	#[test]
+	fn idr_frames_contain_parameter_sets() {
+		assert!(contains_parameter_sets(Codec::H264, &fixture(H264_IDR)));
+		assert!(!contains_parameter_sets(Codec::H264, &fixture(H264_P)));
+		assert!(contains_parameter_sets(Codec::Hevc, &fixture(HEVC_IDR)));
+		assert!(!contains_parameter_sets(Codec::Hevc, &fixture(HEVC_P)));
+	}
+
+	#[test]
+	fn h264_extradata() {
+		let extradata = fixture(H264_EXTRADATA);
+		assert_eq!(avcc_length_size(Codec::H264, &extradata), Some(4));
+		assert_eq!(ParameterSets::from_extradata(Codec::H264, &extradata), ParameterSets::from_annexb(Codec::H264, &fixture(H264_IDR)));
+
+		// Annex-B extradata has the parameter sets as they are.
+		assert_eq!(avcc_length_size(Codec::H264, &fixture(H264_IDR)), None);
+		assert_eq!(ParameterSets::from_extradata(Codec::H264, &fixture(H264_IDR)), ParameterSets::from_annexb(Codec::H264, &fixture(H264_IDR)));
+		assert_eq!(ParameterSets::from_extradata(Codec::Hevc, &fixture(HEVC_IDR)), ParameterSets::from_annexb(Codec::Hevc, &fixture(HEVC_IDR)));
+	}
+
+	#[test]
+	fn truncated_extradata() {
+		let extradata = fixture(H264_EXTRADATA);
+		for length in 0..extradata.len() {
+			assert_eq!(ParameterSets::from_extradata(Codec::H264, &extradata[..length]), None, "parsed {length} bytes of extradata");
+		}
+	}
+
+	#[test]
+	fn avcc_frames() {
+		let nal_units = fixture_nal_units(H264_IDR);
+		let expected: Vec<u8> = nal_units.iter().flat_map(|nal_unit| START_CODE.iter().chain(nal_unit).copied().collect::<Vec<_>>()).collect();
+		for length_size in 1..=4 {
+			assert_eq!(avcc_to_annexb(&avcc(&nal_units, length_size), length_size), Ok(expected.clone()), "length size {length_size}");
+		}
+
+		assert_eq!(avcc_to_annexb(&avcc(&nal_units, 4), 0), Err(()));
+		assert_eq!(avcc_to_annexb(&avcc(&nal_units, 4), 5), Err(()));
+	}
+
+	#[test]
+	fn truncated_avcc_frames() {
+		let frame = avcc(&fixture_nal_units(H264_IDR), 4);
+		let boundaries: Vec<usize> = fixture_nal_units(H264_IDR).iter()
+			.scan(0, |offset, nal_unit| {
+				*offset += 4 + nal_unit.len();
+				Some(*offset)
+			})
+			.collect();
+
+		for length in 1..frame.len() {
+			// Cutting between NAL units leaves a shorter, valid frame.
+			assert_eq!(avcc_to_annexb(&frame[..length], 4).is_ok(), boundaries.contains(&length), "converted {length} bytes of a frame");
+		}
+	}
+}
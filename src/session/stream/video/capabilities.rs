@@ -0,0 +1,123 @@
+use crate::config::VideoStreamConfig;
+#[cfg(feature = "nvidia")]
+use crate::config::{ColorRange, ColorSpace, H264DecoderConfig};
+
+#[cfg(feature = "nvidia")]
+use super::encoder::Encoder;
+
+/// Resolution at which encoders are opened to check if they work.
+#[cfg(feature = "nvidia")]
+const PROBE_WIDTH: u32 = 1280;
+#[cfg(feature = "nvidia")]
+const PROBE_HEIGHT: u32 = 720;
+#[cfg(feature = "nvidia")]
+const PROBE_FPS: u32 = 60;
+#[cfg(feature = "nvidia")]
+const PROBE_BITRATE: usize = 10_000_000;
+
+/// Largest frames that NVENC encodes, per codec.
+const MAX_RESOLUTION_H264: (u32, u32) = (4096, 4096);
+const MAX_RESOLUTION_HEVC: (u32, u32) = (8192, 8192);
+
+/// Flags for `ServerCodecModeSupport` in the serverinfo response.
+const CODEC_MODE_H264: u32 = 0x0003;
+const CODEC_MODE_HEVC: u32 = 0x0100;
+
+/// Video formats as requested by the client in the ANNOUNCE request.
+pub const VIDEO_FORMAT_H264: u32 = 0;
+pub const VIDEO_FORMAT_HEVC: u32 = 1;
+
+/// Codecs that were found to work on this host.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncoderCapabilities {
+	pub h264: bool,
+	pub hevc: bool,
+}
+
+impl EncoderCapabilities {
+	/// Check which of the configured encoders can actually be opened on this host.
+	///
+	/// This catches missing drivers, unsupported GPUs and hosts that ran out of encoder sessions.
+	pub async fn probe(config: VideoStreamConfig) -> Self {
+		let capabilities = tokio::task::spawn_blocking(move || Self::open_encoders(&config)).await;
+		let capabilities = capabilities.unwrap_or_else(|e| {
+			tracing::error!("Failed to probe video encoders: {e}");
+			Self::default()
+		});
+		tracing::info!("Video encoder support: H264: {}, HEVC: {}.", capabilities.h264, capabilities.hevc);
+
+		capabilities
+	}
+
+	#[cfg(feature = "nvidia")]
+	fn open_encoders(config: &VideoStreamConfig) -> Self {
+		let cuda_device = match cudarc::driver::CudaDevice::new(0) {
+			Ok(cuda_device) => cuda_device,
+			Err(e) => {
+				tracing::error!("Failed to initialize CUDA, no video encoders are available: {e}");
+				return Self::default();
+			},
+		};
+
+		let probe = |codec_names: Vec<String>| {
+			Encoder::new_with_fallback(
+				&cuda_device,
+				&codec_names,
+				PROBE_WIDTH, PROBE_HEIGHT,
+				None,
+				PROBE_FPS,
+				PROBE_BITRATE,
+				1,
+				ColorSpace::default(),
+				ColorRange::default(),
+				&H264DecoderConfig::default(),
+				&config.encoder_options,
+			).is_ok()
+		};
+
+		Self {
+			h264: probe(config.codecs_h264()),
+			hevc: probe(config.codecs_hevc()),
+		}
+	}
+
+	/// Without NVENC there are no encoders to open, so clients are told that no codec is supported.
+	#[cfg(not(feature = "nvidia"))]
+	fn open_encoders(_config: &VideoStreamConfig) -> Self {
+		tracing::error!("Video encoders require building with the 'nvidia' feature, no video encoders are available.");
+		Self::default()
+	}
+
+	/// Whether any video encoder is available.
+	pub fn any(&self) -> bool {
+		self.h264 || self.hevc
+	}
+
+	/// Whether any of the available encoders can encode frames of `width` by `height`.
+	pub fn supports_resolution(&self, width: u32, height: u32) -> bool {
+		let fits = |(max_width, max_height): (u32, u32)| width <= max_width && height <= max_height;
+		width > 0 && height > 0
+			&& ((self.h264 && fits(MAX_RESOLUTION_H264)) || (self.hevc && fits(MAX_RESOLUTION_HEVC)))
+	}
+
+	/// Whether the video format requested by a client is supported.
+	pub fn supports(&self, video_format: u32) -> bool {
+		match video_format {
+			VIDEO_FORMAT_H264 => self.h264,
+			VIDEO_FORMAT_HEVC => self.hevc,
+			_ => false,
+		}
+	}
+
+	/// Value for `ServerCodecModeSupport` in the serverinfo response.
+	pub fn codec_mode_support(&self) -> u32 {
+		let mut flags = 0;
+		if self.h264 {
+			flags |= CODEC_MODE_H264;
+		}
+		if self.hevc {
+			flags |= CODEC_MODE_HEVC;
+		}
+		flags
+	}
+}
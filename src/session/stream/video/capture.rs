@@ -1,9 +1,17 @@
-use std::sync::{Arc, Mutex};
+use std::{sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc}, time::{Duration, Instant}};
 
 use async_shutdown::ShutdownManager;
+use cudarc::driver::sys::{CUstream, CUstream_flags};
 use ffmpeg::Frame;
 use nvfbc::{CudaCapturer, BufferFormat, cuda::CaptureMethod};
 
+use crate::config::{CaptureRegion, IdleConfig};
+
+use super::{fence::FencedFrame, idle::{IdleDetector, SAMPLED_ROW_STRIDE}, queue::FrameQueue};
+
+/// Number of bytes per pixel in captured BGRA frames.
+const BYTES_PER_PIXEL: usize = 4;
+
 
 pub struct FrameCapturer {
 	capturer: CudaCapturer,
@@ -24,12 +32,35 @@ impl FrameCapturer {
 			.map_err(|e| tracing::error!("Failed to get NvFBC status: {e}"))
 	}
 
+	/// Capture `frames` frames at `framerate` without copying them, returning how long acquiring each frame took.
+	pub fn measure(mut self, framerate: u32, frames: u32) -> Result<Vec<Duration>, ()> {
+		self.capturer.bind_context()
+			.map_err(|e| tracing::error!("Failed to bind frame capturer CUDA context: {e}"))?;
+		self.capturer.start(BufferFormat::Bgra, framerate)
+			.map_err(|e| tracing::error!("Failed to start CUDA capture device: {e}"))?;
+
+		let mut latencies = Vec::with_capacity(frames as usize);
+		for _ in 0..frames {
+			let start = Instant::now();
+			self.capturer.next_frame(CaptureMethod::NoWaitIfNewFrame)
+				.map_err(|e| tracing::error!("Failed to wait for new CUDA frame: {e}"))?;
+			latencies.push(start.elapsed());
+		}
+
+		Ok(latencies)
+	}
+
+	#[allow(clippy::too_many_arguments)]
 	pub fn run(
 		mut self,
 		framerate: u32,
-		mut capture_buffer: Frame,
-		intermediate_buffer: Arc<Mutex<Frame>>,
-		notifier: Arc<std::sync::Condvar>,
+		screen_width: u32,
+		region: Option<CaptureRegion>,
+		mut capture_buffer: FencedFrame,
+		frame_queue: Arc<FrameQueue>,
+		captured_frames: Arc<AtomicU32>,
+		idle: Option<IdleConfig>,
+		input_received: Arc<AtomicBool>,
 		stop_signal: ShutdownManager<()>,
 	) -> Result<(), ()> {
 		self.capturer.bind_context()
@@ -38,31 +69,50 @@ impl FrameCapturer {
 			.map_err(|e| tracing::error!("Failed to start CUDA capture device: {e}"))?;
 		tracing::info!("Started frame capture.");
 
+		let stream = CudaStream::new()?;
+		let mut idle_detector = idle.as_ref().map(IdleDetector::new);
+
 		while !stop_signal.is_shutdown_triggered() {
+			// Make sure the previous copy finished, before NvFBC writes the next frame to its buffer.
+			stream.synchronize()?;
+
 			let frame_info = self.capturer.next_frame(CaptureMethod::NoWaitIfNewFrame)
 				.map_err(|e| tracing::error!("Failed to wait for new CUDA frame: {e}"))?;
 			tracing::trace!("Frame info: {:#?}", frame_info);
 
 			// capture_buffer.as_raw_mut().data[0] = frame_info.device_buffer as *mut u8;
-			unsafe {
-				if let Err(e) = cudarc::driver::result::memcpy_dtod_sync(
-					(*capture_buffer.as_mut_ptr()).data[0] as cudarc::driver::sys::CUdeviceptr,
-					frame_info.device_buffer as cudarc::driver::sys::CUdeviceptr,
-					frame_info.device_buffer_len as usize
-				) {
-					tracing::error!("Failed to copy CUDA memory: {e}");
+			let result = match region {
+				Some(region) => unsafe { copy_region(&mut capture_buffer.frame, frame_info.device_buffer as cudarc::driver::sys::CUdeviceptr, screen_width, region, stream.0) },
+				None => unsafe {
+					cudarc::driver::sys::cuMemcpyDtoDAsync_v2(
+						(*capture_buffer.frame.as_mut_ptr()).data[0] as cudarc::driver::sys::CUdeviceptr,
+						frame_info.device_buffer as cudarc::driver::sys::CUdeviceptr,
+						frame_info.device_buffer_len as usize,
+						stream.0,
+					).result()
+				},
+			};
+			if let Err(e) = result {
+				tracing::error!("Failed to copy CUDA memory: {e}");
+				continue;
+			}
+
+			// Frames that are skipped while idle are overwritten by the next capture.
+			if let Some(idle_detector) = &mut idle_detector {
+				if let Err(e) = unsafe { sample_rows(&capture_buffer.frame, idle_detector, stream.0) } {
+					tracing::warn!("Failed to sample captured frame: {e}");
+				}
+				if !idle_detector.update(input_received.swap(false, Ordering::Relaxed)) {
 					continue;
 				}
 			}
 
-			// Swap the intermediate buffer with the output buffer and signal that we have a new frame.
-			// Note that the lock is only held while swapping buffers, to minimize wait time for others locking the buffer.
-			{
-				let mut lock = intermediate_buffer.lock()
-					.map_err(|e| tracing::error!("Failed to lock intermediate buffer: {e}"))?;
-				std::mem::swap(&mut *lock, &mut capture_buffer);
-			}
-			notifier.notify_one();
+			// The copy runs asynchronously, the encoder waits for this fence before it reads the frame.
+			capture_buffer.fence.signal(stream.0)?;
+
+			// Queue the frame for the encoder, which gives us another frame to capture into.
+			frame_queue.push(&mut capture_buffer)?;
+			captured_frames.fetch_add(1, Ordering::Relaxed);
 		}
 
 		tracing::debug!("Received stop signal.");
@@ -70,3 +120,77 @@ impl FrameCapturer {
 		Ok(())
 	}
 }
+
+/// Copy a region of a captured desktop frame to a frame with the size of that region.
+unsafe fn copy_region(
+	destination: &mut Frame,
+	source: cudarc::driver::sys::CUdeviceptr,
+	screen_width: u32,
+	region: CaptureRegion,
+	stream: CUstream,
+) -> Result<(), cudarc::driver::DriverError> {
+	let mut copy: cudarc::driver::sys::CUDA_MEMCPY2D = std::mem::zeroed();
+	copy.srcMemoryType = cudarc::driver::sys::CUmemorytype::CU_MEMORYTYPE_DEVICE;
+	copy.srcDevice = source;
+	copy.srcPitch = screen_width as usize * BYTES_PER_PIXEL;
+	copy.srcXInBytes = region.x as usize * BYTES_PER_PIXEL;
+	copy.srcY = region.y as usize;
+	copy.dstMemoryType = cudarc::driver::sys::CUmemorytype::CU_MEMORYTYPE_DEVICE;
+	copy.dstDevice = (*destination.as_mut_ptr()).data[0] as cudarc::driver::sys::CUdeviceptr;
+	copy.dstPitch = (*destination.as_ptr()).linesize[0] as usize;
+	copy.WidthInBytes = region.width as usize * BYTES_PER_PIXEL;
+	copy.Height = region.height as usize;
+
+	cudarc::driver::sys::cuMemcpy2DAsync_v2(&copy, stream).result()
+}
+
+/// Copy every `SAMPLED_ROW_STRIDE`-th row of a frame to the host, for the idle detector to compare.
+///
+/// Copying to pageable host memory returns after the copy finished, so the rows can be read straight away.
+unsafe fn sample_rows(
+	source: &Frame,
+	idle_detector: &mut IdleDetector,
+	stream: CUstream,
+) -> Result<(), cudarc::driver::DriverError> {
+	let width_in_bytes = (*source.as_ptr()).width as usize * BYTES_PER_PIXEL;
+	let rows = (*source.as_ptr()).height as usize / SAMPLED_ROW_STRIDE as usize;
+	let destination = idle_detector.rows_mut(width_in_bytes * rows);
+
+	let mut copy: cudarc::driver::sys::CUDA_MEMCPY2D = std::mem::zeroed();
+	copy.srcMemoryType = cudarc::driver::sys::CUmemorytype::CU_MEMORYTYPE_DEVICE;
+	copy.srcDevice = (*source.as_ptr()).data[0] as cudarc::driver::sys::CUdeviceptr;
+	copy.srcPitch = (*source.as_ptr()).linesize[0] as usize * SAMPLED_ROW_STRIDE as usize;
+	copy.dstMemoryType = cudarc::driver::sys::CUmemorytype::CU_MEMORYTYPE_HOST;
+	copy.dstHost = destination.as_mut_ptr() as *mut std::ffi::c_void;
+	copy.dstPitch = width_in_bytes;
+	copy.WidthInBytes = width_in_bytes;
+	copy.Height = rows;
+
+	cudarc::driver::sys::cuMemcpy2DAsync_v2(&copy, stream).result()
+}
+
+/// CUDA stream on which captured frames are copied, destroyed when dropped.
+struct CudaStream(CUstream);
+
+impl CudaStream {
+	fn new() -> Result<Self, ()> {
+		let mut stream = std::ptr::null_mut();
+		unsafe { cudarc::driver::sys::cuStreamCreate(&mut stream, CUstream_flags::CU_STREAM_NON_BLOCKING as u32).result() }
+			.map_err(|e| tracing::error!("Failed to create CUDA stream: {e}"))?;
+
+		Ok(Self(stream))
+	}
+
+	fn synchronize(&self) -> Result<(), ()> {
+		unsafe { cudarc::driver::sys::cuStreamSynchronize(self.0).result() }
+			.map_err(|e| tracing::error!("Failed to synchronize CUDA stream: {e}"))
+	}
+}
+
+impl Drop for CudaStream {
+	fn drop(&mut self) {
+		if let Err(e) = unsafe { cudarc::driver::sys::cuStreamDestroy_v2(self.0).result() } {
+			tracing::warn!("Failed to destroy CUDA stream: {e}");
+		}
+	}
+}
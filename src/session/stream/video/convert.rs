@@ -0,0 +1,542 @@
+//! Conversion of captured BGRA frames to the NV12 and P010 formats of encoders, for encoders that can't read captured
+//! frames from the GPU.
+//!
+//! Rows are converted with AVX2 or NEON when the CPU supports it, the scalar implementation handles the remainder.
+
+use crate::config::{ColorRange, ColorSpace};
+
+/// Fractional bits of the fixed point coefficients, small enough that 10-bit coefficients fit in an `i16`.
+const SHIFT: i32 = 13;
+const ROUND: i32 = 1 << (SHIFT - 1);
+
+/// Bytes per pixel of captured frames, in B, G, R, X order.
+const SOURCE_PIXEL_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+	/// 8-bit luma plane followed by an interleaved chroma plane of half the resolution.
+	Nv12,
+
+	/// Like NV12 with 16-bit little endian samples, of which the upper 10 bits are used.
+	P010,
+}
+
+impl OutputFormat {
+	pub(super) fn bytes_per_sample(self) -> usize {
+		match self {
+			Self::Nv12 => 1,
+			Self::P010 => 2,
+		}
+	}
+
+	fn depth(self) -> u32 {
+		match self {
+			Self::Nv12 => 8,
+			Self::P010 => 10,
+		}
+	}
+}
+
+/// Fixed point coefficients for B, G and R, in the order of the source pixels.
+#[derive(Clone, Copy, Debug)]
+struct Coefficients {
+	y: [i16; 3],
+	u: [i16; 3],
+	v: [i16; 3],
+	y_offset: i32,
+	uv_offset: i32,
+	max: i32,
+}
+
+impl Coefficients {
+	fn new(format: OutputFormat, color_space: ColorSpace, color_range: ColorRange) -> Self {
+		let (kr, kb) = match color_space {
+			ColorSpace::Bt601 => (0.299, 0.114),
+			ColorSpace::Bt709 => (0.2126, 0.0722),
+			ColorSpace::Bt2020 => (0.2627, 0.0593),
+		};
+		let kg = 1.0 - kr - kb;
+
+		let depth = format.depth();
+		let max = (1 << depth) - 1;
+		let (y_scale, uv_scale, y_offset) = match color_range {
+			ColorRange::Limited => {
+				let scale = (1 << (depth - 8)) as f64 / 255.0;
+				(219.0 * scale, 224.0 * scale, 16 << (depth - 8))
+			},
+			ColorRange::Full => (max as f64 / 255.0, max as f64 / 255.0, 0),
+		};
+
+		let fixed = |value: f64| (value * (1 << SHIFT) as f64).round() as i16;
+		Self {
+			y: [fixed(y_scale * kb), fixed(y_scale * kg), fixed(y_scale * kr)],
+			u: [
+				fixed(uv_scale * 0.5),
+				fixed(-uv_scale * kg / (2.0 * (1.0 - kb))),
+				fixed(-uv_scale * kr / (2.0 * (1.0 - kb))),
+			],
+			v: [
+				fixed(-uv_scale * kb / (2.0 * (1.0 - kr))),
+				fixed(-uv_scale * kg / (2.0 * (1.0 - kr))),
+				fixed(uv_scale * 0.5),
+			],
+			y_offset,
+			uv_offset: 1 << (depth - 1),
+			max,
+		}
+	}
+
+	/// Luma of a single pixel.
+	fn y(&self, pixel: &[u8]) -> i32 {
+		let sum = dot(&self.y, pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+		(((sum + ROUND) >> SHIFT) + self.y_offset).clamp(0, self.max)
+	}
+
+	/// Chroma of a block, from the sums of its four pixels.
+	fn uv(&self, b: i32, g: i32, r: i32) -> (i32, i32) {
+		let u = ((dot(&self.u, b, g, r) + (ROUND << 2)) >> (SHIFT + 2)) + self.uv_offset;
+		let v = ((dot(&self.v, b, g, r) + (ROUND << 2)) >> (SHIFT + 2)) + self.uv_offset;
+		(u.clamp(0, self.max), v.clamp(0, self.max))
+	}
+}
+
+fn dot(coefficients: &[i16; 3], b: i32, g: i32, r: i32) -> i32 {
+	coefficients[0] as i32 * b + coefficients[1] as i32 * g + coefficients[2] as i32 * r
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Implementation {
+	Scalar,
+	#[cfg(target_arch = "x86_64")]
+	Avx2,
+	#[cfg(target_arch = "aarch64")]
+	Neon,
+}
+
+impl Implementation {
+	fn detect() -> Self {
+		#[cfg(target_arch = "x86_64")]
+		if is_x86_feature_detected!("avx2") {
+			return Self::Avx2;
+		}
+
+		#[cfg(target_arch = "aarch64")]
+		if std::arch::is_aarch64_feature_detected!("neon") {
+			return Self::Neon;
+		}
+
+		Self::Scalar
+	}
+}
+
+/// Converts BGRA frames to a YUV 4:2:0 format, with the fastest implementation that the CPU supports.
+pub struct Converter {
+	format: OutputFormat,
+	coefficients: Coefficients,
+	implementation: Implementation,
+}
+
+impl Converter {
+	pub fn new(format: OutputFormat, color_space: ColorSpace, color_range: ColorRange) -> Self {
+		let implementation = Implementation::detect();
+		tracing::debug!("Converting frames to {format:?} with the {implementation:?} implementation.");
+
+		Self { format, coefficients: Coefficients::new(format, color_space, color_range), implementation }
+	}
+
+	/// Converter that only uses the scalar implementation, as reference for the faster implementations.
+	pub(super) fn scalar(format: OutputFormat, color_space: ColorSpace, color_range: ColorRange) -> Self {
+		Self { format, coefficients: Coefficients::new(format, color_space, color_range), implementation: Implementation::Scalar }
+	}
+
+	pub(super) fn format(&self) -> OutputFormat {
+		self.format
+	}
+
+	/// Name of the implementation that converts rows.
+	pub(super) fn implementation(&self) -> &'static str {
+		match self.implementation {
+			Implementation::Scalar => "scalar",
+			#[cfg(target_arch = "x86_64")]
+			Implementation::Avx2 => "AVX2",
+			#[cfg(target_arch = "aarch64")]
+			Implementation::Neon => "NEON",
+		}
+	}
+
+	/// Convert a frame of `width` by `height` pixels to the luma plane `y` and the chroma plane `uv`.
+	///
+	/// Strides are in bytes, fails if a plane is too small for the frame.
+	#[allow(clippy::too_many_arguments)]
+	pub fn convert(
+		&self,
+		source: &[u8],
+		source_stride: usize,
+		width: usize,
+		height: usize,
+		y: &mut [u8],
+		y_stride: usize,
+		uv: &mut [u8],
+		uv_stride: usize,
+	) -> Result<(), ()> {
+		if width == 0 || height == 0 {
+			return Ok(());
+		}
+
+		let sample_size = self.format.bytes_per_sample();
+		let chroma_width = width.div_ceil(2);
+		let chroma_height = height.div_ceil(2);
+		let fits = |plane: &[u8], stride: usize, row_size: usize, rows: usize| {
+			stride >= row_size && plane.len() >= stride * (rows - 1) + row_size
+		};
+		if !fits(source, source_stride, width * SOURCE_PIXEL_SIZE, height)
+			|| !fits(y, y_stride, width * sample_size, height)
+			|| !fits(uv, uv_stride, chroma_width * 2 * sample_size, chroma_height)
+		{
+			tracing::error!("Planes are too small to convert a frame of {width}x{height}.");
+			return Err(());
+		}
+
+		for chroma_row in 0..chroma_height {
+			let top = chroma_row * 2;
+			// The last row is repeated for frames with an odd height.
+			let bottom = (top + 1).min(height - 1);
+
+			let top_source = &source[top * source_stride..][..width * SOURCE_PIXEL_SIZE];
+			let bottom_source = &source[bottom * source_stride..][..width * SOURCE_PIXEL_SIZE];
+
+			self.y_row(top_source, &mut y[top * y_stride..][..width * sample_size], width);
+			if bottom != top {
+				self.y_row(bottom_source, &mut y[bottom * y_stride..][..width * sample_size], width);
+			}
+			self.uv_row(top_source, bottom_source, &mut uv[chroma_row * uv_stride..][..chroma_width * 2 * sample_size], width);
+		}
+
+		Ok(())
+	}
+
+	fn y_row(&self, source: &[u8], destination: &mut [u8], width: usize) {
+		let converted = match self.implementation {
+			Implementation::Scalar => 0,
+			#[cfg(target_arch = "x86_64")]
+			Implementation::Avx2 => unsafe { avx2::y_row(&self.coefficients, self.format, source, destination, width) },
+			#[cfg(target_arch = "aarch64")]
+			Implementation::Neon => unsafe { neon::y_row(&self.coefficients, self.format, source, destination, width) },
+		};
+
+		for x in converted..width {
+			let value = self.coefficients.y(&source[x * SOURCE_PIXEL_SIZE..]);
+			self.store(destination, x, value);
+		}
+	}
+
+	fn uv_row(&self, top: &[u8], bottom: &[u8], destination: &mut [u8], width: usize) {
+		let converted = match self.implementation {
+			Implementation::Scalar => 0,
+			#[cfg(target_arch = "x86_64")]
+			Implementation::Avx2 => unsafe { avx2::uv_row(&self.coefficients, self.format, top, bottom, destination, width) },
+			#[cfg(target_arch = "aarch64")]
+			Implementation::Neon => unsafe { neon::uv_row(&self.coefficients, self.format, top, bottom, destination, width) },
+		};
+
+		for x in (converted..width).step_by(2) {
+			// The last column is repeated for frames with an odd width.
+			let left = x * SOURCE_PIXEL_SIZE;
+			let right = (x + 1).min(width - 1) * SOURCE_PIXEL_SIZE;
+			let sum = |channel: usize| {
+				top[left + channel] as i32 + top[right + channel] as i32
+					+ bottom[left + channel] as i32 + bottom[right + channel] as i32
+			};
+
+			let (u, v) = self.coefficients.uv(sum(0), sum(1), sum(2));
+			self.store(destination, x, u);
+			self.store(destination, x + 1, v);
+		}
+	}
+
+	fn store(&self, destination: &mut [u8], index: usize, value: i32) {
+		match self.format {
+			OutputFormat::Nv12 => destination[index] = value as u8,
+			OutputFormat::P010 => destination[index * 2..index * 2 + 2].copy_from_slice(&((value as u16) << 6).to_le_bytes()),
+		}
+	}
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+	use std::arch::x86_64::*;
+
+	use super::{Coefficients, OutputFormat, ROUND, SHIFT, SOURCE_PIXEL_SIZE};
+
+	/// Pixels per iteration, which fill a single register.
+	const PIXELS: usize = 8;
+
+	/// Coefficients repeated for every pixel, in the B, G, R, X order of the source.
+	#[target_feature(enable = "avx2")]
+	unsafe fn pattern(coefficients: &[i16; 3]) -> __m256i {
+		let [b, g, r] = *coefficients;
+		_mm256_setr_epi16(b, g, r, 0, b, g, r, 0, b, g, r, 0, b, g, r, 0)
+	}
+
+	/// Store eight values, ordered as four in each 128-bit lane.
+	#[target_feature(enable = "avx2")]
+	unsafe fn store(format: OutputFormat, values: __m256i, destination: *mut u8) {
+		match format {
+			OutputFormat::Nv12 => {
+				let words = _mm256_packus_epi32(values, values);
+				let bytes = _mm256_packus_epi16(words, words);
+				let gathered = _mm256_permutevar8x32_epi32(bytes, _mm256_setr_epi32(0, 4, 0, 0, 0, 0, 0, 0));
+				_mm_storel_epi64(destination as *mut __m128i, _mm256_castsi256_si128(gathered));
+			},
+			OutputFormat::P010 => {
+				let words = _mm256_slli_epi16(_mm256_packus_epi32(values, values), 6);
+				let gathered = _mm256_permute4x64_epi64(words, 0b00_00_10_00);
+				_mm_storeu_si128(destination as *mut __m128i, _mm256_castsi256_si128(gathered));
+			},
+		}
+	}
+
+	/// Convert the luma of as many pixels as fit in whole registers, returns the number of pixels converted.
+	#[target_feature(enable = "avx2")]
+	pub unsafe fn y_row(coefficients: &Coefficients, format: OutputFormat, source: &[u8], destination: &mut [u8], width: usize) -> usize {
+		let zero = _mm256_setzero_si256();
+		let pattern = pattern(&coefficients.y);
+		let round = _mm256_set1_epi32(ROUND);
+		let offset = _mm256_set1_epi32(coefficients.y_offset);
+		let max = _mm256_set1_epi32(coefficients.max);
+		let sample_size = if format == OutputFormat::P010 { 2 } else { 1 };
+
+		let chunks = width / PIXELS;
+		for chunk in 0..chunks {
+			let pixels = _mm256_loadu_si256(source.as_ptr().add(chunk * PIXELS * SOURCE_PIXEL_SIZE) as *const __m256i);
+
+			// Pixels 0, 1 | 4, 5 and 2, 3 | 6, 7, as 16-bit channels.
+			let low = _mm256_madd_epi16(_mm256_unpacklo_epi8(pixels, zero), pattern);
+			let high = _mm256_madd_epi16(_mm256_unpackhi_epi8(pixels, zero), pattern);
+			let sums = _mm256_hadd_epi32(low, high);
+
+			let values = _mm256_add_epi32(_mm256_srai_epi32(_mm256_add_epi32(sums, round), SHIFT), offset);
+			let values = _mm256_min_epi32(_mm256_max_epi32(values, zero), max);
+			store(format, values, destination.as_mut_ptr().add(chunk * PIXELS * sample_size));
+		}
+
+		chunks * PIXELS
+	}
+
+	/// Convert the chroma of two rows for as many pixels as fit in whole registers, returns the number of pixels converted.
+	#[target_feature(enable = "avx2")]
+	pub unsafe fn uv_row(
+		coefficients: &Coefficients,
+		format: OutputFormat,
+		top: &[u8],
+		bottom: &[u8],
+		destination: &mut [u8],
+		width: usize,
+	) -> usize {
+		let zero = _mm256_setzero_si256();
+		let u_pattern = pattern(&coefficients.u);
+		let v_pattern = pattern(&coefficients.v);
+		let round = _mm256_set1_epi32(ROUND << 2);
+		let offset = _mm256_set1_epi32(coefficients.uv_offset);
+		let max = _mm256_set1_epi32(coefficients.max);
+		let sample_size = if format == OutputFormat::P010 { 2 } else { 1 };
+
+		let chunks = width / PIXELS;
+		for chunk in 0..chunks {
+			let offset_bytes = chunk * PIXELS * SOURCE_PIXEL_SIZE;
+			let top = _mm256_loadu_si256(top.as_ptr().add(offset_bytes) as *const __m256i);
+			let bottom = _mm256_loadu_si256(bottom.as_ptr().add(offset_bytes) as *const __m256i);
+
+			// Sum the rows, pixels 0, 1 | 4, 5 and 2, 3 | 6, 7 as 16-bit channels.
+			let low = _mm256_add_epi16(_mm256_unpacklo_epi8(top, zero), _mm256_unpacklo_epi8(bottom, zero));
+			let high = _mm256_add_epi16(_mm256_unpackhi_epi8(top, zero), _mm256_unpackhi_epi8(bottom, zero));
+
+			// Sum neighbouring pixels, which leaves the blocks 0 | 2 and 1 | 3 in the lower halves of the lanes.
+			let low = _mm256_add_epi16(low, _mm256_srli_si256(low, 8));
+			let high = _mm256_add_epi16(high, _mm256_srli_si256(high, 8));
+			let blocks = _mm256_unpacklo_epi64(low, high);
+
+			// U of blocks 0, 1 and V of blocks 0, 1 | the same for blocks 2, 3.
+			let sums = _mm256_hadd_epi32(_mm256_madd_epi16(blocks, u_pattern), _mm256_madd_epi16(blocks, v_pattern));
+			let sums = _mm256_shuffle_epi32(sums, 0b11_01_10_00);
+
+			let values = _mm256_add_epi32(_mm256_srai_epi32(_mm256_add_epi32(sums, round), SHIFT + 2), offset);
+			let values = _mm256_min_epi32(_mm256_max_epi32(values, zero), max);
+			store(format, values, destination.as_mut_ptr().add(chunk * PIXELS * sample_size));
+		}
+
+		chunks * PIXELS
+	}
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+	use std::arch::aarch64::*;
+
+	use super::{Coefficients, OutputFormat, SHIFT, SOURCE_PIXEL_SIZE};
+
+	/// Pixels per iteration.
+	const PIXELS: usize = 8;
+
+	/// Clamp eight values and store them.
+	#[target_feature(enable = "neon")]
+	unsafe fn store(format: OutputFormat, low: int32x4_t, high: int32x4_t, max: i32, destination: *mut u8) {
+		let zero = vdupq_n_s32(0);
+		let max = vdupq_n_s32(max);
+		let low = vminq_s32(vmaxq_s32(low, zero), max);
+		let high = vminq_s32(vmaxq_s32(high, zero), max);
+		let values = vcombine_s16(vmovn_s32(low), vmovn_s32(high));
+
+		match format {
+			OutputFormat::Nv12 => vst1_u8(destination, vqmovun_s16(values)),
+			OutputFormat::P010 => vst1q_u16(destination as *mut u16, vshlq_n_u16::<6>(vreinterpretq_u16_s16(values))),
+		}
+	}
+
+	#[target_feature(enable = "neon")]
+	unsafe fn dot(coefficients: &[i16; 3], b: int16x4_t, g: int16x4_t, r: int16x4_t) -> int32x4_t {
+		let sum = vmull_n_s16(b, coefficients[0]);
+		let sum = vmlal_n_s16(sum, g, coefficients[1]);
+		vmlal_n_s16(sum, r, coefficients[2])
+	}
+
+	/// Convert the luma of whole chunks of pixels, returns the number of pixels converted.
+	#[target_feature(enable = "neon")]
+	pub unsafe fn y_row(coefficients: &Coefficients, format: OutputFormat, source: &[u8], destination: &mut [u8], width: usize) -> usize {
+		let offset = vdupq_n_s32(coefficients.y_offset);
+		let sample_size = if format == OutputFormat::P010 { 2 } else { 1 };
+
+		let chunks = width / PIXELS;
+		for chunk in 0..chunks {
+			let pixels = vld4_u8(source.as_ptr().add(chunk * PIXELS * SOURCE_PIXEL_SIZE));
+			let b = vreinterpretq_s16_u16(vmovl_u8(pixels.0));
+			let g = vreinterpretq_s16_u16(vmovl_u8(pixels.1));
+			let r = vreinterpretq_s16_u16(vmovl_u8(pixels.2));
+
+			let low = dot(&coefficients.y, vget_low_s16(b), vget_low_s16(g), vget_low_s16(r));
+			let high = dot(&coefficients.y, vget_high_s16(b), vget_high_s16(g), vget_high_s16(r));
+			let low = vaddq_s32(vrshrq_n_s32::<SHIFT>(low), offset);
+			let high = vaddq_s32(vrshrq_n_s32::<SHIFT>(high), offset);
+
+			store(format, low, high, coefficients.max, destination.as_mut_ptr().add(chunk * PIXELS * sample_size));
+		}
+
+		chunks * PIXELS
+	}
+
+	/// Convert the chroma of two rows for whole chunks of pixels, returns the number of pixels converted.
+	#[target_feature(enable = "neon")]
+	pub unsafe fn uv_row(
+		coefficients: &Coefficients,
+		format: OutputFormat,
+		top: &[u8],
+		bottom: &[u8],
+		destination: &mut [u8],
+		width: usize,
+	) -> usize {
+		let offset = vdupq_n_s32(coefficients.uv_offset);
+		let sample_size = if format == OutputFormat::P010 { 2 } else { 1 };
+
+		let chunks = width / PIXELS;
+		for chunk in 0..chunks {
+			let top = vld4_u8(top.as_ptr().add(chunk * PIXELS * SOURCE_PIXEL_SIZE));
+			let bottom = vld4_u8(bottom.as_ptr().add(chunk * PIXELS * SOURCE_PIXEL_SIZE));
+
+			// Sums of the four pixels of each block, per channel.
+			let sum = |top: uint8x8_t, bottom: uint8x8_t| vreinterpret_s16_u16(vadd_u16(vpaddl_u8(top), vpaddl_u8(bottom)));
+			let b = sum(top.0, bottom.0);
+			let g = sum(top.1, bottom.1);
+			let r = sum(top.2, bottom.2);
+
+			let u = vaddq_s32(vrshrq_n_s32::<{ SHIFT + 2 }>(dot(&coefficients.u, b, g, r)), offset);
+			let v = vaddq_s32(vrshrq_n_s32::<{ SHIFT + 2 }>(dot(&coefficients.v, b, g, r)), offset);
+
+			// Interleave U and V.
+			let low = vzip1q_s32(u, v);
+			let high = vzip2q_s32(u, v);
+			store(format, low, high, coefficients.max, destination.as_mut_ptr().add(chunk * PIXELS * sample_size));
+		}
+
+		chunks * PIXELS
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use proptest::prelude::*;
+
+	use super::*;
+
+	/// Convert a frame to planes with padded strides, returning the luma and chroma planes.
+	fn convert(converter: &Converter, source: &[u8], width: usize, height: usize) -> (Vec<u8>, Vec<u8>) {
+		let sample_size = converter.format.bytes_per_sample();
+		let y_stride = width * sample_size + 3;
+		let uv_stride = width.div_ceil(2) * 2 * sample_size + 5;
+		let mut y = vec![0; y_stride * height];
+		let mut uv = vec![0; uv_stride * height.div_ceil(2)];
+		converter.convert(source, width * SOURCE_PIXEL_SIZE + 8, width, height, &mut y, y_stride, &mut uv, uv_stride)
+			.expect("planes fit the frame");
+
+		(y, uv)
+	}
+
+	fn frame() -> impl Strategy<Value = (usize, usize, Vec<u8>)> {
+		// Widths above 32 pixels are converted with SIMD up to the remainder, which the scalar implementation converts.
+		(1..100usize, 1..12usize).prop_flat_map(|(width, height)| {
+			let size = (width * SOURCE_PIXEL_SIZE + 8) * height;
+			(Just(width), Just(height), proptest::collection::vec(any::<u8>(), size))
+		})
+	}
+
+	fn color_space() -> impl Strategy<Value = ColorSpace> {
+		prop_oneof![Just(ColorSpace::Bt601), Just(ColorSpace::Bt709), Just(ColorSpace::Bt2020)]
+	}
+
+	fn color_range() -> impl Strategy<Value = ColorRange> {
+		prop_oneof![Just(ColorRange::Limited), Just(ColorRange::Full)]
+	}
+
+	proptest! {
+		#[test]
+		fn detected_implementation_matches_scalar(
+			(width, height, source) in frame(),
+			format in prop_oneof![Just(OutputFormat::Nv12), Just(OutputFormat::P010)],
+			color_space in color_space(),
+			color_range in color_range(),
+		) {
+			let scalar = Converter::scalar(format, color_space, color_range);
+			let detected = Converter::new(format, color_space, color_range);
+
+			let (scalar_y, scalar_uv) = convert(&scalar, &source, width, height);
+			let (detected_y, detected_uv) = convert(&detected, &source, width, height);
+			prop_assert_eq!(scalar_y, detected_y, "luma of {}x{} {:?} with {}", width, height, format, detected.implementation());
+			prop_assert_eq!(scalar_uv, detected_uv, "chroma of {}x{} {:?} with {}", width, height, format, detected.implementation());
+		}
+	}
+
+	#[test]
+	fn black_and_white() {
+		let source = [0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+		for (color_range, black, white) in [(ColorRange::Limited, 16, 235), (ColorRange::Full, 0, 255)] {
+			let converter = Converter::scalar(OutputFormat::Nv12, ColorSpace::Bt709, color_range);
+			let mut y = [0; 2];
+			let mut uv = [0; 2];
+			converter.convert(&source, 8, 2, 1, &mut y, 2, &mut uv, 2).unwrap();
+			assert_eq!(y, [black, white]);
+			assert_eq!(uv, [128, 128]);
+		}
+	}
+
+	#[test]
+	fn planes_too_small() {
+		let converter = Converter::new(OutputFormat::P010, ColorSpace::Bt601, ColorRange::Limited);
+		let source = [0; 4 * 4 * 4];
+		let mut y = [0; 4 * 4 * 2];
+		let mut uv = [0; 4 * 2 * 2];
+		assert!(converter.convert(&source, 16, 4, 4, &mut y, 8, &mut uv, 8).is_ok());
+		assert!(converter.convert(&source, 16, 4, 4, &mut y[1..], 8, &mut uv, 8).is_err());
+		assert!(converter.convert(&source, 16, 4, 4, &mut y, 8, &mut uv[1..], 8).is_err());
+		assert!(converter.convert(&source, 16, 4, 4, &mut y, 6, &mut uv, 8).is_err());
+		assert!(converter.convert(&source[1..], 16, 4, 4, &mut y, 8, &mut uv, 8).is_err());
+	}
+}
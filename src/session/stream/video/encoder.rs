@@ -1,9 +1,10 @@
-use std::{collections::{hash_map::Entry, HashMap}, sync::{Arc, Mutex}};
+use std::{collections::{hash_map::Entry, HashMap}, sync::{atomic::{AtomicU32, Ordering}, Arc}};
 
 use async_shutdown::ShutdownManager;
 use cudarc::driver::CudaDevice;
 use ffmpeg::{
 	codec::packet::flag::Flags,
+	color,
 	format::Pixel,
 	option::Settable,
 	Frame,
@@ -11,11 +12,18 @@ use ffmpeg::{
 };
 use reed_solomon_erasure::{galois_8, ReedSolomon};
 
-use crate::{ffmpeg::{hwdevice::CudaDeviceContextBuilder, hwframe::{HwFrameContext, HwFrameContextBuilder}}, session::stream::RtpHeader};
+use crate::{config::{ColorRange, ColorSpace, H264DecoderConfig, H264Level}, ffmpeg::{check_ret, hwdevice::CudaDeviceContextBuilder, hwframe::{HwFrameContext, HwFrameContextBuilder}}, session::{stats::SessionStats, stream::RtpHeader, SessionClock}};
+
+use super::{bitstream::{self, Codec, ParameterSets}, convert::{Converter, OutputFormat}, fence::FencedFrame, queue::FrameQueue, snapshot::capture_snapshot, tap::VideoTap};
 
 /// Maximum allowed number of shards in the encoder (data + parity).
 pub const MAX_SHARDS: usize = 255;
 
+/// Pixel formats that encoders without CUDA support can receive, in order of preference.
+///
+/// NV12 and P010 come first, since frames are converted to those with SIMD instead of swscale.
+const SOFTWARE_PIXEL_FORMATS: [Pixel; 3] = [Pixel::NV12, Pixel::YUV420P, Pixel::P010LE];
+
 #[repr(u8)]
 enum RtpFlag {
 	ContainsPicData = 0x1,
@@ -65,20 +73,191 @@ impl NvVideoPacket {
 	}
 }
 
+/// Converts captured CUDA frames for encoders that can't read CUDA frames directly, or that encode at a lower resolution.
+struct SoftwareConverter {
+	/// Captured frame, downloaded from the GPU.
+	download: ffmpeg::frame::Video,
+
+	/// Downloaded frame, converted to the pixel format of the encoder.
+	converted: ffmpeg::frame::Video,
+
+	conversion: Conversion,
+}
+
+enum Conversion {
+	/// Frames are scaled or converted to a format without a SIMD implementation by swscale.
+	Scaler(ffmpeg::software::scaling::Context),
+
+	/// Frames are converted to NV12 or P010 at the same resolution.
+	Simd(Converter),
+}
+
+impl SoftwareConverter {
+	fn new(
+		width: u32,
+		height: u32,
+		encode_width: u32,
+		encode_height: u32,
+		format: Pixel,
+		color_space: ColorSpace,
+		color_range: ColorRange,
+	) -> Result<Self, ()> {
+		let simd_format = match format {
+			Pixel::NV12 => Some(OutputFormat::Nv12),
+			Pixel::P010LE => Some(OutputFormat::P010),
+			_ => None,
+		};
+		if let Some(simd_format) = simd_format.filter(|_| width == encode_width && height == encode_height) {
+			return Ok(Self {
+				download: ffmpeg::frame::Video::new(Pixel::ZRGB32, width, height),
+				converted: ffmpeg::frame::Video::new(format, encode_width, encode_height),
+				conversion: Conversion::Simd(Converter::new(simd_format, color_space, color_range)),
+			});
+		}
+
+		let mut scaler = ffmpeg::software::scaling::Context::get(
+			Pixel::ZRGB32, width, height,
+			format, encode_width, encode_height,
+			ffmpeg::software::scaling::Flags::FAST_BILINEAR,
+		)
+			.map_err(|e| tracing::error!("Failed to create pixel format converter: {e}"))?;
+
+		// Captured frames are full range RGB, the converted frames use the color space and range of the stream.
+		let sws_color_space = match color_space {
+			ColorSpace::Bt601 => ffmpeg::sys::SWS_CS_ITU601,
+			ColorSpace::Bt709 => ffmpeg::sys::SWS_CS_ITU709,
+			ColorSpace::Bt2020 => ffmpeg::sys::SWS_CS_BT2020,
+		};
+		unsafe {
+			let source = ffmpeg::sys::sws_getCoefficients(ffmpeg::sys::SWS_CS_DEFAULT as i32);
+			let destination = ffmpeg::sys::sws_getCoefficients(sws_color_space as i32);
+			ffmpeg::sys::sws_setColorspaceDetails(
+				scaler.as_mut_ptr(),
+				source,
+				1,
+				destination,
+				(color_range == ColorRange::Full) as i32,
+				0, 1 << 16, 1 << 16,
+			);
+		}
+
+		Ok(Self {
+			download: ffmpeg::frame::Video::new(Pixel::ZRGB32, width, height),
+			converted: ffmpeg::frame::Video::new(format, encode_width, encode_height),
+			conversion: Conversion::Scaler(scaler),
+		})
+	}
+
+	fn convert(&mut self, source: &Frame) -> Result<&Frame, ()> {
+		unsafe {
+			check_ret(ffmpeg::sys::av_hwframe_transfer_data(self.download.as_mut_ptr(), source.as_ptr(), 0))
+				.map_err(|e| tracing::error!("Failed to download frame from GPU: {e}"))?;
+		}
+
+		match &mut self.conversion {
+			Conversion::Scaler(scaler) => {
+				scaler.run(&self.download, &mut self.converted)
+					.map_err(|e| tracing::error!("Failed to convert frame: {e}"))?;
+			},
+			Conversion::Simd(converter) => {
+				let width = self.converted.width() as usize;
+				let height = self.converted.height() as usize;
+				let (y_stride, uv_stride) = (self.converted.stride(0), self.converted.stride(1));
+
+				// The planes are borrowed at the same time, which the safe accessors of the frame don't allow.
+				let (y, uv) = unsafe {
+					let frame = &*self.converted.as_ptr();
+					(
+						std::slice::from_raw_parts_mut(frame.data[0], y_stride * height),
+						std::slice::from_raw_parts_mut(frame.data[1], uv_stride * height.div_ceil(2)),
+					)
+				};
+				converter.convert(self.download.data(0), self.download.stride(0), width, height, y, y_stride, uv, uv_stride)?;
+			},
+		}
+
+		self.converted.set_pts(source.pts());
+		unsafe {
+			(*self.converted.as_mut_ptr()).pict_type = (*source.as_ptr()).pict_type;
+			(*self.converted.as_mut_ptr()).key_frame = (*source.as_ptr()).key_frame;
+		}
+
+		Ok(&*self.converted)
+	}
+}
+
 pub struct Encoder {
 	encoder: ffmpeg::encoder::Video,
 	pub hw_frame_context: HwFrameContext,
+	converter: Option<SoftwareConverter>,
 	fec_encoders: HashMap<(usize, usize), ReedSolomon<galois_8::Field>>,
+	codec: Codec,
+
+	/// Size of the length prefix of NAL units, for encoders that don't write Annex-B bitstreams.
+	avcc_length_size: Option<usize>,
+
+	/// Parameter sets of the stream, sent with every IDR frame that doesn't include them.
+	parameter_sets: Option<ParameterSets>,
+
+	/// Whether the encoder picks up a new bitrate while it is running, which NVENC and libx264 do.
+	live_bitrate: bool,
 }
 
 impl Encoder {
+	/// Open the first encoder from `codec_names` that works, returns the encoder and the name of its codec.
+	#[allow(clippy::too_many_arguments)]
+	pub fn new_with_fallback(
+		cuda_device: &CudaDevice,
+		codec_names: &[String],
+		width: u32,
+		height: u32,
+		encode_size: Option<(u32, u32)>,
+		framerate: u32,
+		bitrate: usize,
+		slices: u32,
+		color_space: ColorSpace,
+		color_range: ColorRange,
+		h264_decoder: &H264DecoderConfig,
+		options: &HashMap<String, String>,
+	) -> Result<(Self, String), ()> {
+		for (index, codec_name) in codec_names.iter().enumerate() {
+			match Self::new(cuda_device, codec_name, width, height, encode_size, framerate, bitrate, slices, color_space, color_range, h264_decoder, options) {
+				Ok(encoder) => {
+					if index > 0 {
+						tracing::warn!("Preferred encoder '{}' is not available, using '{codec_name}' instead.", codec_names[0]);
+					}
+					return Ok((encoder, codec_name.clone()));
+				},
+				Err(()) => {
+					if let Some(next_codec_name) = codec_names.get(index + 1) {
+						tracing::warn!("Failed to open encoder '{codec_name}', falling back to '{next_codec_name}'.");
+					}
+				},
+			}
+		}
+
+		tracing::error!("None of the encoders {codec_names:?} could be opened.");
+		Err(())
+	}
+
+	/// Open an encoder for frames of `width` by `height`, which are scaled to `encode_size` if provided.
+	///
+	/// H.264 encoders are limited to the profile and level of `h264_decoder`, `options` are passed to the encoder after its
+	/// default options, see `stream.video.encoder_options`.
+	#[allow(clippy::too_many_arguments)]
 	pub fn new(
 		cuda_device: &CudaDevice,
 		codec_name: &str,
 		width: u32,
 		height: u32,
+		encode_size: Option<(u32, u32)>,
 		framerate: u32,
 		bitrate: usize,
+		slices: u32,
+		color_space: ColorSpace,
+		color_range: ColorRange,
+		h264_decoder: &H264DecoderConfig,
+		options: &HashMap<String, String>,
 	) -> Result<Self, ()> {
 		let cuda_device_context = CudaDeviceContextBuilder::new()
 			.map_err(|e| tracing::error!("Failed to create CUDA device context: {e}"))?
@@ -101,38 +280,154 @@ impl Encoder {
 		let codec = ffmpeg::encoder::find_by_name(codec_name)
 			.ok_or_else(|| tracing::error!("Failed to find codec by name '{codec_name}'."))?;
 
+		// Encoders that support CUDA frames receive the captured frames directly, other encoders and
+		// encoders that encode at a lower resolution receive frames that are downloaded from the GPU and converted.
+		let supported_formats: Vec<Pixel> = codec.video()
+			.ok()
+			.and_then(|video| video.formats())
+			.map(|formats| formats.collect())
+			.unwrap_or_default();
+		let supports_cuda = supported_formats.contains(&Pixel::CUDA);
+		let software_format = if supports_cuda && encode_size.is_none() {
+			None
+		} else {
+			let format = SOFTWARE_PIXEL_FORMATS.into_iter()
+				.find(|format| supported_formats.contains(format))
+				.ok_or_else(|| tracing::warn!("Encoder '{codec_name}' supports none of {SOFTWARE_PIXEL_FORMATS:?}."))?;
+			tracing::debug!("Encoder '{codec_name}' receives frames converted to {format:?}.");
+			Some(format)
+		};
+		let (encode_width, encode_height) = encode_size.unwrap_or((width, height));
+
 		let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
 			.encoder()
 			.video()
 			.map_err(|e| tracing::error!("Failed to create video encoder: {e}"))?;
 
-		encoder.set_width(width);
-		encoder.set_height(height);
+		encoder.set_width(encode_width);
+		encoder.set_height(encode_height);
 		encoder.set_frame_rate(Some((framerate as i32, 1)));
 		encoder.set_time_base((framerate as i32, 1));
 		encoder.set_max_b_frames(0);
 		encoder.set_bit_rate(bitrate);
 		encoder.set_gop(i32::max_value() as u32);
+
+		// Slices can be decoded in parallel by the client, and a lost slice doesn't corrupt the entire frame.
+		unsafe {
+			(*encoder.as_mut_ptr()).slices = slices as i32;
+		}
+
+		// The color description ends up in the VUI of the parameter sets, which tells the decoder how to convert to RGB.
+		let (primaries, transfer, space) = match color_space {
+			ColorSpace::Bt601 => (color::Primaries::SMPTE170M, color::TransferCharacteristic::SMPTE170M, color::Space::SMPTE170M),
+			ColorSpace::Bt709 => (color::Primaries::BT709, color::TransferCharacteristic::BT709, color::Space::BT709),
+			ColorSpace::Bt2020 => (color::Primaries::BT2020, color::TransferCharacteristic::BT2020_10, color::Space::BT2020NCL),
+		};
+		let range = match color_range {
+			ColorRange::Limited => color::Range::MPEG,
+			ColorRange::Full => color::Range::JPEG,
+		};
+		unsafe {
+			(*encoder.as_mut_ptr()).color_primaries = primaries.into();
+			(*encoder.as_mut_ptr()).color_trc = transfer.into();
+			(*encoder.as_mut_ptr()).colorspace = space.into();
+			(*encoder.as_mut_ptr()).color_range = range.into();
+		}
+
+		match software_format {
+			None => unsafe {
+				(*encoder.as_mut_ptr()).pix_fmt = Pixel::CUDA.into();
+				(*encoder.as_mut_ptr()).hw_frames_ctx = hw_frame_context.as_raw_mut();
+			},
+			Some(format) => encoder.set_format(format),
+		}
 		unsafe {
-			(*encoder.as_mut_ptr()).pix_fmt = Pixel::CUDA.into();
-			(*encoder.as_mut_ptr()).hw_frames_ctx = hw_frame_context.as_raw_mut();
 			(*encoder.as_mut_ptr()).delay = 0;
-			(*encoder.as_mut_ptr()).refs = 0;
 		}
-		encoder.set_str("preset", "fast")
-			.map_err(|e| tracing::error!("Failed to set preset for encoder: {e}"))?;
-		encoder.set_str("tune", "ull")
-			.map_err(|e| tracing::error!("Failed to set tuning option for encoder: {e}"))?;
-		encoder.set_str("forced-idr", "1")
-			.map_err(|e| tracing::error!("Failed to set forced-idr for encoder: {e}"))?;
+
+		if supports_cuda {
+			unsafe {
+				(*encoder.as_mut_ptr()).refs = 0;
+			}
+			encoder.set_str("preset", "fast")
+				.map_err(|e| tracing::error!("Failed to set preset for encoder: {e}"))?;
+			encoder.set_str("tune", "ull")
+				.map_err(|e| tracing::error!("Failed to set tuning option for encoder: {e}"))?;
+			encoder.set_str("forced-idr", "1")
+				.map_err(|e| tracing::error!("Failed to set forced-idr for encoder: {e}"))?;
+		} else {
+			// Not every software encoder knows these options, so failing to set them is not fatal.
+			let _ = encoder.set_str("preset", "ultrafast")
+				.map_err(|e| tracing::debug!("Failed to set preset for encoder: {e}"));
+			let _ = encoder.set_str("tune", "zerolatency")
+				.map_err(|e| tracing::debug!("Failed to set tuning option for encoder: {e}"));
+			let _ = encoder.set_str("forced-idr", "1")
+				.map_err(|e| tracing::debug!("Failed to set forced-idr for encoder: {e}"));
+		}
+
+		// A stream in a profile or level the decoder doesn't support can't be decoded at all, so this encoder is unusable then.
+		if codec.id() == ffmpeg::codec::Id::H264 {
+			if let Some(profile) = h264_decoder.profile {
+				encoder.set_str("profile", profile.name())
+					.map_err(|e| tracing::warn!("Failed to set H.264 profile '{}' for encoder '{codec_name}': {e}", profile.name()))?;
+			}
+			if h264_decoder.max_level.is_some() {
+				let level = H264Level::required(encode_width, encode_height, framerate)
+					.ok_or_else(|| tracing::warn!("No H.264 level allows {encode_width}x{encode_height} at {framerate} FPS."))?;
+				tracing::debug!("Encoding H.264 at level {}.", level.name());
+
+				// NVENC has its own option for the level, other encoders like libx264 read it from the codec context.
+				let _ = encoder.set_str("level", level.name());
+				unsafe {
+					(*encoder.as_mut_ptr()).level = level.idc();
+				}
+			}
+		}
+
+		// Fallback encoders often don't know the options meant for the preferred encoder, so those are skipped.
+		for (name, value) in options {
+			match encoder.set_str(name, value) {
+				Ok(()) => tracing::debug!("Set encoder option '{name}' to '{value}' for '{codec_name}'."),
+				Err(e) => tracing::warn!("Encoder '{codec_name}' doesn't accept option '{name}' = '{value}', skipping it: {e}"),
+			}
+		}
 
 		let encoder = encoder.open()
 			.map_err(|e| tracing::error!("Failed to start encoder: {e}"))?;
 
+		// Encoders with a global header write the parameter sets to the extradata, others only write them in IDR frames.
+		let codec = if codec.id() == ffmpeg::codec::Id::HEVC { Codec::Hevc } else { Codec::H264 };
+		let extradata = unsafe {
+			let context = encoder.as_ptr();
+			if (*context).extradata.is_null() {
+				&[][..]
+			} else {
+				std::slice::from_raw_parts((*context).extradata, (*context).extradata_size as usize)
+			}
+		};
+		let avcc_length_size = bitstream::avcc_length_size(codec, extradata);
+		let parameter_sets = ParameterSets::from_extradata(codec, extradata);
+
+		let converter = match software_format {
+			Some(format) => Some(SoftwareConverter::new(width, height, encode_width, encode_height, format, color_space, color_range)?),
+			None => None,
+		};
+
+		// libx264 reconfigures itself when the bitrate of the codec context changes, other software encoders ignore it.
+		let live_bitrate = supports_cuda || codec_name == "libx264";
+		if !live_bitrate {
+			tracing::info!("Encoder '{codec_name}' can't change its bitrate during the stream, the bitrate stays at {} kbps.", bitrate / 1000);
+		}
+
 		Ok(Self {
 			encoder,
 			hw_frame_context,
+			converter,
 			fec_encoders: HashMap::new(),
+			codec,
+			avcc_length_size,
+			parameter_sets,
+			live_bitrate,
 		})
 	}
 
@@ -141,40 +436,46 @@ impl Encoder {
 		mut self,
 		packet_tx: tokio::sync::mpsc::Sender<Vec<u8>>,
 		mut idr_frame_request_rx: tokio::sync::broadcast::Receiver<()>,
+		mut bitrate_rx: tokio::sync::watch::Receiver<usize>,
 		packet_size: usize,
 		minimum_fec_packets: u32,
 		fec_percentage: u8,
-		mut encoder_buffer: Frame,
-		intermediate_buffer: Arc<Mutex<Frame>>,
-		notifier: Arc<std::sync::Condvar>,
+		mut encoder_buffer: FencedFrame,
+		frame_queue: Arc<FrameQueue>,
+		encoded_frames: Arc<AtomicU32>,
+		stats: SessionStats,
+		clock: SessionClock,
+		video_tap: VideoTap,
 		stop_signal: ShutdownManager<()>,
 	) -> Result<(), ()> {
 		let mut packet = Packet::empty();
 
+		if let Some(parameter_sets) = &self.parameter_sets {
+			stats.set_video_parameter_sets(parameter_sets.fmtp_parameters());
+		}
+
 		let mut frame_number = 0u32;
 		let mut sequence_number = 0u32;
-		let stream_start_time = std::time::Instant::now();
 		while !stop_signal.is_shutdown_triggered() {
-			// Swap the intermediate buffer with the output buffer.
-			// Note that the lock is only held while swapping buffers, to minimize wait time for others locking the buffer.
-			{
-				tracing::trace!("Waiting for new frame.");
-				// Wait for a new frame.
-				let lock = intermediate_buffer.lock()
-					.map_err(|e| tracing::error!("Failed to acquire buffer lock: {e}"))?;
-				let mut result = notifier.wait_timeout(lock, std::time::Duration::from_millis(500))
-					.map_err(|e| tracing::error!("Failed to wait for new frame: {e}"))?;
-
-				// Didn't get a lock, let's check shutdown status and try again.
-				if result.1.timed_out() {
-					continue;
-				}
+			// Take the next captured frame, the frame we encoded before goes back to the capturer.
+			tracing::trace!("Waiting for new frame.");
+			if !frame_queue.pop(&mut encoder_buffer, std::time::Duration::from_millis(500))? {
+				// No new frame yet, let's check shutdown status and try again.
+				continue;
+			}
+			tracing::trace!("Swapped new frame with old frame.");
 
-				tracing::trace!("Received notification of new frame.");
+			// Wait until the GPU finished copying the captured frame, otherwise we could encode a partially written frame.
+			stats.record_fence_wait(encoder_buffer.fence.wait()?);
+			let encoder_buffer = &mut encoder_buffer.frame;
 
-				std::mem::swap(&mut *result.0, &mut encoder_buffer);
+			// Snapshots show the captured frame, before it is scaled down for the encoder.
+			for request in video_tap.take_snapshot_requests() {
+				if let Ok(snapshot) = capture_snapshot(encoder_buffer, request.max_width) {
+					let _ = request.response_tx.send(snapshot);
+				}
 			}
-			tracing::trace!("Swapped new frame with old frame.");
+
 			frame_number += 1;
 			encoder_buffer.set_pts(Some(frame_number as i64));
 
@@ -188,40 +489,67 @@ impl Encoder {
 			}
 
 			// Check if there was an IDR frame request.
-			match idr_frame_request_rx.try_recv() {
-				Ok(_) => {
-					tracing::debug!("Received request for IDR frame.");
-					unsafe {
-						(*encoder_buffer.as_mut_ptr()).pict_type = ffmpeg::picture::Type::I.into();
-						(*encoder_buffer.as_mut_ptr()).key_frame = 1;
-					}
-				},
-				Err(tokio::sync::broadcast::error::TryRecvError::Empty) => {},
-				Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => {},
+			let idr_frame_requested = match idr_frame_request_rx.try_recv() {
+				Ok(_) => true,
+				// Requests that arrived together overflow the channel, which still means an IDR frame was requested.
+				Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => true,
+				Err(tokio::sync::broadcast::error::TryRecvError::Empty) => false,
 				Err(_) => {
 					tracing::debug!("Channel closed, quitting encoder task.");
 					return Ok(());
 				}
+			};
+			if idr_frame_requested {
+				tracing::debug!("Received request for IDR frame.");
+				unsafe {
+					(*encoder_buffer.as_mut_ptr()).pict_type = ffmpeg::picture::Type::I.into();
+					(*encoder_buffer.as_mut_ptr()).key_frame = 1;
+				}
 			}
 
-			// Send the frame to the encoder.
-			self.encoder.send_frame(&encoder_buffer)
+			// Check if the target bitrate changed, NVENC and libx264 support reconfiguring the bitrate on the fly.
+			if bitrate_rx.has_changed().unwrap_or(false) {
+				let bitrate = *bitrate_rx.borrow_and_update();
+				if !self.live_bitrate {
+					tracing::debug!("Ignoring bitrate change to {} kbps, the encoder can't change its bitrate.", bitrate / 1000);
+				} else {
+					tracing::debug!("Changing encoder bitrate to {} kbps.", bitrate / 1000);
+					unsafe {
+						(*self.encoder.as_mut_ptr()).bit_rate = bitrate as i64;
+						(*self.encoder.as_mut_ptr()).rc_max_rate = bitrate as i64;
+					}
+				}
+			}
+
+			// Send the frame to the encoder, converting it first if the encoder can't read CUDA frames.
+			let encode_start = std::time::Instant::now();
+			let frame = match &mut self.converter {
+				Some(converter) => converter.convert(encoder_buffer)?,
+				None => &*encoder_buffer,
+			};
+			self.encoder.send_frame(frame)
 				.map_err(|e| tracing::error!("Error sending frame for encoding: {e}"))?;
 
 			loop {
 				match self.encoder.receive_packet(&mut packet) {
 					Ok(()) => {
 						tracing::trace!("Received frame {} from encoder, converting frame to packets.", packet.pts().unwrap_or(-1));
+						let key_frame = packet.flags().contains(Flags::KEY);
+						let data = self.annexb_with_parameter_sets(&packet, key_frame, &stats)?;
+						video_tap.publish(&data, key_frame, self.codec);
 						self.encode_packet(
-							&packet,
+							&data,
+							key_frame,
 							&packet_tx,
 							packet_size,
 							minimum_fec_packets,
 							fec_percentage,
 							frame_number,
 							&mut sequence_number,
-							stream_start_time,
-						)?
+							&clock,
+						)?;
+						encoded_frames.fetch_add(1, Ordering::Relaxed);
+						stats.record_encoded_frame(encode_start.elapsed());
 					},
 					Err(e) => {
 						match e {
@@ -247,36 +575,97 @@ impl Encoder {
 		Ok(())
 	}
 
+	/// Encode a single frame and wait for its packets, returns the size of the encoded data in bytes.
+	pub fn encode_frame(&mut self, frame: &Frame) -> Result<usize, ()> {
+		let frame = match &mut self.converter {
+			Some(converter) => converter.convert(frame)?,
+			None => frame,
+		};
+		self.encoder.send_frame(frame)
+			.map_err(|e| tracing::error!("Error sending frame for encoding: {e}"))?;
+
+		let mut packet = Packet::empty();
+		let mut size = 0;
+		loop {
+			match self.encoder.receive_packet(&mut packet) {
+				Ok(()) => size += packet.size(),
+				Err(ffmpeg::Error::Other { errno: ffmpeg::sys::EAGAIN }) | Err(ffmpeg::Error::Eof) => break,
+				Err(e) => {
+					tracing::error!("Unexpected error while encoding: {e}");
+					return Err(());
+				},
+			}
+		}
+
+		Ok(size)
+	}
+
+	/// Convert the packet to Annex-B if needed, and make sure IDR frames start with the parameter sets.
+	fn annexb_with_parameter_sets(&mut self, packet: &Packet, key_frame: bool, stats: &SessionStats) -> Result<Vec<u8>, ()> {
+		let data = packet.data()
+			.ok_or_else(|| tracing::error!("Packet is empty, but we expected it to be full."))?;
+		let data = match self.avcc_length_size {
+			Some(length_size) => bitstream::avcc_to_annexb(data, length_size)?,
+			None => data.to_vec(),
+		};
+
+		if !key_frame {
+			return Ok(data);
+		}
+
+		if bitstream::contains_parameter_sets(self.codec, &data) {
+			let parameter_sets = ParameterSets::from_annexb(self.codec, &data);
+			if parameter_sets.is_some() && parameter_sets != self.parameter_sets {
+				tracing::debug!("Received new parameter sets from the encoder.");
+				self.parameter_sets = parameter_sets;
+				if let Some(parameter_sets) = &self.parameter_sets {
+					stats.set_video_parameter_sets(parameter_sets.fmtp_parameters());
+				}
+			}
+			return Ok(data);
+		}
+
+		match &self.parameter_sets {
+			Some(parameter_sets) => {
+				tracing::trace!("Prepending parameter sets to IDR frame.");
+				Ok([parameter_sets.to_annexb(), data].concat())
+			},
+			None => {
+				tracing::warn!("IDR frame has no parameter sets and the encoder didn't provide any, the client may fail to decode it.");
+				Ok(data)
+			},
+		}
+	}
+
 	#[allow(clippy::too_many_arguments)] // TODO: Problem for later..
 	fn encode_packet(
 		&mut self,
-		packet: &Packet,
+		packet_data: &[u8],
+		key_frame: bool,
 		packet_tx: &tokio::sync::mpsc::Sender<Vec<u8>>,
 		requested_packet_size: usize,
 		minimum_fec_packets: u32,
 		fec_percentage: u8,
 		frame_number: u32,
 		sequence_number: &mut u32,
-		stream_start_time: std::time::Instant,
+		clock: &SessionClock,
 	) -> Result<(), ()> {
 		// Random padding, because we need it.
 		const PADDING: u32 = 0;
 
-		let timestamp = ((std::time::Instant::now() - stream_start_time).as_micros() / (1000 / 90)) as u32;
+		let timestamp = clock.rtp_timestamp();
 
 		// TODO: Figure out what this header means?
 		let video_frame_header = VideoFrameHeader {
 			header_type: 0x01, // Always 0x01 for short headers. What is this exactly?
 			padding1: 0,
-			frame_type: if packet.flags().contains(Flags::KEY) { 2 } else { 1 },
+			frame_type: if key_frame { 2 } else { 1 },
 			padding2: 0,
 		};
 
 		// Prefix the frame with a VideoFrameHeader.
 		let mut buffer = Vec::with_capacity(std::mem::size_of::<VideoFrameHeader>());
 		video_frame_header.serialize(&mut buffer);
-		let packet_data = packet.data()
-			.ok_or_else(|| tracing::error!("Packet is empty, but we expected it to be full."))?;
 		let packet_data = [&buffer, packet_data].concat();
 
 		let requested_shard_payload_size = requested_packet_size - std::mem::size_of::<NvVideoPacket>();
@@ -380,7 +769,14 @@ impl Encoder {
 					shard.extend(vec![0u8; requested_shard_payload_size - (end - start)]);
 				}
 
-				shards.push(shard);
+				// Data shards are sent right away, so the client receives them while the parity shards are computed.
+				if nr_parity_shards > 0 {
+					shards.push(shard.clone());
+				}
+				if packet_tx.blocking_send(shard).is_err() {
+					tracing::info!("Channel closed, couldn't send packet.");
+					return Ok(());
+				}
 
 				*sequence_number += 1;
 			}
@@ -410,8 +806,8 @@ impl Encoder {
 				}
 			}
 
-			for (index, shard) in shards.into_iter().enumerate() {
-				tracing::trace!("Sending shard {}/{} with size {} bytes.", index + 1, nr_data_shards + nr_parity_shards, shard.len());
+			for (index, shard) in shards.into_iter().enumerate().skip(nr_data_shards) {
+				tracing::trace!("Sending parity shard {}/{} with size {} bytes.", index + 1, nr_data_shards + nr_parity_shards, shard.len());
 				if packet_tx.blocking_send(shard).is_err() {
 					tracing::info!("Channel closed, couldn't send packet.");
 					return Ok(());
@@ -0,0 +1,120 @@
+//! Encryption of video packets, for clients that ask for it so that others on the network can't watch the stream.
+//!
+//! Every packet is encrypted with AES-GCM and the session key, and prefixed with the initialization vector, the frame
+//! index and the authentication tag. The frame index stays readable, so the client can drop packets of old frames
+//! without decrypting them.
+
+use crate::{crypto, session::SessionKeys};
+
+/// Offset of the frame index in a video packet, after the RTP header, the padding and the stream packet index.
+const FRAME_INDEX_OFFSET: usize = 20;
+
+const INITIALIZATION_VECTOR_LENGTH: usize = 12;
+
+pub struct VideoEncryptor {
+	/// Number of packets that were encrypted, which makes every initialization vector unique.
+	counter: u64,
+}
+
+impl VideoEncryptor {
+	pub fn new() -> Self {
+		Self { counter: 0 }
+	}
+
+	/// Encrypt a video packet, including its RTP header.
+	pub fn encrypt(&mut self, keys: &SessionKeys, packet: &[u8]) -> Result<Vec<u8>, ()> {
+		let frame_index = packet.get(FRAME_INDEX_OFFSET..FRAME_INDEX_OFFSET + 4)
+			.ok_or_else(|| tracing::warn!("Can't encrypt video packet of {} bytes, it is too short to be a video packet.", packet.len()))?;
+
+		let initialization_vector = self.next_initialization_vector();
+		let (encrypted, tag) = crypto::encrypt_gcm(keys.key(), &initialization_vector, packet)
+			.map_err(|e| tracing::error!("Failed to encrypt video packet: {e}"))?;
+
+		let mut buffer = Vec::with_capacity(INITIALIZATION_VECTOR_LENGTH + frame_index.len() + tag.len() + encrypted.len());
+		buffer.extend(initialization_vector);
+		buffer.extend(frame_index);
+		buffer.extend(tag);
+		buffer.extend(encrypted);
+
+		Ok(buffer)
+	}
+
+	/// The packet counter, followed by a 'V' that keeps the vector apart from those of the control stream, which uses
+	/// the same key.
+	fn next_initialization_vector(&mut self) -> [u8; INITIALIZATION_VECTOR_LENGTH] {
+		let mut initialization_vector = [0u8; INITIALIZATION_VECTOR_LENGTH];
+		initialization_vector[..8].copy_from_slice(&self.counter.to_le_bytes());
+		initialization_vector[INITIALIZATION_VECTOR_LENGTH - 1] = b'V';
+		self.counter += 1;
+		initialization_vector
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Length of the header that precedes the ciphertext: the initialization vector, the frame index and the tag.
+	const HEADER_LENGTH: usize = INITIALIZATION_VECTOR_LENGTH + 4 + crypto::GCM_TAG_LENGTH;
+
+	fn keys() -> SessionKeys {
+		SessionKeys::from_launch_parameters(b"0123456789abcdef", 1).unwrap()
+	}
+
+	/// A video packet of frame 0x01020304 with a payload.
+	fn packet() -> Vec<u8> {
+		let mut packet = vec![0u8; FRAME_INDEX_OFFSET];
+		packet.extend([1, 2, 3, 4]);
+		packet.extend(b"slice data");
+		packet
+	}
+
+	/// Decrypt a packet like a client does.
+	fn decrypt(keys: &SessionKeys, buffer: &[u8]) -> Result<Vec<u8>, crypto::CryptoError> {
+		let (initialization_vector, rest) = buffer.split_at(INITIALIZATION_VECTOR_LENGTH);
+		let (tag, ciphertext) = rest[4..].split_at(crypto::GCM_TAG_LENGTH);
+		crypto::decrypt_gcm(keys.key(), initialization_vector, ciphertext, tag)
+	}
+
+	#[test]
+	fn round_trip() {
+		let keys = keys();
+		let mut encryptor = VideoEncryptor::new();
+
+		let first = encryptor.encrypt(&keys, &packet()).unwrap();
+		let second = encryptor.encrypt(&keys, &packet()).unwrap();
+		for buffer in [&first, &second] {
+			assert_eq!(buffer.len(), HEADER_LENGTH + packet().len());
+			assert_eq!(&buffer[INITIALIZATION_VECTOR_LENGTH..][..4], &[1, 2, 3, 4]);
+			assert_eq!(decrypt(&keys, buffer).unwrap(), packet());
+		}
+
+		// Every packet has its own initialization vector.
+		assert_ne!(first[..INITIALIZATION_VECTOR_LENGTH], second[..INITIALIZATION_VECTOR_LENGTH]);
+		assert_eq!(first[INITIALIZATION_VECTOR_LENGTH - 1], b'V');
+	}
+
+	#[test]
+	fn rejects_short_packets() {
+		let mut encryptor = VideoEncryptor::new();
+		assert!(encryptor.encrypt(&keys(), &packet()[..FRAME_INDEX_OFFSET + 3]).is_err());
+		assert!(encryptor.encrypt(&keys(), &packet()[..FRAME_INDEX_OFFSET + 4]).is_ok());
+	}
+
+	#[test]
+	fn tampered_packets_fail_to_decrypt() {
+		let keys = keys();
+		let buffer = VideoEncryptor::new().encrypt(&keys, &packet()).unwrap();
+
+		let mut wrong_tag = buffer.clone();
+		wrong_tag[INITIALIZATION_VECTOR_LENGTH + 4] ^= 1;
+		assert!(decrypt(&keys, &wrong_tag).is_err());
+
+		let mut wrong_payload = buffer.clone();
+		*wrong_payload.last_mut().unwrap() ^= 1;
+		assert!(decrypt(&keys, &wrong_payload).is_err());
+
+		let other_keys = SessionKeys::from_launch_parameters(b"fedcba9876543210", 1).unwrap();
+		assert!(decrypt(&other_keys, &buffer).is_err());
+	}
+}
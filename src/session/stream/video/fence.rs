@@ -0,0 +1,52 @@
+use std::time::{Duration, Instant};
+
+use cudarc::driver::sys::{CUevent, CUevent_flags, CUstream};
+use ffmpeg::Frame;
+
+/// GPU fence that is signalled once the GPU has finished the work that was queued before it.
+pub struct FrameFence {
+	event: CUevent,
+}
+
+// The event is only a handle, CUDA allows using it from any thread.
+unsafe impl Send for FrameFence {}
+
+impl FrameFence {
+	/// Create a fence in the CUDA context that is bound to the current thread.
+	pub fn new() -> Result<Self, ()> {
+		let mut event = std::ptr::null_mut();
+		unsafe { cudarc::driver::sys::cuEventCreate(&mut event, CUevent_flags::CU_EVENT_DISABLE_TIMING as u32).result() }
+			.map_err(|e| tracing::error!("Failed to create CUDA event: {e}"))?;
+
+		Ok(Self { event })
+	}
+
+	/// Signal the fence once the work that is currently queued on `stream` is finished.
+	pub fn signal(&self, stream: CUstream) -> Result<(), ()> {
+		unsafe { cudarc::driver::sys::cuEventRecord(self.event, stream).result() }
+			.map_err(|e| tracing::error!("Failed to record CUDA event: {e}"))
+	}
+
+	/// Block until the fence is signalled, returns how long we had to wait.
+	pub fn wait(&self) -> Result<Duration, ()> {
+		let start = Instant::now();
+		unsafe { cudarc::driver::sys::cuEventSynchronize(self.event).result() }
+			.map_err(|e| tracing::error!("Failed to wait for CUDA event: {e}"))?;
+
+		Ok(start.elapsed())
+	}
+}
+
+impl Drop for FrameFence {
+	fn drop(&mut self) {
+		if let Err(e) = unsafe { cudarc::driver::sys::cuEventDestroy_v2(self.event).result() } {
+			tracing::warn!("Failed to destroy CUDA event: {e}");
+		}
+	}
+}
+
+/// A frame on the GPU, with a fence that is signalled once the GPU has finished writing to it.
+pub struct FencedFrame {
+	pub frame: Frame,
+	pub fence: FrameFence,
+}
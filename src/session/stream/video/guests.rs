@@ -0,0 +1,132 @@
+//! Keeps guests of the session connected by sending them the key frames of the video stream.
+//!
+//! Moonlight closes its connection when it doesn't receive a complete video frame shortly after it started streaming,
+//! so guests can't go without video entirely. Sending them only the key frames keeps them connected for a fraction of
+//! the bitrate, and shows them the screen as a slideshow. The key frames are encrypted with the keys of each guest.
+
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::session::{guests::Guest, SessionKeys};
+
+use super::encryption::VideoEncryptor;
+
+/// Offset of the frame index in a video packet, after the RTP header, the padding and the stream packet index.
+const FRAME_INDEX_OFFSET: usize = 20;
+
+/// Offset of the frame type in the first packet of a frame, after the video packet header and the first bytes of the
+/// frame header.
+const FRAME_TYPE_OFFSET: usize = 35;
+
+/// Frame type of key frames.
+const KEY_FRAME_TYPE: u8 = 2;
+
+/// Guests that stopped sending PINGs for this long don't receive key frames anymore.
+const GUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct GuestDestination {
+	keys: SessionKeys,
+
+	/// Encryptors are kept for as long as the keys don't change, so initialization vectors are never reused.
+	encryptor: VideoEncryptor,
+
+	last_ping: Instant,
+}
+
+pub struct GuestVideo {
+	/// Guests by the address their PINGs came from.
+	guests: HashMap<SocketAddr, GuestDestination>,
+
+	/// Time after which guests get a new key frame, if set.
+	key_frame_interval: Option<Duration>,
+
+	/// Frame index of the frame that is being sent and whether it is a key frame.
+	frame: Option<(u32, bool)>,
+
+	/// When the last key frame was sent to guests.
+	last_key_frame: Option<Instant>,
+}
+
+impl GuestVideo {
+	pub fn new(key_frame_interval: Option<Duration>) -> Self {
+		Self { guests: HashMap::new(), key_frame_interval, frame: None, last_key_frame: None }
+	}
+
+	/// Handle a PING of a guest, returns whether the guest is new and needs a key frame to show something.
+	pub fn ping(&mut self, address: SocketAddr, guest: &Guest) -> bool {
+		let now = Instant::now();
+		if let Some(destination) = self.guests.get_mut(&address) {
+			let active = now.duration_since(destination.last_ping) < GUEST_TIMEOUT;
+			destination.last_ping = now;
+			if destination.keys.key() == guest.keys.key() {
+				return !active;
+			}
+
+			destination.keys = guest.keys.clone();
+			destination.encryptor = VideoEncryptor::new();
+			return true;
+		}
+
+		tracing::info!("Sending key frames of the video stream to guest {} at {address}.", guest.client_id);
+		self.guests.insert(address, GuestDestination { keys: guest.keys.clone(), encryptor: VideoEncryptor::new(), last_ping: now });
+		true
+	}
+
+	/// Whether to request a key frame for the guests, because the last one they got is older than the interval.
+	///
+	/// The request counts as the last key frame, so it isn't repeated while the encoder is still working on it.
+	pub fn request_key_frame(&mut self) -> bool {
+		let Some(interval) = self.key_frame_interval else {
+			return false;
+		};
+
+		let due = self.last_key_frame.map_or(true, |last_key_frame| last_key_frame.elapsed() >= interval);
+		if !due || !self.guests.values().any(|destination| destination.last_ping.elapsed() < GUEST_TIMEOUT) {
+			return false;
+		}
+
+		self.last_key_frame = Some(Instant::now());
+		true
+	}
+
+	/// The copies of a video packet to send to guests, which is nothing unless the packet belongs to a key frame.
+	///
+	/// Packets are passed in the order they are sent, so the first packet of every frame carries the frame header.
+	pub fn packets(&mut self, packet: &[u8], encrypt: bool) -> Vec<(SocketAddr, Vec<u8>)> {
+		let Some(frame_index) = packet.get(FRAME_INDEX_OFFSET..FRAME_INDEX_OFFSET + 4)
+			.and_then(|bytes| bytes.try_into().ok())
+			.map(u32::from_le_bytes)
+		else {
+			return Vec::new();
+		};
+
+		let key_frame = match self.frame {
+			Some((index, key_frame)) if index == frame_index => key_frame,
+			_ => {
+				let key_frame = packet.get(FRAME_TYPE_OFFSET) == Some(&KEY_FRAME_TYPE);
+				self.frame = Some((frame_index, key_frame));
+				if key_frame {
+					self.last_key_frame = Some(Instant::now());
+				}
+				key_frame
+			},
+		};
+		if !key_frame {
+			return Vec::new();
+		}
+
+		let now = Instant::now();
+		self.guests.iter_mut()
+			.filter(|(_, destination)| now.duration_since(destination.last_ping) < GUEST_TIMEOUT)
+			.filter_map(|(address, destination)| {
+				let packet = if encrypt {
+					destination.encryptor.encrypt(&destination.keys, packet).ok()?
+				} else {
+					packet.to_vec()
+				};
+				Some((*address, packet))
+			})
+			.collect()
+	}
+}
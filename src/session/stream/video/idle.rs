@@ -0,0 +1,80 @@
+//! Lowers the encode rate while the screen doesn't change, so an idle desktop doesn't keep the GPU busy.
+
+use std::time::{Duration, Instant};
+
+use crate::config::IdleConfig;
+
+/// Only every n-th row of a frame is compared to detect changes, which is enough to notice a moving cursor or typed text.
+pub const SAMPLED_ROW_STRIDE: u32 = 8;
+
+/// Decides which captured frames are encoded, based on whether the screen changed.
+pub struct IdleDetector {
+	/// Time without changes after which the stream is idle.
+	timeout: Duration,
+
+	/// Time between two encoded frames while idle.
+	keepalive_interval: Duration,
+
+	/// Sampled rows of the previous frame.
+	previous_rows: Vec<u8>,
+
+	/// Sampled rows of the current frame.
+	current_rows: Vec<u8>,
+
+	/// When the screen changed or input was received for the last time.
+	last_activity: Instant,
+
+	/// When a frame was encoded for the last time.
+	last_encoded: Instant,
+
+	/// Whether the stream is currently idle.
+	idle: bool,
+}
+
+impl IdleDetector {
+	pub fn new(config: &IdleConfig) -> Self {
+		let now = Instant::now();
+		Self {
+			timeout: Duration::from_millis(config.timeout),
+			keepalive_interval: Duration::from_secs(1) / config.keepalive_fps.max(1),
+			previous_rows: Vec::new(),
+			current_rows: Vec::new(),
+			last_activity: now,
+			last_encoded: now,
+			idle: false,
+		}
+	}
+
+	/// Buffer to write the sampled rows of the next frame in, resized to `size` bytes.
+	pub fn rows_mut(&mut self, size: usize) -> &mut [u8] {
+		self.current_rows.resize(size, 0);
+		&mut self.current_rows
+	}
+
+	/// Compare the rows written through `rows_mut` with the previous frame, returns whether this frame should be encoded.
+	///
+	/// Frames are encoded immediately when the screen changed or input was received since the previous frame.
+	pub fn update(&mut self, input_received: bool) -> bool {
+		let now = Instant::now();
+		let changed = self.current_rows != self.previous_rows;
+		std::mem::swap(&mut self.current_rows, &mut self.previous_rows);
+
+		if changed || input_received {
+			if self.idle {
+				tracing::debug!("Screen is active again, encoding every frame.");
+				self.idle = false;
+			}
+			self.last_activity = now;
+		} else if !self.idle && now.duration_since(self.last_activity) >= self.timeout {
+			tracing::debug!("Screen is idle, encoding a frame every {:?}.", self.keepalive_interval);
+			self.idle = true;
+		}
+
+		if self.idle && now.duration_since(self.last_encoded) < self.keepalive_interval {
+			return false;
+		}
+
+		self.last_encoded = now;
+		true
+	}
+}
@@ -1,21 +1,89 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use async_shutdown::ShutdownManager;
-use ffmpeg::{format::Pixel, Frame};
-use tokio::{net::UdpSocket, sync::mpsc::{self, Sender}};
+use tokio::{net::UdpSocket, sync::{mpsc::{self, Sender}, watch}};
+use tracing::Instrument;
 
-use crate::{config::Config, ffmpeg::{check_ret, hwframe::HwFrameContext}};
+use crate::{config::{CaptureArea, ColorRange, ColorSpace, Config, H264DecoderConfig, QosConfig}, session::{guests::Guests, stats::SessionStats, SessionClock, SessionKeys}, supervisor::Supervisor};
 
+use super::{ping::PingTracker, socket::{SocketEvent, StreamSocket}};
+
+mod bench;
+pub use bench::{benchmark_capture, benchmark_conversion, benchmark_encoders, BenchmarkOptions, BenchmarkResult, CaptureBenchmarkResult, ConversionBenchmarkResult};
+
+#[cfg_attr(not(feature = "nvidia"), allow(dead_code))]
+mod bitstream;
+mod capabilities;
+pub use capabilities::EncoderCapabilities;
+
+#[cfg(feature = "nvidia")]
 mod capture;
-use capture::FrameCapturer;
 
+#[cfg(feature = "nvidia")]
+mod convert;
+
+#[cfg(feature = "nvidia")]
 mod encoder;
-use encoder::Encoder;
+
+mod encryption;
+use encryption::VideoEncryptor;
+
+#[cfg(feature = "nvidia")]
+mod fence;
+
+mod guests;
+use guests::GuestVideo;
+
+#[cfg(feature = "nvidia")]
+mod idle;
+
+mod pacing;
+pub use pacing::FrameStats;
+use pacing::PacingController;
+
+#[cfg(feature = "nvidia")]
+mod pipeline;
+#[cfg(feature = "nvidia")]
+use pipeline::Pipeline;
+
+mod probe;
+use probe::BandwidthProbe;
+
+#[cfg(feature = "nvidia")]
+mod queue;
+
+mod scaling;
+use scaling::{scaled_size, ResolutionController};
+
+#[cfg(feature = "nvidia")]
+mod snapshot;
+
+mod tap;
+pub use tap::VideoTap;
+#[cfg(feature = "webrtc")]
+pub use bitstream::Codec;
+
+mod txtime;
+use txtime::TxTimeScheduler;
+
+/// Number of frame intervals in which captured frames need to be encoded, before the pipeline is considered stalled.
+const WATCHDOG_STALLED_FRAMES: u32 = 30;
+
+/// Number of times a stalled pipeline is restarted before giving up.
+const MAX_PIPELINE_RESTARTS: u32 = 3;
 
 #[derive(Debug)]
 enum VideoStreamCommand {
 	Start,
 	RequestIdrFrame,
+	ReportLosses(u32),
+	UpdateFrameStats(FrameStats),
+	UpdateSettings(VideoStreamSettings),
+	UpdateCaptureArea(Option<CaptureArea>),
+	Pause,
+	Resume,
+	NotifyInput,
+	UpdateKeys(SessionKeys),
 }
 
 #[derive(Clone, Debug, Default)]
@@ -28,28 +96,88 @@ pub struct VideoStreamContext {
 	pub minimum_fec_packets: u32,
 	pub qos: bool,
 	pub video_format: u32,
+
+	/// Number of slices each frame is encoded in.
+	pub slices_per_frame: u32,
+
+	/// Color space and range of the encoded frames.
+	pub color_space: ColorSpace,
+	pub color_range: ColorRange,
+
+	/// Payload the client sends in its PING messages, to authenticate them.
+	pub ping_payload: String,
+
+	/// If set, video packets are encrypted with these keys.
+	pub encryption_keys: Option<SessionKeys>,
+
+	/// H.264 profile and level that the decoder of the client supports.
+	pub h264_decoder: H264DecoderConfig,
+
+	/// Guests of the session, which receive the key frames of the stream.
+	pub guests: Guests,
+}
+
+/// Video settings that a client can change while streaming.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VideoStreamSettings {
+	/// Maximum bitrate in bits per second.
+	pub bitrate: Option<usize>,
+
+	/// Frames per second.
+	pub fps: Option<u32>,
 }
 
 #[derive(Clone)]
 pub struct VideoStream {
-	command_tx: Sender<VideoStreamCommand>
+	command_tx: Sender<VideoStreamCommand>,
+	listening_rx: watch::Receiver<bool>,
+	started_rx: watch::Receiver<Option<Result<(), ()>>>,
 }
 
 struct VideoStreamInner {
 }
 
 impl VideoStream {
-	pub fn new(config: Config, context: VideoStreamContext, stop_signal: ShutdownManager<()>) -> Self {
+	pub fn new(config: Config, context: VideoStreamContext, stats: SessionStats, clock: SessionClock, video_tap: VideoTap, stop_signal: ShutdownManager<()>) -> Self {
 		let (command_tx, command_rx) = mpsc::channel(10);
+		let (listening_tx, listening_rx) = watch::channel(false);
+		let (started_tx, started_rx) = watch::channel(None);
 		let inner = VideoStreamInner { };
-		tokio::spawn(stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
-			config,
-			context,
-			command_rx,
-			stop_signal.clone()
-		))));
+		Supervisor::new(stop_signal.clone()).spawn_critical("video stream", (), {
+			let stop_signal = stop_signal.clone();
+			async move {
+				// Cancelling the stream on shutdown isn't a failure.
+				stop_signal.wrap_cancel(inner.run(
+					config,
+					context,
+					stats,
+					clock,
+					video_tap,
+					command_rx,
+					listening_tx,
+					started_tx,
+					stop_signal.clone()
+				)).await.unwrap_or(Ok(()))
+			}
+		});
+
+		Self { command_tx, listening_rx, started_rx }
+	}
 
-		Self { command_tx }
+	/// Wait until the stream listens for the client, fails if the stream stopped before that.
+	pub async fn wait_listening(&self) -> Result<(), ()> {
+		self.listening_rx.clone().wait_for(|listening| *listening).await
+			.map(|_| ())
+			.map_err(|_| tracing::error!("Video stream stopped before it was listening."))
+	}
+
+	/// Wait until the client started the stream, with the result of starting the encoder.
+	///
+	/// Returns `None` if the stream stopped before the client started it.
+	pub async fn wait_started(&self) -> Option<Result<(), ()>> {
+		let mut started_rx = self.started_rx.clone();
+		let started = started_rx.wait_for(|started| started.is_some()).await.ok()?;
+		*started
 	}
 
 	pub async fn start(&self) -> Result<(), ()> {
@@ -61,6 +189,52 @@ impl VideoStream {
 		self.command_tx.send(VideoStreamCommand::RequestIdrFrame).await
 			.map_err(|e| tracing::warn!("Failed to send RequestIdrFrame command: {e}"))
 	}
+
+	/// Report frames or packets that the client lost.
+	pub async fn report_losses(&self, losses: u32) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::ReportLosses(losses)).await
+			.map_err(|e| tracing::warn!("Failed to send ReportLosses command: {e}"))
+	}
+
+	/// Report how timely the client receives frames.
+	pub async fn update_frame_stats(&self, stats: FrameStats) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::UpdateFrameStats(stats)).await
+			.map_err(|e| tracing::warn!("Failed to send UpdateFrameStats command: {e}"))
+	}
+
+	pub async fn update_settings(&self, settings: VideoStreamSettings) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::UpdateSettings(settings)).await
+			.map_err(|e| tracing::warn!("Failed to send UpdateSettings command: {e}"))
+	}
+
+	pub async fn update_capture_area(&self, capture_area: Option<CaptureArea>) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::UpdateCaptureArea(capture_area)).await
+			.map_err(|e| tracing::warn!("Failed to send UpdateCaptureArea command: {e}"))
+	}
+
+	/// Stop capturing and encoding frames until the stream is resumed.
+	pub async fn pause(&self) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::Pause).await
+			.map_err(|e| tracing::warn!("Failed to send Pause command: {e}"))
+	}
+
+	/// Encrypt packets with new keys, if packets are encrypted.
+	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::UpdateKeys(keys)).await
+			.map_err(|e| tracing::warn!("Failed to send UpdateKeys command: {e}"))
+	}
+
+	/// Tell the capturer that the client sent input, so an idle stream encodes every frame again.
+	pub async fn notify_input(&self) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::NotifyInput).await
+			.map_err(|e| tracing::warn!("Failed to send NotifyInput command: {e}"))
+	}
+
+	/// Continue a paused stream, starting with an IDR frame.
+	pub async fn resume(&self) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::Resume).await
+			.map_err(|e| tracing::warn!("Failed to send Resume command: {e}"))
+	}
 }
 
 impl VideoStreamInner {
@@ -68,17 +242,21 @@ impl VideoStreamInner {
 		self,
 		config: Config,
 		mut context: VideoStreamContext,
+		stats: SessionStats,
+		clock: SessionClock,
+		video_tap: VideoTap,
 		mut command_rx: mpsc::Receiver<VideoStreamCommand>,
+		listening_tx: watch::Sender<bool>,
+		started_tx: watch::Sender<Option<Result<(), ()>>>,
 		stop_signal: ShutdownManager<()>,
 	) -> Result<(), ()> {
 		let socket = UdpSocket::bind((config.address, config.stream.video.port))
 			.await
 			.map_err(|e| tracing::error!("Failed to bind to UDP socket: {e}"))?;
 
-		if context.qos {
-			// TODO: Check this value 160, what does it mean exactly?
-			tracing::debug!("Enabling QoS on video socket.");
-			socket.set_tos(160)
+		if context.qos && config.network.qos.video != 0 {
+			tracing::debug!("Marking video packets with DSCP {}.", config.network.qos.video);
+			socket.set_tos(QosConfig::tos(config.network.qos.video))
 				.map_err(|e| tracing::error!("Failed to set QoS on the video socket: {e}"))?;
 		}
 
@@ -87,167 +265,458 @@ impl VideoStreamInner {
 			socket.local_addr()
 				.map_err(|e| tracing::error!("Failed to get local address associated with control socket: {e}"))?
 		);
-
-		let (packet_tx, mut packet_rx) = mpsc::channel::<Vec<u8>>(1024);
-		tokio::spawn(async move {
-			let mut buf = [0; 1024];
-			let mut client_address = None;
-
-			loop {
-				tokio::select! {
-					packet = packet_rx.recv() => {
-						match packet {
-							Some(packet) => {
-								if let Some(client_address) = client_address {
-									if let Err(e) = socket.send_to(packet.as_slice(), client_address).await {
-										tracing::warn!("Failed to send packet to client: {e}");
-									}
-								}
-							},
-							None => {
-								tracing::debug!("Packet channel closed.");
-								break;
-							},
-						}
-					},
-
-					message = socket.recv_from(&mut buf) => {
-						let (len, address) = match message {
-							Ok((len, address)) => (len, address),
-							Err(e) => {
-								tracing::warn!("Failed to receive message: {e}");
-								break;
-							},
-						};
-
-						if &buf[..len] == b"PING" {
-							tracing::trace!("Received video stream PING message from {address}.");
-							client_address = Some(address);
-						} else {
-							tracing::warn!("Received unknown message on video stream of length {len}.");
+		listening_tx.send_replace(true);
+
+		// Without SO_TXTIME support packets are sent as soon as they are encoded.
+		let mut txtime_scheduler = config.stream.video.txtime.as_ref()
+			.and_then(|txtime| TxTimeScheduler::new(&socket, txtime).ok());
+
+		let socket = Arc::new(socket);
+		#[cfg(feature = "netsim")]
+		let mut network_simulator = config.stream.network_simulation.clone()
+			.map(|simulation| super::netsim::NetworkSimulator::new("video", simulation, socket.clone()));
+		let (packet_tx, packet_rx) = mpsc::channel::<Vec<u8>>(1024);
+		let (bitrate_tx, _bitrate_rx) = watch::channel(context.bitrate);
+		let (encode_deadline_tx, _encode_deadline_rx) = watch::channel(None);
+		let ping_tracker = PingTracker::new("video", &context.ping_payload);
+		let mut stream_socket = StreamSocket::new(socket.clone(), packet_rx, ping_tracker, stop_signal.clone());
+		if config.coop.max_guests > 0 {
+			stream_socket.accept_guests(context.guests.clone());
+		}
+		let key_frame_interval = (config.coop.guest_key_frame_interval > 0)
+			.then(|| std::time::Duration::from_millis(config.coop.guest_key_frame_interval));
+		if context.encryption_keys.is_some() {
+			tracing::info!("Encrypting video packets.");
+		}
+		let (keys_tx, keys_rx) = watch::channel(context.encryption_keys.clone());
+		let (idr_frame_request_tx, _idr_frame_request_rx) = tokio::sync::broadcast::channel(1);
+		tokio::spawn({
+			let socket = socket.clone();
+			let stats = stats.clone();
+			let idr_frame_request_tx = idr_frame_request_tx.clone();
+			let bitrate_rx = bitrate_tx.subscribe();
+			let mut encryptor = VideoEncryptor::new();
+			let mut guest_video = GuestVideo::new(key_frame_interval);
+			async move {
+				while let Some(event) = stream_socket.next().await {
+					let (packet, client_address) = match event {
+						SocketEvent::Send(packet, client_address) => (packet, client_address),
+						SocketEvent::ClientAddressChanged(_) => {
+							// Frames encoded before the first PING were dropped, and a client that moved to another
+							// address may have missed some, either way it needs an IDR frame to decode what follows.
+							// Without a running encoder there is nobody to receive the request, nor a need for it.
+							let _ = idr_frame_request_tx.send(());
+							continue;
+						},
+						SocketEvent::GuestPing(address, guest) => {
+							// Guests only receive key frames, a guest that just joined gets one right away.
+							if guest_video.ping(address, &guest) {
+								let _ = idr_frame_request_tx.send(());
+							}
+							continue;
+						},
+					};
+
+					let encrypt = keys_rx.borrow().is_some();
+					for (address, packet) in guest_video.packets(&packet, encrypt) {
+						if let Err(e) = socket.send_to(packet.as_slice(), address).await {
+							tracing::debug!("Failed to send packet to guest at {address}: {e}");
 						}
-					},
+					}
+					if guest_video.request_key_frame() {
+						let _ = idr_frame_request_tx.send(());
+					}
+
+					let packet = match keys_rx.borrow().as_ref() {
+						Some(keys) => match encryptor.encrypt(keys, &packet) {
+							Ok(packet) => packet,
+							Err(()) => continue,
+						},
+						None => packet,
+					};
+
+					#[cfg(feature = "netsim")]
+					if let Some(network_simulator) = network_simulator.as_mut() {
+						stats.record_bytes_sent(packet.len());
+						network_simulator.send_to(packet, client_address);
+						continue;
+					}
+
+					let result = match txtime_scheduler.as_mut() {
+						Some(scheduler) => {
+							let txtime = scheduler.schedule(packet.len(), *bitrate_rx.borrow());
+							txtime::send_to(&socket, packet.as_slice(), client_address, txtime).await
+						},
+						None => socket.send_to(packet.as_slice(), client_address).await,
+					};
+
+					match result {
+						Ok(bytes) => stats.record_bytes_sent(bytes),
+						Err(e) => tracing::warn!("Failed to send packet to client: {e}"),
+					}
 				}
-			}
 
-			tracing::debug!("Stopping video stream.");
+				tracing::debug!("Stopping video stream.");
+			}.in_current_span()
 		});
 
-		let mut started_streaming = false;
-		let (idr_frame_request_tx, _idr_frame_request_rx) = tokio::sync::broadcast::channel(1);
-		while let Some(command) = command_rx.recv().await {
+		let mut pacing_controller = None;
+		let mut resolution_controller: Option<ResolutionController> = None;
+		let mut bandwidth_probe: Option<BandwidthProbe> = None;
+		let mut scale = 100;
+		let mut capture_area = config.stream.video.capture.clone();
+		let mut pipeline: Option<Pipeline> = None;
+		let mut watchdog: Option<tokio::time::Interval> = None;
+		let mut pacing_window = tokio::time::interval(pacing::WINDOW);
+		pacing_window.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+		let mut restarts = 0;
+		let mut paused = false;
+		loop {
+			let command = tokio::select! {
+				command = command_rx.recv() => match command {
+					Some(command) => command,
+					None => break,
+				},
+
+				_ = async { watchdog.as_mut().unwrap().tick().await }, if watchdog.is_some() => {
+					let Some(current_pipeline) = &mut pipeline else {
+						continue;
+					};
+					if !current_pipeline.is_stalled() {
+						continue;
+					}
+
+					restarts += 1;
+					if restarts > MAX_PIPELINE_RESTARTS {
+						tracing::error!("Video pipeline stalled again after {MAX_PIPELINE_RESTARTS} restarts, stopping stream.");
+						return Err(());
+					}
+
+					tracing::warn!("Video pipeline stalled, restarting it (attempt {restarts}/{MAX_PIPELINE_RESTARTS}).");
+					current_pipeline.stop();
+					pipeline = Some(Pipeline::start(
+						&config,
+						&mut context,
+						&stats,
+						&packet_tx,
+						&idr_frame_request_tx,
+						&bitrate_tx,
+						&encode_deadline_tx,
+						capture_area.as_ref(),
+						scale,
+						&clock,
+						&video_tap,
+						&stop_signal,
+					)?);
+
+					// Make sure the client can decode the stream of the new encoder straight away.
+					let _ = idr_frame_request_tx.send(());
+					continue;
+				},
+
+				_ = tokio::time::sleep_until(bandwidth_probe.as_ref().map_or_else(tokio::time::Instant::now, BandwidthProbe::deadline)), if bandwidth_probe.is_some() => {
+					let Some(bitrate) = bandwidth_probe.take().and_then(|probe| probe.finish(context.bitrate)) else {
+						continue;
+					};
+
+					context.bitrate = bitrate;
+					pacing_controller = Some(PacingController::new(context.bitrate, context.fps));
+					if let Some(resolution_controller) = &mut resolution_controller {
+						resolution_controller.set_max_bitrate(context.bitrate);
+					}
+					bitrate_tx.send_replace(context.bitrate);
+					encode_deadline_tx.send_replace(None);
+					continue;
+				},
+
+				_ = pacing_window.tick() => {
+					let Some(pacing_controller) = &mut pacing_controller else {
+						continue;
+					};
+
+					if pacing_controller.update() {
+						bitrate_tx.send_replace(pacing_controller.target_bitrate());
+						encode_deadline_tx.send_replace(pacing_controller.encode_deadline());
+					}
+
+					// Lowering the bitrate alone isn't always enough, so the encoder can also switch to a lower resolution.
+					let Some(new_scale) = resolution_controller.as_mut().and_then(|controller| controller.update(pacing_controller.target_bitrate())) else {
+						continue;
+					};
+					let Some(current_pipeline) = &pipeline else {
+						continue;
+					};
+
+					scale = new_scale;
+					let (width, height) = scaled_size(context.width, context.height, scale);
+					tracing::info!("Changing encode resolution to {width}x{height} ({scale}% of {}x{}).", context.width, context.height);
+					current_pipeline.stop();
+					pipeline = Some(Pipeline::start(
+						&config,
+						&mut context,
+						&stats,
+						&packet_tx,
+						&idr_frame_request_tx,
+						&bitrate_tx,
+						&encode_deadline_tx,
+						capture_area.as_ref(),
+						scale,
+						&clock,
+						&video_tap,
+						&stop_signal,
+					)?);
+					let _ = idr_frame_request_tx.send(());
+					continue;
+				},
+			};
+
 			match command {
 				VideoStreamCommand::RequestIdrFrame => {
+					// Clients can ask before the encoder started or while the stream is paused, every pipeline starts
+					// with an IDR frame, so there is nothing to do until then.
+					if pipeline.is_none() {
+						tracing::debug!("Received request for IDR frame while no encoder is running, the stream starts with one.");
+						continue;
+					}
+
+					// Clients also ask for an IDR frame when their decoder starts, so the bandwidth probe doesn't count it as a
+					// loss. The pacing controller only reacts to losses over several windows, so a single request doesn't matter.
+					if let Some(pacing_controller) = &mut pacing_controller {
+						pacing_controller.record_losses(1);
+					}
+
 					tracing::info!("Received request for IDR frame, next frame will be an IDR frame.");
 					idr_frame_request_tx.send(())
 						.map_err(|e| tracing::error!("Failed to send IDR frame request to encoder: {e}"))?;
 				},
-				VideoStreamCommand::Start => {
-					if started_streaming {
-						tracing::warn!("Can't start streaming twice.");
+				VideoStreamCommand::ReportLosses(losses) => {
+					if let Some(bandwidth_probe) = &mut bandwidth_probe {
+						bandwidth_probe.record_losses(losses);
+					}
+					if let Some(pacing_controller) = &mut pacing_controller {
+						pacing_controller.record_losses(losses);
+					}
+				},
+				VideoStreamCommand::UpdateFrameStats(stats) => {
+					tracing::trace!("Received frame stats: {stats:?}");
+					if let Some(pacing_controller) = &mut pacing_controller {
+						pacing_controller.record_frame_stats(&stats);
+					}
+				},
+				VideoStreamCommand::UpdateSettings(settings) => {
+					let fps_changed = settings.fps.is_some_and(|fps| fps != context.fps);
+					if let Some(bitrate) = settings.bitrate {
+						context.bitrate = bitrate;
+					}
+					if let Some(fps) = settings.fps {
+						context.fps = fps;
+					}
+
+					let Some(current_pipeline) = &pipeline else {
+						tracing::debug!("Video stream has not started yet, using {settings:?} when it starts.");
 						continue;
+					};
+
+					tracing::info!("Changing video stream to {} kbps at {} fps.", context.bitrate / 1000, context.fps);
+					pacing_controller = Some(PacingController::new(context.bitrate, context.fps));
+					if let Some(resolution_controller) = &mut resolution_controller {
+						resolution_controller.set_max_bitrate(context.bitrate);
+					}
+					encode_deadline_tx.send_replace(None);
+
+					// NVENC reconfigures the bitrate on the fly, but the capturer needs to be restarted for a different framerate.
+					bitrate_tx.send_replace(context.bitrate);
+					if fps_changed {
+						current_pipeline.stop();
+						pipeline = Some(Pipeline::start(
+							&config,
+							&mut context,
+							&stats,
+							&packet_tx,
+							&idr_frame_request_tx,
+							&bitrate_tx,
+							&encode_deadline_tx,
+							capture_area.as_ref(),
+							scale,
+							&clock,
+							&video_tap,
+							&stop_signal,
+						)?);
+						let _ = idr_frame_request_tx.send(());
+
+						let period = std::time::Duration::from_secs(1) * WATCHDOG_STALLED_FRAMES / context.fps.max(1);
+						watchdog = Some(tokio::time::interval_at(tokio::time::Instant::now() + period, period));
 					}
+				},
+				VideoStreamCommand::UpdateCaptureArea(area) => {
+					capture_area = area;
 
-					// TODO: Make the GPU index configurable.
-					let cuda_device = cudarc::driver::CudaDevice::new(0)
-						.map_err(|e| tracing::error!("Failed to initialize CUDA: {e}"))?;
-
-					let capturer = FrameCapturer::new()?;
-					let status = capturer.status()?;
-					if status.screen_size.w != context.width || status.screen_size.h != context.height {
-						// TODO: Resize the CUDA buffer to the requested size?
-						tracing::warn!(
-							"Client asked for resolution {}x{}, but we are generating a resolution of {}x{}.",
-							context.width, context.height, status.screen_size.w, status.screen_size.h
-						);
-						context.width = status.screen_size.w;
-						context.height = status.screen_size.h;
-					}
-
-					let mut encoder = Encoder::new(
-						&cuda_device,
-						if context.video_format == 0 { &config.stream.video.codec_h264 } else { &config.stream.video.codec_hevc },
-						context.width, context.height,
-						context.fps,
-						context.bitrate,
-					)?;
-
-					let capture_buffer = create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
-					let intermediate_buffer = Arc::new(Mutex::new(create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?));
-					let encoder_buffer = create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
-					let notifier = Arc::new(std::sync::Condvar::new());
-
-					let capture_thread = std::thread::Builder::new().name("video-capture".to_string()).spawn({
-						let intermediate_buffer = intermediate_buffer.clone();
-						let notifier = notifier.clone();
-						let context = context.clone();
-						let stop_signal = stop_signal.clone();
-						move || {
-							cuda_device.bind_to_thread()
-								.map_err(|e| tracing::error!("Failed to bind CUDA device to thread: {e}"))?;
-							capturer.run(
-								context.fps,
-								capture_buffer,
-								intermediate_buffer,
-								notifier,
-								stop_signal,
-							)
+					let Some(current_pipeline) = &pipeline else {
+						tracing::debug!("Video stream has not started yet, capturing {capture_area:?} when it starts.");
+						continue;
+					};
+
+					tracing::info!("Changing captured area to {capture_area:?}.");
+					current_pipeline.stop();
+					pipeline = Some(Pipeline::start(
+						&config,
+						&mut context,
+						&stats,
+						&packet_tx,
+						&idr_frame_request_tx,
+						&bitrate_tx,
+						&encode_deadline_tx,
+						capture_area.as_ref(),
+						scale,
+						&clock,
+						&video_tap,
+						&stop_signal,
+					)?);
+					let _ = idr_frame_request_tx.send(());
+				},
+				VideoStreamCommand::UpdateKeys(keys) => {
+					keys_tx.send_modify(|current| {
+						if current.is_some() {
+							*current = Some(keys);
 						}
 					});
-					if let Err(e) = capture_thread {
-						tracing::error!("Failed to start video capture thread: {e}");
+				},
+				VideoStreamCommand::NotifyInput => {
+					if let Some(pipeline) = &pipeline {
+						pipeline.notify_input();
+					}
+				},
+				VideoStreamCommand::Pause => {
+					let Some(current_pipeline) = pipeline.take() else {
+						continue;
+					};
+
+					tracing::info!("Pausing video stream.");
+					current_pipeline.stop();
+					watchdog = None;
+					paused = true;
+				},
+				VideoStreamCommand::Resume => {
+					if !paused {
 						continue;
 					}
 
-					let encode_thread = std::thread::Builder::new().name("video-encode".to_string()).spawn({
-						let packet_tx = packet_tx.clone();
-						let notifier = notifier.clone();
-						let idr_frame_request_rx = idr_frame_request_tx.subscribe();
-						let context = context.clone();
-						let stop_signal = stop_signal.clone();
-						move || {
-							encoder.run(
-								packet_tx,
-								idr_frame_request_rx,
-								context.packet_size,
-								context.minimum_fec_packets,
-								config.stream.video.fec_percentage,
-								encoder_buffer,
-								intermediate_buffer,
-								notifier,
-								stop_signal,
-							)
-						}
-					});
-					if let Err(e) = encode_thread {
-						tracing::error!("Failed to start video encoding thread: {e}");
+					tracing::info!("Resuming video stream.");
+					paused = false;
+					pipeline = Some(Pipeline::start(
+						&config,
+						&mut context,
+						&stats,
+						&packet_tx,
+						&idr_frame_request_tx,
+						&bitrate_tx,
+						&encode_deadline_tx,
+						capture_area.as_ref(),
+						scale,
+						&clock,
+						&video_tap,
+						&stop_signal,
+					)?);
+					let _ = idr_frame_request_tx.send(());
+
+					let period = std::time::Duration::from_secs(1) * WATCHDOG_STALLED_FRAMES / context.fps.max(1);
+					watchdog = Some(tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+				},
+				VideoStreamCommand::Start => {
+					// A client that repeats StartB is waiting for a frame it can decode, so it gets a fresh IDR frame.
+					if pipeline.is_some() {
+						tracing::debug!("Received start while already streaming, next frame will be an IDR frame.");
+						let _ = idr_frame_request_tx.send(());
+						continue;
+					}
+					if paused {
+						tracing::warn!("Can't start streaming while paused.");
 						continue;
 					}
 
-					started_streaming = true;
+					pacing_controller = Some(PacingController::new(context.bitrate, context.fps));
+					resolution_controller = config.stream.video.dynamic_resolution.as_ref()
+						.map(|dynamic_resolution| ResolutionController::new(context.bitrate, dynamic_resolution.minimum_scale));
+					bitrate_tx.send_replace(context.bitrate);
+
+					pipeline = match Pipeline::start(
+						&config,
+						&mut context,
+						&stats,
+						&packet_tx,
+						&idr_frame_request_tx,
+						&bitrate_tx,
+						&encode_deadline_tx,
+						capture_area.as_ref(),
+						scale,
+						&clock,
+						&video_tap,
+						&stop_signal,
+					) {
+						Ok(pipeline) => Some(pipeline),
+						Err(()) => {
+							// Without an encoder the client would wait for video until it times out, the session closes instead.
+							started_tx.send_replace(Some(Err(())));
+							continue;
+						},
+					};
+					started_tx.send_replace(Some(Ok(())));
+
+					// Encoders start with an IDR frame by themselves, this makes sure of it for those that don't.
+					let _ = idr_frame_request_tx.send(());
+
+					// The first frames of the stream show whether the client can keep up with the requested bitrate.
+					bandwidth_probe = config.stream.video.bandwidth_probe.as_ref().map(BandwidthProbe::new);
+
+					let period = std::time::Duration::from_secs(1) * WATCHDOG_STALLED_FRAMES / context.fps.max(1);
+					watchdog = Some(tokio::time::interval_at(tokio::time::Instant::now() + period, period));
 				},
 			}
 		}
 
+		if let Some(pipeline) = &pipeline {
+			pipeline.stop();
+		}
+
 		tracing::debug!("Command channel closed.");
 		Ok(())
 	}
 }
 
-fn create_frame(width: u32, height: u32, pixel_format: Pixel, context: &mut HwFrameContext) -> Result<Frame, ()> {
-	unsafe {
-		let mut frame = Frame::empty();
-		(*frame.as_mut_ptr()).format = pixel_format as i32;
-		(*frame.as_mut_ptr()).width = width as i32;
-		(*frame.as_mut_ptr()).height = height as i32;
-		(*frame.as_mut_ptr()).hw_frames_ctx = context.as_raw_mut();
-
-		check_ret(ffmpeg::sys::av_hwframe_get_buffer(context.as_raw_mut(), frame.as_mut_ptr(), 0))
-			.map_err(|e| tracing::error!("Failed to create CUDA frame: {e}"))?;
-		check_ret(ffmpeg::sys::av_hwframe_get_buffer(context.as_raw_mut(), frame.as_mut_ptr(), 0))
-			.map_err(|e| println!("Failed to allocate hardware frame: {e}"))?;
-		(*frame.as_mut_ptr()).linesize[0] = (*frame.as_ptr()).width * 4;
-
-		Ok(frame)
+/// Without NvFBC and NVENC there is nothing to capture and encode frames with, so a pipeline never starts.
+#[cfg(not(feature = "nvidia"))]
+enum Pipeline {}
+
+#[cfg(not(feature = "nvidia"))]
+impl Pipeline {
+	#[allow(clippy::too_many_arguments)]
+	fn start(
+		_config: &Config,
+		_context: &mut VideoStreamContext,
+		_stats: &SessionStats,
+		_packet_tx: &Sender<Vec<u8>>,
+		_idr_frame_request_tx: &tokio::sync::broadcast::Sender<()>,
+		_bitrate_tx: &watch::Sender<usize>,
+		_encode_deadline_tx: &watch::Sender<Option<std::time::Duration>>,
+		_capture_area: Option<&CaptureArea>,
+		_scale: u32,
+		_clock: &SessionClock,
+		_video_tap: &VideoTap,
+		_session_stop_signal: &ShutdownManager<()>,
+	) -> Result<Self, ()> {
+		tracing::error!("Streaming video requires building with the 'nvidia' feature.");
+		Err(())
+	}
+
+	fn is_stalled(&mut self) -> bool {
+		match *self {}
+	}
+
+	fn notify_input(&self) {
+		match *self {}
+	}
+
+	fn stop(&self) {
+		match *self {}
 	}
 }
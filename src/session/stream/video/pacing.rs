@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+/// Length of the windows in which the losses that the client reports are counted.
+pub const WINDOW: Duration = Duration::from_secs(1);
+
+/// Number of consecutive windows with losses before the bitrate is lowered.
+const LOSSY_WINDOWS_THRESHOLD: u32 = 3;
+
+/// Number of consecutive windows without losses before the bitrate is raised again.
+const CLEAN_WINDOWS_THRESHOLD: u32 = 10;
+
+/// Percentage by which the bitrate is lowered when the client is losing frames.
+const DECREASE_PERCENTAGE: usize = 10;
+
+/// Percentage by which the bitrate is raised when the client is receiving all frames.
+const INCREASE_PERCENTAGE: usize = 5;
+
+/// The bitrate is never lowered below this percentage of the requested bitrate.
+const MINIMUM_BITRATE_PERCENTAGE: usize = 20;
+
+/// Number of frame intervals that captured frames may wait for the encoder, once the deadline is first enforced.
+const MAXIMUM_DEADLINE_FRAMES: u32 = 3;
+
+/// Frames arrive late when their jitter exceeds this percentage of the frame interval.
+const LATE_JITTER_PERCENTAGE: u32 = 50;
+
+/// Frames arrive late when they are queued on the client for more than this number of frame intervals.
+const LATE_QUEUE_DELAY_FRAMES: u32 = 1;
+
+/// Frame statistics as periodically reported by the client.
+#[derive(Debug, PartialEq)]
+pub struct FrameStats {
+	/// Average deviation of frame arrival times from the expected frame interval, in microseconds.
+	pub jitter: u32,
+
+	/// Average time frames spent queued on the client before being decoded, in microseconds.
+	pub queue_delay: u32,
+}
+
+/// Adjusts the encoder bitrate and encode deadline based on the losses and frame statistics that the client reports.
+///
+/// Moonlight reports lost frames through IDR frame requests and invalidated reference frames (and, for older clients,
+/// loss statistics), and how timely frames arrive through frame statistics. While the client keeps losing frames or
+/// receiving them late, the bitrate is lowered and captured frames get less time to wait for the encoder, so a late
+/// frame is skipped for a newer one instead of adding to the delay.
+pub struct PacingController {
+	/// The bitrate requested by the client, this is never exceeded.
+	max_bitrate: usize,
+
+	/// The bitrate the encoder should currently use.
+	target_bitrate: usize,
+
+	/// Expected time between two frames.
+	frame_interval: Duration,
+
+	/// Time captured frames may wait for the encoder, if limited.
+	encode_deadline: Option<Duration>,
+
+	/// Number of losses reported in the current window.
+	losses: u32,
+
+	/// Whether the client reported frames arriving late in the current window.
+	late: bool,
+
+	/// Number of consecutive windows with losses or late frames.
+	lossy_windows: u32,
+
+	/// Number of consecutive windows without losses or late frames.
+	clean_windows: u32,
+}
+
+impl PacingController {
+	pub fn new(max_bitrate: usize, fps: u32) -> Self {
+		Self {
+			max_bitrate,
+			target_bitrate: max_bitrate,
+			frame_interval: Duration::from_secs(1) / fps.max(1),
+			encode_deadline: None,
+			losses: 0,
+			late: false,
+			lossy_windows: 0,
+			clean_windows: 0,
+		}
+	}
+
+	pub fn target_bitrate(&self) -> usize {
+		self.target_bitrate
+	}
+
+	pub fn encode_deadline(&self) -> Option<Duration> {
+		self.encode_deadline
+	}
+
+	/// Record losses that the client reported in the current window.
+	pub fn record_losses(&mut self, losses: u32) {
+		self.losses = self.losses.saturating_add(losses);
+	}
+
+	/// Record frame statistics that the client reported in the current window.
+	pub fn record_frame_stats(&mut self, stats: &FrameStats) {
+		let frame_interval = self.frame_interval.as_micros() as u32;
+		let late = stats.jitter > frame_interval * LATE_JITTER_PERCENTAGE / 100
+			|| stats.queue_delay > frame_interval * LATE_QUEUE_DELAY_FRAMES;
+		if late {
+			tracing::trace!("Client is receiving frames late: {stats:?}");
+		}
+		self.late |= late;
+	}
+
+	/// End the current window, returns whether the target bitrate or encode deadline changed.
+	pub fn update(&mut self) -> bool {
+		let losses = std::mem::take(&mut self.losses);
+		let late = std::mem::take(&mut self.late);
+		if losses > 0 || late {
+			self.lossy_windows += 1;
+			self.clean_windows = 0;
+		} else {
+			self.clean_windows += 1;
+			self.lossy_windows = 0;
+		}
+
+		let previous = (self.target_bitrate, self.encode_deadline);
+		if self.lossy_windows >= LOSSY_WINDOWS_THRESHOLD {
+			self.lossy_windows = 0;
+			let minimum_bitrate = self.max_bitrate * MINIMUM_BITRATE_PERCENTAGE / 100;
+			self.target_bitrate = (self.target_bitrate * (100 - DECREASE_PERCENTAGE) / 100).max(minimum_bitrate);
+			self.encode_deadline = Some(match self.encode_deadline {
+				Some(deadline) => deadline.saturating_sub(self.frame_interval).max(self.frame_interval),
+				None => self.frame_interval * MAXIMUM_DEADLINE_FRAMES,
+			});
+		} else if self.clean_windows >= CLEAN_WINDOWS_THRESHOLD {
+			self.clean_windows = 0;
+			self.target_bitrate = (self.target_bitrate * (100 + INCREASE_PERCENTAGE) / 100).min(self.max_bitrate);
+			self.encode_deadline = self.encode_deadline
+				.map(|deadline| deadline + self.frame_interval)
+				.filter(|deadline| *deadline <= self.frame_interval * MAXIMUM_DEADLINE_FRAMES);
+		}
+
+		if (self.target_bitrate, self.encode_deadline) == previous {
+			return false;
+		}
+
+		tracing::debug!(
+			"Client reported {losses} losses{}, changing bitrate from {} kbps to {} kbps and encode deadline from {:?} to {:?}.",
+			if late { " and late frames" } else { "" },
+			previous.0 / 1000, self.target_bitrate / 1000, previous.1, self.encode_deadline,
+		);
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BITRATE: usize = 10_000_000;
+
+	/// Frame statistics of a client that receives frames at 60 FPS on time.
+	const ON_TIME: FrameStats = FrameStats { jitter: 2_000, queue_delay: 5_000 };
+
+	#[test]
+	fn late_frames_lower_bitrate() {
+		let mut controller = PacingController::new(BITRATE, 60);
+		for _ in 0..LOSSY_WINDOWS_THRESHOLD - 1 {
+			controller.record_frame_stats(&FrameStats { jitter: 2_000, queue_delay: 40_000 });
+			assert!(!controller.update());
+		}
+
+		controller.record_frame_stats(&FrameStats { jitter: 12_000, queue_delay: 5_000 });
+		assert!(controller.update());
+		assert_eq!(controller.target_bitrate(), BITRATE * (100 - DECREASE_PERCENTAGE) / 100);
+		assert_eq!(controller.encode_deadline(), Some(Duration::from_secs(1) / 60 * MAXIMUM_DEADLINE_FRAMES));
+	}
+
+	#[test]
+	fn timely_frames_keep_bitrate() {
+		let mut controller = PacingController::new(BITRATE, 60);
+		for _ in 0..LOSSY_WINDOWS_THRESHOLD {
+			controller.record_frame_stats(&ON_TIME);
+			assert!(!controller.update());
+		}
+		assert_eq!(controller.target_bitrate(), BITRATE);
+	}
+
+	#[test]
+	fn bitrate_recovers_once_frames_arrive_on_time() {
+		let mut controller = PacingController::new(BITRATE, 60);
+		for _ in 0..LOSSY_WINDOWS_THRESHOLD {
+			controller.record_frame_stats(&FrameStats { jitter: 2_000, queue_delay: 40_000 });
+			controller.update();
+		}
+		assert!(controller.target_bitrate() < BITRATE);
+
+		// Every run of clean windows raises the bitrate a little, until it is back at the requested bitrate.
+		for _ in 0..CLEAN_WINDOWS_THRESHOLD * 3 {
+			controller.record_frame_stats(&ON_TIME);
+			controller.update();
+		}
+		assert_eq!(controller.target_bitrate(), BITRATE);
+		assert_eq!(controller.encode_deadline(), None);
+	}
+}
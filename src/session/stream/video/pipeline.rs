@@ -0,0 +1,279 @@
+//! The capture and encode threads of a video stream, which capture frames with NvFBC and encode them with NVENC.
+
+use std::sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc};
+
+use async_shutdown::ShutdownManager;
+use ffmpeg::{format::Pixel, Frame};
+use tokio::sync::{mpsc::Sender, watch};
+
+use crate::{config::{CaptureArea, CaptureRegion, Config}, ffmpeg::{check_ret, hwframe::HwFrameContext}, session::{stats::SessionStats, SessionClock}};
+
+use super::{capabilities, capture::FrameCapturer, encoder::Encoder, fence::{FencedFrame, FrameFence}, queue::FrameQueue, scaling::scaled_size, VideoStreamContext, VideoTap};
+
+/// Threads that capture and encode frames.
+pub struct Pipeline {
+	capture_thread: std::thread::JoinHandle<Result<(), ()>>,
+	encode_thread: std::thread::JoinHandle<Result<(), ()>>,
+
+	/// Stops the threads of this pipeline.
+	stop_signal: ShutdownManager<()>,
+
+	/// Number of frames captured so far.
+	captured_frames: Arc<AtomicU32>,
+
+	/// Number of frames encoded so far.
+	encoded_frames: Arc<AtomicU32>,
+
+	/// Number of captured and encoded frames at the previous watchdog check.
+	last_progress: (u32, u32),
+
+	/// Set when the client sent input, which ends an idle period of the capturer.
+	input_received: Arc<AtomicBool>,
+}
+
+impl Pipeline {
+	#[allow(clippy::too_many_arguments)]
+	pub fn start(
+		config: &Config,
+		context: &mut VideoStreamContext,
+		stats: &SessionStats,
+		packet_tx: &Sender<Vec<u8>>,
+		idr_frame_request_tx: &tokio::sync::broadcast::Sender<()>,
+		bitrate_tx: &watch::Sender<usize>,
+		encode_deadline_tx: &watch::Sender<Option<std::time::Duration>>,
+		capture_area: Option<&CaptureArea>,
+		scale: u32,
+		clock: &SessionClock,
+		video_tap: &VideoTap,
+		session_stop_signal: &ShutdownManager<()>,
+	) -> Result<Self, ()> {
+		// TODO: Make the GPU index configurable.
+		let cuda_device = cudarc::driver::CudaDevice::new(0)
+			.map_err(|e| tracing::error!("Failed to initialize CUDA: {e}"))?;
+
+		let capturer = FrameCapturer::new()?;
+		let status = capturer.status()?;
+		let screen = CaptureRegion { x: 0, y: 0, width: status.screen_size.w, height: status.screen_size.h };
+		let region = capture_area.and_then(|area| capture_region(area, &screen));
+		let size = region.unwrap_or(screen);
+		if size.width != context.width || size.height != context.height {
+			// TODO: Resize the CUDA buffer to the requested size?
+			tracing::warn!(
+				"Client asked for resolution {}x{}, but we are generating a resolution of {}x{}.",
+				context.width, context.height, size.width, size.height
+			);
+			context.width = size.width;
+			context.height = size.height;
+		}
+
+		let codec_names = if context.video_format == capabilities::VIDEO_FORMAT_H264 {
+			config.stream.video.codecs_h264()
+		} else {
+			config.stream.video.codecs_hevc()
+		};
+		// Frames are always captured at the stream resolution, the encoder scales them down when the bitrate is starved.
+		let encode_size = (scale < 100).then(|| scaled_size(context.width, context.height, scale));
+		let (mut encoder, codec_name) = Encoder::new_with_fallback(
+			&cuda_device,
+			&codec_names,
+			context.width, context.height,
+			encode_size,
+			context.fps,
+			*bitrate_tx.borrow(),
+			context.slices_per_frame,
+			context.color_space,
+			context.color_range,
+			&context.h264_decoder,
+			&config.stream.video.encoder_options,
+		)?;
+		stats.set_video_encoder(codec_name);
+
+		// The fences of the frames are created in the CUDA context of the device.
+		cuda_device.bind_to_thread()
+			.map_err(|e| tracing::error!("Failed to bind CUDA device to thread: {e}"))?;
+		let capture_buffer = create_fenced_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
+		let encoder_buffer = create_fenced_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
+		let queue_config = &config.stream.video.encode_queue;
+		if queue_config.depth == 0 {
+			tracing::warn!("The encode queue needs room for at least one frame, using a depth of 1.");
+		}
+		let queued_buffers = (0..queue_config.depth.max(1))
+			.map(|_| create_fenced_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context))
+			.collect::<Result<Vec<_>, ()>>()?;
+		let frame_queue = Arc::new(FrameQueue::new(
+			queued_buffers,
+			queue_config.drop_policy,
+			encode_deadline_tx.subscribe(),
+			stats.clone(),
+		));
+
+		// The pipeline stops when the session stops, but it can also be stopped separately to restart it.
+		let stop_signal = ShutdownManager::new();
+		tokio::spawn({
+			let stop_signal = stop_signal.clone();
+			let session_stop_signal = session_stop_signal.clone();
+			async move {
+				tokio::select! {
+					_ = session_stop_signal.wait_shutdown_triggered() => {
+						let _ = stop_signal.trigger_shutdown(());
+					},
+					_ = stop_signal.wait_shutdown_triggered() => {},
+				}
+			}
+		});
+
+		let captured_frames = Arc::new(AtomicU32::new(0));
+		let encoded_frames = Arc::new(AtomicU32::new(0));
+		let input_received = Arc::new(AtomicBool::new(false));
+
+		let capture_thread = std::thread::Builder::new().name("video-capture".to_string()).spawn({
+			let frame_queue = frame_queue.clone();
+			let fps = context.fps;
+			let captured_frames = captured_frames.clone();
+			let idle = config.stream.video.idle.clone();
+			let input_received = input_received.clone();
+			let stop_signal = stop_signal.clone();
+			let span = tracing::Span::current();
+			move || span.in_scope(|| {
+				cuda_device.bind_to_thread()
+					.map_err(|e| tracing::error!("Failed to bind CUDA device to thread: {e}"))?;
+				capturer.run(
+					fps,
+					screen.width,
+					region,
+					capture_buffer,
+					frame_queue,
+					captured_frames,
+					idle,
+					input_received,
+					stop_signal,
+				)
+			})
+		})
+			.map_err(|e| tracing::error!("Failed to start video capture thread: {e}"))?;
+
+		let encode_thread = std::thread::Builder::new().name("video-encode".to_string()).spawn({
+			let packet_tx = packet_tx.clone();
+			let idr_frame_request_rx = idr_frame_request_tx.subscribe();
+			let bitrate_rx = bitrate_tx.subscribe();
+			let packet_size = context.packet_size;
+			let minimum_fec_packets = context.minimum_fec_packets;
+			let fec_percentage = config.stream.video.fec_percentage;
+			let encoded_frames = encoded_frames.clone();
+			let stats = stats.clone();
+			let clock = *clock;
+			let video_tap = video_tap.clone();
+			let stop_signal = stop_signal.clone();
+			let span = tracing::Span::current();
+			move || span.in_scope(|| {
+				encoder.run(
+					packet_tx,
+					idr_frame_request_rx,
+					bitrate_rx,
+					packet_size,
+					minimum_fec_packets,
+					fec_percentage,
+					encoder_buffer,
+					frame_queue,
+					encoded_frames,
+					stats,
+					clock,
+					video_tap,
+					stop_signal,
+				)
+			})
+		});
+		let encode_thread = match encode_thread {
+			Ok(encode_thread) => encode_thread,
+			Err(e) => {
+				tracing::error!("Failed to start video encoding thread: {e}");
+				let _ = stop_signal.trigger_shutdown(());
+				return Err(());
+			},
+		};
+
+		Ok(Self {
+			capture_thread,
+			encode_thread,
+			stop_signal,
+			captured_frames,
+			encoded_frames,
+			last_progress: (0, 0),
+			input_received,
+		})
+	}
+
+	/// Check whether the pipeline stopped making progress since the previous check.
+	///
+	/// A pipeline is stalled when one of its threads exited, or when frames were captured but none were encoded.
+	/// The capturer only produces frames when the screen changes, so a lack of captured frames is not a stall.
+	/// Frames that are skipped while idle are not counted as captured.
+	pub fn is_stalled(&mut self) -> bool {
+		if self.capture_thread.is_finished() || self.encode_thread.is_finished() {
+			return true;
+		}
+
+		let progress = (self.captured_frames.load(Ordering::Relaxed), self.encoded_frames.load(Ordering::Relaxed));
+		let stalled = progress.0 != self.last_progress.0 && progress.1 == self.last_progress.1;
+		self.last_progress = progress;
+
+		stalled
+	}
+
+	/// End an idle period of the capturer, because the client sent input.
+	pub fn notify_input(&self) {
+		self.input_received.store(true, Ordering::Relaxed);
+	}
+
+	pub fn stop(&self) {
+		let _ = self.stop_signal.trigger_shutdown(());
+	}
+}
+
+/// Resolve the area of the desktop to capture, returns `None` if the entire desktop should be captured.
+fn capture_region(area: &CaptureArea, screen: &CaptureRegion) -> Option<CaptureRegion> {
+	let region = match area {
+		CaptureArea::Output(output) => crate::host::monitors::output_region(output).ok()?,
+		CaptureArea::Region(region) => *region,
+	};
+
+	if region.width == 0
+		|| region.height == 0
+		|| region.x.checked_add(region.width).map_or(true, |right| right > screen.width)
+		|| region.y.checked_add(region.height).map_or(true, |bottom| bottom > screen.height)
+	{
+		tracing::warn!(
+			"Capture area {region:?} does not fit in the desktop of {}x{}, capturing the entire desktop instead.",
+			screen.width, screen.height,
+		);
+		return None;
+	}
+
+	tracing::info!("Capturing {}x{} region at offset {}x{} of the desktop.", region.width, region.height, region.x, region.y);
+	Some(region)
+}
+
+fn create_fenced_frame(width: u32, height: u32, pixel_format: Pixel, context: &mut HwFrameContext) -> Result<FencedFrame, ()> {
+	Ok(FencedFrame {
+		frame: create_frame(width, height, pixel_format, context)?,
+		fence: FrameFence::new()?,
+	})
+}
+
+pub fn create_frame(width: u32, height: u32, pixel_format: Pixel, context: &mut HwFrameContext) -> Result<Frame, ()> {
+	unsafe {
+		let mut frame = Frame::empty();
+		(*frame.as_mut_ptr()).format = pixel_format as i32;
+		(*frame.as_mut_ptr()).width = width as i32;
+		(*frame.as_mut_ptr()).height = height as i32;
+		(*frame.as_mut_ptr()).hw_frames_ctx = context.as_raw_mut();
+
+		check_ret(ffmpeg::sys::av_hwframe_get_buffer(context.as_raw_mut(), frame.as_mut_ptr(), 0))
+			.map_err(|e| tracing::error!("Failed to create CUDA frame: {e}"))?;
+		check_ret(ffmpeg::sys::av_hwframe_get_buffer(context.as_raw_mut(), frame.as_mut_ptr(), 0))
+			.map_err(|e| println!("Failed to allocate hardware frame: {e}"))?;
+		(*frame.as_mut_ptr()).linesize[0] = (*frame.as_ptr()).width * 4;
+
+		Ok(frame)
+	}
+}
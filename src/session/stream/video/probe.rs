@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::BandwidthProbeConfig;
+
+/// Watches the losses that the client reports right after the stream started.
+///
+/// This is not a burst of padding before the stream: Moonlight doesn't report on packets it can't decode, and during
+/// RTSP setup the host doesn't know the video port of the client yet, so padding packets tell nothing about what arrived.
+/// Instead, the first frames of the stream are the probe: if the client reports losses on them (through loss
+/// statistics or invalidated reference frames) the requested bitrate is more than the network can sustain.
+///
+/// IDR frame requests don't count, clients send one when their decoder starts.
+pub struct BandwidthProbe {
+	/// When the probe ends.
+	deadline: Instant,
+
+	/// Percentage of the requested bitrate to continue with when the client reported losses.
+	usable_percentage: u8,
+
+	/// Number of losses the client reported so far.
+	losses: u32,
+}
+
+impl BandwidthProbe {
+	pub fn new(config: &BandwidthProbeConfig) -> Self {
+		tracing::debug!("Probing bandwidth for {}ms.", config.duration);
+		Self {
+			deadline: Instant::now() + Duration::from_millis(config.duration),
+			usable_percentage: config.usable_percentage.min(100),
+			losses: 0,
+		}
+	}
+
+	pub fn deadline(&self) -> Instant {
+		self.deadline
+	}
+
+	/// Record losses that the client reported.
+	pub fn record_losses(&mut self, losses: u32) {
+		self.losses = self.losses.saturating_add(losses);
+	}
+
+	/// End the probe, returning the bitrate to continue with if the client couldn't keep up with `bitrate`.
+	pub fn finish(self, bitrate: usize) -> Option<usize> {
+		if self.losses == 0 {
+			tracing::info!("Client reported no losses during the bandwidth probe, keeping {} kbps.", bitrate / 1000);
+			return None;
+		}
+
+		let usable_bitrate = bitrate / 100 * self.usable_percentage as usize;
+		tracing::info!(
+			"Client reported {} losses during the bandwidth probe, limiting bitrate from {} kbps to {} kbps.",
+			self.losses, bitrate / 1000, usable_bitrate / 1000,
+		);
+
+		Some(usable_bitrate)
+	}
+}
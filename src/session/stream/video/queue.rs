@@ -0,0 +1,117 @@
+//! Bounded queue of captured frames waiting for the encoder.
+//!
+//! The capturer and encoder exchange frames instead of copying them: the capturer hands in the frame it just captured
+//! and gets back a frame to capture the next one into, the encoder hands in the frame it just encoded and gets back the
+//! next frame to encode. When the encoder can't keep up, frames are dropped according to the drop policy, so a slow
+//! encoder lowers the framerate instead of adding latency.
+//!
+//! With an encode deadline, queued frames that waited longer than the deadline are skipped when a newer frame is
+//! queued, which bounds the latency a deep queue adds while the client is losing frames.
+
+use std::{collections::VecDeque, sync::{Condvar, Mutex}, time::{Duration, Instant}};
+
+use tokio::sync::watch;
+
+use crate::{config::FrameDropPolicy, session::stats::SessionStats};
+
+use super::fence::FencedFrame;
+
+pub struct FrameQueue {
+	state: Mutex<FrameQueueState>,
+	notifier: Condvar,
+	drop_policy: FrameDropPolicy,
+	encode_deadline: watch::Receiver<Option<Duration>>,
+	stats: SessionStats,
+}
+
+struct FrameQueueState {
+	/// Captured frames with the time they were queued, oldest first.
+	queued: VecDeque<(Instant, FencedFrame)>,
+
+	/// Frames that can be captured into.
+	free: Vec<FencedFrame>,
+}
+
+impl FrameQueue {
+	/// Create a queue that holds the given frames, its depth is the number of frames.
+	///
+	/// The capturer and encoder each hold one more frame of their own.
+	pub fn new(
+		frames: Vec<FencedFrame>,
+		drop_policy: FrameDropPolicy,
+		encode_deadline: watch::Receiver<Option<Duration>>,
+		stats: SessionStats,
+	) -> Self {
+		Self {
+			state: Mutex::new(FrameQueueState { queued: VecDeque::with_capacity(frames.len()), free: frames }),
+			notifier: Condvar::new(),
+			drop_policy,
+			encode_deadline,
+			stats,
+		}
+	}
+
+	/// Queue a captured frame, `frame` is replaced with the frame to capture into next.
+	pub fn push(&self, frame: &mut FencedFrame) -> Result<(), ()> {
+		let mut state = self.state.lock()
+			.map_err(|e| tracing::error!("Failed to lock frame queue: {e}"))?;
+
+		let next = match state.free.pop() {
+			Some(next) => next,
+			None => {
+				self.stats.record_dropped_frame();
+				match self.drop_policy {
+					// The oldest frame is overwritten by the next capture.
+					FrameDropPolicy::DropOldest => {
+						tracing::trace!("Encoder is behind, dropping the oldest queued frame.");
+						let Some((_, oldest)) = state.queued.pop_front() else {
+							// A queue without frames has nothing to drop, the captured frame is overwritten instead.
+							return Ok(());
+						};
+						oldest
+					},
+					// The captured frame is kept by the capturer, so the next capture overwrites it.
+					FrameDropPolicy::DropNewest => {
+						tracing::trace!("Encoder is behind, dropping the captured frame.");
+						return Ok(());
+					},
+				}
+			},
+		};
+
+		state.queued.push_back((Instant::now(), std::mem::replace(frame, next)));
+		drop(state);
+		self.notifier.notify_one();
+
+		Ok(())
+	}
+
+	/// Wait up to `timeout` for a captured frame, which is swapped with `frame`.
+	///
+	/// Returns whether a frame was received, the previous frame is reused for capturing.
+	pub fn pop(&self, frame: &mut FencedFrame, timeout: Duration) -> Result<bool, ()> {
+		let state = self.state.lock()
+			.map_err(|e| tracing::error!("Failed to lock frame queue: {e}"))?;
+		let (mut state, _) = self.notifier.wait_timeout_while(state, timeout, |state| state.queued.is_empty())
+			.map_err(|e| tracing::error!("Failed to wait for new frame: {e}"))?;
+
+		// The newest frame is always encoded, a frame is only late if there is a newer one to take its place.
+		if let Some(deadline) = *self.encode_deadline.borrow() {
+			while state.queued.len() > 1 && state.queued.front().is_some_and(|(queued_at, _)| queued_at.elapsed() > deadline) {
+				tracing::trace!("Queued frame missed the encode deadline of {deadline:?}, dropping it.");
+				if let Some((_, late)) = state.queued.pop_front() {
+					state.free.push(late);
+					self.stats.record_dropped_frame();
+				}
+			}
+		}
+
+		let Some((_, next)) = state.queued.pop_front() else {
+			return Ok(false);
+		};
+		let previous = std::mem::replace(frame, next);
+		state.free.push(previous);
+
+		Ok(true)
+	}
+}
@@ -0,0 +1,92 @@
+//! Lowers the encode resolution while the bitrate is starved, so the stream stays fluid at a lower quality.
+
+/// Number of consecutive starved reports before the resolution is lowered.
+const STARVED_REPORTS_THRESHOLD: u32 = 5;
+
+/// Number of consecutive healthy reports before the resolution is raised again.
+const HEALTHY_REPORTS_THRESHOLD: u32 = 30;
+
+/// The bitrate is starved when the pacing controller lowered it below this percentage of the requested bitrate.
+const STARVED_BITRATE_PERCENTAGE: usize = 50;
+
+/// The bitrate is healthy when it is above this percentage of the requested bitrate.
+const HEALTHY_BITRATE_PERCENTAGE: usize = 90;
+
+/// Percentage by which the scale changes in each step.
+const SCALE_STEP: u32 = 25;
+
+/// Decides the scale of the encode resolution, as a percentage of the captured resolution.
+pub struct ResolutionController {
+	/// The bitrate requested by the client.
+	max_bitrate: usize,
+
+	/// The scale is never lowered below this percentage.
+	minimum_scale: u32,
+
+	/// The scale the encoder should currently use.
+	scale: u32,
+
+	/// Number of consecutive reports in which the bitrate was starved.
+	starved_reports: u32,
+
+	/// Number of consecutive reports in which the bitrate was healthy.
+	healthy_reports: u32,
+}
+
+impl ResolutionController {
+	pub fn new(max_bitrate: usize, minimum_scale: u32) -> Self {
+		Self {
+			max_bitrate,
+			minimum_scale: minimum_scale.clamp(SCALE_STEP, 100),
+			scale: 100,
+			starved_reports: 0,
+			healthy_reports: 0,
+		}
+	}
+
+	pub fn scale(&self) -> u32 {
+		self.scale
+	}
+
+	pub fn set_max_bitrate(&mut self, max_bitrate: usize) {
+		self.max_bitrate = max_bitrate;
+	}
+
+	/// Process the bitrate the pacing controller settled on, returns the new scale if it changed.
+	pub fn update(&mut self, target_bitrate: usize) -> Option<u32> {
+		if target_bitrate < self.max_bitrate * STARVED_BITRATE_PERCENTAGE / 100 {
+			self.starved_reports += 1;
+			self.healthy_reports = 0;
+		} else if target_bitrate > self.max_bitrate * HEALTHY_BITRATE_PERCENTAGE / 100 {
+			self.healthy_reports += 1;
+			self.starved_reports = 0;
+		} else {
+			self.starved_reports = 0;
+			self.healthy_reports = 0;
+		}
+
+		let previous_scale = self.scale;
+		if self.starved_reports >= STARVED_REPORTS_THRESHOLD {
+			self.starved_reports = 0;
+			self.scale = self.scale.saturating_sub(SCALE_STEP).max(self.minimum_scale);
+		} else if self.healthy_reports >= HEALTHY_REPORTS_THRESHOLD {
+			self.healthy_reports = 0;
+			self.scale = (self.scale + SCALE_STEP).min(100);
+		}
+
+		if self.scale != previous_scale {
+			tracing::debug!(
+				"Bitrate is {} kbps of the requested {} kbps, changing encode scale from {previous_scale}% to {}%.",
+				target_bitrate / 1000, self.max_bitrate / 1000, self.scale,
+			);
+			Some(self.scale)
+		} else {
+			None
+		}
+	}
+}
+
+/// Size of a frame scaled to `scale` percent, rounded down to even dimensions for chroma subsampling.
+pub fn scaled_size(width: u32, height: u32, scale: u32) -> (u32, u32) {
+	((width * scale / 100) & !1, (height * scale / 100) & !1)
+}
@@ -0,0 +1,45 @@
+//! Downscaled copies of captured frames, used as thumbnails of the stream.
+
+use ffmpeg::{format::Pixel, Frame};
+
+use crate::ffmpeg::check_ret;
+
+use super::tap::Snapshot;
+
+/// Download a captured frame from the GPU and scale it down to at most `max_width` pixels wide.
+pub fn capture_snapshot(source: &Frame, max_width: u32) -> Result<Snapshot, ()> {
+	let (width, height) = unsafe { ((*source.as_ptr()).width as u32, (*source.as_ptr()).height as u32) };
+	if width == 0 || height == 0 {
+		tracing::warn!("Can't take a snapshot of an empty frame.");
+		return Err(());
+	}
+
+	let mut download = ffmpeg::frame::Video::new(Pixel::ZRGB32, width, height);
+	unsafe {
+		check_ret(ffmpeg::sys::av_hwframe_transfer_data(download.as_mut_ptr(), source.as_ptr(), 0))
+			.map_err(|e| tracing::error!("Failed to download frame for snapshot: {e}"))?;
+	}
+
+	let snapshot_width = max_width.clamp(1, width);
+	let snapshot_height = ((height as u64 * snapshot_width as u64) / width as u64).max(1) as u32;
+	let mut scaled = ffmpeg::frame::Video::new(Pixel::RGB24, snapshot_width, snapshot_height);
+	ffmpeg::software::scaling::Context::get(
+		Pixel::ZRGB32, width, height,
+		Pixel::RGB24, snapshot_width, snapshot_height,
+		ffmpeg::software::scaling::Flags::BILINEAR,
+	)
+		.map_err(|e| tracing::error!("Failed to create snapshot scaler: {e}"))?
+		.run(&download, &mut scaled)
+		.map_err(|e| tracing::error!("Failed to scale snapshot: {e}"))?;
+
+	// Rows of the frame can be padded, the snapshot is not.
+	let row_size = snapshot_width as usize * 3;
+	let rgb = scaled.data(0)
+		.chunks(scaled.stride(0))
+		.take(snapshot_height as usize)
+		.flat_map(|row| &row[..row_size])
+		.copied()
+		.collect();
+
+	Ok(Snapshot { width: snapshot_width, height: snapshot_height, rgb })
+}
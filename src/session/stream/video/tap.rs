@@ -0,0 +1,111 @@
+//! Copies of the encoded video frames, for outputs next to the Moonlight stream such as browsers over WebRTC, and
+//! snapshots of the captured frames.
+//!
+//! Frames are only copied while something is subscribed or a snapshot was requested, so the tap costs nothing when it
+//! isn't used.
+
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::{broadcast, oneshot};
+
+use super::bitstream::Codec;
+
+/// Number of frames a subscriber can fall behind before it starts missing frames.
+const CAPACITY: usize = 30;
+
+/// A frame as it came out of the encoder, in Annex-B format with the parameter sets before every IDR frame.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+pub struct EncodedFrame {
+	pub data: Bytes,
+	pub key_frame: bool,
+	pub codec: Codec,
+}
+
+/// A captured frame, downscaled and downloaded from the GPU.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+	pub width: u32,
+	pub height: u32,
+
+	/// Packed 8-bit RGB pixels, row by row without padding.
+	pub rgb: Vec<u8>,
+}
+
+#[cfg_attr(not(feature = "nvidia"), allow(dead_code))]
+pub struct SnapshotRequest {
+	/// The snapshot is scaled down to this width if the frame is wider, the aspect ratio is kept.
+	pub max_width: u32,
+
+	pub response_tx: oneshot::Sender<Snapshot>,
+}
+
+/// Publishes the frames of every session's video stream.
+#[derive(Clone)]
+pub struct VideoTap {
+	frame_tx: broadcast::Sender<EncodedFrame>,
+	snapshot_requests: Arc<Mutex<Vec<SnapshotRequest>>>,
+
+	/// Set while there are snapshot requests, so the encoder doesn't have to lock the requests for every frame.
+	snapshot_requested: Arc<AtomicBool>,
+}
+
+impl VideoTap {
+	pub fn new() -> Self {
+		let (frame_tx, _) = broadcast::channel(CAPACITY);
+		Self { frame_tx, snapshot_requests: Default::default(), snapshot_requested: Default::default() }
+	}
+
+	/// Request a snapshot of the next captured frame, which is only answered while a stream is running.
+	pub fn request_snapshot(&self, max_width: u32) -> Result<oneshot::Receiver<Snapshot>, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		let mut requests = self.snapshot_requests.lock()
+			.map_err(|e| tracing::error!("Failed to lock snapshot requests: {e}"))?;
+
+		// Requests that timed out while no stream was running are of no use anymore.
+		requests.retain(|request| !request.response_tx.is_closed());
+		requests.push(SnapshotRequest { max_width, response_tx });
+		self.snapshot_requested.store(true, Ordering::Release);
+
+		Ok(response_rx)
+	}
+
+	/// Take the pending snapshot requests, called by the encoder for every captured frame.
+	#[cfg_attr(not(feature = "nvidia"), allow(dead_code))]
+	pub fn take_snapshot_requests(&self) -> Vec<SnapshotRequest> {
+		if !self.snapshot_requested.swap(false, Ordering::Acquire) {
+			return Vec::new();
+		}
+
+		match self.snapshot_requests.lock() {
+			Ok(mut requests) => std::mem::take(&mut *requests),
+			Err(e) => {
+				tracing::error!("Failed to lock snapshot requests: {e}");
+				Vec::new()
+			},
+		}
+	}
+
+	/// Receive the frames that are encoded from now on, subscribers should request an IDR frame to start decoding.
+	#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+	pub fn subscribe(&self) -> broadcast::Receiver<EncodedFrame> {
+		self.frame_tx.subscribe()
+	}
+
+	#[cfg_attr(not(feature = "nvidia"), allow(dead_code))]
+	pub fn publish(&self, data: &[u8], key_frame: bool, codec: Codec) {
+		if self.frame_tx.receiver_count() == 0 {
+			return;
+		}
+
+		// Fails only if every subscriber left in the meantime.
+		let _ = self.frame_tx.send(EncodedFrame { data: Bytes::copy_from_slice(data), key_frame, codec });
+	}
+}
+
+impl Default for VideoTap {
+	fn default() -> Self {
+		Self::new()
+	}
+}
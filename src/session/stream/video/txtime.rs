@@ -0,0 +1,170 @@
+//! Precise transmission of video packets with `SO_TXTIME`, so the kernel or NIC paces them instead of the send loop.
+//!
+//! Every packet carries the time at which it should leave the host, the ETF qdisc holds it until then.
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_imports))]
+use std::{io, mem, net::SocketAddr, os::fd::{AsRawFd, RawFd}, ptr};
+
+use tokio::{io::Interest, net::UdpSocket};
+
+use crate::config::TxTimeConfig;
+
+/// Packets aren't scheduled further ahead than this, so a burst doesn't build up latency in the qdisc.
+const MAX_SCHEDULE_AHEAD_NS: u64 = 50_000_000;
+
+/// Argument of the `SO_TXTIME` socket option, see `struct sock_txtime` in `linux/net_tstamp.h`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SockTxTime {
+	clockid: libc::clockid_t,
+	flags: u32,
+}
+
+/// Assigns transmission times to video packets, spreading them out at a multiple of the video bitrate.
+pub struct TxTimeScheduler {
+	/// Rate at which packets are transmitted, as a multiple of the video bitrate.
+	pacing_factor: f64,
+
+	/// Time in nanoseconds between sending a packet and its earliest transmission time.
+	lead_time: u64,
+
+	/// Earliest transmission time of the next packet, in nanoseconds of `CLOCK_TAI`.
+	next_txtime: u64,
+}
+
+impl TxTimeScheduler {
+	/// `SO_TXTIME` only exists on Linux.
+	#[cfg(not(target_os = "linux"))]
+	pub fn new(_socket: &UdpSocket, _config: &TxTimeConfig) -> Result<Self, ()> {
+		tracing::warn!("SO_TXTIME is only supported on Linux, sending video packets as soon as they are encoded.");
+		Err(())
+	}
+
+	/// Enable `SO_TXTIME` on the socket, fails if the kernel doesn't support it.
+	#[cfg(target_os = "linux")]
+	pub fn new(socket: &UdpSocket, config: &TxTimeConfig) -> Result<Self, ()> {
+		// The ETF qdisc only accepts timestamps of CLOCK_TAI.
+		let option = SockTxTime { clockid: libc::CLOCK_TAI, flags: 0 };
+		let result = unsafe {
+			libc::setsockopt(
+				socket.as_raw_fd(),
+				libc::SOL_SOCKET,
+				libc::SO_TXTIME,
+				&option as *const SockTxTime as *const libc::c_void,
+				mem::size_of::<SockTxTime>() as libc::socklen_t,
+			)
+		};
+		if result != 0 {
+			tracing::warn!("Failed to enable SO_TXTIME on the video socket: {}", io::Error::last_os_error());
+			return Err(());
+		}
+
+		tracing::debug!("Scheduling video packets with SO_TXTIME at {}x the video bitrate.", config.pacing_factor);
+		Ok(Self {
+			pacing_factor: config.pacing_factor.max(1.0),
+			lead_time: config.lead_time * 1000,
+			next_txtime: 0,
+		})
+	}
+
+	/// Transmission time of the next packet of `size` bytes, given the current video bitrate in bits per second.
+	pub fn schedule(&mut self, size: usize, bitrate: usize) -> u64 {
+		let earliest = tai_now() + self.lead_time;
+		let mut txtime = self.next_txtime.max(earliest);
+		if txtime > earliest + MAX_SCHEDULE_AHEAD_NS {
+			tracing::trace!("Video packets are scheduled too far ahead, resetting the transmission schedule.");
+			txtime = earliest;
+		}
+
+		let rate = bitrate.max(1) as f64 * self.pacing_factor;
+		self.next_txtime = txtime + (size as f64 * 8.0 / rate * 1e9) as u64;
+		txtime
+	}
+}
+
+/// Send a packet that leaves the host at `txtime`, in nanoseconds of `CLOCK_TAI`.
+pub async fn send_to(socket: &UdpSocket, data: &[u8], address: SocketAddr, txtime: u64) -> io::Result<usize> {
+	socket.async_io(Interest::WRITABLE, || send_with_txtime(socket.as_raw_fd(), data, address, txtime)).await
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_with_txtime(_fd: RawFd, _data: &[u8], _address: SocketAddr, _txtime: u64) -> io::Result<usize> {
+	Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn send_with_txtime(fd: RawFd, data: &[u8], address: SocketAddr, txtime: u64) -> io::Result<usize> {
+	let (mut storage, address_length) = socket_address(address);
+	let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+
+	// Aligned buffer for a single control message holding the transmission time.
+	let mut control = [0u64; 4];
+
+	unsafe {
+		let mut message: libc::msghdr = mem::zeroed();
+		message.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+		message.msg_namelen = address_length;
+		message.msg_iov = &mut iov;
+		message.msg_iovlen = 1;
+		message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+		message.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u64>() as u32) as _;
+
+		let header = libc::CMSG_FIRSTHDR(&message);
+		(*header).cmsg_level = libc::SOL_SOCKET;
+		(*header).cmsg_type = libc::SCM_TXTIME;
+		(*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<u64>() as u32) as _;
+		ptr::write_unaligned(libc::CMSG_DATA(header) as *mut u64, txtime);
+
+		let sent = libc::sendmsg(fd, &message, 0);
+		if sent < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(sent as usize)
+	}
+}
+
+/// Convert an address to the C representation that `sendmsg` expects.
+#[cfg(target_os = "linux")]
+fn socket_address(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+	let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+	let length = match address {
+		SocketAddr::V4(address) => {
+			let address = libc::sockaddr_in {
+				sin_family: libc::AF_INET as libc::sa_family_t,
+				sin_port: address.port().to_be(),
+				sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(address.ip().octets()) },
+				sin_zero: [0; 8],
+			};
+			unsafe { ptr::write(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in, address) };
+			mem::size_of::<libc::sockaddr_in>()
+		},
+		SocketAddr::V6(address) => {
+			let address = libc::sockaddr_in6 {
+				sin6_family: libc::AF_INET6 as libc::sa_family_t,
+				sin6_port: address.port().to_be(),
+				sin6_flowinfo: address.flowinfo(),
+				sin6_addr: libc::in6_addr { s6_addr: address.ip().octets() },
+				sin6_scope_id: address.scope_id(),
+			};
+			unsafe { ptr::write(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6, address) };
+			mem::size_of::<libc::sockaddr_in6>()
+		},
+	};
+
+	(storage, length as libc::socklen_t)
+}
+
+/// Current time of `CLOCK_TAI` in nanoseconds.
+#[cfg(not(target_os = "linux"))]
+fn tai_now() -> u64 {
+	0
+}
+
+/// Current time of `CLOCK_TAI` in nanoseconds.
+#[cfg(target_os = "linux")]
+fn tai_now() -> u64 {
+	let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { libc::clock_gettime(libc::CLOCK_TAI, &mut time) };
+	time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
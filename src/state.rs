@@ -1,16 +1,62 @@
-use std::path::{Path, PathBuf};
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
 use serde::{Serialize, Deserialize};
 use tokio::sync::{mpsc, oneshot};
 
+use crate::storage;
+
 enum StateCommand {
 	GetUuid(oneshot::Sender<String>),
 	Save(PathBuf, oneshot::Sender<Result<(), ()>>),
 	HasClient(String, oneshot::Sender<bool>),
-	AddClient(String),
+	AddClient(String, ClientInfo),
+	ClientSeen(String, oneshot::Sender<Option<ClientInfo>>),
+	GetClients(oneshot::Sender<Vec<PairedClient>>),
+	RenameClient(String, Option<String>, oneshot::Sender<bool>),
 	// RemoveClient(String, oneshot::Sender<bool>),
 }
 
+/// What is known about a paired client, besides the fingerprint of its certificate.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientInfo {
+	/// Name the user gave the client.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+
+	/// Name of the device that the client reported when it paired.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub device_name: Option<String>,
+
+	/// Kind of client, from the user agent of its pairing request.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub client_type: Option<String>,
+
+	/// Time the client paired, in seconds since the UNIX epoch.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub paired_at: Option<u64>,
+
+	/// Time the client last made an authenticated request, in seconds since the UNIX epoch.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub last_seen: Option<u64>,
+}
+
+impl ClientInfo {
+	/// Name to show for the client, the name the user gave it or else the name of its device.
+	pub fn display_name(&self) -> Option<&str> {
+		self.name.as_deref().or(self.device_name.as_deref())
+	}
+}
+
+/// A paired client with what is known about it.
+#[derive(Clone, Debug, Serialize)]
+pub struct PairedClient {
+	/// Fingerprint of the certificate the client paired with.
+	pub id: String,
+
+	#[serde(flatten)]
+	pub info: ClientInfo,
+}
+
 #[derive(Clone)]
 pub struct State {
 	command_tx: mpsc::Sender<StateCommand>,
@@ -26,22 +72,11 @@ impl State {
 
 		let (command_tx, command_rx) = mpsc::channel(10);
 
-		let inner: StateInner;
-		if path.exists() {
-			let serialized = std::fs::read_to_string(&path)
-				.map_err(|e| tracing::error!("Failed to read state file: {e}"))?;
-			inner = toml::from_str(&serialized)
-				.map_err(|e| tracing::error!("Failed to parse state file: {e}"))?;
-
-			tracing::debug!("Successfully loaded state from {:?}", path);
-			tracing::trace!("State: {inner:?}");
-
-			tokio::spawn(inner.run(command_rx));
-
-		} else {
-			let inner = StateInner::new();
-			tokio::spawn(inner.run(command_rx));
-		}
+		let inner = match StateInner::load(&path)? {
+			Some(inner) => inner,
+			None => StateInner::new(),
+		};
+		tokio::spawn(inner.run(command_rx));
 
 		let state = Self { command_tx, path };
 		state.save().await?;
@@ -63,6 +98,7 @@ impl State {
 		result_rx.await.map_err(|e| tracing::error!("Failed to receive Save response: {e}"))?
 	}
 
+	/// Check whether the client with this certificate fingerprint is paired.
 	pub async fn has_client(&self, client: String) -> Result<bool, ()> {
 		let (result_tx, result_rx) = oneshot::channel();
 		self.command_tx.send(StateCommand::HasClient(client, result_tx)).await
@@ -74,11 +110,44 @@ impl State {
 		Ok(result)
 	}
 
-	pub async fn add_client(&self, client: String) -> Result<(), ()> {
-		self.command_tx.send(StateCommand::AddClient(client)).await
+	/// Pair the client with this certificate fingerprint.
+	pub async fn add_client(&self, client: String, info: ClientInfo) -> Result<(), ()> {
+		self.command_tx.send(StateCommand::AddClient(client, info)).await
 			.map_err(|e| tracing::error!("Failed to send AddClient command: {e}"))
 	}
 
+	/// Record that the client with this certificate fingerprint was seen now, returns what is known about it or `None` if it
+	/// isn't paired.
+	pub async fn client_seen(&self, client: String) -> Result<Option<ClientInfo>, ()> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::ClientSeen(client, result_tx)).await
+			.map_err(|e| tracing::error!("Failed to send ClientSeen command: {e}"))?;
+		let result = result_rx.await.map_err(|e| tracing::error!("Failed to receive ClientSeen response: {e}"))?;
+
+		self.save().await?;
+
+		Ok(result)
+	}
+
+	pub async fn get_clients(&self) -> Result<Vec<PairedClient>, ()> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::GetClients(result_tx)).await
+			.map_err(|e| tracing::error!("Failed to send GetClients command: {e}"))?;
+		result_rx.await.map_err(|e| tracing::error!("Failed to receive GetClients response: {e}"))
+	}
+
+	/// Give a client a name, or remove its name with `None`, returns false if the client isn't paired.
+	pub async fn rename_client(&self, client: String, name: Option<String>) -> Result<bool, ()> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::RenameClient(client, name, result_tx)).await
+			.map_err(|e| tracing::error!("Failed to send RenameClient command: {e}"))?;
+		let result = result_rx.await.map_err(|e| tracing::error!("Failed to receive RenameClient response: {e}"))?;
+
+		self.save().await?;
+
+		Ok(result)
+	}
+
 	// pub async fn remove_client(&self, client: String) -> Result<bool, ()> {
 	// 	let (result_tx, result_rx) = oneshot::channel();
 	// 	self.command_tx.send(StateCommand::RemoveClient(client, result_tx)).await
@@ -94,12 +163,57 @@ impl State {
 #[derive(Debug, Serialize, Deserialize)]
 struct StateInner {
 	unique_id: String,
+
+	/// Paired clients by the fingerprint of the certificate they paired with.
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	paired_clients: BTreeMap<String, ClientInfo>,
+
+	/// Unique ids of clients that paired before their certificate was recorded.
+	///
+	/// Every Moonlight client sends the same unique id, so these can't be told apart and have to pair again.
+	#[serde(default, skip_serializing)]
 	clients: Vec<String>,
 }
 
 impl StateInner {
 	fn new() -> Self {
-		Self { unique_id: uuid::Uuid::new_v4().to_string(), clients: Default::default() }
+		Self { unique_id: uuid::Uuid::new_v4().to_string(), paired_clients: Default::default(), clients: Default::default() }
+	}
+
+	/// Load the state from a file, returns `None` if there is no usable state.
+	///
+	/// A state file that can't be parsed is backed up, so that a new state is generated instead of failing to start.
+	fn load(path: &Path) -> Result<Option<Self>, ()> {
+		let serialized = match std::fs::read_to_string(path) {
+			Ok(serialized) => serialized,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+				tracing::error!("State file {path:?} is not valid UTF-8, generating a new state: {e}");
+				storage::back_up_corrupt(path)?;
+				return Ok(None);
+			},
+			Err(e) => {
+				tracing::error!("Failed to read state file: {e}");
+				return Err(());
+			},
+		};
+
+		match toml::from_str::<Self>(&serialized) {
+			Ok(mut inner) => {
+				tracing::debug!("Successfully loaded state from {:?}", path);
+				tracing::trace!("State: {inner:?}");
+				if !inner.clients.is_empty() {
+					tracing::warn!("Forgetting {} client(s) that paired without a recorded certificate, they need to pair again.", inner.clients.len());
+					inner.clients.clear();
+				}
+				Ok(Some(inner))
+			},
+			Err(e) => {
+				tracing::error!("Failed to parse state file, generating a new state, paired clients need to pair again: {e}");
+				storage::back_up_corrupt(path)?;
+				Ok(None)
+			},
+		}
 	}
 
 	async fn run(mut self, mut command_rx: mpsc::Receiver<StateCommand>) {
@@ -124,9 +238,41 @@ impl StateInner {
 					}
 				},
 
-				StateCommand::AddClient(client) => {
+				StateCommand::AddClient(client, info) => {
 					// TODO: Return error to caller.
-					let _ = self.add_client(client);
+					self.add_client(client, info);
+				},
+
+				StateCommand::ClientSeen(client, result_tx) => {
+					let info = self.paired_clients.get_mut(&client).map(|info| {
+						info.last_seen = Some(unix_seconds());
+						info.clone()
+					});
+					if result_tx.send(info).is_err() {
+						tracing::error!("Failed to send ClientSeen result.");
+					}
+				},
+
+				StateCommand::GetClients(result_tx) => {
+					let clients = self.paired_clients.iter()
+						.map(|(id, info)| PairedClient { id: id.clone(), info: info.clone() })
+						.collect();
+					if result_tx.send(clients).is_err() {
+						tracing::error!("Failed to send GetClients result.");
+					}
+				},
+
+				StateCommand::RenameClient(client, name, result_tx) => {
+					let renamed = match self.paired_clients.get_mut(&client) {
+						Some(info) => {
+							info.name = name;
+							true
+						},
+						None => false,
+					};
+					if result_tx.send(renamed).is_err() {
+						tracing::error!("Failed to send RenameClient result.");
+					}
 				},
 
 				// StateCommand::RemoveClient(client, result_tx) => {
@@ -139,24 +285,23 @@ impl StateInner {
 	}
 
 	pub fn save<P: AsRef<Path>>(&self, file: P) -> Result<(), ()> {
-		let parent_dir = file.as_ref().parent().ok_or_else(|| tracing::error!("Failed to get state dir for file {:?}", file.as_ref()))?;
-		std::fs::create_dir_all(parent_dir)
-			.map_err(|e| tracing::error!("Failed to create state dir: {e}"))?;
-
-		std::fs::write(file, toml::to_string_pretty(self).map_err(|e| tracing::error!("Failed to serialize state: {e}"))?)
-			.map_err(|e| tracing::error!("Failed to save state file: {e}"))
+		let serialized = toml::to_string_pretty(self).map_err(|e| tracing::error!("Failed to serialize state: {e}"))?;
+		storage::write_atomic(file.as_ref(), serialized.as_bytes())
+			.map_err(|()| tracing::error!("Failed to save state file."))
 	}
 
 	fn has_client(&self, key: &String) -> bool {
-		self.clients.contains(key)
+		self.paired_clients.contains_key(key)
 	}
 
-	fn add_client(&mut self, key: String) -> bool {
-		if self.clients.contains(&key) {
+	fn add_client(&mut self, key: String, mut info: ClientInfo) -> bool {
+		if self.paired_clients.contains_key(&key) {
 			tracing::error!("Failed to add client ('{key}'), client already exists.");
 			false
 		} else {
-			self.clients.push(key);
+			info.paired_at = Some(unix_seconds());
+			info.last_seen = info.paired_at;
+			self.paired_clients.insert(key, info);
 			true
 		}
 	}
@@ -171,3 +316,95 @@ impl StateInner {
 	// 	}
 	// }
 }
+
+fn unix_seconds() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FINGERPRINT: &str = "AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89";
+
+	/// Backups of a corrupt state file next to `path`.
+	fn backups(path: &Path) -> Vec<PathBuf> {
+		std::fs::read_dir(path.parent().unwrap()).unwrap()
+			.map(|entry| entry.unwrap().path())
+			.filter(|backup| backup.file_name().unwrap().to_string_lossy().starts_with("state.toml.corrupt-"))
+			.collect()
+	}
+
+	#[test]
+	fn load_saved_state() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("moonshine").join("state.toml");
+		let mut state = StateInner::new();
+		let info = ClientInfo { device_name: Some("Steam Deck".to_string()), ..Default::default() };
+		assert!(state.add_client(FINGERPRINT.to_string(), info.clone()));
+		assert!(!state.add_client(FINGERPRINT.to_string(), info));
+		state.save(&path).unwrap();
+
+		let loaded = StateInner::load(&path).unwrap().unwrap();
+		assert_eq!(loaded.unique_id, state.unique_id);
+		assert!(loaded.has_client(&FINGERPRINT.to_string()));
+		assert_eq!(loaded.paired_clients[FINGERPRINT].device_name.as_deref(), Some("Steam Deck"));
+		assert!(loaded.paired_clients[FINGERPRINT].paired_at.is_some());
+		assert!(backups(&path).is_empty());
+	}
+
+	#[test]
+	fn clients_without_certificate_need_to_pair_again() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.toml");
+		std::fs::write(&path, "unique_id = \"5f0b6f3c-1f4e-4c8a-9a2e-6d1c0b7e9a41\"\nclients = [\"0123456789ABCDEF\"]\n").unwrap();
+
+		let loaded = StateInner::load(&path).unwrap().unwrap();
+		assert_eq!(loaded.unique_id, "5f0b6f3c-1f4e-4c8a-9a2e-6d1c0b7e9a41");
+		assert!(loaded.clients.is_empty());
+		assert!(!loaded.has_client(&"0123456789ABCDEF".to_string()));
+
+		loaded.save(&path).unwrap();
+		assert!(!std::fs::read_to_string(&path).unwrap().contains("0123456789ABCDEF"));
+		assert!(backups(&path).is_empty());
+	}
+
+	#[test]
+	fn missing_state() {
+		let dir = tempfile::tempdir().unwrap();
+		assert!(StateInner::load(&dir.path().join("state.toml")).unwrap().is_none());
+	}
+
+	#[test]
+	fn invalid_toml_is_backed_up() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.toml");
+		let contents = "unique_id = \"5f0b6f3c-1f4e-4c8a-9a2e-6d1c0b7e9a41\"\nclients = [\"0123";
+		std::fs::write(&path, contents).unwrap();
+
+		assert!(StateInner::load(&path).unwrap().is_none());
+		assert!(!path.exists());
+		let backups = backups(&path);
+		assert_eq!(backups.len(), 1);
+		assert_eq!(std::fs::read_to_string(&backups[0]).unwrap(), contents);
+
+		// A new state takes the place of the corrupt one.
+		let state = StateInner::new();
+		state.save(&path).unwrap();
+		let loaded = StateInner::load(&path).unwrap().unwrap();
+		assert_ne!(loaded.unique_id, "5f0b6f3c-1f4e-4c8a-9a2e-6d1c0b7e9a41");
+		assert_eq!(loaded.unique_id, state.unique_id);
+		assert!(loaded.paired_clients.is_empty());
+	}
+
+	#[test]
+	fn invalid_utf8_is_backed_up() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.toml");
+		std::fs::write(&path, b"unique_id = \"\xff\xfe\"\n").unwrap();
+
+		assert!(StateInner::load(&path).unwrap().is_none());
+		assert!(!path.exists());
+		assert_eq!(backups(&path).len(), 1);
+	}
+}
@@ -0,0 +1,105 @@
+//! Crash-safe writes of the files that moonshine keeps in its data directory.
+
+use std::{fs::File, io::Write, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+/// Replace the contents of a file, such that a crash leaves either the old or the new contents behind.
+///
+/// The contents are written to a temporary file next to `path`, synced to disk and then renamed over `path`.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), ()> {
+	let parent_dir = path.parent().ok_or_else(|| tracing::error!("Failed to get parent dir of {path:?}"))?;
+	std::fs::create_dir_all(parent_dir)
+		.map_err(|e| tracing::error!("Failed to create dir {parent_dir:?}: {e}"))?;
+
+	let temporary_path = sibling_path(path, "tmp");
+	let mut file = File::create(&temporary_path)
+		.map_err(|e| tracing::error!("Failed to create {temporary_path:?}: {e}"))?;
+	file.write_all(contents)
+		.and_then(|()| file.sync_all())
+		.map_err(|e| tracing::error!("Failed to write {temporary_path:?}: {e}"))?;
+	drop(file);
+
+	std::fs::rename(&temporary_path, path)
+		.map_err(|e| tracing::error!("Failed to move {temporary_path:?} to {path:?}: {e}"))?;
+
+	// The rename is only durable once the directory entry is synced as well.
+	File::open(parent_dir)
+		.and_then(|dir| dir.sync_all())
+		.map_err(|e| tracing::warn!("Failed to sync dir {parent_dir:?}: {e}"))
+		.ok();
+
+	Ok(())
+}
+
+/// Move a file that failed to parse out of the way, so it can be inspected later while a new file takes its place.
+///
+/// Returns the path of the backup.
+pub fn back_up_corrupt(path: &Path) -> Result<PathBuf, ()> {
+	let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+	let backup_path = sibling_path(path, &format!("corrupt-{timestamp}"));
+	std::fs::rename(path, &backup_path)
+		.map_err(|e| tracing::error!("Failed to back up corrupt file {path:?} to {backup_path:?}: {e}"))?;
+
+	tracing::warn!("Moved corrupt file {path:?} to {backup_path:?}.");
+	Ok(backup_path)
+}
+
+/// Path of a file next to `path`, with `suffix` appended to its file name.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+	let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+	file_name.push(format!(".{suffix}"));
+	path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Names of the files in a directory, sorted.
+	fn file_names(dir: &Path) -> Vec<String> {
+		let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+			.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+			.collect();
+		names.sort();
+		names
+	}
+
+	#[test]
+	fn write_atomic_creates_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("moonshine").join("state.toml");
+
+		write_atomic(&path, b"first").unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"first");
+		assert_eq!(file_names(path.parent().unwrap()), ["state.toml"]);
+	}
+
+	#[test]
+	fn write_atomic_replaces_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.toml");
+		std::fs::write(&path, b"a longer first version").unwrap();
+
+		write_atomic(&path, b"second").unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"second");
+		assert_eq!(file_names(dir.path()), ["state.toml"]);
+	}
+
+	#[test]
+	fn back_up_corrupt_moves_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("state.toml");
+		std::fs::write(&path, b"corrupt").unwrap();
+
+		let backup_path = back_up_corrupt(&path).unwrap();
+		assert!(!path.exists());
+		assert_eq!(backup_path.parent(), Some(dir.path()));
+		assert!(backup_path.file_name().unwrap().to_string_lossy().starts_with("state.toml.corrupt-"));
+		assert_eq!(std::fs::read(&backup_path).unwrap(), b"corrupt");
+	}
+
+	#[test]
+	fn back_up_missing_file() {
+		let dir = tempfile::tempdir().unwrap();
+		assert!(back_up_corrupt(&dir.path().join("state.toml")).is_err());
+	}
+}
@@ -0,0 +1,272 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use serde::Deserialize;
+
+use crate::config::{ApplicationConfig, Config, WebserverConfig};
+
+/// Port that Sunshine uses for HTTP when `port` is not configured, the other ports are offsets from this port.
+const SUNSHINE_DEFAULT_PORT: u16 = 47989;
+
+/// Applications as stored in Sunshine's `apps.json`.
+#[derive(Debug, Default, Deserialize)]
+struct SunshineApps {
+	/// Environment variables that are set for all applications.
+	#[serde(default)]
+	env: HashMap<String, String>,
+
+	#[serde(default)]
+	apps: Vec<SunshineApp>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SunshineApp {
+	name: String,
+
+	/// Command that runs the application.
+	#[serde(default)]
+	cmd: String,
+
+	/// Commands that run before the application starts (`do`) and after it stops (`undo`).
+	#[serde(default, rename = "prep-cmd")]
+	prep_cmd: Vec<SunshinePrepCommand>,
+
+	/// Commands that start in the background.
+	#[serde(default)]
+	detached: Vec<String>,
+
+	#[serde(default, rename = "image-path")]
+	image_path: String,
+
+	/// Whether the global preparation commands are skipped for this application.
+	#[serde(default, rename = "exclude-global-prep-cmd", deserialize_with = "deserialize_bool")]
+	exclude_global_prep_cmd: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SunshinePrepCommand {
+	#[serde(default, rename = "do")]
+	run_before: String,
+
+	#[serde(default, rename = "undo")]
+	run_after: String,
+}
+
+/// Convert a Sunshine configuration to a Moonshine configuration.
+///
+/// `path` is either Sunshine's config directory or its `sunshine.conf` file.
+/// The result is written to `output`, or printed if no output is given.
+#[allow(clippy::result_unit_err)]
+pub fn import(path: &Path, output: Option<&Path>) -> Result<(), ()> {
+	let config_path = if path.is_dir() { path.join("sunshine.conf") } else { path.to_path_buf() };
+	// Paths in the Moonshine config shouldn't depend on the directory the import was run from.
+	let config_dir = config_path.parent()
+		.filter(|dir| !dir.as_os_str().is_empty())
+		.unwrap_or(Path::new("."));
+	let config_dir = config_dir.canonicalize().unwrap_or_else(|_| config_dir.to_path_buf());
+
+	// Sunshine runs fine without a config file, in which case everything has default values.
+	let sunshine_config = if config_path.exists() {
+		let contents = std::fs::read_to_string(&config_path)
+			.map_err(|e| tracing::error!("Failed to read {}: {e}", config_path.display()))?;
+		parse_config(&contents)
+	} else {
+		tracing::warn!("No Sunshine config found at {}, using Sunshine's defaults.", config_path.display());
+		HashMap::new()
+	};
+
+	let mut config = Config::default();
+
+	if let Some(name) = sunshine_config.get("sunshine_name") {
+		config.name = name.clone();
+	}
+
+	let port: u16 = match sunshine_config.get("port") {
+		Some(port) => port.parse()
+			.map_err(|e| tracing::error!("Failed to parse Sunshine port '{port}': {e}"))?,
+		None => SUNSHINE_DEFAULT_PORT,
+	};
+	if !(5..=u16::MAX - 21).contains(&port) {
+		tracing::error!("Sunshine port {port} is out of range, the ports of all streams must be valid.");
+		return Err(());
+	}
+	config.webserver.port = port;
+	config.webserver.port_https = port - 5;
+	config.stream.video.port = port + 9;
+	config.stream.control.port = port + 10;
+	config.stream.audio.port = port + 11;
+	config.stream.port = port + 21;
+
+	// Keep using the same certificate, so clients keep recognizing the host.
+	config.webserver.certificate = sunshine_config.get("cert")
+		.map(|cert| config_dir.join(cert))
+		.unwrap_or_else(|| config_dir.join("credentials").join("cacert.pem"));
+	config.webserver.private_key = sunshine_config.get("pkey")
+		.map(|pkey| config_dir.join(pkey))
+		.unwrap_or_else(|| config_dir.join("credentials").join("cakey.pem"));
+	if !config.webserver.certificate.exists() || !config.webserver.private_key.exists() {
+		tracing::warn!("Sunshine certificate or private key not found, Moonshine will create a new certificate.");
+		config.webserver.certificate = WebserverConfig::default().certificate;
+		config.webserver.private_key = WebserverConfig::default().private_key;
+	}
+
+	let global_prep_commands: Vec<SunshinePrepCommand> = match sunshine_config.get("global_prep_cmd") {
+		Some(commands) => serde_json::from_str(commands)
+			.map_err(|e| tracing::error!("Failed to parse Sunshine global_prep_cmd: {e}"))?,
+		None => Vec::new(),
+	};
+
+	let apps_path = sunshine_config.get("file_apps")
+		.map(|apps| config_dir.join(apps))
+		.unwrap_or_else(|| config_dir.join("apps.json"));
+	let apps: SunshineApps = if apps_path.exists() {
+		let contents = std::fs::read_to_string(&apps_path)
+			.map_err(|e| tracing::error!("Failed to read {}: {e}", apps_path.display()))?;
+		serde_json::from_str(&contents)
+			.map_err(|e| tracing::error!("Failed to parse {}: {e}", apps_path.display()))?
+	} else {
+		tracing::warn!("No Sunshine applications found at {}.", apps_path.display());
+		SunshineApps::default()
+	};
+
+	config.applications = apps.apps.iter()
+		.map(|app| convert_application(app, &apps.env, &global_prep_commands))
+		.collect();
+	tracing::info!("Imported {} applications from Sunshine.", config.applications.len());
+
+	let serialized_config = toml::to_string_pretty(&config)
+		.map_err(|e| tracing::error!("Failed to serialize config: {e}"))?;
+
+	match output {
+		Some(output) => {
+			if output.exists() {
+				tracing::error!("Refusing to overwrite existing config file {}.", output.display());
+				return Err(());
+			}
+
+			if let Some(parent) = output.parent() {
+				std::fs::create_dir_all(parent)
+					.map_err(|e| tracing::error!("Failed to create config directory: {e}"))?;
+			}
+			std::fs::write(output, serialized_config)
+				.map_err(|e| tracing::error!("Failed to save config file: {e}"))?;
+			tracing::info!("Saved imported config to {}.", output.display());
+		},
+		None => print!("{serialized_config}"),
+	}
+
+	Ok(())
+}
+
+/// Parse the `key = value` lines of a `sunshine.conf` file.
+fn parse_config(contents: &str) -> HashMap<String, String> {
+	contents.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.filter_map(|line| {
+			let (key, value) = line.split_once('=')?;
+			Some((key.trim().to_string(), value.trim().to_string()))
+		})
+		.collect()
+}
+
+fn convert_application(
+	app: &SunshineApp,
+	env: &HashMap<String, String>,
+	global_prep_commands: &[SunshinePrepCommand],
+) -> ApplicationConfig {
+	let prep_commands: Vec<&SunshinePrepCommand> = if app.exclude_global_prep_cmd {
+		app.prep_cmd.iter().collect()
+	} else {
+		global_prep_commands.iter().chain(app.prep_cmd.iter()).collect()
+	};
+
+	let mut run_before: Vec<Vec<String>> = prep_commands.iter()
+		.filter(|command| !command.run_before.trim().is_empty())
+		.map(|command| convert_command(&command.run_before, env))
+		.collect();
+	run_before.extend(app.detached.iter()
+		.filter(|command| !command.trim().is_empty())
+		.map(|command| convert_command(command, env))
+	);
+	// Sunshine waits for `cmd` like moonshine tracks the command of an application, detached commands are left running.
+	let command = Some(&app.cmd)
+		.filter(|command| !command.trim().is_empty())
+		.map(|command| convert_command(command, env));
+
+	// Sunshine undoes preparation commands in reverse order.
+	let run_after: Vec<Vec<String>> = prep_commands.iter()
+		.rev()
+		.filter(|command| !command.run_after.trim().is_empty())
+		.map(|command| convert_command(&command.run_after, env))
+		.collect();
+
+	// Relative image paths refer to Sunshine's own assets.
+	let boxart = Some(PathBuf::from(&app.image_path))
+		.filter(|path| path.is_absolute());
+
+	ApplicationConfig {
+		title: app.name.clone(),
+		boxart,
+		run_before: Some(run_before).filter(|commands| !commands.is_empty()),
+		command,
+		run_after: Some(run_after).filter(|commands| !commands.is_empty()),
+		privacy_mode: None,
+		monitor: None,
+		gamescope: false,
+		wrapper: None,
+		stream_timeout: None,
+		input_profile: None,
+		terminate_on_cancel: None,
+	}
+}
+
+/// Convert a Sunshine command line to a command that runs it through a shell, with the application environment.
+fn convert_command(command: &str, env: &HashMap<String, String>) -> Vec<String> {
+	let mut converted = Vec::new();
+	if !env.is_empty() {
+		converted.push("env".to_string());
+		let mut env: Vec<_> = env.iter().collect();
+		env.sort();
+		converted.extend(env.into_iter().map(|(key, value)| format!("{key}={}", convert_variables(value))));
+	}
+
+	converted.extend(["sh".to_string(), "-c".to_string(), convert_variables(command)]);
+	converted
+}
+
+/// Convert Sunshine's `$(VARIABLE)` syntax, replacing the requested resolution with Moonshine's template values.
+fn convert_variables(value: &str) -> String {
+	let mut result = String::new();
+	let mut rest = value;
+	while let Some(start) = rest.find("$(") {
+		let Some(end) = rest[start..].find(')') else {
+			break;
+		};
+
+		result.push_str(&rest[..start]);
+		match &rest[start + 2..start + end] {
+			"SUNSHINE_CLIENT_WIDTH" => result.push_str("{width}"),
+			"SUNSHINE_CLIENT_HEIGHT" => result.push_str("{height}"),
+			variable => result.push_str(&format!("${{{variable}}}")),
+		}
+		rest = &rest[start + end + 1..];
+	}
+	result.push_str(rest);
+
+	result
+}
+
+/// Sunshine stores booleans as either JSON booleans or strings.
+fn deserialize_bool<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Bool {
+		Bool(bool),
+		String(String),
+	}
+
+	Ok(match Bool::deserialize(deserializer)? {
+		Bool::Bool(value) => value,
+		Bool::String(value) => value == "true",
+	})
+}
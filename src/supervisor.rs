@@ -0,0 +1,121 @@
+//! Supervision of long-running tasks, so that a task that dies is never silently gone.
+//!
+//! Restartable tasks are restarted with an exponential backoff, critical tasks shut down whatever they are part of
+//! (the host or a session) when they exit. Tasks run in the span they were spawned from, so their logs carry the
+//! session they belong to.
+
+use std::{future::Future, time::{Duration, Instant}};
+
+use async_shutdown::ShutdownManager;
+use tokio::task::JoinError;
+use tracing::Instrument;
+
+/// Delay before the first restart, it doubles with every failure in a row.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Restartable tasks that fail this many times in a row are given up on.
+const MAX_RESTARTS: u32 = 5;
+
+/// A task that ran for this long before failing is considered to have recovered, which resets its backoff.
+const STABLE_DURATION: Duration = Duration::from_secs(60);
+
+/// Spawns tasks and watches how they exit.
+#[derive(Clone)]
+pub struct Supervisor<T: Clone> {
+	shutdown: ShutdownManager<T>,
+}
+
+impl<T: Clone + Send + Sync + 'static> Supervisor<T> {
+	pub fn new(shutdown: ShutdownManager<T>) -> Self {
+		Self { shutdown }
+	}
+
+	/// Spawn a task that is essential, `reason` is used to shut down once it exits.
+	///
+	/// The task isn't cancelled on shutdown, tasks that should stop then need to watch for the shutdown themselves.
+	pub fn spawn_critical<F>(&self, name: &'static str, reason: T, task: F)
+	where
+		F: Future<Output = Result<(), ()>> + Send + 'static,
+	{
+		let supervisor = self.clone();
+		tokio::spawn(async move {
+			let result = tokio::spawn(task.in_current_span()).await;
+			supervisor.critical_exit(name, reason, result);
+		}.in_current_span());
+	}
+
+	/// Like [`Self::spawn_critical`], for tasks that block and run on a thread of their own.
+	pub fn spawn_critical_blocking<F>(&self, name: &'static str, reason: T, task: F)
+	where
+		F: FnOnce() -> Result<(), ()> + Send + 'static,
+	{
+		let supervisor = self.clone();
+		let span = tracing::Span::current();
+		tokio::spawn(async move {
+			let result = tokio::task::spawn_blocking({
+				let span = span.clone();
+				move || span.in_scope(task)
+			}).await;
+			supervisor.critical_exit(name, reason, result);
+		}.instrument(span));
+	}
+
+	/// Spawn a task that is restarted when it fails, `task` creates a new instance of the task for every attempt.
+	///
+	/// The task is no longer restarted after shutdown, or after failing too often in a row.
+	pub fn spawn_restartable<F, Fut>(&self, name: &'static str, mut task: F)
+	where
+		F: FnMut() -> Fut + Send + 'static,
+		Fut: Future<Output = Result<(), ()>> + Send + 'static,
+	{
+		tokio::spawn(self.shutdown.wrap_cancel(async move {
+			let mut failures = 0;
+			loop {
+				let started = Instant::now();
+				let Some(reason) = exit_reason(tokio::spawn(task().in_current_span()).await) else {
+					tracing::debug!("Task '{name}' finished.");
+					return;
+				};
+
+				if started.elapsed() >= STABLE_DURATION {
+					failures = 0;
+				}
+				failures += 1;
+				if failures > MAX_RESTARTS {
+					tracing::error!("Task '{name}' {reason} {MAX_RESTARTS} times in a row, no longer restarting it.");
+					return;
+				}
+
+				let backoff = INITIAL_BACKOFF.saturating_mul(1 << (failures - 1)).min(MAX_BACKOFF);
+				tracing::warn!("Task '{name}' {reason}, restarting it in {}s.", backoff.as_secs());
+				tokio::time::sleep(backoff).await;
+			}
+		}.in_current_span()));
+	}
+
+	fn critical_exit(&self, name: &str, reason: T, result: Result<Result<(), ()>, JoinError>) {
+		if self.shutdown.is_shutdown_triggered() {
+			tracing::debug!("Task '{name}' stopped.");
+			return;
+		}
+
+		match exit_reason(result) {
+			Some(exit_reason) => tracing::error!("Task '{name}' {exit_reason}, shutting down."),
+			None => tracing::debug!("Task '{name}' finished, shutting down."),
+		}
+
+		// Fails if something else triggered the shutdown in the meantime, which is fine.
+		let _ = self.shutdown.trigger_shutdown(reason);
+	}
+}
+
+/// Describes why a task exited, or `None` if it finished successfully.
+fn exit_reason(result: Result<Result<(), ()>, JoinError>) -> Option<&'static str> {
+	match result {
+		Ok(Ok(())) => None,
+		Ok(Err(())) => Some("failed"),
+		Err(e) if e.is_panic() => Some("panicked"),
+		Err(_) => Some("was cancelled"),
+	}
+}
@@ -0,0 +1,129 @@
+//! Opt-in recording of the protocol exchanges with clients.
+//!
+//! Transcripts make it possible to reproduce client compatibility issues, without access to the client.
+//! Secrets (keys, pairing challenges, input events) are redacted and large payloads are truncated.
+
+use std::{fmt::Arguments, fs::File, io::Write, path::PathBuf, sync::{Arc, Mutex}, time::{Instant, SystemTime, UNIX_EPOCH}};
+
+/// Query parameters whose values are never written to a transcript.
+const REDACTED_PARAMETERS: &[&str] = &[
+	"rikey",
+	"rikeyid",
+	"salt",
+	"clientcert",
+	"clientchallenge",
+	"serverchallengeresp",
+	"clientpairingsecret",
+	"pin",
+	"token",
+];
+
+/// Maximum number of bytes of a payload that is written to a transcript.
+const MAX_PAYLOAD_LENGTH: usize = 2048;
+
+/// Records protocol messages to a file per session, or does nothing when transcripts are disabled.
+#[derive(Clone, Default)]
+pub struct Transcript {
+	inner: Option<Arc<Mutex<TranscriptInner>>>,
+}
+
+struct TranscriptInner {
+	/// Directory in which transcripts are written.
+	directory: PathBuf,
+
+	/// Transcript that is currently written to, created when the first message is recorded.
+	file: Option<File>,
+
+	/// Moment the current transcript was created.
+	start: Instant,
+}
+
+impl Transcript {
+	/// Write transcripts to `directory`, or disable transcripts if it is None.
+	pub fn new(directory: Option<PathBuf>) -> Self {
+		let inner = directory.map(|directory| Arc::new(Mutex::new(TranscriptInner {
+			directory,
+			file: None,
+			start: Instant::now(),
+		})));
+
+		Self { inner }
+	}
+
+	/// Start a new transcript, messages that are recorded after this are written to a new file.
+	pub fn begin_session(&self) {
+		let Some(inner) = &self.inner else {
+			return;
+		};
+
+		if let Ok(mut inner) = inner.lock() {
+			inner.file = None;
+		}
+	}
+
+	/// Record a message, `source` describes where it came from (for example "http" or "rtsp").
+	pub fn record(&self, source: &str, message: Arguments) {
+		let Some(inner) = &self.inner else {
+			return;
+		};
+		let Ok(mut inner) = inner.lock() else {
+			return;
+		};
+
+		if inner.file.is_none() {
+			let Ok(file) = inner.create_file() else {
+				return;
+			};
+			inner.file = Some(file);
+			inner.start = Instant::now();
+		}
+
+		let elapsed = inner.start.elapsed().as_secs_f64();
+		if let Some(file) = &mut inner.file {
+			if let Err(e) = writeln!(file, "[{elapsed:>10.3}] {source:<7} {message}") {
+				tracing::warn!("Failed to write to transcript: {e}");
+			}
+		}
+	}
+}
+
+impl TranscriptInner {
+	fn create_file(&self) -> Result<File, ()> {
+		std::fs::create_dir_all(&self.directory)
+			.map_err(|e| tracing::warn!("Failed to create transcript directory {}: {e}", self.directory.display()))?;
+
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_secs()).unwrap_or_default();
+		let path = self.directory.join(format!("session-{timestamp}.log"));
+		tracing::info!("Writing protocol transcript to {}.", path.display());
+
+		let mut file = File::create(&path)
+			.map_err(|e| tracing::warn!("Failed to create transcript {}: {e}", path.display()))?;
+		let _ = writeln!(file, "Moonshine {} protocol transcript, started at {timestamp}.", env!("CARGO_PKG_VERSION"));
+
+		Ok(file)
+	}
+}
+
+/// Format a query string with the values of secret parameters redacted.
+pub fn redact_query(query: &str) -> String {
+	url::form_urlencoded::parse(query.as_bytes())
+		.map(|(name, value)| {
+			if REDACTED_PARAMETERS.contains(&name.as_ref()) {
+				format!("{name}=<redacted>")
+			} else {
+				format!("{name}={value}")
+			}
+		})
+		.collect::<Vec<_>>()
+		.join("&")
+}
+
+/// Format a payload as text, truncated to a maximum length.
+pub fn truncate_payload(payload: &[u8]) -> String {
+	let text = String::from_utf8_lossy(&payload[..payload.len().min(MAX_PAYLOAD_LENGTH)]).into_owned();
+	if payload.len() > MAX_PAYLOAD_LENGTH {
+		format!("{text}... ({} bytes truncated)", payload.len() - MAX_PAYLOAD_LENGTH)
+	} else {
+		text
+	}
+}
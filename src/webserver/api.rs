@@ -0,0 +1,318 @@
+//! JSON API used to inspect and control the host, only available to clients on the host itself.
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, header::{self, HeaderValue}, Method, Request, Response, StatusCode};
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::{ApplicationConfig, CaptureArea}, host::monitors::list_monitors};
+
+use super::{not_found, params::QueryParams, Webserver};
+
+/// Width of snapshots if the request doesn't ask for one.
+const DEFAULT_SNAPSHOT_WIDTH: u32 = 640;
+
+/// Time to wait for a frame to take a snapshot of, while idle frames are only captured once per second.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(2);
+
+impl Webserver {
+	pub(super) async fn api(
+		&self,
+		request: Request<hyper::body::Incoming>,
+		remote_address: SocketAddr,
+	) -> Response<Full<Bytes>> {
+		if !remote_address.ip().to_canonical().is_loopback() {
+			tracing::warn!("Rejecting API request from {remote_address}, the API is only available from the host itself.");
+			return json_error(StatusCode::FORBIDDEN, "The API is only available from the host itself.");
+		}
+
+		match (request.method(), request.uri().path()) {
+			(&Method::GET, "/api/stats") => self.api_stats().await,
+			(&Method::GET, "/api/monitors") => api_monitors(),
+			(&Method::GET, "/api/history") => self.api_history().await,
+			(&Method::GET, "/api/bandwidth") => self.api_bandwidth().await,
+			(&Method::GET, "/api/logs") => self.api_logs(&request),
+			(&Method::PUT, "/api/capture") => self.api_update_capture(request).await,
+			(&Method::PUT, "/api/audio") => self.api_update_audio(request).await,
+			(&Method::GET, "/api/applications") => self.api_applications(),
+			(&Method::POST, "/api/applications/refresh") => self.api_refresh_applications().await,
+			(&Method::GET, "/api/clients") => self.api_clients().await,
+			(&Method::GET, "/api/gamepads") => self.api_gamepads().await,
+			(&Method::GET, "/api/snapshot") => self.snapshot(QueryParams::from_uri(request.uri())).await,
+			(&Method::PUT, path) if path.starts_with("/api/clients/") => self.api_rename_client(request).await,
+			(method, uri) => {
+				tracing::warn!("Unhandled {method} API request with URI '{uri}'");
+				not_found()
+			},
+		}
+	}
+
+	async fn api_stats(&self) -> Response<Full<Bytes>> {
+		let stats = match self.session_manager.get_session_stats().await {
+			Ok(Some(stats)) => stats,
+			Ok(None) => return json_error(StatusCode::NOT_FOUND, "There is no active session."),
+			Err(()) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to retrieve session stats."),
+		};
+
+		match stats.snapshot() {
+			Ok(snapshot) => json_response(&snapshot),
+			Err(()) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to retrieve session stats."),
+		}
+	}
+
+	fn api_applications(&self) -> Response<Full<Bytes>> {
+		let applications: Vec<ApplicationSummary> = self.applications.list()
+			.into_iter()
+			.map(ApplicationSummary::from)
+			.collect();
+		json_response(&applications)
+	}
+
+	/// Run the application scanners again, so that newly installed applications show up for clients.
+	async fn api_refresh_applications(&self) -> Response<Full<Bytes>> {
+		let applications = self.applications.clone();
+		match tokio::task::spawn_blocking(move || applications.refresh()).await {
+			Ok(count) => {
+				tracing::info!("Found {count} applications while refreshing the application catalog.");
+				self.api_applications()
+			},
+			Err(e) => {
+				tracing::error!("Failed to refresh applications: {e}");
+				json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to refresh applications.")
+			},
+		}
+	}
+
+	async fn api_history(&self) -> Response<Full<Bytes>> {
+		match self.session_manager.get_history().await {
+			Ok(history) => json_response(&history),
+			Err(()) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to retrieve session history."),
+		}
+	}
+
+	async fn api_bandwidth(&self) -> Response<Full<Bytes>> {
+		match self.session_manager.get_bandwidth_usage().await {
+			Ok(usage) => json_response(&usage),
+			Err(()) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to retrieve bandwidth usage."),
+		}
+	}
+
+	/// Recent log records, optionally only those of the last `minutes` minutes.
+	fn api_logs(&self, request: &Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+		let minutes: Option<u64> = match QueryParams::from_uri(request.uri()).optional("minutes") {
+			Ok(minutes) => minutes,
+			Err(response) => return response,
+		};
+
+		match self.logs.recent(minutes.map(|minutes| Duration::from_secs(minutes * 60))) {
+			Ok(records) => json_response(&records),
+			Err(()) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to retrieve logs."),
+		}
+	}
+
+	async fn api_clients(&self) -> Response<Full<Bytes>> {
+		match self.client_manager.list_clients().await {
+			Ok(clients) => json_response(&clients),
+			Err(()) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to list clients."),
+		}
+	}
+
+	async fn api_gamepads(&self) -> Response<Full<Bytes>> {
+		match self.session_manager.get_gamepads().await {
+			Ok(gamepads) => json_response(&gamepads),
+			Err(()) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to list gamepads."),
+		}
+	}
+
+	/// The most recently captured frame as a JPEG or PNG image, scaled down to `width` pixels wide.
+	///
+	/// Also serves the thumbnail of the dashboard, which isn't limited to the host itself.
+	pub(super) async fn snapshot(&self, params: QueryParams) -> Response<Full<Bytes>> {
+		let width: u32 = match params.optional("width") {
+			Ok(width) => width.unwrap_or(DEFAULT_SNAPSHOT_WIDTH),
+			Err(response) => return response,
+		};
+		let format: Option<String> = match params.optional("format") {
+			Ok(format) => format,
+			Err(response) => return response,
+		};
+		let (format, content_type) = match format.as_deref() {
+			None | Some("jpeg") | Some("jpg") => (ImageFormat::Jpeg, "image/jpeg"),
+			Some("png") => (ImageFormat::Png, "image/png"),
+			Some(format) => return json_error(StatusCode::BAD_REQUEST, &format!("Unsupported snapshot format '{format}', expected 'jpeg' or 'png'.")),
+		};
+
+		let Ok(snapshot_rx) = self.session_manager.video_tap().request_snapshot(width) else {
+			return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to request a snapshot.");
+		};
+		let snapshot = match tokio::time::timeout(SNAPSHOT_TIMEOUT, snapshot_rx).await {
+			Ok(Ok(snapshot)) => snapshot,
+			Ok(Err(_)) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to take a snapshot."),
+			Err(_) => return json_error(StatusCode::SERVICE_UNAVAILABLE, "No frame was captured, is a stream running?"),
+		};
+
+		let Some(image) = image::RgbImage::from_raw(snapshot.width, snapshot.height, snapshot.rgb) else {
+			return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Snapshot has an unexpected size.");
+		};
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		if let Err(e) = image.write_to(&mut buffer, format) {
+			tracing::warn!("Failed to encode snapshot: {e}");
+			return json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode snapshot.");
+		}
+
+		let mut response = Response::new(Full::new(Bytes::from(buffer.into_inner())));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+		response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+		response
+	}
+
+	/// Give the client with the certificate fingerprint in the path a name, which is shown instead of its fingerprint.
+	async fn api_rename_client(&self, request: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+		let id = request.uri().path().trim_start_matches("/api/clients/").to_string();
+		let body = match request.into_body().collect().await {
+			Ok(body) => body.to_bytes(),
+			Err(e) => {
+				tracing::warn!("Failed to read API request body: {e}");
+				return json_error(StatusCode::BAD_REQUEST, "Failed to read request body.");
+			},
+		};
+
+		let update: ClientUpdate = match serde_json::from_slice(&body) {
+			Ok(update) => update,
+			Err(e) => {
+				tracing::warn!("Failed to parse client update: {e}");
+				return json_error(StatusCode::BAD_REQUEST, &format!("Invalid client update: {e}"));
+			},
+		};
+		let name = update.name.map(|name| name.trim().to_string()).filter(|name| !name.is_empty());
+
+		match self.client_manager.rename_client(&id, name.clone()).await {
+			Ok(true) => {
+				tracing::info!("Renamed client '{id}' to {name:?}.");
+				json_response(&ClientUpdate { name })
+			},
+			Ok(false) => json_error(StatusCode::NOT_FOUND, "There is no paired client with this id."),
+			Err(()) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to rename client."),
+		}
+	}
+
+	/// Change the part of the desktop that is streamed in the running session.
+	///
+	/// The body is a capture area like `stream.video.capture` in the config, or `null` to stream the entire desktop.
+	async fn api_update_capture(&self, request: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+		let body = match request.into_body().collect().await {
+			Ok(body) => body.to_bytes(),
+			Err(e) => {
+				tracing::warn!("Failed to read API request body: {e}");
+				return json_error(StatusCode::BAD_REQUEST, "Failed to read request body.");
+			},
+		};
+
+		let capture_area: Option<CaptureArea> = match serde_json::from_slice(&body) {
+			Ok(capture_area) => capture_area,
+			Err(e) => {
+				tracing::warn!("Failed to parse capture area: {e}");
+				return json_error(StatusCode::BAD_REQUEST, &format!("Invalid capture area: {e}"));
+			},
+		};
+
+		match self.session_manager.update_capture_area(capture_area.clone()).await {
+			Ok(()) => json_response(&capture_area),
+			Err(()) => json_error(StatusCode::CONFLICT, "There is no running stream."),
+		}
+	}
+
+	/// Change the audio settings of the running session.
+	async fn api_update_audio(&self, request: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+		let body = match request.into_body().collect().await {
+			Ok(body) => body.to_bytes(),
+			Err(e) => {
+				tracing::warn!("Failed to read API request body: {e}");
+				return json_error(StatusCode::BAD_REQUEST, "Failed to read request body.");
+			},
+		};
+
+		let settings: AudioSettings = match serde_json::from_slice(&body) {
+			Ok(settings) => settings,
+			Err(e) => {
+				tracing::warn!("Failed to parse audio settings: {e}");
+				return json_error(StatusCode::BAD_REQUEST, &format!("Invalid audio settings: {e}"));
+			},
+		};
+
+		// Opus supports bitrates between 6 kbps and 510 kbps.
+		if !(6_000..=510_000).contains(&settings.bitrate) {
+			return json_error(StatusCode::BAD_REQUEST, "The bitrate should be between 6000 and 510000 bits per second.");
+		}
+
+		match self.session_manager.update_audio_bitrate(settings.bitrate).await {
+			Ok(()) => json_response(&settings),
+			Err(()) => json_error(StatusCode::CONFLICT, "There is no running stream."),
+		}
+	}
+}
+
+/// An application as it is shown to clients.
+#[derive(Serialize)]
+struct ApplicationSummary {
+	id: i32,
+	title: String,
+	boxart: Option<PathBuf>,
+}
+
+impl From<ApplicationConfig> for ApplicationSummary {
+	fn from(application: ApplicationConfig) -> Self {
+		Self { id: application.id(), title: application.title, boxart: application.boxart }
+	}
+}
+
+/// Changes to a paired client.
+#[derive(Deserialize, Serialize)]
+struct ClientUpdate {
+	/// Name of the client, `null` or an empty name removes it.
+	name: Option<String>,
+}
+
+/// Audio settings that can be changed while streaming.
+#[derive(Deserialize, Serialize)]
+struct AudioSettings {
+	/// Bitrate of the encoded audio in bits per second.
+	bitrate: u32,
+}
+
+fn api_monitors() -> Response<Full<Bytes>> {
+	match list_monitors() {
+		Ok(monitors) => json_response(&monitors),
+		Err(()) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to list monitors."),
+	}
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Full<Bytes>> {
+	match serde_json::to_vec(value) {
+		Ok(body) => {
+			let mut response = Response::new(Full::new(Bytes::from(body)));
+			response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+			response
+		},
+		Err(e) => {
+			tracing::error!("Failed to serialize API response: {e}");
+			json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to serialize response.")
+		},
+	}
+}
+
+fn json_error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+	let body = serde_json::json!({ "error": message }).to_string();
+	Response::builder()
+		.status(status)
+		.header(header::CONTENT_TYPE, "application/json")
+		.body(Full::new(Bytes::from(body)))
+		.map_err(|e| tracing::error!("Failed to build API error response: {e}"))
+		.unwrap_or_else(|()| {
+			let mut response = Response::new(Full::new(Bytes::from("Internal server error")));
+			*response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+			response
+		})
+}
@@ -0,0 +1,191 @@
+//! Live view of the running session, which pushes updates to the browser using server-sent events.
+
+use std::{convert::Infallible, net::SocketAddr, pin::Pin, task::{Context, Poll}, time::{Duration, Instant}};
+
+use http_body_util::Full;
+use hyper::{body::{Body, Bytes, Frame, SizeHint}, header::{self, HeaderValue}, Response, StatusCode};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::session::{manager::SessionManager, stats::SessionStatsSnapshot, ClientId, SessionId};
+
+use super::{params::QueryParams, Webserver};
+
+/// Time between two dashboard updates.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Body of a response, which is either sent at once or streamed as events.
+pub enum ResponseBody {
+	Full(Full<Bytes>),
+	Events(mpsc::Receiver<Bytes>),
+}
+
+impl Body for ResponseBody {
+	type Data = Bytes;
+	type Error = Infallible;
+
+	fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+		match self.get_mut() {
+			Self::Full(body) => Pin::new(body).poll_frame(cx),
+			Self::Events(events_rx) => events_rx.poll_recv(cx).map(|event| event.map(|event| Ok(Frame::data(event)))),
+		}
+	}
+
+	fn is_end_stream(&self) -> bool {
+		match self {
+			Self::Full(body) => body.is_end_stream(),
+			Self::Events(_) => false,
+		}
+	}
+
+	fn size_hint(&self) -> SizeHint {
+		match self {
+			Self::Full(body) => body.size_hint(),
+			Self::Events(_) => SizeHint::default(),
+		}
+	}
+}
+
+/// State of the running session, as shown on the dashboard.
+#[derive(Serialize)]
+struct DashboardSession {
+	session_id: SessionId,
+	client_id: ClientId,
+	application: String,
+	resolution: (u32, u32),
+	refresh_rate: u32,
+	video_encoder: Option<String>,
+
+	/// Bitrate of the audio and video packets since the previous update, in bits per second.
+	bitrate: u64,
+
+	/// Number of frames encoded per second since the previous update.
+	fps: f64,
+
+	/// Median time it took to encode a frame, in microseconds.
+	encode_latency: u64,
+
+	/// Number of packets the client reported as lost since the previous update.
+	packets_lost: u64,
+}
+
+impl Webserver {
+	/// The dashboard is always available on the host itself, other devices need `webserver.remote_dashboard`.
+	fn dashboard_allowed(&self, remote_address: SocketAddr) -> bool {
+		self.config.webserver.remote_dashboard || remote_address.ip().to_canonical().is_loopback()
+	}
+
+	pub(super) fn dashboard(&self, remote_address: SocketAddr) -> Response<Full<Bytes>> {
+		if !self.dashboard_allowed(remote_address) {
+			tracing::warn!("Rejecting dashboard request from {remote_address}, the remote dashboard is disabled.");
+			return forbidden();
+		}
+
+		let content = include_bytes!("../../assets/dashboard.html");
+		let mut response = Response::new(Full::new(Bytes::from_static(content)));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=UTF-8"));
+
+		response
+	}
+
+	/// Thumbnail of the running stream, see [`Webserver::snapshot`].
+	pub(super) async fn dashboard_snapshot(&self, params: QueryParams, remote_address: SocketAddr) -> Response<Full<Bytes>> {
+		if !self.dashboard_allowed(remote_address) {
+			tracing::warn!("Rejecting dashboard request from {remote_address}, the remote dashboard is disabled.");
+			return forbidden();
+		}
+
+		self.snapshot(params).await
+	}
+
+	/// Stream updates of the running session, until the browser closes the connection.
+	pub(super) fn dashboard_events(&self, remote_address: SocketAddr) -> Response<ResponseBody> {
+		if !self.dashboard_allowed(remote_address) {
+			tracing::warn!("Rejecting dashboard request from {remote_address}, the remote dashboard is disabled.");
+			return forbidden().map(ResponseBody::Full);
+		}
+
+		let (events_tx, events_rx) = mpsc::channel(1);
+		tokio::spawn(send_dashboard_events(self.session_manager.clone(), events_tx));
+
+		let mut response = Response::new(ResponseBody::Events(events_rx));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+		response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+		response
+	}
+}
+
+async fn send_dashboard_events(session_manager: SessionManager, events_tx: mpsc::Sender<Bytes>) {
+	let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+	let mut previous: Option<(Instant, SessionStatsSnapshot)> = None;
+
+	loop {
+		interval.tick().await;
+
+		let session = dashboard_session(&session_manager, &mut previous).await;
+		let event = match serde_json::to_string(&session) {
+			Ok(event) => event,
+			Err(e) => {
+				tracing::error!("Failed to serialize dashboard update: {e}");
+				break;
+			},
+		};
+
+		if events_tx.send(Bytes::from(format!("data: {event}\n\n"))).await.is_err() {
+			tracing::debug!("Dashboard closed, stopping updates.");
+			break;
+		}
+	}
+}
+
+/// Collect the state of the running session, rates are computed relative to the previous snapshot.
+async fn dashboard_session(
+	session_manager: &SessionManager,
+	previous: &mut Option<(Instant, SessionStatsSnapshot)>,
+) -> Option<DashboardSession> {
+	let context = session_manager.get_session_context().await.ok().flatten();
+	let stats = session_manager.get_session_stats().await.ok().flatten();
+	let (Some(context), Some(stats)) = (context, stats) else {
+		*previous = None;
+		return None;
+	};
+	let snapshot = stats.snapshot().ok()?;
+	let now = Instant::now();
+
+	// Counters only go down when a new session started since the previous update.
+	let (bitrate, fps, packets_lost) = match previous.as_ref() {
+		Some((time, last)) if snapshot.bytes_sent >= last.bytes_sent && snapshot.frames_encoded >= last.frames_encoded => {
+			let elapsed = now.duration_since(*time).as_secs_f64().max(f64::EPSILON);
+			(
+				((snapshot.bytes_sent - last.bytes_sent) as f64 * 8.0 / elapsed) as u64,
+				(snapshot.frames_encoded - last.frames_encoded) as f64 / elapsed,
+				snapshot.packets_lost.saturating_sub(last.packets_lost),
+			)
+		},
+		_ => (0, 0.0, 0),
+	};
+
+	let session = DashboardSession {
+		session_id: context.session_id,
+		client_id: context.client_id,
+		application: context.application.title,
+		resolution: context.resolution,
+		refresh_rate: context.refresh_rate,
+		video_encoder: snapshot.video_encoder.clone(),
+		bitrate,
+		fps,
+		encode_latency: snapshot.encode_latency.p50,
+		packets_lost,
+	};
+	*previous = Some((now, snapshot));
+
+	Some(session)
+}
+
+fn forbidden() -> Response<Full<Bytes>> {
+	Response::builder()
+		.status(StatusCode::FORBIDDEN)
+		.body(Full::new(Bytes::from("FORBIDDEN")))
+		.unwrap()
+}
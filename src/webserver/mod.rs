@@ -1,4 +1,4 @@
-use std::{collections::HashMap, convert::Infallible, net::{IpAddr, SocketAddr, ToSocketAddrs}, path::PathBuf, str::FromStr};
+use std::{convert::Infallible, net::{IpAddr, SocketAddr, ToSocketAddrs}, path::PathBuf, str::FromStr};
 
 use async_shutdown::ShutdownManager;
 use http_body_util::Full;
@@ -6,54 +6,91 @@ use hyper::{body::Bytes, header::{self, HeaderValue}, service::service_fn, Metho
 use hyper_util::rt::tokio::TokioIo;
 use image::ImageFormat;
 use network_interface::NetworkInterfaceConfig;
-use openssl::x509::X509;
 use tokio::net::TcpListener;
 
-use crate::{config::Config, clients::ClientManager, webserver::tls::TlsAcceptor, session::{manager::SessionManager, SessionContext, SessionKeys}};
+use crate::{app_scanner::ApplicationCatalog, config::{BandwidthCapAction, Config, H264Level}, crypto::{self, Certificate}, clients::{ClientManager, PinRecipient}, external_address::{self, ExternalAddress}, logging::Logs, webserver::tls::TlsAcceptor, session::{guests::{Guest, JoinError}, manager::{InitializeSessionError, SessionManager}, stream::EncoderCapabilities, ClientCapabilities, SessionContext, SessionId, SessionKeys}, transcript::{self, Transcript}};
 
-use self::pairing::handle_pair_request;
+use self::{dashboard::ResponseBody, pairing::handle_pair_request, params::QueryParams, xml::{XmlElements, XmlResponse}};
 
+mod api;
+mod dashboard;
 mod pairing;
+mod params;
 mod tls;
+#[cfg(feature = "webrtc")]
+mod whep;
+mod xml;
 
 // The negative fourth value is to indicate that we are following the protocol introduced with Sunshine.
 const SERVERINFO_APP_VERSION: &str = "7.1.431.-1";
 const SERVERINFO_GFE_VERSION: &str = "3.23.0.74";
 
+/// Endpoints that require a paired client, these are only served over HTTPS.
+const HTTPS_ONLY_PATHS: [&str; 5] = ["/applist", "/appasset", "/launch", "/resume", "/cancel"];
+
 #[derive(Clone)]
 pub struct Webserver {
 	config: Config,
+	applications: ApplicationCatalog,
 	unique_id: String,
 	client_manager: ClientManager,
 	session_manager: SessionManager,
-	server_certs: X509,
+	server_certs: Certificate,
+	encoder_capabilities: EncoderCapabilities,
+	external_address: ExternalAddress,
+	transcript: Transcript,
+	logs: Logs,
+	#[cfg(feature = "webrtc")]
+	whep_server: Option<crate::whep::WhepServer>,
 }
 
 impl Webserver {
-	#[allow(clippy::result_unit_err)]
+	#[allow(clippy::result_unit_err, clippy::too_many_arguments)]
 	pub fn new(
 		config: Config,
+		applications: ApplicationCatalog,
 		unique_id: String,
-		server_certs: X509,
+		server_certs: Certificate,
+		encoder_capabilities: EncoderCapabilities,
+		external_address: ExternalAddress,
 		client_manager: ClientManager,
 		session_manager: SessionManager,
+		transcript: Transcript,
+		logs: Logs,
+		runtime: tokio::runtime::Handle,
 		shutdown: ShutdownManager<i32>,
 	) -> Result<Self, ()> {
+		#[cfg(feature = "webrtc")]
+		let whep_server = config.webrtc.clone()
+			.map(|webrtc| crate::whep::WhepServer::new(webrtc, session_manager.clone()))
+			.transpose()?;
+		#[cfg(not(feature = "webrtc"))]
+		if config.webrtc.is_some() {
+			tracing::warn!("WebRTC is configured, but Moonshine was built without the 'webrtc' feature.");
+		}
+
 		let server = Self {
 			config: config.clone(),
+			applications,
 			unique_id,
 			client_manager,
 			session_manager,
 			server_certs,
+			encoder_capabilities,
+			external_address,
+			transcript,
+			logs,
+			#[cfg(feature = "webrtc")]
+			whep_server,
 		};
 
-		// Run HTTP webserver.
+		// Run HTTP webserver, connections are handled on the same runtime as the listener.
 		let http_address = (config.address.clone(), config.webserver.port).to_socket_addrs()
 			.map_err(|e| tracing::error!("Failed to resolve address '{}:{}': {e}", config.address, config.webserver.port))?
 			.next()
 			.ok_or_else(|| tracing::error!("Failed to resolve address '{}:{}'", config.address, config.webserver.port))?;
 
-		tokio::spawn({
+		runtime.spawn({
 			let server = server.clone();
 			let shutdown = shutdown.clone();
 
@@ -65,9 +102,14 @@ impl Webserver {
 
 					tracing::info!("HTTP server listening for connections on {http_address}");
 					loop {
-						let (connection, address) = listener.accept().await
+						let (connection, remote_address) = listener.accept().await
 							.map_err(|e| tracing::error!("Failed to accept connection: {e}"))?;
-						tracing::trace!("Accepted connection from {address}.");
+						tracing::trace!("Accepted connection from {remote_address}.");
+
+						if !server.config.network.is_allowed(remote_address.ip()) {
+							tracing::warn!("Rejecting connection from {remote_address}, address is not in an allowed subnet.");
+							continue;
+						}
 
 						let address = connection.local_addr().ok();
 						let mac_address = if let Some(address) = address {
@@ -83,7 +125,7 @@ impl Webserver {
 							async move {
 								let _ = hyper::server::conn::http1::Builder::new()
 									.serve_connection(io, service_fn(|request| {
-										server.serve(request, address, mac_address.clone(), false)
+										server.serve(request, remote_address, address, mac_address.clone(), false, None)
 									})).await;
 							}
 						});
@@ -104,7 +146,7 @@ impl Webserver {
 			.next()
 			.ok_or_else(|| tracing::error!("Failed to resolve address '{}:{}'", config.address, config.webserver.port_https))?;
 
-		tokio::spawn({
+		runtime.spawn({
 			let server = server.clone();
 			async move {
 				let _ = shutdown.wrap_cancel(shutdown.wrap_trigger_shutdown(2, async move {
@@ -114,9 +156,14 @@ impl Webserver {
 
 					tracing::info!("HTTPS server listening for connections on {https_address}");
 					loop {
-						let (connection, address) = listener.accept().await
+						let (connection, remote_address) = listener.accept().await
 							.map_err(|e| tracing::error!("Failed to accept connection: {e}"))?;
-						tracing::trace!("Accepted TLS connection from {address}.");
+						tracing::trace!("Accepted TLS connection from {remote_address}.");
+
+						if !server.config.network.is_allowed(remote_address.ip()) {
+							tracing::warn!("Rejecting connection from {remote_address}, address is not in an allowed subnet.");
+							continue;
+						}
 
 						let address = connection.local_addr().ok();
 						let mac_address = if let Some(address) = address {
@@ -125,8 +172,8 @@ impl Webserver {
 							None
 						};
 
-						let connection = match acceptor.accept(connection).await {
-							Ok(connection) => connection,
+						let (connection, client_certificate) = match acceptor.accept(connection).await {
+							Ok(accepted) => accepted,
 							Err(()) => continue,
 						};
 
@@ -137,7 +184,7 @@ impl Webserver {
 							async move {
 								let _ = hyper::server::conn::http1::Builder::new()
 									.serve_connection(io, service_fn(|request| {
-										server.serve(request, address, mac_address.clone(), true)
+										server.serve(request, remote_address, address, mac_address.clone(), true, client_certificate.clone())
 									})).await;
 							}
 						});
@@ -158,33 +205,49 @@ impl Webserver {
 	async fn serve(
 		&self,
 		request: Request<hyper::body::Incoming>,
+		remote_address: SocketAddr,
 		local_address: Option<SocketAddr>,
 		mac_address: Option<String>,
 		https: bool,
-	) -> Result<Response<Full<Bytes>>, Infallible> {
-		let params = request.uri()
-			.query()
-			.map(|v| {
-				url::form_urlencoded::parse(v.as_bytes())
-					.into_owned()
-					.collect()
-			})
-			.unwrap_or_default();
+		client_certificate: Option<String>,
+	) -> Result<Response<ResponseBody>, Infallible> {
+		let params = QueryParams::from_uri(request.uri());
 
 		tracing::info!("Received {} request for {}.", request.method(), request.uri().path());
+		// Every launch starts a new transcript, so that it contains the entire session.
+		if request.uri().path() == "/launch" {
+			self.transcript.begin_session();
+		}
+		let transcript_request = format!(
+			"{remote_address} {} {}?{}{}",
+			request.method(),
+			request.uri().path(),
+			transcript::redact_query(request.uri().query().unwrap_or_default()),
+			if https { " (https)" } else { "" },
+		);
+
+		// Dashboard events are streamed for as long as the dashboard is open.
+		if !https && request.method() == Method::GET && request.uri().path() == "/dashboard/events" {
+			let response = self.dashboard_events(remote_address);
+			self.transcript.record("http", format_args!("{transcript_request} -> {}", response.status()));
+			return Ok(response);
+		}
 
 		let response = if https {
 			match (request.method(), request.uri().path()) {
-				(&Method::GET, "/serverinfo") => self.server_info(params, mac_address, https).await,
+				(&Method::GET, "/serverinfo") => self.server_info(remote_address, local_address, mac_address, https, client_certificate.as_deref()).await,
 				(&Method::GET, "/applist") => self.app_list(),
 				(&Method::GET, "/appasset") => self.app_asset(params),
 				(&Method::GET, "/pair") => {
-					handle_pair_request(request, params, local_address, &self.server_certs, &self.client_manager).await
+					handle_pair_request(request, params, remote_address, local_address, &self.server_certs, &self.client_manager, &self.config.webserver.pairing).await
 				}
 				// (&Method::GET, "/unpair") => self.unpair(params).await,
-				(&Method::GET, "/launch") => self.launch(params).await,
-				(&Method::GET, "/resume") => self.resume(params).await,
-				(&Method::GET, "/cancel") => self.cancel().await,
+				(&Method::GET, "/launch") => self.launch(params, remote_address, local_address, client_certificate.as_deref()).await,
+				(&Method::GET, "/resume") => self.resume(params, remote_address, local_address, client_certificate.as_deref()).await,
+				(&Method::GET, "/cancel") => self.cancel(client_certificate.as_deref()).await,
+				// Offers carry the Bearer token and the SDP of the viewer, so WebRTC is only served over TLS.
+				#[cfg(feature = "webrtc")]
+				(_, uri) if uri.starts_with("/webrtc") => self.whep(request).await,
 				(method, uri) => {
 					tracing::warn!("Unhandled {method} request with URI '{uri}'");
 					not_found()
@@ -192,12 +255,20 @@ impl Webserver {
 			}
 		} else {
 			match (request.method(), request.uri().path()) {
-				(&Method::GET, "/serverinfo") => self.server_info(params, mac_address, https).await,
+				(&Method::GET, "/serverinfo") => self.server_info(remote_address, local_address, mac_address, https, None).await,
 				(&Method::GET, "/pair") => {
-					handle_pair_request(request, params, local_address, &self.server_certs, &self.client_manager).await
+					handle_pair_request(request, params, remote_address, local_address, &self.server_certs, &self.client_manager, &self.config.webserver.pairing).await
 				}
 				(&Method::GET, "/pin") => self.pin().await,
+				(&Method::GET, "/history") => self.history().await,
+				(&Method::GET, "/dashboard") => self.dashboard(remote_address),
+				(&Method::GET, "/dashboard/snapshot") => self.dashboard_snapshot(params, remote_address).await,
 				(&Method::GET, "/submit-pin") => self.submit_pin(params).await,
+				(&Method::GET, "/pairing-pin") => self.pairing_pin(params).await,
+				(&Method::GET, "/fingerprint") => self.fingerprint(),
+				(&Method::GET, path) if HTTPS_ONLY_PATHS.contains(&path) => self.redirect_to_https(&request, local_address),
+				(_, uri) if uri.starts_with("/webrtc") => self.redirect_to_https(&request, local_address),
+				(_, uri) if uri.starts_with("/api/") => self.api(request, remote_address).await,
 				(method, uri) => {
 					tracing::warn!("Unhandled {method} request with URI '{uri}'");
 					not_found()
@@ -205,48 +276,33 @@ impl Webserver {
 			}
 		};
 
-		Ok(response)
+		self.transcript.record("http", format_args!("{transcript_request} -> {}", response.status()));
+
+		Ok(response.map(ResponseBody::Full))
 	}
 
 	fn app_list(&self) -> Response<Full<Bytes>> {
-		let mut response = "<root status_code=\"200\">".to_string();
-		for application in self.config.applications.iter() {
-			response += "<App>";
-
-			// TODO: Fix HDR support.
-			response += "<IsHdrSupported>0</IsHdrSupported>";
-			response += format!("<AppTitle>{}</AppTitle>", application.title).as_ref();
-			response += format!("<ID>{}</ID>", application.id()).as_ref();
-
-			response += "</App>";
+		let mut response = XmlResponse::ok();
+		for application in self.applications.list().iter() {
+			let app = XmlElements::new()
+				// TODO: Fix HDR support.
+				.element("IsHdrSupported", 0)
+				.element("AppTitle", &application.title)
+				.element("ID", application.id());
+
+			response = response.parent("App", app);
 		}
 
-		response += "</root>";
-
-		let mut response = Response::new(Full::new(Bytes::from(response)));
-		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
-		response
+		response.build()
 	}
 
-	fn app_asset(&self, mut params: HashMap<String, String>) -> Response<Full<Bytes>> {
-		let application_id = match params.remove("appid") {
-			Some(application_id) => application_id,
-			None => {
-				let message = format!("Expected 'appasset' in launch request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
-		};
-		let application_id: i32 = match application_id.parse() {
+	fn app_asset(&self, params: QueryParams) -> Response<Full<Bytes>> {
+		let application_id: i32 = match params.required("appid") {
 			Ok(application_id) => application_id,
-			Err(e) => {
-				let message = format!("Failed to parse application ID: {e}");
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+			Err(response) => return response,
 		};
 
-		let application = match self.config.applications.iter().find(|&a| a.id() == application_id) {
+		let application = match self.applications.find(application_id) {
 			Some(application) => application,
 			None => {
 				let message = format!("Couldn't find application with ID {}.", application_id - 1);
@@ -304,19 +360,12 @@ impl Webserver {
 
 	async fn server_info(
 		&self,
-		params: HashMap<String, String>,
+		remote_address: SocketAddr,
+		local_address: Option<SocketAddr>,
 		mac_address: Option<String>,
 		https: bool,
+		client_certificate: Option<&str>,
 	) -> Response<Full<Bytes>> {
-		let unique_id = match params.get("uniqueid") {
-			Some(unique_id) => unique_id.clone(),
-			None => {
-				let message = format!("Expected 'uniqueid' in /serverinfo request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
-		};
-
 		let session_context = match self.session_manager.get_session_context().await {
 			Ok(session_context) => session_context,
 			Err(()) => {
@@ -327,35 +376,80 @@ impl Webserver {
 		};
 
 		// Seems we should only say we paired when using HTTPS.
-		let paired = if https {
-			if self.client_manager.is_paired(unique_id).await.unwrap_or(false) {
-				"1"
-			} else {
-				"0"
-			}
-		} else { "0" };
+		// A client is paired when it presents the certificate it paired with, every Moonlight client sends the same unique id.
+		let paired = https && match client_certificate {
+			Some(client_certificate) => match self.client_manager.client_seen(client_certificate).await {
+				Ok(Some(client)) => {
+					tracing::debug!("Client '{}' is paired.", client.display_name().unwrap_or(client_certificate));
+					true
+				},
+				Ok(None) | Err(()) => false,
+			},
+			None => false,
+		};
 
 		// TODO: Check the use of some of these values, we leave most of them blank and Moonlight doesn't care.
-		let mut response = "<root status_code=\"200\">".to_string();
-		response += &format!("<hostname>{}</hostname>", self.config.name);
-		response += &format!("<appversion>{}</appversion>", SERVERINFO_APP_VERSION);
-		response += &format!("<GfeVersion>{}</GfeVersion>", SERVERINFO_GFE_VERSION);
-		response += &format!("<uniqueid>{}</uniqueid>", self.unique_id);
-		response += &format!("<HttpsPort>{}</HttpsPort>", self.config.webserver.port_https);
-		response += "<ExternalPort></ExternalPort>";
-		response += &format!("<mac>{}</mac>", mac_address.unwrap_or("".to_string()));
-		response += "<MaxLumaPixelsHEVC>1869449984</MaxLumaPixelsHEVC>"; // TODO: Check if HEVC is supported, set this to 0 if it is not.
-		response += "<LocalIP></LocalIP>";
-		response += "<ServerCodecModeSupport>259</ServerCodecModeSupport>";
-		response += "<SupportedDisplayMode></SupportedDisplayMode>";
-		response += &format!("<PairStatus>{paired}</PairStatus>");
-		response += &format!("<currentgame>{}</currentgame>", session_context.clone().map(|s| s.application_id).unwrap_or(0));
-		response += &format!("<state>{}</state>", session_context.map(|_| "MOONSHINE_SERVER_BUSY").unwrap_or("MOONSHINE_SERVER_FREE"));
-		response += "</root>";
-
-		let mut response = Response::new(Full::new(Bytes::from(response)));
-		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
-		response
+		// Unauthenticated clients don't get to know what is running, and only the client that launched the app can resume it.
+		// That client is recognized by its certificate, other Moonlight clients send the same unique id.
+		let current_game = session_context.as_ref()
+			.filter(|context| paired && client_certificate.is_some_and(|client_certificate| context.is_owned_by(client_certificate)))
+			.map(|context| context.application_id)
+			.unwrap_or(0);
+		let current_client = session_context.as_ref()
+			.filter(|_| paired)
+			.map(|context| context.owner_name().to_string())
+			.unwrap_or_default();
+
+		// Clients outside the local network can only reach the host on its external address.
+		let external_address = self.external_address.get()
+			.filter(|_| local_address.is_some_and(|local_address| external_address::is_external(remote_address.ip(), local_address.ip())));
+
+		XmlResponse::ok()
+			.element("hostname", &self.config.name)
+			.element("appversion", SERVERINFO_APP_VERSION)
+			.element("GfeVersion", SERVERINFO_GFE_VERSION)
+			.element("uniqueid", &self.unique_id)
+			.element("HttpsPort", self.config.webserver.port_https)
+			.element("ExternalIP", external_address.map(|address| address.to_string()).unwrap_or_default())
+			.element("ExternalPort", if external_address.is_some() { self.config.webserver.port.to_string() } else { String::new() })
+			.element("mac", mac_address.unwrap_or_default())
+			.element("MaxLumaPixelsHEVC", if self.encoder_capabilities.hevc { 1869449984 } else { 0 })
+			.element("LocalIP", local_address.map(|address| address.ip().to_string()).unwrap_or_default())
+			.element("ServerCodecModeSupport", self.encoder_capabilities.codec_mode_support())
+			.element("SupportedDisplayMode", "")
+			.element("PairStatus", if paired { "1" } else { "0" })
+			.element("currentgame", current_game)
+			.element("currentclient", current_client)
+			.element("state", session_context.map(|_| "MOONSHINE_SERVER_BUSY").unwrap_or("MOONSHINE_SERVER_FREE"))
+			.build()
+	}
+
+	/// Redirect a request for an endpoint that requires a paired client to the HTTPS server.
+	fn redirect_to_https(
+		&self,
+		request: &Request<hyper::body::Incoming>,
+		local_address: Option<SocketAddr>,
+	) -> Response<Full<Bytes>> {
+		let port = self.config.webserver.port_https;
+		let authority = request.headers().get(header::HOST)
+			.and_then(|host| host.to_str().ok())
+			.and_then(|host| host.parse::<hyper::http::uri::Authority>().ok())
+			.map(|authority| format!("{}:{port}", authority.host()))
+			.or_else(|| local_address.map(|address| SocketAddr::new(address.ip(), port).to_string()));
+		let Some(authority) = authority else {
+			tracing::warn!("Can't redirect {} to HTTPS, the host address is unknown.", request.uri().path());
+			return not_found();
+		};
+
+		let path = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+		let location = format!("https://{authority}{path}");
+		tracing::debug!("Redirecting {} to {location}.", request.uri().path());
+
+		Response::builder()
+			.status(StatusCode::TEMPORARY_REDIRECT)
+			.header(header::LOCATION, location)
+			.body(Full::new(Bytes::new()))
+			.unwrap()
 	}
 
 	async fn pin(
@@ -368,33 +462,47 @@ impl Webserver {
 		response
 	}
 
+	/// Page that shows the session history, it uses the API so it only works on the host itself.
+	async fn history(
+		&self,
+	) -> Response<Full<Bytes>> {
+		let content = include_bytes!("../../assets/history.html");
+		let mut response = Response::new(Full::new(Bytes::from_static(content)));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=UTF-8"));
+
+		response
+	}
+
 	async fn submit_pin(
 		&self,
-		params: HashMap<String, String>,
+		params: QueryParams,
 	) -> Response<Full<Bytes>> {
-		let unique_id = match params.get("uniqueid") {
-			Some(unique_id) => unique_id,
-			None => {
-				let message = format!("Expected 'uniqueid' in pin request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		// The PIN page uses the token from the link that was shown when pairing started, scripts use the unique id.
+		let token: Option<String> = match params.optional("token") {
+			Ok(token) => token,
+			Err(response) => return response,
+		};
+		let recipient = match token {
+			Some(token) => PinRecipient::Token(token),
+			None => match params.required("uniqueid") {
+				Ok(unique_id) => PinRecipient::Id(unique_id),
+				Err(response) => return response,
+			},
 		};
-
-		let pin = match params.get("pin") {
-			Some(pin) => pin,
-			None => {
-				let message = format!("Expected 'pin' in pin request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		let pin: String = match params.required("pin") {
+			Ok(pin) => pin,
+			Err(response) => return response,
 		};
 
-		let response = self.client_manager.register_pin(unique_id, pin).await;
+		let message = match &recipient {
+			PinRecipient::Id(unique_id) => format!("Successfully received pin '{pin}' for unique id '{unique_id}'."),
+			PinRecipient::Token(_) => format!("Successfully received pin '{pin}'."),
+		};
+		let response = self.client_manager.register_pin(recipient, &pin).await;
 		match response {
 			Ok(()) =>
 				match Response::builder().status(StatusCode::OK)
-					.body(Full::new(Bytes::from(format!("Successfully received pin '{}' for unique id '{}'.", pin, unique_id))))
+					.body(Full::new(Bytes::from(message)))
 				{
 					Ok(response) => response,
 					Err(e) => {
@@ -408,6 +516,35 @@ impl Webserver {
 		}
 	}
 
+	/// The PIN the server generated for a pairing attempt, for the PIN page that was opened through the pairing link.
+	async fn pairing_pin(
+		&self,
+		params: QueryParams,
+	) -> Response<Full<Bytes>> {
+		let token: String = match params.required("token") {
+			Ok(token) => token,
+			Err(response) => return response,
+		};
+
+		match self.client_manager.get_server_pin(&token).await {
+			Ok(Some(pin)) => Response::new(Full::new(Bytes::from(pin))),
+			Ok(None) => not_found(),
+			Err(()) => bad_request("Failed to retrieve pairing PIN".to_string()),
+		}
+	}
+
+	/// Fingerprint of the server certificate, shown on the PIN page.
+	fn fingerprint(&self) -> Response<Full<Bytes>> {
+		match crate::crypto::certificate_fingerprint(&self.server_certs) {
+			Ok(fingerprint) => Response::new(Full::new(Bytes::from(fingerprint))),
+			Err(e) => {
+				let message = format!("Failed to compute server certificate fingerprint: {e}");
+				tracing::warn!("{message}");
+				bad_request(message)
+			},
+		}
+	}
+
 	// This is disabled, because all moonlight clients seem to share the same uniqueid.
 	// This means that if we 'unpair', we unpair all moonlight clients.
 	// TODO: Collaborate with moonlight to give clients a truly unique ID.
@@ -436,243 +573,365 @@ impl Webserver {
 
 	async fn launch(
 		&self,
-		mut params: HashMap<String, String>,
+		params: QueryParams,
+		remote_address: SocketAddr,
+		local_address: Option<SocketAddr>,
+		client_certificate: Option<&str>,
 	) -> Response<Full<Bytes>> {
-		let unique_id = match params.remove("uniqueid") {
-			Some(unique_id) => unique_id,
-			None => {
-				let message = format!("Expected 'uniqueid' in launch request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		// A paired client presents the certificate it paired with, the session belongs to the device with that certificate.
+		let Some(client_certificate) = client_certificate else {
+			return LaunchError::NotPaired.into_response();
 		};
-
-		match self.client_manager.is_paired(unique_id).await {
-			Ok(paired) => paired,
+		let client = match self.client_manager.client_seen(client_certificate).await {
+			Ok(Some(client)) => client,
+			Ok(None) => return LaunchError::NotPaired.into_response(),
 			Err(()) => return bad_request("Failed to check client paired status".to_string()),
 		};
 
-		let application_id = match params.remove("appid") {
-			Some(application_id) => application_id,
-			None => {
-				let message = format!("Expected 'appid' in launch request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
-		};
-		let application_id: i32 = match application_id.parse() {
+		let application_id: i32 = match params.required("appid") {
 			Ok(application_id) => application_id,
-			Err(e) => {
-				let message = format!("Failed to parse application ID: {e}");
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+			Err(response) => return response,
 		};
-
-		let mode = match params.remove("mode") {
-			Some(mode) => mode,
-			None => {
-				let message = format!("Expected 'mode' in launch request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		let DisplayMode { width, height, refresh_rate } = match params.required("mode") {
+			Ok(mode) => mode,
+			Err(response) => return response,
 		};
-		let mode_parts: Vec<&str> = mode.split('x').collect();
-		if mode_parts.len() != 3 {
-			let message = format!("Expected mode in format WxHxR, but got '{mode}'.");
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
-		let width: u32 = match mode_parts[0].parse() {
-			Ok(width) => width,
-			Err(e) => {
-				let message = format!("Failed to parse width: {e}");
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		let remote_input_key = match params.required_hex("rikey") {
+			Ok(remote_input_key) => remote_input_key,
+			Err(response) => return response,
 		};
-		let height: u32 = match mode_parts[1].parse() {
-			Ok(height) => height,
-			Err(e) => {
-				let message = format!("Failed to parse height: {e}");
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		let remote_input_key_id: i64 = match params.required("rikeyid") {
+			Ok(remote_input_key_id) => remote_input_key_id,
+			Err(response) => return response,
 		};
-		let refresh_rate: u32 = match mode_parts[2].parse() {
-			Ok(refresh_rate) => refresh_rate,
-			Err(e) => {
-				let message = format!("Failed to parse refresh rate: {e}");
+		let keys = match SessionKeys::from_launch_parameters(&remote_input_key, remote_input_key_id) {
+			Ok(keys) => keys,
+			Err(message) => {
 				tracing::warn!("{message}");
 				return bad_request(message);
-			}
+			},
 		};
 
-		let remote_input_key = match params.remove("rikey") {
-			Some(remote_input_key) => remote_input_key,
-			None => {
-				let message = format!("Expected 'rikey' in launch request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
-		};
-		let remote_input_key = match hex::decode(remote_input_key) {
-			Ok(remote_input_key) => remote_input_key,
-			Err(e) => {
-				let message = format!("Failed to decode remote input key: {e}");
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		let application = match self.applications.find(application_id) {
+			Some(application) => application,
+			None => return LaunchError::UnknownApplication(application_id).into_response(),
 		};
 
-		let remote_input_key_id: String = match params.remove("rikeyid") {
-			Some(remote_input_key_id) => remote_input_key_id,
-			None => {
-				let message = format!("Expected 'rikey_id' in launch request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
+		if !self.encoder_capabilities.any() {
+			return LaunchError::NoEncoder.into_response();
+		}
+		if !self.encoder_capabilities.supports_resolution(width, height) || refresh_rate == 0 {
+			return LaunchError::UnsupportedMode { width, height, refresh_rate }.into_response();
+		}
+
+		// An H.264 stream above the level of the decoder can't be decoded, refusing the launch at least tells the user why.
+		if let Some(max_level) = self.config.stream.video.h264_decoder_for(client_certificate).max_level {
+			let required_level = H264Level::required(width, height, refresh_rate);
+			if required_level.map_or(true, |required_level| required_level > max_level) {
+				return LaunchError::UnsupportedLevel { width, height, refresh_rate, max_level, required_level }.into_response();
 			}
+		}
+
+		if let Err(response) = self.check_bandwidth_cap().await {
+			return response;
+		}
+
+		let surround_audio_info = match params.optional("surroundAudioInfo") {
+			Ok(surround_audio_info) => surround_audio_info,
+			Err(response) => return response,
 		};
-		let remote_input_key_id: i64 = match remote_input_key_id.parse() {
-			Ok(remote_input_key_id) => remote_input_key_id,
-			Err(e) => {
-				let message = format!("Couldn't parse 'rikey_id' in launch request, got '{remote_input_key_id}' with error: {e}");
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		let hdr_mode = match params.optional("hdrMode") {
+			Ok(hdr_mode) => hdr_mode,
+			Err(response) => return response,
 		};
+		let local_audio_play_mode = match params.optional("localAudioPlayMode") {
+			Ok(local_audio_play_mode) => local_audio_play_mode,
+			Err(response) => return response,
+		};
+		let core_version = match params.optional("corever") {
+			Ok(core_version) => core_version,
+			Err(response) => return response,
+		};
+		let capabilities = ClientCapabilities::from_launch_parameters(surround_audio_info, hdr_mode, local_audio_play_mode, core_version);
+		let encrypted_rtsp = capabilities.encrypted_rtsp;
 
-		let application = match self.config.applications.iter().find(|&a| a.id() == application_id) {
-			Some(application) => application,
-			None => {
-				let message = format!("Couldn't find application with ID {}.", application_id - 1);
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		let ping_payload = match crypto::random_bytes::<8>() {
+			Ok(ping_payload) => hex::encode(ping_payload),
+			Err(e) => {
+				tracing::error!("Failed to create ping payload: {e}");
+				return LaunchError::Failed.into_response();
+			},
 		};
 
 		let initialize_result = self.session_manager.initialize_session(SessionContext {
-			application: application.clone(),
+			application,
 			application_id,
 			resolution: (width, height),
 			refresh_rate,
-			keys: SessionKeys {
-				remote_input_key,
-				remote_input_key_id,
-			}
+			keys,
+			session_id: SessionId::new(),
+			client_id: client_certificate.to_string().into(),
+			client_name: client.display_name().map(|name| name.to_string()),
+			capabilities,
+			ping_payload,
 		}).await;
 
-		if initialize_result.is_err() {
-			return bad_request("Failed to start session".to_string());
+		match initialize_result {
+			Ok(()) => {},
+			Err(InitializeSessionError::SessionActive(application, session_id)) => return LaunchError::SessionActive(application, session_id).into_response(),
+			Err(InitializeSessionError::Failed) => return LaunchError::Failed.into_response(),
 		}
 
-		let mut response = "<root status_code=\"200\">".to_string();
-		response += "<gamesession>1</gamesession>";
-		response += "</root>";
+		let mut response = XmlResponse::ok();
+		if let Some(session_url) = self.session_url(remote_address, local_address, encrypted_rtsp) {
+			response = response.element("sessionUrl0", session_url);
+		}
+		response
+			.element("gamesession", 1)
+			.build()
+	}
+
+	/// URL of the RTSP server, at an address that the client can reach.
+	///
+	/// The `rtspenc` scheme tells the client to encrypt its RTSP messages.
+	fn session_url(&self, remote_address: SocketAddr, local_address: Option<SocketAddr>, encrypted_rtsp: bool) -> Option<String> {
+		let address = self.external_address.for_client(remote_address.ip(), local_address?.ip());
+		let scheme = if encrypted_rtsp { "rtspenc" } else { "rtsp" };
+		Some(format!("{scheme}://{}", SocketAddr::new(address, self.config.stream.port)))
+	}
 
-		// TODO: Return sessionUrl0.
+	/// Warn about or refuse a new session if the monthly bandwidth cap is reached.
+	async fn check_bandwidth_cap(&self) -> Result<(), Response<Full<Bytes>>> {
+		let Some(bandwidth_cap) = &self.config.bandwidth_cap else {
+			return Ok(());
+		};
+		let Ok(usage) = self.session_manager.get_bandwidth_usage().await else {
+			return Ok(());
+		};
 
-		let mut response = Response::new(Full::new(Bytes::from(response)));
-		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
+		let limit = bandwidth_cap.monthly_limit.saturating_mul(1_000_000_000);
+		if usage.bytes_sent < limit {
+			return Ok(());
+		}
 
-		response
+		let message = format!(
+			"Streamed {:.1} GB in {}, which exceeds the monthly limit of {} GB.",
+			usage.bytes_sent as f64 / 1e9,
+			usage.month,
+			bandwidth_cap.monthly_limit,
+		);
+		match bandwidth_cap.action {
+			BandwidthCapAction::Warn => {
+				tracing::warn!("{message}");
+				Ok(())
+			},
+			BandwidthCapAction::Refuse => {
+				tracing::warn!("{message} Refusing to start a new session.");
+				Err(service_unavailable(message))
+			},
+		}
 	}
 
 	async fn resume(
 		&self,
-		mut params: HashMap<String, String>,
+		params: QueryParams,
+		remote_address: SocketAddr,
+		local_address: Option<SocketAddr>,
+		client_certificate: Option<&str>,
 	) -> Response<Full<Bytes>> {
-		let unique_id = match params.remove("uniqueid") {
-			Some(unique_id) => unique_id,
-			None => {
-				let message = format!("Expected 'uniqueid' in resume request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		// Guests have to be paired as well, with their own certificate.
+		let Some(client_certificate) = client_certificate else {
+			return LaunchError::NotPaired.into_response();
 		};
-
-		match self.client_manager.is_paired(unique_id).await {
-			Ok(paired) => paired,
+		let client = match self.client_manager.client_seen(client_certificate).await {
+			Ok(Some(client)) => client,
+			Ok(None) => return LaunchError::NotPaired.into_response(),
 			Err(()) => return bad_request("Failed to check client paired status".to_string()),
 		};
 
-		let remote_input_key = match params.remove("rikey") {
-			Some(remote_input_key) => remote_input_key,
-			None => {
-				let message = format!("Expected 'rikey' in resume request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
-		};
-		let remote_input_key = match hex::decode(remote_input_key) {
-			Ok(remote_input_key) => remote_input_key,
-			Err(e) => {
-				let message = format!("Failed to decode remote input key: {e}");
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		// The RTSP server decides on encryption by the session, not by the client that resumes it.
+		// Other clients can join the session as guests, if the host allows it. They share the unique id of the client that
+		// launched the session, only their certificate tells them apart.
+		let (encrypted_rtsp, is_guest) = match self.session_manager.get_session_context().await {
+			Ok(Some(context)) if !context.is_owned_by(client_certificate) => {
+				if self.config.coop.max_guests == 0 {
+					return LaunchError::NotOwner(context.owner_name().to_string()).into_response();
+				}
+				(context.capabilities.encrypted_rtsp, true)
+			},
+			Ok(Some(context)) => (context.capabilities.encrypted_rtsp, false),
+			Ok(None) => return LaunchError::NoSession.into_response(),
+			Err(()) => return LaunchError::Failed.into_response(),
 		};
 
-		let remote_input_key_id: String = match params.remove("rikeyid") {
-			Some(remote_input_key_id) => remote_input_key_id,
-			None => {
-				let message = format!("Expected 'rikey_id' in resume request, got {:?}.", params.keys());
-				tracing::warn!("{message}");
-				return bad_request(message);
-			}
+		let remote_input_key = match params.required_hex("rikey") {
+			Ok(remote_input_key) => remote_input_key,
+			Err(response) => return response,
 		};
-		let remote_input_key_id: i64 = match remote_input_key_id.parse() {
+		let remote_input_key_id: i64 = match params.required("rikeyid") {
 			Ok(remote_input_key_id) => remote_input_key_id,
-			Err(e) => {
-				let message = format!("Couldn't parse 'rikey_id' in resume request, got '{remote_input_key_id}' with error: {e}");
+			Err(response) => return response,
+		};
+
+		let keys = match SessionKeys::from_launch_parameters(&remote_input_key, remote_input_key_id) {
+			Ok(keys) => keys,
+			Err(message) => {
 				tracing::warn!("{message}");
 				return bad_request(message);
-			}
+			},
 		};
 
-		let update_result = self.session_manager.update_keys(SessionKeys {
-			remote_input_key,
-			remote_input_key_id,
-		}).await;
-		if update_result.is_err() {
-			return bad_request("Failed to update session keys".to_string());
+		if is_guest {
+			let guests = match self.session_manager.get_guests().await {
+				Ok(Some(guests)) => guests,
+				Ok(None) => return LaunchError::NoSession.into_response(),
+				Err(()) => return LaunchError::Failed.into_response(),
+			};
+			let guest = Guest {
+				client_id: client_certificate.to_string(),
+				client_name: client.display_name().map(|name| name.to_string()),
+				address: remote_address.ip(),
+				keys,
+			};
+			match guests.join(guest, self.config.coop.max_guests) {
+				Ok(()) => {},
+				Err(JoinError::Full(max_guests)) => return LaunchError::GuestsFull(max_guests).into_response(),
+				Err(JoinError::Failed) => return LaunchError::Failed.into_response(),
+			}
+		} else {
+			let update_result = self.session_manager.update_keys(keys).await;
+			if update_result.is_err() {
+				return bad_request("Failed to update session keys".to_string());
+			}
 		}
 
-		let mut response = "<root status_code=\"200\">".to_string();
-
-		// TODO: Return sessionUrl0.
-
-		response += "<resume>1</resume>";
-		response += "</root>";
-
-		let mut response = Response::new(Full::new(Bytes::from(response)));
-		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
-
+		let mut response = XmlResponse::ok();
+		if let Some(session_url) = self.session_url(remote_address, local_address, encrypted_rtsp) {
+			response = response.element("sessionUrl0", session_url);
+		}
 		response
+			.element("resume", 1)
+			.build()
 	}
 
-	async fn cancel(&self) -> Response<Full<Bytes>> {
+	async fn cancel(&self, client_certificate: Option<&str>) -> Response<Full<Bytes>> {
+		// Another client with the same unique id must not quit the session of the client that launched it.
+		let Some(client_certificate) = client_certificate else {
+			return LaunchError::NotPaired.into_response();
+		};
+		match self.client_manager.client_seen(client_certificate).await {
+			Ok(Some(_)) => {},
+			Ok(None) => return LaunchError::NotPaired.into_response(),
+			Err(()) => return bad_request("Failed to check client paired status".to_string()),
+		};
+
+		match self.session_manager.get_session_context().await {
+			Ok(Some(context)) if !context.is_owned_by(client_certificate) => return LaunchError::NotOwner(context.owner_name().to_string()).into_response(),
+			Ok(_) => {},
+			Err(()) => return LaunchError::Failed.into_response(),
+		}
+
 		if self.session_manager.stop_session().await.is_err() {
 			let message = "Failed to stop session".to_string();
 			tracing::warn!("{message}");
 			return bad_request(message);
 		}
 
-		let mut response = "<root status_code=\"200\">".to_string();
-		response += "<cancel>1</cancel>";
-		response += "</root>";
+		XmlResponse::ok()
+			.element("cancel", 1)
+			.build()
+	}
+}
 
-		let mut response = Response::new(Full::new(Bytes::from(response)));
-		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
-		response
+/// Reasons to refuse a launch, resume or cancel request.
+///
+/// Moonlight shows the status message of the response to the user, together with the status code.
+/// The codes follow those of GameStream and Sunshine, so clients can tell these failures apart.
+enum LaunchError {
+	NotPaired,
+	UnknownApplication(i32),
+	NoEncoder,
+	UnsupportedMode { width: u32, height: u32, refresh_rate: u32 },
+	UnsupportedLevel { width: u32, height: u32, refresh_rate: u32, max_level: H264Level, required_level: Option<H264Level> },
+	SessionActive(String, SessionId),
+	NotOwner(String),
+	GuestsFull(usize),
+	NoSession,
+	Failed,
+}
+
+impl LaunchError {
+	fn status_code(&self) -> u16 {
+		match self {
+			Self::NotPaired => 401,
+			Self::UnknownApplication(_) => 404,
+			Self::SessionActive(..) | Self::NotOwner(_) | Self::GuestsFull(_) => 400,
+			Self::NoEncoder | Self::UnsupportedMode { .. } | Self::UnsupportedLevel { .. } | Self::NoSession | Self::Failed => 503,
+		}
+	}
+
+	fn message(&self) -> String {
+		match self {
+			Self::NotPaired => "The client is not paired with this host.".to_string(),
+			Self::UnknownApplication(application_id) => format!("Cannot find requested application with ID {}.", application_id - 1),
+			Self::NoEncoder => "No working video encoder is available on the host.".to_string(),
+			Self::UnsupportedMode { width, height, refresh_rate } =>
+				format!("The host can't stream at {width}x{height} with {refresh_rate} FPS."),
+			Self::UnsupportedLevel { width, height, refresh_rate, max_level, required_level } => match required_level {
+				Some(required_level) => format!(
+					"{width}x{height} with {refresh_rate} FPS needs H.264 level {}, the client decodes up to level {}.",
+					required_level.name(), max_level.name(),
+				),
+				None => format!("{width}x{height} with {refresh_rate} FPS exceeds every H.264 level, the client decodes up to level {}.", max_level.name()),
+			},
+			Self::SessionActive(application, session_id) =>
+				format!("An app is already running on this host ('{application}', session {session_id}), quit it before launching another."),
+			Self::NotOwner(owner) => format!("The running app was launched by '{owner}', only that client can resume or quit it."),
+			Self::GuestsFull(max_guests) => format!("The running app already has {max_guests} guests, no more can join."),
+			Self::NoSession => "No running app to resume.".to_string(),
+			Self::Failed => "Failed to start the specified application.".to_string(),
+		}
+	}
+
+	fn into_response(self) -> Response<Full<Bytes>> {
+		let message = self.message();
+		tracing::warn!("{message}");
+		XmlResponse::error(self.status_code(), message).build()
+	}
+}
+
+/// Display mode requested by a client, in the format `<width>x<height>x<refresh rate>`.
+struct DisplayMode {
+	width: u32,
+	height: u32,
+	refresh_rate: u32,
+}
+
+impl FromStr for DisplayMode {
+	type Err = String;
+
+	fn from_str(mode: &str) -> Result<Self, Self::Err> {
+		let parts: Vec<&str> = mode.split('x').collect();
+		let [width, height, refresh_rate] = parts[..] else {
+			return Err(format!("expected mode in format WxHxR, but got '{mode}'"));
+		};
+
+		Ok(Self {
+			width: width.parse().map_err(|e| format!("failed to parse width: {e}"))?,
+			height: height.parse().map_err(|e| format!("failed to parse height: {e}"))?,
+			refresh_rate: refresh_rate.parse().map_err(|e| format!("failed to parse refresh rate: {e}"))?,
+		})
 	}
 }
 
+/// Reply with an error for a request that can't be handled.
 fn bad_request(message: String) -> Response<Full<Bytes>> {
-	Response::builder()
-		.status(StatusCode::BAD_REQUEST)
-		.body(Full::new(Bytes::from(message)))
-		.unwrap()
+	XmlResponse::error(400, message).build()
+}
+
+/// Reply with an error that Moonlight shows to the user.
+fn service_unavailable(message: String) -> Response<Full<Bytes>> {
+	XmlResponse::error(503, message).build()
 }
 
 fn not_found() -> Response<Full<Bytes>> {
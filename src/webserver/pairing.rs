@@ -1,11 +1,17 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::net::SocketAddr;
 
 use http_body_util::Full;
-use hyper::{body::Bytes, header::{self, HeaderValue}, Request, Response};
+use hyper::{body::Bytes, Request, Response};
 use notify_rust::Notification;
-use tokio::sync::Notify;
+use tokio::sync::oneshot;
 
-use crate::{clients::PendingClient, webserver::bad_request, clients::ClientManager};
+use crate::{clients::{self, PendingClient}, crypto::{self, Certificate}, config::{PairingConfig, PinDisplay, PinMode}, webserver::{bad_request, params::QueryParams, xml::XmlResponse}, clients::ClientManager};
+
+/// Length of the challenge that the client encrypts with the PIN.
+const CHALLENGE_LENGTH: usize = 16;
+
+/// Length of the client pairing secret: the secret itself followed by its 2048 bit RSA signature.
+const CLIENT_PAIRING_SECRET_LENGTH: usize = 16 + 256;
 
 /// Handle a pairing request from a client.
 ///
@@ -21,31 +27,41 @@ use crate::{clients::PendingClient, webserver::bad_request, clients::ClientManag
 ///   5. /pair?clientpairingsecret=...
 ///
 /// After completing these steps, we have paired with the client.
+///
+/// Normally the client shows a PIN and the first step waits until the user entered it on the host.
+/// In server PIN mode the host generates the PIN and shows it instead, and the user enters it on the client.
 pub async fn handle_pair_request(
 	request: Request<hyper::body::Incoming>,
-	mut params: HashMap<String, String>,
+	params: QueryParams,
+	remote_address: SocketAddr,
 	local_address: Option<SocketAddr>,
-	server_certs: &openssl::x509::X509,
+	server_certs: &Certificate,
 	client_manager: &ClientManager,
+	pairing: &PairingConfig,
 ) -> Response<Full<Bytes>> {
-	if params.contains_key("phrase") {
-		match params.remove("phrase").unwrap().as_str() {
-			"getservercert" => get_server_cert(request, params, local_address, server_certs, client_manager).await,
-			"pairchallenge" => pair_challenge(params, client_manager).await,
+	let phrase: Option<String> = match params.optional("phrase") {
+		Ok(phrase) => phrase,
+		Err(response) => return response,
+	};
+
+	if let Some(phrase) = phrase {
+		match phrase.as_str() {
+			"getservercert" => get_server_cert(request, params, remote_address, local_address, server_certs, client_manager, pairing).await,
+			"pairchallenge" => pair_challenge(params, remote_address, client_manager).await,
 			unknown => {
 				let message = format!("Unknown pair phrase received: {}", unknown);
 				tracing::warn!("{message}");
 				bad_request(message)
 			}
 		}
-	} else if params.contains_key("clientchallenge") {
-		client_challenge(params, client_manager).await
-	} else if params.contains_key("serverchallengeresp") {
-		server_challenge_response(params, client_manager).await
-	} else if params.contains_key("clientpairingsecret") {
-		client_pairing_secret(params, client_manager).await
+	} else if params.contains("clientchallenge") {
+		client_challenge(params, remote_address, client_manager).await
+	} else if params.contains("serverchallengeresp") {
+		server_challenge_response(params, remote_address, client_manager).await
+	} else if params.contains("clientpairingsecret") {
+		client_pairing_secret(params, remote_address, client_manager).await
 	} else {
-		let message = format!("Unknown pair command with params: {:?}", params);
+		let message = format!("Unknown pair command with params: {:?}", params.names());
 		tracing::warn!("{message}");
 		bad_request(message)
 	}
@@ -53,21 +69,35 @@ pub async fn handle_pair_request(
 
 async fn get_server_cert(
 	request: Request<hyper::body::Incoming>,
-	mut params: HashMap<String, String>,
+	params: QueryParams,
+	remote_address: SocketAddr,
 	local_address: Option<SocketAddr>,
-	server_pem: &openssl::x509::X509,
+	server_pem: &Certificate,
 	client_manager: &ClientManager,
+	pairing: &PairingConfig,
 ) -> Response<Full<Bytes>> {
-	let client_cert = match params.remove("clientcert") {
-		Some(client_cert) => client_cert,
-		None => {
-			let message = format!("Expected 'clientcert' in get server cert request, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+	let client_cert = match params.required_hex("clientcert") {
+		Ok(client_cert) => client_cert,
+		Err(response) => return response,
+	};
+	let unique_id: String = match params.required("uniqueid") {
+		Ok(unique_id) => unique_id,
+		Err(response) => return response,
 	};
-	let client_cert = match hex::decode(client_cert) {
-		Ok(cert) => cert,
+	let device_name: Option<String> = match params.optional("devicename") {
+		Ok(device_name) => device_name,
+		Err(response) => return response,
+	};
+	let client_type = request.headers().get(hyper::header::USER_AGENT)
+		.and_then(|user_agent| user_agent.to_str().ok())
+		.map(|user_agent| user_agent.to_string());
+	let salt: [u8; 16] = match params.required_hex_array("salt") {
+		Ok(salt) => salt,
+		Err(response) => return response,
+	};
+
+	let pem = match Certificate::from_pem(client_cert.as_slice()) {
+		Ok(pem) => pem,
 		Err(e) => {
 			let message = format!("{e}");
 			tracing::warn!("{message}");
@@ -75,103 +105,90 @@ async fn get_server_cert(
 		}
 	};
 
-	let unique_id = match params.remove("uniqueid") {
-		Some(unique_id) => unique_id,
-		None => {
-			let message = format!("Expected 'uniqueid' in get server cert request, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+	// On an untrusted network this is how a user can tell their own client from one that intercepted the pairing.
+	match crypto::certificate_fingerprint(&pem) {
+		Ok(fingerprint) => tracing::info!("Client '{unique_id}' at {remote_address} wants to pair with certificate fingerprint (SHA-256) {fingerprint}"),
+		Err(e) => tracing::warn!("Failed to compute the certificate fingerprint of client '{unique_id}': {e}"),
+	}
+
+	let server_pin = match pairing.pin {
+		PinMode::Client => None,
+		PinMode::Server => match PendingClient::create_server_pin() {
+			Ok(pin) => Some(pin),
+			Err(()) => return bad_request("Failed to create PIN".to_string()),
+		},
+	};
+	let key = match &server_pin {
+		Some(pin) => match clients::create_key(&salt, pin) {
+			Ok(key) => Some(key),
+			Err(e) => {
+				tracing::warn!("{e}");
+				return bad_request(e);
+			},
+		},
+		None => None,
 	};
 
-	let salt = match params.remove("salt") {
-		Some(salt) => salt,
-		None => {
-			let message = format!("Expected 'salt' in get server cert request, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+	// A generated PIN is only shown on the web page if that is enabled, otherwise the link is of no use.
+	let pin_token = if server_pin.is_none() || pairing.pin_display.contains(&PinDisplay::Web) {
+		PendingClient::create_pin_token().ok()
+	} else {
+		None
 	};
-	let salt = match hex::decode(salt) {
-		Ok(salt) => salt,
-		Err(e) => {
-			let message = format!("{e}");
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+	let (pin_tx, pin_rx) = oneshot::channel();
+	let pending_client = PendingClient {
+		id: unique_id.clone(),
+		address: remote_address.ip(),
+		device_name,
+		client_type,
+		pem,
+		salt,
+		pin_tx: Some(pin_tx),
+		pin_token: pin_token.clone(),
+		server_pin: server_pin.clone(),
+		key,
+		server_secret: None,
+		server_challenge: None,
+		client_hash: None,
+		failed_attempts: 0,
+		verified: false,
 	};
-	let salt: [u8; 16] = match salt.try_into() {
-		Ok(salt) => salt,
-		Err(e) => {
-			let message = format!("Failed to parse salt value, expected exactly 16 values but got {e:?}");
+	match client_manager.start_pairing(pending_client).await {
+		Ok(()) => {},
+		Err(()) => {
+			let message = "Failed to start pairing client".to_string();
 			tracing::warn!("{message}");
 			return bad_request(message);
 		}
 	};
 
-	let pem = match openssl::x509::X509::from_pem(client_cert.as_slice()) {
-		Ok(pem) => pem,
-		Err(e) => {
-			let message = format!("{e}");
-			tracing::warn!("{message}");
-			return bad_request(message);
+	// The link contains a one-time token, so it also works from another device without knowing the client id.
+	let pin_url = local_address.map(|local_address| {
+		let scheme = request.uri().scheme().map(|s| s.to_string()).unwrap_or("http".to_string());
+		let pin_url = format!("{}://{}:{}/pin", scheme, local_address.ip(), local_address.port());
+		match &pin_token {
+			Some((token, _)) => format!("{pin_url}?token={token}"),
+			None => pin_url,
 		}
-	};
+	});
 
-	let pin_notifier = {
-		let pending_client = PendingClient {
-			id: unique_id.clone(),
-			pem,
-			salt,
-			pin_notify: Arc::new(Notify::new()),
-			key: None,
-			server_secret: None,
-			server_challenge: None,
-			client_hash: None,
-		};
-		let notify = pending_client.pin_notify.clone();
-
-		match client_manager.start_pairing(pending_client).await {
-			Ok(()) => {},
-			Err(()) => {
-				let message = "Failed to start pairing client".to_string();
+	match server_pin {
+		Some(pin) => show_server_pin(&pin, &unique_id, pin_url.filter(|_| pin_token.is_some()), &pairing.pin_display),
+		None => {
+			// Emit a notification, allowing the user to automatically open the PIN page.
+			if let Some(pin_url) = pin_url {
+				tracing::info!("Waiting for pin to be sent at {pin_url}");
+				show_notification("Received pairing request.".to_string(), "Enter PIN", Some(pin_url));
+			}
+
+			if pin_rx.await.is_err() {
+				let message = "Pairing attempt was replaced or invalidated before a PIN was entered".to_string();
 				tracing::warn!("{message}");
 				return bad_request(message);
 			}
-		};
-
-		notify
-	};
-
-	// Emit a notification, allowing the user to automatically open the PIN page.
-	if let Some(local_address) = local_address {
-		let scheme = request.uri().scheme().map(|s| s.to_string()).unwrap_or("http".to_string());
-		let pin_url = format!("{}://{}:{}/pin", scheme, local_address.ip(), local_address.port());
-		tracing::info!("Waiting for pin to be sent at {pin_url}");
-
-		let _ = std::thread::Builder::new().name("pin-notification".to_string()).spawn(move || {
-			Notification::new()
-				.appname("Moonshine")
-				.summary("Received pairing request.")
-				.action("default", "default")
-				.action("open", "Enter PIN")
-				.show()
-				.map_err(|e| tracing::warn!("Failed to show PIN notification: {e}"))?
-				.wait_for_action(|action| {
-					if action != "__closed" {
-						let _ = open::that(pin_url);
-					}
-				});
-
-				Ok::<(), ()>(())
-		});
+		},
 	}
 
-	pin_notifier.notified().await;
-
-	let mut response = "<root status_code=\"200\">".to_string();
-	response += "<paired>1</paired>";
-
 	let serialized_server_pem = match server_pem.to_pem() {
 		Ok(pem) => pem,
 		Err(e) => {
@@ -181,179 +198,151 @@ async fn get_server_cert(
 		}
 	};
 
-	response += &format!("<plaincert>{}</plaincert>", hex::encode(serialized_server_pem));
-	response += "</root>";
+	XmlResponse::ok()
+		.element("paired", 1)
+		.element("plaincert", hex::encode(serialized_server_pem))
+		.build()
+}
 
-	let mut response = Response::new(Full::new(Bytes::from(response)));
-	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
+/// Show a PIN generated by the server wherever the configuration asks for, `pin_url` opens a page that shows the PIN.
+fn show_server_pin(pin: &str, unique_id: &str, pin_url: Option<String>, display: &[PinDisplay]) {
+	if display.is_empty() {
+		tracing::warn!("Generated a pairing PIN for client '{unique_id}', but it isn't shown anywhere, see `webserver.pairing.pin_display`.");
+	}
+	if display.contains(&PinDisplay::Log) {
+		tracing::info!("Enter PIN {pin} on client '{unique_id}' to pair it.");
+	}
+	if display.contains(&PinDisplay::Web) {
+		if let Some(pin_url) = &pin_url {
+			tracing::info!("The pairing PIN is shown at {pin_url}");
+		}
+	}
+	if display.contains(&PinDisplay::Notification) {
+		show_notification(format!("Received pairing request, enter PIN {pin} on the client."), "Show PIN", pin_url);
+	}
+}
+
+/// Show a desktop notification about a pairing request, with an action that opens `url`.
+fn show_notification(summary: String, action: &'static str, url: Option<String>) {
+	let _ = std::thread::Builder::new().name("pin-notification".to_string()).spawn(move || {
+		let mut notification = Notification::new();
+		notification
+			.appname("Moonshine")
+			.summary(&summary)
+			.action("default", "default");
+		if url.is_some() {
+			notification.action("open", action);
+		}
+
+		notification
+			.show()
+			.map_err(|e| tracing::warn!("Failed to show PIN notification: {e}"))?
+			.wait_for_action(|action| {
+				if action != "__closed" {
+					if let Some(url) = url {
+						let _ = open::that(url);
+					}
+				}
+			});
 
-	response
+		Ok::<(), ()>(())
+	});
 }
 
 async fn client_challenge(
-	mut params: HashMap<String, String>,
+	params: QueryParams,
+	remote_address: SocketAddr,
 	client_manager: &ClientManager,
 ) -> Response<Full<Bytes>> {
-	let unique_id = match params.remove("uniqueid") {
-		Some(unique_id) => unique_id,
-		None => {
-			let message = format!("Expected 'uniqueid' in get server cert request, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+	let unique_id: String = match params.required("uniqueid") {
+		Ok(unique_id) => unique_id,
+		Err(response) => return response,
 	};
-	let challenge = match params.remove("clientchallenge") {
-		Some(challenge) => challenge,
-		None => {
-			let message = format!("Expected 'clientchallenge' in get server cert request, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
-	};
-	let challenge = match hex::decode(challenge) {
+	let challenge: [u8; CHALLENGE_LENGTH] = match params.required_hex_array("clientchallenge") {
 		Ok(challenge) => challenge,
-		Err(e) => {
-			let message = e.to_string();
-			tracing::error!("{message}");
-			return bad_request(message)
-		}
+		Err(response) => return response,
 	};
 
-	let challenge_response = match client_manager.client_challenge(&unique_id, challenge).await {
+	let challenge_response = match client_manager.client_challenge(&unique_id, remote_address.ip(), challenge.to_vec()).await {
 		Ok(challenge_response) => challenge_response,
 		Err(()) => {
 			return bad_request("Failed to process client challenge".to_string());
 		}
 	};
 
-	let mut response = "<root status_code=\"200\">".to_string();
-	response += "<paired>1</paired>";
-	response += &format!("<challengeresponse>{}</challengeresponse>", hex::encode(challenge_response));
-	response += "</root>";
-
-	let mut response = Response::new(Full::new(Bytes::from(response)));
-	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
-
-	response
+	XmlResponse::ok()
+		.element("paired", 1)
+		.element("challengeresponse", hex::encode(challenge_response))
+		.build()
 }
 
 async fn server_challenge_response(
-	mut params: HashMap<String, String>,
+	params: QueryParams,
+	remote_address: SocketAddr,
 	client_manager: &ClientManager,
 ) -> Response<Full<Bytes>> {
-	let server_challenge_response = match params.remove("serverchallengeresp") {
-		Some(server_challenge_response) => server_challenge_response,
-		None => {
-			let message = format!("Expected 'serverchallengeresp' in server challenge response request, got {:?}.", params.keys());
-			tracing::error!("{message}");
-			return bad_request(message);
-		}
-	};
-	let server_challenge_response = match hex::decode(server_challenge_response) {
+	let server_challenge_response = match params.required_hex("serverchallengeresp") {
 		Ok(server_challenge_response) => server_challenge_response,
-		Err(e) => {
-			let message = e.to_string();
-			tracing::error!("{message}");
-			return bad_request(message);
-		}
+		Err(response) => return response,
 	};
-
-	let unique_id = match params.remove("uniqueid") {
-		Some(unique_id) => unique_id,
-		None => {
-			let message = format!("Expected 'uniqueid' in get server cert request, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+	let unique_id: String = match params.required("uniqueid") {
+		Ok(unique_id) => unique_id,
+		Err(response) => return response,
 	};
 
-	let pairing_secret = match client_manager.server_challenge_response(&unique_id, server_challenge_response).await {
+	let pairing_secret = match client_manager.server_challenge_response(&unique_id, remote_address.ip(), server_challenge_response).await {
 		Ok(pairing_secret) => pairing_secret,
 		Err(()) => {
 			return bad_request("Failed to process server challenge response".to_string());
 		}
 	};
 
-	let mut response = "<root status_code=\"200\">".to_string();
-	response += "<paired>1</paired>";
-	response += &format!("<pairingsecret>{}</pairingsecret>", hex::encode(pairing_secret));
-	response += "</root>";
-
-	let mut response = Response::new(Full::new(Bytes::from(response)));
-	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
-
-	response
+	XmlResponse::ok()
+		.element("paired", 1)
+		.element("pairingsecret", hex::encode(pairing_secret))
+		.build()
 }
 
 async fn pair_challenge(
-	mut params: HashMap<String, String>,
+	params: QueryParams,
+	remote_address: SocketAddr,
 	client_manager: &ClientManager,
 ) -> Response<Full<Bytes>>{
-	let unique_id = match params.remove("uniqueid") {
-		Some(unique_id) => unique_id,
-		None => {
-			let message = format!("Expected 'uniqueid' in pair challenge, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+	let unique_id: String = match params.required("uniqueid") {
+		Ok(unique_id) => unique_id,
+		Err(response) => return response,
 	};
 
-	// All moonlight clients use the same uniqueid, so we ignore errors here.
-	let _ = client_manager.add_client(&unique_id).await;
-
-
-	let mut response = "<root status_code=\"200\">".to_string();
-	response += "<paired>1</paired>";
-	response += "</root>";
-
-	let mut response = Response::new(Full::new(Bytes::from(response)));
-	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
+	if client_manager.add_client(&unique_id, remote_address.ip()).await.is_err() {
+		return bad_request("Failed to pair client".to_string());
+	}
 
-	response
+	XmlResponse::ok()
+		.element("paired", 1)
+		.build()
 }
 
 async fn client_pairing_secret(
-	mut params: HashMap<String, String>,
+	params: QueryParams,
+	remote_address: SocketAddr,
 	client_manager: &ClientManager,
 ) -> Response<Full<Bytes>> {
-	let client_pairing_secret = match params.remove("clientpairingsecret") {
-		Some(client_pairing_secret) => client_pairing_secret,
-		None => {
-			let message = format!("Expected 'clientpairingsecret' in client pairing secret request, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
-	};
-	let client_pairing_secret = match hex::decode(client_pairing_secret) {
+	let client_pairing_secret: [u8; CLIENT_PAIRING_SECRET_LENGTH] = match params.required_hex_array("clientpairingsecret") {
 		Ok(client_pairing_secret) => client_pairing_secret,
-		Err(e) => {
-			let message = e.to_string();
-			tracing::error!("{message}");
-			return bad_request(message);
-		}
+		Err(response) => return response,
 	};
-
-	let unique_id = match params.remove("uniqueid") {
-		Some(unique_id) => unique_id,
-		None => {
-			let message = format!("Expected 'uniqueid' in pair challenge, got {:?}.", params.keys());
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+	let unique_id: String = match params.required("uniqueid") {
+		Ok(unique_id) => unique_id,
+		Err(response) => return response,
 	};
 
-	if client_manager.check_client_pairing_secret(&unique_id, client_pairing_secret).await.is_err() {
+	if client_manager.check_client_pairing_secret(&unique_id, remote_address.ip(), client_pairing_secret.to_vec()).await.is_err() {
 		return bad_request("Failed to check client pairing secret".to_string());
 	}
 
 	// TODO: Verify x509 cert.
 
-	let mut response = "<root status_code=\"200\">".to_string();
-	response += "<paired>1</paired>";
-	response += "</root>";
-
-	let mut response = Response::new(Full::new(Bytes::from(response)));
-	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
-
-	response
+	XmlResponse::ok()
+		.element("paired", 1)
+		.build()
 }
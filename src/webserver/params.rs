@@ -0,0 +1,82 @@
+//! Typed access to the query parameters of a request.
+
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use http_body_util::Full;
+use hyper::{body::Bytes, Response, Uri};
+
+use super::bad_request;
+
+/// Query parameters of a request.
+///
+/// Failing to extract a parameter results in a response that describes what was wrong with the request,
+/// which the endpoint can return directly.
+pub struct QueryParams {
+	path: String,
+	params: HashMap<String, String>,
+}
+
+impl QueryParams {
+	pub fn from_uri(uri: &Uri) -> Self {
+		let params = uri.query()
+			.map(|query| {
+				url::form_urlencoded::parse(query.as_bytes())
+					.into_owned()
+					.collect()
+			})
+			.unwrap_or_default();
+
+		Self { path: uri.path().to_string(), params }
+	}
+
+	pub fn contains(&self, name: &str) -> bool {
+		self.params.contains_key(name)
+	}
+
+	/// Names of all provided parameters.
+	pub fn names(&self) -> Vec<&str> {
+		self.params.keys().map(|name| name.as_str()).collect()
+	}
+
+	/// Parse a parameter that the request needs to provide.
+	pub fn required<T>(&self, name: &str) -> Result<T, Response<Full<Bytes>>>
+	where
+		T: FromStr,
+		T::Err: Display,
+	{
+		self.optional(name)?
+			.ok_or_else(|| self.error(format!("Expected '{name}' in {} request, got {:?}.", self.path, self.names())))
+	}
+
+	/// Parse a parameter that the request may provide.
+	pub fn optional<T>(&self, name: &str) -> Result<Option<T>, Response<Full<Bytes>>>
+	where
+		T: FromStr,
+		T::Err: Display,
+	{
+		self.params.get(name)
+			.map(|value| value.parse()
+				.map_err(|e| self.error(format!("Failed to parse '{name}' in {} request: {e}", self.path))))
+			.transpose()
+	}
+
+	/// Decode a hex encoded parameter that the request needs to provide.
+	pub fn required_hex(&self, name: &str) -> Result<Vec<u8>, Response<Full<Bytes>>> {
+		let value: String = self.required(name)?;
+		hex::decode(value)
+			.map_err(|e| self.error(format!("Failed to decode '{name}' in {} request: {e}", self.path)))
+	}
+
+	/// Decode a hex encoded parameter of exactly `N` bytes that the request needs to provide.
+	pub fn required_hex_array<const N: usize>(&self, name: &str) -> Result<[u8; N], Response<Full<Bytes>>> {
+		let value = self.required_hex(name)?;
+		let length = value.len();
+		value.try_into()
+			.map_err(|_| self.error(format!("Expected '{name}' in {} request to be {N} bytes, got {length} bytes.", self.path)))
+	}
+
+	fn error(&self, message: String) -> Response<Full<Bytes>> {
+		tracing::warn!("{message}");
+		bad_request(message)
+	}
+}
@@ -1,41 +1,190 @@
-use std::{path::Path, pin::Pin};
+use std::path::Path;
 
-use openssl::ssl::{SslMethod, SslFiletype, SslAcceptor, Ssl};
 use tokio::net::TcpStream;
-use tokio_openssl::SslStream;
 
-pub struct TlsAcceptor {
-	acceptor: SslAcceptor,
+use crate::crypto;
+
+#[cfg(not(feature = "rust-crypto"))]
+pub use openssl_acceptor::TlsAcceptor;
+#[cfg(feature = "rust-crypto")]
+pub use rustls_acceptor::TlsAcceptor;
+
+/// Fingerprint of the certificate the client presented.
+///
+/// Clients are asked for a certificate, but any certificate is accepted. Moonlight presents the self-signed certificate
+/// it paired with, and since all Moonlight clients send the same unique id this is what tells them apart. The
+/// certificate is trusted because it was exchanged while pairing, not because of who signed it, and clients that are
+/// still pairing don't have one that the host knows.
+fn client_fingerprint(certificate: Option<&[u8]>) -> Option<String> {
+	crypto::der_fingerprint(certificate?)
+		.map_err(|e| tracing::warn!("Failed to compute the fingerprint of the client certificate: {e}"))
+		.ok()
 }
 
-impl TlsAcceptor {
-	pub fn from_config<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<Self, ()> {
-		let acceptor = load_tls_files(certificate, private_key)?;
-		Ok(Self { acceptor })
+#[cfg(not(feature = "rust-crypto"))]
+mod openssl_acceptor {
+	use std::pin::Pin;
+
+	use openssl::ssl::{SslMethod, SslFiletype, SslAcceptor, SslVerifyMode, Ssl};
+	use tokio_openssl::SslStream;
+
+	use super::*;
+
+	pub struct TlsAcceptor {
+		acceptor: SslAcceptor,
+	}
+
+	impl TlsAcceptor {
+		pub fn from_config<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<Self, ()> {
+			let acceptor = load_tls_files(certificate, private_key)?;
+			Ok(Self { acceptor })
+		}
+
+		/// Complete the TLS handshake, returns the stream and the fingerprint of the client certificate, if it sent one.
+		pub async fn accept(&self, connection: TcpStream) -> Result<(SslStream<TcpStream>, Option<String>), ()> {
+			let ssl = Ssl::new(self.acceptor.context())
+				.map_err(|e| tracing::error!("Failed to initialize TLS session: {}", e))?;
+
+			let mut stream = tokio_openssl::SslStream::new(ssl, connection)
+				.map_err(|e| tracing::error!("Failed to create TLS stream: {}", e))?;
+			Pin::new(&mut stream).accept()
+				.await
+				.map_err(|e| tracing::error!("TLS handshake failed: {}", e))?;
+
+			let client_certificate = stream.ssl().peer_certificate()
+				.and_then(|certificate| certificate.to_der()
+					.map_err(|e| tracing::warn!("Failed to encode client certificate: {}", e))
+					.ok()
+				);
+			let client_certificate = client_fingerprint(client_certificate.as_deref());
+			Ok((stream, client_certificate))
+		}
 	}
 
-	pub async fn accept(&self, connection: TcpStream) -> Result<SslStream<TcpStream>, ()> {
-		let ssl = Ssl::new(self.acceptor.context())
-			.map_err(|e| tracing::error!("Failed to initialize TLS session: {}", e))?;
+	fn load_tls_files<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<SslAcceptor, ()> {
+		let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls_server())
+			.map_err(|e| tracing::error!("Failed to initialize SSL acceptor: {}", e))?;
+		builder
+			.set_private_key_file(&private_key, SslFiletype::PEM)
+			.map_err(|e| tracing::error!("Failed to set private key file '{:?}': {}", private_key.as_ref(), e))?;
+		builder
+			.set_certificate_chain_file(&certificate)
+			.map_err(|e| tracing::error!("Failed to set certificate file '{:?}': {}", certificate.as_ref(), e))?;
 
-		let mut stream = tokio_openssl::SslStream::new(ssl, connection)
-			.map_err(|e| tracing::error!("Failed to create TLS stream: {}", e))?;
-		Pin::new(&mut stream).accept()
-			.await
-			.map_err(|e| tracing::error!("TLS handshake failed: {}", e))?;
-		Ok(stream)
+		// Any client certificate is accepted, see `client_fingerprint`.
+		builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+		// Without a session id context OpenSSL refuses to resume sessions in which a client certificate was requested.
+		builder
+			.set_session_id_context(b"moonshine")
+			.map_err(|e| tracing::error!("Failed to set TLS session id context: {}", e))?;
+
+		Ok(builder.build())
 	}
 }
 
-fn load_tls_files<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<SslAcceptor, ()> {
-	let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls_server())
-		.map_err(|e| tracing::error!("Failed to initialize SSL acceptor: {}", e))?;
-	builder
-		.set_private_key_file(&private_key, SslFiletype::PEM)
-		.map_err(|e| tracing::error!("Failed to set private key file '{:?}': {}", private_key.as_ref(), e))?;
-	builder
-		.set_certificate_chain_file(&certificate)
-		.map_err(|e| tracing::error!("Failed to set certificate file '{:?}': {}", certificate.as_ref(), e))?;
-
-	Ok(builder.build())
+#[cfg(feature = "rust-crypto")]
+mod rustls_acceptor {
+	use std::sync::Arc;
+
+	use rustls::{
+		client::danger::HandshakeSignatureValid,
+		crypto::CryptoProvider,
+		pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, UnixTime},
+		server::danger::{ClientCertVerified, ClientCertVerifier},
+		DigitallySignedStruct,
+		DistinguishedName,
+		ServerConfig,
+		SignatureScheme,
+	};
+	use tokio_rustls::server::TlsStream;
+
+	use super::*;
+
+	pub struct TlsAcceptor {
+		acceptor: tokio_rustls::TlsAcceptor,
+	}
+
+	impl TlsAcceptor {
+		pub fn from_config<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<Self, ()> {
+			let config = load_tls_files(certificate, private_key)?;
+			Ok(Self { acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(config)) })
+		}
+
+		/// Complete the TLS handshake, returns the stream and the fingerprint of the client certificate, if it sent one.
+		pub async fn accept(&self, connection: TcpStream) -> Result<(TlsStream<TcpStream>, Option<String>), ()> {
+			let stream = self.acceptor.accept(connection)
+				.await
+				.map_err(|e| tracing::error!("TLS handshake failed: {}", e))?;
+
+			let client_certificate = stream.get_ref().1.peer_certificates()
+				.and_then(|certificates| certificates.first());
+			let client_certificate = client_fingerprint(client_certificate.map(|certificate| certificate.as_ref()));
+			Ok((stream, client_certificate))
+		}
+	}
+
+	fn load_tls_files<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<ServerConfig, ()> {
+		let certificates = CertificateDer::pem_file_iter(&certificate)
+			.and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+			.map_err(|e| tracing::error!("Failed to read certificate file '{:?}': {}", certificate.as_ref(), e))?;
+		let private_key = PrivateKeyDer::from_pem_file(&private_key)
+			.map_err(|e| tracing::error!("Failed to read private key file '{:?}': {}", private_key.as_ref(), e))?;
+
+		let provider = Arc::new(rustls::crypto::ring::default_provider());
+		ServerConfig::builder_with_provider(provider.clone())
+			.with_safe_default_protocol_versions()
+			.map_err(|e| tracing::error!("Failed to initialize TLS acceptor: {}", e))?
+			.with_client_cert_verifier(Arc::new(AnyClientCertificate { provider }))
+			.with_single_cert(certificates, private_key)
+			.map_err(|e| tracing::error!("Failed to set certificate and private key: {}", e))
+	}
+
+	/// Asks clients for a certificate and accepts any, see `client_fingerprint`.
+	///
+	/// The handshake signatures are still checked, so a client can't present a certificate without its private key.
+	#[derive(Debug)]
+	struct AnyClientCertificate {
+		provider: Arc<CryptoProvider>,
+	}
+
+	impl ClientCertVerifier for AnyClientCertificate {
+		fn client_auth_mandatory(&self) -> bool {
+			false
+		}
+
+		fn root_hint_subjects(&self) -> &[DistinguishedName] {
+			&[]
+		}
+
+		fn verify_client_cert(
+			&self,
+			_end_entity: &CertificateDer<'_>,
+			_intermediates: &[CertificateDer<'_>],
+			_now: UnixTime,
+		) -> Result<ClientCertVerified, rustls::Error> {
+			Ok(ClientCertVerified::assertion())
+		}
+
+		fn verify_tls12_signature(
+			&self,
+			message: &[u8],
+			certificate: &CertificateDer<'_>,
+			signature: &DigitallySignedStruct,
+		) -> Result<HandshakeSignatureValid, rustls::Error> {
+			rustls::crypto::verify_tls12_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+		}
+
+		fn verify_tls13_signature(
+			&self,
+			message: &[u8],
+			certificate: &CertificateDer<'_>,
+			signature: &DigitallySignedStruct,
+		) -> Result<HandshakeSignatureValid, rustls::Error> {
+			rustls::crypto::verify_tls13_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+		}
+
+		fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+			self.provider.signature_verification_algorithms.supported_schemes()
+		}
+	}
 }
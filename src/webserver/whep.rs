@@ -0,0 +1,122 @@
+//! WHEP endpoint and viewer page, through which browsers watch the stream over WebRTC.
+
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{body::Bytes, header::{self, HeaderValue}, Method, Request, Response, StatusCode};
+use uuid::Uuid;
+
+use crate::{crypto, whep::{ConnectError, WhepServer}};
+
+use super::{not_found, Webserver};
+
+/// Path that offers are posted to, viewers are resources below it.
+const WHEP_PATH: &str = "/webrtc/whep";
+
+/// Largest offer that is accepted, SDP offers of browsers are a few kilobytes.
+const MAX_OFFER_LENGTH: usize = 64 * 1024;
+
+impl Webserver {
+	pub(super) async fn whep(&self, request: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+		let Some(whep_server) = &self.whep_server else {
+			tracing::warn!("Rejecting WebRTC request, WebRTC is not configured.");
+			return not_found();
+		};
+
+		match (request.method(), request.uri().path()) {
+			(&Method::GET, "/webrtc") => viewer_page(),
+			(&Method::POST, WHEP_PATH) => self.whep_offer(whep_server, request).await,
+			(&Method::DELETE, path) if path.starts_with(WHEP_PATH) => self.whep_disconnect(whep_server, &request).await,
+			(method, uri) => {
+				tracing::warn!("Unhandled {method} WebRTC request with URI '{uri}'");
+				not_found()
+			},
+		}
+	}
+
+	async fn whep_offer(&self, whep_server: &WhepServer, request: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+		if !self.whep_authorized(&request) {
+			return status_response(StatusCode::UNAUTHORIZED);
+		}
+
+		let body = match Limited::new(request.into_body(), MAX_OFFER_LENGTH).collect().await {
+			Ok(body) => body.to_bytes(),
+			Err(e) if e.is::<LengthLimitError>() => {
+				tracing::warn!("Rejecting WebRTC offer larger than {MAX_OFFER_LENGTH} bytes.");
+				return status_response(StatusCode::PAYLOAD_TOO_LARGE);
+			},
+			Err(e) => {
+				tracing::warn!("Failed to read WebRTC offer: {e}");
+				return status_response(StatusCode::BAD_REQUEST);
+			},
+		};
+		let offer = match String::from_utf8(body.to_vec()) {
+			Ok(offer) => offer,
+			Err(e) => {
+				tracing::warn!("WebRTC offer is not valid UTF-8: {e}");
+				return status_response(StatusCode::BAD_REQUEST);
+			},
+		};
+
+		let (id, answer) = match whep_server.connect(offer).await {
+			Ok(connection) => connection,
+			Err(ConnectError::TooManyViewers) => return status_response(StatusCode::SERVICE_UNAVAILABLE),
+			Err(ConnectError::Failed) => return status_response(StatusCode::BAD_REQUEST),
+		};
+
+		Response::builder()
+			.status(StatusCode::CREATED)
+			.header(header::CONTENT_TYPE, "application/sdp")
+			.header(header::LOCATION, format!("{WHEP_PATH}/{id}"))
+			.body(Full::new(Bytes::from(answer)))
+			.unwrap()
+	}
+
+	async fn whep_disconnect(&self, whep_server: &WhepServer, request: &Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+		if !self.whep_authorized(request) {
+			return status_response(StatusCode::UNAUTHORIZED);
+		}
+
+		let id = request.uri().path().trim_start_matches(WHEP_PATH).trim_start_matches('/');
+		let Ok(id) = Uuid::parse_str(id) else {
+			return not_found();
+		};
+
+		match whep_server.disconnect(id).await {
+			Ok(true) => status_response(StatusCode::OK),
+			Ok(false) => not_found(),
+			Err(()) => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+		}
+	}
+
+	/// Check the bearer token of a request, which WHEP uses for authentication.
+	fn whep_authorized(&self, request: &Request<hyper::body::Incoming>) -> bool {
+		let Some(config) = &self.config.webrtc else {
+			return false;
+		};
+
+		let token = request.headers().get(header::AUTHORIZATION)
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.strip_prefix("Bearer "));
+		let authorized = !config.token.is_empty()
+			&& token.is_some_and(|token| crypto::secrets_equal(token.as_bytes(), config.token.as_bytes()));
+		if !authorized {
+			tracing::warn!("Rejecting WebRTC request without a valid token.");
+		}
+
+		authorized
+	}
+}
+
+fn viewer_page() -> Response<Full<Bytes>> {
+	let content = include_bytes!("../../assets/webrtc.html");
+	let mut response = Response::new(Full::new(Bytes::from_static(content)));
+	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=UTF-8"));
+
+	response
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+	Response::builder()
+		.status(status)
+		.body(Full::new(Bytes::new()))
+		.unwrap()
+}
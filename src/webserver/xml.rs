@@ -0,0 +1,100 @@
+//! XML responses in the format that GameStream clients expect.
+
+use std::fmt::{Display, Write};
+
+use http_body_util::Full;
+use hyper::{body::Bytes, header::{self, HeaderValue}, Response};
+
+/// Elements inside an XML element.
+#[derive(Default)]
+pub struct XmlElements {
+	content: String,
+}
+
+impl XmlElements {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add an element containing `value`, which is escaped.
+	pub fn element(mut self, name: &str, value: impl Display) -> Self {
+		let _ = write!(self.content, "<{name}>{}</{name}>", escape(&value.to_string()));
+		self
+	}
+
+	/// Add an element containing other elements.
+	pub fn parent(mut self, name: &str, children: XmlElements) -> Self {
+		let _ = write!(self.content, "<{name}>{}</{name}>", children.content);
+		self
+	}
+}
+
+/// Response with a `<root>` element that reports the status of the request.
+///
+/// Clients read the status from the `status_code` and `status_message` attributes,
+/// so the HTTP status is always 200, also for errors.
+pub struct XmlResponse {
+	status_code: u16,
+	status_message: Option<String>,
+	elements: XmlElements,
+}
+
+impl XmlResponse {
+	pub fn ok() -> Self {
+		Self {
+			status_code: 200,
+			status_message: None,
+			elements: XmlElements::new(),
+		}
+	}
+
+	/// An error with a message that clients can show to the user.
+	pub fn error(status_code: u16, message: impl Into<String>) -> Self {
+		Self {
+			status_code,
+			status_message: Some(message.into()),
+			elements: XmlElements::new(),
+		}
+	}
+
+	/// Add an element containing `value`, which is escaped.
+	pub fn element(mut self, name: &str, value: impl Display) -> Self {
+		self.elements = self.elements.element(name, value);
+		self
+	}
+
+	/// Add an element containing other elements.
+	pub fn parent(mut self, name: &str, children: XmlElements) -> Self {
+		self.elements = self.elements.parent(name, children);
+		self
+	}
+
+	pub fn build(self) -> Response<Full<Bytes>> {
+		let mut body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?><root status_code=\"{}\"", self.status_code);
+		if let Some(status_message) = &self.status_message {
+			let _ = write!(body, " status_message=\"{}\"", escape(status_message));
+		}
+		let _ = write!(body, ">{}</root>", self.elements.content);
+
+		let mut response = Response::new(Full::new(Bytes::from(body)));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
+		response
+	}
+}
+
+/// Escape characters that have a special meaning in XML.
+fn escape(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for character in value.chars() {
+		match character {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&apos;"),
+			character => escaped.push(character),
+		}
+	}
+
+	escaped
+}
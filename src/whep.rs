@@ -0,0 +1,306 @@
+//! Output of the video stream to browsers over WebRTC, to watch the host when no Moonlight client is at hand.
+//!
+//! Browsers connect through WHEP: they POST an SDP offer to the webserver and receive the answer, deleting the resource
+//! from the `Location` header disconnects them. Viewers receive the encoded frames of whichever session is running,
+//! input is not supported.
+
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use async_shutdown::ShutdownManager;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use uuid::Uuid;
+use webrtc::{
+	api::{interceptor_registry::register_default_interceptors, media_engine::{MediaEngine, MIME_TYPE_H264}, APIBuilder, API},
+	ice_transport::ice_server::RTCIceServer,
+	interceptor::registry::Registry,
+	media::Sample,
+	peer_connection::{configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState, sdp::session_description::RTCSessionDescription, RTCPeerConnection},
+	rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+	track::track_local::{track_local_static_sample::TrackLocalStaticSample, TrackLocal},
+};
+
+use crate::{config::WebRtcConfig, session::{manager::SessionManager, stream::Codec}};
+
+/// Duration of the first frame a viewer receives, after that it is the time between frames.
+const INITIAL_FRAME_DURATION: Duration = Duration::from_millis(16);
+
+enum WhepCommand {
+	Connect(String, oneshot::Sender<Result<(Uuid, String), ConnectError>>),
+	Disconnect(Uuid, oneshot::Sender<bool>),
+	Closed(Uuid),
+}
+
+/// Reasons a viewer could not connect.
+#[derive(Debug)]
+pub enum ConnectError {
+	/// The maximum number of viewers is watching already.
+	TooManyViewers,
+
+	/// The offer was invalid or the connection could not be set up, the reason is logged.
+	Failed,
+}
+
+#[derive(Clone)]
+pub struct WhepServer {
+	command_tx: mpsc::Sender<WhepCommand>,
+}
+
+struct WhepServerInner {
+	config: WebRtcConfig,
+	api: API,
+	session_manager: SessionManager,
+	viewers: HashMap<Uuid, Viewer>,
+}
+
+struct Viewer {
+	peer_connection: Arc<RTCPeerConnection>,
+
+	/// Stops sending frames to the viewer.
+	stop_signal: ShutdownManager<()>,
+}
+
+impl WhepServer {
+	pub fn new(config: WebRtcConfig, session_manager: SessionManager) -> Result<Self, ()> {
+		let mut media_engine = MediaEngine::default();
+		media_engine.register_default_codecs()
+			.map_err(|e| tracing::error!("Failed to register WebRTC codecs: {e}"))?;
+		let registry = register_default_interceptors(Registry::new(), &mut media_engine)
+			.map_err(|e| tracing::error!("Failed to register WebRTC interceptors: {e}"))?;
+		let api = APIBuilder::new()
+			.with_media_engine(media_engine)
+			.with_interceptor_registry(registry)
+			.build();
+
+		let (command_tx, command_rx) = mpsc::channel(10);
+		let inner = WhepServerInner { config, api, session_manager, viewers: HashMap::new() };
+		tokio::spawn(inner.run(command_rx, command_tx.downgrade()));
+
+		Ok(Self { command_tx })
+	}
+
+	/// Connect a viewer with its SDP offer, returns the id of the viewer and the SDP answer.
+	pub async fn connect(&self, offer: String) -> Result<(Uuid, String), ConnectError> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(WhepCommand::Connect(offer, response_tx)).await
+			.map_err(|e| tracing::error!("Failed to send Connect command: {e}"))
+			.map_err(|()| ConnectError::Failed)?;
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for Connect response: {e}"))
+			.map_err(|()| ConnectError::Failed)?
+	}
+
+	/// Disconnect a viewer, returns false if there is no viewer with this id.
+	pub async fn disconnect(&self, id: Uuid) -> Result<bool, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(WhepCommand::Disconnect(id, response_tx)).await
+			.map_err(|e| tracing::error!("Failed to send Disconnect command: {e}"))?;
+		response_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for Disconnect response: {e}"))
+	}
+}
+
+impl WhepServerInner {
+	async fn run(mut self, mut command_rx: mpsc::Receiver<WhepCommand>, command_tx: mpsc::WeakSender<WhepCommand>) {
+		while let Some(command) = command_rx.recv().await {
+			match command {
+				WhepCommand::Connect(offer, response_tx) => {
+					let result = self.connect(offer, command_tx.clone()).await;
+					if response_tx.send(result).is_err() {
+						tracing::error!("Failed to send Connect response.");
+					}
+				},
+				WhepCommand::Disconnect(id, response_tx) => {
+					let found = self.remove_viewer(id).await;
+					if response_tx.send(found).is_err() {
+						tracing::error!("Failed to send Disconnect response.");
+					}
+				},
+				WhepCommand::Closed(id) => {
+					self.remove_viewer(id).await;
+				},
+			}
+		}
+
+		for (_, viewer) in self.viewers.drain() {
+			viewer.close().await;
+		}
+		tracing::debug!("WHEP command channel closed.");
+	}
+
+	async fn connect(&mut self, offer: String, command_tx: mpsc::WeakSender<WhepCommand>) -> Result<(Uuid, String), ConnectError> {
+		if self.viewers.len() >= self.config.max_viewers {
+			tracing::warn!("Rejecting WebRTC viewer, {} viewers are watching already.", self.viewers.len());
+			return Err(ConnectError::TooManyViewers);
+		}
+
+		// Viewers on the local network are reached through host candidates, which need no servers.
+		let mut configuration = RTCConfiguration::default();
+		if !self.config.ice_servers.is_empty() {
+			configuration.ice_servers = vec![RTCIceServer { urls: self.config.ice_servers.clone(), ..Default::default() }];
+		}
+		let peer_connection = Arc::new(self.api.new_peer_connection(configuration).await
+			.map_err(|e| tracing::error!("Failed to create WebRTC peer connection: {e}"))
+			.map_err(|()| ConnectError::Failed)?);
+
+		let id = Uuid::new_v4();
+		let viewer = Viewer { peer_connection, stop_signal: ShutdownManager::new() };
+		let answer = match viewer.negotiate(id, offer, self.session_manager.clone(), command_tx).await {
+			Ok(answer) => answer,
+			Err(()) => {
+				viewer.close().await;
+				return Err(ConnectError::Failed);
+			},
+		};
+
+		tracing::info!("WebRTC viewer {id} connected.");
+		self.viewers.insert(id, viewer);
+		Ok((id, answer))
+	}
+
+	async fn remove_viewer(&mut self, id: Uuid) -> bool {
+		let Some(viewer) = self.viewers.remove(&id) else {
+			return false;
+		};
+
+		tracing::info!("WebRTC viewer {id} disconnected.");
+		viewer.close().await;
+		true
+	}
+}
+
+impl Viewer {
+	/// Answer the offer of the viewer and start sending frames once it is connected.
+	async fn negotiate(
+		&self,
+		id: Uuid,
+		offer: String,
+		session_manager: SessionManager,
+		command_tx: mpsc::WeakSender<WhepCommand>,
+	) -> Result<String, ()> {
+		let track = Arc::new(TrackLocalStaticSample::new(
+			RTCRtpCodecCapability { mime_type: MIME_TYPE_H264.to_string(), ..Default::default() },
+			"video".to_string(),
+			"moonshine".to_string(),
+		));
+		let rtp_sender = self.peer_connection.add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>).await
+			.map_err(|e| tracing::error!("Failed to add video track: {e}"))?;
+
+		// RTCP packets have to be read for the interceptors to handle retransmission requests.
+		tokio::spawn(async move {
+			let mut buffer = vec![0u8; 1500];
+			while rtp_sender.read(&mut buffer).await.is_ok() {}
+		});
+
+		let (connected_tx, connected_rx) = watch::channel(false);
+		self.peer_connection.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+			tracing::debug!("WebRTC viewer {id} is {state}.");
+			match state {
+				RTCPeerConnectionState::Connected => {
+					connected_tx.send_replace(true);
+				},
+				RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+					if let Some(command_tx) = command_tx.upgrade() {
+						// Fails if the viewer was removed already.
+						let _ = command_tx.try_send(WhepCommand::Closed(id));
+					}
+				},
+				_ => {},
+			}
+			Box::pin(async {})
+		}));
+
+		let offer = RTCSessionDescription::offer(offer)
+			.map_err(|e| tracing::warn!("Failed to parse WebRTC offer: {e}"))?;
+		self.peer_connection.set_remote_description(offer).await
+			.map_err(|e| tracing::warn!("Failed to set WebRTC offer: {e}"))?;
+		let answer = self.peer_connection.create_answer(None).await
+			.map_err(|e| tracing::error!("Failed to create WebRTC answer: {e}"))?;
+
+		// WHEP expects the candidates in the answer, so wait until they are gathered.
+		let mut gathering_complete = self.peer_connection.gathering_complete_promise().await;
+		self.peer_connection.set_local_description(answer).await
+			.map_err(|e| tracing::error!("Failed to set WebRTC answer: {e}"))?;
+		let _ = gathering_complete.recv().await;
+		let answer = self.peer_connection.local_description().await
+			.ok_or_else(|| tracing::error!("WebRTC peer connection has no answer after gathering candidates."))?;
+
+		tokio::spawn(send_frames(id, track, connected_rx, session_manager, self.stop_signal.clone()));
+
+		Ok(answer.sdp)
+	}
+
+	async fn close(&self) {
+		let _ = self.stop_signal.trigger_shutdown(());
+		if let Err(e) = self.peer_connection.close().await {
+			tracing::warn!("Failed to close WebRTC peer connection: {e}");
+		}
+	}
+}
+
+/// Send the encoded frames to a viewer, starting with an IDR frame once it is connected.
+async fn send_frames(
+	id: Uuid,
+	track: Arc<TrackLocalStaticSample>,
+	mut connected_rx: watch::Receiver<bool>,
+	session_manager: SessionManager,
+	stop_signal: ShutdownManager<()>,
+) {
+	tokio::select! {
+		_ = stop_signal.wait_shutdown_triggered() => return,
+		result = connected_rx.wait_for(|connected| *connected) => if result.is_err() {
+			return;
+		},
+	}
+
+	let mut frames = session_manager.video_tap().subscribe();
+	let _ = session_manager.request_idr_frame().await;
+	let mut waiting_for_key_frame = true;
+	let mut warned_codec = false;
+	let mut last_frame: Option<Instant> = None;
+	loop {
+		let frame = tokio::select! {
+			_ = stop_signal.wait_shutdown_triggered() => break,
+			frame = frames.recv() => frame,
+		};
+
+		let frame = match frame {
+			Ok(frame) => frame,
+			Err(broadcast::error::RecvError::Lagged(missed)) => {
+				tracing::debug!("WebRTC viewer {id} missed {missed} frames, waiting for the next IDR frame.");
+				waiting_for_key_frame = true;
+				let _ = session_manager.request_idr_frame().await;
+				continue;
+			},
+			Err(broadcast::error::RecvError::Closed) => break,
+		};
+
+		// Browsers reliably decode only H.264, so HEVC sessions can't be watched.
+		if frame.codec != Codec::H264 {
+			if !warned_codec {
+				tracing::warn!("WebRTC viewer {id} can't watch the session, it is not streamed in H.264.");
+				warned_codec = true;
+			}
+			waiting_for_key_frame = true;
+			continue;
+		}
+		warned_codec = false;
+
+		if waiting_for_key_frame {
+			if !frame.key_frame {
+				continue;
+			}
+			waiting_for_key_frame = false;
+		}
+
+		let now = Instant::now();
+		let duration = last_frame.map_or(INITIAL_FRAME_DURATION, |last_frame| now - last_frame);
+		last_frame = Some(now);
+
+		if let Err(e) = track.write_sample(&Sample { data: frame.data, duration, ..Default::default() }).await {
+			tracing::warn!("Failed to send frame to WebRTC viewer {id}: {e}");
+			break;
+		}
+	}
+
+	tracing::debug!("Stopped sending frames to WebRTC viewer {id}.");
+}
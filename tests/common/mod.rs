@@ -0,0 +1,488 @@
+//! A host that runs on loopback for the integration tests, and a client that talks to it the way Moonlight does.
+//!
+//! Messages are compared against the fixtures in `tests/fixtures/protocol`, see the README there for their format.
+
+// Every test binary uses a different part of the harness.
+#![allow(dead_code)]
+
+use std::{collections::HashMap, io::{Read, Write}, net::{IpAddr, Ipv4Addr, TcpListener, TcpStream, UdpSocket}, path::PathBuf, sync::{Mutex, MutexGuard}, time::{Duration, Instant}};
+
+use moonshine_core::{config::{ApplicationConfig, Config}, Moonshine};
+use network_interface::NetworkInterfaceConfig;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// Unique id of the host, it is written to the state before the host starts so that responses don't change per run.
+pub const HOST_ID: &str = "5f0b6f3c-1f4e-4c8a-9a2e-6d1c0b7e9a41";
+
+/// Unique id that Moonlight clients send, they all use the same one.
+pub const CLIENT_ID: &str = "0123456789ABCDEF";
+
+/// Moonlight sends a new `uuid` with every request, the host ignores it.
+pub const REQUEST_UUID: &str = "e1a6e7d9-8f0b-4b7e-9d53-2c3f5a1b9c42";
+
+/// Time to wait for the host to start or respond.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The host keeps its state in the data directory of the process, so only one host runs at a time.
+static HOST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Values for the `{placeholders}` in fixtures.
+pub type Values = HashMap<&'static str, String>;
+
+/// A host with its own data directory, certificate and ports, it stops when dropped.
+pub struct TestHost {
+	pub config: Config,
+	runtime: Option<Runtime>,
+	host: Option<Moonshine>,
+	directory: TempDir,
+	_lock: MutexGuard<'static, ()>,
+}
+
+impl TestHost {
+	pub fn start() -> Self {
+		Self::start_with(|_| {})
+	}
+
+	/// Start a host with the test configuration, after `configure` changed it.
+	///
+	/// Encoders are disabled, so hosts with and without a GPU respond the same.
+	pub fn start_with(configure: impl FnOnce(&mut Config)) -> Self {
+		let lock = HOST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+		let _ = tracing_subscriber::fmt()
+			.with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+			.with_test_writer()
+			.try_init();
+
+		let directory = tempfile::tempdir().expect("failed to create data directory");
+		let state_directory = directory.path().join("moonshine");
+		std::fs::create_dir_all(&state_directory).expect("failed to create state directory");
+		std::fs::write(state_directory.join("state.toml"), format!("unique_id = \"{HOST_ID}\"\nclients = []\n"))
+			.expect("failed to write state");
+		std::env::set_var("XDG_DATA_HOME", directory.path());
+
+		let [http_port, https_port, rtsp_port, video_port, audio_port, control_port] = free_ports();
+		let mut config = Config {
+			address: Ipv4Addr::LOCALHOST.to_string(),
+			applications: vec![ApplicationConfig { title: "Desktop".to_string(), ..Default::default() }],
+			..Default::default()
+		};
+		config.webserver.port = http_port;
+		config.webserver.port_https = https_port;
+		config.webserver.certificate = directory.path().join("cert.pem");
+		config.webserver.private_key = directory.path().join("key.pem");
+		config.stream.port = rtsp_port;
+		config.stream.video.port = video_port;
+		config.stream.audio.port = audio_port;
+		config.stream.control.port = control_port;
+		config.stream.video.codec_h264 = "none".to_string();
+		config.stream.video.codec_hevc = "none".to_string();
+		config.stream.video.fallback_codecs_h264.clear();
+		config.stream.video.fallback_codecs_hevc.clear();
+		configure(&mut config);
+
+		let runtime = tokio::runtime::Builder::new_multi_thread()
+			.enable_all()
+			.build()
+			.expect("failed to create runtime");
+		let host = runtime.block_on(Moonshine::builder().config(config.clone()).build())
+			.expect("failed to start host");
+
+		let host = Self { config, runtime: Some(runtime), host: Some(host), directory, _lock: lock };
+		for port in [http_port, https_port, rtsp_port] {
+			wait_for_port(port);
+		}
+
+		host
+	}
+
+	/// Values of this host for the placeholders in fixtures.
+	pub fn values(&self) -> Values {
+		let certificate = std::fs::read(&self.config.webserver.certificate).expect("host didn't write its certificate");
+		Values::from([
+			("http_port", self.config.webserver.port.to_string()),
+			("https_port", self.config.webserver.port_https.to_string()),
+			("rtsp_port", self.config.stream.port.to_string()),
+			("video_port", self.config.stream.video.port.to_string()),
+			("audio_port", self.config.stream.audio.port.to_string()),
+			("control_port", self.config.stream.control.port.to_string()),
+			("host_id", HOST_ID.to_string()),
+			("mac", mac_address(Ipv4Addr::LOCALHOST.into())),
+			("app_id", self.config.applications[0].id().to_string()),
+			("server_cert", hex::encode(certificate)),
+			("client_id", CLIENT_ID.to_string()),
+			("uuid", REQUEST_UUID.to_string()),
+		])
+	}
+
+	/// Send a request to the HTTP server, returns the response.
+	pub fn http(&self, request: &[u8]) -> Vec<u8> {
+		let mut stream = connect(self.config.webserver.port);
+		stream.write_all(request).expect("failed to send request");
+		read_http_response(&mut stream)
+	}
+
+	/// Send a request to the HTTPS server with the certificate of `client`, returns the response.
+	#[cfg(feature = "openssl")]
+	pub fn https(&self, client: &Client, request: &[u8]) -> Vec<u8> {
+		use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+		let mut connector = SslConnector::builder(SslMethod::tls_client()).expect("failed to create TLS connector");
+		connector.set_verify(SslVerifyMode::NONE);
+		connector.set_certificate(&client.certificate).expect("failed to set client certificate");
+		connector.set_private_key(&client.private_key).expect("failed to set client private key");
+		let mut stream = connector.build()
+			.configure()
+			.expect("failed to configure TLS connection")
+			.verify_hostname(false)
+			.connect("localhost", connect(self.config.webserver.port_https))
+			.expect("TLS handshake failed");
+
+		// Moonlight pins the certificate that the host sent while pairing.
+		if let Some(server_certificate) = &client.server_certificate {
+			let certificate = stream.ssl().peer_certificate().expect("host presented no certificate");
+			assert_eq!(
+				certificate.to_der().expect("failed to encode host certificate"),
+				server_certificate.to_der().expect("failed to encode pinned certificate"),
+				"host presented a different certificate than it paired with",
+			);
+		}
+
+		stream.write_all(request).expect("failed to send request");
+		read_http_response(&mut stream)
+	}
+
+	/// Send a request to the RTSP server, returns the response.
+	///
+	/// The host closes the connection after every response, like Moonlight expects.
+	pub fn rtsp(&self, request: &[u8]) -> Vec<u8> {
+		let mut stream = connect(self.config.stream.port);
+		stream.write_all(request).expect("failed to send request");
+		let mut response = Vec::new();
+		stream.read_to_end(&mut response).expect("failed to read response");
+		response
+	}
+}
+
+impl Drop for TestHost {
+	fn drop(&mut self) {
+		let Some(runtime) = self.runtime.take() else {
+			return;
+		};
+
+		if let Some(host) = self.host.take() {
+			let _guard = runtime.enter();
+			let _ = host.shutdown_handle().trigger_shutdown(0);
+			drop(host);
+		}
+
+		// Some services block a thread until the process exits, like publishing the host on the network.
+		runtime.shutdown_background();
+	}
+}
+
+/// A client with its own certificate, which pairs with and talks to the host like Moonlight.
+#[cfg(feature = "openssl")]
+pub struct Client {
+	pub id: String,
+	certificate: openssl::x509::X509,
+	private_key: openssl::pkey::PKey<openssl::pkey::Private>,
+
+	/// Certificate the host sent while pairing.
+	server_certificate: Option<openssl::x509::X509>,
+}
+
+/// A message of the pairing process and the response of the host.
+#[cfg(feature = "openssl")]
+pub struct Exchange {
+	/// Name of the fixtures of the request and response, relative to `tests/fixtures/protocol`.
+	pub name: &'static str,
+
+	pub response: Vec<u8>,
+
+	/// Values for the placeholders in the fixtures, including the random values of this exchange.
+	pub values: Values,
+}
+
+#[cfg(feature = "openssl")]
+impl Client {
+	/// Create a client with a new certificate, the way Moonlight creates one when it first starts.
+	pub fn new(id: &str) -> Self {
+		use openssl::{asn1::Asn1Time, bn::BigNum, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::{X509Builder, X509NameBuilder}};
+
+		let private_key = PKey::from_rsa(Rsa::generate(2048).expect("failed to generate key"))
+			.expect("failed to wrap key");
+
+		let mut name = X509NameBuilder::new().expect("failed to create name");
+		name.append_entry_by_text("CN", "NVIDIA GameStream Client").expect("failed to set common name");
+		let name = name.build();
+
+		let mut builder = X509Builder::new().expect("failed to create certificate");
+		builder.set_version(2).expect("failed to set version");
+		let serial_number = BigNum::from_u32(0).and_then(|number| number.to_asn1_integer()).expect("failed to create serial number");
+		builder.set_serial_number(&serial_number).expect("failed to set serial number");
+		builder.set_subject_name(&name).expect("failed to set subject");
+		builder.set_issuer_name(&name).expect("failed to set issuer");
+		builder.set_pubkey(&private_key).expect("failed to set public key");
+		builder.set_not_before(&Asn1Time::days_from_now(0).expect("failed to create time")).expect("failed to set start");
+		builder.set_not_after(&Asn1Time::days_from_now(20 * 365).expect("failed to create time")).expect("failed to set end");
+		builder.sign(&private_key, MessageDigest::sha256()).expect("failed to sign certificate");
+
+		Self { id: id.to_string(), certificate: builder.build(), private_key, server_certificate: None }
+	}
+
+	/// Pair with the host, entering `pin` on the host like a user would.
+	///
+	/// Every step is checked the way Moonlight checks it, the exchanges are returned so the messages can be compared
+	/// with their fixtures.
+	pub fn pair(&mut self, host: &TestHost, pin: &str) -> Vec<Exchange> {
+		let mut exchanges = Vec::new();
+		let mut values = host.values();
+		values.insert("client_id", self.id.clone());
+		values.insert("pin", pin.to_string());
+
+		// Moonlight waits for the user to enter the PIN on the host before the host answers with its certificate.
+		let salt = random::<16>();
+		let key = pairing_key(&salt, pin);
+		values.insert("salt", hex::encode(salt));
+		values.insert("client_cert", hex::encode(self.certificate.to_pem().expect("failed to encode certificate")));
+		let submit_pin = std::thread::spawn({
+			let host_values = values.clone();
+			let port = host.config.webserver.port;
+			move || submit_pin(port, &host_values)
+		});
+		let response = host.http(&fixture("pair/getservercert.request", &values));
+		let server_certificate = hex::decode(element(&response, "plaincert")).expect("server certificate is not hex");
+		let server_certificate = openssl::x509::X509::from_pem(&server_certificate).expect("server certificate is not PEM");
+		exchanges.push(Exchange { name: "pair/submit-pin", response: submit_pin.join().expect("submitting the PIN failed"), values: values.clone() });
+		exchanges.push(Exchange { name: "pair/getservercert", response, values: values.clone() });
+
+		// The host proves it knows the PIN by decrypting the challenge, and hashes it with its own secret.
+		let client_challenge = random::<16>();
+		values.insert("client_challenge", hex::encode(aes_ecb(openssl::symm::Mode::Encrypt, &key, &client_challenge)));
+		let response = host.http(&fixture("pair/clientchallenge.request", &values));
+		values.insert("challenge_response", element(&response, "challengeresponse"));
+		let challenge_response = hex::decode(&values["challenge_response"]).expect("challenge response is not hex");
+		let challenge_response = aes_ecb(openssl::symm::Mode::Decrypt, &key, &challenge_response);
+		assert_eq!(challenge_response.len(), 48, "challenge response is a hash and a challenge");
+		let (server_hash, server_challenge) = challenge_response.split_at(32);
+		exchanges.push(Exchange { name: "pair/clientchallenge", response, values: values.clone() });
+
+		// The client answers the challenge of the host, the host reveals its secret.
+		let client_secret = random::<16>();
+		let client_hash = sha256(&[server_challenge, self.certificate.signature().as_slice(), &client_secret].concat());
+		values.insert("server_challenge_response", hex::encode(aes_ecb(openssl::symm::Mode::Encrypt, &key, &client_hash)));
+		let response = host.http(&fixture("pair/serverchallengeresp.request", &values));
+		values.insert("pairing_secret", element(&response, "pairingsecret"));
+		let pairing_secret = hex::decode(&values["pairing_secret"]).expect("pairing secret is not hex");
+		assert_eq!(pairing_secret.len(), 16 + 256, "pairing secret is a secret and its signature");
+		let (server_secret, server_signature) = pairing_secret.split_at(16);
+		assert!(verify(server_secret, server_signature, &server_certificate), "pairing secret is not signed by the host");
+		let expected_hash = sha256(&[&client_challenge, server_certificate.signature().as_slice(), server_secret].concat());
+		assert_eq!(server_hash, expected_hash, "host doesn't know the PIN");
+		exchanges.push(Exchange { name: "pair/serverchallengeresp", response, values: values.clone() });
+
+		// The client reveals its secret, signed with the key of its certificate.
+		let client_pairing_secret = [client_secret.as_slice(), &sign(&client_secret, &self.private_key)].concat();
+		values.insert("client_pairing_secret", hex::encode(client_pairing_secret));
+		let response = host.http(&fixture("pair/clientpairingsecret.request", &values));
+		exchanges.push(Exchange { name: "pair/clientpairingsecret", response, values: values.clone() });
+
+		// The last step goes over HTTPS, with the certificates that were exchanged.
+		self.server_certificate = Some(server_certificate);
+		let response = host.https(self, &fixture("pair/pairchallenge.request", &values));
+		exchanges.push(Exchange { name: "pair/pairchallenge", response, values });
+
+		exchanges
+	}
+}
+
+/// Enter the PIN for a client on the host, retrying until the host started pairing with it.
+#[cfg(feature = "openssl")]
+fn submit_pin(port: u16, values: &Values) -> Vec<u8> {
+	let deadline = Instant::now() + TIMEOUT;
+	loop {
+		let mut stream = connect(port);
+		stream.write_all(&fixture("pair/submit-pin.request", values)).expect("failed to send request");
+		let response = read_http_response(&mut stream);
+		if response.windows(12).any(|window| window == b"Successfully") || Instant::now() > deadline {
+			return response;
+		}
+
+		std::thread::sleep(Duration::from_millis(50));
+	}
+}
+
+/// Read a fixture from `tests/fixtures/protocol`, with its `{placeholders}` replaced by `values`.
+///
+/// `{content-length}` is replaced last, by the length of the body after the other placeholders were replaced.
+pub fn fixture(name: &str, values: &Values) -> Vec<u8> {
+	let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/protocol").join(name);
+	let mut message = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("failed to read fixture {path:?}: {e}"));
+	for (name, value) in values {
+		message = message.replace(&format!("{{{name}}}"), value);
+	}
+
+	let body_length = message.split_once("\r\n\r\n").map_or(0, |(_, body)| body.len());
+	message.replace("{content-length}", &body_length.to_string()).into_bytes()
+}
+
+/// Assert that a response matches the fixture it is expected to be.
+///
+/// Header names are compared without case and in any order, and the `Date` header is left out because it changes with
+/// every response. The status line, header values and body have to match byte for byte.
+#[track_caller]
+pub fn assert_response(response: &[u8], expected: &[u8]) {
+	assert_eq!(normalize(response), normalize(expected));
+}
+
+fn normalize(message: &[u8]) -> String {
+	let message = String::from_utf8_lossy(message);
+	let (head, body) = message.split_once("\r\n\r\n").unwrap_or((&message, ""));
+	let mut lines = head.split("\r\n");
+	let status = lines.next().unwrap_or_default();
+	let mut headers: Vec<String> = lines
+		.filter_map(|line| line.split_once(':'))
+		.map(|(name, value)| (name.to_ascii_lowercase(), value.trim()))
+		.filter(|(name, _)| name != "date")
+		.map(|(name, value)| format!("{name}: {value}"))
+		.collect();
+	headers.sort();
+
+	format!("{status}\r\n{}\r\n\r\n{body}", headers.join("\r\n"))
+}
+
+/// Text of the first `<name>` element in an XML response.
+#[track_caller]
+pub fn element(response: &[u8], name: &str) -> String {
+	let response = String::from_utf8_lossy(response);
+	response.split_once(&format!("<{name}>"))
+		.and_then(|(_, rest)| rest.split_once(&format!("</{name}>")))
+		.map(|(value, _)| value.to_string())
+		.unwrap_or_else(|| panic!("no <{name}> element in response: {response}"))
+}
+
+/// Value of the first header called `name` in a response.
+#[track_caller]
+pub fn header(response: &[u8], name: &str) -> String {
+	let response = String::from_utf8_lossy(response);
+	response.split("\r\n")
+		.take_while(|line| !line.is_empty())
+		.filter_map(|line| line.split_once(':'))
+		.find(|(header, _)| header.eq_ignore_ascii_case(name))
+		.map(|(_, value)| value.trim().to_string())
+		.unwrap_or_else(|| panic!("no {name} header in response: {response}"))
+}
+
+/// Read an HTTP response with a `Content-Length`, without waiting for the host to close the connection.
+fn read_http_response(stream: &mut impl Read) -> Vec<u8> {
+	let mut response = Vec::new();
+	let mut buffer = [0u8; 4096];
+	loop {
+		if let Some(head_length) = response.windows(4).position(|window| window == b"\r\n\r\n").map(|position| position + 4) {
+			let head = String::from_utf8_lossy(&response[..head_length]);
+			let content_length = head.split("\r\n")
+				.filter_map(|line| line.split_once(':'))
+				.find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+				.and_then(|(_, value)| value.trim().parse::<usize>().ok())
+				.unwrap_or(0);
+			if response.len() >= head_length + content_length {
+				response.truncate(head_length + content_length);
+				return response;
+			}
+		}
+
+		let bytes_read = stream.read(&mut buffer).expect("failed to read response");
+		if bytes_read == 0 {
+			return response;
+		}
+		response.extend_from_slice(&buffer[..bytes_read]);
+	}
+}
+
+fn connect(port: u16) -> TcpStream {
+	let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).expect("failed to connect to host");
+	stream.set_read_timeout(Some(TIMEOUT)).expect("failed to set read timeout");
+	stream
+}
+
+/// Ports that are free for both TCP and UDP on loopback.
+///
+/// All sockets stay open until every port is picked, so no port is picked twice.
+fn free_ports<const N: usize>() -> [u16; N] {
+	let sockets: Vec<(TcpListener, UdpSocket)> = (0..N)
+		.map(|_| loop {
+			let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("failed to bind TCP socket");
+			let port = listener.local_addr().expect("failed to get TCP port").port();
+			if let Ok(socket) = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)) {
+				break (listener, socket);
+			}
+		})
+		.collect();
+
+	std::array::from_fn(|index| sockets[index].0.local_addr().expect("failed to get TCP port").port())
+}
+
+/// Wait until the host accepts connections on a port.
+fn wait_for_port(port: u16) {
+	let deadline = Instant::now() + TIMEOUT;
+	while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err() {
+		assert!(Instant::now() < deadline, "host didn't start listening on port {port}");
+		std::thread::sleep(Duration::from_millis(20));
+	}
+}
+
+/// MAC address that the host reports when it is reached on `address`, that of the interface with the address.
+fn mac_address(address: IpAddr) -> String {
+	network_interface::NetworkInterface::show()
+		.unwrap_or_default()
+		.into_iter()
+		.find(|interface| interface.addr.iter().any(|interface_address| interface_address.ip() == address))
+		.and_then(|interface| interface.mac_addr)
+		.unwrap_or_default()
+}
+
+#[cfg(feature = "openssl")]
+fn random<const N: usize>() -> [u8; N] {
+	let mut bytes = [0u8; N];
+	openssl::rand::rand_bytes(&mut bytes).expect("failed to generate random bytes");
+	bytes
+}
+
+#[cfg(feature = "openssl")]
+fn sha256(data: &[u8]) -> [u8; 32] {
+	openssl::sha::sha256(data)
+}
+
+/// Key that encrypts the pairing challenges, from the salt of the client and the PIN.
+#[cfg(feature = "openssl")]
+fn pairing_key(salt: &[u8], pin: &str) -> Vec<u8> {
+	sha256(&[salt, pin.as_bytes()].concat())[..16].to_vec()
+}
+
+#[cfg(feature = "openssl")]
+fn aes_ecb(mode: openssl::symm::Mode, key: &[u8], data: &[u8]) -> Vec<u8> {
+	let mut crypter = openssl::symm::Crypter::new(openssl::symm::Cipher::aes_128_ecb(), mode, key, None)
+		.expect("failed to create cipher");
+	crypter.pad(false);
+	let mut output = vec![0u8; data.len() + 16];
+	let mut length = crypter.update(data, &mut output).expect("failed to run cipher");
+	length += crypter.finalize(&mut output[length..]).expect("failed to finish cipher");
+	output.truncate(length);
+	output
+}
+
+#[cfg(feature = "openssl")]
+fn sign(data: &[u8], key: &openssl::pkey::PKey<openssl::pkey::Private>) -> Vec<u8> {
+	let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), key).expect("failed to create signer");
+	signer.update(data).expect("failed to sign");
+	signer.sign_to_vec().expect("failed to sign")
+}
+
+#[cfg(feature = "openssl")]
+fn verify(data: &[u8], signature: &[u8], certificate: &openssl::x509::X509) -> bool {
+	let key = certificate.public_key().expect("certificate has no public key");
+	let mut verifier = openssl::sign::Verifier::new(openssl::hash::MessageDigest::sha256(), &key).expect("failed to create verifier");
+	verifier.update(data).expect("failed to verify");
+	verifier.verify(signature).unwrap_or(false)
+}
@@ -0,0 +1,254 @@
+//! A simulated Moonlight client that streams from the host from start to finish.
+//!
+//! The client pairs, launches, goes through the RTSP handshake, connects to the control stream and sends PINGs to the
+//! audio and video streams, then checks that RTP packets keep arriving while it sends input and pings. A second client
+//! with the same unique id, like every Moonlight client has, checks that only the client that launched can resume or
+//! quit, and a device that never paired is refused although it sends that id too. Unlike `tests/protocol_vectors.rs`
+//! this needs a host that can actually stream: an NVIDIA GPU with a display to capture and a PulseAudio server. Run it
+//! with `cargo test --features e2e`.
+//!
+//! ENet can only be initialized once per process, so the host uses the native implementation and the client the ENet
+//! C library, which also checks the native implementation against the reference.
+
+#![cfg(feature = "e2e")]
+
+mod common;
+
+use std::{net::{Ipv4Addr, UdpSocket}, time::{Duration, Instant}};
+
+use common::{assert_response, element, fixture, header, Client, TestHost, Values, CLIENT_ID, TIMEOUT};
+use enet::{Address, BandwidthLimit, ChannelLimit, Enet, Event, Host, Packet, PacketMode};
+use moonshine_core::config::{Config, VideoStreamConfig};
+
+/// Number of channels Moonlight opens on the control stream.
+const CONTROL_CHANNEL_COUNT: usize = 0x30;
+
+/// Channel that Moonlight sends most control messages on.
+const GENERIC_CHANNEL: u8 = 0x00;
+
+/// Channel that Moonlight sends keyboard input on.
+const KEYBOARD_CHANNEL: u8 = 0x02;
+
+/// Number of intervals in which packets have to arrive on both streams.
+const STREAM_CHECKS: usize = 5;
+
+/// Length of the intervals in which packets have to arrive, enough for a key frame after the stream started.
+const STREAM_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time between the PINGs of the client, moonlight-common-c sends them every 500 milliseconds.
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A stream socket of the client, which sends PINGs to the host and receives RTP packets from it.
+struct StreamSocket {
+	name: &'static str,
+	socket: UdpSocket,
+	port: u16,
+	payload: String,
+	sequence_number: u32,
+	packets: usize,
+}
+
+impl StreamSocket {
+	fn new(name: &'static str, port: u16, payload: String) -> Self {
+		let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("failed to bind stream socket");
+		socket.set_nonblocking(true).expect("failed to make stream socket non-blocking");
+		Self { name, socket, port, payload, sequence_number: 0, packets: 0 }
+	}
+
+	/// Send a PING with the payload from the SETUP response, followed by a sequence number like moonlight-common-c.
+	fn ping(&mut self) {
+		self.sequence_number += 1;
+		let mut message = self.payload.as_bytes().to_vec();
+		message.extend(self.sequence_number.to_be_bytes());
+		self.socket.send_to(&message, (Ipv4Addr::LOCALHOST, self.port)).expect("failed to send PING");
+	}
+
+	/// Receive the packets that arrived, checking that they are RTP packets.
+	fn receive(&mut self) {
+		let mut buffer = [0u8; 2048];
+		while let Ok(length) = self.socket.recv(&mut buffer) {
+			assert!(length >= 12, "{} packet of {length} bytes is too short for an RTP header", self.name);
+			assert_eq!(buffer[0] >> 6, 2, "{} packet isn't RTP version 2", self.name);
+			self.packets += 1;
+		}
+	}
+}
+
+/// A control message from `tests/fixtures/protocol/control`.
+fn control_message(name: &str) -> Packet {
+	let message = fixture(&format!("control/{name}.hex"), &Values::new());
+	let message = hex::decode(String::from_utf8_lossy(&message).trim()).expect("control fixture isn't hex");
+	Packet::new(&message, PacketMode::ReliableSequenced).expect("failed to create control packet")
+}
+
+/// Wait until the control host connected to the host.
+fn wait_for_connect(host: &mut Host<()>) {
+	let deadline = Instant::now() + TIMEOUT;
+	while Instant::now() < deadline {
+		if let Some(Event::Connect(_)) = host.service(100).expect("failed to service control stream") {
+			return;
+		}
+	}
+
+	panic!("control stream didn't connect");
+}
+
+/// Keep the streams going for `duration`, sending PINGs and receiving packets, panics when the host disconnects.
+fn stream_for(control: &mut Host<()>, audio: &mut StreamSocket, video: &mut StreamSocket, duration: Duration) {
+	let start = Instant::now();
+	let mut last_ping = start;
+	while start.elapsed() < duration {
+		if let Some(Event::Disconnect(..)) = control.service(50).expect("failed to service control stream") {
+			panic!("host closed the control stream");
+		}
+
+		if last_ping.elapsed() >= PING_INTERVAL {
+			last_ping = Instant::now();
+			audio.ping();
+			video.ping();
+		}
+
+		audio.receive();
+		video.receive();
+	}
+}
+
+/// Start a host with the default encoders, after `configure` changed its configuration.
+fn streaming_host(configure: impl FnOnce(&mut Config)) -> TestHost {
+	TestHost::start_with(|config| {
+		let defaults = VideoStreamConfig::default();
+		config.stream.video.codec_h264 = defaults.codec_h264;
+		config.stream.video.codec_hevc = defaults.codec_hevc;
+		config.stream.video.fallback_codecs_h264 = defaults.fallback_codecs_h264;
+		config.stream.video.fallback_codecs_hevc = defaults.fallback_codecs_hevc;
+		configure(config);
+	})
+}
+
+/// Pair a new client and launch the desktop with it.
+fn launch(host: &TestHost, values: &Values) -> Client {
+	let mut client = Client::new(CLIENT_ID);
+	client.pair(host, "1234");
+
+	let response = host.https(&client, &fixture("https/launch.request", values));
+	assert_eq!(element(&response, "gamesession"), "1", "launch failed: {}", String::from_utf8_lossy(&response));
+	client
+}
+
+#[test]
+fn stream() {
+	let host = streaming_host(|_| {});
+	let values = host.values();
+	launch(&host, &values);
+
+	// The host only sends the ping payloads when a session exists, which the protocol vectors don't have.
+	host.rtsp(&fixture("rtsp/options.request", &values));
+	host.rtsp(&fixture("rtsp/describe.request", &values));
+	let audio_payload = header(&host.rtsp(&fixture("rtsp/setup-audio.request", &values)), "X-SS-Ping-Payload");
+	let video_payload = header(&host.rtsp(&fixture("rtsp/setup-video.request", &values)), "X-SS-Ping-Payload");
+	assert_response(&host.rtsp(&fixture("rtsp/setup-control.request", &values)), &fixture("rtsp/setup-control.response", &values));
+
+	// Moonlight asks for an encrypted control stream, the control fixtures are encrypted with the key of the launch.
+	let announce = String::from_utf8(fixture("rtsp/announce.request", &values)).expect("announce isn't UTF-8")
+		.replace("x-ss-general.encryptionEnabled:0", "x-ss-general.encryptionEnabled:1");
+	for request in [announce.into_bytes(), fixture("rtsp/play.request", &values)] {
+		let response = host.rtsp(&request);
+		assert!(response.starts_with(b"RTSP/1.0 200 OK\r\n"), "unexpected response: {}", String::from_utf8_lossy(&response));
+	}
+
+	let enet = Enet::new().expect("failed to initialize ENet");
+	let mut control = enet
+		.create_host::<()>(None, 1, ChannelLimit::Limited(CONTROL_CHANNEL_COUNT), BandwidthLimit::Unlimited, BandwidthLimit::Unlimited)
+		.expect("failed to create control host");
+	control.connect(&Address::new(Ipv4Addr::LOCALHOST, host.config.stream.control.port), CONTROL_CHANNEL_COUNT, 0)
+		.expect("failed to connect control stream");
+	wait_for_connect(&mut control);
+
+	let mut audio = StreamSocket::new("audio", host.config.stream.audio.port, audio_payload);
+	let mut video = StreamSocket::new("video", host.config.stream.video.port, video_payload);
+	audio.ping();
+	video.ping();
+
+	let mut peer = control.peers().next().expect("control stream has no peer");
+	peer.send_packet(control_message("start_a"), GENERIC_CHANNEL).expect("failed to send StartA");
+	peer.send_packet(control_message("start_b"), GENERIC_CHANNEL).expect("failed to send StartB");
+	peer.send_packet(control_message("ping"), GENERIC_CHANNEL).expect("failed to send ping");
+	peer.send_packet(control_message("input_key_down"), KEYBOARD_CHANNEL).expect("failed to send input");
+
+	// The stream is alive as long as the host keeps sending packets on both streams, not just right after it started.
+	for _ in 0..STREAM_CHECKS {
+		let (audio_packets, video_packets) = (audio.packets, video.packets);
+		stream_for(&mut control, &mut audio, &mut video, STREAM_CHECK_INTERVAL);
+		assert!(audio.packets > audio_packets, "no audio packets arrived for {STREAM_CHECK_INTERVAL:?}");
+		assert!(video.packets > video_packets, "no video packets arrived for {STREAM_CHECK_INTERVAL:?}");
+	}
+}
+
+#[test]
+fn clients_with_the_same_id() {
+	let host = streaming_host(|config| config.coop.max_guests = 0);
+	let values = host.values();
+	let owner = launch(&host, &values);
+
+	// Every Moonlight client sends the same unique id, only its certificate tells it apart from the one that launched.
+	let mut other = Client::new(CLIENT_ID);
+	other.pair(&host, "1234");
+	assert_eq!(owner.id, other.id);
+
+	let response = host.https(&other, &fixture("https/resume.request", &values));
+	assert!(
+		String::from_utf8_lossy(&response).contains("status_code=\"400\""),
+		"another client resumed the session: {}",
+		String::from_utf8_lossy(&response),
+	);
+
+	let response = host.https(&owner, &fixture("https/resume.request", &values));
+	assert_eq!(element(&response, "resume"), "1", "resume failed: {}", String::from_utf8_lossy(&response));
+}
+
+#[test]
+fn unpaired_certificates_are_refused() {
+	let host = streaming_host(|config| config.coop.max_guests = 1);
+	let values = host.values();
+	let _owner = launch(&host, &values);
+
+	// A device that never paired sends the same unique id as the paired clients, but not a certificate the host knows.
+	let stranger = Client::new(CLIENT_ID);
+	for request in ["https/resume.request", "https/cancel.request", "https/launch.request"] {
+		let response = host.https(&stranger, &fixture(request, &values));
+		assert!(
+			String::from_utf8_lossy(&response).contains("status_code=\"401\""),
+			"{request} was accepted from an unpaired client: {}",
+			String::from_utf8_lossy(&response),
+		);
+	}
+
+	let response = host.https(&stranger, &fixture("https/serverinfo.request", &values));
+	assert_eq!(element(&response, "PairStatus"), "0");
+}
+
+#[test]
+fn other_clients_see_a_busy_host() {
+	let host = streaming_host(|_| {});
+	let values = host.values();
+	let owner = launch(&host, &values);
+	let mut other = Client::new(CLIENT_ID);
+	other.pair(&host, "1234");
+
+	// Only the client that launched sees the running application, and so only it offers to resume or quit it.
+	let response = host.https(&owner, &fixture("https/serverinfo.request", &values));
+	assert_eq!(element(&response, "currentgame"), values["app_id"]);
+	let response = host.https(&other, &fixture("https/serverinfo.request", &values));
+	assert_eq!(element(&response, "currentgame"), "0");
+	assert_eq!(element(&response, "state"), "MOONSHINE_SERVER_BUSY");
+
+	let response = host.https(&other, &fixture("https/cancel.request", &values));
+	assert!(
+		String::from_utf8_lossy(&response).contains("status_code=\"400\""),
+		"another client quit the session: {}",
+		String::from_utf8_lossy(&response),
+	);
+
+	let response = host.https(&owner, &fixture("https/cancel.request", &values));
+	assert_eq!(element(&response, "cancel"), "1", "cancel failed: {}", String::from_utf8_lossy(&response));
+}
@@ -0,0 +1,92 @@
+//! Requests in the format of Moonlight to the webserver, pairing and RTSP server, compared byte for byte with the
+//! responses the host sent when the vectors in `tests/fixtures/protocol` were recorded.
+//!
+//! The vectors are self-generated rather than captured from real clients, so they catch regressions in the hand-rolled
+//! formats but not misunderstandings of the protocol.
+//!
+//! The host runs without video encoders, so these cover everything up to the point where a stream would start.
+
+#![cfg(feature = "openssl")]
+
+mod common;
+
+use common::{assert_response, fixture, Client, TestHost, Values, CLIENT_ID};
+
+/// Send the request fixture `<name>.request` over HTTP and compare the response with `<name>.response`.
+fn http(host: &TestHost, name: &str, values: &Values) {
+	let response = host.http(&fixture(&format!("{name}.request"), values));
+	assert_response(&response, &fixture(&format!("{name}.response"), values));
+}
+
+fn https(host: &TestHost, client: &Client, name: &str, values: &Values) {
+	let response = host.https(client, &fixture(&format!("{name}.request"), values));
+	assert_response(&response, &fixture(&format!("{name}.response"), values));
+}
+
+fn rtsp(host: &TestHost, name: &str, values: &Values) {
+	let response = host.rtsp(&fixture(&format!("{name}.request"), values));
+	assert_response(&response, &fixture(&format!("{name}.response"), values));
+}
+
+fn paired_client(host: &TestHost) -> Client {
+	let mut client = Client::new(CLIENT_ID);
+	client.pair(host, "1234");
+	client
+}
+
+#[test]
+fn serverinfo() {
+	let host = TestHost::start();
+	http(&host, "http/serverinfo", &host.values());
+}
+
+#[test]
+fn https_only_paths_redirect() {
+	let host = TestHost::start();
+	http(&host, "http/applist", &host.values());
+}
+
+#[test]
+fn pairing() {
+	let host = TestHost::start();
+	let mut client = Client::new(CLIENT_ID);
+	for exchange in client.pair(&host, "1234") {
+		assert_response(&exchange.response, &fixture(&format!("{}.response", exchange.name), &exchange.values));
+	}
+
+	// A paired client is only recognized over HTTPS.
+	https(&host, &client, "https/serverinfo", &host.values());
+	http(&host, "http/serverinfo", &host.values());
+}
+
+#[test]
+fn applist() {
+	let host = TestHost::start();
+	let client = paired_client(&host);
+	https(&host, &client, "https/applist", &host.values());
+}
+
+#[test]
+fn launch_without_encoder() {
+	let host = TestHost::start();
+	let client = paired_client(&host);
+	https(&host, &client, "https/launch", &host.values());
+	https(&host, &client, "https/resume", &host.values());
+	https(&host, &client, "https/cancel", &host.values());
+}
+
+#[test]
+fn launch_unpaired() {
+	let host = TestHost::start();
+	let client = Client::new(CLIENT_ID);
+	https(&host, &client, "https/launch-unpaired", &host.values());
+}
+
+#[test]
+fn rtsp_handshake() {
+	let host = TestHost::start();
+	let values = host.values();
+	for name in ["options", "describe", "setup-audio", "setup-video", "setup-control", "announce", "play"] {
+		rtsp(&host, &format!("rtsp/{name}"), &values);
+	}
+}